@@ -0,0 +1,149 @@
+//! Micro-benchmarks of the paths drivers hit per request or per packet.
+//!
+//! Everything runs against in-memory backends (the mock configuration space
+//! and a shared-memory ring on the heap), so the numbers measure this
+//! crate's overhead, not the hardware's. Compare against a baseline with
+//! `cargo bench --bench hotpaths -- --save-baseline main` and
+//! `cargo bench --bench hotpaths -- --baseline main`.
+
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use driverkit::iomem::{IOBuf, IOBufChain};
+use driverkit::pci::mock::MockConfig;
+use driverkit::pci::{CapabilityId, PCIAddress, PciDevice};
+use driverkit::shmring::{ShmDesc, ShmRing, ShmRingFeatures};
+use driverkit::PciInterface;
+
+const SLOTS: u32 = 256;
+const BATCH: usize = 32;
+
+/// A NIC-like function: 64-bit BAR 0, 32-bit BAR 2, power management and
+/// MSI-X capabilities.
+fn device() -> PciDevice<MockConfig> {
+    let mut space = [0u8; 0x100];
+    space[0..4].copy_from_slice(&0x10d3_8086u32.to_le_bytes());
+    space[6] = 0x10;
+    space[0x34] = 0x40;
+    space[0x40..0x44].copy_from_slice(&0x0003_5001u32.to_le_bytes());
+    space[0x50..0x54].copy_from_slice(&0x0004_0011u32.to_le_bytes());
+    space[0x54..0x58].copy_from_slice(&0x0000_0002u32.to_le_bytes());
+
+    let addr = PCIAddress {
+        bus: 0,
+        dev: 3,
+        fun: 0,
+    };
+    let mut config = MockConfig::from_bytes(addr, &space);
+    config.set_memory_bar(0, 0xfe00_0000, 0x2_0000, true);
+    config.set_memory_bar(2, 0xfe02_0000, 0x4000, false);
+    PciDevice::from_config(config).unwrap()
+}
+
+fn config_access(c: &mut Criterion) {
+    let mut device = device();
+    let mut group = c.benchmark_group("config");
+    group.bench_function("read32", |b| {
+        b.iter(|| device.config().read(black_box(0x40)))
+    });
+    group.bench_function("find_capability", |b| {
+        b.iter(|| device.find_capability(black_box(CapabilityId::MsiX)))
+    });
+    group.bench_function("enable_bus_mastering", |b| {
+        b.iter(|| device.enable_bus_mastering())
+    });
+    group.finish();
+}
+
+fn bar_decode(c: &mut Criterion) {
+    let device = device();
+    let mut group = c.benchmark_group("bar");
+    group.bench_function("decode_64bit", |b| b.iter(|| device.bar(black_box(0))));
+    group.bench_function("table", |b| b.iter(|| device.bar_table()));
+    group.finish();
+}
+
+/// Cache-line aligned memory for a ring.
+#[repr(C, align(64))]
+struct RingMemory([u8; 0xc0 + SLOTS as usize * 16]);
+
+fn desc(id: u16) -> ShmDesc {
+    ShmDesc {
+        offset: id as u64 * 2048,
+        len: 1500,
+        flags: 0,
+        id,
+    }
+}
+
+fn ring(c: &mut Criterion) {
+    // Declared first, so it's dropped after the ring ends
+    let mut memory = Box::new(RingMemory([0; 0xc0 + SLOTS as usize * 16]));
+    let len = memory.0.len();
+    assert_eq!(len, ShmRing::required_len(SLOTS));
+    let base = NonNull::new(memory.0.as_mut_ptr()).unwrap();
+    // Safety: the memory outlives both ends and nothing else accesses it
+    let mut producer =
+        unsafe { ShmRing::create(base, len, SLOTS, ShmRingFeatures::empty()) }.unwrap();
+    let mut consumer = unsafe {
+        ShmRing::attach(
+            base,
+            len,
+            ShmRingFeatures::empty(),
+            ShmRingFeatures::empty(),
+        )
+    }
+    .unwrap();
+
+    let mut group = c.benchmark_group("ring");
+    group.bench_function("push_pop", |b| {
+        b.iter(|| {
+            producer.push(black_box(desc(1))).unwrap();
+            consumer.pop().unwrap().unwrap()
+        })
+    });
+    let descs: Vec<ShmDesc> = (0..BATCH as u16).map(desc).collect();
+    let mut out = vec![ShmDesc::default(); BATCH];
+    group.bench_function("push_pop_batch32", |b| {
+        b.iter(|| {
+            assert_eq!(producer.push_batch(black_box(&descs)).unwrap(), BATCH);
+            consumer.pop_batch(&mut out).unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn dma_map(c: &mut Criterion) {
+    let layout = Layout::from_size_align(4096, 4096).unwrap();
+    let mut group = c.benchmark_group("dma");
+    let mut buf = IOBuf::new(layout).unwrap();
+    group.bench_function("map_unmap_4k", |b| {
+        b.iter(|| {
+            buf.give_to_device();
+            buf.take_from_device();
+        })
+    });
+    let mut chain = IOBufChain::new(0, 4).unwrap();
+    for _ in 0..4 {
+        chain.append(IOBuf::new(layout).unwrap());
+    }
+    group.bench_function("map_unmap_chain4", |b| {
+        b.iter(|| {
+            chain.give_to_device();
+            chain.take_from_device();
+        })
+    });
+    group.bench_function("alloc_map_unmap_free_4k", |b| {
+        b.iter(|| {
+            let mut buf = IOBuf::new(layout).unwrap();
+            buf.give_to_device();
+            buf.take_from_device();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, config_access, bar_decode, ring, dma_map);
+criterion_main!(benches);
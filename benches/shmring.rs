@@ -0,0 +1,171 @@
+//! Cross-core throughput of the shared-memory ring.
+//!
+//! A producer and a consumer thread move descriptors through a ring with
+//! 256 slots. `packed` is a ring with both indices on one cache line that
+//! reads the peer's index on every operation (the layout before the indices
+//! were split), the others use [`ShmRing`].
+//!
+//! Run with `cargo bench --bench shmring`, pin the process to two physical
+//! cores (e.g., `taskset -c 2,4`) for stable numbers.
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use driverkit::shmring::{ShmDesc, ShmRing, ShmRingFeatures};
+
+const SLOTS: u32 = 256;
+const COUNT: u32 = 20_000_000;
+const BATCH: usize = 32;
+
+/// A cache-line aligned, zeroed region shared by both threads.
+struct Region {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl Region {
+    fn new(len: usize) -> Region {
+        let layout = Layout::from_size_align(len, 64).unwrap();
+        // Safety: the layout has a non-zero size
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) }).expect("out of memory");
+        Region { ptr, layout }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        // Safety: allocated with the same layout in `new`
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+/// Both indices next to each other, no shadow copies.
+#[repr(C, align(64))]
+struct PackedRing {
+    producer: AtomicU32,
+    consumer: AtomicU32,
+    slots: [ShmDesc; SLOTS as usize],
+}
+
+impl PackedRing {
+    fn push(&self, desc: ShmDesc) -> bool {
+        let head = self.producer.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.consumer.load(Ordering::Acquire)) >= SLOTS {
+            return false;
+        }
+        let slot = &self.slots[(head % SLOTS) as usize] as *const ShmDesc as *mut ShmDesc;
+        // Safety: the slot is owned by the producer until published
+        unsafe { slot.write_volatile(desc) };
+        self.producer.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<ShmDesc> {
+        let tail = self.consumer.load(Ordering::Relaxed);
+        if self.producer.load(Ordering::Acquire) == tail {
+            return None;
+        }
+        // Safety: the slot was published by the producer
+        let desc =
+            unsafe { (&self.slots[(tail % SLOTS) as usize] as *const ShmDesc).read_volatile() };
+        self.consumer.store(tail.wrapping_add(1), Ordering::Release);
+        Some(desc)
+    }
+}
+
+fn desc(id: u32) -> ShmDesc {
+    ShmDesc {
+        offset: id as u64 * 2048,
+        len: 1500,
+        flags: 0,
+        id: id as u16,
+    }
+}
+
+fn report(name: &str, start: Instant) {
+    let elapsed = start.elapsed();
+    println!(
+        "{:<10} {:>8.2} Mdesc/s ({:?})",
+        name,
+        COUNT as f64 / elapsed.as_secs_f64() / 1e6,
+        elapsed
+    );
+}
+
+fn packed() {
+    let region = Region::new(std::mem::size_of::<PackedRing>());
+    let addr = region.ptr.as_ptr() as usize;
+    let start = Instant::now();
+    let consumer = thread::spawn(move || {
+        // Safety: the region is zeroed (a valid empty ring) and outlives
+        // the thread
+        let ring = unsafe { &*(addr as *const PackedRing) };
+        let mut received = 0;
+        while received < COUNT {
+            if let Some(desc) = ring.pop() {
+                assert_eq!(desc.id, received as u16);
+                received += 1;
+            }
+        }
+    });
+    // Safety: see above
+    let ring = unsafe { &*(addr as *const PackedRing) };
+    for id in 0..COUNT {
+        while !ring.push(desc(id)) {}
+    }
+    consumer.join().unwrap();
+    report("packed", start);
+}
+
+fn shmring(batch: usize) {
+    let len = ShmRing::required_len(SLOTS);
+    let region = Region::new(len);
+    // Safety: the region is valid for `len` bytes, cache-line aligned and
+    // outlives both rings
+    let mut producer =
+        unsafe { ShmRing::create(region.ptr, len, SLOTS, ShmRingFeatures::empty()) }.unwrap();
+    let mut consumer = unsafe {
+        ShmRing::attach(
+            region.ptr,
+            len,
+            ShmRingFeatures::empty(),
+            ShmRingFeatures::empty(),
+        )
+    }
+    .unwrap();
+
+    let start = Instant::now();
+    let thread = thread::spawn(move || {
+        let mut descs = vec![ShmDesc::default(); batch];
+        let mut received = 0;
+        while received < COUNT {
+            let count = consumer.pop_batch(&mut descs).unwrap();
+            for desc in &descs[..count] {
+                assert_eq!(desc.id, received as u16);
+                received += 1;
+            }
+        }
+    });
+    let mut descs = Vec::with_capacity(batch);
+    let mut id = 0;
+    while id < COUNT {
+        descs.clear();
+        descs.extend((id..COUNT.min(id + batch as u32)).map(desc));
+        let mut sent = 0;
+        while sent < descs.len() {
+            sent += producer.push_batch(&descs[sent..]).unwrap();
+        }
+        id += descs.len() as u32;
+    }
+    thread.join().unwrap();
+    report(if batch == 1 { "shmring" } else { "batched" }, start);
+}
+
+fn main() {
+    packed();
+    shmring(1);
+    shmring(BATCH);
+}
@@ -0,0 +1,85 @@
+//! Derive macros for driverkit.
+//!
+//! `#[derive(DriverControl)]` implements `DriverControl::state()` and
+//! `DriverControl::set_state()` by reading/writing the field marked with
+//! `#[driver_state]`:
+//!
+//! ```ignore
+//! use driverkit::{DriverControl, DriverState};
+//!
+//! #[derive(DriverControl)]
+//! struct MyDriver {
+//!     #[driver_state]
+//!     state: DriverState,
+//!     // ...
+//! }
+//! ```
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Index, Member};
+
+#[proc_macro_derive(DriverControl, attributes(driver_state))]
+pub fn derive_driver_control(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match state_field(&input) {
+        Ok(field) => {
+            let name = &input.ident;
+            let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+            let expanded = quote! {
+                impl #impl_generics ::driverkit::DriverControl for #name #ty_generics #where_clause {
+                    fn state(&self) -> ::driverkit::DriverState {
+                        self.#field
+                    }
+
+                    fn set_state(&mut self, ds: ::driverkit::DriverState) {
+                        self.#field = ds;
+                    }
+                }
+            };
+            expanded.into()
+        }
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Finds the (single) field marked with `#[driver_state]`.
+fn state_field(input: &DeriveInput) -> syn::Result<Member> {
+    let fields = match &input.data {
+        Data::Struct(s) => &s.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "DriverControl can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut marked = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.attrs.iter().any(|a| a.path.is_ident("driver_state")));
+
+    let (idx, field) = marked.next().ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "DriverControl derive needs a field marked with #[driver_state]",
+        )
+    })?;
+    if let Some((_, dup)) = marked.next() {
+        return Err(syn::Error::new_spanned(
+            dup,
+            "only one field can be marked with #[driver_state]",
+        ));
+    }
+
+    Ok(match (fields, &field.ident) {
+        (Fields::Named(_), Some(ident)) => Member::Named(ident.clone()),
+        _ => Member::Unnamed(Index::from(idx)),
+    })
+}
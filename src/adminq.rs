@@ -0,0 +1,266 @@
+//! Command queues to device firmware.
+//!
+//! Many devices (i40e, ice, NVMe, ...) are configured by sending commands to
+//! their firmware: the driver writes a command descriptor to a ring in DMA
+//! memory, bumps a tail doorbell and polls for the completion, which carries
+//! a driver chosen tag (cookie, command identifier) to match it with the
+//! command. [`AdminQueue`] implements this, the device specific parts
+//! (descriptor formats, registers, where completions show up) are provided
+//! by an [`AdminQueueHw`].
+
+use alloc::alloc::Layout;
+use core::marker::PhantomData;
+use core::time::Duration;
+
+use custom_error::custom_error;
+
+use crate::dma_safe::DmaSafe;
+use crate::iomem::{DmaObject, IOBuf, IOMemError};
+use crate::poll::poll_until;
+use crate::IOAddr;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub AdminQueueError
+    Timeout = "timed out waiting for the completion",
+    NotEnabled = "the device didn't enable the queue",
+    OutOfMemory = "couldn't allocate the ring",
+}
+
+impl From<IOMemError> for AdminQueueError {
+    fn from(_e: IOMemError) -> Self {
+        AdminQueueError::OutOfMemory
+    }
+}
+
+/// A ring of descriptors of type `T` in DMA memory.
+pub struct DmaRing<T> {
+    buf: IOBuf,
+    len: usize,
+    _desc: PhantomData<T>,
+}
+
+impl<T: DmaSafe + Copy> DmaRing<T> {
+    /// Allocates a zeroed ring of `len` descriptors.
+    pub fn new(len: usize) -> Result<DmaRing<T>, IOMemError> {
+        let layout = Layout::array::<T>(len)
+            .and_then(|l| l.align_to(64))
+            .map_err(|_e| IOMemError::OutOfMemory)?;
+        Ok(DmaRing {
+            buf: IOBuf::new(layout)?,
+            len,
+            _desc: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Device address of the first descriptor.
+    pub fn ioaddr(&self) -> IOAddr {
+        self.buf.ioaddr()
+    }
+
+    pub fn read(&self, index: usize) -> T {
+        assert!(index < self.len);
+        // Safety: in bounds, the buffer is aligned for `T`
+        unsafe { core::ptr::read_volatile((self.buf.as_slice().as_ptr() as *const T).add(index)) }
+    }
+
+    pub fn write(&mut self, index: usize, desc: T) {
+        assert!(index < self.len);
+        // Safety: in bounds, the buffer is aligned for `T`
+        unsafe {
+            core::ptr::write_volatile(
+                (self.buf.as_mut_slice().as_mut_ptr() as *mut T).add(index),
+                desc,
+            )
+        };
+    }
+}
+
+/// Device specific part of an [`AdminQueue`].
+pub trait AdminQueueHw {
+    type Command: DmaSafe + Copy;
+    type Completion: Copy;
+
+    /// Programs the command ring into the device and enables the queue.
+    fn enable(&mut self, ring: &DmaRing<Self::Command>) -> Result<(), AdminQueueError>;
+
+    /// Disables the queue (before the ring is freed).
+    fn disable(&mut self);
+
+    /// Stores the sequence number in the command (e.g., in its cookie).
+    fn set_sequence(command: &mut Self::Command, sequence: u16);
+
+    /// The sequence number of the command a completion belongs to.
+    fn sequence(completion: &Self::Completion) -> u16;
+
+    /// Tells the device that commands up to (excluding) `tail` are ready.
+    fn ring_doorbell(&mut self, tail: usize);
+
+    /// Returns the next completion the device posted, if any. Devices that
+    /// write completions back into the command slot read them from `ring`.
+    fn poll_completion(&mut self, ring: &DmaRing<Self::Command>) -> Option<Self::Completion>;
+}
+
+/// A firmware command queue with one outstanding command at a time.
+pub struct AdminQueue<H: AdminQueueHw> {
+    hw: H,
+    ring: DmaRing<H::Command>,
+    tail: usize,
+    sequence: u16,
+    timeout: Duration,
+}
+
+impl<H: AdminQueueHw> AdminQueue<H> {
+    /// Default time to wait for a completion.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Allocates a ring with `len` commands and enables the queue.
+    pub fn new(mut hw: H, len: usize) -> Result<AdminQueue<H>, AdminQueueError> {
+        let ring = DmaRing::new(len)?;
+        hw.enable(&ring)?;
+        Ok(AdminQueue {
+            hw,
+            ring,
+            tail: 0,
+            sequence: 0,
+            timeout: Self::DEFAULT_TIMEOUT,
+        })
+    }
+
+    pub fn hw(&self) -> &H {
+        &self.hw
+    }
+
+    pub fn hw_mut(&mut self) -> &mut H {
+        &mut self.hw
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Submits `command` and waits for its completion.
+    ///
+    /// Completions of earlier commands (that timed out) are dropped.
+    pub fn execute(&mut self, mut command: H::Command) -> Result<H::Completion, AdminQueueError> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let sequence = self.sequence;
+        H::set_sequence(&mut command, sequence);
+
+        self.ring.write(self.tail, command);
+        self.tail = (self.tail + 1) % self.ring.len();
+        {
+            span!(TRACE, "doorbell", tail = self.tail);
+            self.hw.ring_doorbell(self.tail);
+        }
+
+        let (hw, ring) = (&mut self.hw, &self.ring);
+        let mut completion = None;
+        let done = poll_until(
+            || match hw.poll_completion(ring) {
+                Some(c) if H::sequence(&c) == sequence => {
+                    completion = Some(c);
+                    true
+                }
+                Some(c) => {
+                    warn!("adminq: dropping stale completion {}", H::sequence(&c));
+                    false
+                }
+                None => false,
+            },
+            self.timeout,
+        );
+
+        match completion {
+            Some(c) if done => Ok(c),
+            _ => Err(AdminQueueError::Timeout),
+        }
+    }
+}
+
+impl<H: AdminQueueHw> Drop for AdminQueue<H> {
+    fn drop(&mut self) {
+        self.hw.disable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+
+    /// Completes every command with its value doubled, and first with a
+    /// stale completion if `stale` is set.
+    struct Doubler {
+        head: usize,
+        completions: VecDeque<(u16, u32)>,
+        stale: bool,
+        last_command: Option<[u32; 2]>,
+    }
+
+    impl AdminQueueHw for Doubler {
+        type Command = [u32; 2];
+        type Completion = (u16, u32);
+
+        fn enable(&mut self, _ring: &DmaRing<[u32; 2]>) -> Result<(), AdminQueueError> {
+            Ok(())
+        }
+
+        fn disable(&mut self) {}
+
+        fn set_sequence(command: &mut [u32; 2], sequence: u16) {
+            command[0] = sequence as u32;
+        }
+
+        fn sequence(completion: &(u16, u32)) -> u16 {
+            completion.0
+        }
+
+        fn ring_doorbell(&mut self, tail: usize) {
+            assert_eq!(tail, (self.head + 1) % 4);
+            self.head = tail;
+        }
+
+        fn poll_completion(&mut self, ring: &DmaRing<[u32; 2]>) -> Option<(u16, u32)> {
+            let [sequence, value] = ring.read((self.head + 3) % 4);
+            let sequence = sequence as u16;
+            if self.last_command != Some([sequence as u32, value]) {
+                self.last_command = Some([sequence as u32, value]);
+                if self.stale {
+                    self.completions.push_back((sequence.wrapping_sub(1), 0));
+                }
+                self.completions.push_back((sequence, value * 2));
+            }
+            self.completions.pop_front()
+        }
+    }
+
+    #[test]
+    fn sequence_matching() {
+        let hw = Doubler {
+            head: 0,
+            completions: VecDeque::new(),
+            stale: false,
+            last_command: None,
+        };
+        let mut queue = AdminQueue::new(hw, 4).unwrap();
+        for i in 0..6 {
+            assert_eq!(queue.execute([0, i]).unwrap(), (i as u16 + 1, 2 * i));
+        }
+
+        queue.hw_mut().stale = true;
+        assert_eq!(queue.execute([0, 21]).unwrap(), (7, 42));
+    }
+}
@@ -0,0 +1,328 @@
+//! ATA IDENTIFY data and SMART.
+//!
+//! [`IdentifyData`] is the typed form of the 512 bytes IDENTIFY DEVICE
+//! returns, [`SmartData`] the vendor-neutral part of SMART READ DATA. Both
+//! are read through [`AhciPort`], so health monitoring doesn't need its own
+//! passthrough to the device.
+
+use alloc::alloc::Layout;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use bit_field::BitField;
+
+use super::fis::H2dFis;
+use super::{AhciError, AhciPort, DeviceKind};
+use crate::iomem::IOBuf;
+
+/// Size of IDENTIFY and SMART data.
+pub const ATA_SECTOR_SIZE: usize = 512;
+
+const ATA_IDENTIFY_DEVICE: u8 = 0xec;
+const ATA_IDENTIFY_PACKET_DEVICE: u8 = 0xa1;
+const ATA_SMART: u8 = 0xb0;
+
+/// SMART subcommands (feature register).
+const SMART_READ_DATA: u16 = 0xd0;
+const SMART_RETURN_STATUS: u16 = 0xda;
+/// LBA mid/high of SMART commands, and of a SMART RETURN STATUS with the
+/// threshold exceeded.
+const SMART_LBA: u64 = 0xc2_4f00;
+const SMART_LBA_THRESHOLD_EXCEEDED: u64 = 0x2c_f400;
+
+/// Signature byte of the IDENTIFY integrity word (255).
+const IDENTIFY_CHECKSUM_SIGNATURE: u8 = 0xa5;
+/// Attribute entries in the SMART data.
+const SMART_ATTRIBUTES: usize = 30;
+const SMART_ATTRIBUTE_SIZE: usize = 12;
+
+/// Little-endian word `index` of ATA data.
+fn word(data: &[u8], index: usize) -> u16 {
+    u16::from_le_bytes([data[2 * index], data[2 * index + 1]])
+}
+
+/// An ATA string (words `words`), which stores its characters byte-swapped,
+/// without the space padding.
+fn ata_string(data: &[u8], words: core::ops::Range<usize>) -> String {
+    let s: String = words
+        .flat_map(|index| {
+            let [low, high] = word(data, index).to_le_bytes();
+            [high, low]
+        })
+        .map(|b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '?'
+            }
+        })
+        .collect();
+    String::from(s.trim())
+}
+
+/// Whether the sum of all bytes is 0 (the checksum of IDENTIFY and SMART
+/// data).
+fn checksum_valid(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// The device's answer to IDENTIFY (PACKET) DEVICE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentifyData {
+    pub serial: String,
+    pub firmware: String,
+    pub model: String,
+    /// Capacity in logical sectors.
+    pub sectors: u64,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub lba48: bool,
+    /// NCQ queue depth, None without NCQ support.
+    pub ncq_depth: Option<u8>,
+    pub smart_supported: bool,
+    pub smart_enabled: bool,
+    /// Supports DATA SET MANAGEMENT with TRIM.
+    pub trim: bool,
+}
+
+impl IdentifyData {
+    /// Parses the 512 bytes of IDENTIFY data.
+    pub fn parse(data: &[u8]) -> Result<IdentifyData, AhciError> {
+        if data.len() < ATA_SECTOR_SIZE {
+            return Err(AhciError::InvalidData);
+        }
+        let data = &data[..ATA_SECTOR_SIZE];
+        if data[510] == IDENTIFY_CHECKSUM_SIGNATURE && !checksum_valid(data) {
+            return Err(AhciError::InvalidData);
+        }
+
+        let lba48 = word(data, 83).get_bit(10);
+        let sectors = if lba48 {
+            (100..104).rev().fold(0u64, |sectors, index| {
+                sectors << 16 | word(data, index) as u64
+            })
+        } else {
+            (word(data, 61) as u64) << 16 | word(data, 60) as u64
+        };
+
+        // Word 106 is valid if bit 14 is set and bit 15 clear
+        let sector_info = word(data, 106);
+        let sector_info_valid = sector_info.get_bits(14..16) == 0b01;
+        let logical_sector_size = if sector_info_valid && sector_info.get_bit(12) {
+            ((word(data, 118) as u32) << 16 | word(data, 117) as u32) * 2
+        } else {
+            ATA_SECTOR_SIZE as u32
+        };
+        let physical_sector_size = if sector_info_valid && sector_info.get_bit(13) {
+            logical_sector_size << sector_info.get_bits(0..4)
+        } else {
+            logical_sector_size
+        };
+
+        let ncq_depth = if word(data, 76).get_bit(8) {
+            Some(word(data, 75).get_bits(0..5) as u8 + 1)
+        } else {
+            None
+        };
+
+        Ok(IdentifyData {
+            serial: ata_string(data, 10..20),
+            firmware: ata_string(data, 23..27),
+            model: ata_string(data, 27..47),
+            sectors,
+            logical_sector_size,
+            physical_sector_size,
+            lba48,
+            ncq_depth,
+            smart_supported: word(data, 82).get_bit(0),
+            smart_enabled: word(data, 85).get_bit(0),
+            trim: word(data, 169).get_bit(0),
+        })
+    }
+
+    /// Capacity in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.sectors * self.logical_sector_size as u64
+    }
+}
+
+/// A SMART attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SmartAttribute {
+    pub id: u8,
+    pub flags: u16,
+    /// Normalized value (typically 1 to 253, higher is better).
+    pub current: u8,
+    /// Lowest normalized value seen.
+    pub worst: u8,
+    /// Vendor specific raw value (48 bits).
+    pub raw: u64,
+}
+
+impl SmartAttribute {
+    /// Whether the device fails once the attribute crosses its threshold
+    /// (rather than it being advisory).
+    pub fn is_prefailure(&self) -> bool {
+        self.flags.get_bit(0)
+    }
+}
+
+/// The result of SMART READ DATA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmartData {
+    pub revision: u16,
+    pub attributes: Vec<SmartAttribute>,
+    pub offline_collection_status: u8,
+    pub self_test_status: u8,
+}
+
+impl SmartData {
+    /// Parses the 512 bytes of SMART data.
+    pub fn parse(data: &[u8]) -> Result<SmartData, AhciError> {
+        if data.len() < ATA_SECTOR_SIZE || !checksum_valid(&data[..ATA_SECTOR_SIZE]) {
+            return Err(AhciError::InvalidData);
+        }
+        let attributes = data[2..2 + SMART_ATTRIBUTES * SMART_ATTRIBUTE_SIZE]
+            .chunks_exact(SMART_ATTRIBUTE_SIZE)
+            .filter(|entry| entry[0] != 0)
+            .map(|entry| {
+                let mut raw = [0u8; 8];
+                raw[..6].copy_from_slice(&entry[5..11]);
+                SmartAttribute {
+                    id: entry[0],
+                    flags: u16::from_le_bytes([entry[1], entry[2]]),
+                    current: entry[3],
+                    worst: entry[4],
+                    raw: u64::from_le_bytes(raw),
+                }
+            })
+            .collect();
+        Ok(SmartData {
+            revision: word(data, 0),
+            attributes,
+            offline_collection_status: data[362],
+            self_test_status: data[363],
+        })
+    }
+
+    pub fn attribute(&self, id: u8) -> Option<&SmartAttribute> {
+        self.attributes.iter().find(|attribute| attribute.id == id)
+    }
+}
+
+impl AhciPort {
+    /// Executes a command that reads a sector of data from the device.
+    fn read_sector(&mut self, fis: &H2dFis) -> Result<IOBuf, AhciError> {
+        let layout = Layout::from_size_align(ATA_SECTOR_SIZE, 2).unwrap();
+        let mut buf = IOBuf::new(layout)?;
+        self.execute(fis, Some((&mut buf, false)))?;
+        Ok(buf)
+    }
+
+    /// Reads the IDENTIFY (PACKET) DEVICE data of the attached device.
+    pub fn identify(&mut self) -> Result<IdentifyData, AhciError> {
+        let command = match self.kind() {
+            Some(DeviceKind::Atapi) => ATA_IDENTIFY_PACKET_DEVICE,
+            _ => ATA_IDENTIFY_DEVICE,
+        };
+        let data = self.read_sector(&H2dFis::command(command))?;
+        IdentifyData::parse(data.as_slice())
+    }
+
+    fn smart_command(feature: u16) -> H2dFis {
+        H2dFis {
+            features: feature,
+            lba: SMART_LBA,
+            ..H2dFis::command(ATA_SMART)
+        }
+    }
+
+    /// Reads the device's SMART attributes.
+    pub fn smart_read_data(&mut self) -> Result<SmartData, AhciError> {
+        let data = self.read_sector(&AhciPort::smart_command(SMART_READ_DATA))?;
+        SmartData::parse(data.as_slice())
+    }
+
+    /// Asks the device whether any SMART threshold is exceeded.
+    ///
+    /// # Returns
+    /// True if the device predicts a failure.
+    pub fn smart_threshold_exceeded(&mut self) -> Result<bool, AhciError> {
+        let fis = self.execute(&AhciPort::smart_command(SMART_RETURN_STATUS), None)?;
+        Ok(fis.lba.get_bits(8..24) == SMART_LBA_THRESHOLD_EXCEEDED.get_bits(8..24))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_word(data: &mut [u8], index: usize, value: u16) {
+        data[2 * index..2 * index + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_string(data: &mut [u8], start: usize, s: &[u8]) {
+        for (index, pair) in s.chunks(2).enumerate() {
+            set_word(data, start + index, u16::from_be_bytes([pair[0], pair[1]]));
+        }
+    }
+
+    fn seal(data: &mut [u8]) {
+        let sum = data[..511].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        data[511] = 0u8.wrapping_sub(sum);
+    }
+
+    #[test]
+    fn identify_and_smart() {
+        let mut data = [0u8; ATA_SECTOR_SIZE];
+        set_string(&mut data, 10, b"      S3Z9NB0K123456");
+        set_string(&mut data, 23, b"RVT0    ");
+        set_string(&mut data, 27, b"Samsung SSD 860 EVO 500GB               ");
+        set_word(&mut data, 75, 31);
+        set_word(&mut data, 76, 1 << 8);
+        set_word(&mut data, 82, 1);
+        set_word(&mut data, 83, 1 << 10);
+        set_word(&mut data, 85, 1);
+        set_word(&mut data, 100, 0x6030);
+        set_word(&mut data, 101, 0x3a38);
+        set_word(&mut data, 106, 0x4003);
+        data[510] = IDENTIFY_CHECKSUM_SIGNATURE;
+        seal(&mut data);
+
+        let identify = IdentifyData::parse(&data).unwrap();
+        assert_eq!(identify.serial, "S3Z9NB0K123456");
+        assert_eq!(identify.firmware, "RVT0");
+        assert_eq!(identify.model, "Samsung SSD 860 EVO 500GB");
+        assert_eq!(identify.sectors, 976_773_168);
+        assert_eq!(identify.capacity(), 500_107_862_016);
+        assert_eq!(identify.logical_sector_size, 512);
+        // Word 106 bit 13 isn't set, so the exponent doesn't apply
+        assert_eq!(identify.physical_sector_size, 512);
+        assert_eq!(identify.ncq_depth, Some(32));
+        assert!(identify.smart_supported && identify.smart_enabled && !identify.trim);
+        data[0] ^= 1;
+        assert!(matches!(
+            IdentifyData::parse(&data),
+            Err(AhciError::InvalidData)
+        ));
+
+        let mut smart = [0u8; ATA_SECTOR_SIZE];
+        set_word(&mut smart, 0, 0x10);
+        smart[2..14].copy_from_slice(&[0x05, 0x33, 0x00, 100, 99, 0x07, 0, 0, 0, 0, 0, 0]);
+        smart[14..26].copy_from_slice(&[0x09, 0x32, 0x00, 95, 95, 0x10, 0x27, 0, 0, 0, 0, 0]);
+        seal(&mut smart);
+        let smart = SmartData::parse(&smart).unwrap();
+        assert_eq!(smart.attributes.len(), 2);
+        let reallocated = smart.attribute(0x05).unwrap();
+        assert!(reallocated.is_prefailure());
+        assert_eq!(
+            (reallocated.current, reallocated.worst, reallocated.raw),
+            (100, 99, 7)
+        );
+        assert_eq!(smart.attribute(0x09).unwrap().raw, 10000);
+
+        let fis = AhciPort::smart_command(SMART_READ_DATA).to_bytes();
+        assert_eq!(fis[2..7], [ATA_SMART, 0xd0, 0x00, 0x4f, 0xc2]);
+    }
+}
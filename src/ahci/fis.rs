@@ -0,0 +1,76 @@
+//! Frame information structures (FIS) exchanged with SATA devices.
+
+use bit_field::BitField;
+
+/// Length of a host to device register FIS.
+pub const H2D_FIS_LEN: usize = 20;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+/// A host to device register FIS (carries an ATA command).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct H2dFis {
+    /// Port multiplier port the FIS is addressed to.
+    pub pmp: u8,
+    pub command: u8,
+    pub features: u16,
+    /// 48-bit LBA.
+    pub lba: u64,
+    pub device: u8,
+    pub count: u16,
+}
+
+impl H2dFis {
+    pub fn command(command: u8) -> H2dFis {
+        H2dFis {
+            command,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; H2D_FIS_LEN] {
+        let mut fis = [0u8; H2D_FIS_LEN];
+        fis[0] = FIS_TYPE_REG_H2D;
+        // Command (not device control) register update
+        fis[1] = *self.pmp.get_bits(0..4).set_bit(7, true);
+        fis[2] = self.command;
+        fis[3] = self.features as u8;
+        let lba = self.lba.to_le_bytes();
+        fis[4..7].copy_from_slice(&lba[0..3]);
+        fis[7] = self.device;
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[11] = (self.features >> 8) as u8;
+        fis[12..14].copy_from_slice(&self.count.to_le_bytes());
+        fis
+    }
+}
+
+/// Status and error fields of a device to host register FIS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct D2hFis {
+    pub status: u8,
+    pub error: u8,
+    pub lba: u64,
+    pub count: u16,
+}
+
+impl D2hFis {
+    pub fn from_bytes(fis: &[u8]) -> D2hFis {
+        let mut lba = [0u8; 8];
+        lba[0..3].copy_from_slice(&fis[4..7]);
+        lba[3..6].copy_from_slice(&fis[8..11]);
+        D2hFis {
+            status: fis[2],
+            error: fis[3],
+            lba: u64::from_le_bytes(lba),
+            count: u16::from_le_bytes([fis[12], fis[13]]),
+        }
+    }
+
+    /// The value returned by a Read Port Multiplier command.
+    pub fn pm_value(&self) -> u32 {
+        self.count as u32 & 0xff | (self.lba as u32 & 0xff_ffff) << 8
+    }
+}
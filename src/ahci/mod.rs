@@ -0,0 +1,156 @@
+//! AHCI SATA host controllers.
+//!
+//! See the Serial ATA AHCI specification, revision 1.3.1. [`Ahci`] covers
+//! the HBA's generic registers, [`AhciPort`] a port with the device (or port
+//! multiplier) behind it.
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use crate::arch::VAddr;
+use crate::iomem::IOMemError;
+
+pub mod ata;
+pub mod fis;
+pub mod port;
+
+pub use ata::{IdentifyData, SmartAttribute, SmartData};
+pub use fis::{D2hFis, H2dFis};
+pub use port::{AhciPort, PortEvent, Presence};
+
+/// PCI class code of AHCI controllers (mass storage, SATA, AHCI 1.0).
+pub const AHCI_CLASS: (u8, u8, u8) = (0x01, 0x06, 0x01);
+/// The most ports an HBA can have.
+pub const MAX_PORTS: u8 = 32;
+
+/// Generic host control registers.
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const IS: usize = 0x08;
+const PI: usize = 0x0c;
+const VS: usize = 0x10;
+
+const CAP_SPM: usize = 17;
+const CAP_SSS: usize = 27;
+const GHC_IE: usize = 1;
+const GHC_AE: usize = 31;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub AhciError
+    NotImplemented{port: u8} = "port {port} isn't implemented by the HBA",
+    NoDevice = "no device is attached to the port",
+    Timeout = "the port or device didn't respond in time",
+    DeviceError{status: u8, error: u8} = "the device failed the command (status {status:#x}, error {error:#x})",
+    NotPortMultiplier = "the attached device isn't a port multiplier",
+    TransferTooLarge = "the transfer is too large for a single PRD entry",
+    InvalidData = "the device returned malformed IDENTIFY or SMART data",
+    OutOfMemory = "couldn't allocate the port's DMA memory",
+}
+
+impl From<IOMemError> for AhciError {
+    fn from(_e: IOMemError) -> Self {
+        AhciError::OutOfMemory
+    }
+}
+
+/// The kind of device behind a port, from its signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceKind {
+    Ata,
+    Atapi,
+    /// Enclosure management bridge.
+    Semb,
+    PortMultiplier,
+    Unknown(u32),
+}
+
+impl From<u32> for DeviceKind {
+    fn from(signature: u32) -> DeviceKind {
+        match signature {
+            0x0000_0101 => DeviceKind::Ata,
+            0xeb14_0101 => DeviceKind::Atapi,
+            0xc33c_0101 => DeviceKind::Semb,
+            0x9669_0101 => DeviceKind::PortMultiplier,
+            other => DeviceKind::Unknown(other),
+        }
+    }
+}
+
+/// An AHCI host bus adapter.
+pub struct Ahci {
+    regs: VAddr,
+}
+
+impl Ahci {
+    /// Switches the HBA to AHCI mode.
+    ///
+    /// # Safety
+    /// `regs` must be the mapped ABAR (BAR 5) of an AHCI controller.
+    pub unsafe fn new(regs: VAddr) -> Ahci {
+        let mut ahci = Ahci { regs };
+        let ghc = ahci.read_reg(GHC);
+        ahci.write_reg(GHC, *ghc.clone().set_bit(GHC_AE, true));
+        ahci
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        // Safety: `regs` points to the ABAR (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u32>()) }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u32) {
+        // Safety: `regs` points to the ABAR (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u32>(), value) };
+    }
+
+    /// AHCI version as (major, minor).
+    pub fn version(&self) -> (u16, u16) {
+        let vs = self.read_reg(VS);
+        ((vs >> 16) as u16, vs as u16)
+    }
+
+    pub fn supports_port_multiplier(&self) -> bool {
+        self.read_reg(CAP).get_bit(CAP_SPM)
+    }
+
+    /// Whether ports have to be spun up explicitly (staggered spin-up).
+    pub fn staggered_spin_up(&self) -> bool {
+        self.read_reg(CAP).get_bit(CAP_SSS)
+    }
+
+    /// Bitmap of the implemented ports.
+    pub fn implemented_ports(&self) -> u32 {
+        self.read_reg(PI)
+    }
+
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        let ghc = self.read_reg(GHC);
+        self.write_reg(GHC, *ghc.clone().set_bit(GHC_IE, enabled));
+    }
+
+    /// Returns and acknowledges the ports with pending interrupts (the
+    /// ports' own status has to be cleared first, see
+    /// [`AhciPort::handle_interrupt`]).
+    pub fn pending_ports(&mut self) -> u32 {
+        let pending = self.read_reg(IS);
+        self.write_reg(IS, pending);
+        pending
+    }
+
+    /// Sets up port `index` (allocating its command list and FIS area) and
+    /// detects the attached device.
+    pub fn port(&mut self, index: u8) -> Result<AhciPort, AhciError> {
+        if index >= MAX_PORTS || !self.implemented_ports().get_bit(index as usize) {
+            return Err(AhciError::NotImplemented { port: index });
+        }
+        let staggered = self.staggered_spin_up();
+        let supports_pm = self.supports_port_multiplier();
+        // Safety: the port registers are within the ABAR
+        let mut port =
+            unsafe { AhciPort::new(self.regs + port::port_offset(index), index, supports_pm)? };
+        port.init(staggered)?;
+        Ok(port)
+    }
+}
@@ -0,0 +1,542 @@
+//! AHCI ports: presence detection, hot-plug, command execution and port
+//! multipliers.
+//!
+//! A port learns about devices in two ways: through the PHY (hot presence,
+//! PxSSTS.DET and the PhyRdy/port connect change interrupts) and, on ports
+//! with a mechanical presence switch, through cold presence detection
+//! (PxCMD.CPS), which also works while the port is powered down.
+//! [`AhciPort::handle_interrupt`] (or [`AhciPort::poll_hotplug`] on HBAs
+//! without interrupts) turns changes of either into [`PortEvent`]s.
+
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::fis::{D2hFis, H2dFis, H2D_FIS_LEN};
+use super::{AhciError, DeviceKind};
+use crate::arch::VAddr;
+use crate::iomem::{DmaObject, IOBuf};
+use crate::poll::poll_until;
+
+/// Port registers, relative to the port's register block.
+const CLB: usize = 0x00;
+const CLBU: usize = 0x04;
+const FB: usize = 0x08;
+const FBU: usize = 0x0c;
+const IS: usize = 0x10;
+const IE: usize = 0x14;
+const CMD: usize = 0x18;
+const TFD: usize = 0x20;
+const SIG: usize = 0x24;
+const SSTS: usize = 0x28;
+const SCTL: usize = 0x2c;
+const SERR: usize = 0x30;
+const CI: usize = 0x38;
+
+const CMD_ST: usize = 0;
+const CMD_SUD: usize = 1;
+const CMD_POD: usize = 2;
+const CMD_FRE: usize = 4;
+const CMD_FR: usize = 14;
+const CMD_CR: usize = 15;
+const CMD_CPS: usize = 16;
+const CMD_PMA: usize = 17;
+const CMD_CPD: usize = 20;
+
+const IS_PCS: usize = 6;
+const IS_PRCS: usize = 22;
+const IS_TFES: usize = 30;
+const IS_CPDS: usize = 31;
+
+const SERR_DIAG_N: usize = 16;
+const SERR_DIAG_X: usize = 26;
+
+const STS_ERR: usize = 0;
+const STS_DRQ: usize = 3;
+const STS_BSY: usize = 7;
+
+/// SSTS.DET: device present and PHY communication established.
+const DET_PRESENT: u32 = 3;
+
+/// Read Port Multiplier (PMP 15 addresses the port multiplier itself).
+const ATA_READ_PM: u8 = 0xe4;
+const PMP_CONTROL: u8 = 0x0f;
+/// Port multiplier general status register: number of fan-out ports.
+const GSCR_PORT_INFO: u8 = 2;
+/// Port status and control register of a fan-out port: SStatus.
+const PSCR_SSTATUS: u8 = 0;
+
+/// Layout of the port's DMA memory (offsets from the aligned start): the
+/// command list (1 KiB aligned), the received FIS area (256 byte aligned)
+/// and the command table of slot 0 with a single PRD entry (128 byte
+/// aligned).
+const COMMAND_LIST_ALIGN: usize = 1024;
+const FIS_AREA: usize = 0x400;
+const COMMAND_TABLE: usize = 0x500;
+const COMMAND_TABLE_LEN: usize = 0x90;
+/// Offset of the D2H register FIS in the received FIS area.
+const RFIS: usize = 0x40;
+/// Offset of the PRD table in the command table.
+const PRDT: usize = 0x80;
+/// The most bytes a PRD entry can describe.
+const MAX_PRD_BYTES: usize = 4 << 20;
+
+const SPIN_UP_TIMEOUT: Duration = Duration::from_millis(10);
+const ENGINE_TIMEOUT: Duration = Duration::from_millis(500);
+const RESET_TIMEOUT: Duration = Duration::from_millis(1000);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Offset of the registers of port `index` in the ABAR.
+pub(crate) fn port_offset(index: u8) -> usize {
+    0x100 + index as usize * 0x80
+}
+
+/// Whether (and how) a device is present on a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Presence {
+    None,
+    /// The presence switch reports a device, but the port is powered down
+    /// or the PHY hasn't come up yet.
+    ColdPresent,
+    /// A device is present but PHY communication isn't established.
+    NoPhy,
+    Present,
+    /// The PHY is offline (disabled or in BIST loopback).
+    Offline,
+}
+
+impl Presence {
+    fn from_regs(ssts: u32, cmd: u32) -> Presence {
+        match ssts.get_bits(0..4) {
+            0 if cmd.get_bit(CMD_CPD) && cmd.get_bit(CMD_CPS) => Presence::ColdPresent,
+            0 => Presence::None,
+            1 => Presence::NoPhy,
+            DET_PRESENT => Presence::Present,
+            _ => Presence::Offline,
+        }
+    }
+}
+
+/// A change of the device attached to a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PortEvent {
+    Attached(DeviceKind),
+    Detached,
+}
+
+/// A port of an AHCI HBA.
+pub struct AhciPort {
+    regs: VAddr,
+    index: u8,
+    supports_pm: bool,
+    /// Command list, received FIS area and command table.
+    mem: IOBuf,
+    /// Offset of the command list in `mem`.
+    base: usize,
+    presence: Presence,
+    kind: Option<DeviceKind>,
+}
+
+impl AhciPort {
+    /// Allocates the port's DMA memory, see [`super::Ahci::port`].
+    ///
+    /// # Safety
+    /// `regs` must point to the registers of port `index`.
+    pub(crate) unsafe fn new(
+        regs: VAddr,
+        index: u8,
+        supports_pm: bool,
+    ) -> Result<AhciPort, AhciError> {
+        let len = COMMAND_LIST_ALIGN + COMMAND_TABLE + COMMAND_TABLE_LEN;
+        let layout = Layout::from_size_align(len, COMMAND_LIST_ALIGN).unwrap();
+        let mem = IOBuf::new(layout)?;
+        // IOBufs aren't necessarily aligned, so the command list starts at the
+        // next 1 KiB boundary
+        let misalignment = mem.ioaddr().as_u64() as usize & (COMMAND_LIST_ALIGN - 1);
+        let base = (COMMAND_LIST_ALIGN - misalignment) % COMMAND_LIST_ALIGN;
+        Ok(AhciPort {
+            regs,
+            index,
+            supports_pm,
+            mem,
+            base,
+            presence: Presence::None,
+            kind: None,
+        })
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        // Safety: `regs` points to the port's registers (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u32>()) }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u32) {
+        // Safety: `regs` points to the port's registers (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u32>(), value) };
+    }
+
+    fn update_reg(&mut self, offset: usize, bit: usize, value: bool) {
+        let reg = self.read_reg(offset);
+        self.write_reg(offset, *reg.clone().set_bit(bit, value));
+    }
+
+    /// Device address of `offset` in the port's DMA memory.
+    fn mem_addr(&self, offset: usize) -> u64 {
+        self.mem.ioaddr().as_u64() + (self.base + offset) as u64
+    }
+
+    /// Points the HBA to the port's memory, spins the port up and detects
+    /// the attached device.
+    pub(crate) fn init(&mut self, staggered: bool) -> Result<(), AhciError> {
+        self.stop()?;
+        let clb = self.mem_addr(0);
+        let fb = self.mem_addr(FIS_AREA);
+        self.write_reg(CLB, clb as u32);
+        self.write_reg(CLBU, (clb >> 32) as u32);
+        self.write_reg(FB, fb as u32);
+        self.write_reg(FBU, (fb >> 32) as u32);
+        self.write_reg(SERR, u32::MAX);
+        self.write_reg(IS, u32::MAX);
+        let mut ie = 0u32;
+        ie.set_bit(IS_PCS, true);
+        ie.set_bit(IS_PRCS, true);
+        ie.set_bit(IS_TFES, true);
+        ie.set_bit(IS_CPDS, self.read_reg(CMD).get_bit(CMD_CPD));
+        self.write_reg(IE, ie);
+
+        if self.read_reg(CMD).get_bit(CMD_CPD) {
+            self.update_reg(CMD, CMD_POD, true);
+        }
+        if staggered {
+            self.update_reg(CMD, CMD_SUD, true);
+        }
+        self.update_reg(CMD, CMD_FRE, true);
+        poll_until(
+            || self.read_reg(SSTS).get_bits(0..4) == DET_PRESENT,
+            SPIN_UP_TIMEOUT,
+        );
+
+        self.presence = self.presence();
+        if self.presence == Presence::NoPhy {
+            // The device didn't finish OOB signaling on its own
+            self.comreset()?;
+        }
+        if let Some(PortEvent::Attached(kind)) = self.check_presence()? {
+            info!("AHCI port {}: {:?} attached", self.index, kind);
+        }
+        Ok(())
+    }
+
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// The current presence state, read from the port.
+    pub fn presence(&self) -> Presence {
+        Presence::from_regs(self.read_reg(SSTS), self.read_reg(CMD))
+    }
+
+    /// The kind of the attached device, if any.
+    pub fn kind(&self) -> Option<DeviceKind> {
+        self.kind
+    }
+
+    /// Resets the link (COMRESET) and waits for the device to come back.
+    pub fn comreset(&mut self) -> Result<(), AhciError> {
+        self.stop()?;
+        let sctl = self.read_reg(SCTL);
+        self.write_reg(SCTL, *sctl.clone().set_bits(0..4, 1));
+        // COMRESET has to be asserted for at least 1 ms
+        poll_until(|| false, Duration::from_millis(1));
+        self.write_reg(SCTL, *sctl.clone().set_bits(0..4, 0));
+        let linked = poll_until(
+            || self.read_reg(SSTS).get_bits(0..4) == DET_PRESENT,
+            RESET_TIMEOUT,
+        );
+        self.write_reg(SERR, u32::MAX);
+        if !linked {
+            return Err(AhciError::NoDevice);
+        }
+        self.presence = Presence::Present;
+        self.identify_device()?;
+        Ok(())
+    }
+
+    /// Waits for the device's signature and starts the command engine.
+    fn identify_device(&mut self) -> Result<DeviceKind, AhciError> {
+        let ready = poll_until(|| !self.device_busy(), RESET_TIMEOUT);
+        if !ready {
+            return Err(AhciError::Timeout);
+        }
+        let kind = DeviceKind::from(self.read_reg(SIG));
+        self.kind = Some(kind);
+        self.start()?;
+        Ok(kind)
+    }
+
+    fn device_busy(&self) -> bool {
+        let status = self.read_reg(TFD);
+        status.get_bit(STS_BSY) || status.get_bit(STS_DRQ)
+    }
+
+    /// Starts the command engine.
+    pub fn start(&mut self) -> Result<(), AhciError> {
+        if !poll_until(|| !self.read_reg(CMD).get_bit(CMD_CR), ENGINE_TIMEOUT) {
+            return Err(AhciError::Timeout);
+        }
+        self.update_reg(CMD, CMD_FRE, true);
+        self.update_reg(CMD, CMD_ST, true);
+        Ok(())
+    }
+
+    /// Stops the command engine (FIS reception keeps running).
+    pub fn stop(&mut self) -> Result<(), AhciError> {
+        self.update_reg(CMD, CMD_ST, false);
+        if !poll_until(|| !self.read_reg(CMD).get_bit(CMD_CR), ENGINE_TIMEOUT) {
+            return Err(AhciError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Stops the command engine and FIS reception, e.g., before the port's
+    /// memory is freed.
+    pub fn shutdown(&mut self) -> Result<(), AhciError> {
+        self.stop()?;
+        self.update_reg(CMD, CMD_FRE, false);
+        if !poll_until(|| !self.read_reg(CMD).get_bit(CMD_FR), ENGINE_TIMEOUT) {
+            return Err(AhciError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Compares the port's presence state to the last known one and reports
+    /// the change, if any.
+    fn check_presence(&mut self) -> Result<Option<PortEvent>, AhciError> {
+        let presence = self.presence();
+        let was_attached = self.kind.is_some();
+        self.presence = presence;
+        match presence {
+            Presence::Present if !was_attached => {
+                Ok(Some(PortEvent::Attached(self.identify_device()?)))
+            }
+            Presence::Present => Ok(None),
+            _ if was_attached => {
+                self.kind = None;
+                self.stop()?;
+                Ok(Some(PortEvent::Detached))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Handles the port's interrupt: acknowledges it and reports hot-plug
+    /// events. Command errors are reported by [`AhciPort::execute`].
+    pub fn handle_interrupt(&mut self) -> Result<Option<PortEvent>, AhciError> {
+        let status = self.read_reg(IS);
+        self.write_reg(IS, status);
+        if !(status.get_bit(IS_PCS) || status.get_bit(IS_PRCS) || status.get_bit(IS_CPDS)) {
+            return Ok(None);
+        }
+        self.poll_hotplug()
+    }
+
+    /// Checks for hot-plug events without relying on interrupts.
+    pub fn poll_hotplug(&mut self) -> Result<Option<PortEvent>, AhciError> {
+        // PxIS.PCS and PxIS.PRCS only clear with their SERR diagnostics
+        let mut diag = 0u32;
+        diag.set_bit(SERR_DIAG_N, true);
+        diag.set_bit(SERR_DIAG_X, true);
+        self.write_reg(SERR, diag);
+        if self.presence() == Presence::ColdPresent && self.presence != Presence::ColdPresent {
+            // A device was plugged into a powered down port
+            self.update_reg(CMD, CMD_POD, true);
+            self.update_reg(CMD, CMD_SUD, true);
+            poll_until(
+                || self.read_reg(SSTS).get_bits(0..4) == DET_PRESENT,
+                SPIN_UP_TIMEOUT,
+            );
+        }
+        let event = self.check_presence()?;
+        if let Some(event) = event {
+            debug!("AHCI port {}: {:?}", self.index, event);
+        }
+        Ok(event)
+    }
+
+    /// Issues `fis` in command slot 0 and waits for its completion. `data` is
+    /// the buffer to transfer and whether it's written to the device.
+    ///
+    /// # Returns
+    /// The device's D2H register FIS.
+    pub fn execute(
+        &mut self,
+        fis: &H2dFis,
+        data: Option<(&mut IOBuf, bool)>,
+    ) -> Result<D2hFis, AhciError> {
+        if self.kind.is_none() {
+            return Err(AhciError::NoDevice);
+        }
+        if !poll_until(|| !self.device_busy(), COMMAND_TIMEOUT) {
+            return Err(AhciError::Timeout);
+        }
+
+        let mut header = 0u32;
+        header.set_bits(0..5, (H2D_FIS_LEN / 4) as u32);
+        header.set_bits(12..16, fis.pmp as u32);
+        let mut prd = [0u8; 16];
+        let mut data = match data {
+            Some((buf, write)) => {
+                if buf.len() > MAX_PRD_BYTES || buf.is_empty() {
+                    return Err(AhciError::TransferTooLarge);
+                }
+                header.set_bit(6, write);
+                header.set_bits(16..32, 1);
+                prd[0..8].copy_from_slice(&buf.ioaddr().as_u64().to_le_bytes());
+                prd[12..16].copy_from_slice(&(buf.len() as u32 - 1).to_le_bytes());
+                Some(buf)
+            }
+            None => None,
+        };
+
+        let table = self.mem_addr(COMMAND_TABLE);
+        let mut slot = [0u8; 16];
+        slot[0..4].copy_from_slice(&header.to_le_bytes());
+        slot[8..16].copy_from_slice(&table.to_le_bytes());
+        let base = self.base;
+        self.mem.copy_in_at(base, &slot)?;
+        self.mem.copy_in_at(base + COMMAND_TABLE, &fis.to_bytes())?;
+        self.mem.copy_in_at(base + COMMAND_TABLE + PRDT, &prd)?;
+        if let Some(buf) = data.as_mut() {
+            buf.give_to_device();
+        }
+        fence(Ordering::SeqCst);
+
+        self.write_reg(IS, u32::MAX);
+        self.write_reg(CI, 1);
+        let done = poll_until(
+            || !self.read_reg(CI).get_bit(0) || self.read_reg(IS).get_bit(IS_TFES),
+            COMMAND_TIMEOUT,
+        );
+        if let Some(buf) = data {
+            buf.take_from_device();
+        }
+        fence(Ordering::SeqCst);
+
+        let tfd = self.read_reg(TFD);
+        if self.read_reg(IS).get_bit(IS_TFES) || tfd.get_bit(STS_ERR) {
+            // The HBA stops processing the command list on task file errors
+            self.write_reg(SERR, u32::MAX);
+            self.write_reg(IS, u32::MAX);
+            self.stop()?;
+            self.start()?;
+            return Err(AhciError::DeviceError {
+                status: tfd.get_bits(0..8) as u8,
+                error: tfd.get_bits(8..16) as u8,
+            });
+        }
+        if !done {
+            return Err(AhciError::Timeout);
+        }
+        let rfis = self.base + FIS_AREA + RFIS;
+        Ok(D2hFis::from_bytes(
+            &self.mem.as_slice()[rfis..rfis + H2D_FIS_LEN],
+        ))
+    }
+
+    /// Reads register `reg` of the port multiplier (`port` 15 for its
+    /// general registers, a fan-out port for that port's registers).
+    pub fn read_pm_register(&mut self, port: u8, reg: u8) -> Result<u32, AhciError> {
+        if self.kind != Some(DeviceKind::PortMultiplier) {
+            return Err(AhciError::NotPortMultiplier);
+        }
+        let fis = H2dFis {
+            pmp: PMP_CONTROL,
+            command: ATA_READ_PM,
+            features: reg as u16,
+            device: port,
+            ..Default::default()
+        };
+        Ok(self.execute(&fis, None)?.pm_value())
+    }
+
+    /// Enumerates the fan-out ports of an attached port multiplier.
+    ///
+    /// # Returns
+    /// The fan-out ports with an established link, commands to the devices
+    /// behind them are addressed with [`H2dFis::pmp`].
+    pub fn enumerate_port_multiplier(&mut self) -> Result<Vec<u8>, AhciError> {
+        if !self.supports_pm || self.kind != Some(DeviceKind::PortMultiplier) {
+            return Err(AhciError::NotPortMultiplier);
+        }
+        // PxCMD.PMA may only change while the command engine is stopped
+        self.stop()?;
+        self.update_reg(CMD, CMD_PMA, true);
+        self.start()?;
+
+        let ports = self
+            .read_pm_register(PMP_CONTROL, GSCR_PORT_INFO)?
+            .get_bits(0..4) as u8;
+        let mut present = Vec::new();
+        for port in 0..ports {
+            let sstatus = self.read_pm_register(port, PSCR_SSTATUS)?;
+            if sstatus.get_bits(0..4) == DET_PRESENT {
+                present.push(port);
+            }
+        }
+        debug!(
+            "AHCI port {}: port multiplier with {} ports, {} devices",
+            self.index,
+            ports,
+            present.len()
+        );
+        Ok(present)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presence_and_fis() {
+        let mut cmd = 0u32;
+        assert_eq!(Presence::from_regs(0x0, cmd), Presence::None);
+        cmd.set_bit(CMD_CPD, true);
+        cmd.set_bit(CMD_CPS, true);
+        assert_eq!(Presence::from_regs(0x0, cmd), Presence::ColdPresent);
+        assert_eq!(Presence::from_regs(0x1, cmd), Presence::NoPhy);
+        assert_eq!(Presence::from_regs(0x123, cmd), Presence::Present);
+        assert_eq!(Presence::from_regs(0x4, 0), Presence::Offline);
+        assert_eq!(port_offset(2), 0x200);
+
+        assert_eq!(DeviceKind::from(0x9669_0101), DeviceKind::PortMultiplier);
+        assert_eq!(DeviceKind::from(0x0000_0101), DeviceKind::Ata);
+
+        let fis = H2dFis {
+            pmp: PMP_CONTROL,
+            command: ATA_READ_PM,
+            features: GSCR_PORT_INFO as u16,
+            lba: 0x0605_0403_0201,
+            device: 3,
+            count: 0x0102,
+        };
+        let bytes = fis.to_bytes();
+        assert_eq!(bytes[0..4], [0x27, 0x8f, 0xe4, 0x02]);
+        assert_eq!(bytes[4..8], [0x01, 0x02, 0x03, 0x03]);
+        assert_eq!(bytes[8..14], [0x04, 0x05, 0x06, 0x00, 0x02, 0x01]);
+
+        let mut rfis = [0u8; H2D_FIS_LEN];
+        rfis[0] = 0x34;
+        rfis[2] = 0x50;
+        rfis[4..7].copy_from_slice(&[0x12, 0x34, 0x56]);
+        rfis[12] = 0x05;
+        let d2h = D2hFis::from_bytes(&rfis);
+        assert_eq!(d2h.status, 0x50);
+        assert_eq!(d2h.pm_value(), 0x5634_1205);
+    }
+}
@@ -0,0 +1,429 @@
+//! GICv3 LPI (locality-specific peripheral interrupt) configuration.
+//!
+//! MSIs on GICv3 systems are LPIs: message based interrupts (INTID 8192 and
+//! up), translated from device writes by the ITS. Their configuration
+//! (enable bit and priority) is kept in a property table in memory, shared
+//! by all redistributors, and their pending state in a pending table per
+//! redistributor. Both have to be set up before a redistributor accepts
+//! LPIs. See the GICv3 architecture specification (ARM IHI 0069), 5.1.
+
+use core::ops::Range;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use super::{dma_sync_for_device, PAddr, VAddr};
+use crate::poll::poll_until;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub GicError
+    NoLpiSupport = "the GIC doesn't support LPIs",
+    InvalidIdBits{bits: u32} = "{bits} interrupt ID bits don't cover any LPI",
+    UnalignedTable{paddr: u64} = "table at {paddr:#x} isn't aligned",
+    InvalidLpi{intid: u32} = "INTID {intid} isn't in the LPI table",
+    LpisAlreadyEnabled = "LPIs are already enabled on the redistributor",
+    Timeout = "the redistributor didn't complete the operation",
+}
+
+/// The first LPI INTID.
+pub const FIRST_LPI: u32 = 8192;
+
+/// Priority given to LPIs until they are configured (lower is higher).
+pub const LPI_DEFAULT_PRIORITY: u8 = 0xa0;
+
+/// Distributor type register and fields.
+const GICD_TYPER: usize = 0x0004;
+const GICD_TYPER_LPIS: usize = 17;
+const GICD_TYPER_IDBITS: Range<usize> = 19..24;
+
+/// Redistributor registers (RD_base frame).
+const GICR_CTLR: usize = 0x0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_PROPBASER: usize = 0x0070;
+const GICR_PENDBASER: usize = 0x0078;
+const GICR_INVLPIR: usize = 0x00a0;
+const GICR_SYNCR: usize = 0x00c0;
+
+/// GICR_CTLR bits.
+const GICR_CTLR_ENABLE_LPIS: usize = 0;
+const GICR_CTLR_RWP: usize = 3;
+
+/// GICR_TYPER fields.
+const GICR_TYPER_PLPIS: usize = 0;
+const GICR_TYPER_VLPIS: usize = 1;
+const GICR_TYPER_DIRECT_LPI: usize = 3;
+const GICR_TYPER_LAST: usize = 4;
+const GICR_TYPER_PROCESSOR_NUMBER: Range<usize> = 8..24;
+const GICR_TYPER_AFFINITY: Range<usize> = 32..64;
+
+/// GICR_PROPBASER/GICR_PENDBASER fields.
+const BASER_ID_BITS: Range<usize> = 0..5;
+const BASER_INNER_CACHE: Range<usize> = 7..10;
+const BASER_SHAREABILITY: Range<usize> = 10..12;
+const PROPBASER_PA: Range<usize> = 12..52;
+const PENDBASER_PA: Range<usize> = 16..52;
+const PENDBASER_PTZ: usize = 62;
+
+/// Cacheability: Normal, inner write-back read/write-allocate.
+const CACHE_WB: u64 = 0b111;
+/// Cacheability: Normal, inner non-cacheable.
+const CACHE_NC: u64 = 0b001;
+/// Shareability: non-shareable and inner shareable.
+const SHARE_NONE: u64 = 0b00;
+const SHARE_INNER: u64 = 0b01;
+
+/// LPI property table entry bits.
+const PROP_ENABLE: usize = 0;
+const PROP_PRIORITY: Range<usize> = 2..8;
+
+/// Size of a redistributor's RD_base and SGI_base frames (plus two more
+/// frames with virtual LPIs).
+const GICR_FRAME_SIZE: usize = 0x2_0000;
+const GICR_VLPI_FRAME_SIZE: usize = 0x4_0000;
+
+const PROPERTY_TABLE_ALIGN: u64 = 0x1000;
+const PENDING_TABLE_ALIGN: u64 = 0x1_0000;
+
+/// Time the redistributor may take to complete a register write.
+const TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10);
+
+/// Number of interrupt ID bits of the distributor (GICD_TYPER.IDbits), or
+/// `None` if it doesn't support LPIs.
+///
+/// # Safety
+/// `gicd` must be the mapping of the distributor registers.
+pub unsafe fn lpi_id_bits(gicd: VAddr) -> Option<u32> {
+    let typer = core::ptr::read_volatile((gicd + GICD_TYPER).as_ptr::<u32>());
+    if typer.get_bit(GICD_TYPER_LPIS) {
+        Some(typer.get_bits(GICD_TYPER_IDBITS) + 1)
+    } else {
+        None
+    }
+}
+
+/// GICR_PROPBASER value for a property table at `paddr`.
+fn propbaser(paddr: u64, id_bits: u32, cacheable: bool) -> u64 {
+    let mut value = 0u64;
+    value.set_bits(PROPBASER_PA, paddr >> 12);
+    value.set_bits(BASER_ID_BITS, id_bits as u64 - 1);
+    value.set_bits(
+        BASER_INNER_CACHE,
+        if cacheable { CACHE_WB } else { CACHE_NC },
+    );
+    value.set_bits(
+        BASER_SHAREABILITY,
+        if cacheable { SHARE_INNER } else { SHARE_NONE },
+    );
+    value
+}
+
+/// GICR_PENDBASER value for a (zeroed) pending table at `paddr`.
+fn pendbaser(paddr: u64, cacheable: bool) -> u64 {
+    let mut value = 0u64;
+    value.set_bits(PENDBASER_PA, paddr >> 16);
+    value.set_bits(
+        BASER_INNER_CACHE,
+        if cacheable { CACHE_WB } else { CACHE_NC },
+    );
+    value.set_bits(
+        BASER_SHAREABILITY,
+        if cacheable { SHARE_INNER } else { SHARE_NONE },
+    );
+    value.set_bit(PENDBASER_PTZ, true);
+    value
+}
+
+/// The LPI property table (a byte per LPI).
+pub struct LpiPropertyTable {
+    vaddr: VAddr,
+    paddr: PAddr,
+    id_bits: u32,
+}
+
+impl LpiPropertyTable {
+    /// Size in bytes of a table for `id_bits` interrupt ID bits.
+    pub fn size(id_bits: u32) -> usize {
+        (1usize << id_bits) - FIRST_LPI as usize
+    }
+
+    /// Initializes the table at `vaddr`/`paddr`, all LPIs start disabled
+    /// with [`LPI_DEFAULT_PRIORITY`].
+    ///
+    /// # Safety
+    /// `vaddr` must map [`LpiPropertyTable::size`] bytes of physical memory
+    /// at `paddr` that aren't used otherwise (4 KiB aligned).
+    pub unsafe fn new(
+        vaddr: VAddr,
+        paddr: PAddr,
+        id_bits: u32,
+    ) -> Result<LpiPropertyTable, GicError> {
+        if !(15..=32).contains(&id_bits) {
+            return Err(GicError::InvalidIdBits { bits: id_bits });
+        }
+        if !paddr.as_u64().is_multiple_of(PROPERTY_TABLE_ALIGN) {
+            return Err(GicError::UnalignedTable {
+                paddr: paddr.as_u64(),
+            });
+        }
+        let size = LpiPropertyTable::size(id_bits);
+        let mut entry = 0u8;
+        entry.set_bits(PROP_PRIORITY, LPI_DEFAULT_PRIORITY >> 2);
+        core::ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), entry, size);
+        dma_sync_for_device(vaddr, size);
+        Ok(LpiPropertyTable {
+            vaddr,
+            paddr,
+            id_bits,
+        })
+    }
+
+    pub fn id_bits(&self) -> u32 {
+        self.id_bits
+    }
+
+    pub fn paddr(&self) -> PAddr {
+        self.paddr
+    }
+
+    /// The LPI INTIDs covered by the table.
+    pub fn lpis(&self) -> Range<u32> {
+        FIRST_LPI..(1u64 << self.id_bits).min(u32::MAX as u64) as u32
+    }
+
+    fn entry(&self, intid: u32) -> Result<VAddr, GicError> {
+        if !self.lpis().contains(&intid) {
+            return Err(GicError::InvalidLpi { intid });
+        }
+        Ok(self.vaddr + (intid - FIRST_LPI) as usize)
+    }
+
+    /// Priority and enable bit of `intid`.
+    pub fn config(&self, intid: u32) -> Result<(u8, bool), GicError> {
+        // Safety: the entry is in the table (see `new`)
+        let entry = unsafe { core::ptr::read_volatile(self.entry(intid)?.as_ptr::<u8>()) };
+        Ok((
+            entry.get_bits(PROP_PRIORITY) << 2,
+            entry.get_bit(PROP_ENABLE),
+        ))
+    }
+
+    /// Configures `intid` (the low two priority bits are ignored).
+    ///
+    /// The redistributors cache the table, so the change only takes effect
+    /// once the LPI is invalidated (ITS `INV` or
+    /// [`Redistributor::invalidate`]).
+    pub fn set_config(&mut self, intid: u32, priority: u8, enabled: bool) -> Result<(), GicError> {
+        let entry_addr = self.entry(intid)?;
+        let mut entry = 0u8;
+        entry.set_bits(PROP_PRIORITY, priority >> 2);
+        entry.set_bit(PROP_ENABLE, enabled);
+        // Safety: the entry is in the table (see `new`)
+        unsafe { core::ptr::write_volatile(entry_addr.as_mut_ptr::<u8>(), entry) };
+        dma_sync_for_device(entry_addr, 1);
+        Ok(())
+    }
+
+    pub fn set_enabled(&mut self, intid: u32, enabled: bool) -> Result<(), GicError> {
+        let (priority, _) = self.config(intid)?;
+        self.set_config(intid, priority, enabled)
+    }
+}
+
+/// The LPI pending table of a redistributor (a bit per INTID).
+pub struct LpiPendingTable {
+    paddr: PAddr,
+}
+
+impl LpiPendingTable {
+    /// Size in bytes of a table for `id_bits` interrupt ID bits.
+    pub fn size(id_bits: u32) -> usize {
+        (1usize << id_bits) / 8
+    }
+
+    /// Zeroes the table at `vaddr`/`paddr`.
+    ///
+    /// # Safety
+    /// `vaddr` must map [`LpiPendingTable::size`] bytes of physical memory
+    /// at `paddr` that aren't used otherwise (64 KiB aligned).
+    pub unsafe fn new(
+        vaddr: VAddr,
+        paddr: PAddr,
+        id_bits: u32,
+    ) -> Result<LpiPendingTable, GicError> {
+        if !paddr.as_u64().is_multiple_of(PENDING_TABLE_ALIGN) {
+            return Err(GicError::UnalignedTable {
+                paddr: paddr.as_u64(),
+            });
+        }
+        let size = LpiPendingTable::size(id_bits);
+        core::ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), 0, size);
+        dma_sync_for_device(vaddr, size);
+        Ok(LpiPendingTable { paddr })
+    }
+
+    pub fn paddr(&self) -> PAddr {
+        self.paddr
+    }
+}
+
+/// A GICv3 redistributor (the RD_base frame).
+pub struct Redistributor {
+    base: VAddr,
+}
+
+impl Redistributor {
+    /// # Safety
+    /// `base` must be the mapping of the RD_base frame of a redistributor.
+    pub unsafe fn new(base: VAddr) -> Redistributor {
+        Redistributor { base }
+    }
+
+    /// Finds the redistributor of the core with `affinity` (Aff3.Aff2.Aff1.
+    /// Aff0 as in MPIDR_EL1) in the redistributor region at `gicr`.
+    ///
+    /// # Safety
+    /// `gicr` must be the mapping of a (complete) redistributor region.
+    pub unsafe fn find(gicr: VAddr, affinity: u32) -> Option<Redistributor> {
+        let mut frame = gicr;
+        loop {
+            let redistributor = Redistributor::new(frame);
+            let typer = redistributor.typer();
+            if typer.get_bits(GICR_TYPER_AFFINITY) as u32 == affinity {
+                return Some(redistributor);
+            }
+            if typer.get_bit(GICR_TYPER_LAST) {
+                return None;
+            }
+            frame += if typer.get_bit(GICR_TYPER_VLPIS) {
+                GICR_VLPI_FRAME_SIZE
+            } else {
+                GICR_FRAME_SIZE
+            };
+        }
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        // Safety: `base` points to the RD_base frame (see `new`)
+        unsafe { core::ptr::read_volatile((self.base + offset).as_ptr::<u32>()) }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        // Safety: `base` points to the RD_base frame (see `new`)
+        unsafe { core::ptr::write_volatile((self.base + offset).as_mut_ptr::<u32>(), value) };
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        // Safety: `base` points to the RD_base frame (see `new`)
+        unsafe { core::ptr::read_volatile((self.base + offset).as_ptr::<u64>()) }
+    }
+
+    fn write64(&mut self, offset: usize, value: u64) {
+        // Safety: `base` points to the RD_base frame (see `new`)
+        unsafe { core::ptr::write_volatile((self.base + offset).as_mut_ptr::<u64>(), value) };
+    }
+
+    fn typer(&self) -> u64 {
+        self.read64(GICR_TYPER)
+    }
+
+    /// Affinity (Aff3.Aff2.Aff1.Aff0) of the core it belongs to.
+    pub fn affinity(&self) -> u32 {
+        self.typer().get_bits(GICR_TYPER_AFFINITY) as u32
+    }
+
+    /// The processor number, used as target by ITSs that don't use physical
+    /// addresses (GITS_TYPER.PTA clear).
+    pub fn processor_number(&self) -> u16 {
+        self.typer().get_bits(GICR_TYPER_PROCESSOR_NUMBER) as u16
+    }
+
+    pub fn supports_lpis(&self) -> bool {
+        self.typer().get_bit(GICR_TYPER_PLPIS)
+    }
+
+    /// Whether LPIs can be invalidated without an ITS.
+    pub fn supports_direct_lpi(&self) -> bool {
+        self.typer().get_bit(GICR_TYPER_DIRECT_LPI)
+    }
+
+    pub fn lpis_enabled(&self) -> bool {
+        self.read32(GICR_CTLR).get_bit(GICR_CTLR_ENABLE_LPIS)
+    }
+
+    /// Programs the LPI tables and enables LPIs.
+    ///
+    /// LPIs can't be disabled again on most implementations, so this works
+    /// only once per redistributor.
+    pub fn enable_lpis(
+        &mut self,
+        properties: &LpiPropertyTable,
+        pending: &LpiPendingTable,
+    ) -> Result<(), GicError> {
+        if !self.supports_lpis() {
+            return Err(GicError::NoLpiSupport);
+        }
+        if self.lpis_enabled() {
+            return Err(GicError::LpisAlreadyEnabled);
+        }
+
+        let id_bits = properties.id_bits();
+        self.write64(
+            GICR_PROPBASER,
+            propbaser(properties.paddr().as_u64(), id_bits, true),
+        );
+        if self.read64(GICR_PROPBASER).get_bits(BASER_SHAREABILITY) == SHARE_NONE {
+            // The GIC isn't coherent with the caches, the tables are
+            // cleaned to memory after every update
+            debug!("gic: redistributor isn't cache coherent");
+            self.write64(
+                GICR_PROPBASER,
+                propbaser(properties.paddr().as_u64(), id_bits, false),
+            );
+        }
+        self.write64(GICR_PENDBASER, pendbaser(pending.paddr().as_u64(), true));
+        if self.read64(GICR_PENDBASER).get_bits(BASER_SHAREABILITY) == SHARE_NONE {
+            self.write64(GICR_PENDBASER, pendbaser(pending.paddr().as_u64(), false));
+        }
+
+        let mut ctlr = self.read32(GICR_CTLR);
+        ctlr.set_bit(GICR_CTLR_ENABLE_LPIS, true);
+        self.write32(GICR_CTLR, ctlr);
+        self.wait_for_rwp()?;
+        debug!("gic: LPIs enabled on redistributor {:#x}", self.affinity());
+        Ok(())
+    }
+
+    fn wait_for_rwp(&self) -> Result<(), GicError> {
+        let done = poll_until(|| !self.read32(GICR_CTLR).get_bit(GICR_CTLR_RWP), TIMEOUT);
+        done.then_some(()).ok_or(GicError::Timeout)
+    }
+
+    /// Makes the redistributor reload the configuration of `intid` (see
+    /// [`Redistributor::supports_direct_lpi`], use the ITS `INV` command
+    /// otherwise).
+    pub fn invalidate(&mut self, intid: u32) -> Result<(), GicError> {
+        self.write64(GICR_INVLPIR, intid as u64);
+        let done = poll_until(|| !self.read32(GICR_SYNCR).get_bit(0), TIMEOUT);
+        done.then_some(()).ok_or(GicError::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baser_encoding() {
+        assert_eq!(
+            propbaser(0x8_0000_1000, 16, true),
+            0x8_0000_1000 | 0b01 << 10 | 0b111 << 7 | 15
+        );
+        assert_eq!(
+            pendbaser(0x4_0001_0000, false),
+            1 << 62 | 0x4_0001_0000 | 0b001 << 7
+        );
+        assert_eq!(LpiPropertyTable::size(16), 65536 - 8192);
+        assert_eq!(LpiPendingTable::size(16), 8192);
+    }
+}
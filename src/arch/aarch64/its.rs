@@ -0,0 +1,540 @@
+//! The GICv3 Interrupt Translation Service (ITS).
+//!
+//! A device raises an MSI by writing its EventID to GITS_TRANSLATER, the
+//! ITS looks up the DeviceID (the PCI requester ID) in its device table,
+//! translates the EventID through the device's interrupt translation table
+//! (ITT) to an LPI and a collection, and forwards the LPI to the
+//! redistributor the collection is mapped to. The tables are maintained
+//! with commands in a ring in memory. See the GICv3 architecture
+//! specification (ARM IHI 0069), 5.2 and 5.3.
+
+use core::ops::Range;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use super::gic::Redistributor;
+use super::{dma_sync_for_cpu, dma_sync_for_device, PAddr, VAddr};
+use crate::pci::PCIAddress;
+use crate::poll::poll_until;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub ItsError
+    UnalignedTable{paddr: u64} = "table at {paddr:#x} isn't aligned",
+    NoTable = "the ITS doesn't have a table of that type",
+    TableTooLarge{size: usize} = "a table of {size} bytes is too large",
+    NoCommandQueue = "the command queue isn't set up",
+    CommandQueueFull = "the command queue is full",
+    Stalled = "the ITS stalled on a command error",
+    Timeout = "the ITS didn't process the commands in time",
+}
+
+/// Registers (ITS_base frame).
+const GITS_CTLR: usize = 0x0000;
+const GITS_TYPER: usize = 0x0008;
+const GITS_CBASER: usize = 0x0080;
+const GITS_CWRITER: usize = 0x0088;
+const GITS_CREADR: usize = 0x0090;
+const GITS_BASER: usize = 0x0100;
+const GITS_BASER_COUNT: usize = 8;
+/// The doorbell devices write to, in the translation register frame.
+const GITS_TRANSLATER: u64 = 0x1_0040;
+
+/// GITS_CTLR bits.
+const GITS_CTLR_ENABLED: usize = 0;
+const GITS_CTLR_QUIESCENT: usize = 31;
+
+/// GITS_TYPER fields.
+const GITS_TYPER_ITT_ENTRY_SIZE: Range<usize> = 4..8;
+const GITS_TYPER_ID_BITS: Range<usize> = 8..13;
+const GITS_TYPER_DEV_BITS: Range<usize> = 13..18;
+const GITS_TYPER_PTA: usize = 19;
+
+/// GITS_CBASER and GITS_BASER<n> fields.
+const BASER_SIZE: Range<usize> = 0..8;
+const BASER_PAGE_SIZE: Range<usize> = 8..10;
+const BASER_SHAREABILITY: Range<usize> = 10..12;
+const BASER_PA: Range<usize> = 12..48;
+const BASER_ENTRY_SIZE: Range<usize> = 48..53;
+const BASER_TYPE: Range<usize> = 56..59;
+const BASER_INNER_CACHE: Range<usize> = 59..62;
+const BASER_VALID: usize = 63;
+
+/// Normal, inner write-back read/write-allocate, inner shareable.
+const CACHE_WB: u64 = 0b111;
+const SHARE_INNER: u64 = 0b01;
+
+/// GITS_CREADR bits.
+const GITS_CREADR_STALLED: usize = 0;
+const GITS_CWRITER_OFFSET: Range<usize> = 5..20;
+
+const PAGE_SIZE: usize = 0x1000;
+const COMMAND_SIZE: usize = 32;
+/// ITTs must be 256 byte aligned.
+const ITT_ALIGN: u64 = 0x100;
+
+/// Time the ITS may take to process the queued commands.
+const TIMEOUT: core::time::Duration = core::time::Duration::from_millis(100);
+
+/// Returns the DeviceID of a PCI function (its requester ID), assuming the
+/// usual identity mapping of requester IDs to DeviceIDs of the root
+/// complex.
+pub fn device_id(addr: PCIAddress) -> u32 {
+    (addr.bus as u32) << 8 | (addr.dev as u32) << 3 | addr.fun as u32
+}
+
+/// Tables the ITS keeps in memory (GITS_BASER<n>.Type).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ItsTableType {
+    Devices = 1,
+    VirtualPes = 2,
+    Collections = 4,
+}
+
+/// The target of a collection: the physical address of the
+/// redistributor's RD_base frame or its processor number, depending on
+/// [`Its::targets_physical_address`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RdBase(u64);
+
+/// An ITS command.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ItsCommand {
+    /// Maps (or with `itt` `None` unmaps) a device to its ITT, which holds
+    /// `1 << event_bits` events.
+    Mapd {
+        device_id: u32,
+        itt: Option<u64>,
+        event_bits: u8,
+    },
+    /// Maps (or with `target` `None` unmaps) a collection to a
+    /// redistributor.
+    Mapc { icid: u16, target: Option<RdBase> },
+    /// Maps an event of a device to an LPI in a collection.
+    Mapti {
+        device_id: u32,
+        event_id: u32,
+        lpi: u32,
+        icid: u16,
+    },
+    /// Reloads the configuration of the LPI an event is mapped to.
+    Inv { device_id: u32, event_id: u32 },
+    /// Reloads the configuration of all LPIs of a collection.
+    Invall { icid: u16 },
+    /// Unmaps an event (and clears its LPI's pending state).
+    Discard { device_id: u32, event_id: u32 },
+    /// Waits until the effects of the previous commands on a redistributor
+    /// are visible.
+    Sync { target: RdBase },
+}
+
+impl ItsCommand {
+    const MAPD: u64 = 0x08;
+    const MAPC: u64 = 0x09;
+    const MAPTI: u64 = 0x0a;
+    const INV: u64 = 0x0c;
+    const INVALL: u64 = 0x0d;
+    const DISCARD: u64 = 0x0f;
+    const SYNC: u64 = 0x05;
+
+    /// The command as it is written to the queue.
+    pub fn encode(&self) -> [u64; 4] {
+        let mut cmd = [0u64; 4];
+        let device = |cmd: &mut [u64; 4], opcode: u64, device_id: u32| {
+            cmd[0].set_bits(0..8, opcode);
+            cmd[0].set_bits(32..64, device_id as u64);
+        };
+        match *self {
+            ItsCommand::Mapd {
+                device_id,
+                itt,
+                event_bits,
+            } => {
+                device(&mut cmd, ItsCommand::MAPD, device_id);
+                cmd[1].set_bits(0..5, event_bits.max(1) as u64 - 1);
+                if let Some(itt) = itt {
+                    cmd[2].set_bits(8..52, itt >> 8);
+                    cmd[2].set_bit(63, true);
+                }
+            }
+            ItsCommand::Mapc { icid, target } => {
+                cmd[0].set_bits(0..8, ItsCommand::MAPC);
+                cmd[2].set_bits(0..16, icid as u64);
+                if let Some(RdBase(rdbase)) = target {
+                    cmd[2].set_bits(16..52, rdbase);
+                    cmd[2].set_bit(63, true);
+                }
+            }
+            ItsCommand::Mapti {
+                device_id,
+                event_id,
+                lpi,
+                icid,
+            } => {
+                device(&mut cmd, ItsCommand::MAPTI, device_id);
+                cmd[1].set_bits(0..32, event_id as u64);
+                cmd[1].set_bits(32..64, lpi as u64);
+                cmd[2].set_bits(0..16, icid as u64);
+            }
+            ItsCommand::Inv {
+                device_id,
+                event_id,
+            } => {
+                device(&mut cmd, ItsCommand::INV, device_id);
+                cmd[1].set_bits(0..32, event_id as u64);
+            }
+            ItsCommand::Invall { icid } => {
+                cmd[0].set_bits(0..8, ItsCommand::INVALL);
+                cmd[2].set_bits(0..16, icid as u64);
+            }
+            ItsCommand::Discard {
+                device_id,
+                event_id,
+            } => {
+                device(&mut cmd, ItsCommand::DISCARD, device_id);
+                cmd[1].set_bits(0..32, event_id as u64);
+            }
+            ItsCommand::Sync {
+                target: RdBase(rdbase),
+            } => {
+                cmd[0].set_bits(0..8, ItsCommand::SYNC);
+                cmd[2].set_bits(16..52, rdbase);
+            }
+        }
+        cmd
+    }
+}
+
+/// The command queue.
+struct CommandQueue {
+    vaddr: VAddr,
+    size: usize,
+    /// Offset of the next command.
+    write: usize,
+}
+
+/// An ITS.
+pub struct Its {
+    base: VAddr,
+    paddr: PAddr,
+    queue: Option<CommandQueue>,
+}
+
+impl Its {
+    /// # Safety
+    /// `base` must be the mapping of the ITS_base frame of the ITS whose
+    /// registers are at `paddr`.
+    pub unsafe fn new(base: VAddr, paddr: PAddr) -> Its {
+        Its {
+            base,
+            paddr,
+            queue: None,
+        }
+    }
+
+    fn read(&self, offset: usize) -> u64 {
+        // Safety: `base` points to the ITS registers (see `new`)
+        unsafe { core::ptr::read_volatile((self.base + offset).as_ptr::<u64>()) }
+    }
+
+    fn write(&mut self, offset: usize, value: u64) {
+        // Safety: `base` points to the ITS registers (see `new`)
+        unsafe { core::ptr::write_volatile((self.base + offset).as_mut_ptr::<u64>(), value) };
+    }
+
+    /// Bytes per ITT entry.
+    pub fn itt_entry_size(&self) -> usize {
+        self.read(GITS_TYPER).get_bits(GITS_TYPER_ITT_ENTRY_SIZE) as usize + 1
+    }
+
+    /// Number of EventID bits.
+    pub fn event_bits(&self) -> u8 {
+        self.read(GITS_TYPER).get_bits(GITS_TYPER_ID_BITS) as u8 + 1
+    }
+
+    /// Number of DeviceID bits.
+    pub fn device_bits(&self) -> u8 {
+        self.read(GITS_TYPER).get_bits(GITS_TYPER_DEV_BITS) as u8 + 1
+    }
+
+    /// Whether collections target redistributors by physical address
+    /// (GITS_TYPER.PTA) rather than by processor number.
+    pub fn targets_physical_address(&self) -> bool {
+        self.read(GITS_TYPER).get_bit(GITS_TYPER_PTA)
+    }
+
+    /// The collection target for `redistributor`, whose RD_base frame is at
+    /// `paddr`.
+    pub fn target(&self, redistributor: &Redistributor, paddr: PAddr) -> RdBase {
+        if self.targets_physical_address() {
+            RdBase(paddr.as_u64() >> 16)
+        } else {
+            RdBase(redistributor.processor_number() as u64)
+        }
+    }
+
+    /// Size in bytes of an ITT for `1 << event_bits` events.
+    pub fn itt_size(&self, event_bits: u8) -> usize {
+        ((1usize << event_bits) * self.itt_entry_size()).max(ITT_ALIGN as usize)
+    }
+
+    /// The MSI message that raises `event_id` of the writing device.
+    pub fn msi_message(&self, event_id: u32) -> (u64, u32) {
+        (self.paddr.as_u64() + GITS_TRANSLATER, event_id)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.read(GITS_CTLR).get_bit(GITS_CTLR_ENABLED)
+    }
+
+    pub fn enable(&mut self) {
+        let mut ctlr = self.read(GITS_CTLR);
+        ctlr.set_bit(GITS_CTLR_ENABLED, true);
+        self.write(GITS_CTLR, ctlr);
+    }
+
+    /// Disables the ITS and waits until it is quiescent.
+    pub fn disable(&mut self) -> Result<(), ItsError> {
+        let mut ctlr = self.read(GITS_CTLR);
+        ctlr.set_bit(GITS_CTLR_ENABLED, false);
+        self.write(GITS_CTLR, ctlr);
+        let done = poll_until(
+            || self.read(GITS_CTLR).get_bit(GITS_CTLR_QUIESCENT),
+            TIMEOUT,
+        );
+        done.then_some(()).ok_or(ItsError::Timeout)
+    }
+
+    /// Points the (flat) table of type `kind` to the `size` bytes at
+    /// `vaddr`/`paddr`, the ITS must be disabled.
+    ///
+    /// # Safety
+    /// `vaddr` must map `size` bytes of physical memory at `paddr` (64 KiB
+    /// aligned) that aren't used otherwise.
+    pub unsafe fn set_table(
+        &mut self,
+        kind: ItsTableType,
+        vaddr: VAddr,
+        paddr: PAddr,
+        size: usize,
+    ) -> Result<(), ItsError> {
+        if !paddr.as_u64().is_multiple_of(0x1_0000) {
+            return Err(ItsError::UnalignedTable {
+                paddr: paddr.as_u64(),
+            });
+        }
+        let pages = size.div_ceil(PAGE_SIZE);
+        if pages == 0 || pages > 256 {
+            return Err(ItsError::TableTooLarge { size });
+        }
+        let offset = (0..GITS_BASER_COUNT)
+            .map(|n| GITS_BASER + n * 8)
+            .find(|&offset| self.read(offset).get_bits(BASER_TYPE) == kind as u64)
+            .ok_or(ItsError::NoTable)?;
+
+        core::ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), 0, pages * PAGE_SIZE);
+        dma_sync_for_device(vaddr, pages * PAGE_SIZE);
+
+        let mut baser = self.read(offset);
+        baser.set_bits(BASER_SIZE, pages as u64 - 1);
+        baser.set_bits(BASER_PAGE_SIZE, 0);
+        baser.set_bits(BASER_PA, paddr.as_u64() >> 12);
+        baser.set_bits(BASER_SHAREABILITY, SHARE_INNER);
+        baser.set_bits(BASER_INNER_CACHE, CACHE_WB);
+        baser.set_bit(BASER_VALID, true);
+        self.write(offset, baser);
+        debug!(
+            "its: {:?} table with {} entries",
+            kind,
+            pages * PAGE_SIZE / (baser.get_bits(BASER_ENTRY_SIZE) as usize + 1)
+        );
+        Ok(())
+    }
+
+    /// Sets up the command queue in the `size` bytes at `vaddr`/`paddr`, the
+    /// ITS must be disabled.
+    ///
+    /// # Safety
+    /// `vaddr` must map `size` bytes of physical memory at `paddr` (64 KiB
+    /// aligned) that aren't used otherwise.
+    pub unsafe fn set_command_queue(
+        &mut self,
+        vaddr: VAddr,
+        paddr: PAddr,
+        size: usize,
+    ) -> Result<(), ItsError> {
+        if !paddr.as_u64().is_multiple_of(0x1_0000) {
+            return Err(ItsError::UnalignedTable {
+                paddr: paddr.as_u64(),
+            });
+        }
+        let pages = size / PAGE_SIZE;
+        if pages == 0 || pages > 256 {
+            return Err(ItsError::TableTooLarge { size });
+        }
+        core::ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), 0, pages * PAGE_SIZE);
+
+        let mut cbaser = 0u64;
+        cbaser.set_bits(BASER_SIZE, pages as u64 - 1);
+        cbaser.set_bits(BASER_PA, paddr.as_u64() >> 12);
+        cbaser.set_bits(BASER_SHAREABILITY, SHARE_INNER);
+        cbaser.set_bits(BASER_INNER_CACHE, CACHE_WB);
+        cbaser.set_bit(BASER_VALID, true);
+        self.write(GITS_CBASER, cbaser);
+        self.write(GITS_CWRITER, 0);
+        self.queue = Some(CommandQueue {
+            vaddr,
+            size: pages * PAGE_SIZE,
+            write: 0,
+        });
+        Ok(())
+    }
+
+    fn read_offset(&self) -> Result<usize, ItsError> {
+        let creadr = self.read(GITS_CREADR);
+        if creadr.get_bit(GITS_CREADR_STALLED) {
+            return Err(ItsError::Stalled);
+        }
+        Ok((creadr.get_bits(GITS_CWRITER_OFFSET) as usize) * COMMAND_SIZE)
+    }
+
+    /// Queues `command` (see [`Its::wait`]).
+    pub fn submit(&mut self, command: ItsCommand) -> Result<(), ItsError> {
+        let read = self.read_offset()?;
+        let queue = self.queue.as_mut().ok_or(ItsError::NoCommandQueue)?;
+        let next = (queue.write + COMMAND_SIZE) % queue.size;
+        if next == read {
+            return Err(ItsError::CommandQueueFull);
+        }
+
+        let slot = queue.vaddr + queue.write;
+        for (i, dword) in command.encode().iter().enumerate() {
+            // Safety: the slot is in the queue (see `set_command_queue`)
+            unsafe { core::ptr::write_volatile((slot + i * 8).as_mut_ptr::<u64>(), dword.to_le()) };
+        }
+        dma_sync_for_device(slot, COMMAND_SIZE);
+        queue.write = next;
+        self.write(GITS_CWRITER, next as u64);
+        Ok(())
+    }
+
+    /// Waits until the ITS processed all queued commands.
+    pub fn wait(&mut self) -> Result<(), ItsError> {
+        let write = self.queue.as_ref().ok_or(ItsError::NoCommandQueue)?.write;
+        let mut result = Ok(());
+        let done = poll_until(
+            || match self.read_offset() {
+                Ok(read) => read == write,
+                Err(e) => {
+                    result = Err(e);
+                    true
+                }
+            },
+            TIMEOUT,
+        );
+        if let Some(queue) = self.queue.as_ref() {
+            dma_sync_for_cpu(queue.vaddr, queue.size);
+        }
+        result?;
+        done.then_some(()).ok_or(ItsError::Timeout)
+    }
+
+    /// Maps collection `icid` to `target` and waits for it.
+    pub fn map_collection(&mut self, icid: u16, target: RdBase) -> Result<(), ItsError> {
+        self.submit(ItsCommand::Mapc {
+            icid,
+            target: Some(target),
+        })?;
+        self.submit(ItsCommand::Sync { target })?;
+        self.wait()
+    }
+
+    /// Maps the PCI device at `addr` to the ITT at `itt` (see
+    /// [`Its::itt_size`]) for `1 << event_bits` events, returns its
+    /// DeviceID.
+    pub fn map_device(
+        &mut self,
+        addr: PCIAddress,
+        itt: PAddr,
+        event_bits: u8,
+    ) -> Result<u32, ItsError> {
+        if !itt.as_u64().is_multiple_of(ITT_ALIGN) {
+            return Err(ItsError::UnalignedTable {
+                paddr: itt.as_u64(),
+            });
+        }
+        let device_id = device_id(addr);
+        self.submit(ItsCommand::Mapd {
+            device_id,
+            itt: Some(itt.as_u64()),
+            event_bits,
+        })?;
+        self.wait()?;
+        Ok(device_id)
+    }
+
+    /// Maps `event_id` of a device to `lpi` in collection `icid` (on
+    /// `target`), makes the GIC load the LPI's configuration and waits for
+    /// it.
+    pub fn map_event(
+        &mut self,
+        device_id: u32,
+        event_id: u32,
+        lpi: u32,
+        icid: u16,
+        target: RdBase,
+    ) -> Result<(), ItsError> {
+        self.submit(ItsCommand::Mapti {
+            device_id,
+            event_id,
+            lpi,
+            icid,
+        })?;
+        self.submit(ItsCommand::Inv {
+            device_id,
+            event_id,
+        })?;
+        self.submit(ItsCommand::Sync { target })?;
+        self.wait()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_encoding() {
+        let addr = PCIAddress {
+            bus: 0x3,
+            dev: 0x1f,
+            fun: 0x7,
+        };
+        assert_eq!(device_id(addr), 0x3ff);
+
+        let mapd = ItsCommand::Mapd {
+            device_id: 0x3ff,
+            itt: Some(0x8_1234_5600),
+            event_bits: 5,
+        };
+        assert_eq!(
+            mapd.encode(),
+            [0x3ff_0000_0008, 4, 1 << 63 | 0x8_1234_5600, 0]
+        );
+        let mapti = ItsCommand::Mapti {
+            device_id: 1,
+            event_id: 2,
+            lpi: 8192,
+            icid: 3,
+        };
+        assert_eq!(mapti.encode(), [1 << 32 | 0x0a, 8192 << 32 | 2, 3, 0]);
+        let sync = ItsCommand::Sync {
+            target: RdBase(0x8_0a00_0000 >> 16),
+        };
+        assert_eq!(sync.encode(), [0x05, 0, 0x8_0a00_0000, 0]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Memory attributes (MAIR_EL1) for device mappings.
+//!
+//! Page table entries select one of the eight attributes in MAIR_EL1 by
+//! index. BARs have to be mapped with a Device attribute, or Normal
+//! non-cacheable for write-combining on prefetchable BARs. Device-nGnRE,
+//! which Linux uses for `ioremap`, allows early write acknowledgement
+//! (posted writes), Device-nGnRnE doesn't. See the Arm ARM, B2.7.
+
+use custom_error::custom_error;
+
+use bit_field::BitField;
+
+use crate::iomem::{MapOptions, MemoryType};
+use crate::pci::{Bar, BarType};
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub MairError
+    Cacheable = "cacheable memory can't be used for device mappings",
+    NotPrefetchable = "Normal memory is only allowed for prefetchable BARs",
+    NotMemoryBar = "IO BARs can't be mapped",
+    NotInMair{attribute: u8} = "attribute {attribute:#x} isn't in MAIR_EL1",
+}
+
+/// Stage 1 page/block descriptor fields.
+const DESC_ATTR_INDEX: core::ops::Range<usize> = 2..5;
+const DESC_AP_READ_ONLY: usize = 7;
+const DESC_PXN: usize = 53;
+const DESC_UXN: usize = 54;
+
+/// The MAIR attributes used for BARs (and normal memory).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MairAttribute {
+    DeviceNGnRnE = 0x00,
+    DeviceNGnRE = 0x04,
+    DeviceGRE = 0x0c,
+    /// Normal, inner and outer non-cacheable.
+    NormalNc = 0x44,
+    /// Normal, inner and outer write-back read/write-allocate.
+    NormalWb = 0xff,
+}
+
+impl MairAttribute {
+    /// The attribute that implements `memory_type`.
+    pub fn for_memory_type(memory_type: MemoryType) -> MairAttribute {
+        match memory_type {
+            MemoryType::Strict => MairAttribute::DeviceNGnRnE,
+            MemoryType::Device => MairAttribute::DeviceNGnRE,
+            MemoryType::WriteCombining => MairAttribute::NormalNc,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<MairAttribute> {
+        match byte {
+            0x00 => Some(MairAttribute::DeviceNGnRnE),
+            0x04 => Some(MairAttribute::DeviceNGnRE),
+            0x0c => Some(MairAttribute::DeviceGRE),
+            0x44 => Some(MairAttribute::NormalNc),
+            0xff => Some(MairAttribute::NormalWb),
+            _ => None,
+        }
+    }
+
+    pub fn is_device(self) -> bool {
+        (self as u8) & 0xf0 == 0
+    }
+
+    /// Whether writes may be acknowledged before they reach the device.
+    pub fn posted_writes(self) -> bool {
+        self != MairAttribute::DeviceNGnRnE
+    }
+
+    /// Checks that mapping `bar` with this attribute is valid.
+    pub fn validate_for_bar(self, bar: &Bar) -> Result<(), MairError> {
+        match (self, bar.region_type) {
+            (_, BarType::IO) => Err(MairError::NotMemoryBar),
+            (MairAttribute::NormalWb, _) => Err(MairError::Cacheable),
+            (MairAttribute::NormalNc, _) if !bar.prefetchable => Err(MairError::NotPrefetchable),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Index (AttrIndx of page table entries) of `attribute` in the MAIR value
+/// `mair`.
+pub fn attribute_index(mair: u64, attribute: MairAttribute) -> Result<u8, MairError> {
+    mair.to_le_bytes()
+        .iter()
+        .position(|&byte| byte == attribute as u8)
+        .map(|index| index as u8)
+        .ok_or(MairError::NotInMair {
+            attribute: attribute as u8,
+        })
+}
+
+/// The current MAIR_EL1.
+pub fn read_mair_el1() -> u64 {
+    let mair: u64;
+    unsafe { core::arch::asm!("mrs {}, mair_el1", out(reg) mair) };
+    mair
+}
+
+/// The AttrIndx to map `bar` with `memory_type`, after checking the
+/// combination is valid and configured in MAIR_EL1.
+pub fn bar_attribute_index(bar: &Bar, memory_type: MemoryType) -> Result<u8, MairError> {
+    let attribute = MairAttribute::for_memory_type(memory_type);
+    attribute.validate_for_bar(bar)?;
+    attribute_index(read_mair_el1(), attribute)
+}
+
+/// The attribute bits of a stage 1 page or block descriptor that maps
+/// device memory with `options`, given the MAIR_EL1 value `mair` (see
+/// [`read_mair_el1`]).
+///
+/// Device memory is always mapped execute-never, as the architecture
+/// requires to keep instruction fetches away from it.
+pub fn descriptor_attributes(mair: u64, options: MapOptions) -> Result<u64, MairError> {
+    let attribute = MairAttribute::for_memory_type(options.memory_type);
+    let mut bits = 0u64;
+    bits.set_bits(DESC_ATTR_INDEX, attribute_index(mair, attribute)? as u64);
+    bits.set_bit(DESC_AP_READ_ONLY, options.read_only);
+    let execute_never = options.execute_never || attribute.is_device();
+    bits.set_bit(DESC_PXN, execute_never);
+    bits.set_bit(DESC_UXN, execute_never);
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes() {
+        // nGnRnE, nGnRE, GRE, NC, WB, WT
+        let mair = 0x0000_bbff_440c_0400;
+        assert_eq!(
+            attribute_index(mair, MairAttribute::DeviceNGnRE).unwrap(),
+            1
+        );
+        assert_eq!(attribute_index(mair, MairAttribute::NormalNc).unwrap(), 3);
+
+        let mut bar = Bar {
+            region_type: BarType::Mem,
+            prefetchable: false,
+            address: 0x1000_0000,
+            size: 0x1000,
+        };
+        let wc = MairAttribute::for_memory_type(MemoryType::WriteCombining);
+        assert!(matches!(
+            wc.validate_for_bar(&bar),
+            Err(MairError::NotPrefetchable)
+        ));
+        bar.prefetchable = true;
+        assert!(wc.validate_for_bar(&bar).is_ok());
+        assert!(!MairAttribute::DeviceNGnRnE.posted_writes());
+
+        let options = MapOptions::device().read_only(true).execute_never(false);
+        assert_eq!(
+            descriptor_attributes(mair, options).unwrap(),
+            1 << 54 | 1 << 53 | 1 << 7 | 1 << 2
+        );
+    }
+}
@@ -2,7 +2,11 @@
 
 pub use armv8::aarch64::vm::granule4k::{IOAddr, PAddr, VAddr};
 
-use crate::pci::PCIAddress;
+use crate::pci::{PCIAddress, PciError};
+
+pub mod gic;
+pub mod its;
+pub mod mair;
 
 pub trait MsrInterface {
     unsafe fn write(&mut self, msr: u32, value: u64) {
@@ -13,6 +17,54 @@ pub trait MsrInterface {
     }
 }
 
+/// MSIs go to the ITS, which routes them to a CPU through its collection
+/// (see [`its`]), the message address doesn't select one.
+pub fn msi_set_destination(_address: u64, _cpu: u32) -> Result<u64, PciError> {
+    Err(PciError::MsiAffinityUnsupported)
+}
+
+/// Size of the smallest data cache line (CTR_EL0.DminLine).
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    unsafe { core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Makes CPU writes to `len` bytes at `vaddr` visible to devices (cleans
+/// the data cache to the point of coherency).
+pub fn dma_sync_for_device(vaddr: VAddr, len: usize) {
+    let line = dcache_line_size();
+    let start = vaddr.as_usize() & !(line - 1);
+    for addr in (start..vaddr.as_usize() + len).step_by(line) {
+        unsafe { core::arch::asm!("dc cvac, {}", in(reg) addr) };
+    }
+    unsafe { core::arch::asm!("dsb sy") };
+}
+
+/// Makes device writes to `len` bytes at `vaddr` visible to the CPU (cleans
+/// and invalidates the data cache).
+pub fn dma_sync_for_cpu(vaddr: VAddr, len: usize) {
+    let line = dcache_line_size();
+    let start = vaddr.as_usize() & !(line - 1);
+    for addr in (start..vaddr.as_usize() + len).step_by(line) {
+        unsafe { core::arch::asm!("dc civac, {}", in(reg) addr) };
+    }
+    unsafe { core::arch::asm!("dsb sy") };
+}
+
+/// Size of a cache line in bytes (the smallest data cache line).
+pub fn cache_line_size() -> usize {
+    dcache_line_size()
+}
+
+/// Idles the CPU in a polling loop until the next event (`wfe`).
+///
+/// The core wakes up on `sev`, an interrupt or the generic timer's event
+/// stream (if enabled, Linux enables it at 10 kHz), not on device writes.
+pub fn wait_for_event() {
+    unsafe { core::arch::asm!("wfe") };
+}
+
 pub trait PciInterface {
     const PCI_CONF_ADDR: u16 = 0xcf8;
     const PCI_CONF_DATA: u16 = 0xcfc;
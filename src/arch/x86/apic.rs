@@ -0,0 +1,263 @@
+//! The local APIC timer.
+//!
+//! Every core has one: a down counter clocked by the bus (or core crystal)
+//! clock divided by a configurable divisor, that raises the interrupt in its
+//! LVT entry when it reaches zero (once or periodically). CPUs with the
+//! TSC-deadline mode instead fire when the TSC reaches the value written to
+//! `IA32_TSC_DEADLINE`. The registers are accessed through the xAPIC MMIO
+//! page or, in x2APIC mode, through MSRs. See Intel SDM Vol. 3, 10.5.4.
+
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::{MsrInterface, VAddr};
+use crate::clock::Clock;
+
+/// Physical address of the xAPIC registers after reset.
+pub const XAPIC_DEFAULT_BASE: u64 = 0xfee0_0000;
+
+/// Registers (xAPIC MMIO offsets, the x2APIC MSR is `0x800 + offset / 16`).
+const EOI: usize = 0x0b0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE_CONFIG: usize = 0x3e0;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// LVT timer fields.
+const LVT_VECTOR: core::ops::Range<usize> = 0..8;
+const LVT_MASKED: usize = 16;
+const LVT_TIMER_MODE: core::ops::Range<usize> = 17..19;
+
+/// Timer modes (LVT_TIMER_MODE).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    TscDeadline = 0b10,
+}
+
+/// Divisors of the timer clock.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TimerDivide {
+    By1,
+    By2,
+    By4,
+    By8,
+    By16,
+    By32,
+    By64,
+    By128,
+}
+
+impl TimerDivide {
+    pub fn divisor(self) -> u32 {
+        1 << self as u32
+    }
+
+    /// The divide configuration register value (bits 0, 1 and 3).
+    fn encode(self) -> u32 {
+        // By2 is 0b0000, ..., By128 is 0b1010 and By1 is 0b1011
+        let value = (self as u32).wrapping_sub(1) & 0b111;
+        (value & 0b11) | (value & 0b100) << 1
+    }
+}
+
+/// Whether the CPU supports the TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    x86::cpuid::CpuId::new()
+        .get_feature_info()
+        .is_some_and(|features| features.has_tsc_deadline())
+}
+
+/// The local APIC timer of the current core.
+pub struct LocalApicTimer<M: MsrInterface> {
+    /// The xAPIC registers, `None` in x2APIC mode.
+    regs: Option<VAddr>,
+    msr: M,
+    divide: TimerDivide,
+    /// Timer ticks per second (after dividing), see
+    /// [`LocalApicTimer::calibrate`].
+    frequency: Option<u64>,
+}
+
+impl<M: MsrInterface> LocalApicTimer<M> {
+    /// # Safety
+    /// `regs` must be the (uncached) mapping of the local APIC registers, the
+    /// APIC must be in xAPIC mode and the timer must only be used on the
+    /// core that owns it.
+    pub unsafe fn xapic(regs: VAddr, msr: M) -> LocalApicTimer<M> {
+        LocalApicTimer {
+            regs: Some(regs),
+            msr,
+            divide: TimerDivide::By1,
+            frequency: None,
+        }
+    }
+
+    /// # Safety
+    /// The APIC must be in x2APIC mode and the timer must only be used on
+    /// the core that owns it.
+    pub unsafe fn x2apic(msr: M) -> LocalApicTimer<M> {
+        LocalApicTimer {
+            regs: None,
+            msr,
+            divide: TimerDivide::By1,
+            frequency: None,
+        }
+    }
+
+    fn read(&mut self, offset: usize) -> u32 {
+        match self.regs {
+            // Safety: `regs` points to the APIC registers (see `xapic`)
+            Some(regs) => unsafe { core::ptr::read_volatile((regs + offset).as_ptr::<u32>()) },
+            // Safety: the APIC is in x2APIC mode (see `x2apic`)
+            None => unsafe { self.msr.read(X2APIC_MSR_BASE + (offset >> 4) as u32) as u32 },
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        match self.regs {
+            // Safety: `regs` points to the APIC registers (see `xapic`)
+            Some(regs) => unsafe {
+                core::ptr::write_volatile((regs + offset).as_mut_ptr::<u32>(), value)
+            },
+            // Safety: the APIC is in x2APIC mode (see `x2apic`)
+            None => unsafe {
+                self.msr
+                    .write(X2APIC_MSR_BASE + (offset >> 4) as u32, value as u64)
+            },
+        }
+    }
+
+    /// Signals the end of the current interrupt.
+    pub fn eoi(&mut self) {
+        self.write(EOI, 0);
+    }
+
+    pub fn divide(&self) -> TimerDivide {
+        self.divide
+    }
+
+    pub fn set_divide(&mut self, divide: TimerDivide) {
+        self.write(TIMER_DIVIDE_CONFIG, divide.encode());
+        if let Some(frequency) = self.frequency.as_mut() {
+            *frequency = *frequency * self.divide.divisor() as u64 / divide.divisor() as u64;
+        }
+        self.divide = divide;
+    }
+
+    /// Timer ticks per second, if known.
+    pub fn frequency(&self) -> Option<u64> {
+        self.frequency
+    }
+
+    /// Sets the timer frequency (e.g., from CPUID leaf 0x15).
+    pub fn set_frequency(&mut self, frequency: u64) {
+        self.frequency = Some(frequency);
+    }
+
+    /// Measures the timer frequency by letting it count down for `window`
+    /// of `clock`, returns the ticks per second.
+    pub fn calibrate<C: Clock + ?Sized>(&mut self, clock: &C, window: Duration) -> u64 {
+        self.mask();
+        self.write(TIMER_INITIAL_COUNT, u32::MAX);
+        clock.delay(window);
+        let elapsed = u32::MAX - self.read(TIMER_CURRENT_COUNT);
+        self.write(TIMER_INITIAL_COUNT, 0);
+
+        let frequency = (elapsed as u128 * 1_000_000_000 / window.as_nanos().max(1)) as u64;
+        debug!("apic: timer runs at {} Hz", frequency);
+        self.frequency = Some(frequency);
+        frequency
+    }
+
+    fn set_lvt(&mut self, vector: u8, mode: TimerMode) {
+        let mut lvt = 0u32;
+        lvt.set_bits(LVT_VECTOR, vector as u32);
+        lvt.set_bits(LVT_TIMER_MODE, mode as u32);
+        self.write(LVT_TIMER, lvt);
+    }
+
+    /// Raises `vector` once after `ticks` timer ticks.
+    pub fn start_oneshot(&mut self, vector: u8, ticks: u32) {
+        self.set_lvt(vector, TimerMode::OneShot);
+        self.write(TIMER_INITIAL_COUNT, ticks);
+    }
+
+    /// Raises `vector` every `ticks` timer ticks.
+    pub fn start_periodic(&mut self, vector: u8, ticks: u32) {
+        self.set_lvt(vector, TimerMode::Periodic);
+        self.write(TIMER_INITIAL_COUNT, ticks);
+    }
+
+    /// Converts `duration` to timer ticks (saturating), needs a known
+    /// frequency.
+    pub fn duration_to_ticks(&self, duration: Duration) -> Option<u32> {
+        let ticks = duration.as_nanos() * self.frequency? as u128 / 1_000_000_000;
+        Some(ticks.clamp(1, u32::MAX as u128) as u32)
+    }
+
+    /// Raises `vector` once after `delay`, returns `false` if the frequency
+    /// isn't known.
+    pub fn start_oneshot_in(&mut self, vector: u8, delay: Duration) -> bool {
+        match self.duration_to_ticks(delay) {
+            Some(ticks) => {
+                self.start_oneshot(vector, ticks);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Raises `vector` once the TSC reaches `deadline` (see
+    /// [`has_tsc_deadline`]).
+    pub fn start_tsc_deadline(&mut self, vector: u8, deadline: u64) {
+        self.set_lvt(vector, TimerMode::TscDeadline);
+        // The LVT write has to be ordered before the deadline MSR write
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        // Safety: the LVT is in TSC-deadline mode, so the MSR exists
+        unsafe { self.msr.write(IA32_TSC_DEADLINE, deadline) };
+    }
+
+    /// Ticks left until the timer fires (0 if it isn't running).
+    pub fn current_count(&mut self) -> u32 {
+        self.read(TIMER_CURRENT_COUNT)
+    }
+
+    /// Stops the timer (in any mode) and masks its interrupt.
+    pub fn stop(&mut self) {
+        self.mask();
+        self.write(TIMER_INITIAL_COUNT, 0);
+        if self.read(LVT_TIMER).get_bits(LVT_TIMER_MODE) == TimerMode::TscDeadline as u32 {
+            // Safety: the LVT is in TSC-deadline mode, so the MSR exists
+            unsafe { self.msr.write(IA32_TSC_DEADLINE, 0) };
+        }
+    }
+
+    fn mask(&mut self) {
+        let mut lvt = self.read(LVT_TIMER);
+        lvt.set_bit(LVT_MASKED, true);
+        self.write(LVT_TIMER, lvt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divide_encoding() {
+        assert_eq!(TimerDivide::By1.encode(), 0b1011);
+        assert_eq!(TimerDivide::By2.encode(), 0b0000);
+        assert_eq!(TimerDivide::By16.encode(), 0b0011);
+        assert_eq!(TimerDivide::By32.encode(), 0b1000);
+        assert_eq!(TimerDivide::By128.encode(), 0b1010);
+        assert_eq!(TimerDivide::By64.divisor(), 64);
+    }
+}
@@ -0,0 +1,431 @@
+//! The High Precision Event Timer (HPET).
+//!
+//! A free running main counter with a fixed period and a number of
+//! comparators that raise an interrupt (through the IO-APIC or as an MSI,
+//! "FSB delivery") when the counter reaches their value. The register block
+//! is found through the ACPI HPET table. See the IA-PC HPET specification
+//! 1.0a.
+
+use core::convert::TryInto;
+use core::time::Duration;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use super::{PAddr, VAddr};
+use crate::clock::Clock;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub HpetError
+    InvalidTable = "not a valid ACPI HPET table",
+    NotMemoryMapped = "the HPET registers aren't in memory space",
+    InvalidPeriod{period: u32} = "invalid counter period {period} fs",
+    NoComparator{index: usize} = "comparator {index} doesn't exist",
+    RouteNotSupported{irq: u8} = "the comparator can't be routed to IO-APIC input {irq}",
+    FsbNotSupported = "the comparator doesn't support FSB (MSI) delivery",
+    PeriodicNotSupported = "the comparator doesn't support periodic mode",
+}
+
+/// Size of the register block.
+pub const HPET_REGS_SIZE: usize = 0x400;
+
+/// General registers.
+const GCAP_ID: usize = 0x000;
+const GEN_CONF: usize = 0x010;
+const GINTR_STA: usize = 0x020;
+const MAIN_CNT: usize = 0x0f0;
+
+/// GCAP_ID fields.
+const GCAP_NUM_TIM_CAP: core::ops::Range<usize> = 8..13;
+const GCAP_COUNT_SIZE_CAP: usize = 13;
+const GCAP_COUNTER_CLK_PERIOD: core::ops::Range<usize> = 32..64;
+
+/// GEN_CONF bits.
+const GEN_CONF_ENABLE_CNF: usize = 0;
+
+/// Comparator registers (`TIMn_* + n * TIM_STRIDE`).
+const TIM_CONF: usize = 0x100;
+const TIM_COMP: usize = 0x108;
+const TIM_FSB_ROUTE: usize = 0x110;
+const TIM_STRIDE: usize = 0x20;
+
+/// TIMn_CONF fields.
+const TN_INT_TYPE_CNF: usize = 1;
+const TN_INT_ENB_CNF: usize = 2;
+const TN_TYPE_CNF: usize = 3;
+const TN_PER_INT_CAP: usize = 4;
+const TN_SIZE_CAP: usize = 5;
+const TN_VAL_SET_CNF: usize = 6;
+const TN_32MODE_CNF: usize = 8;
+const TN_INT_ROUTE_CNF: core::ops::Range<usize> = 9..14;
+const TN_FSB_EN_CNF: usize = 14;
+const TN_FSB_INT_DEL_CAP: usize = 15;
+const TN_INT_ROUTE_CAP: core::ops::Range<usize> = 32..64;
+
+/// Longest valid counter period (100 ns).
+const MAX_PERIOD_FS: u32 = 0x05f5_e100;
+const FS_PER_NS: u128 = 1_000_000;
+
+/// The parts of the ACPI HPET table ("HPET") we need.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HpetTable {
+    /// Event timer block ID (a copy of the low half of GCAP_ID).
+    pub block_id: u32,
+    /// Physical address of the register block.
+    pub base: u64,
+    /// Sequence number of this HPET.
+    pub number: u8,
+    /// Minimum tick in periodic mode without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+impl HpetTable {
+    const SIGNATURE: &'static [u8; 4] = b"HPET";
+    const LENGTH: usize = 56;
+    /// ACPI generic address structure, address space ID of memory.
+    const GAS_SYSTEM_MEMORY: u8 = 0;
+
+    /// Parses the table (including the ACPI header) in `table`.
+    pub fn parse(table: &[u8]) -> Result<HpetTable, HpetError> {
+        if table.len() < HpetTable::LENGTH || &table[0..4] != HpetTable::SIGNATURE {
+            return Err(HpetError::InvalidTable);
+        }
+        let length = u32::from_le_bytes(table[4..8].try_into().unwrap()) as usize;
+        if length < HpetTable::LENGTH || length > table.len() {
+            return Err(HpetError::InvalidTable);
+        }
+        let checksum = table[..length]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if checksum != 0 {
+            return Err(HpetError::InvalidTable);
+        }
+        if table[40] != HpetTable::GAS_SYSTEM_MEMORY {
+            return Err(HpetError::NotMemoryMapped);
+        }
+
+        Ok(HpetTable {
+            block_id: u32::from_le_bytes(table[36..40].try_into().unwrap()),
+            base: u64::from_le_bytes(table[44..52].try_into().unwrap()),
+            number: table[52],
+            minimum_tick: u16::from_le_bytes(table[53..55].try_into().unwrap()),
+        })
+    }
+
+    pub fn base(&self) -> PAddr {
+        PAddr::from(self.base)
+    }
+}
+
+/// An HPET.
+pub struct Hpet {
+    regs: VAddr,
+    /// Counter period in femtoseconds.
+    period: u32,
+    comparators: usize,
+    counter_64bit: bool,
+}
+
+impl Hpet {
+    /// # Safety
+    /// `regs` must be the (uncached) mapping of the HPET register block
+    /// ([`HPET_REGS_SIZE`] bytes).
+    pub unsafe fn new(regs: VAddr) -> Result<Hpet, HpetError> {
+        let mut hpet = Hpet {
+            regs,
+            period: 0,
+            comparators: 0,
+            counter_64bit: false,
+        };
+        let caps = hpet.read(GCAP_ID);
+        let period = caps.get_bits(GCAP_COUNTER_CLK_PERIOD) as u32;
+        if period == 0 || period > MAX_PERIOD_FS {
+            return Err(HpetError::InvalidPeriod { period });
+        }
+        hpet.period = period;
+        hpet.comparators = caps.get_bits(GCAP_NUM_TIM_CAP) as usize + 1;
+        hpet.counter_64bit = caps.get_bit(GCAP_COUNT_SIZE_CAP);
+        debug!(
+            "hpet: period {} fs, {} comparators, 64-bit {:?}",
+            period, hpet.comparators, hpet.counter_64bit
+        );
+        Ok(hpet)
+    }
+
+    /// Discovers the HPET from the ACPI HPET `table` and maps its registers
+    /// with `map`.
+    ///
+    /// # Safety
+    /// `map` must return an uncached mapping of at least [`HPET_REGS_SIZE`]
+    /// bytes of the given physical address.
+    pub unsafe fn from_acpi(
+        table: &[u8],
+        map: impl FnOnce(PAddr) -> VAddr,
+    ) -> Result<Hpet, HpetError> {
+        let table = HpetTable::parse(table)?;
+        Hpet::new(map(table.base()))
+    }
+
+    fn read(&self, offset: usize) -> u64 {
+        // Safety: `regs` points to the register block (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u64>()) }
+    }
+
+    fn write(&mut self, offset: usize, value: u64) {
+        // Safety: `regs` points to the register block (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u64>(), value) };
+    }
+
+    /// Counter period in femtoseconds.
+    pub fn period_fs(&self) -> u32 {
+        self.period
+    }
+
+    /// Counter frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period as u64
+    }
+
+    /// Whether the main counter is 64 bits wide (otherwise it wraps after 32).
+    pub fn counter_is_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+
+    pub fn comparators(&self) -> usize {
+        self.comparators
+    }
+
+    /// Starts the main counter (and enables comparator interrupts).
+    pub fn enable(&mut self) {
+        let mut conf = self.read(GEN_CONF);
+        conf.set_bit(GEN_CONF_ENABLE_CNF, true);
+        self.write(GEN_CONF, conf);
+    }
+
+    /// Halts the main counter.
+    pub fn disable(&mut self) {
+        let mut conf = self.read(GEN_CONF);
+        conf.set_bit(GEN_CONF_ENABLE_CNF, false);
+        self.write(GEN_CONF, conf);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.read(GEN_CONF).get_bit(GEN_CONF_ENABLE_CNF)
+    }
+
+    /// The main counter value.
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_CNT)
+    }
+
+    /// Sets the main counter, only allowed while it is halted.
+    pub fn set_counter(&mut self, value: u64) {
+        debug_assert!(!self.is_enabled());
+        self.write(MAIN_CNT, value);
+    }
+
+    /// Converts counter ticks to time.
+    pub fn ticks_to_duration(&self, ticks: u64) -> Duration {
+        let nanos = ticks as u128 * self.period as u128 / FS_PER_NS;
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// Converts time to counter ticks (rounded up).
+    pub fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        let fs = duration.as_nanos() * FS_PER_NS;
+        fs.div_ceil(self.period as u128) as u64
+    }
+
+    /// Comparators with a pending level-triggered interrupt (bit per
+    /// comparator).
+    pub fn interrupt_status(&self) -> u32 {
+        self.read(GINTR_STA) as u32
+    }
+
+    /// Acknowledges the level-triggered interrupts in `mask`.
+    pub fn clear_interrupts(&mut self, mask: u32) {
+        self.write(GINTR_STA, mask as u64);
+    }
+
+    /// The comparator `index`.
+    pub fn comparator(&mut self, index: usize) -> Result<Comparator<'_>, HpetError> {
+        if index >= self.comparators {
+            return Err(HpetError::NoComparator { index });
+        }
+        Ok(Comparator { hpet: self, index })
+    }
+}
+
+impl Clock for Hpet {
+    /// The main counter in nanoseconds, the counter has to be enabled.
+    fn now(&self) -> Duration {
+        self.ticks_to_duration(self.counter())
+    }
+}
+
+/// How a comparator signals its interrupt.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HpetRoute {
+    /// An IO-APIC input, edge or level triggered.
+    IoApic { irq: u8, level: bool },
+    /// An MSI (FSB delivery) with the given message address and data.
+    Msi { address: u32, data: u32 },
+}
+
+/// One of the comparators ("timers") of an [`Hpet`].
+pub struct Comparator<'a> {
+    hpet: &'a mut Hpet,
+    index: usize,
+}
+
+impl<'a> Comparator<'a> {
+    fn offset(&self, register: usize) -> usize {
+        register + self.index * TIM_STRIDE
+    }
+
+    fn config(&self) -> u64 {
+        self.hpet.read(self.offset(TIM_CONF))
+    }
+
+    fn set_config(&mut self, config: u64) {
+        let offset = self.offset(TIM_CONF);
+        self.hpet.write(offset, config);
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn supports_periodic(&self) -> bool {
+        self.config().get_bit(TN_PER_INT_CAP)
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.config().get_bit(TN_SIZE_CAP)
+    }
+
+    pub fn supports_fsb(&self) -> bool {
+        self.config().get_bit(TN_FSB_INT_DEL_CAP)
+    }
+
+    /// IO-APIC inputs the comparator can be routed to (bit per input).
+    pub fn ioapic_routes(&self) -> u32 {
+        self.config().get_bits(TN_INT_ROUTE_CAP) as u32
+    }
+
+    /// Sets where the interrupt goes.
+    pub fn set_route(&mut self, route: HpetRoute) -> Result<(), HpetError> {
+        let mut config = self.config();
+        match route {
+            HpetRoute::IoApic { irq, level } => {
+                if irq >= 32 || !self.ioapic_routes().get_bit(irq as usize) {
+                    return Err(HpetError::RouteNotSupported { irq });
+                }
+                config.set_bits(TN_INT_ROUTE_CNF, irq as u64);
+                config.set_bit(TN_INT_TYPE_CNF, level);
+                config.set_bit(TN_FSB_EN_CNF, false);
+            }
+            HpetRoute::Msi { address, data } => {
+                if !self.supports_fsb() {
+                    return Err(HpetError::FsbNotSupported);
+                }
+                let offset = self.offset(TIM_FSB_ROUTE);
+                self.hpet
+                    .write(offset, (address as u64) << 32 | data as u64);
+                config.set_bit(TN_INT_TYPE_CNF, false);
+                config.set_bit(TN_FSB_EN_CNF, true);
+            }
+        }
+        self.set_config(config);
+        Ok(())
+    }
+
+    /// Raises an interrupt once, when the main counter reaches `deadline`.
+    pub fn set_oneshot(&mut self, deadline: u64) {
+        let mut config = self.config();
+        config.set_bit(TN_TYPE_CNF, false);
+        config.set_bit(TN_32MODE_CNF, false);
+        config.set_bit(TN_INT_ENB_CNF, true);
+        self.set_config(config);
+        let offset = self.offset(TIM_COMP);
+        self.hpet.write(offset, deadline);
+    }
+
+    /// Raises an interrupt once, `delay` from now.
+    pub fn set_oneshot_in(&mut self, delay: Duration) {
+        let deadline = self
+            .hpet
+            .counter()
+            .wrapping_add(self.hpet.duration_to_ticks(delay));
+        self.set_oneshot(deadline);
+    }
+
+    /// Raises an interrupt every `period`, starting one period from now.
+    ///
+    /// The counter should be halted while this is programmed, otherwise the
+    /// first interrupt can be missed if it passes the comparator meanwhile.
+    pub fn set_periodic(&mut self, period: Duration) -> Result<(), HpetError> {
+        if !self.supports_periodic() {
+            return Err(HpetError::PeriodicNotSupported);
+        }
+        let ticks = self.hpet.duration_to_ticks(period).max(1);
+        let start = self.hpet.counter().wrapping_add(ticks);
+
+        let mut config = self.config();
+        config.set_bit(TN_TYPE_CNF, true);
+        config.set_bit(TN_32MODE_CNF, false);
+        config.set_bit(TN_INT_ENB_CNF, true);
+        config.set_bit(TN_VAL_SET_CNF, true);
+        self.set_config(config);
+        // With VAL_SET the first write sets the comparator, the second the
+        // period (accumulator).
+        let offset = self.offset(TIM_COMP);
+        self.hpet.write(offset, start);
+        self.hpet.write(offset, ticks);
+        Ok(())
+    }
+
+    /// Stops the comparator from raising interrupts.
+    pub fn disable(&mut self) {
+        let mut config = self.config();
+        config.set_bit(TN_INT_ENB_CNF, false);
+        config.set_bit(TN_TYPE_CNF, false);
+        self.set_config(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_table() {
+        let mut table = [0u8; 56];
+        table[0..4].copy_from_slice(b"HPET");
+        table[4..8].copy_from_slice(&56u32.to_le_bytes());
+        table[36..40].copy_from_slice(&0x8086_a201u32.to_le_bytes());
+        table[44..52].copy_from_slice(&0xfed0_0000u64.to_le_bytes());
+        table[53..55].copy_from_slice(&0x80u16.to_le_bytes());
+        let sum = table.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        table[9] = 0u8.wrapping_sub(sum);
+
+        let hpet = HpetTable::parse(&table).unwrap();
+        assert_eq!(hpet.base, 0xfed0_0000);
+        assert_eq!(hpet.block_id, 0x8086_a201);
+        assert_eq!(hpet.minimum_tick, 0x80);
+
+        table[40] = 1;
+        assert!(matches!(
+            HpetTable::parse(&table),
+            Err(HpetError::InvalidTable)
+        ));
+        table[9] = table[9].wrapping_sub(1);
+        assert!(matches!(
+            HpetTable::parse(&table),
+            Err(HpetError::NotMemoryMapped)
+        ));
+    }
+}
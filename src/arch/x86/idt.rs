@@ -0,0 +1,330 @@
+//! A minimal IDT and interrupt vector allocator for bare-metal hosts.
+//!
+//! Hosts that don't have their own interrupt infrastructure can load the
+//! [`Idt`] here: every device vector (0x30..=0xef) gets an entry stub that
+//! saves the caller-saved registers and calls the handler registered for
+//! the vector, followed by the end-of-interrupt hook. Device vectors are
+//! handed out as [`Vector`]s, whose MSI message can be programmed into an
+//! MSI-X entry directly (see
+//! [`PciDeviceHandle::claim_msix_vectors`](crate::pci::PciDeviceHandle::claim_msix_vectors)).
+//!
+//! Handlers run with interrupts disabled on the interrupted stack and must
+//! not touch FPU/SSE state, which isn't saved.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use custom_error::custom_error;
+
+use super::msi_address;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub IdtError
+    NoVectors = "all device interrupt vectors are in use",
+    InvalidVector{vector: u8} = "vector {vector} isn't a device vector",
+    VectorInUse{vector: u8} = "vector {vector} is already in use",
+}
+
+/// Vectors handed out for devices (below are the exceptions and the legacy
+/// PIC range, above the IPIs, the APIC timer and the spurious vector).
+pub const FIRST_DEVICE_VECTOR: u8 = 0x30;
+pub const LAST_DEVICE_VECTOR: u8 = 0xef;
+const DEVICE_VECTORS: usize = (LAST_DEVICE_VECTOR - FIRST_DEVICE_VECTOR) as usize + 1;
+
+/// Called with the vector that fired.
+pub type InterruptHandler = fn(vector: u8);
+
+/// Handlers by vector (function pointers, 0 if none is registered).
+static HANDLERS: [AtomicUsize; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; 256]
+};
+/// Called after every handler (e.g., the local APIC EOI).
+static EOI: AtomicUsize = AtomicUsize::new(0);
+/// Allocated device vectors (bit per vector).
+static ALLOCATED: spin::Mutex<[u64; 4]> = spin::Mutex::new([0; 4]);
+
+fn is_device_vector(vector: u8) -> bool {
+    (FIRST_DEVICE_VECTOR..=LAST_DEVICE_VECTOR).contains(&vector)
+}
+
+/// Sets the function that signals the end of an interrupt, called after
+/// the handler.
+pub fn set_eoi_handler(eoi: fn()) {
+    EOI.store(eoi as usize, Ordering::Release);
+}
+
+/// Calls the handler registered for `vector`, returns whether there was
+/// one.
+///
+/// The entry stubs call this, hosts with their own IDT can call it from
+/// their device interrupt handlers.
+pub fn dispatch(vector: u8) -> bool {
+    let handler = HANDLERS[vector as usize].load(Ordering::Acquire);
+    if handler != 0 {
+        // Safety: only `InterruptHandler`s are stored in `HANDLERS`
+        let handler: InterruptHandler = unsafe { core::mem::transmute(handler) };
+        handler(vector);
+    } else {
+        warn!("idt: spurious interrupt on vector {}", vector);
+    }
+    handler != 0
+}
+
+#[no_mangle]
+extern "C" fn driverkit_idt_dispatch(vector: u64) {
+    dispatch(vector as u8);
+    let eoi = EOI.load(Ordering::Acquire);
+    if eoi != 0 {
+        // Safety: only `fn()`s are stored in `EOI`
+        let eoi: fn() = unsafe { core::mem::transmute(eoi) };
+        eoi();
+    }
+}
+
+/// An allocated device interrupt vector with a handler, freed on drop.
+#[derive(Debug)]
+pub struct Vector {
+    vector: u8,
+}
+
+impl Vector {
+    /// Allocates any free device vector and registers `handler` for it.
+    pub fn allocate(handler: InterruptHandler) -> Result<Vector, IdtError> {
+        let mut allocated = ALLOCATED.lock();
+        let vector = (FIRST_DEVICE_VECTOR..=LAST_DEVICE_VECTOR)
+            .find(|&v| allocated[v as usize / 64] & (1 << (v % 64)) == 0)
+            .ok_or(IdtError::NoVectors)?;
+        allocated[vector as usize / 64] |= 1 << (vector % 64);
+        HANDLERS[vector as usize].store(handler as usize, Ordering::Release);
+        Ok(Vector { vector })
+    }
+
+    /// Allocates device vector `vector` and registers `handler` for it.
+    pub fn allocate_at(vector: u8, handler: InterruptHandler) -> Result<Vector, IdtError> {
+        if !is_device_vector(vector) {
+            return Err(IdtError::InvalidVector { vector });
+        }
+        let mut allocated = ALLOCATED.lock();
+        if allocated[vector as usize / 64] & (1 << (vector % 64)) != 0 {
+            return Err(IdtError::VectorInUse { vector });
+        }
+        allocated[vector as usize / 64] |= 1 << (vector % 64);
+        HANDLERS[vector as usize].store(handler as usize, Ordering::Release);
+        Ok(Vector { vector })
+    }
+
+    pub fn number(&self) -> u8 {
+        self.vector
+    }
+
+    /// Replaces the handler.
+    pub fn set_handler(&mut self, handler: InterruptHandler) {
+        HANDLERS[self.vector as usize].store(handler as usize, Ordering::Release);
+    }
+
+    /// The MSI message address and data (edge triggered, fixed delivery)
+    /// that raise this vector on the core with local APIC ID `apic_id`.
+    pub fn msi_message(&self, apic_id: u8) -> (u64, u32) {
+        (msi_address(apic_id), self.vector as u32)
+    }
+}
+
+impl Drop for Vector {
+    fn drop(&mut self) {
+        HANDLERS[self.vector as usize].store(0, Ordering::Release);
+        ALLOCATED.lock()[self.vector as usize / 64] &= !(1 << (self.vector % 64));
+    }
+}
+
+/// Number of free device vectors.
+pub fn free_vectors() -> usize {
+    let allocated = ALLOCATED.lock();
+    DEVICE_VECTORS
+        - allocated
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum::<usize>()
+}
+
+// One 16-byte stub per device vector that pushes the vector and jumps to the
+// common entry. The CPU aligns the stack to 16 bytes before pushing the
+// 5-word interrupt frame, so after the vector and 9 registers one more word
+// aligns it for the call.
+core::arch::global_asm!(
+    ".pushsection .text.driverkit_idt, \"ax\"",
+    ".p2align 4",
+    ".global driverkit_idt_stubs",
+    "driverkit_idt_stubs:",
+    ".set driverkit_idt_vector, {first}",
+    ".rept {count}",
+    ".p2align 4",
+    "pushq $driverkit_idt_vector",
+    "jmp driverkit_idt_common",
+    ".set driverkit_idt_vector, driverkit_idt_vector + 1",
+    ".endr",
+    "driverkit_idt_common:",
+    "pushq %rax",
+    "pushq %rcx",
+    "pushq %rdx",
+    "pushq %rsi",
+    "pushq %rdi",
+    "pushq %r8",
+    "pushq %r9",
+    "pushq %r10",
+    "pushq %r11",
+    "movq 72(%rsp), %rdi",
+    "subq $8, %rsp",
+    "cld",
+    "call driverkit_idt_dispatch",
+    "addq $8, %rsp",
+    "popq %r11",
+    "popq %r10",
+    "popq %r9",
+    "popq %r8",
+    "popq %rdi",
+    "popq %rsi",
+    "popq %rdx",
+    "popq %rcx",
+    "popq %rax",
+    "addq $8, %rsp",
+    "iretq",
+    ".popsection",
+    first = const FIRST_DEVICE_VECTOR,
+    count = const DEVICE_VECTORS,
+    options(att_syntax)
+);
+
+extern "C" {
+    static driverkit_idt_stubs: u8;
+}
+
+/// Size of an entry stub.
+const STUB_SIZE: usize = 16;
+
+/// An IDT gate descriptor.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    attributes: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl IdtEntry {
+    /// Present, DPL 0, 64-bit interrupt gate.
+    const INTERRUPT_GATE: u8 = 0x8e;
+
+    /// An interrupt gate to `handler` in code segment `selector`, on
+    /// interrupt stack `ist` (0 for the current stack).
+    pub fn interrupt_gate(handler: u64, selector: u16, ist: u8) -> IdtEntry {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector,
+            ist: ist & 0x7,
+            attributes: IdtEntry::INTERRUPT_GATE,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+
+    pub fn handler(&self) -> u64 {
+        self.offset_low as u64 | (self.offset_mid as u64) << 16 | (self.offset_high as u64) << 32
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.attributes & 0x80 != 0
+    }
+}
+
+/// An interrupt descriptor table.
+#[repr(C, align(16))]
+pub struct Idt {
+    entries: [IdtEntry; 256],
+}
+
+impl Default for Idt {
+    fn default() -> Self {
+        Idt::new()
+    }
+}
+
+impl Idt {
+    /// An IDT without any gates.
+    pub fn new() -> Idt {
+        Idt {
+            entries: [IdtEntry::default(); 256],
+        }
+    }
+
+    /// Points the device vectors to the entry stubs that call
+    /// [`dispatch`], using the current code segment.
+    pub fn install_device_stubs(&mut self) {
+        let selector = x86::segmentation::cs().bits();
+        // Safety: the stubs are defined in `global_asm!` above
+        let stubs = unsafe { &driverkit_idt_stubs as *const u8 as u64 };
+        for vector in FIRST_DEVICE_VECTOR..=LAST_DEVICE_VECTOR {
+            let stub = stubs + ((vector - FIRST_DEVICE_VECTOR) as usize * STUB_SIZE) as u64;
+            self.set_entry(vector, IdtEntry::interrupt_gate(stub, selector, 0));
+        }
+    }
+
+    pub fn entry(&self, vector: u8) -> &IdtEntry {
+        &self.entries[vector as usize]
+    }
+
+    /// Sets the gate for `vector` (e.g., exception handlers of the host).
+    pub fn set_entry(&mut self, vector: u8, entry: IdtEntry) {
+        self.entries[vector as usize] = entry;
+    }
+
+    /// Loads the IDT on the current core.
+    ///
+    /// # Safety
+    /// Needs CPL 0, the gates must point to valid handlers.
+    pub unsafe fn load(&'static self) {
+        let pointer = x86::dtables::DescriptorTablePointer::new_from_slice(&self.entries);
+        x86::dtables::lidt(&pointer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nop(_vector: u8) {}
+
+    #[test]
+    fn allocate_vectors() {
+        let free = free_vectors();
+        let first = Vector::allocate(nop).unwrap();
+        assert!(is_device_vector(first.number()));
+        assert!(matches!(
+            Vector::allocate_at(first.number(), nop),
+            Err(IdtError::VectorInUse { .. })
+        ));
+        assert!(matches!(
+            Vector::allocate_at(0x20, nop),
+            Err(IdtError::InvalidVector { vector: 0x20 })
+        ));
+        assert_eq!(first.msi_message(3), (0xfee0_3000, first.number() as u32));
+        assert_eq!(free_vectors(), free - 1);
+        drop(first);
+        assert_eq!(free_vectors(), free);
+
+        let mut idt = Idt::new();
+        idt.install_device_stubs();
+        let stub = idt.entry(FIRST_DEVICE_VECTOR + 1).handler();
+        assert_eq!(
+            stub - idt.entry(FIRST_DEVICE_VECTOR).handler(),
+            STUB_SIZE as u64
+        );
+        assert!(!idt.entry(0).is_present());
+    }
+}
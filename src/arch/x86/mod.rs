@@ -2,9 +2,16 @@
 
 extern crate x86;
 
+pub mod apic;
+pub mod hpet;
+#[cfg(feature = "idt")]
+pub mod idt;
+
 pub use x86::current::paging::{IOAddr, PAddr, VAddr};
 
-use crate::pci::PCIAddress;
+use core::convert::TryFrom;
+
+use crate::pci::{PCIAddress, PciError};
 
 pub trait MsrInterface {
     /// Write a MSR.
@@ -26,6 +33,54 @@ pub trait MsrInterface {
     }
 }
 
+/// Base of the MSI address range (see Intel SDM Vol. 3, 10.11.1).
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+/// Returns the MSI message address that targets the local APIC with ID
+/// `apic_id` (physical destination mode).
+pub fn msi_address(apic_id: u8) -> u64 {
+    MSI_ADDRESS_BASE | (apic_id as u64) << 12
+}
+
+/// Replaces the destination APIC ID (bits 12..20) of an MSI message address.
+///
+/// The address only has room for 8-bit APIC IDs, CPUs with larger (x2APIC)
+/// IDs can only be reached through interrupt remapping.
+pub fn msi_set_destination(address: u64, apic_id: u32) -> Result<u64, PciError> {
+    let apic_id =
+        u8::try_from(apic_id).map_err(|_| PciError::MsiDestinationInvalid { cpu: apic_id })?;
+    Ok((address & !0x000f_f000) | (apic_id as u64) << 12)
+}
+
+/// Makes CPU writes to `len` bytes at `vaddr` visible to devices.
+///
+/// DMA is cache coherent on x86, so this only orders the preceding writes
+/// before the ones that follow (e.g., to a doorbell).
+pub fn dma_sync_for_device(_vaddr: VAddr, _len: usize) {
+    core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+}
+
+/// Makes device writes to `len` bytes at `vaddr` visible to the CPU.
+///
+/// DMA is cache coherent on x86, so this only orders the subsequent reads
+/// after the ones that precede it (e.g., of a descriptor's done bit).
+pub fn dma_sync_for_cpu(_vaddr: VAddr, _len: usize) {
+    core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+}
+
+/// Size of a cache line in bytes.
+pub fn cache_line_size() -> usize {
+    64
+}
+
+/// Idles the CPU briefly in a polling loop.
+///
+/// x86 can't wait for plain memory writes without MONITOR/MWAIT (which
+/// isn't available in user mode), so this is a `pause`.
+pub fn wait_for_event() {
+    core::hint::spin_loop();
+}
+
 pub trait PciInterface {
     const PCI_CONF_ADDR: u16 = 0xcf8;
     const PCI_CONF_DATA: u16 = 0xcfc;
@@ -48,6 +103,7 @@ pub trait PciInterface {
 impl PciInterface for PCIAddress {
     fn read(&self, offset: u32) -> u32 {
         let addr = self.addr() | offset;
+        crate::metrics::PCI_CONFIG_READS.inc();
 
         unsafe {
             x86::io::outl(<Self as PciInterface>::PCI_CONF_ADDR, addr);
@@ -57,6 +113,7 @@ impl PciInterface for PCIAddress {
 
     fn write(&mut self, offset: u32, value: u32) {
         let addr = self.addr() | offset;
+        crate::metrics::PCI_CONFIG_WRITES.inc();
 
         unsafe {
             x86::io::outl(<Self as PciInterface>::PCI_CONF_ADDR, addr);
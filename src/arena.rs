@@ -0,0 +1,202 @@
+//! A bump allocator for per-device driver metadata.
+//!
+//! Drivers allocate lots of small, long-lived objects at attach time
+//! (queue bookkeeping, descriptor shadows, lookup tables) and free all of
+//! them at detach. An [`Arena`] takes one block of memory up front, hands
+//! out pieces of it by bumping an offset and releases everything at once
+//! with [`Arena::reset`] or when dropped, so a kernel embedding the crate
+//! only sees one allocation per device.
+//!
+//! `&Arena` implements [`Allocator`], so it works with `Vec::new_in`,
+//! `Box::new_in` etc. Freeing individual allocations is a no-op (except for
+//! the most recent one).
+
+use alloc::alloc::{AllocError, Allocator, Layout};
+use core::cell::Cell;
+use core::ptr::NonNull;
+
+use custom_error::custom_error;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub ArenaError
+    OutOfMemory{size: usize} = "the arena doesn't have {size} bytes left",
+    InvalidCapacity = "the arena capacity is invalid",
+}
+
+/// Alignment of the backing memory.
+const ARENA_ALIGN: usize = 64;
+
+/// A fixed-size bump allocator (see the module documentation).
+#[derive(Debug)]
+pub struct Arena {
+    base: NonNull<u8>,
+    capacity: usize,
+    /// Offset of the first free byte.
+    next: Cell<usize>,
+    /// Whether `base` was allocated by the arena (and has to be freed).
+    owned: bool,
+}
+
+impl Arena {
+    /// Creates an arena of `capacity` bytes, allocated from the global
+    /// allocator.
+    pub fn new(capacity: usize) -> Result<Arena, ArenaError> {
+        let layout = Arena::layout(capacity)?;
+        // Safety: the layout has a non-zero size (checked by `layout`)
+        let base = unsafe { alloc::alloc::alloc(layout) };
+        let base = NonNull::new(base).ok_or(ArenaError::OutOfMemory { size: capacity })?;
+        Ok(Arena {
+            base,
+            capacity,
+            next: Cell::new(0),
+            owned: true,
+        })
+    }
+
+    /// Creates an arena that hands out `buffer` (e.g., memory set aside by
+    /// the kernel for the device).
+    pub fn from_buffer(buffer: &'static mut [u8]) -> Arena {
+        Arena {
+            capacity: buffer.len(),
+            // Safety: slices are never null
+            base: unsafe { NonNull::new_unchecked(buffer.as_mut_ptr()) },
+            next: Cell::new(0),
+            owned: false,
+        }
+    }
+
+    fn layout(capacity: usize) -> Result<Layout, ArenaError> {
+        if capacity == 0 {
+            return Err(ArenaError::InvalidCapacity);
+        }
+        Layout::from_size_align(capacity, ARENA_ALIGN).map_err(|_e| ArenaError::InvalidCapacity)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes handed out (including alignment padding).
+    pub fn used(&self) -> usize {
+        self.next.get()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.capacity - self.used()
+    }
+
+    /// Carves `layout` out of the arena.
+    fn bump(&self, layout: Layout) -> Result<NonNull<u8>, ArenaError> {
+        let addr = self.base.as_ptr() as usize + self.next.get();
+        let padding = addr.next_multiple_of(layout.align()) - addr;
+        let start = self.next.get() + padding;
+        match start.checked_add(layout.size()) {
+            Some(end) if end <= self.capacity => {
+                self.next.set(end);
+                // Safety: `start` is within the arena
+                Ok(unsafe { NonNull::new_unchecked(self.base.as_ptr().add(start)) })
+            }
+            _ => Err(ArenaError::OutOfMemory {
+                size: layout.size(),
+            }),
+        }
+    }
+
+    /// Moves `value` into the arena.
+    ///
+    /// Note that the value is never dropped, the arena only releases the
+    /// memory.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T>(&self, value: T) -> Result<&mut T, ArenaError> {
+        let ptr = self.bump(Layout::new::<T>())?.cast::<T>();
+        // Safety: the memory is valid and aligned for a T and not handed
+        // out again before a `reset`, which needs &mut self
+        unsafe {
+            ptr.as_ptr().write(value);
+            Ok(&mut *ptr.as_ptr())
+        }
+    }
+
+    /// Copies `values` into the arena.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, values: &[T]) -> Result<&mut [T], ArenaError> {
+        let layout = Layout::for_value(values);
+        let ptr = self.bump(layout)?.cast::<T>();
+        // Safety: as in `alloc`, `values` can't overlap fresh arena memory
+        unsafe {
+            core::ptr::copy_nonoverlapping(values.as_ptr(), ptr.as_ptr(), values.len());
+            Ok(core::slice::from_raw_parts_mut(ptr.as_ptr(), values.len()))
+        }
+    }
+
+    /// Frees all allocations at once (e.g., when the driver detaches).
+    pub fn reset(&mut self) {
+        trace!(
+            "Arena reset, {} of {} bytes were used",
+            self.used(),
+            self.capacity
+        );
+        self.next.set(0);
+    }
+}
+
+// Safety: the arena owns its memory, borrows of it can't outlive a move
+unsafe impl Send for Arena {}
+
+unsafe impl Allocator for &Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.bump(layout).map_err(|_e| AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // Only the most recent allocation can be given back
+        let end = ptr.as_ptr() as usize + layout.size() - self.base.as_ptr() as usize;
+        if end == self.next.get() {
+            self.next.set(end - layout.size());
+        }
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        if self.owned {
+            // Safety: allocated in `new` with the same layout
+            unsafe {
+                alloc::alloc::dealloc(self.base.as_ptr(), Arena::layout(self.capacity).unwrap())
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn bump_and_reset() {
+        let mut arena = Arena::new(256).unwrap();
+        let a = arena.alloc(1u8).unwrap();
+        *a = 2;
+        let b = arena.alloc(0x1234_5678u64).unwrap();
+        assert_eq!(*b, 0x1234_5678);
+        assert!((b as *const u64 as usize).is_multiple_of(8));
+        assert_eq!(arena.used(), 16);
+
+        let mut v: Vec<u32, &Arena> = Vec::with_capacity_in(16, &arena);
+        v.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(arena.used(), 16 + 64);
+        drop(v);
+        assert_eq!(arena.used(), 16);
+
+        assert!(matches!(
+            arena.alloc([0u8; 512]),
+            Err(ArenaError::OutOfMemory { size: 512 })
+        ));
+        assert_eq!(arena.alloc_slice(&[7u16; 4]).unwrap(), &[7, 7, 7, 7]);
+        arena.reset();
+        assert_eq!(arena.remaining(), 256);
+    }
+}
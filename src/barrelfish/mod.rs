@@ -4,6 +4,28 @@ use libbarrelfish::pci::*;
 use libbarrelfish::*;
 use libc;
 
+use crate::iomem::MapOptions;
+
+/// Flags of a vregion (see include/barrelfish/vregion.h).
+const VREGION_FLAGS_READ: u32 = 0x01;
+const VREGION_FLAGS_WRITE: u32 = 0x02;
+const VREGION_FLAGS_EXECUTE: u32 = 0x04;
+const VREGION_FLAGS_NOCACHE: u32 = 0x08;
+
+/// The vregion flags to map device memory with `options`.
+///
+/// Barrelfish has no write-combining mappings, those are uncached as well.
+pub fn vregion_flags(options: MapOptions) -> u32 {
+    let mut flags = VREGION_FLAGS_READ | VREGION_FLAGS_NOCACHE;
+    if !options.read_only {
+        flags |= VREGION_FLAGS_WRITE;
+    }
+    if !options.execute_never {
+        flags |= VREGION_FLAGS_EXECUTE;
+    }
+    flags
+}
+
 /// Has to be > 0xffff
 pub const PCI_DONT_CARE: u32 = 0x10000;
 
@@ -0,0 +1,29 @@
+//! Lists the PCI devices of a Linux system, similar to `lspci`.
+//!
+//! Usage: driverkit-lspci [-v]
+
+extern crate driverkit;
+
+#[cfg(target_os = "linux")]
+pub fn main() {
+    use driverkit::pci::ReadOnlyPciDevice;
+    use driverkit::sysfs;
+
+    let verbose = std::env::args().skip(1).any(|arg| arg == "-v");
+    let devices = sysfs::scan().expect("Can't enumerate PCI devices in sysfs");
+
+    for device in devices {
+        if verbose {
+            let bars = sysfs::resources(device.pci_address()).unwrap_or_default();
+            let device = ReadOnlyPciDevice::new(device).with_bars(&bars);
+            println!("{}", device.verbose());
+        } else {
+            println!("{}", device);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn main() {
+    eprintln!("driverkit-lspci is only supported on Linux");
+}
@@ -0,0 +1,85 @@
+//! Definitions for block devices.
+//!
+//! A [`BlockDevice`] takes requests through a [`DevQueue`]: each
+//! [`IOBufChain`](crate::iomem::IOBufChain) is one request, its
+//! [`block_io`](crate::iomem::IOBufChain::block_io) tells the driver what to
+//! do with the chain's segments. [`RequestQueue`] sits on top and batches
+//! requests for the device.
+
+use custom_error::custom_error;
+
+use crate::devq::{DevQueue, DevQueueError};
+use crate::iomem::IOMemError;
+
+pub mod queue;
+
+pub use queue::{Completion, RequestId, RequestQueue};
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub BlockError
+    Empty = "the read or write doesn't transfer any data",
+    Misaligned = "the transfer isn't a multiple of the sector size",
+    OutOfRange{sector: u64} = "the transfer at sector {sector} is beyond the end of the device",
+    TooLarge = "the transfer exceeds the device's request size limits",
+    OutOfMemory = "the operation caused an out-of-memory condition",
+    QueueFailure = "the device queue failed",
+}
+
+impl From<DevQueueError> for BlockError {
+    fn from(e: DevQueueError) -> Self {
+        match e {
+            DevQueueError::OutOfMemory => BlockError::OutOfMemory,
+            _ => BlockError::QueueFailure,
+        }
+    }
+}
+
+impl From<IOMemError> for BlockError {
+    fn from(_e: IOMemError) -> Self {
+        BlockError::OutOfMemory
+    }
+}
+
+/// What a request does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BlockOp {
+    Read,
+    Write,
+    /// Writes back the device's volatile cache (the request has no data).
+    Flush,
+}
+
+/// The operation of a request and the sector it starts at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlockIo {
+    pub op: BlockOp,
+    pub sector: u64,
+}
+
+/// Size and request limits of a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlockGeometry {
+    /// Logical sector size in bytes.
+    pub sector_size: usize,
+    /// Capacity in sectors.
+    pub sectors: u64,
+    /// Number of requests the device can have outstanding.
+    pub queue_depth: usize,
+    /// The most sectors a single request may transfer.
+    pub max_sectors: u64,
+    /// The most segments (buffers) a single request may have.
+    pub max_segments: usize,
+}
+
+/// A block device, independent of the controller model.
+pub trait BlockDevice {
+    type Queue: DevQueue;
+
+    fn geometry(&self) -> BlockGeometry;
+
+    fn queue(&mut self) -> &mut Self::Queue;
+}
@@ -0,0 +1,419 @@
+//! A request queue in front of a block device.
+//!
+//! [`RequestQueue`] keeps submitted requests in FIFO order (there is no
+//! elevator) but coalesces a request with the one submitted before it if
+//! their sector ranges are adjacent, so sequential I/O reaches the device as
+//! few large requests. At most the device's queue depth of (merged) requests
+//! is outstanding at a time; once the device completes a merged request it
+//! is split up again and every submitter gets its own buffers back.
+
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, BlockError, BlockGeometry, BlockIo, BlockOp};
+use crate::devq::DevQueue;
+use crate::iomem::IOBufChain;
+
+/// Identifies a submitted request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RequestId(u64);
+
+/// A completed request.
+#[derive(Debug)]
+pub struct Completion {
+    pub id: RequestId,
+    pub io: BlockIo,
+    /// The buffers as they were submitted.
+    pub bufs: IOBufChain,
+}
+
+/// A submitted request that is part of a (possibly merged) device request.
+#[derive(Debug)]
+struct Part {
+    id: RequestId,
+    io: BlockIo,
+    segments: usize,
+}
+
+/// A request for the device, made up of one or more submitted requests.
+#[derive(Debug)]
+struct DeviceRequest {
+    io: BlockIo,
+    sectors: u64,
+    bufs: IOBufChain,
+    /// In sector order.
+    parts: Vec<Part>,
+}
+
+impl DeviceRequest {
+    fn end(&self) -> u64 {
+        self.io.sector + self.sectors
+    }
+}
+
+/// Batches requests for a [`BlockDevice`].
+pub struct RequestQueue<D: BlockDevice> {
+    device: D,
+    geometry: BlockGeometry,
+    depth: usize,
+    next_id: u64,
+    /// Submitted but not yet handed to the device.
+    pending: VecDeque<DeviceRequest>,
+    /// Parts of the requests the device works on, in submission order.
+    inflight: VecDeque<Vec<Part>>,
+    completed: VecDeque<Completion>,
+}
+
+impl<D: BlockDevice> RequestQueue<D> {
+    /// A queue using the device's full queue depth.
+    pub fn new(device: D) -> RequestQueue<D> {
+        let depth = device.geometry().queue_depth;
+        RequestQueue::with_queue_depth(device, depth)
+    }
+
+    /// A queue that keeps at most `depth` requests outstanding (limited to the
+    /// device's queue depth).
+    pub fn with_queue_depth(device: D, depth: usize) -> RequestQueue<D> {
+        let geometry = device.geometry();
+        RequestQueue {
+            device,
+            geometry,
+            depth: depth.clamp(1, geometry.queue_depth.max(1)),
+            next_id: 0,
+            pending: VecDeque::new(),
+            inflight: VecDeque::new(),
+            completed: VecDeque::new(),
+        }
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Number of (merged) requests waiting to be dispatched.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of (merged) requests the device works on.
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    /// Queues a request to `op` the sectors starting at `sector` with the
+    /// buffers of `bufs` (a flush ignores them). Nothing reaches the device
+    /// before [`RequestQueue::dispatch`].
+    ///
+    /// # Returns
+    /// The ID the request completes with, or the error and the buffers if
+    /// the request is invalid.
+    #[allow(clippy::result_large_err)]
+    pub fn submit(
+        &mut self,
+        op: BlockOp,
+        sector: u64,
+        bufs: IOBufChain,
+    ) -> Result<RequestId, (BlockError, IOBufChain)> {
+        let sectors = match self.check(op, sector, &bufs) {
+            Ok(sectors) => sectors,
+            Err(e) => return Err((e, bufs)),
+        };
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        let io = BlockIo { op, sector };
+        let part = Part {
+            id,
+            io,
+            segments: bufs.segments.len(),
+        };
+        match self.pending.back_mut() {
+            Some(last) if Self::can_merge(&self.geometry, last, io, sectors, part.segments) => {
+                if last.end() == sector {
+                    last.bufs.segments.extend(bufs.segments);
+                    last.parts.push(part);
+                } else {
+                    for segment in bufs.segments.into_iter().rev() {
+                        last.bufs.segments.push_front(segment);
+                    }
+                    last.parts.insert(0, part);
+                    last.io.sector = sector;
+                }
+                last.sectors += sectors;
+            }
+            _ => self.pending.push_back(DeviceRequest {
+                io,
+                sectors,
+                bufs,
+                parts: alloc::vec![part],
+            }),
+        }
+        Ok(id)
+    }
+
+    /// Validates a request, returns its length in sectors.
+    fn check(&self, op: BlockOp, sector: u64, bufs: &IOBufChain) -> Result<u64, BlockError> {
+        if op == BlockOp::Flush {
+            return Ok(0);
+        }
+        let len: usize = bufs.segments.iter().map(|seg| seg.len()).sum();
+        if len == 0 {
+            return Err(BlockError::Empty);
+        }
+        if !len.is_multiple_of(self.geometry.sector_size) {
+            return Err(BlockError::Misaligned);
+        }
+        let sectors = (len / self.geometry.sector_size) as u64;
+        if sector
+            .checked_add(sectors)
+            .is_none_or(|end| end > self.geometry.sectors)
+        {
+            return Err(BlockError::OutOfRange { sector });
+        }
+        if sectors > self.geometry.max_sectors || bufs.segments.len() > self.geometry.max_segments {
+            return Err(BlockError::TooLarge);
+        }
+        Ok(sectors)
+    }
+
+    /// Whether a request can be merged into `last`, either right behind or
+    /// right in front of it. Flushes are never merged, so they order the
+    /// requests around them.
+    fn can_merge(
+        geometry: &BlockGeometry,
+        last: &DeviceRequest,
+        io: BlockIo,
+        sectors: u64,
+        segments: usize,
+    ) -> bool {
+        io.op != BlockOp::Flush
+            && last.io.op == io.op
+            && (last.end() == io.sector || io.sector + sectors == last.io.sector)
+            && last.sectors + sectors <= geometry.max_sectors
+            && last.bufs.segments.len() + segments <= geometry.max_segments
+    }
+
+    /// Hands pending requests to the device until its queue depth is reached.
+    ///
+    /// # Returns
+    /// The number of (merged) requests dispatched.
+    pub fn dispatch(&mut self) -> Result<usize, BlockError> {
+        let mut dispatched = 0;
+        while self.inflight.len() < self.depth {
+            let request = match self.pending.pop_front() {
+                Some(request) => request,
+                None => break,
+            };
+            let queue = self.device.queue();
+            if !queue.can_enqueue(request.bufs.segments.len().max(1)) {
+                self.pending.push_front(request);
+                break;
+            }
+            let DeviceRequest {
+                io,
+                sectors,
+                mut bufs,
+                parts,
+            } = request;
+            bufs.block_io = Some(io);
+            if let Err(bufs) = queue.enqueue(bufs) {
+                self.pending.push_front(DeviceRequest {
+                    io,
+                    sectors,
+                    bufs,
+                    parts,
+                });
+                break;
+            }
+            self.inflight.push_back(parts);
+            dispatched += 1;
+        }
+        if dispatched > 0 {
+            self.device.queue().flush()?;
+        }
+        Ok(dispatched)
+    }
+
+    /// Collects the requests the device completed, see
+    /// [`RequestQueue::next_completion`].
+    ///
+    /// # Returns
+    /// The number of (submitted) requests completed.
+    pub fn complete(&mut self) -> Result<usize, BlockError> {
+        let mut completed = 0;
+        while self.device.queue().can_dequeue(false) > 0 {
+            let mut bufs = self.device.queue().dequeue()?;
+            let parts = match self.inflight.pop_front() {
+                Some(parts) => parts,
+                None => {
+                    warn!("block device completed a request that wasn't dispatched");
+                    continue;
+                }
+            };
+            completed += parts.len();
+            if parts.len() == 1 {
+                let part = &parts[0];
+                self.completed.push_back(Completion {
+                    id: part.id,
+                    io: part.io,
+                    bufs,
+                });
+                continue;
+            }
+            for part in parts {
+                let mut split = IOBufChain::new(bufs.flags, part.segments)?;
+                split.segments.extend(bufs.segments.drain(..part.segments));
+                split.block_io = Some(part.io);
+                self.completed.push_back(Completion {
+                    id: part.id,
+                    io: part.io,
+                    bufs: split,
+                });
+            }
+        }
+        Ok(completed)
+    }
+
+    /// The next completed request, in the order the device completed them.
+    pub fn next_completion(&mut self) -> Option<Completion> {
+        self.completed.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devq::DevQueueError;
+    use crate::iomem::IOBuf;
+    use alloc::alloc::Layout;
+
+    const SECTOR: usize = 512;
+
+    /// Completes requests on flush and records what it got.
+    #[derive(Default)]
+    struct Disk {
+        requests: Vec<(BlockIo, usize)>,
+        done: VecDeque<IOBufChain>,
+    }
+
+    impl DevQueue for Disk {
+        fn enqueue(&mut self, bufs: IOBufChain) -> Result<(), IOBufChain> {
+            self.requests
+                .push((bufs.block_io.unwrap(), bufs.segments.len()));
+            self.done.push_back(bufs);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<usize, DevQueueError> {
+            Ok(self.done.len())
+        }
+
+        fn can_enqueue(&self, _how_many_seg: usize) -> bool {
+            true
+        }
+
+        fn dequeue(&mut self) -> Result<IOBufChain, DevQueueError> {
+            self.done.pop_front().ok_or(DevQueueError::QueueEmpty)
+        }
+
+        fn can_dequeue(&mut self, _exact: bool) -> usize {
+            self.done.len()
+        }
+    }
+
+    impl BlockDevice for Disk {
+        type Queue = Disk;
+
+        fn geometry(&self) -> BlockGeometry {
+            BlockGeometry {
+                sector_size: SECTOR,
+                sectors: 1024,
+                queue_depth: 2,
+                max_sectors: 64,
+                max_segments: 4,
+            }
+        }
+
+        fn queue(&mut self) -> &mut Disk {
+            self
+        }
+    }
+
+    fn bufs(sectors: usize) -> IOBufChain {
+        let mut chain = IOBufChain::new(0, 1).unwrap();
+        chain.append(
+            IOBuf::new(Layout::from_size_align(sectors * SECTOR, SECTOR).unwrap()).unwrap(),
+        );
+        chain
+    }
+
+    fn write(sector: u64) -> BlockIo {
+        BlockIo {
+            op: BlockOp::Write,
+            sector,
+        }
+    }
+
+    #[test]
+    fn merge_and_queue_depth() {
+        let mut queue = RequestQueue::with_queue_depth(Disk::default(), 8);
+        assert_eq!(queue.queue_depth(), 2);
+        assert!(matches!(
+            queue.submit(BlockOp::Read, 1020, bufs(8)),
+            Err((BlockError::OutOfRange { sector: 1020 }, _))
+        ));
+
+        let a = queue.submit(BlockOp::Write, 8, bufs(8)).unwrap();
+        let b = queue.submit(BlockOp::Write, 16, bufs(8)).unwrap();
+        let c = queue.submit(BlockOp::Write, 0, bufs(8)).unwrap();
+        let d = queue.submit(BlockOp::Read, 24, bufs(8)).unwrap();
+        let e = queue
+            .submit(BlockOp::Flush, 0, IOBufChain::new(0, 0).unwrap())
+            .unwrap();
+        let f = queue
+            .submit(BlockOp::Flush, 0, IOBufChain::new(0, 0).unwrap())
+            .unwrap();
+        assert_eq!(queue.pending(), 4);
+
+        assert_eq!(queue.dispatch().unwrap(), 2);
+        assert_eq!(queue.dispatch().unwrap(), 0);
+        assert_eq!(
+            queue.device().requests,
+            [
+                (write(0), 3),
+                (
+                    BlockIo {
+                        op: BlockOp::Read,
+                        sector: 24
+                    },
+                    1
+                )
+            ]
+        );
+
+        assert_eq!(queue.complete().unwrap(), 4);
+        let completions: Vec<(RequestId, BlockIo, usize)> =
+            core::iter::from_fn(|| queue.next_completion())
+                .map(|c| (c.id, c.io, c.bufs.segments[0].len()))
+                .collect();
+        assert_eq!(
+            completions[..3],
+            [
+                (c, write(0), 8 * SECTOR),
+                (a, write(8), 8 * SECTOR),
+                (b, write(16), 8 * SECTOR)
+            ]
+        );
+        assert_eq!(completions[3].0, d);
+
+        assert_eq!(queue.dispatch().unwrap(), 2);
+        assert_eq!(queue.complete().unwrap(), 2);
+        assert_eq!(queue.next_completion().unwrap().id, e);
+        assert_eq!(queue.next_completion().unwrap().id, f);
+        assert_eq!((queue.pending(), queue.inflight()), (0, 0));
+    }
+}
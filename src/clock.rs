@@ -0,0 +1,26 @@
+//! Monotonic time sources.
+
+use core::time::Duration;
+
+/// A monotonic clock.
+///
+/// On Unix, [`timedops::SystemClock`](crate::timedops::SystemClock) is the
+/// default, bare-metal code uses a timer device (e.g., the HPET on x86).
+pub trait Clock {
+    /// Time since an arbitrary (but fixed) point in the past.
+    fn now(&self) -> Duration;
+
+    /// Busy-waits for `duration`.
+    fn delay(&self, duration: Duration) {
+        let end = self.now() + duration;
+        while self.now() < end {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
@@ -1,9 +1,19 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
 use custom_error::custom_error;
 
 // library includes
+use crate::clock::Clock;
+use crate::fixed::FixedVec;
 use crate::iomem::IOBufChain;
+use crate::metrics;
 
-custom_error! {pub DevQueueError
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub DevQueueError
     BufferInvalid = "one of the supplied buffers was invalid",
     OutOfMemory = "the operation caused an out-of-memory condition",
     QueueFull = "the queue was full. Can't enqueue more buffers.",
@@ -63,6 +73,29 @@ pub trait DevQueue {
     ///   dequeue.
     fn dequeue(&mut self) -> Result<IOBufChain, DevQueueError>;
 
+    /// Dequeues up to `N` processed IOBufChains into `batch` without
+    /// allocating (e.g., to hand completions to the client in one go).
+    ///
+    /// # Returns
+    /// The number of IOBufChains added to `batch`.
+    fn dequeue_batch<const N: usize>(&mut self, batch: &mut FixedVec<IOBufChain, N>) -> usize
+    where
+        Self: Sized,
+    {
+        let mut dequeued = 0;
+        while !batch.is_full() && self.can_dequeue(false) > 0 {
+            match self.dequeue() {
+                Ok(bufs) => {
+                    // Can't fail, the batch isn't full
+                    let _ = batch.push(bufs);
+                    dequeued += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        dequeued
+    }
+
     /// Checks if there are buffers ready to be dequeued and returns the count
     /// of processed buffers.
     ///
@@ -75,4 +108,777 @@ pub trait DevQueue {
     /// - The number of IOBufChains that are ready to be dequeued (using
     ///   `dequeue`).
     fn can_dequeue(&mut self, exact: bool) -> usize;
+
+    /// The occupancy watermarks of the queue (if the queue supports them).
+    ///
+    /// Clients use this to configure the thresholds and install callbacks,
+    /// see [`Watermarks`].
+    fn watermarks(&mut self) -> Option<&mut Watermarks> {
+        None
+    }
+}
+
+/// Occupancy queries of a [`DevQueue`], for queues that track how many
+/// segments they hold.
+pub trait QueueOccupancy: DevQueue {
+    /// Returns the number of segments currently enqueued (i.e., handed to
+    /// the queue but not yet dequeued).
+    fn len(&self) -> usize;
+
+    /// Returns the total number of segments the queue can hold.
+    fn capacity(&self) -> usize;
+
+    /// Returns the number of segments that can still be enqueued.
+    fn free_slots(&self) -> usize {
+        self.capacity().saturating_sub(self.len())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Number of TX completions reclaimed per trigger by default.
+pub const DEFAULT_RECLAIM_BUDGET: usize = 32;
+
+/// When a TX queue reclaims buffers of completed transmissions.
+///
+/// Reclaiming on enqueue keeps interrupts off the TX path at the cost of
+/// holding on to buffers longer, reclaiming on interrupt frees them as soon
+/// as possible, and explicit reclamation leaves the decision to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReclaimPolicy {
+    /// Reclaim up to `budget` completions whenever a chain is enqueued.
+    OnEnqueue { budget: usize },
+    /// Reclaim up to `budget` completions when the queue's interrupt fires.
+    OnInterrupt { budget: usize },
+    /// Only reclaim when the host calls [`TxReclaim::poll_completions`].
+    Explicit,
+}
+
+impl Default for ReclaimPolicy {
+    fn default() -> Self {
+        ReclaimPolicy::OnEnqueue {
+            budget: DEFAULT_RECLAIM_BUDGET,
+        }
+    }
+}
+
+/// Reclamation of completed TX buffers according to a [`ReclaimPolicy`].
+///
+/// Queue implementations call [`TxReclaim::on_enqueue`] at the end of
+/// `enqueue` and [`TxReclaim::on_interrupt`] from their interrupt handler;
+/// both are no-ops unless the policy asks for reclamation at that point.
+pub trait TxReclaim: DevQueue {
+    fn reclaim_policy(&self) -> ReclaimPolicy;
+
+    fn set_reclaim_policy(&mut self, policy: ReclaimPolicy);
+
+    /// Takes ownership of the buffers of a completed transmission (e.g., to
+    /// return them to a pool).
+    fn complete(&mut self, bufs: IOBufChain);
+
+    /// Reclaims at most `budget` completed chains.
+    ///
+    /// # Returns
+    /// The number of chains handed to [`TxReclaim::complete`].
+    fn poll_completions(&mut self, budget: usize) -> usize {
+        let mut reclaimed = 0;
+        while reclaimed < budget && self.can_dequeue(false) > 0 {
+            match self.dequeue() {
+                Ok(bufs) => {
+                    self.complete(bufs);
+                    reclaimed += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        reclaimed
+    }
+
+    fn on_enqueue(&mut self) -> usize {
+        match self.reclaim_policy() {
+            ReclaimPolicy::OnEnqueue { budget } => self.poll_completions(budget),
+            _ => 0,
+        }
+    }
+
+    fn on_interrupt(&mut self) -> usize {
+        match self.reclaim_policy() {
+            ReclaimPolicy::OnInterrupt { budget } => self.poll_completions(budget),
+            _ => 0,
+        }
+    }
+}
+
+/// When a queue notifies the device (rings its doorbell) about newly
+/// enqueued buffers.
+///
+/// Every doorbell is an uncached MMIO write (a VM exit in a guest), which
+/// dominates the CPU cost at high packet rates. Batching trades a bounded
+/// amount of latency for fewer of them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NotifyPolicy {
+    /// Notify on every flush.
+    #[default]
+    Always,
+    /// Notify once `batch` chains are pending or the oldest pending chain
+    /// has waited for `max_delay`.
+    Batched { batch: usize, max_delay: Duration },
+}
+
+/// Whether a device that asked to be notified once the producer index
+/// passes `event` needs a notification for the move from `old` to `new`
+/// (`vring_need_event` of virtio's `EVENT_IDX` feature).
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// Doorbell counters of a [`Notifier`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NotifyStats {
+    /// Chains made available to the device.
+    pub chains: u64,
+    pub doorbells: u64,
+}
+
+/// Notification suppression according to a [`NotifyPolicy`] and, where
+/// the device publishes one, its event index.
+///
+/// Queue implementations call [`Notifier::publish`] in `flush` with the
+/// chains they made available and ring the doorbell if it returns true, and
+/// [`Notifier::poll`] from their poll loop (or a timer) so a partial batch
+/// isn't held back longer than the policy allows.
+///
+/// The crate doesn't implement virtqueues, so hooking this into a virtio TX
+/// path is left to the driver's virtqueue: with `EVENT_IDX` negotiated it
+/// passes the new `avail->idx` as the producer index and the `avail_event`
+/// the device published at the end of the used ring as `event`, and writes
+/// `avail->idx` before reading `avail_event` (with a full barrier in
+/// between) so it can't miss an update of the device.
+#[derive(Debug)]
+pub struct Notifier<C> {
+    policy: NotifyPolicy,
+    clock: C,
+    pending: usize,
+    /// When the oldest pending chain was published.
+    oldest: Option<Duration>,
+    /// Producer index at the last decision (for the event index).
+    last_idx: u16,
+    stats: NotifyStats,
+}
+
+impl<C: Clock> Notifier<C> {
+    pub fn new(policy: NotifyPolicy, clock: C) -> Self {
+        Notifier {
+            policy,
+            clock,
+            pending: 0,
+            oldest: None,
+            last_idx: 0,
+            stats: NotifyStats::default(),
+        }
+    }
+
+    pub fn policy(&self) -> NotifyPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: NotifyPolicy) {
+        self.policy = policy;
+    }
+
+    /// Chains published since the last doorbell.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn stats(&self) -> NotifyStats {
+        self.stats
+    }
+
+    /// Records that `count` chains were made available, moving the producer
+    /// index to `idx`. `event` is the device's event index (None unless
+    /// `EVENT_IDX` or an equivalent was negotiated).
+    ///
+    /// # Returns
+    /// Whether the queue has to ring its doorbell now.
+    pub fn publish(&mut self, count: usize, idx: u16, event: Option<u16>) -> bool {
+        if count > 0 {
+            self.stats.chains += count as u64;
+            self.pending += count;
+            if self.oldest.is_none() {
+                self.oldest = Some(self.clock.now());
+            }
+        }
+        self.decide(idx, event)
+    }
+
+    /// Checks whether pending chains have waited long enough (see
+    /// [`Notifier::publish`] for the arguments).
+    pub fn poll(&mut self, idx: u16, event: Option<u16>) -> bool {
+        self.decide(idx, event)
+    }
+
+    fn decide(&mut self, idx: u16, event: Option<u16>) -> bool {
+        let due = match (self.policy, self.oldest) {
+            (_, None) => false,
+            (NotifyPolicy::Always, Some(_)) => true,
+            (NotifyPolicy::Batched { batch, max_delay }, Some(oldest)) => {
+                self.pending >= batch || self.clock.now().saturating_sub(oldest) >= max_delay
+            }
+        };
+        if !due {
+            return false;
+        }
+
+        // A device that is still busy with earlier buffers will see these
+        // without being told.
+        let wanted = event.is_none_or(|event| need_event(event, idx, self.last_idx));
+        self.last_idx = idx;
+        self.pending = 0;
+        self.oldest = None;
+        if wanted {
+            self.stats.doorbells += 1;
+            metrics::DEVQ_DOORBELLS.inc();
+        }
+        wanted
+    }
+}
+
+/// High/low occupancy watermarks of a queue.
+///
+/// The high callback fires when the occupancy rises to the high watermark,
+/// the low callback fires once it has drained back to the low watermark.
+/// Between the two the queue is considered congested, e.g. a network stack
+/// stops transmitting on high and wakes TX on low.
+///
+/// Queue implementations call [`Watermarks::update`] with the new occupancy
+/// after every enqueue and dequeue.
+pub struct Watermarks {
+    low: usize,
+    high: usize,
+    congested: bool,
+    on_high: Option<Box<dyn FnMut() + Send>>,
+    on_low: Option<Box<dyn FnMut() + Send>>,
+}
+
+impl Watermarks {
+    /// Creates watermarks at `low` and `high` segments (`low < high`).
+    pub fn new(low: usize, high: usize) -> Self {
+        assert!(low < high, "low watermark must be below the high watermark");
+        Watermarks {
+            low,
+            high,
+            congested: false,
+            on_high: None,
+            on_low: None,
+        }
+    }
+
+    /// Creates watermarks at a quarter and three quarters of a queue of
+    /// `capacity` segments, the defaults of the queues that support them.
+    pub fn for_capacity(capacity: usize) -> Self {
+        let high = (capacity * 3 / 4).max(1);
+        Watermarks::new(capacity / 4, high)
+    }
+
+    pub fn set(&mut self, low: usize, high: usize) {
+        assert!(low < high, "low watermark must be below the high watermark");
+        self.low = low;
+        self.high = high;
+    }
+
+    pub fn low(&self) -> usize {
+        self.low
+    }
+
+    pub fn high(&self) -> usize {
+        self.high
+    }
+
+    /// Whether the occupancy reached the high watermark and hasn't drained
+    /// to the low watermark since.
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+
+    pub fn on_high(&mut self, f: impl FnMut() + Send + 'static) {
+        self.on_high = Some(Box::new(f));
+    }
+
+    pub fn on_low(&mut self, f: impl FnMut() + Send + 'static) {
+        self.on_low = Some(Box::new(f));
+    }
+
+    /// Updates the occupancy to `len` segments, invoking a callback if a
+    /// watermark was crossed.
+    pub fn update(&mut self, len: usize) {
+        if !self.congested && len >= self.high {
+            self.congested = true;
+            if let Some(f) = self.on_high.as_mut() {
+                f();
+            }
+        } else if self.congested && len <= self.low {
+            self.congested = false;
+            if let Some(f) = self.on_low.as_mut() {
+                f();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Watermarks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Watermarks")
+            .field("low", &self.low)
+            .field("high", &self.high)
+            .field("congested", &self.congested)
+            .finish()
+    }
+}
+
+/// Per queue-pair traffic counters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QueuePairStats {
+    pub tx_chains: u64,
+    pub tx_bytes: u64,
+    /// Chains handed back to the caller because the TX queue was full.
+    pub tx_full: u64,
+    pub rx_chains: u64,
+    pub rx_bytes: u64,
+    pub interrupts: u64,
+}
+
+/// A TX and an RX queue that share an interrupt vector.
+///
+/// This is the common model of multi-queue NICs: queue pair `n` consists of
+/// TX queue `n` and RX queue `n`, and both signal completions on the same
+/// MSI-X vector (typically pinned to the core that owns the pair).
+#[derive(Debug)]
+pub struct QueuePair<T, R> {
+    index: usize,
+    tx: T,
+    rx: R,
+    vector: Option<usize>,
+    stats: QueuePairStats,
+}
+
+impl<T: DevQueue, R: DevQueue> QueuePair<T, R> {
+    pub fn new(index: usize, tx: T, rx: R) -> Self {
+        QueuePair {
+            index,
+            tx,
+            rx,
+            vector: None,
+            stats: QueuePairStats::default(),
+        }
+    }
+
+    /// Sets the index of the MSI-X vector shared by both queues.
+    pub fn with_vector(mut self, vector: usize) -> Self {
+        self.vector = Some(vector);
+        self
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn vector(&self) -> Option<usize> {
+        self.vector
+    }
+
+    pub fn tx(&self) -> &T {
+        &self.tx
+    }
+
+    pub fn tx_mut(&mut self) -> &mut T {
+        &mut self.tx
+    }
+
+    pub fn rx(&self) -> &R {
+        &self.rx
+    }
+
+    pub fn rx_mut(&mut self) -> &mut R {
+        &mut self.rx
+    }
+
+    pub fn stats(&self) -> &QueuePairStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = QueuePairStats::default();
+    }
+
+    /// Enqueues `bufs` on the TX queue (see [`DevQueue::enqueue`]).
+    pub fn transmit(&mut self, bufs: IOBufChain) -> Result<(), IOBufChain> {
+        let bytes = chain_len(&bufs);
+        match self.tx.enqueue(bufs) {
+            Ok(()) => {
+                self.stats.tx_chains += 1;
+                self.stats.tx_bytes += bytes as u64;
+                metrics::DEVQ_ENQUEUES.inc();
+                Ok(())
+            }
+            Err(bufs) => {
+                self.stats.tx_full += 1;
+                metrics::DEVQ_QUEUE_FULL.inc();
+                Err(bufs)
+            }
+        }
+    }
+
+    /// Dequeues a received chain from the RX queue (see
+    /// [`DevQueue::dequeue`]).
+    pub fn receive(&mut self) -> Result<IOBufChain, DevQueueError> {
+        let bufs = self.rx.dequeue()?;
+        self.stats.rx_chains += 1;
+        self.stats.rx_bytes += chain_len(&bufs) as u64;
+        metrics::DEVQ_DEQUEUES.inc();
+        Ok(bufs)
+    }
+
+    /// Flushes both queues.
+    pub fn flush(&mut self) -> Result<(), DevQueueError> {
+        self.tx.flush()?;
+        self.rx.flush()?;
+        Ok(())
+    }
+
+    /// Accounts an interrupt on the shared vector.
+    ///
+    /// Returns how many chains are ready on the TX and RX queue.
+    pub fn interrupt(&mut self) -> (usize, usize) {
+        self.stats.interrupts += 1;
+        (self.tx.can_dequeue(false), self.rx.can_dequeue(false))
+    }
+
+    pub fn into_inner(self) -> (T, R) {
+        (self.tx, self.rx)
+    }
+}
+
+/// Chains a [`PollExecutor`] dequeues from a queue per visit by default.
+pub const DEFAULT_POLL_BUDGET: usize = 64;
+/// Upper bound of the idle spins of [`IdleBackoff::default`].
+pub const DEFAULT_MAX_IDLE_SPINS: u32 = 1024;
+
+/// How a [`PollExecutor`] waits after a round in which no queue had work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IdleBackoff {
+    /// Poll again right away (lowest latency, keeps the core busy).
+    None,
+    /// Spin with `spin_loop` hints (`pause` on x86), doubling the spins with
+    /// every idle round up to `max_spins`.
+    Spin { max_spins: u32 },
+    /// Wait for an event between idle rounds (`wfe` on aarch64, `pause`
+    /// elsewhere), see [`crate::wait_for_event`].
+    WaitForEvent,
+}
+
+impl Default for IdleBackoff {
+    fn default() -> Self {
+        IdleBackoff::Spin {
+            max_spins: DEFAULT_MAX_IDLE_SPINS,
+        }
+    }
+}
+
+/// Counters of a queue registered with a [`PollExecutor`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PollStats {
+    /// Times the queue was visited.
+    pub polls: u64,
+    pub chains: u64,
+    /// Visits that used up the whole budget (the queue may have more work).
+    pub exhausted: u64,
+}
+
+#[derive(Debug)]
+struct PolledQueue<Q> {
+    queue: Q,
+    budget: usize,
+    stats: PollStats,
+}
+
+/// A busy-polling loop over several queues, e.g., the RX queues of a
+/// bare-metal host without interrupts.
+///
+/// Every round visits the registered queues round-robin (starting one queue
+/// later each round) and hands at most the queue's budget of chains to the
+/// handler, so a busy queue can't starve the others. Rounds without work
+/// back off according to the [`IdleBackoff`].
+#[derive(Debug)]
+pub struct PollExecutor<Q> {
+    queues: Vec<PolledQueue<Q>>,
+    next: usize,
+    backoff: IdleBackoff,
+    idle_rounds: u32,
+}
+
+impl<Q: DevQueue> PollExecutor<Q> {
+    pub fn new(backoff: IdleBackoff) -> Self {
+        PollExecutor {
+            queues: Vec::new(),
+            next: 0,
+            backoff,
+            idle_rounds: 0,
+        }
+    }
+
+    /// Adds `queue`, dequeueing at most `budget` chains per visit.
+    ///
+    /// # Returns
+    /// The id the handler receives chains of this queue with.
+    pub fn register(&mut self, queue: Q, budget: usize) -> usize {
+        self.queues.push(PolledQueue {
+            queue,
+            budget: budget.max(1),
+            stats: PollStats::default(),
+        });
+        self.queues.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    pub fn queue_mut(&mut self, id: usize) -> Option<&mut Q> {
+        self.queues.get_mut(id).map(|q| &mut q.queue)
+    }
+
+    pub fn set_budget(&mut self, id: usize, budget: usize) {
+        if let Some(q) = self.queues.get_mut(id) {
+            q.budget = budget.max(1);
+        }
+    }
+
+    pub fn stats(&self, id: usize) -> Option<PollStats> {
+        self.queues.get(id).map(|q| q.stats)
+    }
+
+    /// Polls every queue once, calling `handler` with the id of the queue
+    /// and each dequeued chain.
+    ///
+    /// # Returns
+    /// The number of chains handled in this round.
+    pub fn poll<F: FnMut(usize, IOBufChain)>(&mut self, handler: &mut F) -> usize {
+        let count = self.queues.len();
+        let mut handled = 0;
+        for i in 0..count {
+            let id = (self.next + i) % count;
+            let q = &mut self.queues[id];
+            let mut chains = 0;
+            while chains < q.budget && q.queue.can_dequeue(false) > 0 {
+                match q.queue.dequeue() {
+                    Ok(bufs) => {
+                        handler(id, bufs);
+                        chains += 1;
+                    }
+                    Err(_) => break,
+                }
+            }
+            q.stats.polls += 1;
+            q.stats.chains += chains as u64;
+            if chains == q.budget {
+                q.stats.exhausted += 1;
+            }
+            handled += chains;
+        }
+        metrics::DEVQ_DEQUEUES.add(handled as u64);
+        if count > 0 {
+            self.next = (self.next + 1) % count;
+        }
+        handled
+    }
+
+    /// Polls until `stop` returns true (checked before every round), backing
+    /// off while the queues are idle.
+    pub fn run<F: FnMut(usize, IOBufChain)>(
+        &mut self,
+        mut handler: F,
+        mut stop: impl FnMut() -> bool,
+    ) {
+        while !stop() {
+            if self.poll(&mut handler) > 0 {
+                self.idle_rounds = 0;
+            } else {
+                self.idle();
+            }
+        }
+    }
+
+    fn idle(&mut self) {
+        self.idle_rounds = self.idle_rounds.saturating_add(1);
+        match self.backoff {
+            IdleBackoff::None => {}
+            IdleBackoff::Spin { max_spins } => {
+                let spins = 1u32
+                    .checked_shl(self.idle_rounds - 1)
+                    .unwrap_or(u32::MAX)
+                    .min(max_spins);
+                for _ in 0..spins {
+                    core::hint::spin_loop();
+                }
+            }
+            IdleBackoff::WaitForEvent => crate::wait_for_event(),
+        }
+    }
+
+    pub fn into_queues(self) -> Vec<Q> {
+        self.queues.into_iter().map(|q| q.queue).collect()
+    }
+}
+
+fn chain_len(bufs: &IOBufChain) -> usize {
+    bufs.segments.iter().map(|buf| buf.len()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn watermark_hysteresis() {
+        let highs = Arc::new(AtomicUsize::new(0));
+        let lows = Arc::new(AtomicUsize::new(0));
+        let mut wm = Watermarks::new(2, 6);
+        let h = highs.clone();
+        wm.on_high(move || {
+            h.fetch_add(1, Ordering::Relaxed);
+        });
+        let l = lows.clone();
+        wm.on_low(move || {
+            l.fetch_add(1, Ordering::Relaxed);
+        });
+
+        for len in [1, 5, 6, 7, 4, 6, 3, 2, 1, 6] {
+            wm.update(len);
+        }
+        assert_eq!(highs.load(Ordering::Relaxed), 2);
+        assert_eq!(lows.load(Ordering::Relaxed), 1);
+        assert!(wm.is_congested());
+    }
+
+    struct FakeClock(core::cell::Cell<Duration>);
+
+    impl Clock for &FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    /// Has `ready` chains to dequeue.
+    struct Ready(usize);
+
+    impl DevQueue for Ready {
+        fn enqueue(&mut self, bufs: IOBufChain) -> Result<(), IOBufChain> {
+            Err(bufs)
+        }
+
+        fn flush(&mut self) -> Result<usize, DevQueueError> {
+            Ok(0)
+        }
+
+        fn can_enqueue(&self, _how_many_seg: usize) -> bool {
+            false
+        }
+
+        fn dequeue(&mut self) -> Result<IOBufChain, DevQueueError> {
+            self.0 = self.0.checked_sub(1).ok_or(DevQueueError::QueueEmpty)?;
+            IOBufChain::new(0, 0).map_err(|_| DevQueueError::OutOfMemory)
+        }
+
+        fn can_dequeue(&mut self, _exact: bool) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn poll_budgets() {
+        let dequeues = metrics::DEVQ_DEQUEUES.get();
+        let mut executor = PollExecutor::new(IdleBackoff::None);
+        let busy = executor.register(Ready(10), 4);
+        let quiet = executor.register(Ready(1), DEFAULT_POLL_BUDGET);
+
+        let mut order = Vec::new();
+        assert_eq!(executor.poll(&mut |id, _| order.push(id)), 5);
+        assert_eq!(order, [busy, busy, busy, busy, quiet]);
+        // The next round starts at the second queue
+        order.clear();
+        assert_eq!(executor.poll(&mut |id, _| order.push(id)), 4);
+        assert_eq!(order, [busy; 4]);
+
+        let mut rounds = 0;
+        let mut handled = 0;
+        executor.run(
+            |_, _| handled += 1,
+            || {
+                rounds += 1;
+                rounds > 3
+            },
+        );
+        assert_eq!(handled, 2);
+        assert_eq!(
+            executor.stats(busy),
+            Some(PollStats {
+                polls: 5,
+                chains: 10,
+                exhausted: 2
+            })
+        );
+        assert_eq!(executor.stats(quiet).unwrap().exhausted, 0);
+        // Other tests may dequeue concurrently
+        assert!(metrics::DEVQ_DEQUEUES.get() - dequeues >= 11);
+    }
+
+    #[test]
+    fn notification_batching() {
+        let clock = FakeClock(core::cell::Cell::new(Duration::ZERO));
+        let policy = NotifyPolicy::Batched {
+            batch: 4,
+            max_delay: Duration::from_micros(50),
+        };
+        let mut notifier = Notifier::new(policy, &clock);
+        let doorbells = metrics::DEVQ_DOORBELLS.get();
+
+        assert!(!notifier.publish(1, 1, None));
+        assert!(!notifier.publish(2, 3, None));
+        assert!(notifier.publish(1, 4, None));
+        assert!(!notifier.publish(1, 5, None));
+        clock.0.set(Duration::from_micros(60));
+        assert!(notifier.poll(5, None));
+        assert!(!notifier.poll(5, None));
+
+        // The device asked to be told once index 10 is passed
+        notifier.set_policy(NotifyPolicy::Always);
+        assert!(!notifier.publish(3, 8, Some(10)));
+        assert!(notifier.publish(4, 12, Some(10)));
+        assert_eq!(
+            notifier.stats(),
+            NotifyStats {
+                chains: 12,
+                doorbells: 3
+            }
+        );
+        assert!(metrics::DEVQ_DOORBELLS.get() - doorbells >= 3);
+
+        assert!(need_event(0xffff, 0, 0xfffe));
+        assert!(!need_event(5, 5, 0));
+    }
 }
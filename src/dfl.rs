@@ -0,0 +1,397 @@
+//! Device Feature Lists of FPGA cards.
+//!
+//! Intel's FPGA cards (and other DFL-based accelerators) don't put their
+//! registers at fixed offsets. BAR 0 starts with a linked list of feature
+//! headers (DFHs): the FPGA Management Engine (FME) with its private
+//! features (thermal, power, partial reconfiguration, ...), followed by
+//! ports. Each port has its own list of private features and the
+//! Accelerator Function Unit (AFU) the user logic is loaded into. Ports can
+//! also live in other BARs, the FME points to them.
+//!
+//! [`FeatureList`] walks one list, [`enumerate`] follows the FME's port
+//! table and the AFU pointers to discover every feature of the card.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+/// Upper bound of features in one list, to stop walking broken lists.
+pub const DFL_MAX_FEATURES: usize = 256;
+/// Ports the FME's port table has room for.
+pub const DFL_MAX_PORTS: usize = 4;
+
+/// Offset of the low half of the GUID of a FIU or AFU.
+const GUID_L: u64 = 0x8;
+/// Offset of the high half of the GUID of a FIU or AFU.
+const GUID_H: u64 = 0x10;
+/// Offset of the pointer from a FIU to its AFU (bits 23:0).
+const NEXT_AFU: u64 = 0x18;
+/// Offset of the FME's port table.
+const FME_PORT_OFFSET: u64 = 0x38;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub DflError
+    OutOfBounds{bar: u8, offset: u64} = "feature header at {offset:#x} is outside of BAR {bar}",
+    Unterminated{bar: u8, offset: u64} = "feature list in BAR {bar} ends at {offset:#x} without end-of-list",
+    TooManyFeatures{bar: u8} = "feature list in BAR {bar} is too long (loop?)",
+}
+
+/// Access to the BARs the feature lists are in.
+pub trait DflRegisters {
+    /// Reads the 64-bit register at `offset` of BAR `bar`, or `None` if the
+    /// BAR isn't mapped or is too small.
+    fn read64(&mut self, bar: u8, offset: u64) -> Option<u64>;
+}
+
+/// Type of a feature (DFH bits 63:60).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FeatureType {
+    /// An Accelerator Function Unit.
+    Afu,
+    /// A private feature of the FME or a port.
+    Private,
+    /// A FPGA Interface Unit, i.e., the FME or a port.
+    Fiu,
+    Unknown(u8),
+}
+
+impl From<u8> for FeatureType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x1 => FeatureType::Afu,
+            0x3 => FeatureType::Private,
+            0x4 => FeatureType::Fiu,
+            other => FeatureType::Unknown(other),
+        }
+    }
+}
+
+/// A Device Feature Header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Dfh(pub u64);
+
+impl Dfh {
+    /// Feature ID (FIU: 0 is the FME, 1 a port; private features: e.g.,
+    /// 1 thermal management, 5 partial reconfiguration).
+    pub fn id(&self) -> u16 {
+        self.0.get_bits(0..12) as u16
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.0.get_bits(12..16) as u8
+    }
+
+    /// Offset of the next header, relative to this one.
+    pub fn next(&self) -> u64 {
+        self.0.get_bits(16..40)
+    }
+
+    /// Whether this is the last feature of the list.
+    pub fn end_of_list(&self) -> bool {
+        self.0.get_bit(40)
+    }
+
+    /// Version of the header format.
+    pub fn version(&self) -> u8 {
+        self.0.get_bits(52..60) as u8
+    }
+
+    pub fn feature_type(&self) -> FeatureType {
+        FeatureType::from(self.0.get_bits(60..64) as u8)
+    }
+}
+
+/// Kind of a FIU, by its feature ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FiuKind {
+    /// FPGA Management Engine.
+    Fme,
+    Port,
+    Unknown(u16),
+}
+
+impl From<u16> for FiuKind {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => FiuKind::Fme,
+            1 => FiuKind::Port,
+            other => FiuKind::Unknown(other),
+        }
+    }
+}
+
+/// A feature found in a list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Feature {
+    pub bar: u8,
+    /// Offset of the header in the BAR (the feature's registers follow it).
+    pub offset: u64,
+    pub header: Dfh,
+    /// GUID of FIUs and AFUs (which identifies the loaded AFU image).
+    pub guid: Option<u128>,
+}
+
+impl Feature {
+    pub fn feature_type(&self) -> FeatureType {
+        self.header.feature_type()
+    }
+
+    /// The kind of a FIU, `None` for other features.
+    pub fn fiu(&self) -> Option<FiuKind> {
+        match self.feature_type() {
+            FeatureType::Fiu => Some(FiuKind::from(self.header.id())),
+            _ => None,
+        }
+    }
+}
+
+/// Iterates over one feature list, from its first header until the one
+/// marked end-of-list.
+///
+/// A broken list (header outside of the BAR, no end marker, too long) ends
+/// the iteration with an error.
+pub struct FeatureList<'r, R: ?Sized> {
+    registers: &'r mut R,
+    bar: u8,
+    next: Option<u64>,
+    seen: usize,
+}
+
+impl<'r, R: DflRegisters + ?Sized> FeatureList<'r, R> {
+    /// Walks the list starting at `offset` of BAR `bar`.
+    pub fn new(registers: &'r mut R, bar: u8, offset: u64) -> Self {
+        FeatureList {
+            registers,
+            bar,
+            next: Some(offset),
+            seen: 0,
+        }
+    }
+
+    fn read(&mut self, offset: u64) -> Result<u64, DflError> {
+        let bar = self.bar;
+        self.registers
+            .read64(bar, offset)
+            .ok_or(DflError::OutOfBounds { bar, offset })
+    }
+
+    fn feature(&mut self, offset: u64) -> Result<Feature, DflError> {
+        let header = Dfh(self.read(offset)?);
+        let guid = match header.feature_type() {
+            FeatureType::Afu | FeatureType::Fiu => {
+                let low = self.read(offset + GUID_L)? as u128;
+                let high = self.read(offset + GUID_H)? as u128;
+                Some(high << 64 | low)
+            }
+            _ => None,
+        };
+        Ok(Feature {
+            bar: self.bar,
+            offset,
+            header,
+            guid,
+        })
+    }
+}
+
+impl<'r, R: DflRegisters + ?Sized> Iterator for FeatureList<'r, R> {
+    type Item = Result<Feature, DflError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.next.take()?;
+        if self.seen == DFL_MAX_FEATURES {
+            return Some(Err(DflError::TooManyFeatures { bar: self.bar }));
+        }
+        self.seen += 1;
+        let feature = match self.feature(offset) {
+            Ok(feature) => feature,
+            Err(e) => return Some(Err(e)),
+        };
+        if !feature.header.end_of_list() {
+            // Offsets are relative and positive, so a list can't loop back
+            // onto itself, only run on forever if it has no end marker
+            match feature.header.next() {
+                0 => {
+                    return Some(Err(DflError::Unterminated {
+                        bar: self.bar,
+                        offset,
+                    }))
+                }
+                next => self.next = Some(offset + next),
+            }
+        }
+        Some(Ok(feature))
+    }
+}
+
+/// The pointer from a FIU to its AFU (0 if it has none).
+pub fn afu_offset<R: DflRegisters + ?Sized>(
+    registers: &mut R,
+    fiu: &Feature,
+) -> Result<u64, DflError> {
+    let offset = fiu.offset + NEXT_AFU;
+    registers
+        .read64(fiu.bar, offset)
+        .map(|value| value.get_bits(0..24))
+        .ok_or(DflError::OutOfBounds {
+            bar: fiu.bar,
+            offset,
+        })
+}
+
+/// The ports of an FME, as (BAR, offset) of their lists.
+pub fn fme_ports<R: DflRegisters + ?Sized>(
+    registers: &mut R,
+    fme: &Feature,
+) -> Result<[Option<(u8, u64)>; DFL_MAX_PORTS], DflError> {
+    let mut ports = [None; DFL_MAX_PORTS];
+    for (index, port) in ports.iter_mut().enumerate() {
+        let offset = fme.offset + FME_PORT_OFFSET + index as u64 * 8;
+        let value = registers
+            .read64(fme.bar, offset)
+            .ok_or(DflError::OutOfBounds {
+                bar: fme.bar,
+                offset,
+            })?;
+        if value.get_bit(60) {
+            *port = Some((value.get_bits(32..35) as u8, value.get_bits(0..24)));
+        }
+    }
+    Ok(ports)
+}
+
+/// Discovers all features of a card whose first list starts at `offset` of
+/// BAR `bar` (usually BAR 0, offset 0).
+///
+/// Follows the port table of the FME (ports listed there are walked only
+/// once, also if they follow the FME in the same list) and the AFU pointer
+/// of every FIU.
+#[cfg(feature = "alloc")]
+pub fn enumerate<R: DflRegisters + ?Sized>(
+    registers: &mut R,
+    bar: u8,
+    offset: u64,
+) -> Result<Vec<Feature>, DflError> {
+    let mut features: Vec<Feature> = Vec::new();
+    let mut lists = Vec::from([(bar, offset)]);
+    while let Some((bar, offset)) = lists.pop() {
+        if features.iter().any(|f| f.bar == bar && f.offset == offset) {
+            continue;
+        }
+        let found =
+            FeatureList::new(registers, bar, offset).collect::<Result<Vec<Feature>, DflError>>()?;
+        for feature in found.iter() {
+            if feature.fiu() == Some(FiuKind::Fme) {
+                lists.extend(fme_ports(registers, feature)?.iter().flatten());
+            }
+            if feature.feature_type() == FeatureType::Fiu {
+                match afu_offset(registers, feature)? {
+                    0 => {}
+                    afu => lists.push((feature.bar, feature.offset + afu)),
+                }
+            }
+            trace!(
+                "DFL feature {:?} {:#x} at BAR {} offset {:#x}",
+                feature.feature_type(),
+                feature.header.id(),
+                feature.bar,
+                feature.offset
+            );
+        }
+        for feature in found {
+            // A list can run into one walked before
+            if !features
+                .iter()
+                .any(|f| f.bar == feature.bar && f.offset == feature.offset)
+            {
+                features.push(feature);
+            }
+        }
+    }
+    features.sort_by_key(|f| (f.bar, f.offset));
+    Ok(features)
+}
+
+// `enumerate` and the mock BARs need alloc
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+
+    struct Bars(BTreeMap<(u8, u64), u64>, u64);
+
+    impl DflRegisters for Bars {
+        fn read64(&mut self, bar: u8, offset: u64) -> Option<u64> {
+            if offset >= self.1 {
+                return None;
+            }
+            Some(self.0.get(&(bar, offset)).copied().unwrap_or(0))
+        }
+    }
+
+    fn dfh(feature_type: u64, id: u64, next: u64, eol: bool) -> u64 {
+        feature_type << 60 | 1 << 52 | (eol as u64) << 40 | next << 16 | id
+    }
+
+    #[test]
+    fn card() {
+        let mut bars = Bars(BTreeMap::new(), 0x10_0000);
+        // FME with a thermal feature, then port 0 in the same list
+        bars.0.insert((0, 0x0), dfh(4, 0, 0x1000, false));
+        bars.0.insert((0, 0x8), 0x1111);
+        bars.0.insert((0, 0x10), 0x2222);
+        bars.0.insert((0, 0x38), 1 << 60 | 0x2000);
+        bars.0.insert((0, 0x40), 1 << 60 | 2 << 32);
+        bars.0.insert((0, 0x1000), dfh(3, 1, 0x1000, false));
+        bars.0.insert((0, 0x2000), dfh(4, 1, 0, true));
+        bars.0.insert((0, 0x2018), 0x1_0000);
+        bars.0.insert((0, 0x1_2000), dfh(1, 0, 0, true));
+        bars.0.insert((0, 0x1_2010), 0xabcd);
+        // Port 1 in BAR 2, without an AFU
+        bars.0.insert((2, 0x0), dfh(4, 1, 0, true));
+
+        let fme = FeatureList::new(&mut bars, 0, 0).next().unwrap().unwrap();
+        assert_eq!(fme.fiu(), Some(FiuKind::Fme));
+        assert_eq!(fme.guid, Some(0x2222 << 64 | 0x1111));
+        assert_eq!(fme.header.version(), 1);
+
+        let features = enumerate(&mut bars, 0, 0).unwrap();
+        let found: Vec<(u8, u64, FeatureType)> = features
+            .iter()
+            .map(|f| (f.bar, f.offset, f.feature_type()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (0, 0x0, FeatureType::Fiu),
+                (0, 0x1000, FeatureType::Private),
+                (0, 0x2000, FeatureType::Fiu),
+                (0, 0x1_2000, FeatureType::Afu),
+                (2, 0x0, FeatureType::Fiu),
+            ]
+        );
+        assert_eq!(features[3].guid, Some(0xabcd << 64));
+
+        // A list running past the BAR or without an end marker
+        bars.0.insert((2, 0x0), dfh(4, 1, 0x10_0000, false));
+        assert!(matches!(
+            enumerate(&mut bars, 0, 0),
+            Err(DflError::OutOfBounds {
+                bar: 2,
+                offset: 0x10_0000
+            })
+        ));
+        bars.0.insert((2, 0x0), dfh(4, 1, 0, false));
+        assert!(matches!(
+            enumerate(&mut bars, 0, 0),
+            Err(DflError::Unterminated { bar: 2, offset: 0 })
+        ));
+    }
+}
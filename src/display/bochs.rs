@@ -0,0 +1,172 @@
+//! The Bochs/QEMU display adapter (`-device bochs-display` or `-vga std`).
+//!
+//! The video memory is BAR 0, BAR 2 holds the (Bochs) VBE "DISPI" registers
+//! at 0x500 and, for the VGA variant, the legacy VGA registers at 0x400.
+//! Setting a mode only takes the resolution, the adapter then scans out the
+//! start of the video memory as a linear 32 bpp framebuffer.
+
+use bit_field::BitField;
+
+use super::{DisplayError, Framebuffer};
+use crate::arch::VAddr;
+use crate::pci::PciDeviceHandle;
+
+pub const BOCHS_VENDOR_ID: u16 = 0x1234;
+pub const BOCHS_DEVICE_ID: u16 = 0x1111;
+
+/// Offsets of the register blocks in BAR 2 (the VGA registers start with
+/// IO port 0x3c0).
+const VGA_IOPORTS: usize = 0x400;
+const DISPI_REGS: usize = 0x500;
+
+/// DISPI registers (16-bit, by index).
+const DISPI_INDEX_ID: usize = 0x0;
+const DISPI_INDEX_XRES: usize = 0x1;
+const DISPI_INDEX_YRES: usize = 0x2;
+const DISPI_INDEX_BPP: usize = 0x3;
+const DISPI_INDEX_ENABLE: usize = 0x4;
+const DISPI_INDEX_BANK: usize = 0x5;
+const DISPI_INDEX_VIRT_WIDTH: usize = 0x6;
+const DISPI_INDEX_VIRT_HEIGHT: usize = 0x7;
+const DISPI_INDEX_X_OFFSET: usize = 0x8;
+const DISPI_INDEX_Y_OFFSET: usize = 0x9;
+
+/// Oldest DISPI version with linear framebuffers and 32 bpp.
+const DISPI_ID_MIN: u16 = 0xb0c2;
+const DISPI_ID_MAX: u16 = 0xb0c5;
+
+/// ENABLE bits.
+const DISPI_ENABLED: usize = 0;
+const DISPI_LFB_ENABLED: usize = 6;
+
+/// VGA attribute controller address register (port 0x3c0) and its
+/// "palette address source" bit, which has to be set for the screen to show
+/// anything.
+const VGA_ATT_W: usize = 0x0;
+const VGA_AR_ENABLE_DISPLAY: u8 = 0x20;
+
+const BPP: u32 = 32;
+
+/// A Bochs display adapter.
+pub struct BochsDisplay {
+    fb: VAddr,
+    fb_size: usize,
+    mmio: VAddr,
+    /// Current resolution, if a mode is set.
+    mode: Option<(u32, u32)>,
+}
+
+impl BochsDisplay {
+    /// # Safety
+    /// `fb` must be the mapping of BAR 0 (`fb_size` bytes) and `mmio` of BAR
+    /// 2 of a Bochs display adapter.
+    pub unsafe fn new(
+        fb: VAddr,
+        fb_size: usize,
+        mmio: VAddr,
+    ) -> Result<BochsDisplay, DisplayError> {
+        let display = BochsDisplay {
+            fb,
+            fb_size,
+            mmio,
+            mode: None,
+        };
+        let id = display.read_dispi(DISPI_INDEX_ID);
+        if !(DISPI_ID_MIN..=DISPI_ID_MAX).contains(&id) {
+            warn!("bochs: unsupported DISPI version {:#x}", id);
+            return Err(DisplayError::NotSupported);
+        }
+        Ok(display)
+    }
+
+    /// Uses the BARs mapped by the builder (see
+    /// [`PciDriverBuilder::map_bars`](crate::pci::PciDriverBuilder::map_bars)).
+    pub fn from_handle(handle: &PciDeviceHandle) -> Result<BochsDisplay, DisplayError> {
+        let device = handle.device();
+        if device.vendor_id() != BOCHS_VENDOR_ID || device.device_id() != BOCHS_DEVICE_ID {
+            return Err(DisplayError::NotSupported);
+        }
+        let fb = handle.bar(0).ok_or(DisplayError::MissingBar { index: 0 })?;
+        let mmio = handle.bar(2).ok_or(DisplayError::MissingBar { index: 2 })?;
+        // Safety: the BARs belong to a Bochs display adapter
+        unsafe { BochsDisplay::new(fb.vaddr, fb.bar.size as usize, mmio.vaddr) }
+    }
+
+    fn read_dispi(&self, index: usize) -> u16 {
+        // Safety: `mmio` points to BAR 2 (see `new`)
+        unsafe { core::ptr::read_volatile((self.mmio + DISPI_REGS + index * 2).as_ptr::<u16>()) }
+    }
+
+    fn write_dispi(&mut self, index: usize, value: u16) {
+        // Safety: `mmio` points to BAR 2 (see `new`)
+        unsafe {
+            core::ptr::write_volatile(
+                (self.mmio + DISPI_REGS + index * 2).as_mut_ptr::<u16>(),
+                value,
+            )
+        };
+    }
+
+    /// Size of the video memory in bytes.
+    pub fn video_memory(&self) -> usize {
+        self.fb_size
+    }
+
+    /// The current resolution, if a mode is set.
+    pub fn mode(&self) -> Option<(u32, u32)> {
+        self.mode
+    }
+
+    /// Switches to `width`x`height` at 32 bpp and returns the framebuffer.
+    pub fn set_mode(&mut self, width: u32, height: u32) -> Result<Framebuffer<'_>, DisplayError> {
+        let size = width as u64 * height as u64 * (BPP / 8) as u64;
+        let valid = 1..=u16::MAX as u32;
+        if !valid.contains(&width) || !valid.contains(&height) || size > self.fb_size as u64 {
+            return Err(DisplayError::ModeTooLarge { width, height });
+        }
+
+        self.write_dispi(DISPI_INDEX_ENABLE, 0);
+        self.write_dispi(DISPI_INDEX_BPP, BPP as u16);
+        self.write_dispi(DISPI_INDEX_XRES, width as u16);
+        self.write_dispi(DISPI_INDEX_YRES, height as u16);
+        self.write_dispi(DISPI_INDEX_BANK, 0);
+        self.write_dispi(DISPI_INDEX_VIRT_WIDTH, width as u16);
+        self.write_dispi(DISPI_INDEX_VIRT_HEIGHT, height as u16);
+        self.write_dispi(DISPI_INDEX_X_OFFSET, 0);
+        self.write_dispi(DISPI_INDEX_Y_OFFSET, 0);
+        let mut enable = 0u16;
+        enable.set_bit(DISPI_ENABLED, true);
+        enable.set_bit(DISPI_LFB_ENABLED, true);
+        self.write_dispi(DISPI_INDEX_ENABLE, enable);
+
+        // Unblank (only does something on the VGA variant)
+        // Safety: `mmio` points to BAR 2 (see `new`)
+        unsafe {
+            core::ptr::write_volatile(
+                (self.mmio + VGA_IOPORTS + VGA_ATT_W).as_mut_ptr::<u8>(),
+                VGA_AR_ENABLE_DISPLAY,
+            )
+        };
+
+        info!("bochs: mode set to {}x{}", width, height);
+        self.mode = Some((width, height));
+        Ok(self.framebuffer().expect("mode is set"))
+    }
+
+    /// The framebuffer of the current mode.
+    pub fn framebuffer(&mut self) -> Option<Framebuffer<'_>> {
+        let (width, height) = self.mode?;
+        // Safety: the mode fits in the video memory (checked by `set_mode`),
+        // which is mapped at `fb` (see `new`)
+        let pixels = unsafe {
+            core::slice::from_raw_parts_mut(self.fb.as_mut_ptr::<u32>(), (width * height) as usize)
+        };
+        Some(Framebuffer::new(pixels, width, height, width))
+    }
+
+    /// Turns the display off.
+    pub fn disable(&mut self) {
+        self.write_dispi(DISPI_INDEX_ENABLE, 0);
+        self.mode = None;
+    }
+}
@@ -0,0 +1,95 @@
+//! Linear framebuffers.
+
+use custom_error::custom_error;
+
+#[cfg(feature = "alloc")]
+pub mod bochs;
+
+#[cfg(feature = "alloc")]
+pub use bochs::BochsDisplay;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub DisplayError
+    NotSupported = "the device isn't a supported display",
+    MissingBar{index: u8} = "BAR {index} isn't mapped",
+    ModeTooLarge{width: u32, height: u32} = "{width}x{height} doesn't fit in the video memory",
+}
+
+/// A 32 bpp (xRGB 8:8:8:8) framebuffer.
+pub struct Framebuffer<'a> {
+    pixels: &'a mut [u32],
+    width: u32,
+    height: u32,
+    /// Pixels from the start of one line to the next.
+    stride: u32,
+}
+
+impl<'a> Framebuffer<'a> {
+    /// Wraps `pixels`, which must hold `height` lines of `stride` pixels.
+    pub fn new(pixels: &'a mut [u32], width: u32, height: u32, stride: u32) -> Framebuffer<'a> {
+        assert!(width <= stride && pixels.len() >= (stride * height) as usize);
+        Framebuffer {
+            pixels,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
+    /// The raw pixel buffer (`height` lines of `stride` pixels).
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        self.pixels
+    }
+
+    /// Sets pixel (`x`, `y`) to `color` (0x00RRGGBB), ignored if it is off
+    /// screen.
+    pub fn put_pixel(&mut self, x: u32, y: u32, color: u32) {
+        if x < self.width && y < self.height {
+            self.pixels[(y * self.stride + x) as usize] = color;
+        }
+    }
+
+    /// Fills a rectangle (clipped to the screen).
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: u32) {
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        for line in y.min(y_end)..y_end {
+            let start = (line * self.stride) as usize;
+            self.pixels[start + x.min(x_end) as usize..start + x_end as usize].fill(color);
+        }
+    }
+
+    pub fn clear(&mut self, color: u32) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clipping() {
+        let mut pixels = [0u32; 8 * 4];
+        let mut fb = Framebuffer::new(&mut pixels, 6, 4, 8);
+        fb.fill_rect(4, 2, 10, 10, 0xff);
+        fb.put_pixel(6, 0, 0xff);
+        fb.put_pixel(0, 0, 0xaa);
+        assert_eq!(pixels[0], 0xaa);
+        assert_eq!(&pixels[16..24], &[0, 0, 0, 0, 0xff, 0xff, 0, 0]);
+        assert_eq!(pixels.iter().filter(|&&p| p == 0xff).count(), 4);
+    }
+}
@@ -0,0 +1,231 @@
+//! Accounting of DMA memory per device, and leak detection.
+//!
+//! [`DmaAllocator`](crate::iomem::DmaAllocator)s tagged with a device (as
+//! handed out by [`PciDeviceHandle`](crate::pci::PciDeviceHandle)) keep
+//! per-device statistics. With the debug mode enabled ([`set_enabled`])
+//! every live allocation and mapping is recorded as well, so
+//! [`report_leaks`] can list what a driver forgot to free when it detaches.
+//! Backends that map memory for devices (e.g., through an IOMMU) report
+//! their mappings with [`track_map`] and [`track_unmap`].
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::pci::PCIAddress;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Per-device statistics.
+static STATS: Mutex<Vec<(Option<PCIAddress>, DmaStats)>> = Mutex::new(Vec::new());
+
+/// Live allocations and mappings (only while enabled).
+static LIVE: Mutex<Vec<DmaRecord>> = Mutex::new(Vec::new());
+
+/// DMA statistics of a device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DmaStats {
+    pub allocations: u64,
+    pub frees: u64,
+    pub maps: u64,
+    pub unmaps: u64,
+    /// Bytes currently allocated.
+    pub bytes: usize,
+    /// The most bytes allocated at the same time.
+    pub peak_bytes: usize,
+}
+
+impl DmaStats {
+    /// Allocations and mappings that weren't freed (or unmapped) yet.
+    pub fn outstanding(&self) -> u64 {
+        (self.allocations - self.frees) + (self.maps - self.unmaps)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DmaRecordKind {
+    Allocation,
+    Mapping,
+}
+
+/// A live allocation or mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DmaRecord {
+    pub device: Option<PCIAddress>,
+    pub kind: DmaRecordKind,
+    /// Virtual address of an allocation, IO address of a mapping.
+    pub address: u64,
+    pub size: usize,
+}
+
+/// Turns recording of live allocations and mappings on or off (statistics
+/// are always kept).
+///
+/// Only allocations made while enabled can be reported as leaks.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        LIVE.lock().clear();
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn update_stats(device: Option<PCIAddress>, f: impl FnOnce(&mut DmaStats)) {
+    let mut stats = STATS.lock();
+    match stats.iter_mut().find(|(d, _)| *d == device) {
+        Some((_, s)) => f(s),
+        None => {
+            let mut s = DmaStats::default();
+            f(&mut s);
+            stats.push((device, s));
+        }
+    }
+}
+
+fn record(record: DmaRecord) {
+    if is_enabled() {
+        LIVE.lock().push(record);
+    }
+}
+
+fn forget(device: Option<PCIAddress>, kind: DmaRecordKind, address: u64) {
+    if is_enabled() {
+        let mut live = LIVE.lock();
+        if let Some(index) = live
+            .iter()
+            .position(|r| r.device == device && r.kind == kind && r.address == address)
+        {
+            live.swap_remove(index);
+        }
+    }
+}
+
+pub(crate) fn track_alloc(device: Option<PCIAddress>, address: u64, size: usize) {
+    update_stats(device, |s| {
+        s.allocations += 1;
+        s.bytes += size;
+        s.peak_bytes = s.peak_bytes.max(s.bytes);
+    });
+    record(DmaRecord {
+        device,
+        kind: DmaRecordKind::Allocation,
+        address,
+        size,
+    });
+}
+
+pub(crate) fn track_free(device: Option<PCIAddress>, address: u64, size: usize) {
+    update_stats(device, |s| {
+        s.frees += 1;
+        s.bytes = s.bytes.saturating_sub(size);
+    });
+    forget(device, DmaRecordKind::Allocation, address);
+}
+
+/// Records that `size` bytes were mapped for `device` at IO address
+/// `ioaddr`.
+pub fn track_map(device: Option<PCIAddress>, ioaddr: u64, size: usize) {
+    update_stats(device, |s| s.maps += 1);
+    record(DmaRecord {
+        device,
+        kind: DmaRecordKind::Mapping,
+        address: ioaddr,
+        size,
+    });
+}
+
+/// Records that the mapping at IO address `ioaddr` was removed.
+pub fn track_unmap(device: Option<PCIAddress>, ioaddr: u64) {
+    update_stats(device, |s| s.unmaps += 1);
+    forget(device, DmaRecordKind::Mapping, ioaddr);
+}
+
+/// The statistics of `device` (None for untagged allocators).
+pub fn stats(device: Option<PCIAddress>) -> DmaStats {
+    STATS
+        .lock()
+        .iter()
+        .find(|(d, _)| *d == device)
+        .map_or(DmaStats::default(), |(_, s)| *s)
+}
+
+/// The live allocations and mappings of `device` (empty unless enabled).
+pub fn live(device: Option<PCIAddress>) -> Vec<DmaRecord> {
+    LIVE.lock()
+        .iter()
+        .filter(|r| r.device == device)
+        .copied()
+        .collect()
+}
+
+/// Logs the live allocations and mappings of all devices.
+pub fn dump() {
+    for r in LIVE.lock().iter() {
+        info!(
+            "{:?}: {:?} at {:#x} ({} bytes)",
+            r.device, r.kind, r.address, r.size
+        );
+    }
+}
+
+/// Logs the allocations and mappings `device` still holds (e.g., when its
+/// driver detaches) and returns how many there are.
+pub fn report_leaks(device: Option<PCIAddress>) -> usize {
+    let leaks = live(device);
+    for r in leaks.iter() {
+        warn!(
+            "{:?} leaked {:?} at {:#x} ({} bytes)",
+            r.device, r.kind, r.address, r.size
+        );
+    }
+    let outstanding = stats(device).outstanding();
+    if leaks.is_empty() && outstanding > 0 {
+        warn!(
+            "{:?} has {} outstanding DMA allocations or mappings",
+            device, outstanding
+        );
+    }
+    leaks.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iomem::DmaAllocator;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn leak_detection() {
+        let addr = PCIAddress {
+            bus: 0xfe,
+            dev: 2,
+            fun: 0,
+        };
+        set_enabled(true);
+        let allocator = DmaAllocator::for_device(addr);
+        let mut kept: Vec<u8, DmaAllocator> = Vec::with_capacity_in(4096, allocator);
+        kept.push(1);
+        {
+            let freed: Vec<u8, DmaAllocator> = Vec::with_capacity_in(128, allocator);
+            drop(freed);
+        }
+        track_map(Some(addr), 0x1000, 4096);
+        track_unmap(Some(addr), 0x1000);
+
+        let s = stats(Some(addr));
+        assert_eq!((s.allocations, s.frees, s.maps, s.unmaps), (2, 1, 1, 1));
+        assert_eq!((s.bytes, s.peak_bytes), (4096, 4096 + 128));
+        assert_eq!(report_leaks(Some(addr)), 1);
+
+        drop(kept);
+        assert_eq!(report_leaks(Some(addr)), 0);
+        assert_eq!(stats(Some(addr)).outstanding(), 0);
+    }
+}
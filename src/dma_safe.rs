@@ -0,0 +1,105 @@
+//! Types that can be reinterpreted from memory a device reads or writes.
+//!
+//! Descriptor rings, completion queues and MSI-X tables are accessed as
+//! arrays of structures laid over DMA memory or a BAR. This is only sound
+//! if the structure has the layout the device expects and every bit
+//! pattern the device may write is a valid value: integer fields in
+//! declaration order without padding (so no uninitialized bytes end up in
+//! device memory either). [`DmaSafe`] marks such types, APIs that
+//! reinterpret device memory require it, and [`dma_safe!`](crate::dma_safe!)
+//! implements it for a struct after checking its layout at compile time.
+
+use crate::endian::{Be32, Le16, Le32, Le64};
+
+/// A plain-old-data type that can be read from and written to memory
+/// shared with a device.
+///
+/// # Safety
+/// The type has no padding, no drop glue and any bit pattern is a valid
+/// value. Implement it for structs with [`dma_safe!`](crate::dma_safe!).
+pub unsafe trait DmaSafe: Sized + 'static {}
+
+macro_rules! dma_safe_primitives {
+    ($($t:ty),*) => {
+        $(
+            // Safety: integers are valid for any bit pattern
+            unsafe impl DmaSafe for $t {}
+        )*
+    };
+}
+
+dma_safe_primitives!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, Le16, Le32, Le64, Be32);
+
+// Safety: arrays have no padding between their elements
+unsafe impl<T: DmaSafe, const N: usize> DmaSafe for [T; N] {}
+
+/// Size of a field, which has to be [`DmaSafe`] itself (used by
+/// [`dma_safe!`](crate::dma_safe!)).
+#[doc(hidden)]
+pub const fn field_size<T, F: DmaSafe>(_field: fn(&T) -> &F) -> usize {
+    core::mem::size_of::<F>()
+}
+
+/// Implements [`DmaSafe`] for structs, listed with all their fields in
+/// declaration order (e.g., `dma_safe!(Desc { addr, len, flags });`).
+///
+/// Compilation fails unless all fields are [`DmaSafe`], and each field
+/// directly follows the previous one (i.e., the struct is `repr(C)` without
+/// padding) and the listed fields make up the whole struct.
+#[macro_export]
+macro_rules! dma_safe {
+    ($($ty:ident { $($field:ident),* $(,)? });* $(;)?) => {
+        $(
+            const _: () = {
+                let mut offset = 0;
+                $(
+                    assert!(
+                        core::mem::offset_of!($ty, $field) == offset,
+                        concat!(stringify!($ty), ".", stringify!($field), " is reordered or preceded by padding")
+                    );
+                    offset += $crate::dma_safe::field_size::<$ty, _>(|s| &s.$field);
+                )*
+                assert!(
+                    offset == core::mem::size_of::<$ty>(),
+                    concat!(stringify!($ty), " has trailing padding or fields that aren't listed")
+                );
+                assert!(!core::mem::needs_drop::<$ty>(), concat!(stringify!($ty), " has drop glue"));
+            };
+            // Safety: the layout is checked above
+            unsafe impl $crate::dma_safe::DmaSafe for $ty {}
+        )*
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C)]
+    struct Desc {
+        addr: Le64,
+        len: u32,
+        flags: [u8; 4],
+    }
+
+    crate::dma_safe!(Desc { addr, len, flags });
+
+    fn require<T: DmaSafe>() -> usize {
+        core::mem::size_of::<T>()
+    }
+
+    #[test]
+    fn descriptor_layout() {
+        assert_eq!(require::<Desc>(), 16);
+        assert_eq!(require::<[Desc; 4]>(), 64);
+        let desc = Desc {
+            addr: Le64::new(0x1000),
+            len: 0x200,
+            flags: [1, 0, 0, 0],
+        };
+        assert_eq!(
+            (desc.addr.get(), desc.len, desc.flags[0]),
+            (0x1000, 0x200, 1)
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Fields of descriptors and registers with a fixed byte order.
+//!
+//! Devices define their descriptors, queue entries and registers in a
+//! fixed byte order, little-endian for virtio, NVMe and most NICs. The
+//! wrappers here store a value in that order and convert it on access, so a
+//! `repr(C)` structure made of them has the layout the device expects on any
+//! target, and a field can't be used without converting it.
+
+use core::fmt;
+
+macro_rules! endian_type {
+    ($(#[$attr:meta])* $name:ident, $int:ty, $to:ident, $from:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+        #[repr(transparent)]
+        pub struct $name($int);
+
+        impl $name {
+            pub const fn new(value: $int) -> Self {
+                $name(value.$to())
+            }
+
+            /// The value in native byte order.
+            pub const fn get(self) -> $int {
+                <$int>::$from(self.0)
+            }
+
+            pub fn set(&mut self, value: $int) {
+                *self = $name::new(value);
+            }
+
+            /// Loads the field at `ptr` (e.g., a register or a descriptor
+            /// the device writes) with a volatile read.
+            ///
+            /// # Safety
+            /// As [`core::ptr::read_volatile`].
+            pub unsafe fn read_volatile(ptr: *const $name) -> $int {
+                core::ptr::read_volatile(ptr).get()
+            }
+
+            /// Stores `value` to the field at `ptr` with a volatile write.
+            ///
+            /// # Safety
+            /// As [`core::ptr::write_volatile`].
+            pub unsafe fn write_volatile(ptr: *mut $name, value: $int) {
+                core::ptr::write_volatile(ptr, $name::new(value))
+            }
+        }
+
+        impl From<$int> for $name {
+            fn from(value: $int) -> Self {
+                $name::new(value)
+            }
+        }
+
+        impl From<$name> for $int {
+            fn from(value: $name) -> Self {
+                value.get()
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(&self.get(), f)
+            }
+        }
+
+        impl fmt::LowerHex for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::LowerHex::fmt(&self.get(), f)
+            }
+        }
+
+        #[cfg(feature = "defmt")]
+        impl defmt::Format for $name {
+            fn format(&self, f: defmt::Formatter) {
+                defmt::Format::format(&self.get(), f)
+            }
+        }
+    };
+}
+
+endian_type!(
+    /// A little-endian `u16`.
+    Le16, u16, to_le, from_le
+);
+endian_type!(
+    /// A little-endian `u32`.
+    Le32, u32, to_le, from_le
+);
+endian_type!(
+    /// A little-endian `u64`.
+    Le64, u64, to_le, from_le
+);
+endian_type!(
+    /// A big-endian `u32` (e.g., registers of some FPGA IP and network
+    /// headers).
+    Be32, u32, to_be, from_be
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_order() {
+        #[repr(C)]
+        struct Desc {
+            len: Le16,
+            flags: Le16,
+            id: Be32,
+            addr: Le64,
+        }
+
+        let mut desc = Desc {
+            len: Le16::new(0x0102),
+            flags: 0x0304.into(),
+            id: Be32::new(0x0506_0708),
+            addr: Le64::default(),
+        };
+        // Safety: `desc` is a valid, aligned field
+        unsafe { Le64::write_volatile(&mut desc.addr, 0x1122_3344_5566_7788) };
+        desc.flags.set(desc.flags.get() | 0x8000);
+
+        // Safety: `Desc` is 16 bytes without padding
+        let bytes: [u8; 16] = unsafe { core::mem::transmute(desc) };
+        assert_eq!(
+            bytes,
+            [
+                0x02, 0x01, 0x04, 0x83, 0x05, 0x06, 0x07, 0x08, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33,
+                0x22, 0x11
+            ]
+        );
+        let id = Be32::new(0x0506_0708);
+        // Safety: as above
+        assert_eq!(unsafe { Be32::read_volatile(&id) }, 0x0506_0708);
+        assert_eq!(u32::from(id), 0x0506_0708);
+        assert_eq!(
+            std::format!("{:?} {:#x}", Le16::new(10), Le32::new(0xab)),
+            "10 0xab"
+        );
+    }
+}
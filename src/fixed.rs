@@ -0,0 +1,263 @@
+//! Fixed-capacity collections for paths that mustn't allocate.
+//!
+//! [`FixedVec`] and [`FixedQueue`] keep up to `N` elements inline, so they
+//! can live on the stack or in a static and work without an allocator
+//! (e.g., capability lists and BAR tables during early boot, or batches of
+//! completions in an interrupt handler). Pushing into a full collection
+//! hands the element back instead of growing.
+
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+
+/// A vector with room for `N` elements.
+pub struct FixedVec<T, const N: usize> {
+    elements: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> FixedVec<T, N> {
+    pub const fn new() -> Self {
+        FixedVec {
+            elements: [const { MaybeUninit::uninit() }; N],
+            len: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends `value`, or returns it if the vector is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.elements[self.len].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        // Safety: the element was initialized and is no longer part of the
+        // vector
+        Some(unsafe { self.elements[self.len].assume_init_read() })
+    }
+
+    /// Appends the items of `iter` until the vector is full.
+    ///
+    /// # Returns
+    /// The first item that didn't fit, if any.
+    pub fn try_extend(&mut self, iter: impl IntoIterator<Item = T>) -> Result<(), T> {
+        iter.into_iter().try_for_each(|value| self.push(value))
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Drops all elements from `len` on.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.pop();
+        }
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        // Safety: the first `len` elements are initialized
+        unsafe { core::slice::from_raw_parts(self.elements.as_ptr() as *const T, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // Safety: as in `as_slice`
+        unsafe { core::slice::from_raw_parts_mut(self.elements.as_mut_ptr() as *mut T, self.len) }
+    }
+}
+
+impl<T, const N: usize> Default for FixedVec<T, N> {
+    fn default() -> Self {
+        FixedVec::new()
+    }
+}
+
+impl<T, const N: usize> Deref for FixedVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> DerefMut for FixedVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: Clone, const N: usize> Clone for FixedVec<T, N> {
+    fn clone(&self) -> Self {
+        let mut clone = FixedVec::new();
+        for value in self.iter() {
+            // Can't fail, the clone has the same capacity
+            let _ = clone.push(value.clone());
+        }
+        clone
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for FixedVec<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq for FixedVec<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Eq, const N: usize> Eq for FixedVec<T, N> {}
+
+impl<T, const N: usize> Drop for FixedVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// A FIFO ring with room for `N` elements.
+pub struct FixedQueue<T, const N: usize> {
+    elements: [MaybeUninit<T>; N],
+    /// Index of the oldest element.
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> FixedQueue<T, N> {
+    pub const fn new() -> Self {
+        FixedQueue {
+            elements: [const { MaybeUninit::uninit() }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends `value` at the back, or returns it if the queue is full.
+    pub fn push_back(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.elements[(self.head + self.len) % N].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the oldest element.
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        // Safety: the element at `head` is initialized while `len > 0`, and
+        // isn't part of the queue anymore afterwards
+        let value = unsafe { self.elements[self.head].assume_init_read() };
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// The oldest element.
+    pub fn front(&self) -> Option<&T> {
+        // Safety: the element at `head` is initialized while `len > 0`
+        (!self.is_empty()).then(|| unsafe { self.elements[self.head].assume_init_ref() })
+    }
+
+    /// Iterates from the oldest to the newest element.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        // Safety: the `len` elements starting at `head` are initialized
+        (0..self.len).map(move |i| unsafe { self.elements[(self.head + i) % N].assume_init_ref() })
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Default for FixedQueue<T, N> {
+    fn default() -> Self {
+        FixedQueue::new()
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for FixedQueue<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T, const N: usize> Drop for FixedQueue<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn vec_and_queue() {
+        let mut v: FixedVec<u32, 3> = FixedVec::new();
+        assert_eq!(v.try_extend([1, 2, 3, 4]), Err(4));
+        assert!(v.is_full());
+        assert_eq!(&v[..], &[1, 2, 3]);
+        assert_eq!(v.pop(), Some(3));
+        v[0] = 7;
+        assert_eq!(v.clone().as_slice(), &[7, 2]);
+
+        let mut q: FixedQueue<u32, 2> = FixedQueue::new();
+        for i in 0..5 {
+            q.push_back(i).unwrap();
+            assert_eq!(q.push_back(i + 1), Ok(()));
+            assert_eq!(q.push_back(i + 2), Err(i + 2));
+            assert_eq!(q.pop_front(), Some(i));
+            assert!(q.iter().eq(&[i + 1]));
+            q.clear();
+        }
+
+        // Elements still in the collections are dropped with them
+        let value = Rc::new(());
+        let mut v: FixedVec<Rc<()>, 2> = FixedVec::new();
+        let mut q: FixedQueue<Rc<()>, 2> = FixedQueue::new();
+        v.push(value.clone()).unwrap();
+        q.push_back(value.clone()).unwrap();
+        assert_eq!(Rc::strong_count(&value), 3);
+        drop(v);
+        drop(q);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,93 @@
+//! Entry points for fuzzing the configuration space parsers.
+//!
+//! Each function takes arbitrary bytes, builds a [`MockConfig`] from them and
+//! runs the same parsing code a driver would run on real hardware. They are
+//! meant to be called from cargo-fuzz targets and are not part of the public
+//! API.
+
+use crate::pci::mock::MockConfig;
+use crate::pci::{PCIAddress, PciDevice, MAX_CAPABILITIES};
+use crate::virtio::pci::virtio_capabilities;
+
+fn device(bytes: &[u8]) -> Option<PciDevice<MockConfig>> {
+    let addr = PCIAddress {
+        bus: 0,
+        dev: 0,
+        fun: 0,
+    };
+    PciDevice::from_config(MockConfig::from_bytes(addr, bytes))
+}
+
+/// Walks the capability chain of a configuration space given as `bytes`.
+pub fn parse_capabilities(bytes: &[u8]) -> usize {
+    device(bytes).map_or(0, |dev| dev.capabilities().take(MAX_CAPABILITIES).count())
+}
+
+/// Decodes and sizes the BARs of a configuration space.
+///
+/// The first 24 bytes are the writable masks of the six BAR registers, the
+/// remaining bytes are the configuration space.
+pub fn parse_bars(bytes: &[u8]) -> usize {
+    if bytes.len() < 24 {
+        return 0;
+    }
+    let (masks, config) = bytes.split_at(24);
+
+    let mut mock = MockConfig::from_bytes(
+        PCIAddress {
+            bus: 0,
+            dev: 0,
+            fun: 0,
+        },
+        config,
+    );
+    for (index, mask) in masks.chunks_exact(4).enumerate() {
+        mock.set_bar_mask(
+            index,
+            u32::from_le_bytes([mask[0], mask[1], mask[2], mask[3]]),
+        );
+    }
+
+    match PciDevice::from_config(mock) {
+        Some(dev) => dev.summary().bars.len(),
+        None => 0,
+    }
+}
+
+/// Parses the virtio PCI capabilities of a configuration space.
+pub fn parse_virtio_capabilities(bytes: &[u8]) -> usize {
+    device(bytes).map_or(0, |dev| {
+        virtio_capabilities(&dev).take(MAX_CAPABILITIES).count()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars_regressions() {
+        // BAR0 with the reserved type 1 and no writable bits
+        let mut input = [0u8; 24 + 0x40];
+        input[24 + 0x10] = 0x02;
+        assert_eq!(parse_bars(&input), 0);
+
+        // 32-bit and 64-bit BARs that only report their type bits
+        input[24 + 0x10] = 0x08;
+        assert_eq!(parse_bars(&input), 0);
+        input[24 + 0x10] = 0x04;
+        assert_eq!(parse_bars(&input), 0);
+
+        // A bridge with a 64-bit BAR 1 (which has no upper half) and a
+        // CardBus bridge, both with sizable BARs
+        let mut input = [0u8; 24 + 0x40];
+        for mask in input[..24].chunks_exact_mut(4) {
+            mask.copy_from_slice(&0xffff_f000u32.to_le_bytes());
+        }
+        input[24 + 0x0e] = 0x01;
+        input[24 + 0x14] = 0x04;
+        assert_eq!(parse_bars(&input), 1);
+        input[24 + 0x0e] = 0x02;
+        assert_eq!(parse_bars(&input), 1);
+    }
+}
@@ -0,0 +1,208 @@
+//! The SMBus controller of Intel ICH/PCH chipsets (i801).
+//!
+//! The controller executes one SMBus transaction at a time, programmed
+//! through a handful of byte registers (in the memory BAR 0 or the IO BAR
+//! 4). It only knows SMBus transactions, so [`I2cBus`] transfers are mapped
+//! to them: register reads become (repeated) read byte data transactions,
+//! which is what EEPROMs and sensors expect.
+
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::{I2cBus, I2cError};
+use crate::arch::VAddr;
+use crate::pci::{ConfigSpace, PciDevice};
+use crate::poll::poll_until;
+
+/// PCI IDs of some PCH SMBus controllers (vendor is Intel, 0x8086).
+pub const DEVICE_IDS: &[u16] = &[
+    0x1c22, // 6 Series (Cougar Point)
+    0x1e22, // 7 Series (Panther Point)
+    0x8c22, // 8 Series (Lynx Point)
+    0x8d22, // C610 (Wellsburg)
+    0xa123, // 100 Series (Sunrise Point)
+    0xa2a3, // 200 Series (Union Point)
+    0xa323, // C620 (Lewisburg) / 300 Series
+    0x2930, // ICH9 (QEMU q35)
+];
+
+/// Host configuration register (PCI config space).
+const HOSTC: u32 = 0x40;
+const HOSTC_HST_EN: usize = 0;
+const HOSTC_I2C_EN: usize = 2;
+
+/// Registers (offsets in the BAR).
+const HST_STS: usize = 0x00;
+const HST_CNT: usize = 0x02;
+const HST_CMD: usize = 0x03;
+const XMIT_SLVA: usize = 0x04;
+const HST_D0: usize = 0x05;
+const HST_D1: usize = 0x06;
+
+/// HST_STS bits.
+const STS_HOST_BUSY: u8 = 1 << 0;
+const STS_INTR: u8 = 1 << 1;
+const STS_DEV_ERR: u8 = 1 << 2;
+const STS_BUS_ERR: u8 = 1 << 3;
+const STS_FAILED: u8 = 1 << 4;
+const STS_INUSE: u8 = 1 << 6;
+const STS_BYTE_DONE: u8 = 1 << 7;
+const STS_ERRORS: u8 = STS_DEV_ERR | STS_BUS_ERR | STS_FAILED;
+/// Bits cleared before and after every transaction (RW1C).
+const STS_CLEAR: u8 = STS_INTR | STS_ERRORS | STS_BYTE_DONE;
+
+/// HST_CNT bits and commands.
+const CNT_KILL: u8 = 1 << 1;
+const CNT_START: u8 = 1 << 6;
+const CMD_QUICK: u8 = 0x00;
+const CMD_BYTE: u8 = 0x04;
+const CMD_BYTE_DATA: u8 = 0x08;
+const CMD_WORD_DATA: u8 = 0x0c;
+
+/// Time a transaction may take.
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A PCH SMBus controller.
+pub struct I801Smbus {
+    regs: VAddr,
+}
+
+impl I801Smbus {
+    /// # Safety
+    /// `regs` must be the mapped memory BAR (BAR0) of a PCH SMBus
+    /// controller.
+    pub unsafe fn new(regs: VAddr) -> I801Smbus {
+        I801Smbus { regs }
+    }
+
+    /// Enables the host interface of `device` in SMBus (not I2C) mode.
+    pub fn enable<A: ConfigSpace>(device: &mut PciDevice<A>) {
+        let config = device.config_mut();
+        let mut hostc = config.read(HOSTC);
+        hostc.set_bit(HOSTC_HST_EN, true);
+        hostc.set_bit(HOSTC_I2C_EN, false);
+        config.write(HOSTC, hostc);
+    }
+
+    fn read_reg(&self, offset: usize) -> u8 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u8>()) }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u8) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u8>(), value) };
+    }
+
+    /// Takes the INUSE semaphore (shared with firmware/ACPI), reading the
+    /// status sets it.
+    fn acquire(&mut self) -> Result<(), I2cError> {
+        if self.read_reg(HST_STS) & STS_INUSE != 0 {
+            return Err(I2cError::Busy);
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        self.write_reg(HST_STS, STS_INUSE);
+    }
+
+    /// Runs a transaction of type `command` with the registers set up.
+    fn transaction(&mut self, addr: u8, command: u8) -> Result<(), I2cError> {
+        self.write_reg(HST_STS, STS_CLEAR);
+        if self.read_reg(HST_STS) & STS_HOST_BUSY != 0 {
+            return Err(I2cError::Busy);
+        }
+        self.write_reg(HST_CNT, command | CNT_START);
+
+        let done = poll_until(
+            || {
+                let status = self.read_reg(HST_STS);
+                status & STS_HOST_BUSY == 0 && status & (STS_INTR | STS_ERRORS) != 0
+            },
+            TIMEOUT,
+        );
+        if !done {
+            warn!("i801: transaction timed out, killing it");
+            self.write_reg(HST_CNT, CNT_KILL);
+            self.write_reg(HST_CNT, 0);
+            self.write_reg(HST_STS, STS_CLEAR);
+            return Err(I2cError::Timeout);
+        }
+
+        let status = self.read_reg(HST_STS);
+        self.write_reg(HST_STS, STS_CLEAR);
+        if status & STS_DEV_ERR != 0 {
+            Err(I2cError::Nack { addr })
+        } else if status & (STS_BUS_ERR | STS_FAILED) != 0 {
+            Err(I2cError::BusError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs an SMBus transaction: `command` with `cmd` as command byte and
+    /// `data` as data bytes (for writes), returns the data bytes.
+    fn smbus(
+        &mut self,
+        addr: u8,
+        read: bool,
+        command: u8,
+        cmd: u8,
+        data: [u8; 2],
+    ) -> Result<[u8; 2], I2cError> {
+        self.acquire()?;
+        self.write_reg(XMIT_SLVA, (addr << 1) | read as u8);
+        self.write_reg(HST_CMD, cmd);
+        self.write_reg(HST_D0, data[0]);
+        self.write_reg(HST_D1, data[1]);
+        let result = self
+            .transaction(addr, command)
+            .map(|()| [self.read_reg(HST_D0), self.read_reg(HST_D1)]);
+        self.release();
+        result
+    }
+}
+
+impl I2cBus for I801Smbus {
+    /// Supports a register (`write` is one byte) followed by reads of any
+    /// length, each byte is read with a read byte data transaction from
+    /// the next register.
+    fn write_read(&mut self, addr: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+        match write {
+            [] => {
+                for byte in read.iter_mut() {
+                    *byte = self.smbus(addr, true, CMD_BYTE, 0, [0; 2])?[0];
+                }
+                Ok(())
+            }
+            [reg] => {
+                for (i, byte) in read.iter_mut().enumerate() {
+                    let reg = reg.wrapping_add(i as u8);
+                    *byte = self.smbus(addr, true, CMD_BYTE_DATA, reg, [0; 2])?[0];
+                }
+                Ok(())
+            }
+            _ => Err(I2cError::Unsupported),
+        }
+    }
+
+    /// Supports writes of up to 3 bytes (quick, send byte, write byte data
+    /// and write word data transactions).
+    fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), I2cError> {
+        match *data {
+            [] => self.smbus(addr, false, CMD_QUICK, 0, [0; 2]),
+            [cmd] => self.smbus(addr, false, CMD_BYTE, cmd, [0; 2]),
+            [cmd, d0] => self.smbus(addr, false, CMD_BYTE_DATA, cmd, [d0, 0]),
+            [cmd, d0, d1] => self.smbus(addr, false, CMD_WORD_DATA, cmd, [d0, d1]),
+            _ => return Err(I2cError::Unsupported),
+        }
+        .map(|_data| ())
+    }
+
+    fn read_word_data(&mut self, addr: u8, reg: u8) -> Result<u16, I2cError> {
+        let data = self.smbus(addr, true, CMD_WORD_DATA, reg, [0; 2])?;
+        Ok(u16::from_le_bytes(data))
+    }
+}
@@ -0,0 +1,58 @@
+//! I2C/SMBus controllers.
+//!
+//! Many devices hang small peripherals off a two-wire bus: SFP module
+//! EEPROMs, temperature sensors, SPD EEPROMs. [`I2cBus`] is what drivers of
+//! such peripherals use, independent of the controller (a PCH SMBus
+//! controller, a bit-banged bus in a NIC, ...).
+
+use custom_error::custom_error;
+
+pub mod i801;
+
+pub use i801::I801Smbus;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub I2cError
+    Nack{addr: u8} = "no acknowledgement from device {addr}",
+    BusError = "bus collision or protocol error",
+    Timeout = "the transaction timed out",
+    Busy = "the controller is in use",
+    Unsupported = "the controller can't do this transfer",
+}
+
+/// A bus of I2C (or SMBus) devices, addressed with 7-bit addresses.
+pub trait I2cBus {
+    /// Writes `write` to the device at `addr`, then reads `read.len()`
+    /// bytes from it (after a repeated start).
+    fn write_read(&mut self, addr: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError>;
+
+    /// Writes `data` to the device at `addr`.
+    fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), I2cError>;
+
+    /// Reads `buf.len()` bytes from the device at `addr`.
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), I2cError> {
+        self.write_read(addr, &[], buf)
+    }
+
+    /// Reads register `reg` of the device at `addr` (SMBus read byte data).
+    fn read_byte_data(&mut self, addr: u8, reg: u8) -> Result<u8, I2cError> {
+        let mut value = [0u8; 1];
+        self.write_read(addr, &[reg], &mut value)?;
+        Ok(value[0])
+    }
+
+    /// Writes register `reg` of the device at `addr` (SMBus write byte
+    /// data).
+    fn write_byte_data(&mut self, addr: u8, reg: u8, value: u8) -> Result<(), I2cError> {
+        self.write(addr, &[reg, value])
+    }
+
+    /// Reads the 16-bit register `reg` (least significant byte first, SMBus
+    /// read word data).
+    fn read_word_data(&mut self, addr: u8, reg: u8) -> Result<u16, I2cError> {
+        let mut value = [0u8; 2];
+        self.write_read(addr, &[reg], &mut value)?;
+        Ok(u16::from_le_bytes(value))
+    }
+}
@@ -9,10 +9,19 @@ use core::ptr::NonNull;
 
 use custom_error::custom_error;
 
+use crate::block::BlockIo;
+use crate::dma_debug;
+use crate::metrics;
+use crate::net::csum::RxChecksum;
+use crate::net::gso::Gso;
+use crate::net::ptp::PhcTime;
+use crate::pci::PCIAddress;
 use crate::{IOAddr, PAddr, VAddr};
 
 // custom error for the IOMemory
-custom_error! {pub IOMemError
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub IOMemError
     OutOfMemory = "reached out of memory",
     NotYetImplemented = "feature not yet implemented"
 }
@@ -26,6 +35,94 @@ impl From<TryReserveError> for IOMemError {
 ///  TODO: get rid of this:
 pub const KERNEL_BASE: u64 = 0x400000000000;
 
+/// Memory type of a device (BAR) mapping.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MemoryType {
+    /// Uncached, writes complete at the device before the CPU continues
+    /// (Device-nGnRnE on aarch64, UC on x86).
+    Strict,
+    /// Uncached, writes may be acknowledged before they reach the device
+    /// (Device-nGnRE on aarch64, as Linux maps BARs, UC on x86 where PCI
+    /// writes are posted anyway). Reads still go to the device and order
+    /// against the writes.
+    #[default]
+    Device,
+    /// Writes may be merged and reordered until a barrier (Normal-NC on
+    /// aarch64, WC on x86), only for prefetchable BARs.
+    WriteCombining,
+}
+
+impl MemoryType {
+    /// Whether writes may complete before they reach the device.
+    pub fn posted_writes(self) -> bool {
+        self != MemoryType::Strict
+    }
+
+    /// Whether the type is only valid for prefetchable BARs.
+    pub fn requires_prefetchable(self) -> bool {
+        self == MemoryType::WriteCombining
+    }
+}
+
+/// How to map device memory (a BAR or other MMIO region).
+///
+/// Accepted by every mapping path: the closures given to
+/// [`PciDriverBuilder::map_bars_with_options`](crate::pci::PciDriverBuilder::map_bars_with_options)
+/// on bare metal and the mapping functions of the OS backends. Backends that
+/// can't honor an option fall back to the stricter behavior (e.g., Linux
+/// only distinguishes write-combining from uncached).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MapOptions {
+    pub memory_type: MemoryType,
+    /// Map without write permission.
+    pub read_only: bool,
+    /// Map without execute permission.
+    pub execute_never: bool,
+}
+
+impl Default for MapOptions {
+    fn default() -> Self {
+        MapOptions {
+            memory_type: MemoryType::Device,
+            read_only: false,
+            execute_never: true,
+        }
+    }
+}
+
+impl MapOptions {
+    /// Uncached read/write mapping with posted writes (the default).
+    pub fn device() -> MapOptions {
+        MapOptions::default()
+    }
+
+    /// Write-combining read/write mapping (for prefetchable BARs).
+    pub fn write_combining() -> MapOptions {
+        MapOptions::default().memory_type(MemoryType::WriteCombining)
+    }
+
+    pub fn memory_type(mut self, memory_type: MemoryType) -> MapOptions {
+        self.memory_type = memory_type;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> MapOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn execute_never(mut self, execute_never: bool) -> MapOptions {
+        self.execute_never = execute_never;
+        self
+    }
+
+    pub fn is_write_combining(&self) -> bool {
+        self.memory_type == MemoryType::WriteCombining
+    }
+}
+
 /// A trait to tag objects which a device needs to read or write over DMA.
 pub trait DmaObject {
     fn paddr(&self) -> PAddr {
@@ -42,12 +139,45 @@ pub trait DmaObject {
 }
 
 /// An allocator that (supposedly) backs memory accessible by devices.
+///
+/// Allocators tagged with a device account their allocations to it (see
+/// [`dma_debug`](crate::dma_debug)).
+///
+/// This used to be a unit struct, code that used `DmaAllocator` as a value
+/// (e.g., `Vec::new_in(DmaAllocator)`) has to use [`DmaAllocator::new`] now.
 #[derive(Debug, Default, Clone, Copy)]
-pub struct DmaAllocator;
+pub struct DmaAllocator {
+    device: Option<PCIAddress>,
+}
+
+impl DmaAllocator {
+    /// An allocator whose allocations aren't accounted to a device.
+    pub const fn new() -> DmaAllocator {
+        DmaAllocator { device: None }
+    }
+
+    /// An allocator whose allocations are accounted to `device`.
+    pub const fn for_device(device: PCIAddress) -> DmaAllocator {
+        DmaAllocator {
+            device: Some(device),
+        }
+    }
+
+    /// The device the allocations are accounted to.
+    pub fn device(&self) -> Option<PCIAddress> {
+        self.device
+    }
+}
 
 unsafe impl Allocator for DmaAllocator {
     /// Allocates IO memory.
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        span!(
+            TRACE,
+            "dma_alloc",
+            device = self.device,
+            size = layout.size()
+        );
         // TODO: ensure IOMMU stuff etc. here, for now:
         unsafe {
             // do the actual allocation, refer to the OS allocator
@@ -55,9 +185,12 @@ unsafe impl Allocator for DmaAllocator {
             if !ptr.is_null() {
                 // wrap in in NonNull, remove option type
                 let ptr_nonnull = NonNull::new(ptr).unwrap();
+                metrics::DMA_BYTES.add(layout.size() as i64);
+                dma_debug::track_alloc(self.device, ptr as u64, layout.size());
                 // construct the NonNull slice for the return
                 Ok(NonNull::slice_from_raw_parts(ptr_nonnull, layout.size()))
             } else {
+                metrics::DMA_ALLOC_FAILURES.inc();
                 Err(AllocError)
             }
         }
@@ -65,9 +198,17 @@ unsafe impl Allocator for DmaAllocator {
 
     /// Deallocates the previously allocated IO memory.
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        span!(
+            TRACE,
+            "dma_free",
+            device = self.device,
+            size = layout.size()
+        );
         // TODO: ensure IOMMU stuff, for now:
         let buf = ptr.as_ptr();
         alloc::alloc::dealloc(buf, layout);
+        metrics::DMA_BYTES.sub(layout.size() as i64);
+        dma_debug::track_free(self.device, buf as u64, layout.size());
     }
 }
 
@@ -75,23 +216,83 @@ unsafe impl Allocator for DmaAllocator {
 /// Represents an IO buffer (data handed to/from device).
 pub struct IOBuf {
     buf: Vec<u8, DmaAllocator>,
+    /// Whether the buffer was given to the device (see
+    /// [`IOBuf::give_to_device`]), only tracked in debug builds.
+    #[cfg(debug_assertions)]
+    device_owned: bool,
 }
 
 impl IOBuf {
     pub fn new(layout: Layout) -> Result<IOBuf, IOMemError> {
-        // get the aligned buffer length
-        // get the layouf for the allocation
-        let allocator = DmaAllocator::default();
+        IOBuf::new_in(layout, DmaAllocator::new())
+    }
+
+    /// A buffer allocated with `allocator` (e.g., one tagged with the device
+    /// it is for).
+    pub fn new_in(layout: Layout, allocator: DmaAllocator) -> Result<IOBuf, IOMemError> {
         let buf: Vec<u8, DmaAllocator> = Vec::with_capacity_in(layout.size(), allocator);
-        let mut iobuf = IOBuf { buf };
+        let mut iobuf = IOBuf {
+            buf,
+            #[cfg(debug_assertions)]
+            device_owned: false,
+        };
+        metrics::IOBUF_ALLOCS.inc();
         // call expand here to make sure the buffer has the full size
         iobuf.expand();
         // info!("IOBuf: new buffer of size {}!",iobuf.capacity());
         Ok(iobuf)
     }
 
+    /// Hands the buffer to the device (e.g., before posting it to a queue).
+    ///
+    /// Makes the CPU's writes visible to the device. Until the buffer is
+    /// taken back with [`IOBuf::take_from_device`] the CPU must not touch its
+    /// contents, which debug builds check.
+    pub fn give_to_device(&mut self) {
+        span!(TRACE, "dma_map", len = self.buf.capacity());
+        #[cfg(debug_assertions)]
+        {
+            assert!(!self.device_owned, "IOBuf given to the device twice");
+            self.device_owned = true;
+        }
+        crate::arch::dma_sync_for_device(self.vaddr(), self.buf.capacity());
+    }
+
+    /// Takes the buffer back from the device (e.g., after the device marked
+    /// its descriptor as done) and makes the device's writes visible.
+    pub fn take_from_device(&mut self) {
+        span!(TRACE, "dma_unmap", len = self.buf.capacity());
+        #[cfg(debug_assertions)]
+        {
+            assert!(
+                self.device_owned,
+                "IOBuf taken from the device but not given to it"
+            );
+            self.device_owned = false;
+        }
+        crate::arch::dma_sync_for_cpu(self.vaddr(), self.buf.capacity());
+    }
+
+    /// Whether the device owns the buffer (always false in release builds).
+    pub fn is_device_owned(&self) -> bool {
+        #[cfg(debug_assertions)]
+        return self.device_owned;
+        #[cfg(not(debug_assertions))]
+        return false;
+    }
+
+    /// Catches accesses to buffers the device owns (in debug builds).
+    #[inline]
+    fn check_cpu_owned(&self) {
+        debug_assert!(
+            !self.is_device_owned(),
+            "IOBuf accessed while owned by the device"
+        );
+    }
+
     /// Fill buffer with as many 0 as capacity allows.
     pub fn expand(&mut self) {
+        self.check_cpu_owned();
         self.buf.resize(self.buf.capacity(), 0);
     }
 
@@ -106,6 +307,7 @@ impl IOBuf {
 
     /// Copy data from `src` into a given `offset` of the `IOBuf`.
     pub fn copy_in_at(&mut self, offset: usize, src: &[u8]) -> Result<usize, IOMemError> {
+        self.check_cpu_owned();
         // Currently we do not allow extending the buffer:
         let remaining_capacity = self.buf.capacity() - offset;
         let cnt = cmp::min(remaining_capacity, src.len());
@@ -124,6 +326,7 @@ impl IOBuf {
 
     /// Copy data out of the IOBuf, starting at a given `offset` into `dst`.
     pub fn copy_out_at(&self, offset: usize, dst: &mut [u8]) -> Result<usize, IOMemError> {
+        self.check_cpu_owned();
         // of the offset is outside of the length of the vector then we
         if offset >= self.buf.len() {
             return Ok(0);
@@ -142,11 +345,13 @@ impl IOBuf {
 
     /// Get a IOBuf contents as slice.
     pub fn as_slice(&self) -> &[u8] {
+        self.check_cpu_owned();
         self.buf.as_slice()
     }
 
     /// Get a IOBuf contents as mutable slice.
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.check_cpu_owned();
         self.buf.as_mut_slice()
     }
 
@@ -167,6 +372,7 @@ impl Index<usize> for IOBuf {
     /// Performs the indexing (`container[index]`) operation.
     #[inline]
     fn index(&self, index: usize) -> &Self::Output {
+        self.check_cpu_owned();
         &self.buf[index]
     }
 }
@@ -188,19 +394,27 @@ pub struct IOBufPool {
     /// Pool of buffers
     pool: Vec<IOBuf>,
     /// The allocator used for new buffers
-    _allocator: DmaAllocator,
+    allocator: DmaAllocator,
     /// The allocation layout of the buffers
     layout: Layout,
 }
 
 impl IOBufPool {
     pub fn new(len: usize, align: usize) -> Result<IOBufPool, IOMemError> {
+        IOBufPool::new_in(len, align, DmaAllocator::new())
+    }
+
+    /// A pool whose buffers are allocated with `allocator`.
+    pub fn new_in(
+        len: usize,
+        align: usize,
+        allocator: DmaAllocator,
+    ) -> Result<IOBufPool, IOMemError> {
         let layout = Layout::from_size_align(len, align).expect("Layout was invalid.");
-        let allocator = DmaAllocator::default();
 
         Ok(IOBufPool {
             pool: vec![],
-            _allocator: allocator,
+            allocator,
             layout,
         })
     }
@@ -208,16 +422,35 @@ impl IOBufPool {
     pub fn get_buf(&mut self) -> Result<IOBuf, IOMemError> {
         if !self.pool.is_empty() {
             let mut buf = self.pool.pop().expect("should have a buffer here");
+            metrics::IOBUF_POOL_HITS.inc();
             buf.expand();
             buf.clear();
             Ok(buf)
         } else {
-            IOBuf::new(self.layout)
+            IOBuf::new_in(self.layout, self.allocator)
         }
     }
 
+    /// Returns a buffer to the pool.
+    ///
+    /// Buffers of a different size than the pool's (e.g., handed out before
+    /// [`IOBufPool::set_buf_len`]) are freed instead.
     pub fn put_buf(&mut self, buf: IOBuf) {
-        self.pool.push(buf)
+        if buf.buf.capacity() == self.layout.size() {
+            self.pool.push(buf)
+        }
+    }
+
+    /// Size of the buffers handed out by the pool.
+    pub fn buf_len(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Changes the size of the buffers, freeing all pooled buffers.
+    pub fn set_buf_len(&mut self, len: usize) {
+        self.layout =
+            Layout::from_size_align(len, self.layout.align()).expect("Layout was invalid.");
+        self.pool.clear();
     }
 }
 
@@ -246,6 +479,15 @@ pub struct IOBufChain {
     /// RSS type
     pub rss_type: u32,
 
+    /// Segmentation offload to perform (set by the stack on tx)
+    pub gso: Option<Gso>,
+
+    /// Hardware timestamp (set by driver on rx)
+    pub hw_timestamp: Option<PhcTime>,
+
+    /// Block operation (set by the block layer)
+    pub block_io: Option<BlockIo>,
+
     /// The `IOBuf` fragments
     pub segments: VecDeque<IOBuf>,
 }
@@ -263,6 +505,9 @@ impl IOBufChain {
             vtag: None,
             rss_flow_id: None,
             rss_type: 0,
+            gso: None,
+            hw_timestamp: None,
+            block_io: None,
             segments: vd,
         })
     }
@@ -304,9 +549,40 @@ impl IOBufChain {
         );
     }
 
+    /// Checksum validation result of the IP header (on rx).
+    pub fn rx_l3_csum(&self) -> RxChecksum {
+        RxChecksum::l3(self.csum_flags)
+    }
+
+    /// Checksum validation result of the TCP/UDP/SCTP header (on rx).
+    pub fn rx_l4_csum(&self) -> RxChecksum {
+        RxChecksum::l4(self.csum_flags)
+    }
+
+    /// Records the hardware checksum validation results (set by driver on
+    /// rx).
+    pub fn set_rx_csum(&mut self, l3: RxChecksum, l4: RxChecksum) {
+        self.csum_flags = RxChecksum::set(self.csum_flags, l3, l4);
+    }
+
     pub fn append(&mut self, buf: IOBuf) {
         self.segments.push_back(buf);
     }
+
+    /// Hands all segments to the device (see [`IOBuf::give_to_device`]).
+    pub fn give_to_device(&mut self) {
+        for seg in self.segments.iter_mut() {
+            seg.give_to_device();
+        }
+    }
+
+    /// Takes all segments back from the device (see
+    /// [`IOBuf::take_from_device`]).
+    pub fn take_from_device(&mut self) {
+        for seg in self.segments.iter_mut() {
+            seg.take_from_device();
+        }
+    }
 }
 
 /// implementation for the index operator [] on IOBuf
@@ -329,3 +605,28 @@ impl Index<usize> for IOBufChain {
         &self.segments[0][0]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_ownership() {
+        let mut buf = IOBuf::new(Layout::from_size_align(64, 64).unwrap()).unwrap();
+        buf.copy_in(&[1, 2, 3]).unwrap();
+        buf.give_to_device();
+        assert_eq!(buf.is_device_owned(), cfg!(debug_assertions));
+        buf.take_from_device();
+        assert!(!buf.is_device_owned());
+        assert_eq!(buf.as_slice(), &[1, 2, 3]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "owned by the device")]
+    fn access_while_device_owned() {
+        let mut buf = IOBuf::new(Layout::from_size_align(64, 64).unwrap()).unwrap();
+        buf.give_to_device();
+        buf.as_mut_slice()[0] = 1;
+    }
+}
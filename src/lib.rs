@@ -1,4 +1,5 @@
-#![feature(core_intrinsics, allocator_api, nonnull_slice_from_raw_parts)]
+#![feature(core_intrinsics, nonnull_slice_from_raw_parts)]
+#![cfg_attr(feature = "alloc", feature(allocator_api))]
 #![cfg_attr(unix, feature(libc))]
 #![no_std]
 
@@ -6,13 +7,15 @@
 #[cfg(unix)]
 extern crate std;
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
 #[cfg(unix)]
 extern crate byteorder;
 #[cfg(unix)]
 extern crate libc;
-#[cfg_attr(unix, macro_use(matches, assert_matches))]
+#[cfg_attr(unix, macro_use(matches))]
+#[cfg_attr(all(unix, test), macro_use(assert_matches))]
 #[cfg(unix)]
 extern crate matches;
 
@@ -22,27 +25,73 @@ extern crate mmap;
 #[cfg(target_os = "barrelfish")]
 extern crate libbarrelfish;
 
+#[macro_use]
+mod diag;
+
+#[cfg(feature = "alloc")]
+pub mod adminq;
+#[cfg(feature = "alloc")]
+pub mod ahci;
+#[cfg(feature = "alloc")]
+pub mod arena;
+pub mod clock;
+#[cfg(feature = "alloc")]
 pub mod devq;
+pub mod dfl;
+pub mod display;
+#[cfg(feature = "alloc")]
+pub mod dma_debug;
+pub mod dma_safe;
+pub mod endian;
+pub mod fixed;
+#[cfg(feature = "alloc")]
+#[doc(hidden)]
+pub mod fuzz;
+pub mod i2c;
+#[cfg(feature = "alloc")]
 pub mod iomem;
+pub mod lifecycle;
+pub mod mailbox;
+pub mod metrics;
 pub mod pci;
+pub mod pm;
+mod poll;
+pub mod shmring;
 #[cfg(unix)]
 pub mod timedops;
 
+/// Definitions for block devices.
+#[cfg(feature = "alloc")]
+pub mod block;
+
 /// Definitions for network devices.
+#[cfg(feature = "alloc")]
 pub mod net;
 
+/// Definitions for NVMe devices.
+#[cfg(feature = "alloc")]
+pub mod nvme;
+
+#[cfg(feature = "alloc")]
+pub mod virtio;
+
 #[cfg(target_os = "barrelfish")]
 mod barrelfish;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "alloc"))]
 mod linux;
 
 #[cfg(target_os = "barrelfish")]
 pub use barrelfish::*;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "alloc"))]
 pub use linux::*;
 
+pub use lifecycle::AsyncDriverControl;
+
+#[cfg(feature = "derive")]
+pub use driverkit_derive::DriverControl;
+
 // The aarch64 platform specific code.
 #[cfg(target_arch = "x86_64")]
 #[path = "arch/x86/mod.rs"]
@@ -56,6 +105,7 @@ mod arch;
 pub use arch::*;
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DriverState {
     Uninitialized,
     Initialized,
@@ -64,49 +114,240 @@ pub enum DriverState {
     Destroyed,
 }
 
+impl DriverState {
+    /// The driver life-cycle transition table.
+    ///
+    /// - Uninitialized -> Initialized
+    /// - Initialized, Detached, Attached(x) -> Attached(y)
+    /// - Attached(x) -> Detached, Destroyed
+    pub fn can_transition_to(&self, next: DriverState) -> bool {
+        use DriverState::*;
+        matches!(
+            (*self, next),
+            (Uninitialized, Initialized)
+                | (Initialized, Attached(_))
+                | (Detached, Attached(_))
+                | (Attached(_), Attached(_))
+                | (Attached(_), Detached)
+                | (Attached(_), Destroyed)
+        )
+    }
+}
+
 /// Driver life-cycle management trait
 pub trait DriverControl: Sized {
     /// Initialize the device
     /// DriverState must be Uninitialized
     fn init(&mut self) {
-        assert!(self.state() == DriverState::Uninitialized);
-        self.set_state(DriverState::Initialized);
+        self.transition(DriverState::Initialized);
     }
 
     /// Attach the driver to the device (claim ownership)
     /// DriverState must be Initialized, Detached or Attached(x)
     fn attach(&mut self) {
-        #[cfg(unix)]
-        assert!(
-            self.state() == DriverState::Initialized
-                || self.state() == DriverState::Detached
-                || matches!(self.state(), DriverState::Attached(_))
-        );
-        self.set_state(DriverState::Attached(0));
+        span!(DEBUG, "attach", driver = self.name());
+        self.transition(DriverState::Attached(0));
     }
 
     /// Detach the driver from the device
-    /// DriverState must be Detached, Attached(x)
+    /// DriverState must be Attached(x)
     fn detach(&mut self) {
-        #[cfg(unix)]
-        assert!(matches!(self.state(), DriverState::Attached(_)));
-        self.set_state(DriverState::Detached);
+        span!(DEBUG, "detach", driver = self.name());
+        self.transition(DriverState::Detached);
     }
 
-    /// Detach the driver from the device
-    /// DriverState must be Detached, Attached(x)
+    /// Change the sleep level of an attached device
+    /// DriverState must be Attached(x)
     fn set_sleep_level(&mut self, level: usize) {
-        #[cfg(unix)]
-        assert_matches!(self.state(), DriverState::Attached(_));
-        self.set_state(DriverState::Attached(level));
+        let from = self.state();
+        if let Err(e) = self.try_set_sleep_level(level) {
+            panic!("{:?} -> {:?}: {}", from, DriverState::Attached(level), e);
+        }
+    }
+
+    /// Like [`DriverControl::set_sleep_level`] but fails (instead of
+    /// attaching the driver) unless the driver is attached.
+    fn try_set_sleep_level(&mut self, level: usize) -> Result<(), lifecycle::TransitionError> {
+        match self.state() {
+            DriverState::Attached(_) => self.try_transition(DriverState::Attached(level)),
+            _ => Err(lifecycle::TransitionError::InvalidTransition),
+        }
     }
 
     fn destroy(mut self) {
-        #[cfg(unix)]
-        assert!(matches!(self.state(), DriverState::Attached(_)));
-        self.set_state(DriverState::Destroyed);
+        self.transition(DriverState::Destroyed);
+    }
+
+    /// Name used to identify the driver in transition events.
+    fn name(&self) -> &'static str {
+        core::any::type_name::<Self>()
+    }
+
+    /// Moves the driver to `next`, checking the transition table and
+    /// consulting the registered [`lifecycle::StateObserver`]s first.
+    fn try_transition(&mut self, next: DriverState) -> Result<(), lifecycle::TransitionError> {
+        let event = lifecycle::TransitionEvent {
+            driver: self.name(),
+            from: self.state(),
+            to: next,
+        };
+        lifecycle::check(&event)?;
+        self.set_state(next);
+        lifecycle::notify(&event);
+        Ok(())
+    }
+
+    /// Like [`DriverControl::try_transition`] but panics if the transition is
+    /// not allowed.
+    fn transition(&mut self, next: DriverState) {
+        let from = self.state();
+        if let Err(e) = self.try_transition(next) {
+            panic!("{:?} -> {:?}: {}", from, next, e);
+        }
     }
 
     fn state(&self) -> DriverState;
     fn set_state(&mut self, ds: DriverState);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use lifecycle::{StateObserver, TransitionError, TransitionEvent};
+
+    struct Driver(DriverState);
+
+    impl DriverControl for Driver {
+        fn state(&self) -> DriverState {
+            self.0
+        }
+
+        fn set_state(&mut self, ds: DriverState) {
+            self.0 = ds;
+        }
+    }
+
+    /// The driver [`Audit`] watches (other tests may change the state of
+    /// their drivers concurrently).
+    struct Observed(DriverState);
+
+    impl DriverControl for Observed {
+        fn state(&self) -> DriverState {
+            self.0
+        }
+
+        fn set_state(&mut self, ds: DriverState) {
+            self.0 = ds;
+        }
+    }
+
+    /// Vetoes detaching `Observed` and counts its completed transitions.
+    struct Audit(AtomicUsize);
+
+    impl StateObserver for Audit {
+        fn allow(&self, event: &TransitionEvent) -> bool {
+            event.driver != core::any::type_name::<Observed>() || event.to != DriverState::Detached
+        }
+
+        fn notify(&self, event: &TransitionEvent) {
+            if event.driver == core::any::type_name::<Observed>() {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    static AUDIT: Audit = Audit(AtomicUsize::new(0));
+
+    #[test]
+    fn transition_table() {
+        use DriverState::*;
+        let states = [
+            Uninitialized,
+            Initialized,
+            Attached(0),
+            Attached(3),
+            Detached,
+            Destroyed,
+        ];
+        let allowed = [
+            (Uninitialized, Initialized),
+            (Initialized, Attached(0)),
+            (Initialized, Attached(3)),
+            (Attached(0), Attached(0)),
+            (Attached(0), Attached(3)),
+            (Attached(3), Attached(0)),
+            (Attached(3), Attached(3)),
+            (Attached(0), Detached),
+            (Attached(3), Detached),
+            (Attached(0), Destroyed),
+            (Attached(3), Destroyed),
+            (Detached, Attached(0)),
+            (Detached, Attached(3)),
+        ];
+        for &from in states.iter() {
+            for &to in states.iter() {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    allowed.contains(&(from, to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+
+        let mut drv = Driver(Initialized);
+        assert_matches!(
+            drv.try_transition(Destroyed),
+            Err(TransitionError::InvalidTransition)
+        );
+        assert_eq!(drv.state(), Initialized);
+    }
+
+    #[test]
+    fn sleep_level_requires_attached() {
+        for state in [DriverState::Initialized, DriverState::Detached] {
+            let mut drv = Driver(state);
+            assert_matches!(
+                drv.try_set_sleep_level(1),
+                Err(TransitionError::InvalidTransition)
+            );
+            assert_eq!(drv.state(), state);
+        }
+
+        let mut drv = Driver(DriverState::Attached(0));
+        drv.try_set_sleep_level(3).unwrap();
+        assert_eq!(drv.state(), DriverState::Attached(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "Detached -> Attached(1)")]
+    fn sleep_level_of_detached_driver() {
+        Driver(DriverState::Detached).set_sleep_level(1);
+    }
+
+    #[test]
+    fn observer_veto_and_notify() {
+        lifecycle::register_observer(&AUDIT).unwrap();
+
+        let mut drv = Observed(DriverState::Uninitialized);
+        drv.init();
+        drv.attach();
+        drv.set_sleep_level(2);
+        assert_eq!(drv.state(), DriverState::Attached(2));
+        assert_eq!(AUDIT.0.load(Ordering::Relaxed), 3);
+
+        assert_matches!(
+            drv.try_transition(DriverState::Detached),
+            Err(TransitionError::Vetoed)
+        );
+        assert_eq!(drv.state(), DriverState::Attached(2));
+        assert_eq!(AUDIT.0.load(Ordering::Relaxed), 3);
+
+        lifecycle::unregister_observer(&AUDIT);
+        drv.detach();
+        assert_eq!(drv.state(), DriverState::Detached);
+        assert_eq!(AUDIT.0.load(Ordering::Relaxed), 3);
+    }
+}
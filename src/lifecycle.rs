@@ -0,0 +1,291 @@
+//! Observers for driver life-cycle transitions.
+//!
+//! Every state change made through
+//! [`DriverControl::try_transition`](crate::DriverControl::try_transition) is
+//! checked against [`DriverState::can_transition_to`] and then reported to the
+//! registered observers. Observers can be used for audit logging, or by the
+//! host to enforce its own policy by vetoing transitions.
+//!
+//! [`AsyncDriverControl`] is the poll-based counterpart of
+//! [`DriverControl`](crate::DriverControl) for devices whose life-cycle
+//! operations involve long waits (firmware boot, link training etc.).
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use custom_error::custom_error;
+use spin::Mutex;
+
+use crate::{DriverControl, DriverState};
+
+/// Maximum number of observers that can be registered at the same time.
+pub const MAX_OBSERVERS: usize = 8;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub TransitionError
+    InvalidTransition = "the driver state transition is not allowed",
+    Vetoed = "an observer refused the driver state transition",
+    TooManyObservers = "no free slot to register another observer",
+}
+
+/// A driver state change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TransitionEvent {
+    /// Name of the driver (see [`DriverControl::name`](crate::DriverControl::name)).
+    pub driver: &'static str,
+    pub from: DriverState,
+    pub to: DriverState,
+}
+
+/// Gets notified about driver state transitions.
+///
+/// Observers are called with the registry lock held and must not register or
+/// unregister observers themselves.
+pub trait StateObserver: Sync {
+    /// Called before a (valid) transition is made, returning false vetoes it.
+    fn allow(&self, _event: &TransitionEvent) -> bool {
+        true
+    }
+
+    /// Called after the driver changed its state.
+    fn notify(&self, event: &TransitionEvent);
+}
+
+static OBSERVERS: Mutex<[Option<&'static dyn StateObserver>; MAX_OBSERVERS]> =
+    Mutex::new([None; MAX_OBSERVERS]);
+
+/// Registers an observer for all driver state transitions.
+pub fn register_observer(observer: &'static dyn StateObserver) -> Result<(), TransitionError> {
+    let mut observers = OBSERVERS.lock();
+    let slot = observers
+        .iter_mut()
+        .find(|o| o.is_none())
+        .ok_or(TransitionError::TooManyObservers)?;
+    *slot = Some(observer);
+    Ok(())
+}
+
+/// Removes a previously registered observer.
+pub fn unregister_observer(observer: &'static dyn StateObserver) {
+    let mut observers = OBSERVERS.lock();
+    for slot in observers.iter_mut() {
+        if let Some(o) = slot {
+            if core::ptr::eq(
+                *o as *const dyn StateObserver as *const (),
+                observer as *const dyn StateObserver as *const (),
+            ) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Checks that `event` is a valid transition and no observer objects to it.
+pub(crate) fn check(event: &TransitionEvent) -> Result<(), TransitionError> {
+    if !event.from.can_transition_to(event.to) {
+        return Err(TransitionError::InvalidTransition);
+    }
+
+    let observers = OBSERVERS.lock();
+    if observers.iter().flatten().all(|o| o.allow(event)) {
+        Ok(())
+    } else {
+        Err(TransitionError::Vetoed)
+    }
+}
+
+/// Reports a completed transition to all observers.
+pub(crate) fn notify(event: &TransitionEvent) {
+    let observers = OBSERVERS.lock();
+    for o in observers.iter().flatten() {
+        o.notify(event);
+    }
+}
+
+/// Driver life-cycle management for operations that can't complete right away.
+///
+/// Instead of busy-waiting for the device, the `poll_*` functions make as
+/// much progress as possible and return `Poll::Pending` (after arranging for
+/// `cx.waker()` to be woken) if they have to wait. The state transition is
+/// validated before the first poll and only applied once the operation
+/// completed.
+pub trait AsyncDriverControl: DriverControl {
+    /// Initialize the device
+    /// DriverState must be Uninitialized
+    fn poll_init(&mut self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    /// Attach the driver to the device (claim ownership)
+    /// DriverState must be Initialized, Detached or Attached(x)
+    fn poll_attach(&mut self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    /// Put the device to sleep level `level`
+    /// DriverState must be Attached(x)
+    fn poll_suspend(&mut self, _level: usize, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    /// Bring the device back to sleep level 0
+    /// DriverState must be Attached(x)
+    fn poll_resume(&mut self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    fn init_async(&mut self) -> Lifecycle<'_, Self> {
+        Lifecycle::new(self, LifecycleOp::Init)
+    }
+
+    fn attach_async(&mut self) -> Lifecycle<'_, Self> {
+        Lifecycle::new(self, LifecycleOp::Attach)
+    }
+
+    fn suspend_async(&mut self, level: usize) -> Lifecycle<'_, Self> {
+        Lifecycle::new(self, LifecycleOp::Suspend(level))
+    }
+
+    fn resume_async(&mut self) -> Lifecycle<'_, Self> {
+        Lifecycle::new(self, LifecycleOp::Resume)
+    }
+}
+
+/// An operation of [`AsyncDriverControl`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleOp {
+    Init,
+    Attach,
+    Suspend(usize),
+    Resume,
+}
+
+impl LifecycleOp {
+    /// The state the driver is in once the operation completed.
+    pub fn target(&self) -> DriverState {
+        match *self {
+            LifecycleOp::Init => DriverState::Initialized,
+            LifecycleOp::Attach | LifecycleOp::Resume => DriverState::Attached(0),
+            LifecycleOp::Suspend(level) => DriverState::Attached(level),
+        }
+    }
+}
+
+/// Future driving an [`AsyncDriverControl`] operation to completion.
+pub struct Lifecycle<'a, D: AsyncDriverControl> {
+    drv: &'a mut D,
+    op: LifecycleOp,
+    started: bool,
+}
+
+impl<'a, D: AsyncDriverControl> Lifecycle<'a, D> {
+    fn new(drv: &'a mut D, op: LifecycleOp) -> Self {
+        Lifecycle {
+            drv,
+            op,
+            started: false,
+        }
+    }
+}
+
+impl<'a, D: AsyncDriverControl> Future for Lifecycle<'a, D> {
+    type Output = Result<(), TransitionError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        span!(DEBUG, "lifecycle", driver = this.drv.name(), op = this.op);
+
+        if !this.started {
+            let from = this.drv.state();
+            let valid = match this.op {
+                LifecycleOp::Suspend(_) | LifecycleOp::Resume => {
+                    matches!(from, DriverState::Attached(_))
+                }
+                _ => from.can_transition_to(this.op.target()),
+            };
+            if !valid {
+                return Poll::Ready(Err(TransitionError::InvalidTransition));
+            }
+            this.started = true;
+        }
+
+        let progress = match this.op {
+            LifecycleOp::Init => this.drv.poll_init(cx),
+            LifecycleOp::Attach => this.drv.poll_attach(cx),
+            LifecycleOp::Suspend(level) => this.drv.poll_suspend(level, cx),
+            LifecycleOp::Resume => this.drv.poll_resume(cx),
+        };
+
+        match progress {
+            Poll::Ready(()) => Poll::Ready(this.drv.try_transition(this.op.target())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::task::Waker;
+
+    /// Needs `polls` more polls until it is attached.
+    struct Slow {
+        state: DriverState,
+        polls: usize,
+    }
+
+    impl DriverControl for Slow {
+        fn state(&self) -> DriverState {
+            self.state
+        }
+
+        fn set_state(&mut self, ds: DriverState) {
+            self.state = ds;
+        }
+    }
+
+    impl AsyncDriverControl for Slow {
+        fn poll_attach(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            if self.polls == 0 {
+                return Poll::Ready(());
+            }
+            self.polls -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn state_applied_on_completion() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut drv = Slow {
+            state: DriverState::Uninitialized,
+            polls: 2,
+        };
+        assert_matches!(
+            Pin::new(&mut drv.init_async()).poll(&mut cx),
+            Poll::Ready(Ok(()))
+        );
+        assert_eq!(drv.state(), DriverState::Initialized);
+
+        let mut attach = drv.attach_async();
+        for _ in 0..2 {
+            assert_matches!(Pin::new(&mut attach).poll(&mut cx), Poll::Pending);
+            assert_eq!(attach.drv.state(), DriverState::Initialized);
+        }
+        assert_matches!(Pin::new(&mut attach).poll(&mut cx), Poll::Ready(Ok(())));
+        assert_eq!(drv.state(), DriverState::Attached(0));
+
+        // Checked before the driver is polled
+        drv.state = DriverState::Detached;
+        drv.polls = 1;
+        assert_matches!(
+            Pin::new(&mut drv.suspend_async(1)).poll(&mut cx),
+            Poll::Ready(Err(TransitionError::InvalidTransition))
+        );
+        assert_eq!((drv.state(), drv.polls), (DriverState::Detached, 1));
+    }
+}
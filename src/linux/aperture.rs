@@ -0,0 +1,300 @@
+//! Devices with a small register BAR and a huge prefetchable BAR.
+//!
+//! GPUs and FPGA cards expose their registers in a BAR of a few MiB and
+//! their memory (framebuffer, card DRAM) in a prefetchable BAR of up to
+//! tens of GiB. The registers have to be mapped uncached, the memory is
+//! best mapped write-combining, and mapping all of it wastes address space
+//! and page tables for data that is touched a window at a time.
+//! [`Aperture`] maps the large BAR on demand in windows and keeps a few of
+//! them around, [`ApertureDevice`] bundles it with the register mapping.
+
+use core::marker::PhantomData;
+use std::fs::File;
+use std::io;
+use std::prelude::v1::*;
+
+use crate::iomem::MapOptions;
+use crate::pci::PCIAddress;
+
+use super::sysfs::{map_resource, open_resource, BarMapping};
+
+/// Size (and alignment) of the windows of an [`Aperture`] by default.
+pub const DEFAULT_WINDOW_SIZE: usize = 2 << 20;
+/// Windows an [`Aperture`] keeps mapped by default.
+pub const DEFAULT_MAX_WINDOWS: usize = 8;
+
+struct Window {
+    offset: u64,
+    mapping: BarMapping,
+    /// When the window was last used (to evict the least recently used).
+    used: u64,
+}
+
+/// A large BAR mapped in windows as they are accessed.
+pub struct Aperture {
+    file: File,
+    size: u64,
+    options: MapOptions,
+    window_size: usize,
+    max_windows: usize,
+    windows: Vec<Window>,
+    clock: u64,
+}
+
+impl Aperture {
+    /// Maps BAR `index` of the device write-combining (uncached if the
+    /// kernel doesn't allow it) in windows of `window_size` bytes (a
+    /// multiple of the page size), keeping at most `max_windows` mapped.
+    pub fn open(
+        addr: PCIAddress,
+        index: u8,
+        window_size: usize,
+        max_windows: usize,
+    ) -> io::Result<Aperture> {
+        let (file, options) = open_resource(addr, index, MapOptions::write_combining())?;
+        let aperture = Aperture::from_file(file, options, window_size, max_windows)?;
+        debug!(
+            "Opened BAR {} of {:?} as aperture of {:#x} bytes",
+            index, addr, aperture.size
+        );
+        Ok(aperture)
+    }
+
+    fn from_file(
+        file: File,
+        options: MapOptions,
+        window_size: usize,
+        max_windows: usize,
+    ) -> io::Result<Aperture> {
+        if window_size == 0 || !window_size.is_multiple_of(4096) || max_windows == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid aperture window size or count",
+            ));
+        }
+        Ok(Aperture {
+            size: file.metadata()?.len(),
+            file,
+            options,
+            window_size,
+            max_windows,
+            windows: Vec::new(),
+            clock: 0,
+        })
+    }
+
+    /// Size of the BAR.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The options the windows are mapped with.
+    pub fn options(&self) -> MapOptions {
+        self.options
+    }
+
+    pub fn mapped_windows(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Makes `len` bytes at `offset` of the BAR accessible, mapping the
+    /// window(s) around them if they aren't mapped yet.
+    ///
+    /// A range within one window reuses that window. A range that crosses
+    /// windows gets a mapping of its own covering all of them. If too many
+    /// windows are mapped, the least recently used one is unmapped.
+    pub fn window(&mut self, offset: u64, len: usize) -> io::Result<ApertureWindow<'_>> {
+        if len == 0
+            || offset
+                .checked_add(len as u64)
+                .is_none_or(|end| end > self.size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range outside of the aperture",
+            ));
+        }
+        let end = offset + len as u64;
+        self.clock += 1;
+
+        let index = match self
+            .windows
+            .iter()
+            .position(|w| w.offset <= offset && end <= w.offset + w.mapping.len() as u64)
+        {
+            Some(index) => index,
+            None => {
+                let window_size = self.window_size as u64;
+                let start = offset / window_size * window_size;
+                let window_end = end
+                    .div_ceil(window_size)
+                    .saturating_mul(window_size)
+                    .min(self.size);
+                if self.windows.len() >= self.max_windows {
+                    let lru = (0..self.windows.len())
+                        .min_by_key(|i| self.windows[*i].used)
+                        .unwrap();
+                    let evicted = self.windows.swap_remove(lru);
+                    trace!("Unmapping aperture window at {:#x}", evicted.offset);
+                }
+                let mapping = BarMapping::map_file(
+                    &self.file,
+                    start,
+                    (window_end - start) as usize,
+                    self.options,
+                )?;
+                trace!(
+                    "Mapped aperture window at {:#x} ({:#x} bytes)",
+                    start,
+                    window_end - start
+                );
+                self.windows.push(Window {
+                    offset: start,
+                    mapping,
+                    used: 0,
+                });
+                self.windows.len() - 1
+            }
+        };
+
+        let window = &mut self.windows[index];
+        window.used = self.clock;
+        Ok(ApertureWindow {
+            ptr: window
+                .mapping
+                .as_mut_ptr()
+                .wrapping_add((offset - window.offset) as usize),
+            len,
+            _aperture: PhantomData,
+        })
+    }
+}
+
+impl core::fmt::Debug for Aperture {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "Aperture({:#x} bytes, {} windows mapped)",
+            self.size,
+            self.windows.len()
+        )
+    }
+}
+
+/// A range of an [`Aperture`], valid until the next call to
+/// [`Aperture::window`].
+#[derive(Debug)]
+pub struct ApertureWindow<'a> {
+    ptr: *mut u8,
+    len: usize,
+    _aperture: PhantomData<&'a mut Aperture>,
+}
+
+impl<'a> ApertureWindow<'a> {
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies `data` to `offset` in the window (e.g., a texture or a
+    /// bitstream).
+    ///
+    /// Write-combined writes may still be buffered afterwards, issue a
+    /// barrier before telling the device about them.
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        assert!(offset
+            .checked_add(data.len())
+            .is_some_and(|end| end <= self.len));
+        // Safety: the range is within the mapped window, which is borrowed
+        // mutably
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(offset), data.len()) };
+    }
+
+    /// Copies `buf.len()` bytes at `offset` in the window to `buf`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        assert!(offset
+            .checked_add(buf.len())
+            .is_some_and(|end| end <= self.len));
+        // Safety: as in `write`
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.ptr.add(offset), buf.as_mut_ptr(), buf.len())
+        };
+    }
+}
+
+/// A device with an uncached register BAR and a windowed aperture BAR.
+#[derive(Debug)]
+pub struct ApertureDevice {
+    registers: BarMapping,
+    aperture: Aperture,
+}
+
+impl ApertureDevice {
+    /// Maps register BAR `registers` of the device uncached and opens BAR
+    /// `aperture` with the default window size and count.
+    pub fn open(addr: PCIAddress, registers: u8, aperture: u8) -> io::Result<ApertureDevice> {
+        Ok(ApertureDevice {
+            registers: map_resource(addr, registers, MapOptions::device())?,
+            aperture: Aperture::open(addr, aperture, DEFAULT_WINDOW_SIZE, DEFAULT_MAX_WINDOWS)?,
+        })
+    }
+
+    pub fn registers(&self) -> &BarMapping {
+        &self.registers
+    }
+
+    pub fn aperture(&mut self) -> &mut Aperture {
+        &mut self.aperture
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn windows_on_demand() {
+        let path = std::env::temp_dir().join(format!("driverkit-aperture-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(64 * 1024).unwrap();
+        let check = file.try_clone().unwrap();
+
+        let mut aperture = Aperture::from_file(file, MapOptions::device(), 8192, 2).unwrap();
+        aperture.window(0x100, 4).unwrap().write(0, b"head");
+        aperture.window(0x1ff0, 4).unwrap().write(0, b"same");
+        assert_eq!(aperture.mapped_windows(), 1);
+        // Crosses into the next window, then evicts the least recent one
+        aperture.window(0x3ffe, 4).unwrap().write(0, b"span");
+        aperture.window(0x100, 4).unwrap();
+        aperture
+            .window(0xf000, 0x1000)
+            .unwrap()
+            .write(0xffc, b"tail");
+        assert_eq!(aperture.mapped_windows(), 2);
+
+        let mut buf = [0u8; 4];
+        aperture.window(0x3ffe, 4).unwrap().read(0, &mut buf);
+        assert_eq!(&buf, b"span");
+        for (offset, expected) in [(0x100, b"head"), (0x1ff0, b"same"), (0xfffc, b"tail")] {
+            check.read_exact_at(&mut buf, offset).unwrap();
+            assert_eq!(&buf, expected);
+        }
+        assert!(aperture.window(0xfffe, 4).is_err());
+    }
+}
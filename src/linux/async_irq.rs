@@ -0,0 +1,270 @@
+//! Interrupt eventfds as async streams.
+//!
+//! [`InterruptFd`] turns a VFIO interrupt eventfd into something an async
+//! driver can `.await`. It doesn't depend on a particular runtime, it only
+//! needs a [`Reactor`] that wakes a task once the fd becomes readable
+//! (e.g., an adapter around tokio's `AsyncFd`, or the [`EpollReactor`] in
+//! here for hosts without a runtime).
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::prelude::v1::*;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Wakes tasks waiting for file descriptors.
+pub trait Reactor {
+    /// Arranges for `waker` to be woken once `fd` is readable, replacing
+    /// any waker registered for `fd` before.
+    fn register(&self, fd: RawFd, waker: &Waker) -> io::Result<()>;
+
+    /// Forgets about `fd`.
+    fn deregister(&self, fd: RawFd) -> io::Result<()>;
+}
+
+impl<R: Reactor + ?Sized> Reactor for &R {
+    fn register(&self, fd: RawFd, waker: &Waker) -> io::Result<()> {
+        (**self).register(fd, waker)
+    }
+
+    fn deregister(&self, fd: RawFd) -> io::Result<()> {
+        (**self).deregister(fd)
+    }
+}
+
+/// The interrupts of one vector, signalled through an eventfd.
+///
+/// Yields the number of interrupts since the last item, like a
+/// `futures::Stream<Item = io::Result<u64>>` (see
+/// [`InterruptFd::poll_next`]).
+pub struct InterruptFd<R: Reactor> {
+    fd: RawFd,
+    vector: usize,
+    reactor: R,
+}
+
+impl<R: Reactor> InterruptFd<R> {
+    /// Wraps the eventfd `fd` of `vector` (which is switched to non-blocking
+    /// mode). The caller keeps ownership of `fd`.
+    pub fn new(fd: RawFd, vector: usize, reactor: R) -> io::Result<InterruptFd<R>> {
+        // Safety: fcntl only operates on the file descriptor
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        // Safety: as above
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(InterruptFd {
+            fd,
+            vector,
+            reactor,
+        })
+    }
+
+    pub fn vector(&self) -> usize {
+        self.vector
+    }
+
+    /// Reads the counter, returns None if no interrupt is pending.
+    fn try_read(&self) -> io::Result<Option<u64>> {
+        let mut buf = [0u8; 8];
+        // Safety: `buf` is valid for 8 bytes
+        let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n == 8 {
+            return Ok(Some(u64::from_ne_bytes(buf)));
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::WouldBlock => Ok(None),
+            _ if n >= 0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "short read of eventfd",
+            )),
+            _ => Err(error),
+        }
+    }
+
+    /// Polls for the next interrupts.
+    pub fn poll_next(&self, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        match self.try_read() {
+            Ok(Some(count)) => return Poll::Ready(Ok(count)),
+            Ok(None) => (),
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+        if let Err(e) = self.reactor.register(self.fd, cx.waker()) {
+            return Poll::Ready(Err(e));
+        }
+        // An interrupt may have arrived before the waker was registered
+        match self.try_read() {
+            Ok(Some(count)) => Poll::Ready(Ok(count)),
+            Ok(None) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Waits for the next interrupts.
+    pub fn next(&self) -> NextInterrupt<'_, R> {
+        NextInterrupt { irq: self }
+    }
+}
+
+impl<R: Reactor> Drop for InterruptFd<R> {
+    fn drop(&mut self) {
+        let _ = self.reactor.deregister(self.fd);
+    }
+}
+
+/// Future returned by [`InterruptFd::next`].
+pub struct NextInterrupt<'a, R: Reactor> {
+    irq: &'a InterruptFd<R>,
+}
+
+impl<'a, R: Reactor> Future for NextInterrupt<'a, R> {
+    type Output = io::Result<u64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.irq.poll_next(cx)
+    }
+}
+
+/// A minimal [`Reactor`] for hosts without an async runtime: some thread
+/// has to call [`EpollReactor::turn`] to wake the tasks.
+pub struct EpollReactor {
+    epoll: RawFd,
+    wakers: Mutex<Vec<(RawFd, Waker)>>,
+}
+
+impl EpollReactor {
+    pub fn new() -> io::Result<EpollReactor> {
+        // Safety: epoll_create1 has no preconditions
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EpollReactor {
+            epoll,
+            wakers: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Waits at most `timeout` for registered fds to become readable and
+    /// wakes their tasks. Returns the number of tasks woken.
+    pub fn turn(&self, timeout: Duration) -> io::Result<usize> {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        let timeout_ms = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+        // Safety: `event` is valid for one entry
+        let n = unsafe { libc::epoll_wait(self.epoll, &mut event, 1, timeout_ms) };
+        if n < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::Interrupted => Ok(0),
+                _ => Err(error),
+            };
+        }
+        if n == 0 {
+            return Ok(0);
+        }
+
+        // One-shot registrations: the fd stays in the epoll set but is
+        // disarmed until the task registers again
+        let fd = event.u64 as RawFd;
+        let mut wakers = self.wakers.lock().unwrap();
+        match wakers.iter().position(|(registered, _)| *registered == fd) {
+            Some(index) => {
+                wakers.swap_remove(index).1.wake();
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+impl Reactor for EpollReactor {
+    fn register(&self, fd: RawFd, waker: &Waker) -> io::Result<()> {
+        let mut wakers = self.wakers.lock().unwrap();
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLONESHOT) as u32,
+            u64: fd as u64,
+        };
+        // Safety: `event` is a valid epoll_event
+        let rearm = unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_MOD, fd, &mut event) };
+        // Safety: as above
+        if rearm != 0
+            && unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_ADD, fd, &mut event) } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        match wakers.iter_mut().find(|(registered, _)| *registered == fd) {
+            Some((_, registered)) => registered.clone_from(waker),
+            None => wakers.push((fd, waker.clone())),
+        }
+        Ok(())
+    }
+
+    fn deregister(&self, fd: RawFd) -> io::Result<()> {
+        self.wakers
+            .lock()
+            .unwrap()
+            .retain(|(registered, _)| *registered != fd);
+        // Safety: the event argument is ignored for EPOLL_CTL_DEL
+        if unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_DEL, fd, core::ptr::null_mut()) }
+            != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EpollReactor {
+    fn drop(&mut self) {
+        // Safety: we own the epoll fd
+        unsafe { libc::close(self.epoll) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn wake_on_interrupt() {
+        // Safety: eventfd has no preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let reactor = EpollReactor::new().unwrap();
+        let irq = InterruptFd::new(fd, 0, &reactor).unwrap();
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(irq.poll_next(&mut cx).is_pending());
+
+        let value = 1u64.to_ne_bytes();
+        // Safety: `value` is valid for 8 bytes
+        assert_eq!(
+            unsafe { libc::write(fd, value.as_ptr() as *const libc::c_void, 8) },
+            8
+        );
+        assert_eq!(reactor.turn(Duration::from_secs(1)).unwrap(), 1);
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(core::matches!(irq.poll_next(&mut cx), Poll::Ready(Ok(1))));
+
+        drop(irq);
+        // Safety: we own the eventfd
+        unsafe { libc::close(fd) };
+    }
+}
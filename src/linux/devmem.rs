@@ -0,0 +1,153 @@
+//! BAR and ECAM mappings through /dev/mem.
+//!
+//! A fallback for when the sysfs resource files aren't there (early
+//! bring-up, unusual kernels) and for ECAM, which sysfs doesn't expose at
+//! all. Needs root and a kernel that permits mapping the range (with
+//! `CONFIG_STRICT_DEVMEM` only MMIO that no driver claimed). Every mapping
+//! is checked against the BAR or ECAM window it should be in, so a bad
+//! offset can't reach arbitrary physical memory.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::RangeInclusive;
+use std::os::unix::fs::OpenOptionsExt;
+use std::prelude::v1::*;
+
+use super::sysfs::BarMapping;
+use crate::arch::VAddr;
+use crate::iomem::{MapOptions, MemoryType};
+use crate::pci::ecam::ECAM_BUS_SIZE;
+use crate::pci::{Bar, BarType, PCIAddress};
+
+pub use crate::pci::{EcamConfig, EcamRegion};
+
+const DEV_MEM: &str = "/dev/mem";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+fn check_root() -> io::Result<()> {
+    // Safety: geteuid has no preconditions
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "mapping /dev/mem needs root",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `len` bytes at `offset` are non-empty and within a region
+/// of `size` bytes.
+fn check_range(offset: u64, len: u64, size: u64) -> io::Result<()> {
+    if len == 0 {
+        return Err(invalid("empty mapping"));
+    }
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(invalid("mapping exceeds the region")),
+    }
+}
+
+/// Maps `len` bytes at physical address `paddr` (uncached, /dev/mem can't
+/// do write-combining).
+fn map_physical(paddr: u64, len: usize, options: MapOptions) -> io::Result<BarMapping> {
+    check_root()?;
+    let options = if options.is_write_combining() {
+        warn!("/dev/mem mappings can't be write-combining, mapping uncached");
+        options.memory_type(MemoryType::Device)
+    } else {
+        options
+    };
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!options.read_only)
+        .custom_flags(libc::O_SYNC)
+        .open(DEV_MEM)?;
+    BarMapping::map_file(&file, paddr, len, options)
+}
+
+/// Maps `len` bytes at `offset` into the memory BAR `bar` (e.g., from
+/// [`PciDevice::bar`](crate::pci::PciDevice::bar)).
+pub fn map_bar(bar: &Bar, offset: u64, len: usize, options: MapOptions) -> io::Result<BarMapping> {
+    span!(DEBUG, "map_bar", address = bar.address + offset, len = len);
+    if let BarType::IO = bar.region_type {
+        return Err(invalid("not a memory BAR"));
+    }
+    if options.is_write_combining() && !bar.prefetchable {
+        return Err(invalid("BAR isn't prefetchable"));
+    }
+    check_range(offset, len as u64, bar.size)?;
+    let mapping = map_physical(bar.address + offset, len, options)?;
+    debug!(
+        "Mapped {:#x} bytes of BAR at {:#x} via /dev/mem",
+        len,
+        bar.address + offset
+    );
+    Ok(mapping)
+}
+
+/// An ECAM (memory mapped configuration space) window mapped through
+/// /dev/mem.
+#[derive(Debug)]
+pub struct Ecam {
+    /// Keeps the window of `region` mapped.
+    _mapping: BarMapping,
+    region: EcamRegion,
+}
+
+impl Ecam {
+    /// Maps the ECAM window for `buses` whose first bus is at physical
+    /// address `base` (from the ACPI MCFG table or the device tree).
+    pub fn map(base: u64, buses: RangeInclusive<u8>) -> io::Result<Ecam> {
+        if buses.is_empty() {
+            return Err(invalid("empty bus range"));
+        }
+        if !base.is_multiple_of(ECAM_BUS_SIZE as u64) {
+            return Err(invalid("ECAM base isn't aligned to a bus"));
+        }
+        let mapping = map_physical(base, EcamRegion::window_len(&buses), MapOptions::device())?;
+        info!(
+            "Mapped ECAM at {:#x} for buses {}..={}",
+            base,
+            buses.start(),
+            buses.end()
+        );
+        // Safety: the mapping covers the window and lives as long as the
+        // region
+        let region = unsafe { EcamRegion::new(VAddr::from(mapping.as_mut_ptr() as u64), buses) };
+        Ok(Ecam {
+            _mapping: mapping,
+            region,
+        })
+    }
+
+    pub fn buses(&self) -> RangeInclusive<u8> {
+        self.region.buses()
+    }
+
+    /// The configuration space of `addr`, if it's in the window.
+    pub fn config(&self, addr: PCIAddress) -> Option<EcamConfig<'_>> {
+        self.region.config(addr)
+    }
+
+    /// The mapped window, e.g., to scan it.
+    pub fn region(&self) -> &EcamRegion {
+        &self.region
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_validation() {
+        assert!(check_range(0, 0x1000, 0x4000).is_ok());
+        assert!(check_range(0x3000, 0x1000, 0x4000).is_ok());
+        assert!(check_range(0x3000, 0x1001, 0x4000).is_err());
+        assert!(check_range(0, 0, 0x4000).is_err());
+        assert!(check_range(u64::MAX, 2, u64::MAX).is_err());
+    }
+}
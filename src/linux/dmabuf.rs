@@ -0,0 +1,272 @@
+//! Sharing DMA buffers with other userspace frameworks as dma-bufs.
+//!
+//! A [`DmaBuf`] is pinned, page-aligned memory backed by a sealed memfd, so
+//! the kernel's udmabuf driver (/dev/udmabuf, `CONFIG_UDMABUF`) can wrap it in
+//! a dma-buf file descriptor. The fd can be handed to GPU (DRM PRIME) or V4L2
+//! (`V4L2_MEMORY_DMABUF`) APIs, which then access the same pages as the
+//! device without copies.
+//!
+//! [`IOBuf`](crate::iomem::IOBuf)s live on the heap and can't be exported,
+//! buffers meant for sharing have to be allocated here. There is no VFIO
+//! backend in this crate yet, so udmabuf is the only exporter.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use crate::VAddr;
+
+use super::mem::{read_pagemap, FOUR_KIB};
+
+const UDMABUF_PATH: &str = "/dev/udmabuf";
+
+/// `_IOW('u', 0x42, struct udmabuf_create)`
+const UDMABUF_CREATE: libc::c_ulong = 0x4018_7542;
+const UDMABUF_FLAGS_CLOEXEC: u32 = 0x01;
+/// `_IOW('b', 0, struct dma_buf_sync)`
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x4008_6200;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+#[repr(C)]
+struct UdmabufCreate {
+    memfd: u32,
+    flags: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// How the CPU accesses an exported buffer, see
+/// [`DmaBufFd::begin_cpu_access`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuAccess {
+    Read = 1,
+    Write = 2,
+    ReadWrite = 3,
+}
+
+/// Memory that can be exported as a dma-buf.
+pub struct DmaBuf {
+    memfd: File,
+    map: *mut u8,
+    len: usize,
+}
+
+// Safety: the mapping is owned by the DmaBuf, accesses go through &/&mut self
+unsafe impl Send for DmaBuf {}
+
+impl DmaBuf {
+    /// Allocates a zeroed buffer of `len` bytes (rounded up to whole
+    /// pages).
+    pub fn new(len: usize) -> io::Result<DmaBuf> {
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty dma-buf"));
+        }
+        let len = len.div_ceil(FOUR_KIB) * FOUR_KIB;
+
+        let name = CString::new("driverkit-dmabuf").unwrap();
+        // Safety: `name` is a valid C string
+        let fd = unsafe {
+            libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: `fd` was just created and isn't owned by anything else
+        let memfd = unsafe { File::from_raw_fd(fd) };
+        memfd.set_len(len as u64)?;
+        // udmabuf refuses memfds that can shrink under the device
+        // Safety: plain fcntl on an fd we own
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, libc::F_SEAL_SHRINK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safety: maps a new region, doesn't touch existing memory
+        let map = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let buf = DmaBuf {
+            memfd,
+            map: map as *mut u8,
+            len,
+        };
+
+        // Make sure the memory is not swapped (the device may DMA into it)
+        // Safety: the range is the mapping created above
+        if unsafe { libc::mlock(map, len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(buf)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn vaddr(&self) -> VAddr {
+        VAddr::from(self.map as u64)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // Safety: the mapping is `len` bytes and lives as long as `self`
+        unsafe { core::slice::from_raw_parts(self.map, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: see `as_slice`, `&mut self` makes the access exclusive
+        unsafe { core::slice::from_raw_parts_mut(self.map, self.len) }
+    }
+
+    /// The physical address of the byte at `offset` (the buffer is only
+    /// physically contiguous within a page).
+    pub fn physical_address(&self, offset: usize) -> io::Result<u64> {
+        if offset >= self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offset beyond the buffer",
+            ));
+        }
+        let page = (self.map as usize + offset) & !(FOUR_KIB - 1);
+        Ok(read_pagemap(page as u64)? + (offset % FOUR_KIB) as u64)
+    }
+
+    /// Exports the whole buffer as a dma-buf.
+    pub fn export(&self) -> io::Result<DmaBufFd> {
+        self.export_range(0, self.len)
+    }
+
+    /// Exports `len` bytes at `offset` (both page-aligned) as a dma-buf.
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] if the kernel doesn't provide
+    /// /dev/udmabuf.
+    pub fn export_range(&self, offset: usize, len: usize) -> io::Result<DmaBufFd> {
+        if !offset.is_multiple_of(FOUR_KIB) || !len.is_multiple_of(FOUR_KIB) || len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dma-buf range isn't page-aligned",
+            ));
+        }
+        if offset.checked_add(len).is_none_or(|end| end > self.len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dma-buf range beyond the buffer",
+            ));
+        }
+
+        let udmabuf = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(UDMABUF_PATH)?;
+        let create = UdmabufCreate {
+            memfd: self.memfd.as_raw_fd() as u32,
+            flags: UDMABUF_FLAGS_CLOEXEC,
+            offset: offset as u64,
+            size: len as u64,
+        };
+        // Safety: `create` matches `struct udmabuf_create`
+        let fd = unsafe { libc::ioctl(udmabuf.as_raw_fd(), UDMABUF_CREATE, &create) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        debug!(
+            "Exported {:#x} bytes at {:#x} as dma-buf {}",
+            len, offset, fd
+        );
+        // Safety: the ioctl returned a new fd we own
+        Ok(DmaBufFd(unsafe { File::from_raw_fd(fd) }))
+    }
+}
+
+impl Drop for DmaBuf {
+    fn drop(&mut self) {
+        // Safety: unmaps the region mapped in `new`, which nothing borrows
+        // anymore
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A dma-buf file descriptor, closed on drop (unless taken with
+/// [`IntoRawFd::into_raw_fd`]).
+#[derive(Debug)]
+pub struct DmaBufFd(File);
+
+impl DmaBufFd {
+    /// Brackets CPU accesses to the buffer while other importers may use
+    /// it, so the exporter can flush or invalidate caches.
+    pub fn begin_cpu_access(&self, access: CpuAccess) -> io::Result<()> {
+        self.sync(DMA_BUF_SYNC_START | access as u64)
+    }
+
+    /// Ends a CPU access started with [`DmaBufFd::begin_cpu_access`].
+    pub fn end_cpu_access(&self, access: CpuAccess) -> io::Result<()> {
+        self.sync(DMA_BUF_SYNC_END | access as u64)
+    }
+
+    fn sync(&self, flags: u64) -> io::Result<()> {
+        // Safety: `flags` matches `struct dma_buf_sync`
+        if unsafe { libc::ioctl(self.0.as_raw_fd(), DMA_BUF_IOCTL_SYNC, &flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for DmaBufFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for DmaBufFd {
+    fn into_raw_fd(self) -> RawFd {
+        self.0.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_and_export() {
+        let mut buf = DmaBuf::new(100).unwrap();
+        assert_eq!(buf.len(), FOUR_KIB);
+        assert!(buf.as_slice().iter().all(|b| *b == 0));
+        buf.as_mut_slice()[..4].copy_from_slice(b"test");
+
+        assert_eq!(
+            buf.export_range(FOUR_KIB, FOUR_KIB).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            buf.export_range(1, FOUR_KIB).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        // Only works with udmabuf support (and access to it)
+        match buf.export() {
+            Ok(fd) => {
+                fd.begin_cpu_access(CpuAccess::Read).unwrap();
+                fd.end_cpu_access(CpuAccess::Read).unwrap();
+            }
+            Err(e) => assert!(matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+            )),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Waiting for device interrupts on Linux.
+//!
+//! VFIO and uio signal interrupts through file descriptors: VFIO hands out
+//! one eventfd per vector, uio a device file that counts interrupts.
+//! [`EventLoop`] waits on all of them with a single epoll instance and
+//! calls the handler registered for the vector that fired.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::prelude::v1::*;
+use std::time::{Duration, Instant};
+
+/// Called with the vector and the number of interrupts since the last call.
+pub type Handler<'a> = Box<dyn FnMut(usize, u64) + 'a>;
+
+/// Maximum number of events handled per `epoll_wait`.
+const MAX_EVENTS: usize = 32;
+
+struct Source<'a> {
+    fd: RawFd,
+    vector: usize,
+    handler: Handler<'a>,
+}
+
+/// Dispatches interrupts signalled through eventfds (or uio device files)
+/// to per-vector handlers.
+pub struct EventLoop<'a> {
+    epoll: RawFd,
+    sources: Vec<Source<'a>>,
+}
+
+impl<'a> EventLoop<'a> {
+    pub fn new() -> io::Result<EventLoop<'a>> {
+        // Safety: epoll_create1 has no preconditions
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventLoop {
+            epoll,
+            sources: Vec::new(),
+        })
+    }
+
+    /// Calls `handler` whenever `fd` signals an interrupt of `vector`.
+    ///
+    /// `fd` must stay open as long as it's registered; reading it has to
+    /// return an 8-byte (eventfd) or 4-byte (uio) counter.
+    pub fn add(&mut self, fd: RawFd, vector: usize, handler: Handler<'a>) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: self.sources.len() as u64,
+        };
+        // Safety: `event` is a valid epoll_event, epoll only stores the fd
+        if unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_ADD, fd, &mut event) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.sources.push(Source {
+            fd,
+            vector,
+            handler,
+        });
+        Ok(())
+    }
+
+    /// Stops watching `fd`.
+    pub fn remove(&mut self, fd: RawFd) -> io::Result<()> {
+        let index = self
+            .sources
+            .iter()
+            .position(|source| source.fd == fd)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "fd isn't registered"))?;
+        // Safety: the event argument is ignored for EPOLL_CTL_DEL
+        if unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_DEL, fd, core::ptr::null_mut()) }
+            != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        // Sources are referred to by index, so keep the (now unreachable)
+        // entry around
+        self.sources[index].fd = -1;
+        Ok(())
+    }
+
+    /// Reads the interrupt counter of `fd`.
+    fn drain(fd: RawFd) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        // Safety: `buf` is valid for 8 bytes
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        match n {
+            8 => Ok(u64::from_ne_bytes(buf)),
+            4 => Ok(u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as u64),
+            _ if n < 0 => Err(io::Error::last_os_error()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "short read of interrupt counter",
+            )),
+        }
+    }
+
+    /// Waits at most `timeout` (forever if None) for interrupts and calls
+    /// their handlers.
+    ///
+    /// Returns the number of handlers called (0 on timeout).
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> io::Result<usize> {
+        // Round up so short timeouts don't turn into busy polling
+        let timeout_ms = timeout.map_or(-1, |t| {
+            t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+        });
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let n = loop {
+            // Safety: `events` is valid for MAX_EVENTS entries
+            let n = unsafe {
+                libc::epoll_wait(
+                    self.epoll,
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as i32,
+                    timeout_ms,
+                )
+            };
+            if n >= 0 {
+                break n as usize;
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        };
+
+        for event in &events[..n] {
+            let source = &mut self.sources[event.u64 as usize];
+            let count = EventLoop::drain(source.fd)?;
+            trace!("Interrupt on vector {} ({} times)", source.vector, count);
+            (source.handler)(source.vector, count);
+        }
+        Ok(n)
+    }
+
+    /// Handles interrupts until `done` returns true or `timeout` expired.
+    ///
+    /// Returns whether `done` returned true.
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut done: impl FnMut() -> bool,
+    ) -> io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        while !done() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            self.run_once(Some(deadline - now))?;
+        }
+        Ok(true)
+    }
+}
+
+impl<'a> Drop for EventLoop<'a> {
+    fn drop(&mut self) {
+        // Safety: we own the epoll fd
+        unsafe { libc::close(self.epoll) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn dispatch() {
+        // Safety: eventfd has no preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let fired = Cell::new(0);
+        let mut event_loop = EventLoop::new().unwrap();
+        event_loop
+            .add(
+                fd,
+                3,
+                Box::new(|vector, count| {
+                    assert_eq!(vector, 3);
+                    fired.set(fired.get() + count);
+                }),
+            )
+            .unwrap();
+
+        assert_eq!(
+            event_loop.run_once(Some(Duration::from_millis(1))).unwrap(),
+            0
+        );
+        let value = 2u64.to_ne_bytes();
+        // Safety: `value` is valid for 8 bytes
+        assert_eq!(
+            unsafe { libc::write(fd, value.as_ptr() as *const libc::c_void, 8) },
+            8
+        );
+        assert!(event_loop
+            .run_until(Duration::from_secs(1), || fired.get() == 2)
+            .unwrap());
+
+        drop(event_loop);
+        // Safety: we own the eventfd
+        unsafe { libc::close(fd) };
+    }
+}
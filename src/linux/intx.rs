@@ -0,0 +1,350 @@
+//! Legacy INTx interrupts through uio_pci_generic or VFIO.
+//!
+//! INTx is level-triggered: the device keeps its pin asserted until the
+//! driver services it. The kernel's handler therefore masks the device
+//! before signalling userspace, by setting Interrupt Disable in the Command
+//! register ("DisINTx+") if the device has it, or by masking the whole line
+//! otherwise (VFIO only, then the line can't be shared). The driver handles
+//! the device and unmasks it through its [`IntxLine`], which clears
+//! DisINTx again. A device that still (or again) asserts INTx then
+//! interrupts right away.
+//!
+//! Several devices on one line can be driven from one process with
+//! [`SharedIntx`]: every interrupt is offered to all handlers, each checks
+//! the Interrupt Status of its device.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::prelude::v1::*;
+
+use crate::pci::{ConfigSpace, PciDevice};
+
+/// `_IO(VFIO_TYPE, VFIO_BASE + 10)`
+const VFIO_DEVICE_SET_IRQS: libc::c_ulong = 0x3b6e;
+const VFIO_PCI_INTX_IRQ_INDEX: u32 = 0;
+const VFIO_IRQ_SET_DATA_NONE: u32 = 1 << 0;
+const VFIO_IRQ_SET_DATA_EVENTFD: u32 = 1 << 2;
+const VFIO_IRQ_SET_ACTION_UNMASK: u32 = 1 << 4;
+const VFIO_IRQ_SET_ACTION_TRIGGER: u32 = 1 << 5;
+
+/// Unhandled interrupts after which [`SharedIntx`] warns (again).
+const UNHANDLED_WARN: u64 = 1000;
+
+/// `struct vfio_irq_set` with room for one eventfd.
+#[repr(C)]
+struct VfioIrqSet {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    start: u32,
+    count: u32,
+    data: i32,
+}
+
+/// The file descriptor an INTx line is signalled through (register it with
+/// an [`EventLoop`](super::eventloop::EventLoop)).
+pub trait IntxLine: AsRawFd {
+    /// Consumes the interrupts signalled so far.
+    ///
+    /// # Returns
+    /// The interrupt counter (uio: total count, VFIO: count since the last
+    /// call).
+    fn ack(&mut self) -> io::Result<u64>;
+
+    /// Unmasks the interrupt once the devices on the line were handled.
+    fn unmask(&mut self) -> io::Result<()>;
+}
+
+/// INTx of a device bound to uio_pci_generic.
+#[derive(Debug)]
+pub struct UioIntx(File);
+
+impl UioIntx {
+    /// Opens /dev/uio`index` and unmasks the interrupt.
+    pub fn open(index: usize) -> io::Result<UioIntx> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/dev/uio{}", index))?;
+        let mut intx = UioIntx(file);
+        intx.unmask()?;
+        Ok(intx)
+    }
+}
+
+impl IntxLine for UioIntx {
+    fn ack(&mut self) -> io::Result<u64> {
+        let mut count = [0u8; 4];
+        self.0.read_exact(&mut count)?;
+        Ok(u32::from_ne_bytes(count) as u64)
+    }
+
+    fn unmask(&mut self) -> io::Result<()> {
+        // uio_pci_generic's irqcontrol clears DisINTx
+        self.0.write_all(&1u32.to_ne_bytes())
+    }
+}
+
+impl AsRawFd for UioIntx {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// INTx of a device driven through VFIO, signalled through an eventfd.
+#[derive(Debug)]
+pub struct VfioIntx {
+    device: RawFd,
+    eventfd: File,
+}
+
+impl VfioIntx {
+    /// Enables INTx of the VFIO device `device` (which must stay open while
+    /// the returned line is used).
+    pub fn new(device: RawFd) -> io::Result<VfioIntx> {
+        // Safety: eventfd has no preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: `fd` was just created and isn't owned by anything else
+        let eventfd = unsafe { File::from_raw_fd(fd) };
+        set_irqs(
+            device,
+            VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+            1,
+            fd,
+        )?;
+        debug!("Enabled INTx of VFIO device {} on eventfd {}", device, fd);
+        Ok(VfioIntx { device, eventfd })
+    }
+}
+
+impl IntxLine for VfioIntx {
+    fn ack(&mut self) -> io::Result<u64> {
+        let mut count = [0u8; 8];
+        self.eventfd.read_exact(&mut count)?;
+        Ok(u64::from_ne_bytes(count))
+    }
+
+    fn unmask(&mut self) -> io::Result<()> {
+        set_irqs(
+            self.device,
+            VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_UNMASK,
+            1,
+            -1,
+        )
+    }
+}
+
+impl AsRawFd for VfioIntx {
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
+
+impl Drop for VfioIntx {
+    fn drop(&mut self) {
+        // A trigger with count 0 disables INTx
+        if let Err(e) = set_irqs(
+            self.device,
+            VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER,
+            0,
+            -1,
+        ) {
+            warn!(
+                "Couldn't disable INTx of VFIO device {} (errno {:?})",
+                self.device,
+                e.raw_os_error()
+            );
+        }
+    }
+}
+
+fn set_irqs(device: RawFd, flags: u32, count: u32, eventfd: RawFd) -> io::Result<()> {
+    let set = VfioIrqSet {
+        argsz: core::mem::size_of::<VfioIrqSet>() as u32,
+        flags,
+        index: VFIO_PCI_INTX_IRQ_INDEX,
+        start: 0,
+        count,
+        data: eventfd,
+    };
+    // Safety: `set` matches `struct vfio_irq_set` with one eventfd of data
+    if unsafe { libc::ioctl(device, VFIO_DEVICE_SET_IRQS, &set) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether a handler's device raised the interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    None,
+    Handled,
+}
+
+/// Handles interrupts of an INTx line shared by several devices.
+pub struct SharedIntx<'a, L> {
+    line: L,
+    handlers: Vec<Box<dyn FnMut() -> IrqReturn + 'a>>,
+    unhandled: u64,
+}
+
+impl<'a, L: IntxLine> SharedIntx<'a, L> {
+    pub fn new(line: L) -> Self {
+        SharedIntx {
+            line,
+            handlers: Vec::new(),
+            unhandled: 0,
+        }
+    }
+
+    /// Adds a handler that checks itself whether its device interrupted.
+    pub fn add(&mut self, handler: impl FnMut() -> IrqReturn + 'a) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Calls `handler` whenever `device` asserts INTx.
+    ///
+    /// Fails if the device can't disable INTx, devices without it can't
+    /// share a line.
+    pub fn add_device<A: ConfigSpace>(
+        &mut self,
+        device: &'a mut PciDevice<A>,
+        mut handler: impl FnMut(&mut PciDevice<A>) + 'a,
+    ) -> io::Result<()> {
+        if !device.intx_mask_supported() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "device can't disable INTx and can't share its line",
+            ));
+        }
+        self.add(move || {
+            if !device.intx_pending() {
+                return IrqReturn::None;
+            }
+            handler(device);
+            IrqReturn::Handled
+        });
+        Ok(())
+    }
+
+    pub fn line(&self) -> &L {
+        &self.line
+    }
+
+    /// Interrupts none of the handlers claimed.
+    pub fn unhandled(&self) -> u64 {
+        self.unhandled
+    }
+
+    /// Handles a signalled interrupt (e.g., from an
+    /// [`EventLoop`](super::eventloop::EventLoop) handler): acknowledges it,
+    /// offers it to all handlers and unmasks the line again.
+    ///
+    /// # Returns
+    /// The number of handlers that claimed the interrupt.
+    pub fn handle(&mut self) -> io::Result<usize> {
+        self.line.ack()?;
+        let mut handled = 0;
+        for handler in self.handlers.iter_mut() {
+            if handler() == IrqReturn::Handled {
+                handled += 1;
+            }
+        }
+        if handled == 0 {
+            self.unhandled += 1;
+            if self.unhandled % UNHANDLED_WARN == 1 {
+                warn!(
+                    "INTx on fd {} not handled by any device ({} times)",
+                    self.line.as_raw_fd(),
+                    self.unhandled
+                );
+            }
+        }
+        self.line.unmask()?;
+        Ok(handled)
+    }
+
+    pub fn into_inner(self) -> L {
+        self.line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::mock::MockConfig;
+    use crate::pci::PCIAddress;
+    use crate::PciInterface;
+
+    #[derive(Default)]
+    struct Line {
+        acks: u64,
+        unmasks: usize,
+    }
+
+    impl IntxLine for Line {
+        fn ack(&mut self) -> io::Result<u64> {
+            self.acks += 1;
+            Ok(self.acks)
+        }
+
+        fn unmask(&mut self) -> io::Result<()> {
+            self.unmasks += 1;
+            Ok(())
+        }
+    }
+
+    impl AsRawFd for Line {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    fn device(dev: u8) -> PciDevice<MockConfig> {
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        space[0x3d] = 1;
+        PciDevice::from_config(MockConfig::from_bytes(
+            PCIAddress {
+                bus: 0,
+                dev,
+                fun: 0,
+            },
+            &space,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn shared_line() {
+        let mut first = device(1);
+        let mut second = device(2);
+        let pending = core::cell::Cell::new(true);
+        let mut first_handled = false;
+        {
+            let mut intx = SharedIntx::new(Line::default());
+            intx.add_device(&mut first, |_| first_handled = true)
+                .unwrap();
+            intx.add(|| match pending.replace(false) {
+                true => IrqReturn::Handled,
+                false => IrqReturn::None,
+            });
+            assert_eq!(intx.handle().unwrap(), 1);
+            assert_eq!(intx.handle().unwrap(), 0);
+            assert_eq!(intx.unhandled(), 1);
+            let line = intx.into_inner();
+            assert_eq!((line.acks, line.unmasks), (2, 2));
+        }
+        assert!(!first_handled);
+
+        // Asserting INTx, masked like the kernel's handler does
+        second.config_mut().write(0x04, 0x0008_0000);
+        assert!(second.check_and_mask_intx() && second.intx_disabled());
+        assert!(!first.check_and_mask_intx() && !first.intx_disabled());
+        assert!(first.intx_mask_supported());
+    }
+}
@@ -0,0 +1,79 @@
+//! Host interrupts of devices driven through VFIO.
+//!
+//! With VFIO the kernel owns the MSI-X table of the device, so interrupts
+//! are steered by changing the affinity of the host IRQ in procfs.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::prelude::v1::*;
+
+use crate::pci::PCIAddress;
+
+use super::sysfs::sysfs_name;
+
+const PROC_INTERRUPTS: &str = "/proc/interrupts";
+
+/// An interrupt line of the host kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostIrq(pub u32);
+
+impl HostIrq {
+    /// Finds the host IRQ VFIO allocated for MSI-X `vector` of the device at
+    /// `addr`.
+    ///
+    /// Returns None if the vector isn't enabled.
+    pub fn vfio_msix(addr: PCIAddress, vector: usize) -> io::Result<Option<HostIrq>> {
+        let interrupts = fs::read_to_string(PROC_INTERRUPTS)?;
+        Ok(find_irq(&interrupts, &vfio_msix_name(addr, vector)).map(HostIrq))
+    }
+
+    /// Only deliver the interrupt to `cpu`.
+    pub fn set_affinity(&self, cpu: usize) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(format!("/proc/irq/{}/smp_affinity_list", self.0))?;
+        file.write_all(format!("{}", cpu).as_bytes())?;
+        debug!("Set affinity of IRQ {} to core {}", self.0, cpu);
+        Ok(())
+    }
+}
+
+/// The name VFIO registers its MSI-X interrupt handlers with.
+fn vfio_msix_name(addr: PCIAddress, vector: usize) -> String {
+    format!("vfio-msix[{}]({})", vector, sysfs_name(addr))
+}
+
+/// Returns the number of the IRQ whose handler is called `name` in the
+/// contents of /proc/interrupts.
+fn find_irq(interrupts: &str, name: &str) -> Option<u32> {
+    interrupts.lines().find_map(|line| {
+        let (irq, rest) = line.trim_start().split_once(':')?;
+        if rest.split_whitespace().any(|field| field == name) {
+            irq.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_vfio_irq() {
+        let interrupts = "           CPU0       CPU1
+  0:         36          0   IO-APIC   2-edge      timer
+ 45:          0         12  IR-PCI-MSI 1048576-edge      vfio-msix[0](0000:02:00.0)
+ 46:          3          0  IR-PCI-MSI 1048577-edge      vfio-msix[1](0000:02:00.0)
+NMI:          0          0   Non-maskable interrupts
+";
+        let addr = PCIAddress {
+            bus: 2,
+            dev: 0,
+            fun: 0,
+        };
+        assert_eq!(find_irq(interrupts, &vfio_msix_name(addr, 1)), Some(46));
+        assert_eq!(find_irq(interrupts, &vfio_msix_name(addr, 2)), None);
+    }
+}
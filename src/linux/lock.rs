@@ -0,0 +1,124 @@
+//! Advisory locks that keep two driver processes off the same device.
+//!
+//! A driver takes an exclusive `flock` on a lock file named after the
+//! device's address when it attaches. The kernel drops the lock when the
+//! process exits (or crashes), so stale lock files don't block anyone. The
+//! file holds the PID of the owner, for error messages.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::prelude::v1::*;
+
+use super::sysfs::sysfs_name;
+use crate::pci::PCIAddress;
+
+/// Where the lock files go (falls back to the temporary directory).
+const LOCK_DIR: &str = "/run/lock";
+
+fn lock_dir() -> PathBuf {
+    let dir = Path::new(LOCK_DIR);
+    if dir.is_dir() {
+        dir.to_path_buf()
+    } else {
+        std::env::temp_dir()
+    }
+}
+
+fn lock_path(dir: &Path, addr: PCIAddress) -> PathBuf {
+    dir.join(format!("driverkit-{}.lock", sysfs_name(addr)))
+}
+
+/// An exclusive claim on a device, released on drop.
+#[derive(Debug)]
+pub struct DeviceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DeviceLock {
+    /// Claims the device at `addr`.
+    ///
+    /// Fails with [`io::ErrorKind::WouldBlock`] if another process (or
+    /// another lock in this one) holds it.
+    pub fn acquire(addr: PCIAddress) -> io::Result<DeviceLock> {
+        DeviceLock::acquire_in(&lock_dir(), addr)
+    }
+
+    /// Claims the device at `addr` with a lock file in `dir`.
+    pub fn acquire_in(dir: &Path, addr: PCIAddress) -> io::Result<DeviceLock> {
+        let path = lock_path(dir, addr);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        // Safety: flock only operates on the file descriptor
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock {
+                let mut owner = String::new();
+                let _ = file.read_to_string(&mut owner);
+                warn!("{:?} is claimed by process {}", addr, owner.trim());
+            }
+            return Err(error);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        debug!("Claimed {:?} ({})", addr, path.to_str().unwrap_or("?"));
+        Ok(DeviceLock { file, path })
+    }
+
+    /// Path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// PID of the process that holds the lock on `addr`, if any.
+    pub fn owner(addr: PCIAddress) -> io::Result<Option<u32>> {
+        let path = lock_path(&lock_dir(), addr);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // Safety: flock only operates on the file descriptor
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+            // Nobody holds it (our shared lock goes away with `file`)
+            return Ok(None);
+        }
+        Ok(fs::read_to_string(&path)?.trim().parse().ok())
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        // Clear the PID, the lock itself goes away with the file descriptor
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive() {
+        let dir = std::env::temp_dir();
+        let addr = PCIAddress {
+            bus: 0xfe,
+            dev: 0x1f,
+            fun: 0x7,
+        };
+        let lock = DeviceLock::acquire_in(&dir, addr).unwrap();
+        let err = DeviceLock::acquire_in(&dir, addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(lock);
+        assert!(DeviceLock::acquire_in(&dir, addr).is_ok());
+    }
+}
@@ -23,7 +23,7 @@ const PAGESIZE: u64 = FOUR_KIB as u64;
 
 /// Function to read the pagemap in Linux.
 /// See also https://www.kernel.org/doc/Documentation/vm/pagemap.txt.
-fn read_pagemap(virtual_page: u64) -> io::Result<u64> {
+pub(super) fn read_pagemap(virtual_page: u64) -> io::Result<u64> {
     assert!(virtual_page % PAGESIZE == 0);
 
     let mut f = File::open("/proc/self/pagemap")?;
@@ -1,6 +1,5 @@
 use std::prelude::v1::*;
 
-use log::debug;
 use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -11,7 +10,22 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::MsrInterface;
 
+pub mod aperture;
+pub mod async_irq;
+#[cfg(feature = "devmem")]
+pub mod devmem;
+pub mod dmabuf;
+pub mod eventloop;
+pub mod intx;
+pub mod irq;
+pub mod lock;
 pub mod mem;
+pub mod netif;
+pub mod pciids;
+pub mod preflight;
+pub mod sysfs;
+pub mod tap;
+pub mod xdp;
 
 pub struct MsrWriter {
     cpu: usize,
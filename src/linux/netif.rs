@@ -0,0 +1,144 @@
+//! Helpers for Linux network interfaces (/sys/class/net and ioctls).
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::prelude::v1::*;
+
+use crate::net::device::{LinkState, NetStats};
+use crate::net::{MacAddress, NetError};
+
+const SYSFS_NET: &str = "/sys/class/net";
+
+impl From<NetError> for io::Error {
+    fn from(e: NetError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+    }
+}
+
+fn sysfs_path(ifname: &str) -> PathBuf {
+    PathBuf::from(SYSFS_NET).join(ifname)
+}
+
+fn read_attr(ifname: &str, attr: &str) -> io::Result<String> {
+    Ok(fs::read_to_string(sysfs_path(ifname).join(attr))?
+        .trim()
+        .to_string())
+}
+
+fn read_number(ifname: &str, attr: &str) -> io::Result<u64> {
+    read_attr(ifname, attr)?
+        .parse()
+        .map_err(|_e| io::Error::new(io::ErrorKind::InvalidData, attr))
+}
+
+/// The index of the interface called `ifname`.
+pub fn ifindex(ifname: &str) -> io::Result<u32> {
+    let name = CString::new(ifname).map_err(|_e| io::Error::from(io::ErrorKind::InvalidInput))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+pub fn mac_address(ifname: &str) -> io::Result<MacAddress> {
+    let address = read_attr(ifname, "address")?;
+    let mut mac = [0u8; 6];
+    let mut octets = address.split(':');
+    for byte in mac.iter_mut() {
+        *byte = octets
+            .next()
+            .and_then(|o| u8::from_str_radix(o, 16).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, address.clone()))?;
+    }
+    Ok(mac)
+}
+
+pub fn link_state(ifname: &str) -> io::Result<LinkState> {
+    // Drivers that don't track the operational state (e.g., tun/tap) report
+    // "unknown", their carrier tells whether the link is usable
+    let up = match read_attr(ifname, "operstate")?.as_str() {
+        "up" => true,
+        "unknown" => read_attr(ifname, "carrier").is_ok_and(|c| c == "1"),
+        _ => false,
+    };
+    if !up {
+        return Ok(LinkState::Down);
+    }
+    // Virtual interfaces don't have a speed (reading fails or returns -1)
+    let speed = read_attr(ifname, "speed")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(0);
+    let full_duplex = read_attr(ifname, "duplex").map_or(true, |d| d == "full");
+    Ok(LinkState::Up { speed, full_duplex })
+}
+
+pub fn stats(ifname: &str) -> io::Result<NetStats> {
+    let stat = |name: &str| read_number(ifname, &format!("statistics/{}", name));
+    Ok(NetStats {
+        rx_packets: stat("rx_packets")?,
+        rx_bytes: stat("rx_bytes")?,
+        rx_dropped: stat("rx_dropped")?,
+        rx_errors: stat("rx_errors")?,
+        rx_multicast: stat("multicast")?,
+        tx_packets: stat("tx_packets")?,
+        tx_bytes: stat("tx_bytes")?,
+        tx_dropped: stat("tx_dropped")?,
+        tx_errors: stat("tx_errors")?,
+    })
+}
+
+pub fn mtu(ifname: &str) -> io::Result<u16> {
+    Ok(read_number(ifname, "mtu")? as u16)
+}
+
+/// Builds a `struct ifreq` for `ifname`.
+pub(crate) fn ifreq(ifname: &str) -> io::Result<libc::ifreq> {
+    let mut ifr: libc::ifreq = unsafe { core::mem::zeroed() };
+    if ifname.len() >= ifr.ifr_name.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, ifname));
+    }
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(ifname.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(ifr)
+}
+
+/// Issues an interface ioctl (`SIOCxIFxxx`) on a throwaway socket.
+fn ifreq_ioctl(request: libc::c_ulong, ifr: &mut libc::ifreq) -> io::Result<()> {
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if sock < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { libc::ioctl(sock, request as _, ifr as *mut libc::ifreq) };
+    let result = if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+    unsafe { libc::close(sock) };
+    result
+}
+
+/// Changes the MTU of the interface (requires CAP_NET_ADMIN).
+pub fn set_mtu(ifname: &str, mtu: u16) -> io::Result<()> {
+    let mut ifr = ifreq(ifname)?;
+    ifr.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+    ifreq_ioctl(libc::SIOCSIFMTU, &mut ifr)
+}
+
+/// Brings the interface up or down (requires CAP_NET_ADMIN).
+pub fn set_up(ifname: &str, up: bool) -> io::Result<()> {
+    let mut ifr = ifreq(ifname)?;
+    ifreq_ioctl(libc::SIOCGIFFLAGS, &mut ifr)?;
+    unsafe {
+        if up {
+            ifr.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        } else {
+            ifr.ifr_ifru.ifru_flags &= !(libc::IFF_UP as libc::c_short);
+        }
+    }
+    ifreq_ioctl(libc::SIOCSIFFLAGS, &mut ifr)
+}
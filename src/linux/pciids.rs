@@ -0,0 +1,135 @@
+//! The system's pci.ids file as a runtime device database.
+//!
+//! Distributions usually ship a more recent pci.ids than the one compiled
+//! into the crate. [`PciIds::load`] parses it once and can be registered
+//! with [`device_db::register_source`](crate::pci::device_db::register_source).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::prelude::v1::*;
+
+use crate::pci::device_db::{make_key, DeviceInfoSource, PciDeviceInfo};
+use crate::pci::{DeviceId, VendorId};
+
+/// Where distributions install pci.ids.
+pub const PCI_IDS_PATHS: [&str; 3] = [
+    "/usr/share/hwdata/pci.ids",
+    "/usr/share/misc/pci.ids",
+    "/usr/share/pci.ids",
+];
+
+/// Devices parsed from a pci.ids file.
+#[derive(Debug, Default)]
+pub struct PciIds {
+    devices: BTreeMap<u32, PciDeviceInfo>,
+}
+
+/// Splits a line of the form `<hex id>  <name>`.
+fn parse_entry(line: &str) -> Option<(u16, &str)> {
+    let (id, name) = line.split_once(char::is_whitespace)?;
+    Some((u16::from_str_radix(id, 16).ok()?, name.trim()))
+}
+
+impl PciIds {
+    /// Parses the vendor and device entries of `contents` (subsystems and
+    /// the class section are skipped).
+    pub fn parse(contents: &'static str) -> PciIds {
+        let mut devices = BTreeMap::new();
+        let mut vendor: Option<(VendorId, &'static str)> = None;
+        for line in contents.lines() {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("\t\t") {
+                continue;
+            }
+            if let Some(device) = line.strip_prefix('\t') {
+                if let (Some((vendor_id, vendor_name)), Some((device_id, device_name))) =
+                    (vendor, parse_entry(device))
+                {
+                    devices.insert(
+                        make_key(vendor_id, device_id),
+                        PciDeviceInfo {
+                            vendor_id,
+                            device_id,
+                            vendor_name,
+                            device_name,
+                        },
+                    );
+                }
+            } else if line.starts_with("C ") {
+                // The class section comes after all vendors
+                break;
+            } else {
+                vendor = parse_entry(line);
+            }
+        }
+        PciIds { devices }
+    }
+
+    /// Reads and parses the pci.ids file at `path`.
+    ///
+    /// The contents are kept for the rest of the program, so this should
+    /// only be done once.
+    pub fn load(path: &Path) -> io::Result<&'static PciIds> {
+        let contents: &'static str = Box::leak(fs::read_to_string(path)?.into_boxed_str());
+        let ids = Box::leak(Box::new(PciIds::parse(contents)));
+        info!(
+            "Loaded {} devices from {}",
+            ids.len(),
+            path.to_str().unwrap_or("?")
+        );
+        Ok(ids)
+    }
+
+    /// Loads the first pci.ids found in [`PCI_IDS_PATHS`].
+    pub fn load_system() -> io::Result<&'static PciIds> {
+        let path = PCI_IDS_PATHS
+            .iter()
+            .map(Path::new)
+            .find(|path| path.is_file())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no pci.ids found"))?;
+        PciIds::load(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+impl DeviceInfoSource for PciIds {
+    fn lookup(&'static self, vendor: VendorId, device: DeviceId) -> Option<&'static PciDeviceInfo> {
+        self.devices.get(&make_key(vendor, device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::device_db::{self, SourceOrder};
+
+    #[test]
+    fn parse_and_register() {
+        let contents = "# comment\n\
+                        fffd  Example Vendor\n\
+                        \t0001  Example NIC\n\
+                        \t\tfffd 0002  Example NIC (rev B)\n\
+                        \t0002  Example GPU\n\
+                        C 02  Network controller\n\
+                        \t00  Ethernet controller\n";
+        let ids: &'static PciIds = Box::leak(Box::new(PciIds::parse(contents)));
+        assert_eq!(ids.len(), 2);
+
+        device_db::register_source(ids, SourceOrder::AfterBuiltin).unwrap();
+        let info = device_db::lookup(0xfffd, 0x0002).unwrap();
+        assert_eq!(
+            (info.vendor_name, info.device_name),
+            ("Example Vendor", "Example GPU")
+        );
+        device_db::unregister_source(ids);
+        assert!(device_db::lookup(0xfffd, 0x0002).is_none());
+    }
+}
@@ -0,0 +1,246 @@
+//! Checks whether the process can drive devices at all.
+//!
+//! Missing privileges or an unconfigured host otherwise show up as an
+//! `EPERM` from some ioctl or a failed mmap deep inside a driver.
+//! [`preflight`] looks at everything a user-space driver typically needs
+//! up front and reports what's missing.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::prelude::v1::*;
+
+use super::sysfs::{bound_driver, parse_sysfs_name, sysfs_path};
+use crate::pci::PCIAddress;
+
+const PROC_SELF_STATUS: &str = "/proc/self/status";
+const SYSFS_IOMMU: &str = "/sys/class/iommu";
+const SYSFS_IOMMU_GROUPS: &str = "/sys/kernel/iommu_groups";
+const SYSFS_HUGEPAGES: &str = "/sys/kernel/mm/hugepages";
+const DEV_VFIO: &str = "/dev/vfio";
+
+/// Capability bit of CAP_SYS_RAWIO (see include/uapi/linux/capability.h).
+const CAP_SYS_RAWIO: u32 = 17;
+
+/// Drivers a device of a VFIO group may be bound to without making the
+/// group unusable.
+const VFIO_COMPATIBLE_DRIVERS: [&str; 3] = ["vfio-pci", "pci-stub", "pcieport"];
+
+/// Reserved huge pages of one size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hugepages {
+    pub total: usize,
+    pub free: usize,
+}
+
+/// State of the VFIO group of a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfioGroup {
+    pub group: u32,
+    /// Whether /dev/vfio/<group> exists (i.e., vfio-pci claimed the group).
+    pub device_node: bool,
+    /// Devices in the group that are bound to other drivers, which makes
+    /// the group unusable.
+    pub blockers: Vec<(PCIAddress, String)>,
+}
+
+impl VfioGroup {
+    pub fn is_viable(&self) -> bool {
+        self.device_node && self.blockers.is_empty()
+    }
+}
+
+/// Result of [`preflight`].
+#[derive(Debug, Clone)]
+pub struct PreflightReport {
+    /// The process has CAP_SYS_RAWIO (needed for /dev/mem, MSRs and port
+    /// IO).
+    pub sys_rawio: bool,
+    /// `iopl` can be used for port IO (x86 only, needs CAP_SYS_RAWIO).
+    pub iopl: bool,
+    /// An IOMMU is present and enabled.
+    pub iommu: bool,
+    /// The VFIO group of the device, if a device was given and it's in one.
+    pub vfio: Option<VfioGroup>,
+    pub hugepages_2mib: Hugepages,
+    pub hugepages_1gib: Hugepages,
+}
+
+impl PreflightReport {
+    /// Describes everything that's missing (empty if nothing is).
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.sys_rawio {
+            problems.push(String::from("missing CAP_SYS_RAWIO"));
+        }
+        if !self.iommu {
+            problems.push(String::from(
+                "no IOMMU (VFIO needs intel_iommu=on or amd_iommu=on)",
+            ));
+        }
+        if let Some(vfio) = &self.vfio {
+            if !vfio.device_node {
+                problems.push(format!(
+                    "{}/{} doesn't exist (bind the device to vfio-pci)",
+                    DEV_VFIO, vfio.group
+                ));
+            }
+            for (addr, driver) in &vfio.blockers {
+                problems.push(format!(
+                    "{:?} in IOMMU group {} is bound to {}",
+                    addr, vfio.group, driver
+                ));
+            }
+        }
+        if self.hugepages_2mib.free == 0 && self.hugepages_1gib.free == 0 {
+            problems.push(String::from("no free huge pages"));
+        }
+        problems
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems().is_empty()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "CAP_SYS_RAWIO: {}", self.sys_rawio)?;
+        writeln!(f, "iopl: {}", self.iopl)?;
+        writeln!(f, "IOMMU: {}", self.iommu)?;
+        if let Some(vfio) = &self.vfio {
+            writeln!(f, "VFIO group {}: viable: {}", vfio.group, vfio.is_viable())?;
+        }
+        writeln!(
+            f,
+            "huge pages: 2 MiB {}/{} free, 1 GiB {}/{} free",
+            self.hugepages_2mib.free,
+            self.hugepages_2mib.total,
+            self.hugepages_1gib.free,
+            self.hugepages_1gib.total
+        )?;
+        for problem in self.problems() {
+            writeln!(f, "problem: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the effective capabilities from the contents of
+/// /proc/<pid>/status.
+fn effective_capabilities(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+}
+
+fn has_sys_rawio() -> bool {
+    fs::read_to_string(PROC_SELF_STATUS)
+        .ok()
+        .and_then(|status| effective_capabilities(&status))
+        .is_some_and(|caps| caps & (1 << CAP_SYS_RAWIO) != 0)
+}
+
+fn has_entries(dir: &str) -> bool {
+    fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some())
+}
+
+fn read_count(path: &Path) -> usize {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn hugepages(size_kib: usize) -> Hugepages {
+    let dir = Path::new(SYSFS_HUGEPAGES).join(format!("hugepages-{}kB", size_kib));
+    Hugepages {
+        total: read_count(&dir.join("nr_hugepages")),
+        free: read_count(&dir.join("free_hugepages")),
+    }
+}
+
+/// Looks up the VFIO group of the device at `addr`.
+///
+/// Returns None if the device isn't in an IOMMU group.
+pub fn vfio_group(addr: PCIAddress) -> io::Result<Option<VfioGroup>> {
+    let link = match fs::read_link(sysfs_path(addr).join("iommu_group")) {
+        Ok(link) => link,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let group = link
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid IOMMU group"))?;
+
+    let mut blockers = Vec::new();
+    for entry in fs::read_dir(Path::new(SYSFS_IOMMU_GROUPS).join(format!("{}/devices", group)))? {
+        let name = entry?.file_name();
+        if let Some(member) = name.to_str().and_then(parse_sysfs_name) {
+            match bound_driver(member)? {
+                Some(driver) if !VFIO_COMPATIBLE_DRIVERS.contains(&driver.as_str()) => {
+                    blockers.push((member, driver))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Ok(Some(VfioGroup {
+        group,
+        device_node: Path::new(DEV_VFIO).join(format!("{}", group)).exists(),
+        blockers,
+    }))
+}
+
+/// Checks privileges, the IOMMU and huge pages, and, if `device` is given,
+/// whether its VFIO group can be used.
+pub fn preflight(device: Option<PCIAddress>) -> PreflightReport {
+    let sys_rawio = has_sys_rawio();
+    let report = PreflightReport {
+        sys_rawio,
+        iopl: cfg!(target_arch = "x86_64") && sys_rawio,
+        iommu: has_entries(SYSFS_IOMMU) || has_entries(SYSFS_IOMMU_GROUPS),
+        vfio: device.and_then(|addr| vfio_group(addr).ok().flatten()),
+        hugepages_2mib: hugepages(2048),
+        hugepages_1gib: hugepages(1024 * 1024),
+    };
+    for problem in report.problems() {
+        warn!("Preflight: {}", problem.as_str());
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let status = "Name:\tdriver\nCapInh:\t0000000000000000\nCapEff:\t0000000000020000\n";
+        let caps = effective_capabilities(status).unwrap();
+        assert!(caps & (1 << CAP_SYS_RAWIO) != 0);
+        assert_eq!(effective_capabilities("Name:\tdriver\n"), None);
+
+        let report = PreflightReport {
+            sys_rawio: true,
+            iopl: true,
+            iommu: true,
+            vfio: Some(VfioGroup {
+                group: 12,
+                device_node: true,
+                blockers: Vec::new(),
+            }),
+            hugepages_2mib: Hugepages {
+                total: 512,
+                free: 512,
+            },
+            hugepages_1gib: Hugepages::default(),
+        };
+        assert!(report.is_ok());
+    }
+}
@@ -0,0 +1,355 @@
+//! Helpers for the PCI sysfs interface (/sys/bus/pci/devices).
+//!
+//! Besides driver (un-)binding this provides a configuration space backend
+//! ([`SysfsConfig`]) that works without port IO privileges, an enumeration
+//! of all devices known to the kernel ([`scan`]) and BAR mappings
+//! ([`map_resource`]).
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::prelude::v1::*;
+
+use crate::iomem::{MapOptions, MemoryType};
+use crate::pci::{Bar, BarType, ConfigSpace, PCIAddress, PciDevice};
+use crate::{PciInterface, VAddr};
+
+/// Flags of the `resource` file (see include/linux/ioport.h).
+const IORESOURCE_IO: u64 = 0x0000_0100;
+const IORESOURCE_MEM: u64 = 0x0000_0200;
+const IORESOURCE_PREFETCH: u64 = 0x0000_2000;
+
+pub const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+const PAGE_SIZE: usize = 4096;
+
+/// Name of the device in sysfs (e.g., `0000:00:1f.3`).
+pub fn sysfs_name(addr: PCIAddress) -> String {
+    format!("0000:{:02x}:{:02x}.{:x}", addr.bus, addr.dev, addr.fun)
+}
+
+/// Directory of the device in sysfs.
+pub fn sysfs_path(addr: PCIAddress) -> PathBuf {
+    PathBuf::from(SYSFS_PCI_DEVICES).join(sysfs_name(addr))
+}
+
+/// Returns the name of the kernel driver currently bound to the device.
+pub fn bound_driver(addr: PCIAddress) -> io::Result<Option<String>> {
+    match fs::read_link(sysfs_path(addr).join("driver")) {
+        Ok(link) => Ok(link
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Detaches the kernel driver (if any) from the device so it can be driven
+/// from user-space.
+pub fn unbind_kernel_driver(addr: PCIAddress) -> io::Result<()> {
+    if let Some(driver) = bound_driver(addr)? {
+        info!(
+            "Unbinding {:?} from kernel driver {}",
+            addr,
+            driver.as_str()
+        );
+        let mut unbind = OpenOptions::new()
+            .write(true)
+            .open(sysfs_path(addr).join("driver/unbind"))?;
+        unbind.write_all(sysfs_name(addr).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Parses a sysfs device name (e.g., `0000:00:1f.3`).
+///
+/// Returns None for malformed names and devices outside of PCI segment 0
+/// (which can't be represented by [`PCIAddress`]).
+pub fn parse_sysfs_name(name: &str) -> Option<PCIAddress> {
+    let mut parts = name.split([':', '.']);
+    let domain = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let dev = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let fun = u8::from_str_radix(parts.next()?, 16).ok()?;
+
+    if domain != 0 || dev > 31 || fun > 7 || parts.next().is_some() {
+        return None;
+    }
+    Some(PCIAddress { bus, dev, fun })
+}
+
+/// The configuration space of a device, accessed through its sysfs `config`
+/// file.
+///
+/// Unprivileged processes can only read the first 64 bytes, everything
+/// beyond reads as 0 (so capability lists appear empty) and writes fail.
+pub struct SysfsConfig {
+    addr: PCIAddress,
+    file: File,
+}
+
+impl SysfsConfig {
+    /// Opens the configuration space of the device at `addr`, read-write if
+    /// permitted and read-only otherwise.
+    pub fn open(addr: PCIAddress) -> io::Result<SysfsConfig> {
+        let path = sysfs_path(addr).join("config");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .or_else(|_e| File::open(&path))?;
+
+        Ok(SysfsConfig { addr, file })
+    }
+}
+
+impl fmt::Debug for SysfsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SysfsConfig({})", sysfs_name(self.addr))
+    }
+}
+
+impl PciInterface for SysfsConfig {
+    fn read(&self, offset: u32) -> u32 {
+        let mut value = [0u8; 4];
+        match self.file.read_at(&mut value, offset as u64) {
+            Ok(4) => u32::from_le_bytes(value),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        if self
+            .file
+            .write_all_at(&value.to_le_bytes(), offset as u64)
+            .is_err()
+        {
+            error!(
+                "Can't write config space of {:?} at {:#x}",
+                self.addr, offset
+            );
+        }
+    }
+}
+
+impl ConfigSpace for SysfsConfig {
+    fn address(&self) -> PCIAddress {
+        self.addr
+    }
+
+    /// The kernel exposes 4 KiB for PCIe functions (reads beyond the
+    /// file, i.e., of conventional PCI functions, return 0).
+    fn config_size(&self) -> u32 {
+        0x1000
+    }
+}
+
+/// Enumerates all PCI devices known to the kernel, sorted by address.
+pub fn scan() -> io::Result<Vec<PciDevice<SysfsConfig>>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(SYSFS_PCI_DEVICES)? {
+        let name = entry?.file_name();
+        if let Some(addr) = name.to_str().and_then(parse_sysfs_name) {
+            if let Some(device) = PciDevice::from_config(SysfsConfig::open(addr)?) {
+                devices.push(device);
+            }
+        }
+    }
+
+    devices.sort_by_key(|d| {
+        let addr = d.pci_address();
+        (addr.bus, addr.dev, addr.fun)
+    });
+    Ok(devices)
+}
+
+/// Reads the BARs of a device as assigned by the kernel from its `resource`
+/// file.
+///
+/// Unlike [`PciDevice::bar`] this doesn't size BARs by writing to them, so
+/// it's safe to use on devices that are in use.
+pub fn resources(addr: PCIAddress) -> io::Result<Vec<(u8, Bar)>> {
+    let content = fs::read_to_string(sysfs_path(addr).join("resource"))?;
+    let mut bars = Vec::new();
+
+    // The first six lines are the BARs, each as `start end flags`
+    for (index, line) in content.lines().take(6).enumerate() {
+        let mut fields = line
+            .split_whitespace()
+            .map(|f| u64::from_str_radix(f.trim_start_matches("0x"), 16));
+        let (start, end, flags) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(Ok(start)), Some(Ok(end)), Some(Ok(flags))) => (start, end, flags),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, line.to_string())),
+        };
+
+        if flags & (IORESOURCE_IO | IORESOURCE_MEM) == 0 || end <= start {
+            continue;
+        }
+        bars.push((
+            index as u8,
+            Bar {
+                region_type: BarType::from(flags & IORESOURCE_IO != 0),
+                prefetchable: flags & IORESOURCE_PREFETCH != 0,
+                address: start,
+                size: end - start + 1,
+            },
+        ));
+    }
+
+    Ok(bars)
+}
+
+/// A BAR mapped through its sysfs `resource<N>` file (or /dev/mem),
+/// unmapped on drop.
+pub struct BarMapping {
+    /// Start of the (page aligned) mapping.
+    map: *mut u8,
+    map_len: usize,
+    /// Offset of the requested range in the mapping.
+    offset: usize,
+    len: usize,
+    options: MapOptions,
+}
+
+impl BarMapping {
+    /// Maps `len` bytes at `offset` of `file`.
+    pub(super) fn map_file(
+        file: &File,
+        offset: u64,
+        len: usize,
+        options: MapOptions,
+    ) -> io::Result<BarMapping> {
+        let page_offset = (offset % PAGE_SIZE as u64) as usize;
+        let map_len = (page_offset + len).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+        let mut prot = libc::PROT_READ;
+        if !options.read_only {
+            prot |= libc::PROT_WRITE;
+        }
+        if !options.execute_never {
+            prot |= libc::PROT_EXEC;
+        }
+        // Safety: maps a new region, doesn't touch existing memory
+        let map = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                map_len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                (offset - page_offset as u64) as libc::off_t,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(BarMapping {
+            map: map as *mut u8,
+            map_len,
+            offset: page_offset,
+            len,
+            options,
+        })
+    }
+
+    pub fn vaddr(&self) -> VAddr {
+        VAddr::from(self.as_mut_ptr() as u64)
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.map.wrapping_add(self.offset)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The options the BAR is actually mapped with (write-combining falls
+    /// back to uncached if the kernel doesn't offer it for the BAR).
+    pub fn options(&self) -> MapOptions {
+        self.options
+    }
+}
+
+impl fmt::Debug for BarMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BarMapping({:p}, {:#x} bytes)",
+            self.as_mut_ptr(),
+            self.len
+        )
+    }
+}
+
+impl Drop for BarMapping {
+    fn drop(&mut self) {
+        // Safety: `map` and `map_len` are the mapping created in `map_file`
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.map_len) };
+    }
+}
+
+/// Maps memory BAR `index` of the device with `options`.
+///
+/// The kernel always maps BARs uncached, except through the `resource<N>_wc`
+/// file it offers for prefetchable BARs, so [`MemoryType::Strict`] and
+/// [`MemoryType::Device`] are the same here.
+pub fn map_resource(addr: PCIAddress, index: u8, options: MapOptions) -> io::Result<BarMapping> {
+    span!(DEBUG, "map_bar", device = addr, index = index);
+    let (file, actual) = open_resource(addr, index, options)?;
+    let len = file.metadata()?.len() as usize;
+    let mapping = BarMapping::map_file(&file, 0, len, actual)?;
+    debug!("Mapped BAR {} of {:?} ({:#x} bytes)", index, addr, len);
+    Ok(mapping)
+}
+
+/// Opens the resource file to map BAR `index` with `options` from.
+///
+/// # Returns
+/// The file and the options it can be mapped with (see
+/// [`BarMapping::options`]).
+pub(super) fn open_resource(
+    addr: PCIAddress,
+    index: u8,
+    options: MapOptions,
+) -> io::Result<(File, MapOptions)> {
+    let path = sysfs_path(addr);
+    let open = |name: String| {
+        OpenOptions::new()
+            .read(true)
+            .write(!options.read_only)
+            .open(path.join(name))
+    };
+
+    let mut actual = options;
+    let wc_file = if options.is_write_combining() {
+        match open(format!("resource{}_wc", index)) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!(
+                    "BAR {} of {:?} can't be mapped write-combining",
+                    index, addr
+                );
+                actual = options.memory_type(MemoryType::Device);
+                None
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        None
+    };
+    let file = match wc_file {
+        Some(file) => file,
+        None => open(format!("resource{}", index))?,
+    };
+    Ok((file, actual))
+}
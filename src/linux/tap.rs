@@ -0,0 +1,416 @@
+//! A network device backed by a Linux TAP interface.
+//!
+//! Frames written by the driver appear as received on the interface and
+//! vice versa, so integration tests (e.g., running smoltcp on top of the
+//! [`NetworkDevice`] abstractions) work without PCI hardware. Creating the
+//! interface needs access to /dev/net/tun, attaching to an existing,
+//! persistent interface owned by the user does not need extra privileges.
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::prelude::v1::*;
+use std::rc::Rc;
+
+use crate::devq::{DevQueue, DevQueueError, QueueOccupancy, QueuePair, Watermarks};
+use crate::iomem::IOBufChain;
+use crate::net::device::{LinkState, NetStats, Offloads};
+use crate::net::{MacAddress, Mtu, NetworkDevice, ETH_HLEN};
+
+use super::netif;
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+/// Maximum number of frames in flight on each queue.
+const QUEUE_CAPACITY: usize = 256;
+
+/// The file descriptor of the TAP interface, closed on drop.
+struct TapFd(RawFd);
+
+impl Drop for TapFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// The TX side of a TAP interface.
+///
+/// Frames are written on `flush`, `dequeue` hands back the written chains.
+/// The occupancy counts the chains until they are dequeued.
+pub struct TapTxQueue {
+    fd: Rc<TapFd>,
+    pending: VecDeque<IOBufChain>,
+    completed: VecDeque<IOBufChain>,
+    watermarks: Watermarks,
+}
+
+impl TapTxQueue {
+    fn new(fd: Rc<TapFd>) -> TapTxQueue {
+        TapTxQueue {
+            fd,
+            pending: VecDeque::new(),
+            completed: VecDeque::new(),
+            watermarks: Watermarks::for_capacity(QUEUE_CAPACITY),
+        }
+    }
+}
+
+impl DevQueue for TapTxQueue {
+    fn enqueue(&mut self, bufs: IOBufChain) -> Result<(), IOBufChain> {
+        if self.len() >= QUEUE_CAPACITY {
+            return Err(bufs);
+        }
+        self.pending.push_back(bufs);
+        let len = self.len();
+        self.watermarks.update(len);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, DevQueueError> {
+        let mut written = 0;
+        while let Some(bufs) = self.pending.pop_front() {
+            let iov: Vec<libc::iovec> = bufs
+                .segments
+                .iter()
+                .filter(|seg| !seg.is_empty())
+                .map(|seg| libc::iovec {
+                    iov_base: seg.as_slice().as_ptr() as *mut libc::c_void,
+                    iov_len: seg.len(),
+                })
+                .collect();
+
+            let ret = unsafe { libc::writev(self.fd.0, iov.as_ptr(), iov.len() as libc::c_int) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                self.pending.push_front(bufs);
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    break;
+                }
+                return Err(DevQueueError::QueueFailure);
+            }
+            self.completed.push_back(bufs);
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    fn can_enqueue(&self, how_many_seg: usize) -> bool {
+        self.len() + how_many_seg <= QUEUE_CAPACITY
+    }
+
+    fn dequeue(&mut self) -> Result<IOBufChain, DevQueueError> {
+        let bufs = self
+            .completed
+            .pop_front()
+            .ok_or(DevQueueError::QueueEmpty)?;
+        let len = self.len();
+        self.watermarks.update(len);
+        Ok(bufs)
+    }
+
+    fn can_dequeue(&mut self, _exact: bool) -> usize {
+        self.completed.len()
+    }
+
+    fn watermarks(&mut self) -> Option<&mut Watermarks> {
+        Some(&mut self.watermarks)
+    }
+}
+
+impl QueueOccupancy for TapTxQueue {
+    fn len(&self) -> usize {
+        self.pending.len() + self.completed.len()
+    }
+
+    fn capacity(&self) -> usize {
+        QUEUE_CAPACITY
+    }
+}
+
+/// The RX side of a TAP interface.
+///
+/// Clients enqueue empty chains that received frames are read into.
+pub struct TapRxQueue {
+    fd: Rc<TapFd>,
+    buffers: VecDeque<IOBufChain>,
+}
+
+impl DevQueue for TapRxQueue {
+    fn enqueue(&mut self, bufs: IOBufChain) -> Result<(), IOBufChain> {
+        if self.buffers.len() >= QUEUE_CAPACITY {
+            return Err(bufs);
+        }
+        self.buffers.push_back(bufs);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, DevQueueError> {
+        Ok(0)
+    }
+
+    fn can_enqueue(&self, how_many_seg: usize) -> bool {
+        self.buffers.len() + how_many_seg <= QUEUE_CAPACITY
+    }
+
+    fn dequeue(&mut self) -> Result<IOBufChain, DevQueueError> {
+        let mut bufs = self.buffers.pop_front().ok_or(DevQueueError::QueueEmpty)?;
+        for seg in bufs.segments.iter_mut() {
+            seg.expand();
+        }
+
+        let iov: Vec<libc::iovec> = bufs
+            .segments
+            .iter_mut()
+            .map(|seg| libc::iovec {
+                iov_base: seg.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+                iov_len: seg.len(),
+            })
+            .collect();
+        let ret = unsafe { libc::readv(self.fd.0, iov.as_ptr(), iov.len() as libc::c_int) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            self.buffers.push_front(bufs);
+            return if err.kind() == io::ErrorKind::WouldBlock {
+                Err(DevQueueError::QueueEmpty)
+            } else {
+                Err(DevQueueError::QueueFailure)
+            };
+        }
+
+        // Trim the segments to the frame
+        let mut remaining = ret as usize;
+        for seg in bufs.segments.iter_mut() {
+            let len = remaining.min(seg.len());
+            seg.truncate(len);
+            remaining -= len;
+        }
+        Ok(bufs)
+    }
+
+    fn can_dequeue(&mut self, _exact: bool) -> usize {
+        if self.buffers.is_empty() {
+            return 0;
+        }
+        let mut pfd = libc::pollfd {
+            fd: self.fd.0,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut pfd, 1, 0) };
+        (ready > 0 && pfd.revents & libc::POLLIN != 0) as usize
+    }
+}
+
+impl QueueOccupancy for TapRxQueue {
+    fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    fn capacity(&self) -> usize {
+        QUEUE_CAPACITY
+    }
+}
+
+/// A TAP interface with one queue pair.
+pub struct TapDevice {
+    ifname: String,
+    queues: [QueuePair<TapTxQueue, TapRxQueue>; 1],
+}
+
+impl TapDevice {
+    /// Creates (or attaches to) the TAP interface `ifname`.
+    pub fn open(ifname: &str) -> io::Result<TapDevice> {
+        let path = CString::new(TUN_PATH).expect("no NUL in path");
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = Rc::new(TapFd(fd));
+
+        let mut ifr = netif::ifreq(ifname)?;
+        ifr.ifr_ifru.ifru_flags = IFF_TAP | IFF_NO_PI;
+        if unsafe { libc::ioctl(fd.0, TUNSETIFF as _, &mut ifr) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The kernel may have picked the name (e.g., for `tap%d`)
+        let name: Vec<u8> = ifr
+            .ifr_name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect();
+        let ifname = String::from_utf8_lossy(&name).into_owned();
+        info!("Opened TAP interface {}", ifname.as_str());
+
+        let tx = TapTxQueue::new(fd.clone());
+        let rx = TapRxQueue {
+            fd,
+            buffers: VecDeque::new(),
+        };
+        Ok(TapDevice {
+            ifname,
+            queues: [QueuePair::new(0, tx, rx)],
+        })
+    }
+
+    pub fn ifname(&self) -> &str {
+        &self.ifname
+    }
+
+    /// Brings the interface up or down (requires CAP_NET_ADMIN).
+    pub fn set_up(&mut self, up: bool) -> io::Result<()> {
+        netif::set_up(&self.ifname, up)
+    }
+}
+
+impl Mtu for TapDevice {
+    type Error = io::Error;
+
+    fn mtu(&self) -> u16 {
+        netif::mtu(&self.ifname).unwrap_or(0)
+    }
+
+    fn max_mtu(&self) -> u16 {
+        u16::MAX - ETH_HLEN as u16
+    }
+
+    fn set_mtu(&mut self, mtu: u16) -> io::Result<()> {
+        self.validate_mtu(mtu)?;
+        netif::set_mtu(&self.ifname, mtu)
+    }
+}
+
+impl NetworkDevice for TapDevice {
+    type TxQueue = TapTxQueue;
+    type RxQueue = TapRxQueue;
+
+    fn mac_address(&self) -> MacAddress {
+        netif::mac_address(&self.ifname).unwrap_or_default()
+    }
+
+    fn link_state(&self) -> LinkState {
+        netif::link_state(&self.ifname).unwrap_or(LinkState::Down)
+    }
+
+    /// Counters of the interface, i.e., RX and TX are seen from the host.
+    fn stats(&self) -> NetStats {
+        netif::stats(&self.ifname).unwrap_or_default()
+    }
+
+    fn offloads(&self) -> Offloads {
+        Offloads::empty()
+    }
+
+    fn num_queue_pairs(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn queue_pair(&mut self, index: usize) -> Option<&mut QueuePair<TapTxQueue, TapRxQueue>> {
+        self.queues.get_mut(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iomem::IOBuf;
+    use core::alloc::Layout;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn frame() -> IOBufChain {
+        let mut chain = IOBufChain::new(0, 1).unwrap();
+        let mut buf = IOBuf::new(Layout::from_size_align(64, 64).unwrap()).unwrap();
+        buf.copy_in(&[0; 60]).unwrap();
+        chain.append(buf);
+        chain
+    }
+
+    #[test]
+    fn tx_watermarks() {
+        let null = CString::new("/dev/null").unwrap();
+        let fd = unsafe { libc::open(null.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+        assert!(fd >= 0);
+        let mut tx = TapTxQueue::new(Rc::new(TapFd(fd)));
+
+        let highs = Arc::new(AtomicUsize::new(0));
+        let lows = Arc::new(AtomicUsize::new(0));
+        let wm = tx.watermarks().unwrap();
+        wm.set(1, 3);
+        let h = highs.clone();
+        wm.on_high(move || {
+            h.fetch_add(1, Ordering::Relaxed);
+        });
+        let l = lows.clone();
+        wm.on_low(move || {
+            l.fetch_add(1, Ordering::Relaxed);
+        });
+
+        for _ in 0..2 {
+            tx.enqueue(frame()).unwrap();
+        }
+        assert_eq!(highs.load(Ordering::Relaxed), 0);
+        tx.enqueue(frame()).unwrap();
+        assert_eq!(highs.load(Ordering::Relaxed), 1);
+
+        // Written chains count until they are dequeued
+        assert_eq!(tx.flush().unwrap(), 3);
+        tx.dequeue().unwrap();
+        assert_eq!(lows.load(Ordering::Relaxed), 0);
+        tx.dequeue().unwrap();
+        assert_eq!(lows.load(Ordering::Relaxed), 1);
+        assert!(!tx.watermarks().unwrap().is_congested());
+    }
+
+    /// Creates a TAP interface, skips (passes) without /dev/net/tun or
+    /// CAP_NET_ADMIN.
+    #[test]
+    fn transmit() {
+        let mut tap = match TapDevice::open("drvkit%d") {
+            Ok(tap) => tap,
+            Err(e) => {
+                eprintln!("skipping, can't create a TAP interface: {}", e);
+                return;
+            }
+        };
+        assert!(tap.ifname().starts_with("drvkit"));
+        // Writes to an interface that is down fail
+        if let Err(e) = tap.set_up(true) {
+            eprintln!("skipping, can't bring {} up: {}", tap.ifname(), e);
+            return;
+        }
+        assert_ne!(tap.mac_address(), [0; 6]);
+
+        let mut frame = [0u8; 60];
+        frame[0..6].copy_from_slice(&[0xff; 6]);
+        frame[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 1]);
+        frame[12..14].copy_from_slice(&0x88b5u16.to_be_bytes());
+        let mut chain = IOBufChain::new(0, 1).unwrap();
+        let mut buf = IOBuf::new(Layout::from_size_align(64, 64).unwrap()).unwrap();
+        buf.copy_in(&frame).unwrap();
+        chain.append(buf);
+
+        let queues = tap.queue_pair(0).unwrap();
+        let tx = queues.tx_mut();
+        tx.enqueue(chain).unwrap();
+        assert_eq!(tx.flush().unwrap(), 1);
+        assert_eq!(tx.can_dequeue(false), 1);
+        assert_eq!(tx.dequeue().unwrap().segments[0].len(), 60);
+
+        // Nothing to receive into
+        let rx = queues.rx_mut();
+        assert_eq!(rx.can_dequeue(false), 0);
+        assert!(rx.dequeue().is_err());
+    }
+}
@@ -0,0 +1,1022 @@
+//! A network device backed by AF_XDP sockets.
+//!
+//! This lets packet processing code written against [`NetworkDevice`] and
+//! [`DevQueue`] run on any Linux interface (including veth pairs) before
+//! moving to a real hardware driver. Every queue pair is one AF_XDP socket
+//! bound to the corresponding queue of the interface; a small XDP program
+//! redirects all received packets to the sockets.
+//!
+//! Packets are copied between the `IOBufChain`s of the client and the UMEM
+//! frames of the socket, so this is meant for testing rather than
+//! performance. Requires CAP_NET_ADMIN and CAP_BPF (or root).
+//!
+//! See also <https://www.kernel.org/doc/html/latest/networking/af_xdp.html>.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::prelude::v1::*;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::devq::{DevQueue, DevQueueError, QueueOccupancy, QueuePair, Watermarks};
+use crate::iomem::{IOBufChain, IOMemError};
+use crate::net::device::{LinkState, NetStats, Offloads};
+use crate::net::{rx_frame_size, MacAddress, Mtu, NetworkDevice};
+
+use super::netif;
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+
+/// Bind flag to force copy mode.
+const XDP_COPY: u16 = 1 << 1;
+
+/// Headroom the kernel reserves in front of every received packet.
+const XDP_PACKET_HEADROOM: u32 = 256;
+
+// bpf(2) commands, map/program types and helpers (see linux/bpf.h)
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+
+/// Size of the `union bpf_attr` we pass to the kernel (large enough for all
+/// commands used here, the unused tail must be zero).
+const BPF_ATTR_SIZE: usize = 128;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// Configuration of the AF_XDP sockets.
+#[derive(Debug, Clone, Copy)]
+pub struct XdpConfig {
+    /// Size of a UMEM frame (power of two, 2048 or 4096).
+    pub frame_size: u32,
+    /// Number of UMEM frames per queue pair.
+    pub frame_count: u32,
+    /// Number of entries of every ring (power of two).
+    pub ring_size: u32,
+    /// Force copy mode even if the driver supports zero-copy.
+    pub copy_mode: bool,
+}
+
+impl Default for XdpConfig {
+    fn default() -> Self {
+        XdpConfig {
+            frame_size: 2048,
+            frame_count: 4096,
+            ring_size: 2048,
+            copy_mode: false,
+        }
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// A single-producer/single-consumer ring shared with the kernel.
+struct Ring<T> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    descs: *mut T,
+    size: u32,
+    map: *mut libc::c_void,
+    map_len: usize,
+}
+
+impl<T: Copy> Ring<T> {
+    /// # Safety
+    /// `fd` must be an AF_XDP socket whose ring at `pgoff` has `size`
+    /// entries and the layout described by `offset`.
+    unsafe fn map(
+        fd: RawFd,
+        offset: &XdpRingOffset,
+        size: u32,
+        pgoff: libc::off_t,
+    ) -> io::Result<Self> {
+        let map_len = offset.desc as usize + size as usize * core::mem::size_of::<T>();
+        let map = libc::mmap(
+            core::ptr::null_mut(),
+            map_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd,
+            pgoff,
+        );
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let base = map as *mut u8;
+        Ok(Ring {
+            producer: base.add(offset.producer as usize) as *const AtomicU32,
+            consumer: base.add(offset.consumer as usize) as *const AtomicU32,
+            descs: base.add(offset.desc as usize) as *mut T,
+            size,
+            map,
+            map_len,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    /// Entries we can produce (for fill and TX rings).
+    fn free(&self) -> u32 {
+        let used = self
+            .producer()
+            .load(Ordering::Relaxed)
+            .wrapping_sub(self.consumer().load(Ordering::Acquire));
+        self.size - used
+    }
+
+    fn push(&mut self, item: T) -> bool {
+        if self.free() == 0 {
+            return false;
+        }
+        let prod = self.producer().load(Ordering::Relaxed);
+        unsafe {
+            self.descs
+                .add((prod & (self.size - 1)) as usize)
+                .write_volatile(item)
+        };
+        self.producer()
+            .store(prod.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Entries we can consume (for completion and RX rings).
+    fn available(&self) -> u32 {
+        self.producer()
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.consumer().load(Ordering::Relaxed))
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.available() == 0 {
+            return None;
+        }
+        let cons = self.consumer().load(Ordering::Relaxed);
+        let item = unsafe {
+            self.descs
+                .add((cons & (self.size - 1)) as usize)
+                .read_volatile()
+        };
+        self.consumer()
+            .store(cons.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map, self.map_len) };
+    }
+}
+
+/// An AF_XDP socket with its UMEM.
+struct Xsk {
+    fd: RawFd,
+    umem: *mut u8,
+    umem_len: usize,
+    frame_size: u32,
+    fill: Ring<u64>,
+    comp: Ring<u64>,
+    rx: Ring<XdpDesc>,
+    tx: Ring<XdpDesc>,
+    /// UMEM frames that are neither in the fill ring nor used for TX.
+    free_frames: Vec<u64>,
+}
+
+impl Xsk {
+    fn open(ifindex: u32, queue: u32, config: &XdpConfig) -> io::Result<Xsk> {
+        let fd = check(unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) })?;
+        // Close the socket if any of the remaining steps fails
+        let guard = FdGuard(fd);
+
+        let umem_len = config.frame_size as usize * config.frame_count as usize;
+        let umem = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let umem_guard = MapGuard(umem, umem_len);
+
+        let reg = XdpUmemReg {
+            addr: umem as u64,
+            len: umem_len as u64,
+            chunk_size: config.frame_size,
+            ..Default::default()
+        };
+        setsockopt(fd, XDP_UMEM_REG, &reg)?;
+        setsockopt(fd, XDP_UMEM_FILL_RING, &config.ring_size)?;
+        setsockopt(fd, XDP_UMEM_COMPLETION_RING, &config.ring_size)?;
+        setsockopt(fd, XDP_RX_RING, &config.ring_size)?;
+        setsockopt(fd, XDP_TX_RING, &config.ring_size)?;
+
+        let mut off = XdpMmapOffsets::default();
+        let mut optlen = core::mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut off as *mut _ as *mut libc::c_void,
+                &mut optlen,
+            )
+        })?;
+
+        // Safety: the rings were configured with `ring_size` entries above
+        let (fill, comp, rx, tx) = unsafe {
+            (
+                Ring::map(fd, &off.fr, config.ring_size, XDP_UMEM_PGOFF_FILL_RING)?,
+                Ring::map(
+                    fd,
+                    &off.cr,
+                    config.ring_size,
+                    XDP_UMEM_PGOFF_COMPLETION_RING,
+                )?,
+                Ring::map(fd, &off.rx, config.ring_size, XDP_PGOFF_RX_RING)?,
+                Ring::map(fd, &off.tx, config.ring_size, XDP_PGOFF_TX_RING)?,
+            )
+        };
+
+        let mut xsk = Xsk {
+            fd,
+            umem: umem as *mut u8,
+            umem_len,
+            frame_size: config.frame_size,
+            fill,
+            comp,
+            rx,
+            tx,
+            free_frames: (0..config.frame_count as u64)
+                .rev()
+                .map(|i| i * config.frame_size as u64)
+                .collect(),
+        };
+        core::mem::forget(guard);
+        core::mem::forget(umem_guard);
+
+        // Half of the frames receive, the other half transmit
+        for _ in 0..(config.frame_count / 2).min(config.ring_size) {
+            let frame = xsk.free_frames.pop().expect("frame_count >= 2");
+            xsk.fill.push(frame);
+        }
+
+        let sxdp = SockaddrXdp {
+            family: AF_XDP as u16,
+            flags: if config.copy_mode { XDP_COPY } else { 0 },
+            ifindex,
+            queue_id: queue,
+            shared_umem_fd: 0,
+        };
+        check(unsafe {
+            libc::bind(
+                fd,
+                &sxdp as *const _ as *const libc::sockaddr,
+                core::mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(xsk)
+    }
+
+    fn frame(&self, addr: u64, len: usize) -> &[u8] {
+        assert!(addr as usize + len <= self.umem_len);
+        unsafe { core::slice::from_raw_parts(self.umem.add(addr as usize), len) }
+    }
+
+    fn frame_mut(&mut self, addr: u64, len: usize) -> &mut [u8] {
+        assert!(addr as usize + len <= self.umem_len);
+        unsafe { core::slice::from_raw_parts_mut(self.umem.add(addr as usize), len) }
+    }
+}
+
+impl Drop for Xsk {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+            libc::munmap(self.umem as *mut libc::c_void, self.umem_len);
+        }
+    }
+}
+
+struct FdGuard(RawFd);
+
+impl Drop for FdGuard {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+struct MapGuard(*mut libc::c_void, usize);
+
+impl Drop for MapGuard {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.0, self.1) };
+    }
+}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, value: &T) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            opt,
+            value as *const T as *const libc::c_void,
+            core::mem::size_of::<T>() as libc::socklen_t,
+        )
+    })
+    .map(|_| ())
+}
+
+fn bpf(cmd: libc::c_long, attr: &mut [u8; BPF_ATTR_SIZE]) -> io::Result<RawFd> {
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_mut_ptr(), BPF_ATTR_SIZE) };
+    check(ret as libc::c_int)
+}
+
+fn put_u32(attr: &mut [u8; BPF_ATTR_SIZE], offset: usize, value: u32) {
+    attr[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(attr: &mut [u8; BPF_ATTR_SIZE], offset: usize, value: u64) {
+    attr[offset..offset + 8].copy_from_slice(&value.to_ne_bytes());
+}
+
+/// Encodes a `struct bpf_insn`.
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> u64 {
+    code as u64
+        | ((dst | (src << 4)) as u64) << 8
+        | (off as u16 as u64) << 16
+        | (imm as u32 as u64) << 32
+}
+
+/// The XDP program redirecting packets to the AF_XDP sockets, attached to
+/// the interface for as long as this is alive.
+struct XdpProgram {
+    map_fd: RawFd,
+    prog_fd: RawFd,
+    link_fd: RawFd,
+}
+
+impl XdpProgram {
+    /// Loads the program with room for sockets of `queues` queues.
+    fn load(queues: u32) -> io::Result<XdpProgram> {
+        let mut attr = [0u8; BPF_ATTR_SIZE];
+        put_u32(&mut attr, 0, BPF_MAP_TYPE_XSKMAP);
+        put_u32(&mut attr, 4, 4);
+        put_u32(&mut attr, 8, 4);
+        put_u32(&mut attr, 12, queues);
+        let map_fd = bpf(BPF_MAP_CREATE, &mut attr)?;
+        let map_guard = FdGuard(map_fd);
+
+        // return bpf_redirect_map(&xsks, ctx->rx_queue_index, XDP_PASS);
+        let insns = [
+            insn(0x61, 2, 1, 16, 0),
+            insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+            insn(0, 0, 0, 0, 0),
+            insn(0xb7, 3, 0, 0, XDP_PASS),
+            insn(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+            insn(0x95, 0, 0, 0, 0),
+        ];
+        let license = b"GPL\0";
+        let mut attr = [0u8; BPF_ATTR_SIZE];
+        put_u32(&mut attr, 0, BPF_PROG_TYPE_XDP);
+        put_u32(&mut attr, 4, insns.len() as u32);
+        put_u64(&mut attr, 8, insns.as_ptr() as u64);
+        put_u64(&mut attr, 16, license.as_ptr() as u64);
+        let prog_fd = bpf(BPF_PROG_LOAD, &mut attr)?;
+
+        core::mem::forget(map_guard);
+        Ok(XdpProgram {
+            map_fd,
+            prog_fd,
+            link_fd: -1,
+        })
+    }
+
+    /// Makes the program deliver packets of `queue` to `xsk`.
+    fn insert(&mut self, queue: u32, xsk: &Xsk) -> io::Result<()> {
+        let key = queue;
+        let value = xsk.fd as u32;
+        let mut attr = [0u8; BPF_ATTR_SIZE];
+        put_u32(&mut attr, 0, self.map_fd as u32);
+        put_u64(&mut attr, 8, &key as *const u32 as u64);
+        put_u64(&mut attr, 16, &value as *const u32 as u64);
+        bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(|_| ())
+    }
+
+    /// Attaches the program to the interface, in native mode if the driver
+    /// supports it and generic (SKB) mode otherwise.
+    fn attach(&mut self, ifindex: u32) -> io::Result<()> {
+        let mut result = Err(io::Error::from(io::ErrorKind::Unsupported));
+        for flags in [0, XDP_FLAGS_SKB_MODE] {
+            let mut attr = [0u8; BPF_ATTR_SIZE];
+            put_u32(&mut attr, 0, self.prog_fd as u32);
+            put_u32(&mut attr, 4, ifindex);
+            put_u32(&mut attr, 8, BPF_XDP);
+            put_u32(&mut attr, 12, flags);
+            result = bpf(BPF_LINK_CREATE, &mut attr);
+            if let Ok(fd) = result {
+                self.link_fd = fd;
+                return Ok(());
+            }
+        }
+        result.map(|_| ())
+    }
+}
+
+impl Drop for XdpProgram {
+    fn drop(&mut self) {
+        unsafe {
+            // Closing the link detaches the program
+            if self.link_fd >= 0 {
+                libc::close(self.link_fd);
+            }
+            libc::close(self.prog_fd);
+            libc::close(self.map_fd);
+        }
+    }
+}
+
+/// The TX side of an AF_XDP socket.
+pub struct XdpTxQueue {
+    xsk: Rc<RefCell<Xsk>>,
+    in_flight: VecDeque<IOBufChain>,
+    /// Chains at the front of `in_flight` whose frames were recycled.
+    completed: usize,
+    unflushed: usize,
+    watermarks: Watermarks,
+}
+
+impl XdpTxQueue {
+    fn new(xsk: Rc<RefCell<Xsk>>) -> XdpTxQueue {
+        let capacity = xsk.borrow().tx.size as usize;
+        XdpTxQueue {
+            xsk,
+            in_flight: VecDeque::new(),
+            completed: 0,
+            unflushed: 0,
+            watermarks: Watermarks::for_capacity(capacity),
+        }
+    }
+
+    /// Moves the frames of completed transmissions back to the free frames.
+    fn reclaim(&mut self) {
+        let mut xsk = self.xsk.borrow_mut();
+        while let Some(frame) = xsk.comp.pop() {
+            xsk.free_frames.push(frame);
+            self.completed += 1;
+        }
+    }
+}
+
+impl DevQueue for XdpTxQueue {
+    fn enqueue(&mut self, bufs: IOBufChain) -> Result<(), IOBufChain> {
+        self.reclaim();
+        let mut xsk = self.xsk.borrow_mut();
+        let len: usize = bufs.segments.iter().map(|s| s.len()).sum();
+        if len > xsk.frame_size as usize {
+            warn!("AF_XDP: can't transmit {} bytes in one frame", len);
+            return Err(bufs);
+        }
+        if xsk.tx.free() == 0 {
+            return Err(bufs);
+        }
+
+        let frame = match xsk.free_frames.pop() {
+            Some(frame) => frame,
+            None => return Err(bufs),
+        };
+        let data = xsk.frame_mut(frame, len);
+        let mut offset = 0;
+        for seg in bufs.segments.iter() {
+            data[offset..offset + seg.len()].copy_from_slice(seg.as_slice());
+            offset += seg.len();
+        }
+        xsk.tx.push(XdpDesc {
+            addr: frame,
+            len: len as u32,
+            options: 0,
+        });
+
+        drop(xsk);
+
+        self.in_flight.push_back(bufs);
+        self.unflushed += 1;
+        self.watermarks.update(self.in_flight.len());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, DevQueueError> {
+        if self.unflushed == 0 {
+            return Ok(0);
+        }
+
+        let xsk = self.xsk.borrow();
+        let ret = unsafe {
+            libc::sendto(
+                xsk.fd,
+                core::ptr::null(),
+                0,
+                libc::MSG_DONTWAIT,
+                core::ptr::null(),
+                0,
+            )
+        };
+        if ret < 0 {
+            // The kernel is busy or out of buffers, it picks up the rest later
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => {}
+                _ => return Err(DevQueueError::QueueFailure),
+            }
+        }
+        Ok(core::mem::replace(&mut self.unflushed, 0))
+    }
+
+    fn can_enqueue(&self, how_many_seg: usize) -> bool {
+        let xsk = self.xsk.borrow();
+        let frames = xsk.free_frames.len() + xsk.comp.available() as usize;
+        frames >= how_many_seg && xsk.tx.free() as usize >= how_many_seg
+    }
+
+    fn dequeue(&mut self) -> Result<IOBufChain, DevQueueError> {
+        self.reclaim();
+        if self.completed == 0 {
+            return Err(DevQueueError::QueueEmpty);
+        }
+        self.completed -= 1;
+        let bufs = self
+            .in_flight
+            .pop_front()
+            .ok_or(DevQueueError::QueueFailure)?;
+        self.watermarks.update(self.in_flight.len());
+        Ok(bufs)
+    }
+
+    fn can_dequeue(&mut self, _exact: bool) -> usize {
+        (self.completed + self.xsk.borrow().comp.available() as usize).min(self.in_flight.len())
+    }
+
+    fn watermarks(&mut self) -> Option<&mut Watermarks> {
+        Some(&mut self.watermarks)
+    }
+}
+
+impl QueueOccupancy for XdpTxQueue {
+    fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.xsk.borrow().tx.size as usize
+    }
+}
+
+/// The RX side of an AF_XDP socket.
+///
+/// Clients enqueue empty chains that received packets are copied into.
+pub struct XdpRxQueue {
+    xsk: Rc<RefCell<Xsk>>,
+    buffers: VecDeque<IOBufChain>,
+}
+
+impl DevQueue for XdpRxQueue {
+    fn enqueue(&mut self, bufs: IOBufChain) -> Result<(), IOBufChain> {
+        if self.buffers.len() >= self.capacity() {
+            return Err(bufs);
+        }
+        self.buffers.push_back(bufs);
+        Ok(())
+    }
+
+    /// The kernel picks up new frames from the fill ring by itself, so
+    /// there is nothing to do.
+    fn flush(&mut self) -> Result<usize, DevQueueError> {
+        Ok(0)
+    }
+
+    fn can_enqueue(&self, how_many_seg: usize) -> bool {
+        self.buffers.len() + how_many_seg <= self.capacity()
+    }
+
+    fn dequeue(&mut self) -> Result<IOBufChain, DevQueueError> {
+        if self.buffers.is_empty() {
+            return Err(DevQueueError::QueueEmpty);
+        }
+        let mut xsk = self.xsk.borrow_mut();
+        let desc = xsk.rx.pop().ok_or(DevQueueError::QueueEmpty)?;
+        let mut bufs = self
+            .buffers
+            .pop_front()
+            .ok_or(DevQueueError::QueueFailure)?;
+
+        let data = xsk.frame(desc.addr, desc.len as usize);
+        let mut offset = 0;
+        let copied: Result<(), IOMemError> = bufs.segments.iter_mut().try_for_each(|seg| {
+            offset += seg.copy_in(&data[offset..])?;
+            Ok(())
+        });
+
+        // Hand the frame back to the kernel (the packet is dropped if it
+        // couldn't be copied)
+        let frame = desc.addr - desc.addr % xsk.frame_size as u64;
+        xsk.fill.push(frame);
+        if copied.is_err() {
+            self.buffers.push_front(bufs);
+            return Err(DevQueueError::BufferInvalid);
+        }
+        if offset < desc.len as usize {
+            warn!(
+                "AF_XDP: truncated packet of {} bytes to {}",
+                desc.len, offset
+            );
+        }
+        Ok(bufs)
+    }
+
+    fn can_dequeue(&mut self, _exact: bool) -> usize {
+        (self.xsk.borrow().rx.available() as usize).min(self.buffers.len())
+    }
+}
+
+impl QueueOccupancy for XdpRxQueue {
+    fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.xsk.borrow().rx.size as usize
+    }
+}
+
+/// A Linux network interface driven through AF_XDP.
+pub struct XdpDevice {
+    ifname: String,
+    frame_size: u32,
+    queues: Vec<QueuePair<XdpTxQueue, XdpRxQueue>>,
+    _program: XdpProgram,
+}
+
+impl XdpDevice {
+    /// Opens `queue_pairs` queues of the interface `ifname`.
+    pub fn open(ifname: &str, queue_pairs: usize, config: XdpConfig) -> io::Result<XdpDevice> {
+        let ifindex = netif::ifindex(ifname)?;
+        let mut program = XdpProgram::load(queue_pairs as u32)?;
+
+        let mut queues = Vec::with_capacity(queue_pairs);
+        for queue in 0..queue_pairs {
+            let xsk = Xsk::open(ifindex, queue as u32, &config)?;
+            program.insert(queue as u32, &xsk)?;
+
+            let xsk = Rc::new(RefCell::new(xsk));
+            let tx = XdpTxQueue::new(xsk.clone());
+            let rx = XdpRxQueue {
+                xsk,
+                buffers: VecDeque::new(),
+            };
+            queues.push(QueuePair::new(queue, tx, rx));
+        }
+
+        program.attach(ifindex)?;
+        info!(
+            "AF_XDP: opened {} with {} queue pair(s)",
+            ifname, queue_pairs
+        );
+
+        Ok(XdpDevice {
+            ifname: ifname.to_string(),
+            frame_size: config.frame_size,
+            queues,
+            _program: program,
+        })
+    }
+
+    pub fn ifname(&self) -> &str {
+        &self.ifname
+    }
+}
+
+impl Mtu for XdpDevice {
+    type Error = io::Error;
+
+    fn mtu(&self) -> u16 {
+        netif::mtu(&self.ifname).unwrap_or(0)
+    }
+
+    /// The largest MTU whose frames fit into a UMEM frame.
+    fn max_mtu(&self) -> u16 {
+        let room = (self.frame_size - XDP_PACKET_HEADROOM) as usize;
+        (room - rx_frame_size(0)) as u16
+    }
+
+    fn set_mtu(&mut self, mtu: u16) -> io::Result<()> {
+        self.validate_mtu(mtu)?;
+        netif::set_mtu(&self.ifname, mtu)
+    }
+}
+
+impl NetworkDevice for XdpDevice {
+    type TxQueue = XdpTxQueue;
+    type RxQueue = XdpRxQueue;
+
+    fn mac_address(&self) -> MacAddress {
+        netif::mac_address(&self.ifname).unwrap_or_default()
+    }
+
+    fn link_state(&self) -> LinkState {
+        netif::link_state(&self.ifname).unwrap_or(LinkState::Down)
+    }
+
+    fn stats(&self) -> NetStats {
+        netif::stats(&self.ifname).unwrap_or_default()
+    }
+
+    fn offloads(&self) -> Offloads {
+        Offloads::empty()
+    }
+
+    fn num_queue_pairs(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn queue_pair(&mut self, index: usize) -> Option<&mut QueuePair<XdpTxQueue, XdpRxQueue>> {
+        self.queues.get_mut(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iomem::IOBuf;
+    use core::alloc::Layout;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    const FRAME_SIZE: u32 = 2048;
+
+    fn anonymous(len: usize) -> *mut libc::c_void {
+        let map = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(map, libc::MAP_FAILED);
+        map
+    }
+
+    /// A ring in anonymous memory, with the indices starting at `start`.
+    fn ring<T: Copy>(size: u32, start: u32) -> Ring<T> {
+        let map_len = 64 + size as usize * core::mem::size_of::<T>();
+        let map = anonymous(map_len);
+        let base = map as *mut u8;
+        let ring = Ring {
+            producer: base as *const AtomicU32,
+            consumer: unsafe { base.add(4) } as *const AtomicU32,
+            descs: unsafe { base.add(64) } as *mut T,
+            size,
+            map,
+            map_len,
+        };
+        ring.producer().store(start, Ordering::Relaxed);
+        ring.consumer().store(start, Ordering::Relaxed);
+        ring
+    }
+
+    /// A socket with `frames` free frames and no kernel behind it: the tests
+    /// play the kernel's side of the rings.
+    fn xsk(frames: u64) -> Rc<RefCell<Xsk>> {
+        let umem_len = (frames * FRAME_SIZE as u64) as usize;
+        Rc::new(RefCell::new(Xsk {
+            fd: -1,
+            umem: anonymous(umem_len) as *mut u8,
+            umem_len,
+            frame_size: FRAME_SIZE,
+            fill: ring(4, 0),
+            comp: ring(4, 0),
+            rx: ring(4, 0),
+            tx: ring(4, 0),
+            free_frames: (0..frames).rev().map(|i| i * FRAME_SIZE as u64).collect(),
+        }))
+    }
+
+    fn packet(byte: u8) -> IOBufChain {
+        let mut chain = IOBufChain::new(0, 1).unwrap();
+        let mut buf = IOBuf::new(Layout::from_size_align(64, 64).unwrap()).unwrap();
+        buf.copy_in(&[byte; 64]).unwrap();
+        chain.append(buf);
+        chain
+    }
+
+    #[test]
+    fn ring_indices() {
+        // The indices wrap around while the ring is in use
+        let mut ring: Ring<u64> = ring(4, u32::MAX - 1);
+        assert_eq!((ring.free(), ring.available()), (4, 0));
+        for i in 0..4 {
+            assert!(ring.push(i));
+        }
+        assert!(!ring.push(4));
+        assert_eq!((ring.free(), ring.available()), (0, 4));
+        assert_eq!(ring.producer().load(Ordering::Relaxed), 2);
+
+        assert_eq!(ring.pop(), Some(0));
+        assert!(ring.push(4));
+        assert_eq!(
+            (1..5).map(|_| ring.pop().unwrap()).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(ring.pop(), None);
+        assert_eq!(ring.free(), 4);
+    }
+
+    #[test]
+    fn tx_recycles_frames() {
+        let xsk = xsk(2);
+        let mut txq = XdpTxQueue::new(xsk.clone());
+
+        assert!(txq.enqueue(packet(1)).is_ok());
+        assert!(txq.enqueue(packet(2)).is_ok());
+        assert!(!txq.can_enqueue(1));
+        assert!(txq.enqueue(packet(3)).is_err());
+        assert!(core::matches!(
+            txq.dequeue(),
+            Err(DevQueueError::QueueEmpty)
+        ));
+
+        // The kernel sends the first packet
+        {
+            let mut xsk = xsk.borrow_mut();
+            let desc = xsk.tx.pop().unwrap();
+            assert_eq!((desc.len, xsk.frame(desc.addr, 64)[0]), (64, 1));
+            xsk.comp.push(desc.addr);
+        }
+        assert!(txq.can_enqueue(1));
+        assert_eq!(txq.can_dequeue(false), 1);
+        // Enqueue reuses the completed frame before the client dequeued
+        assert!(txq.enqueue(packet(3)).is_ok());
+        assert_eq!(txq.can_dequeue(false), 1);
+        assert_eq!(txq.dequeue().unwrap().segments[0].as_slice()[0], 1);
+        assert!(core::matches!(
+            txq.dequeue(),
+            Err(DevQueueError::QueueEmpty)
+        ));
+        assert_eq!(txq.len(), 2);
+    }
+
+    #[test]
+    fn tx_watermarks() {
+        let xsk = xsk(4);
+        let mut txq = XdpTxQueue::new(xsk.clone());
+        let highs = Arc::new(AtomicUsize::new(0));
+        let lows = Arc::new(AtomicUsize::new(0));
+        let wm = txq.watermarks().unwrap();
+        assert_eq!((wm.low(), wm.high()), (1, 3));
+        let h = highs.clone();
+        wm.on_high(move || {
+            h.fetch_add(1, Ordering::Relaxed);
+        });
+        let l = lows.clone();
+        wm.on_low(move || {
+            l.fetch_add(1, Ordering::Relaxed);
+        });
+
+        for i in 0..3 {
+            txq.enqueue(packet(i)).unwrap();
+        }
+        assert_eq!(highs.load(Ordering::Relaxed), 1);
+
+        // The kernel sends all three packets
+        {
+            let mut xsk = xsk.borrow_mut();
+            while let Some(desc) = xsk.tx.pop() {
+                xsk.comp.push(desc.addr);
+            }
+        }
+        txq.dequeue().unwrap();
+        assert_eq!(lows.load(Ordering::Relaxed), 0);
+        txq.dequeue().unwrap();
+        assert_eq!(lows.load(Ordering::Relaxed), 1);
+        assert_eq!(highs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn rx_returns_frames() {
+        let xsk = xsk(2);
+        let mut rxq = XdpRxQueue {
+            xsk: xsk.clone(),
+            buffers: VecDeque::new(),
+        };
+        assert!(core::matches!(
+            rxq.dequeue(),
+            Err(DevQueueError::QueueEmpty)
+        ));
+        rxq.enqueue(packet(0)).unwrap();
+
+        // The kernel received 16 bytes into the second frame
+        {
+            let mut xsk = xsk.borrow_mut();
+            let addr = FRAME_SIZE as u64 + XDP_PACKET_HEADROOM as u64;
+            xsk.frame_mut(addr, 16).copy_from_slice(&[7; 16]);
+            xsk.rx.push(XdpDesc {
+                addr,
+                len: 16,
+                options: 0,
+            });
+        }
+        assert_eq!(rxq.can_dequeue(false), 1);
+        let bufs = rxq.dequeue().unwrap();
+        assert_eq!(bufs.segments[0].as_slice(), [7; 16]);
+        assert_eq!(xsk.borrow_mut().fill.pop(), Some(FRAME_SIZE as u64));
+        assert_eq!(rxq.len(), 0);
+    }
+}
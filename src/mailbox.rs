@@ -0,0 +1,254 @@
+//! Mailboxes between SR-IOV physical and virtual functions.
+//!
+//! PF and VF drivers of most SR-IOV NICs talk through a small buffer of
+//! shared registers, guarded by ownership bits and signaled with a doorbell
+//! (the message and acknowledgement notifications). The device specific part
+//! is the register layout ([`MailboxHw`]), the exchange itself (lock, copy,
+//! ring, wait for the peer) is the same everywhere and lives in [`Mailbox`].
+
+use core::time::Duration;
+
+use custom_error::custom_error;
+
+use crate::poll::poll_until;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub MailboxError
+    Timeout = "timed out waiting for the peer",
+    Busy = "the peer holds the mailbox",
+    TooLong = "the message doesn't fit in the mailbox",
+    Reset = "the peer is resetting",
+}
+
+/// Register interface of a mailbox, seen from one side.
+///
+/// The notification checks consume the notification, i.e., return true only
+/// once per event (hardware often implements them as read-to-clear bits).
+pub trait MailboxHw {
+    /// Size of the shared buffer in 32-bit words.
+    fn size(&self) -> usize;
+
+    /// Tries to take ownership of the shared buffer.
+    fn try_lock(&mut self) -> bool;
+
+    fn read_word(&mut self, index: usize) -> u32;
+
+    fn write_word(&mut self, index: usize, value: u32);
+
+    /// Notifies the peer that a message is in the buffer (and releases it).
+    fn ring_doorbell(&mut self);
+
+    /// Acknowledges the message of the peer (and releases the buffer).
+    fn ack(&mut self);
+
+    /// Whether the peer wrote a message.
+    fn check_message(&mut self) -> bool;
+
+    /// Whether the peer acknowledged our message.
+    fn check_ack(&mut self) -> bool;
+
+    /// Whether the peer is resetting (so nothing will be acknowledged).
+    fn check_reset(&mut self) -> bool {
+        false
+    }
+}
+
+/// Timeouts of the mailbox operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MailboxConfig {
+    /// How long to wait for the lock, an acknowledgement or a message.
+    pub timeout: Duration,
+    /// How often a message is re-sent if the peer doesn't acknowledge it.
+    pub retries: u32,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        MailboxConfig {
+            timeout: Duration::from_millis(500),
+            retries: 2,
+        }
+    }
+}
+
+/// Waits until `cond` holds, for at most `timeout`.
+fn wait<F: FnMut() -> bool>(cond: F, timeout: Duration) -> Result<(), MailboxError> {
+    if poll_until(cond, timeout) {
+        Ok(())
+    } else {
+        Err(MailboxError::Timeout)
+    }
+}
+
+/// Message exchange over a [`MailboxHw`].
+pub struct Mailbox<H> {
+    hw: H,
+    config: MailboxConfig,
+}
+
+impl<H: MailboxHw> Mailbox<H> {
+    pub fn new(hw: H) -> Mailbox<H> {
+        Mailbox::with_config(hw, MailboxConfig::default())
+    }
+
+    pub fn with_config(hw: H, config: MailboxConfig) -> Mailbox<H> {
+        Mailbox { hw, config }
+    }
+
+    pub fn config(&self) -> MailboxConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: MailboxConfig) {
+        self.config = config;
+    }
+
+    pub fn hw(&self) -> &H {
+        &self.hw
+    }
+
+    pub fn hw_mut(&mut self) -> &mut H {
+        &mut self.hw
+    }
+
+    fn lock(&mut self) -> Result<(), MailboxError> {
+        let hw = &mut self.hw;
+        wait(|| hw.try_lock(), self.config.timeout).map_err(|_e| MailboxError::Busy)
+    }
+
+    /// Sends `msg` and waits until the peer acknowledges it, re-sending it
+    /// up to [`MailboxConfig::retries`] times.
+    pub fn post(&mut self, msg: &[u32]) -> Result<(), MailboxError> {
+        if msg.len() > self.hw.size() {
+            return Err(MailboxError::TooLong);
+        }
+
+        let mut attempts = 0;
+        loop {
+            self.lock()?;
+            // Stale notifications belong to an earlier exchange
+            self.hw.check_message();
+            self.hw.check_ack();
+            for (i, word) in msg.iter().enumerate() {
+                self.hw.write_word(i, *word);
+            }
+            {
+                span!(TRACE, "doorbell", attempt = attempts);
+                self.hw.ring_doorbell();
+            }
+
+            let hw = &mut self.hw;
+            match wait(|| hw.check_ack(), self.config.timeout) {
+                Ok(()) => return Ok(()),
+                Err(_e) if self.hw.check_reset() => return Err(MailboxError::Reset),
+                Err(e) if attempts >= self.config.retries => return Err(e),
+                Err(_e) => {
+                    attempts += 1;
+                    debug!("mailbox: message not acknowledged, retry {}", attempts);
+                }
+            }
+        }
+    }
+
+    /// Waits for a message of the peer, copies it to `msg` and acknowledges
+    /// it.
+    pub fn receive(&mut self, msg: &mut [u32]) -> Result<(), MailboxError> {
+        if msg.len() > self.hw.size() {
+            return Err(MailboxError::TooLong);
+        }
+
+        let hw = &mut self.hw;
+        wait(|| hw.check_message(), self.config.timeout)?;
+        self.lock()?;
+        for (i, word) in msg.iter_mut().enumerate() {
+            *word = self.hw.read_word(i);
+        }
+        self.hw.ack();
+        Ok(())
+    }
+
+    /// Sends the first `len` words of `msg` and reads the reply into `msg`.
+    pub fn request(&mut self, msg: &mut [u32], len: usize) -> Result<(), MailboxError> {
+        self.post(&msg[..len])?;
+        self.receive(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A peer that acknowledges every message and answers with the message
+    /// incremented by one.
+    struct Echo {
+        buffer: [u32; 4],
+        pending_ack: bool,
+        pending_msg: bool,
+        ignore: u32,
+    }
+
+    impl MailboxHw for Echo {
+        fn size(&self) -> usize {
+            self.buffer.len()
+        }
+
+        fn try_lock(&mut self) -> bool {
+            true
+        }
+
+        fn read_word(&mut self, index: usize) -> u32 {
+            self.buffer[index]
+        }
+
+        fn write_word(&mut self, index: usize, value: u32) {
+            self.buffer[index] = value;
+        }
+
+        fn ring_doorbell(&mut self) {
+            if self.ignore > 0 {
+                self.ignore -= 1;
+                return;
+            }
+            for word in self.buffer.iter_mut() {
+                *word += 1;
+            }
+            self.pending_ack = true;
+            self.pending_msg = true;
+        }
+
+        fn ack(&mut self) {}
+
+        fn check_message(&mut self) -> bool {
+            core::mem::take(&mut self.pending_msg)
+        }
+
+        fn check_ack(&mut self) -> bool {
+            core::mem::take(&mut self.pending_ack)
+        }
+    }
+
+    #[test]
+    fn request_retries() {
+        let echo = Echo {
+            buffer: [0; 4],
+            pending_ack: false,
+            pending_msg: false,
+            ignore: 1,
+        };
+        let config = MailboxConfig {
+            timeout: Duration::from_millis(5),
+            retries: 1,
+        };
+        let mut mbx = Mailbox::with_config(echo, config);
+
+        let mut msg = [1, 2, 3, 4];
+        mbx.request(&mut msg, 2).unwrap();
+        assert_eq!(msg, [2, 3, 1, 1]);
+        assert!(matches!(mbx.post(&[0; 5]), Err(MailboxError::TooLong)));
+
+        mbx.hw_mut().ignore = 2;
+        assert!(matches!(mbx.post(&[1]), Err(MailboxError::Timeout)));
+    }
+}
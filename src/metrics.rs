@@ -0,0 +1,273 @@
+//! Counters, gauges and histograms to observe drivers.
+//!
+//! Metrics are plain statics updated with relaxed atomics so they are cheap
+//! enough for hot paths and don't need an allocator. Driver-defined metrics
+//! are made visible with [`register`]; the host periodically calls [`export`]
+//! with a [`MetricsSink`] of its choice (log lines, a monitoring agent etc.)
+//! to collect the built-in and registered metrics.
+
+use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use custom_error::custom_error;
+use spin::Mutex;
+
+/// Maximum number of metrics that can be registered in addition to the
+/// built-in ones.
+pub const MAX_METRICS: usize = 64;
+
+/// Number of buckets of a [`Histogram`] (one for zero, one per bit of u64).
+pub const HISTOGRAM_BUCKETS: usize = 65;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub MetricsError
+    RegistryFull = "no free slot to register another metric",
+}
+
+/// Number of 32-bit PCI configuration space reads.
+pub static PCI_CONFIG_READS: Counter = Counter::new("pci.config_reads");
+/// Number of 32-bit PCI configuration space writes.
+pub static PCI_CONFIG_WRITES: Counter = Counter::new("pci.config_writes");
+/// Number of `IOBuf`s allocated from the DMA allocator.
+pub static IOBUF_ALLOCS: Counter = Counter::new("iomem.iobuf_allocs");
+/// Number of `IOBuf`s handed out from an `IOBufPool` without allocating.
+pub static IOBUF_POOL_HITS: Counter = Counter::new("iomem.iobuf_pool_hits");
+/// Number of failed DMA memory allocations.
+pub static DMA_ALLOC_FAILURES: Counter = Counter::new("iomem.dma_alloc_failures");
+/// Bytes currently allocated by the DMA allocator.
+pub static DMA_BYTES: Gauge = Gauge::new("iomem.dma_bytes");
+/// Number of chains enqueued through a `QueuePair`.
+pub static DEVQ_ENQUEUES: Counter = Counter::new("devq.enqueues");
+/// Number of chains a `QueuePair` handed back because the queue was full.
+pub static DEVQ_QUEUE_FULL: Counter = Counter::new("devq.queue_full");
+/// Number of chains dequeued through a `QueuePair` or a `PollExecutor`.
+pub static DEVQ_DEQUEUES: Counter = Counter::new("devq.dequeues");
+/// Number of doorbells a `Notifier` asked for.
+pub static DEVQ_DOORBELLS: Counter = Counter::new("devq.doorbells");
+
+static BUILTIN: [Metric; 10] = [
+    Metric::Counter(&PCI_CONFIG_READS),
+    Metric::Counter(&PCI_CONFIG_WRITES),
+    Metric::Counter(&IOBUF_ALLOCS),
+    Metric::Counter(&IOBUF_POOL_HITS),
+    Metric::Counter(&DMA_ALLOC_FAILURES),
+    Metric::Gauge(&DMA_BYTES),
+    Metric::Counter(&DEVQ_ENQUEUES),
+    Metric::Counter(&DEVQ_QUEUE_FULL),
+    Metric::Counter(&DEVQ_DEQUEUES),
+    Metric::Counter(&DEVQ_DOORBELLS),
+];
+
+static REGISTRY: Mutex<[Option<Metric>; MAX_METRICS]> = Mutex::new([None; MAX_METRICS]);
+
+/// A monotonically increasing count.
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Counter {
+        Counter {
+            name,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down (e.g., queue occupancy).
+#[derive(Debug)]
+pub struct Gauge {
+    name: &'static str,
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str) -> Gauge {
+        Gauge {
+            name,
+            value: AtomicI64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add(&self, n: i64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn sub(&self, n: i64) {
+        self.value.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A distribution of values in power-of-two buckets.
+///
+/// Bucket 0 counts zeros, bucket `i` counts values in `[2^(i-1), 2^i)`.
+#[derive(Debug)]
+pub struct Histogram {
+    name: &'static str,
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new(name: &'static str) -> Histogram {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            name,
+            buckets: [ZERO; HISTOGRAM_BUCKETS],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Index of the bucket `value` is counted in.
+    pub fn bucket_index(value: u64) -> usize {
+        (u64::BITS - value.leading_zeros()) as usize
+    }
+
+    /// The (exclusive) upper bound of values counted in bucket `index`.
+    pub fn bucket_upper_bound(index: usize) -> u64 {
+        1u64.checked_shl(index as u32).unwrap_or(u64::MAX)
+    }
+
+    #[inline]
+    pub fn record(&self, value: u64) {
+        self.buckets[Histogram::bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Number of values in bucket `index`.
+    pub fn bucket(&self, index: usize) -> u64 {
+        self.buckets[index].load(Ordering::Relaxed)
+    }
+
+    /// Number of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of all recorded values.
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+}
+
+/// A reference to a metric.
+#[derive(Debug, Clone, Copy)]
+pub enum Metric {
+    Counter(&'static Counter),
+    Gauge(&'static Gauge),
+    Histogram(&'static Histogram),
+}
+
+impl Metric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Counter(c) => c.name(),
+            Metric::Gauge(g) => g.name(),
+            Metric::Histogram(h) => h.name(),
+        }
+    }
+
+    fn export(&self, sink: &mut dyn MetricsSink) {
+        match self {
+            Metric::Counter(c) => sink.counter(c.name(), c.get()),
+            Metric::Gauge(g) => sink.gauge(g.name(), g.get()),
+            Metric::Histogram(h) => sink.histogram(h.name(), h),
+        }
+    }
+}
+
+/// Receives metric values on [`export`].
+pub trait MetricsSink {
+    fn counter(&mut self, name: &str, value: u64);
+    fn gauge(&mut self, name: &str, value: i64);
+    fn histogram(&mut self, name: &str, histogram: &Histogram);
+}
+
+/// Makes a driver-defined metric visible to [`export`].
+pub fn register(metric: Metric) -> Result<(), MetricsError> {
+    let mut registry = REGISTRY.lock();
+    let slot = registry
+        .iter_mut()
+        .find(|m| m.is_none())
+        .ok_or(MetricsError::RegistryFull)?;
+    *slot = Some(metric);
+    Ok(())
+}
+
+/// Hands the current value of every built-in and registered metric to `sink`.
+pub fn export(sink: &mut dyn MetricsSink) {
+    for metric in BUILTIN.iter() {
+        metric.export(sink);
+    }
+
+    let registry = REGISTRY.lock();
+    for metric in registry.iter().flatten() {
+        metric.export(sink);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        static H: Histogram = Histogram::new("test.histogram");
+        H.record(0);
+        H.record(1);
+        H.record(5);
+        H.record(7);
+        H.record(u64::MAX);
+
+        assert_eq!(H.bucket(0), 1);
+        assert_eq!(H.bucket(1), 1);
+        assert_eq!(H.bucket(3), 2);
+        assert_eq!(H.bucket(64), 1);
+        assert_eq!(H.count(), 5);
+        assert_eq!(Histogram::bucket_upper_bound(3), 8);
+        assert_eq!(Histogram::bucket_upper_bound(64), u64::MAX);
+    }
+}
@@ -144,3 +144,50 @@ pub const CSUM_FLAGS_RX: u32 = CSUM_INNER_L3_CALC
     | CSUM_L5_CALC
     | CSUM_L5_VALID
     | CSUM_COALESCED;
+
+/// Result of the checksum validation of a received packet at one layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RxChecksum {
+    /// The hardware verified the checksum and it is correct.
+    Good,
+    /// The hardware verified the checksum and it is wrong.
+    Bad,
+    /// The hardware didn't check the checksum.
+    Unknown,
+}
+
+impl RxChecksum {
+    fn from_flags(flags: u32, calc: u32, valid: u32) -> RxChecksum {
+        match (flags & calc != 0, flags & valid != 0) {
+            (true, true) => RxChecksum::Good,
+            (true, false) => RxChecksum::Bad,
+            (false, _) => RxChecksum::Unknown,
+        }
+    }
+
+    fn to_flags(self, calc: u32, valid: u32) -> u32 {
+        match self {
+            RxChecksum::Good => calc | valid,
+            RxChecksum::Bad => calc,
+            RxChecksum::Unknown => 0,
+        }
+    }
+
+    /// The L3 (IP header) checksum status encoded in `flags`.
+    pub fn l3(flags: u32) -> RxChecksum {
+        RxChecksum::from_flags(flags, CSUM_L3_CALC, CSUM_L3_VALID)
+    }
+
+    /// The L4 (TCP/UDP/SCTP) checksum status encoded in `flags`.
+    pub fn l4(flags: u32) -> RxChecksum {
+        RxChecksum::from_flags(flags, CSUM_L4_CALC, CSUM_L4_VALID)
+    }
+
+    /// Replaces the L3 and L4 status in `flags`.
+    pub fn set(flags: u32, l3: RxChecksum, l4: RxChecksum) -> u32 {
+        (flags & !(CSUM_L3_CALC | CSUM_L3_VALID | CSUM_L4_CALC | CSUM_L4_VALID))
+            | l3.to_flags(CSUM_L3_CALC, CSUM_L3_VALID)
+            | l4.to_flags(CSUM_L4_CALC, CSUM_L4_VALID)
+    }
+}
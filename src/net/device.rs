@@ -0,0 +1,89 @@
+//! A NIC-independent interface to network devices.
+
+use bitflags::bitflags;
+
+use crate::devq::{DevQueue, QueuePair};
+
+use super::{MacAddress, Mtu};
+
+/// State of the physical link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkState {
+    Down,
+    Up {
+        /// Link speed in Mbit/s.
+        speed: u32,
+        full_duplex: bool,
+    },
+}
+
+impl LinkState {
+    pub fn is_up(&self) -> bool {
+        matches!(self, LinkState::Up { .. })
+    }
+}
+
+/// Device-wide traffic counters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Frames dropped because no RX buffer was available.
+    pub rx_dropped: u64,
+    /// Malformed frames (CRC, length errors etc.).
+    pub rx_errors: u64,
+    pub rx_multicast: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_dropped: u64,
+    pub tx_errors: u64,
+}
+
+bitflags! {
+    /// Offloads a device supports.
+    pub struct Offloads: u32 {
+        /// Validates L3/L4 checksums of received packets.
+        const RX_CSUM = 1 << 0;
+        /// Computes L4 checksums of transmitted packets.
+        const TX_CSUM = 1 << 1;
+        const TSO4 = 1 << 2;
+        const TSO6 = 1 << 3;
+        const UFO = 1 << 4;
+        /// Coalesces received TCP segments.
+        const LRO = 1 << 5;
+        /// Receive side scaling.
+        const RSS = 1 << 6;
+        const VLAN_STRIP = 1 << 7;
+        const VLAN_INSERT = 1 << 8;
+        const VLAN_FILTER = 1 << 9;
+        /// See [`HwTimestamp`](super::HwTimestamp).
+        const HW_TIMESTAMP = 1 << 10;
+        /// See [`FlowSteering`](super::FlowSteering).
+        const FLOW_STEERING = 1 << 11;
+    }
+}
+
+/// A network device, independent of the NIC model.
+///
+/// Optional functionality (filters, timestamping, flow steering etc.) is
+/// provided through the other traits of [`net`](super), [`Offloads`] tells
+/// which of them are backed by hardware.
+pub trait NetworkDevice: Mtu {
+    type TxQueue: DevQueue;
+    type RxQueue: DevQueue;
+
+    fn mac_address(&self) -> MacAddress;
+
+    fn link_state(&self) -> LinkState;
+
+    fn stats(&self) -> NetStats;
+
+    fn offloads(&self) -> Offloads;
+
+    /// Number of TX/RX queue pairs that are set up.
+    fn num_queue_pairs(&self) -> usize;
+
+    fn queue_pair(&mut self, index: usize) -> Option<&mut QueuePair<Self::TxQueue, Self::RxQueue>>;
+}
@@ -0,0 +1,608 @@
+//! Exact-match flow steering (e.g., Flow Director on ixgbe, ntuple filters
+//! in ethtool speak).
+//!
+//! [`IxgbeFlowDirector`] programs the perfect-match filters of ixgbe NICs.
+
+use alloc::vec::Vec;
+use core::net::{IpAddr, Ipv4Addr};
+use core::time::Duration;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use crate::arch::VAddr;
+use crate::poll::poll_until;
+
+/// L4 protocol of a flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlowProtocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+/// The 5-tuple a flow is matched on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowRule {
+    pub protocol: FlowProtocol,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+/// What to do with packets matching a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlowAction {
+    /// Deliver to RX queue `n`.
+    Queue(u16),
+    Drop,
+}
+
+/// Handle of an installed rule.
+pub type FlowId = u32;
+
+/// Programming of exact-match flow steering rules.
+pub trait FlowSteering {
+    type Error;
+
+    /// Maximum number of rules the hardware can hold.
+    fn max_rules(&self) -> usize;
+
+    fn add_rule(&mut self, rule: FlowRule, action: FlowAction) -> Result<FlowId, Self::Error>;
+
+    fn remove_rule(&mut self, id: FlowId) -> Result<(), Self::Error>;
+
+    /// All installed rules.
+    fn rules(&self) -> Vec<(FlowId, FlowRule, FlowAction)>;
+}
+
+/// Book-keeping of installed rules for [`FlowSteering`] implementations
+/// (the hardware tables usually can't be read back).
+#[derive(Debug)]
+pub struct FlowTable {
+    capacity: usize,
+    next_id: FlowId,
+    rules: Vec<(FlowId, FlowRule, FlowAction)>,
+}
+
+impl FlowTable {
+    pub fn new(capacity: usize) -> Self {
+        FlowTable {
+            capacity,
+            next_id: 0,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Records `rule`, returning its id.
+    ///
+    /// Returns None if the table is full or the same 5-tuple is already
+    /// installed.
+    pub fn insert(&mut self, rule: FlowRule, action: FlowAction) -> Option<FlowId> {
+        if self.rules.len() >= self.capacity || self.rules.iter().any(|(_, r, _)| *r == rule) {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.rules.push((id, rule, action));
+        Some(id)
+    }
+
+    pub fn remove(&mut self, id: FlowId) -> Option<(FlowRule, FlowAction)> {
+        let index = self.rules.iter().position(|(i, _, _)| *i == id)?;
+        let (_, rule, action) = self.rules.remove(index);
+        Some((rule, action))
+    }
+
+    pub fn get(&self, id: FlowId) -> Option<(FlowRule, FlowAction)> {
+        self.rules
+            .iter()
+            .find(|(i, _, _)| *i == id)
+            .map(|(_, rule, action)| (*rule, *action))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(FlowId, FlowRule, FlowAction)> {
+        self.rules.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// Flow Director control register.
+const FDIRCTRL: usize = 0x0ee00;
+/// Source IPv4 address of a filter.
+const FDIRIPSA: usize = 0x0ee18;
+/// Destination IPv4 address of a filter.
+const FDIRIPDA: usize = 0x0ee1c;
+/// L4 ports of a filter (source in the low, destination in the high half).
+const FDIRPORT: usize = 0x0ee20;
+/// VLAN tag and flexible bytes of a filter.
+const FDIRVLAN: usize = 0x0ee24;
+/// Bucket hash and software index of a filter.
+const FDIRHASH: usize = 0x0ee28;
+/// Command register, executes a filter operation on the registers above.
+const FDIRCMD: usize = 0x0ee2c;
+/// Hash keys.
+const FDIRHKEY: usize = 0x0ee68;
+const FDIRSKEY: usize = 0x0ee6c;
+/// Masks of the fields compared (a set bit ignores the field or bit).
+const FDIRM: usize = 0x0ee70;
+const FDIRTCPM: usize = 0x0ee78;
+const FDIRUDPM: usize = 0x0ee80;
+const FDIRSIP4M: usize = 0x0ee40;
+const FDIRDIP4M: usize = 0x0ee44;
+
+/// FDIRCTRL: 64 KiB of the RX packet buffer hold the filters.
+const FDIRCTRL_PBALLOC_64K: u32 = 1;
+/// FDIRCTRL: the hardware finished initializing the filter table.
+const FDIRCTRL_INIT_DONE: usize = 3;
+const FDIRCTRL_PERFECT_MATCH: usize = 4;
+/// FDIRCTRL: report the filter match in the RX descriptors.
+const FDIRCTRL_REPORT_STATUS: usize = 5;
+const FDIRCTRL_DROP_QUEUE: core::ops::Range<usize> = 8..15;
+const FDIRCTRL_MAX_LENGTH: core::ops::Range<usize> = 24..28;
+const FDIRCTRL_FULL_THRESH: core::ops::Range<usize> = 28..32;
+
+/// FDIRM: ignore the VLAN ID and priority, the VM pool, the flexible
+/// bytes and the IPv6 destination.
+const FDIRM_5TUPLE: u32 = 0x37;
+
+const FDIRHASH_VALID: usize = 15;
+const FDIRHASH_SW_INDEX: core::ops::Range<usize> = 16..32;
+
+const FDIRCMD_CMD: core::ops::Range<usize> = 0..2;
+const FDIRCMD_CMD_ADD_FLOW: u32 = 1;
+const FDIRCMD_CMD_REMOVE_FLOW: u32 = 2;
+const FDIRCMD_CMD_QUERY_REM_FILT: u32 = 3;
+/// FDIRCMD: the queried filter exists.
+const FDIRCMD_FILTER_VALID: usize = 2;
+/// FDIRCMD: replace an existing filter with the same hash and index.
+const FDIRCMD_FILTER_UPDATE: usize = 3;
+const FDIRCMD_FLOW_TYPE: core::ops::Range<usize> = 5..8;
+const FDIRCMD_DROP: usize = 9;
+const FDIRCMD_LAST: usize = 11;
+const FDIRCMD_QUEUE_EN: usize = 15;
+const FDIRCMD_RX_QUEUE: core::ops::Range<usize> = 16..23;
+
+/// Key of the bucket hash (the same as Linux and DPDK use).
+const BUCKET_HASH_KEY: u32 = 0x3dad_14e2;
+/// Key of the signature hash (only used by signature filters).
+const SIGNATURE_HASH_KEY: u32 = 0x174d_3614;
+
+/// RX queue that dropped packets are steered to.
+const DROP_QUEUE: u16 = 127;
+/// Perfect filters that fit into 64 KiB of the packet buffer.
+const MAX_PERFECT_FILTERS: usize = 2046;
+
+/// How long initialization or a filter command may take.
+const FDIR_TIMEOUT: Duration = Duration::from_millis(20);
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub FlowDirectorError
+    Unsupported = "Flow Director only matches IPv4 rules",
+    InvalidQueue{queue: u16} = "can't steer packets to queue {queue}",
+    Rejected = "the rule is already installed or the filter table is full",
+    NotFound = "no rule with this id is installed",
+    Timeout = "timed out waiting for Flow Director",
+}
+
+/// The flow type of a rule, FDIRCMD.FLOW_TYPE (IPv4 only).
+fn flow_type(protocol: FlowProtocol) -> u32 {
+    match protocol {
+        FlowProtocol::Udp => 1,
+        FlowProtocol::Tcp => 2,
+        FlowProtocol::Sctp => 3,
+    }
+}
+
+fn ipv4_addresses(rule: &FlowRule) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    match (rule.src_ip, rule.dst_ip) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => Some((src, dst)),
+        _ => None,
+    }
+}
+
+/// The 13-bit bucket hash of an IPv4 `rule` (FDIRHASH.BUCKET_HASH), as
+/// computed by the hardware with [`BUCKET_HASH_KEY`].
+fn bucket_hash(rule: &FlowRule, src: Ipv4Addr, dst: Ipv4Addr) -> u16 {
+    // The hash runs over the filter in network byte order: flow type (and
+    // VM pool/VLAN, which we mask), the destination and source addresses,
+    // the ports and the flexible bytes
+    let flow_vm_vlan = flow_type(rule.protocol) << 16;
+    let mut hi =
+        u32::from(dst) ^ u32::from(src) ^ ((rule.src_port as u32) << 16 | rule.dst_port as u32);
+    let mut lo = hi.rotate_left(16);
+    hi ^= flow_vm_vlan ^ (flow_vm_vlan >> 16);
+
+    let mut hash = 0u32;
+    let mut iteration = |n: usize, lo: u32, hi: u32| {
+        if BUCKET_HASH_KEY.get_bit(n) {
+            hash ^= lo >> n;
+        }
+        if BUCKET_HASH_KEY.get_bit(n + 16) {
+            hash ^= hi >> n;
+        }
+    };
+    // Bit 0 is processed before the flow type is applied to the low word
+    iteration(0, lo, hi);
+    lo ^= flow_vm_vlan ^ (flow_vm_vlan << 16);
+    for n in 1..16 {
+        iteration(n, lo, hi);
+    }
+    hash.get_bits(0..13) as u16
+}
+
+/// Perfect-match Flow Director filters of ixgbe (82599 and later) NICs.
+///
+/// Rules match IPv4 TCP, UDP and SCTP 5-tuples, the 82599 can't compare
+/// IPv6 destinations. The RX packet buffer has to leave 64 KiB to the
+/// filters (RXPBSIZE[0] reduced accordingly) before [`IxgbeFlowDirector::init`]
+/// is called, which is up to the driver's packet buffer setup.
+#[derive(Debug)]
+pub struct IxgbeFlowDirector {
+    regs: VAddr,
+    table: FlowTable,
+    /// Software index (FDIRHASH.SW_INDEX) and bucket hash of every rule,
+    /// which identify it in the hardware.
+    filters: Vec<(FlowId, u16, u16)>,
+}
+
+impl IxgbeFlowDirector {
+    /// # Safety
+    /// `regs` must be the mapped register BAR (BAR0) of an ixgbe NIC.
+    pub unsafe fn new(regs: VAddr) -> IxgbeFlowDirector {
+        IxgbeFlowDirector {
+            regs,
+            table: FlowTable::new(MAX_PERFECT_FILTERS),
+            filters: Vec::new(),
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u32>()) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u32>(), value) };
+    }
+
+    /// Enables perfect-match filtering on the 5-tuple and clears the rules.
+    ///
+    /// Has to be done while RX is disabled.
+    pub fn init(&mut self) -> Result<(), FlowDirectorError> {
+        self.write(FDIRM, FDIRM_5TUPLE);
+        self.write(FDIRTCPM, 0);
+        self.write(FDIRUDPM, 0);
+        self.write(FDIRSIP4M, 0);
+        self.write(FDIRDIP4M, 0);
+        self.write(FDIRHKEY, BUCKET_HASH_KEY);
+        self.write(FDIRSKEY, SIGNATURE_HASH_KEY);
+
+        let mut fdirctrl = FDIRCTRL_PBALLOC_64K;
+        fdirctrl.set_bit(FDIRCTRL_PERFECT_MATCH, true);
+        fdirctrl.set_bit(FDIRCTRL_REPORT_STATUS, true);
+        fdirctrl.set_bits(FDIRCTRL_DROP_QUEUE, DROP_QUEUE as u32);
+        fdirctrl.set_bits(FDIRCTRL_MAX_LENGTH, 0xa);
+        fdirctrl.set_bits(FDIRCTRL_FULL_THRESH, 4);
+        self.write(FDIRCTRL, fdirctrl);
+        if !poll_until(
+            || self.read(FDIRCTRL).get_bit(FDIRCTRL_INIT_DONE),
+            FDIR_TIMEOUT,
+        ) {
+            warn!("Flow Director initialization timed out");
+            return Err(FlowDirectorError::Timeout);
+        }
+        self.table = FlowTable::new(MAX_PERFECT_FILTERS);
+        self.filters.clear();
+        Ok(())
+    }
+
+    /// Executes `cmd` on the filter selected by FDIRHASH and returns FDIRCMD
+    /// once the hardware completed it.
+    fn command(&mut self, cmd: u32) -> Result<u32, FlowDirectorError> {
+        self.write(FDIRCMD, cmd);
+        if !poll_until(
+            || self.read(FDIRCMD).get_bits(FDIRCMD_CMD) == 0,
+            FDIR_TIMEOUT,
+        ) {
+            warn!("Flow Director command {:#x} timed out", cmd);
+            return Err(FlowDirectorError::Timeout);
+        }
+        Ok(self.read(FDIRCMD))
+    }
+
+    fn fdirhash(bucket: u16, soft_id: u16) -> u32 {
+        let mut fdirhash = bucket as u32;
+        fdirhash.set_bit(FDIRHASH_VALID, true);
+        fdirhash.set_bits(FDIRHASH_SW_INDEX, soft_id as u32);
+        fdirhash
+    }
+
+    /// The lowest software index no installed rule uses.
+    fn free_soft_id(&self) -> u16 {
+        (0..=u16::MAX)
+            .find(|id| self.filters.iter().all(|(_, soft_id, _)| soft_id != id))
+            .expect("there are fewer rules than indices")
+    }
+}
+
+impl FlowSteering for IxgbeFlowDirector {
+    type Error = FlowDirectorError;
+
+    fn max_rules(&self) -> usize {
+        self.table.capacity()
+    }
+
+    fn add_rule(&mut self, rule: FlowRule, action: FlowAction) -> Result<FlowId, Self::Error> {
+        let (src, dst) = ipv4_addresses(&rule).ok_or(FlowDirectorError::Unsupported)?;
+        let queue = match action {
+            FlowAction::Queue(queue) if queue >= DROP_QUEUE => {
+                return Err(FlowDirectorError::InvalidQueue { queue })
+            }
+            FlowAction::Queue(queue) => queue,
+            FlowAction::Drop => DROP_QUEUE,
+        };
+        let id = self
+            .table
+            .insert(rule, action)
+            .ok_or(FlowDirectorError::Rejected)?;
+        let bucket = bucket_hash(&rule, src, dst);
+        let soft_id = self.free_soft_id();
+
+        self.write(FDIRIPSA, u32::from(src));
+        self.write(FDIRIPDA, u32::from(dst));
+        self.write(
+            FDIRPORT,
+            (rule.dst_port as u32) << 16 | rule.src_port as u32,
+        );
+        self.write(FDIRVLAN, 0);
+        self.write(FDIRHASH, Self::fdirhash(bucket, soft_id));
+
+        let mut cmd = FDIRCMD_CMD_ADD_FLOW;
+        cmd.set_bit(FDIRCMD_FILTER_UPDATE, true);
+        cmd.set_bit(FDIRCMD_LAST, true);
+        cmd.set_bit(FDIRCMD_QUEUE_EN, true);
+        cmd.set_bit(FDIRCMD_DROP, action == FlowAction::Drop);
+        cmd.set_bits(FDIRCMD_FLOW_TYPE, flow_type(rule.protocol));
+        cmd.set_bits(FDIRCMD_RX_QUEUE, queue as u32);
+        if let Err(e) = self.command(cmd) {
+            self.table.remove(id);
+            return Err(e);
+        }
+        self.filters.push((id, soft_id, bucket));
+        Ok(id)
+    }
+
+    fn remove_rule(&mut self, id: FlowId) -> Result<(), Self::Error> {
+        let index = self
+            .filters
+            .iter()
+            .position(|(i, _, _)| *i == id)
+            .ok_or(FlowDirectorError::NotFound)?;
+        let (_, soft_id, bucket) = self.filters[index];
+        let fdirhash = Self::fdirhash(bucket, soft_id);
+
+        self.write(FDIRHASH, fdirhash);
+        let fdircmd = self.command(FDIRCMD_CMD_QUERY_REM_FILT)?;
+        if fdircmd.get_bit(FDIRCMD_FILTER_VALID) {
+            self.write(FDIRHASH, fdirhash);
+            self.command(FDIRCMD_CMD_REMOVE_FLOW)?;
+        }
+        self.filters.remove(index);
+        self.table.remove(id);
+        Ok(())
+    }
+
+    fn rules(&self) -> Vec<(FlowId, FlowRule, FlowAction)> {
+        self.table.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::net::Ipv6Addr;
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Mutex;
+    use std::thread::JoinHandle;
+
+    /// The registers of a NIC in memory, with a thread executing the Flow
+    /// Director commands. Installed filters are recorded as their FDIRHASH,
+    /// FDIRIPSA, FDIRIPDA, FDIRPORT and FDIRCMD.
+    struct MockFdir {
+        regs: Arc<Vec<AtomicU32>>,
+        filters: Arc<Mutex<Vec<[u32; 5]>>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl MockFdir {
+        fn start() -> MockFdir {
+            let regs: Arc<Vec<AtomicU32>> =
+                Arc::new((0..=FDIRUDPM / 4).map(|_| AtomicU32::new(0)).collect());
+            let filters = Arc::new(Mutex::new(Vec::new()));
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let (regs, filters, stop) = (regs.clone(), filters.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    let reg = |offset: usize| regs[offset / 4].load(Ordering::Acquire);
+                    while !stop.load(Ordering::Acquire) {
+                        let ctrl = reg(FDIRCTRL);
+                        if ctrl.get_bit(FDIRCTRL_PERFECT_MATCH) && !ctrl.get_bit(FDIRCTRL_INIT_DONE)
+                        {
+                            regs[FDIRCTRL / 4]
+                                .store(ctrl | 1 << FDIRCTRL_INIT_DONE, Ordering::Release);
+                        }
+
+                        let mut cmd = reg(FDIRCMD);
+                        let hash = reg(FDIRHASH);
+                        let mut filters = filters.lock().unwrap();
+                        let existing = filters.iter().position(|f: &[u32; 5]| f[0] == hash);
+                        match cmd.get_bits(FDIRCMD_CMD) {
+                            0 => {
+                                drop(filters);
+                                std::thread::yield_now();
+                                continue;
+                            }
+                            FDIRCMD_CMD_ADD_FLOW => {
+                                if let Some(index) = existing {
+                                    filters.remove(index);
+                                }
+                                filters.push([
+                                    hash,
+                                    reg(FDIRIPSA),
+                                    reg(FDIRIPDA),
+                                    reg(FDIRPORT),
+                                    cmd,
+                                ]);
+                            }
+                            FDIRCMD_CMD_REMOVE_FLOW => {
+                                if let Some(index) = existing {
+                                    filters.remove(index);
+                                }
+                            }
+                            _ => {
+                                cmd.set_bit(FDIRCMD_FILTER_VALID, existing.is_some());
+                            }
+                        }
+                        cmd.set_bits(FDIRCMD_CMD, 0);
+                        regs[FDIRCMD / 4].store(cmd, Ordering::Release);
+                    }
+                })
+            };
+            MockFdir {
+                regs,
+                filters,
+                stop,
+                thread: Some(thread),
+            }
+        }
+
+        fn fdir(&self) -> IxgbeFlowDirector {
+            // Safety: the tests drop the Flow Director before the registers
+            unsafe { IxgbeFlowDirector::new(VAddr::from(self.regs.as_ptr() as u64)) }
+        }
+
+        fn reg(&self, offset: usize) -> u32 {
+            self.regs[offset / 4].load(Ordering::Acquire)
+        }
+
+        fn filters(&self) -> Vec<[u32; 5]> {
+            self.filters.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for MockFdir {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Release);
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    fn rule(protocol: FlowProtocol) -> FlowRule {
+        FlowRule {
+            protocol,
+            src_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)),
+            dst_ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: 1234,
+            dst_port: 80,
+        }
+    }
+
+    #[test]
+    fn init() {
+        let mock = MockFdir::start();
+        let mut fdir = mock.fdir();
+        fdir.init().unwrap();
+
+        let ctrl = mock.reg(FDIRCTRL);
+        assert!(ctrl.get_bit(FDIRCTRL_PERFECT_MATCH));
+        assert_eq!(ctrl.get_bits(0..2), FDIRCTRL_PBALLOC_64K);
+        assert_eq!(ctrl.get_bits(FDIRCTRL_DROP_QUEUE), DROP_QUEUE as u32);
+        assert_eq!(mock.reg(FDIRM), FDIRM_5TUPLE);
+        assert_eq!(mock.reg(FDIRHKEY), BUCKET_HASH_KEY);
+        assert_eq!(fdir.max_rules(), MAX_PERFECT_FILTERS);
+    }
+
+    #[test]
+    fn add_and_remove_rules() {
+        let mock = MockFdir::start();
+        let mut fdir = mock.fdir();
+        fdir.init().unwrap();
+
+        let tcp = fdir
+            .add_rule(rule(FlowProtocol::Tcp), FlowAction::Queue(3))
+            .unwrap();
+        let [hash, ipsa, ipda, port, cmd] = mock.filters()[0];
+        assert_eq!(hash, 0x809a);
+        assert_eq!(
+            (ipsa, ipda, port),
+            (0xc0a8_010a, 0x0a00_0002, 80 << 16 | 1234)
+        );
+        assert_eq!(cmd.get_bits(FDIRCMD_FLOW_TYPE), 2);
+        assert_eq!(cmd.get_bits(FDIRCMD_RX_QUEUE), 3);
+        assert!(cmd.get_bit(FDIRCMD_QUEUE_EN) && !cmd.get_bit(FDIRCMD_DROP));
+
+        assert!(matches!(
+            fdir.add_rule(rule(FlowProtocol::Tcp), FlowAction::Queue(4)),
+            Err(FlowDirectorError::Rejected)
+        ));
+        assert!(matches!(
+            fdir.add_rule(rule(FlowProtocol::Sctp), FlowAction::Queue(DROP_QUEUE)),
+            Err(FlowDirectorError::InvalidQueue { queue: DROP_QUEUE })
+        ));
+        let v6 = FlowRule {
+            dst_ip: IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ..rule(FlowProtocol::Tcp)
+        };
+        assert!(matches!(
+            fdir.add_rule(v6, FlowAction::Drop),
+            Err(FlowDirectorError::Unsupported)
+        ));
+
+        // The second rule gets the next software index
+        let udp = fdir
+            .add_rule(rule(FlowProtocol::Udp), FlowAction::Drop)
+            .unwrap();
+        let [hash, _, _, _, cmd] = mock.filters()[1];
+        assert_eq!(hash, 1 << 16 | 0x8fe1);
+        assert_eq!(cmd.get_bits(FDIRCMD_FLOW_TYPE), 1);
+        assert_eq!(cmd.get_bits(FDIRCMD_RX_QUEUE), DROP_QUEUE as u32);
+        assert!(cmd.get_bit(FDIRCMD_DROP));
+        assert_eq!(fdir.rules().len(), 2);
+
+        fdir.remove_rule(tcp).unwrap();
+        assert_eq!(mock.filters().len(), 1);
+        assert_eq!(mock.filters()[0][0], 1 << 16 | 0x8fe1);
+        assert!(matches!(
+            fdir.remove_rule(tcp),
+            Err(FlowDirectorError::NotFound)
+        ));
+        assert_eq!(
+            fdir.rules(),
+            [(udp, rule(FlowProtocol::Udp), FlowAction::Drop)]
+        );
+
+        // Its index is free again
+        fdir.add_rule(rule(FlowProtocol::Sctp), FlowAction::Queue(1))
+            .unwrap();
+        assert_eq!(mock.filters()[1][0].get_bits(FDIRHASH_SW_INDEX), 0);
+    }
+}
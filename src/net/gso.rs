@@ -0,0 +1,41 @@
+//! Generic segmentation offload (GSO).
+//!
+//! A stack hands a super-packet (up to 64 KiB) with GSO metadata to the
+//! driver, which lets the device (TSO/UFO) split it into MSS sized segments.
+
+/// The kind of segmentation to perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GsoType {
+    /// TCP over IPv4
+    TcpV4,
+    /// TCP over IPv6
+    TcpV6,
+    /// UDP fragmentation
+    Udp,
+}
+
+/// Segmentation metadata of a TX packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Gso {
+    pub gso_type: GsoType,
+    /// Offset of the L4 (TCP/UDP) header from the start of the frame.
+    pub l4_offset: u16,
+    /// Length of all headers (L2 to L4) that are replicated in every segment.
+    pub hdr_len: u16,
+    /// Maximum payload size of a segment.
+    pub mss: u16,
+    /// Whether the TCP ECN (CWR) bit must be set on the first segment only.
+    pub ecn: bool,
+}
+
+impl Gso {
+    /// Offset of the checksum field within the L4 header.
+    pub fn csum_offset(&self) -> u16 {
+        match self.gso_type {
+            GsoType::TcpV4 | GsoType::TcpV6 => 16,
+            GsoType::Udp => 6,
+        }
+    }
+}
@@ -0,0 +1,1215 @@
+//! The admin queue of Intel 700-series NICs (i40e: X710, XL710, XXV710).
+//!
+//! Unlike older Intel NICs most of the configuration of an i40e is done by
+//! its firmware, which the driver talks to through the admin queue (ATQ):
+//! a ring of 32-byte command descriptors in DMA memory, optionally pointing
+//! to an indirect buffer. This implements the PF side of the ATQ (on top of
+//! [`AdminQueue`]) and the commands needed to identify the device
+//! (firmware/API version, MAC addresses).
+//!
+//! The LAN queues are described to the device by contexts in its Host
+//! Memory Cache (HMC), which [`I40eLanQueues`] backs with host memory. It
+//! programs the TX/RX queue contexts for the driver's descriptor rings and
+//! enables the queues; filling the rings (and setting up the VSI the queues
+//! belong to through the admin queue) is up to the driver.
+
+use alloc::alloc::Layout;
+use core::time::Duration;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use crate::adminq::{AdminQueue, AdminQueueError, AdminQueueHw, DmaRing};
+use crate::arch::VAddr;
+use crate::endian::{Le16, Le32};
+use crate::iomem::{DmaObject, IOBuf, IOMemError};
+use crate::poll::poll_until;
+
+use super::MacAddress;
+
+/// PF admin transmit queue registers.
+const PF_ATQBAL: usize = 0x0008_0000;
+const PF_ATQBAH: usize = 0x0008_0100;
+const PF_ATQLEN: usize = 0x0008_0200;
+const PF_ATQH: usize = 0x0008_0300;
+const PF_ATQT: usize = 0x0008_0400;
+
+/// ATQLEN: the queue is enabled.
+const ATQLEN_ENABLE: u32 = 1 << 31;
+/// ATQLEN: mask of the length field.
+const ATQLEN_MASK: u32 = 0x3ff;
+
+/// Size of the indirect buffers.
+pub const AQ_BUF_LEN: usize = 4096;
+/// Indirect buffers larger than this need [`AQ_FLAG_LB`].
+const AQ_LARGE_BUF: usize = 512;
+
+/// Descriptor flags.
+pub const AQ_FLAG_DD: u16 = 1 << 0;
+pub const AQ_FLAG_CMP: u16 = 1 << 1;
+pub const AQ_FLAG_ERR: u16 = 1 << 2;
+pub const AQ_FLAG_LB: u16 = 1 << 9;
+pub const AQ_FLAG_RD: u16 = 1 << 10;
+pub const AQ_FLAG_BUF: u16 = 1 << 12;
+pub const AQ_FLAG_SI: u16 = 1 << 13;
+
+/// Opcodes.
+pub const AQC_GET_VERSION: u16 = 0x0001;
+pub const AQC_DRIVER_VERSION: u16 = 0x0002;
+pub const AQC_QUEUE_SHUTDOWN: u16 = 0x0003;
+pub const AQC_MAC_ADDRESS_READ: u16 = 0x0107;
+
+/// `AQC_MAC_ADDRESS_READ`: the LAN address is valid.
+const MAC_ADDR_LAN_VALID: u16 = 0x10;
+/// `AQC_MAC_ADDRESS_READ`: the port address is valid.
+const MAC_ADDR_PORT_VALID: u16 = 0x40;
+
+/// PCI requester ID of the PF, the PF number in the low 3 bits (without ARI).
+const PF_FUNC_RID: usize = 0x0009_c000;
+const PF_FUNC_RID_FUNCTION: core::ops::Range<usize> = 0..3;
+/// The LAN queues (absolute indices) the PF owns.
+const PFLAN_QALLOC: usize = 0x001c_0400;
+const PFLAN_QALLOC_FIRSTQ: core::ops::Range<usize> = 0..11;
+const PFLAN_QALLOC_LASTQ: core::ops::Range<usize> = 16..27;
+const PFLAN_QALLOC_VALID: usize = 31;
+
+/// HMC segment descriptor (SD) registers of the PF.
+const PFHMC_SDCMD: usize = 0x000c_0000;
+const PFHMC_SDDATALOW: usize = 0x000c_0100;
+const PFHMC_SDDATAHIGH: usize = 0x000c_0200;
+/// SDCMD: write the data registers to the SD (index in the low bits).
+const PFHMC_SDCMD_PMSDWR: u32 = 1 << 31;
+/// SDDATALOW: the SD is valid.
+const PFHMC_SDDATALOW_PMSDVALID: u32 = 1 << 0;
+/// SDDATALOW: the SD points to a backing page (direct) instead of a page
+/// descriptor table.
+const PFHMC_SDDATALOW_PMSDTYPE: u32 = 1 << 1;
+/// SDDATALOW: number of 4 KiB pages the SD covers.
+const PFHMC_SDDATALOW_PMSDBPCOUNT_SHIFT: u32 = 2;
+/// Size of the LAN TX/RX context objects (log2 of the bytes).
+const GLHMC_LANTXOBJSZ: usize = 0x000c_2004;
+const GLHMC_LANRXOBJSZ: usize = 0x000c_200c;
+const GLHMC_OBJSZ: core::ops::Range<usize> = 0..4;
+/// Number of LAN queues the HMC supports.
+const GLHMC_LANQMAX: usize = 0x000c_2008;
+const GLHMC_LANQMAX_PMLANQMAX: core::ops::Range<usize> = 0..11;
+
+/// Base (in units of [`HMC_OBJ_BASE_ALIGN`]) and number of the LAN TX/RX
+/// contexts of PF `pf` in its HMC space.
+const fn glhmc_lantxbase(pf: u8) -> usize {
+    0x000c_6200 + 4 * pf as usize
+}
+const fn glhmc_lantxcnt(pf: u8) -> usize {
+    0x000c_6300 + 4 * pf as usize
+}
+const fn glhmc_lanrxbase(pf: u8) -> usize {
+    0x000c_6400 + 4 * pf as usize
+}
+const fn glhmc_lanrxcnt(pf: u8) -> usize {
+    0x000c_6500 + 4 * pf as usize
+}
+
+/// Size of the backing page of a direct SD.
+const HMC_DIRECT_BP_SIZE: usize = 2 * 1024 * 1024;
+/// Alignment of the backing page.
+const HMC_PAGE_SIZE: usize = 4096;
+/// Alignment of the first object of each type.
+const HMC_OBJ_BASE_ALIGN: usize = 512;
+
+/// LAN queue registers (of queue indices relative to the PF).
+const fn qtx_ena(queue: u16) -> usize {
+    0x0010_0000 + 4 * queue as usize
+}
+const fn qtx_ctl(queue: u16) -> usize {
+    0x0010_4000 + 4 * queue as usize
+}
+const fn qtx_tail(queue: u16) -> usize {
+    0x0010_8000 + 4 * queue as usize
+}
+const fn qtx_head(queue: u16) -> usize {
+    0x000e_4000 + 4 * queue as usize
+}
+const fn qrx_ena(queue: u16) -> usize {
+    0x0012_0000 + 4 * queue as usize
+}
+const fn qrx_tail(queue: u16) -> usize {
+    0x0012_8000 + 4 * queue as usize
+}
+/// TX queue enable/disable pre-configuration, one register per 128 queues
+/// (of absolute queue indices).
+const fn gllan_txpre_qdis(abs_queue: u16) -> usize {
+    0x000e_6500 + 4 * (abs_queue as usize / 128)
+}
+const GLLAN_TXPRE_QDIS_QINDX: core::ops::Range<usize> = 0..11;
+const GLLAN_TXPRE_QDIS_SET_QDIS: usize = 30;
+const GLLAN_TXPRE_QDIS_CLEAR_QDIS: usize = 31;
+/// QTX_CTL: the queue belongs to a PF (`PF_INDX` in bits 2..6).
+const QTX_CTL_PFVF_Q_PF: u32 = 2;
+const QTX_CTL_PF_INDX_SHIFT: u32 = 2;
+/// QTX_ENA/QRX_ENA: request to enable the queue.
+const QENA_REQ: usize = 0;
+/// QTX_ENA/QRX_ENA: the queue is enabled.
+const QENA_STAT: usize = 2;
+/// How long a queue may take to change its enable state.
+const QUEUE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Size of the packed TX and RX queue contexts.
+const TX_CONTEXT_LEN: usize = 128;
+const RX_CONTEXT_LEN: usize = 32;
+/// Alignment of the rings.
+const RING_ALIGN: u64 = 128;
+/// The number of descriptors of a ring is a multiple of this.
+const RING_DESCRIPTORS_MULTIPLE: u16 = 32;
+/// Maximum number of descriptors of a ring.
+pub const MAX_RING_DESCRIPTORS: u16 = 8160;
+/// RX buffer sizes are in units of 128 bytes.
+const RX_BUF_UNIT: u16 = 128;
+/// Largest RX buffer.
+pub const MAX_RX_BUF_LEN: u16 = 16 * 1024 - RX_BUF_UNIT;
+/// Largest frame a queue can receive.
+pub const MAX_RX_FRAME: u16 = 9728;
+
+/// Fields (lsb, width) of the TX queue context.
+const TXCTX_HEAD: (usize, usize) = (0, 13);
+const TXCTX_NEW_CONTEXT: (usize, usize) = (30, 1);
+const TXCTX_BASE: (usize, usize) = (32, 57);
+const TXCTX_QLEN: (usize, usize) = (161, 13);
+const TXCTX_RDYLIST: (usize, usize) = (7 * 128 + 84, 10);
+/// Fields (lsb, width) of the RX queue context.
+const RXCTX_HEAD: (usize, usize) = (0, 13);
+const RXCTX_BASE: (usize, usize) = (32, 57);
+const RXCTX_QLEN: (usize, usize) = (89, 13);
+const RXCTX_DBUFF: (usize, usize) = (102, 7);
+const RXCTX_DSIZE: (usize, usize) = (116, 1);
+const RXCTX_CRCSTRIP: (usize, usize) = (117, 1);
+const RXCTX_L2TSEL: (usize, usize) = (119, 1);
+const RXCTX_RXMAX: (usize, usize) = (174, 14);
+const RXCTX_LRXQTHRESH: (usize, usize) = (198, 3);
+const RXCTX_PREFENA: (usize, usize) = (201, 1);
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub I40eError
+    Timeout = "timed out waiting for the firmware",
+    Firmware{opcode: u16, retval: u16} = "command {opcode} failed with {retval}",
+    NotEnabled = "the admin queue couldn't be enabled",
+    OutOfMemory = "couldn't allocate DMA memory",
+    InvalidArgument = "invalid argument",
+    NoMacAddress = "the firmware doesn't report a MAC address",
+    InvalidQueue{queue: u16} = "the PF has no LAN queue {queue}",
+    InvalidRing = "rings must be 128-byte aligned and have a multiple of 32 descriptors (up to 8160)",
+    InvalidBufferSize{len: u16, max_frame: u16} = "RX buffers of {len} bytes for frames up to {max_frame} bytes aren't supported",
+    QueueTimeout{queue: u16} = "queue {queue} didn't change its enable state",
+}
+
+impl From<IOMemError> for I40eError {
+    fn from(_e: IOMemError) -> Self {
+        I40eError::OutOfMemory
+    }
+}
+
+impl From<AdminQueueError> for I40eError {
+    fn from(e: AdminQueueError) -> Self {
+        match e {
+            AdminQueueError::Timeout => I40eError::Timeout,
+            AdminQueueError::NotEnabled => I40eError::NotEnabled,
+            AdminQueueError::OutOfMemory => I40eError::OutOfMemory,
+        }
+    }
+}
+
+/// An admin queue descriptor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct AqDesc {
+    pub flags: Le16,
+    pub opcode: Le16,
+    pub datalen: Le16,
+    pub retval: Le16,
+    pub cookie_high: Le32,
+    pub cookie_low: Le32,
+    pub param0: Le32,
+    pub param1: Le32,
+    pub addr_high: Le32,
+    pub addr_low: Le32,
+}
+
+crate::dma_safe!(AqDesc {
+    flags,
+    opcode,
+    datalen,
+    retval,
+    cookie_high,
+    cookie_low,
+    param0,
+    param1,
+    addr_high,
+    addr_low
+});
+
+impl AqDesc {
+    /// A direct command (without buffer).
+    pub fn new(opcode: u16) -> AqDesc {
+        AqDesc {
+            flags: Le16::new(AQ_FLAG_SI),
+            opcode: Le16::new(opcode),
+            ..Default::default()
+        }
+    }
+
+    /// The 16 bytes of command specific parameters (param0..addr_low).
+    pub fn params(&self) -> [u8; 16] {
+        let mut params = [0u8; 16];
+        params[0..4].copy_from_slice(&self.param0.get().to_le_bytes());
+        params[4..8].copy_from_slice(&self.param1.get().to_le_bytes());
+        params[8..12].copy_from_slice(&self.addr_high.get().to_le_bytes());
+        params[12..16].copy_from_slice(&self.addr_low.get().to_le_bytes());
+        params
+    }
+}
+
+/// Firmware and API versions (`AQC_GET_VERSION`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareVersion {
+    pub rom: u32,
+    pub fw_build: u32,
+    pub fw_major: u16,
+    pub fw_minor: u16,
+    pub api_major: u16,
+    pub api_minor: u16,
+}
+
+/// Registers of the admin transmit queue of an i40e PF.
+pub struct I40eAtq {
+    regs: VAddr,
+    len: usize,
+    next_to_clean: usize,
+}
+
+impl I40eAtq {
+    /// # Safety
+    /// `regs` must be the mapped register BAR (BAR0) of an i40e PF.
+    pub unsafe fn new(regs: VAddr, len: usize) -> I40eAtq {
+        I40eAtq {
+            regs,
+            len,
+            next_to_clean: 0,
+        }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.regs + offset).as_mut_ptr::<u32>()
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u32) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile(self.reg(offset), value) };
+    }
+}
+
+impl AdminQueueHw for I40eAtq {
+    type Command = AqDesc;
+    /// The firmware writes the completion back into the command slot.
+    type Completion = AqDesc;
+
+    fn enable(&mut self, ring: &DmaRing<AqDesc>) -> Result<(), AdminQueueError> {
+        let base = ring.ioaddr().as_u64();
+        self.write_reg(PF_ATQH, 0);
+        self.write_reg(PF_ATQT, 0);
+        self.write_reg(PF_ATQBAL, base as u32);
+        self.write_reg(PF_ATQBAH, (base >> 32) as u32);
+        self.write_reg(PF_ATQLEN, self.len as u32 | ATQLEN_ENABLE);
+
+        if self.read_reg(PF_ATQBAL) != base as u32 {
+            return Err(AdminQueueError::NotEnabled);
+        }
+        Ok(())
+    }
+
+    fn disable(&mut self) {
+        self.write_reg(PF_ATQLEN, 0);
+        self.write_reg(PF_ATQBAL, 0);
+        self.write_reg(PF_ATQBAH, 0);
+    }
+
+    fn set_sequence(command: &mut AqDesc, sequence: u16) {
+        command.cookie_low.set(sequence as u32);
+    }
+
+    fn sequence(completion: &AqDesc) -> u16 {
+        completion.cookie_low.get() as u16
+    }
+
+    fn ring_doorbell(&mut self, tail: usize) {
+        self.write_reg(PF_ATQT, tail as u32);
+    }
+
+    fn poll_completion(&mut self, ring: &DmaRing<AqDesc>) -> Option<AqDesc> {
+        // The firmware advances the head past every command it processed
+        if self.read_reg(PF_ATQH) as usize == self.next_to_clean {
+            return None;
+        }
+        let desc = ring.read(self.next_to_clean);
+        self.next_to_clean = (self.next_to_clean + 1) % self.len;
+        Some(desc)
+    }
+}
+
+/// The admin transmit queue of an i40e PF.
+pub struct I40eAdminQueue {
+    queue: AdminQueue<I40eAtq>,
+    buffer: IOBuf,
+}
+
+impl I40eAdminQueue {
+    /// Allocates a queue with `len` descriptors and enables it.
+    ///
+    /// # Safety
+    /// `regs` must be the mapped register BAR (BAR0) of an i40e PF.
+    pub unsafe fn new(regs: VAddr, len: usize) -> Result<I40eAdminQueue, I40eError> {
+        if !(2..=ATQLEN_MASK as usize).contains(&len) {
+            return Err(I40eError::InvalidArgument);
+        }
+
+        let buffer = IOBuf::new(Layout::from_size_align(AQ_BUF_LEN, 64).expect("valid layout"))?;
+        let queue = AdminQueue::new(I40eAtq::new(regs, len), len)?;
+        Ok(I40eAdminQueue { queue, buffer })
+    }
+
+    /// Executes `desc`, with `data` as indirect buffer (which the firmware
+    /// may overwrite with its response), and returns the completed
+    /// descriptor.
+    pub fn execute(
+        &mut self,
+        mut desc: AqDesc,
+        data: Option<&mut [u8]>,
+    ) -> Result<AqDesc, I40eError> {
+        let data_len = data.as_ref().map_or(0, |d| d.len());
+        if data_len > AQ_BUF_LEN {
+            return Err(I40eError::InvalidArgument);
+        }
+
+        if let Some(data) = data.as_ref() {
+            self.buffer.as_mut_slice()[..data_len].copy_from_slice(data);
+            let addr = self.buffer.ioaddr().as_u64();
+            let mut flags = desc.flags.get() | AQ_FLAG_BUF;
+            if data_len > AQ_LARGE_BUF {
+                flags |= AQ_FLAG_LB;
+            }
+            desc.flags.set(flags);
+            desc.datalen.set(data_len as u16);
+            desc.addr_high.set((addr >> 32) as u32);
+            desc.addr_low.set(addr as u32);
+        }
+
+        let completed = self
+            .queue
+            .execute(desc)
+            .inspect_err(|_e| warn!("i40e: admin command {:#x} timed out", desc.opcode.get()))?;
+        if let Some(data) = data {
+            data.copy_from_slice(&self.buffer.as_slice()[..data_len]);
+        }
+        let flags = completed.flags.get();
+        if flags & AQ_FLAG_DD == 0 {
+            return Err(I40eError::Timeout);
+        }
+        if flags & AQ_FLAG_ERR != 0 || completed.retval.get() != 0 {
+            return Err(I40eError::Firmware {
+                opcode: desc.opcode.get(),
+                retval: completed.retval.get(),
+            });
+        }
+        Ok(completed)
+    }
+
+    pub fn firmware_version(&mut self) -> Result<FirmwareVersion, I40eError> {
+        let desc = self.execute(AqDesc::new(AQC_GET_VERSION), None)?;
+        let params = desc.params();
+        let word = |i: usize| u16::from_le_bytes([params[i], params[i + 1]]);
+        Ok(FirmwareVersion {
+            rom: desc.param0.get(),
+            fw_build: desc.param1.get(),
+            fw_major: word(8),
+            fw_minor: word(10),
+            api_major: word(12),
+            api_minor: word(14),
+        })
+    }
+
+    /// Reads the MAC address of the port (or, if the firmware doesn't have
+    /// one, of the LAN function).
+    pub fn mac_address(&mut self) -> Result<MacAddress, I40eError> {
+        // pf_lan_mac, pf_san_mac, port_mac, pf_wol_mac
+        let mut addresses = [0u8; 24];
+        let desc = self.execute(AqDesc::new(AQC_MAC_ADDRESS_READ), Some(&mut addresses))?;
+
+        let flags = desc.param0.get() as u16;
+        let offset = if flags & MAC_ADDR_PORT_VALID != 0 {
+            12
+        } else if flags & MAC_ADDR_LAN_VALID != 0 {
+            0
+        } else {
+            return Err(I40eError::NoMacAddress);
+        };
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&addresses[offset..offset + 6]);
+        Ok(mac)
+    }
+
+    /// Tells the firmware the driver is unloading.
+    pub fn shutdown(&mut self) -> Result<(), I40eError> {
+        let mut desc = AqDesc::new(AQC_QUEUE_SHUTDOWN);
+        // driver_unloading
+        desc.param0.set(1);
+        self.execute(desc, None).map(|_desc| ())
+    }
+}
+
+/// Stores the low `width` bits of `value` at bit `lsb` of the packed
+/// (little-endian) context `ctx`.
+fn pack(ctx: &mut [u8], (lsb, width): (usize, usize), value: u64) {
+    for bit in 0..width {
+        let pos = lsb + bit;
+        ctx[pos / 8].set_bit(pos % 8, value.get_bit(bit));
+    }
+}
+
+fn ring_is_valid(base: u64, descriptors: u16) -> bool {
+    base.is_multiple_of(RING_ALIGN)
+        && descriptors != 0
+        && descriptors.is_multiple_of(RING_DESCRIPTORS_MULTIPLE)
+        && descriptors <= MAX_RING_DESCRIPTORS
+}
+
+/// The HMC context of a LAN TX queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TxQueueContext {
+    /// IO address of the (16-byte) descriptors, 128-byte aligned.
+    pub base: u64,
+    /// Number of descriptors (a multiple of 32, up to
+    /// [`MAX_RING_DESCRIPTORS`]).
+    pub descriptors: u16,
+    /// Handle of the queue set (of the VSI's first traffic class) that
+    /// schedules the queue, as reported by the firmware for the VSI.
+    pub qs_handle: u16,
+}
+
+impl TxQueueContext {
+    fn pack(&self) -> Result<[u8; TX_CONTEXT_LEN], I40eError> {
+        if !ring_is_valid(self.base, self.descriptors) {
+            return Err(I40eError::InvalidRing);
+        }
+        let mut ctx = [0u8; TX_CONTEXT_LEN];
+        pack(&mut ctx, TXCTX_HEAD, 0);
+        pack(&mut ctx, TXCTX_NEW_CONTEXT, 1);
+        pack(&mut ctx, TXCTX_BASE, self.base / RING_ALIGN);
+        pack(&mut ctx, TXCTX_QLEN, self.descriptors as u64);
+        pack(&mut ctx, TXCTX_RDYLIST, self.qs_handle as u64);
+        Ok(ctx)
+    }
+}
+
+/// The HMC context of a LAN RX queue with 32-byte descriptors and one
+/// buffer per descriptor (no header split).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RxQueueContext {
+    /// IO address of the (32-byte) descriptors, 128-byte aligned.
+    pub base: u64,
+    /// Number of descriptors (a multiple of 32, up to
+    /// [`MAX_RING_DESCRIPTORS`]).
+    pub descriptors: u16,
+    /// Size of the buffers, a multiple of 128 bytes up to
+    /// [`MAX_RX_BUF_LEN`].
+    pub buf_len: u16,
+    /// Largest frame the queue accepts (up to [`MAX_RX_FRAME`]), frames
+    /// larger than `buf_len` span several descriptors.
+    pub max_frame: u16,
+}
+
+impl RxQueueContext {
+    fn pack(&self) -> Result<[u8; RX_CONTEXT_LEN], I40eError> {
+        if !ring_is_valid(self.base, self.descriptors) {
+            return Err(I40eError::InvalidRing);
+        }
+        if self.buf_len == 0
+            || !self.buf_len.is_multiple_of(RX_BUF_UNIT)
+            || self.buf_len > MAX_RX_BUF_LEN
+            || self.max_frame > MAX_RX_FRAME
+        {
+            return Err(I40eError::InvalidBufferSize {
+                len: self.buf_len,
+                max_frame: self.max_frame,
+            });
+        }
+        let mut ctx = [0u8; RX_CONTEXT_LEN];
+        pack(&mut ctx, RXCTX_HEAD, 0);
+        pack(&mut ctx, RXCTX_BASE, self.base / RING_ALIGN);
+        pack(&mut ctx, RXCTX_QLEN, self.descriptors as u64);
+        pack(&mut ctx, RXCTX_DBUFF, (self.buf_len / RX_BUF_UNIT) as u64);
+        // 32-byte descriptors
+        pack(&mut ctx, RXCTX_DSIZE, 1);
+        pack(&mut ctx, RXCTX_CRCSTRIP, 1);
+        // Report the (inner) VLAN tag in L2TAG1
+        pack(&mut ctx, RXCTX_L2TSEL, 1);
+        pack(&mut ctx, RXCTX_RXMAX, self.max_frame as u64);
+        // Fetch descriptors once one is left (in units of 64 descriptors)
+        pack(&mut ctx, RXCTX_LRXQTHRESH, 1);
+        pack(&mut ctx, RXCTX_PREFENA, 1);
+        Ok(ctx)
+    }
+}
+
+/// The LAN queues of an i40e PF.
+///
+/// The device keeps the contexts of its queues in the Host Memory Cache
+/// (HMC), which is backed by host memory the driver hands it through
+/// segment descriptors (SDs). The contexts of all queues of a PF fit into a
+/// single direct SD, so this allocates one 2 MiB backing page and places
+/// the TX contexts, followed by the RX contexts, in it. Writing a context
+/// to the backing page and enabling the queue makes the device load it.
+///
+/// Dropping the struct removes the backing page from the HMC, the queues
+/// have to be disabled before.
+pub struct I40eLanQueues {
+    regs: VAddr,
+    pf_id: u8,
+    /// Absolute index of the PF's first queue.
+    first_queue: u16,
+    queues: u16,
+    tx_obj_len: usize,
+    rx_obj_len: usize,
+    /// Offset of the RX contexts in the backing page.
+    rx_base: usize,
+    backing: IOBuf,
+    /// Offset of the (4 KiB aligned) backing page in `backing`.
+    page_offset: usize,
+}
+
+impl I40eLanQueues {
+    /// Sets up the HMC for the first `queues` LAN queues of the PF.
+    ///
+    /// # Safety
+    /// `regs` must be the mapped register BAR (BAR0) of an i40e PF whose
+    /// firmware finished its reset (the HMC isn't set up yet).
+    pub unsafe fn new(regs: VAddr, queues: u16) -> Result<I40eLanQueues, I40eError> {
+        let read = |offset: usize| core::ptr::read_volatile((regs + offset).as_ptr::<u32>());
+
+        let pf_id = read(PF_FUNC_RID).get_bits(PF_FUNC_RID_FUNCTION) as u8;
+        let qalloc = read(PFLAN_QALLOC);
+        let first_queue = qalloc.get_bits(PFLAN_QALLOC_FIRSTQ) as u16;
+        let last_queue = qalloc.get_bits(PFLAN_QALLOC_LASTQ) as u16;
+        let qmax = read(GLHMC_LANQMAX).get_bits(GLHMC_LANQMAX_PMLANQMAX) as u16;
+        if !qalloc.get_bit(PFLAN_QALLOC_VALID)
+            || queues == 0
+            || last_queue < first_queue
+            || queues > last_queue - first_queue + 1
+            || queues > qmax
+        {
+            return Err(I40eError::InvalidArgument);
+        }
+
+        let tx_obj_len = 1usize << read(GLHMC_LANTXOBJSZ).get_bits(GLHMC_OBJSZ);
+        let rx_obj_len = 1usize << read(GLHMC_LANRXOBJSZ).get_bits(GLHMC_OBJSZ);
+        let rx_base = (queues as usize * tx_obj_len).next_multiple_of(HMC_OBJ_BASE_ALIGN);
+        if tx_obj_len < TX_CONTEXT_LEN
+            || rx_obj_len < RX_CONTEXT_LEN
+            || rx_base + queues as usize * rx_obj_len > HMC_DIRECT_BP_SIZE
+        {
+            return Err(I40eError::InvalidArgument);
+        }
+
+        // IOBuf doesn't align beyond what the allocator does, so the page
+        // starts at the first 4 KiB boundary in the buffer
+        let backing = IOBuf::new(
+            Layout::from_size_align(HMC_DIRECT_BP_SIZE + HMC_PAGE_SIZE, HMC_PAGE_SIZE)
+                .expect("valid layout"),
+        )?;
+        let ioaddr = backing.ioaddr().as_u64();
+        let page_offset = (ioaddr.next_multiple_of(HMC_PAGE_SIZE as u64) - ioaddr) as usize;
+
+        let mut lan = I40eLanQueues {
+            regs,
+            pf_id,
+            first_queue,
+            queues,
+            tx_obj_len,
+            rx_obj_len,
+            rx_base,
+            backing,
+            page_offset,
+        };
+        let page = lan.page_ioaddr();
+        lan.write(PFHMC_SDDATAHIGH, (page >> 32) as u32);
+        lan.write(
+            PFHMC_SDDATALOW,
+            page as u32
+                | ((HMC_DIRECT_BP_SIZE / HMC_PAGE_SIZE) as u32)
+                    << PFHMC_SDDATALOW_PMSDBPCOUNT_SHIFT
+                | PFHMC_SDDATALOW_PMSDTYPE
+                | PFHMC_SDDATALOW_PMSDVALID,
+        );
+        // SD 0
+        lan.write(PFHMC_SDCMD, PFHMC_SDCMD_PMSDWR);
+
+        lan.write(glhmc_lantxbase(pf_id), 0);
+        lan.write(glhmc_lantxcnt(pf_id), queues as u32);
+        lan.write(
+            glhmc_lanrxbase(pf_id),
+            (rx_base / HMC_OBJ_BASE_ALIGN) as u32,
+        );
+        lan.write(glhmc_lanrxcnt(pf_id), queues as u32);
+        Ok(lan)
+    }
+
+    /// Number of LAN queue pairs set up.
+    pub fn queues(&self) -> u16 {
+        self.queues
+    }
+
+    fn page_ioaddr(&self) -> u64 {
+        self.backing.ioaddr().as_u64() + self.page_offset as u64
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u32>()) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u32>(), value) };
+    }
+
+    fn check_queue(&self, queue: u16) -> Result<(), I40eError> {
+        if queue >= self.queues {
+            return Err(I40eError::InvalidQueue { queue });
+        }
+        Ok(())
+    }
+
+    /// Copies the packed context `ctx` to `offset` in the backing page.
+    fn write_context(&mut self, offset: usize, ctx: &[u8]) {
+        let start = self.page_offset + offset;
+        self.backing.as_mut_slice()[start..start + ctx.len()].copy_from_slice(ctx);
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Sets the context of the (disabled) TX queue `queue`.
+    pub fn set_tx_context(
+        &mut self,
+        queue: u16,
+        context: &TxQueueContext,
+    ) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        let ctx = context.pack()?;
+        self.write_context(queue as usize * self.tx_obj_len, &ctx);
+        Ok(())
+    }
+
+    /// Sets the context of the (disabled) RX queue `queue`.
+    pub fn set_rx_context(
+        &mut self,
+        queue: u16,
+        context: &RxQueueContext,
+    ) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        let ctx = context.pack()?;
+        self.write_context(self.rx_base + queue as usize * self.rx_obj_len, &ctx);
+        Ok(())
+    }
+
+    /// Tells the device that TX queue `queue` is about to be enabled or
+    /// disabled.
+    fn pre_tx_queue_cfg(&mut self, queue: u16, enable: bool) {
+        let abs_queue = self.first_queue + queue;
+        let mut qdis = self.read(gllan_txpre_qdis(abs_queue));
+        qdis.set_bits(GLLAN_TXPRE_QDIS_QINDX, (abs_queue % 128) as u32);
+        qdis.set_bit(GLLAN_TXPRE_QDIS_SET_QDIS, !enable);
+        qdis.set_bit(GLLAN_TXPRE_QDIS_CLEAR_QDIS, enable);
+        self.write(gllan_txpre_qdis(abs_queue), qdis);
+    }
+
+    /// Sets the enable request of the queue at `offset` (QTX_ENA/QRX_ENA)
+    /// and waits until the device reports the requested state.
+    fn set_queue_enable(
+        &mut self,
+        queue: u16,
+        offset: usize,
+        enable: bool,
+    ) -> Result<(), I40eError> {
+        let mut ena = self.read(offset);
+        ena.set_bit(QENA_REQ, enable);
+        self.write(offset, ena);
+        if !poll_until(
+            || self.read(offset).get_bit(QENA_STAT) == enable,
+            QUEUE_TIMEOUT,
+        ) {
+            warn!(
+                "i40e: queue {} didn't become {}",
+                queue,
+                if enable { "enabled" } else { "disabled" }
+            );
+            return Err(I40eError::QueueTimeout { queue });
+        }
+        Ok(())
+    }
+
+    /// Assigns TX queue `queue` to the PF and enables it, which loads its
+    /// context (see [`I40eLanQueues::set_tx_context`]).
+    pub fn enable_tx_queue(&mut self, queue: u16) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        self.write(
+            qtx_ctl(queue),
+            QTX_CTL_PFVF_Q_PF | (self.pf_id as u32) << QTX_CTL_PF_INDX_SHIFT,
+        );
+        self.pre_tx_queue_cfg(queue, true);
+        self.write(qtx_head(queue), 0);
+        self.write(qtx_tail(queue), 0);
+        self.set_queue_enable(queue, qtx_ena(queue), true)
+    }
+
+    pub fn disable_tx_queue(&mut self, queue: u16) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        self.pre_tx_queue_cfg(queue, false);
+        self.set_queue_enable(queue, qtx_ena(queue), false)
+    }
+
+    /// Enables RX queue `queue`, which loads its context (see
+    /// [`I40eLanQueues::set_rx_context`]).
+    pub fn enable_rx_queue(&mut self, queue: u16) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        self.write(qrx_tail(queue), 0);
+        self.set_queue_enable(queue, qrx_ena(queue), true)
+    }
+
+    pub fn disable_rx_queue(&mut self, queue: u16) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        self.set_queue_enable(queue, qrx_ena(queue), false)
+    }
+
+    /// Hands the TX descriptors up to (excluding) `tail` to the device.
+    pub fn set_tx_tail(&mut self, queue: u16, tail: u16) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        self.write(qtx_tail(queue), tail as u32);
+        Ok(())
+    }
+
+    /// Hands the RX descriptors up to (excluding) `tail` to the device.
+    pub fn set_rx_tail(&mut self, queue: u16, tail: u16) -> Result<(), I40eError> {
+        self.check_queue(queue)?;
+        self.write(qrx_tail(queue), tail as u32);
+        Ok(())
+    }
+}
+
+impl Drop for I40eLanQueues {
+    fn drop(&mut self) {
+        let pf_id = self.pf_id;
+        self.write(glhmc_lantxcnt(pf_id), 0);
+        self.write(glhmc_lanrxcnt(pf_id), 0);
+        // Invalidate SD 0 before the backing page is freed
+        self.write(PFHMC_SDDATAHIGH, 0);
+        self.write(PFHMC_SDDATALOW, 0);
+        self.write(PFHMC_SDCMD, PFHMC_SDCMD_PMSDWR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iomem::KERNEL_BASE;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::thread::JoinHandle;
+
+    /// A register BAR in memory, served by a firmware thread that completes
+    /// the commands in the ATQ with `respond`.
+    struct Firmware {
+        regs: Arc<Vec<AtomicU32>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Firmware {
+        fn start(respond: fn(&mut AqDesc, &mut [u8])) -> Firmware {
+            let regs: Arc<Vec<AtomicU32>> =
+                Arc::new((0..=PF_ATQT / 4).map(|_| AtomicU32::new(0)).collect());
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let (regs, stop) = (regs.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    let reg = |offset: usize| &regs[offset / 4];
+                    while !stop.load(Ordering::Acquire) {
+                        let len = reg(PF_ATQLEN).load(Ordering::Acquire) & ATQLEN_MASK;
+                        let head = reg(PF_ATQH).load(Ordering::Acquire);
+                        if len == 0 || head == reg(PF_ATQT).load(Ordering::Acquire) {
+                            core::hint::spin_loop();
+                            continue;
+                        }
+
+                        // DMA addresses are the virtual addresses minus KERNEL_BASE
+                        let base = (reg(PF_ATQBAH).load(Ordering::Acquire) as u64) << 32
+                            | reg(PF_ATQBAL).load(Ordering::Acquire) as u64;
+                        let slot = (base.wrapping_add(KERNEL_BASE) as *mut AqDesc)
+                            .wrapping_add(head as usize);
+                        // Safety: the driver gave us the ring and the buffer
+                        unsafe {
+                            let mut desc = slot.read_volatile();
+                            let mut buf: &mut [u8] = &mut [];
+                            if desc.flags.get() & AQ_FLAG_BUF != 0 {
+                                let addr = (desc.addr_high.get() as u64) << 32
+                                    | desc.addr_low.get() as u64;
+                                buf = core::slice::from_raw_parts_mut(
+                                    addr.wrapping_add(KERNEL_BASE) as *mut u8,
+                                    desc.datalen.get() as usize,
+                                );
+                            }
+                            respond(&mut desc, buf);
+                            slot.write_volatile(desc);
+                        }
+                        reg(PF_ATQH).store((head + 1) % len, Ordering::Release);
+                    }
+                })
+            };
+            Firmware {
+                regs,
+                stop,
+                thread: Some(thread),
+            }
+        }
+
+        fn queue(&self) -> I40eAdminQueue {
+            // Safety: the tests drop the queue before the registers
+            let mut queue =
+                unsafe { I40eAdminQueue::new(VAddr::from(self.regs.as_ptr() as u64), 4).unwrap() };
+            queue.queue.set_timeout(core::time::Duration::from_secs(5));
+            queue
+        }
+    }
+
+    impl Drop for Firmware {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Release);
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    fn respond(desc: &mut AqDesc, buf: &mut [u8]) {
+        let mut flags = desc.flags.get() | AQ_FLAG_DD | AQ_FLAG_CMP;
+        match desc.opcode.get() {
+            AQC_GET_VERSION => {
+                desc.param0.set(0x0102_0304);
+                desc.param1.set(42);
+                desc.addr_high.set(7 << 16 | 8);
+                desc.addr_low.set(1 << 16 | 9);
+            }
+            AQC_MAC_ADDRESS_READ => {
+                buf[12..18].copy_from_slice(&[0x3c, 0xfd, 0xfe, 1, 2, 3]);
+                desc.param0.set(MAC_ADDR_PORT_VALID as u32);
+            }
+            // Not allowed (EPERM)
+            AQC_QUEUE_SHUTDOWN => {
+                desc.retval.set(1);
+                flags |= AQ_FLAG_ERR;
+            }
+            // Never completed (e.g., after a firmware reset)
+            AQC_DRIVER_VERSION => flags = desc.flags.get(),
+            // Echo the flags the driver set and fill the buffer
+            _ => {
+                desc.param1.set(desc.flags.get() as u32);
+                buf.fill(0xa5);
+            }
+        }
+        desc.flags.set(flags);
+    }
+
+    #[test]
+    fn descriptor_encoding() {
+        let mut desc = AqDesc::new(AQC_MAC_ADDRESS_READ);
+        desc.datalen.set(24);
+        desc.cookie_low.set(0x1122_3344);
+        desc.param0.set(0x0102_0304);
+        desc.addr_low.set(0xaabb_ccdd);
+        assert_eq!(core::mem::size_of::<AqDesc>(), 32);
+
+        // Safety: `AqDesc` is 32 bytes without padding
+        let bytes: [u8; 32] = unsafe { core::mem::transmute(desc) };
+        assert_eq!(bytes[0..8], [0x00, 0x20, 0x07, 0x01, 24, 0, 0, 0]);
+        assert_eq!(
+            bytes[12..20],
+            [0x44, 0x33, 0x22, 0x11, 0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(bytes[28..32], [0xdd, 0xcc, 0xbb, 0xaa]);
+        assert_eq!(desc.params()[0..4], [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(desc.params()[12..16], [0xdd, 0xcc, 0xbb, 0xaa]);
+    }
+
+    #[test]
+    fn admin_commands() {
+        let firmware = Firmware::start(respond);
+        let mut queue = firmware.queue();
+
+        let version = queue.firmware_version().unwrap();
+        assert_eq!(
+            version,
+            FirmwareVersion {
+                rom: 0x0102_0304,
+                fw_build: 42,
+                fw_major: 8,
+                fw_minor: 7,
+                api_major: 9,
+                api_minor: 1,
+            }
+        );
+        assert_eq!(queue.mac_address().unwrap(), [0x3c, 0xfd, 0xfe, 1, 2, 3]);
+
+        // Indirect buffers larger than 512 bytes need the LB flag
+        let mut data = [0u8; 600];
+        let desc = queue.execute(AqDesc::new(0x0700), Some(&mut data)).unwrap();
+        assert_eq!(
+            desc.param1.get() as u16,
+            AQ_FLAG_SI | AQ_FLAG_BUF | AQ_FLAG_LB
+        );
+        assert!(data.iter().all(|b| *b == 0xa5));
+        let desc = queue
+            .execute(AqDesc::new(0x0700), Some(&mut data[..16]))
+            .unwrap();
+        assert_eq!(desc.param1.get() as u16, AQ_FLAG_SI | AQ_FLAG_BUF);
+
+        let mut too_large = [0u8; AQ_BUF_LEN + 1];
+        assert!(matches!(
+            queue.execute(AqDesc::new(0x0700), Some(&mut too_large)),
+            Err(I40eError::InvalidArgument)
+        ));
+        assert!(matches!(
+            queue.shutdown(),
+            Err(I40eError::Firmware {
+                opcode: AQC_QUEUE_SHUTDOWN,
+                retval: 1
+            })
+        ));
+        assert!(matches!(
+            queue.execute(AqDesc::new(AQC_DRIVER_VERSION), None),
+            Err(I40eError::Timeout)
+        ));
+    }
+
+    /// A register BAR in memory for the LAN queues of PF 2 (which owns the
+    /// absolute queues 64..80), served by a thread that enables and disables
+    /// the queues as requested.
+    struct Lan {
+        regs: Arc<Vec<AtomicU32>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Lan {
+        const QUEUES: u16 = 4;
+
+        fn start() -> Lan {
+            let regs: Arc<Vec<AtomicU32>> =
+                Arc::new((0..=PFLAN_QALLOC / 4).map(|_| AtomicU32::new(0)).collect());
+            let reg = |offset: usize| &regs[offset / 4];
+            reg(PF_FUNC_RID).store(0x8a, Ordering::Relaxed);
+            reg(PFLAN_QALLOC).store(1 << 31 | 79 << 16 | 64, Ordering::Relaxed);
+            reg(GLHMC_LANQMAX).store(1536, Ordering::Relaxed);
+            reg(GLHMC_LANTXOBJSZ).store(7, Ordering::Relaxed);
+            reg(GLHMC_LANRXOBJSZ).store(5, Ordering::Relaxed);
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let (regs, stop) = (regs.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        let mut idle = true;
+                        for queue in 0..Lan::QUEUES {
+                            for ena in [&regs[qtx_ena(queue) / 4], &regs[qrx_ena(queue) / 4]] {
+                                let value = ena.load(Ordering::Acquire);
+                                if value.get_bit(QENA_REQ) != value.get_bit(QENA_STAT) {
+                                    let mut value = value;
+                                    value.set_bit(QENA_STAT, value.get_bit(QENA_REQ));
+                                    ena.store(value, Ordering::Release);
+                                    idle = false;
+                                }
+                            }
+                        }
+                        if idle {
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            };
+            Lan {
+                regs,
+                stop,
+                thread: Some(thread),
+            }
+        }
+
+        fn reg(&self, offset: usize) -> u32 {
+            self.regs[offset / 4].load(Ordering::Acquire)
+        }
+
+        fn queues(&self, queues: u16) -> Result<I40eLanQueues, I40eError> {
+            // Safety: the tests drop the queues before the registers
+            unsafe { I40eLanQueues::new(VAddr::from(self.regs.as_ptr() as u64), queues) }
+        }
+    }
+
+    impl Drop for Lan {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Release);
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    /// Reads `width` bits at `lsb` of a packed context.
+    fn unpack(ctx: &[u8], (lsb, width): (usize, usize)) -> u64 {
+        let mut value = 0u64;
+        for bit in 0..width {
+            let pos = lsb + bit;
+            value.set_bit(bit, ctx[pos / 8].get_bit(pos % 8));
+        }
+        value
+    }
+
+    const TX: TxQueueContext = TxQueueContext {
+        base: 0x1_2345_6780,
+        descriptors: 512,
+        qs_handle: 0x123,
+    };
+
+    const RX: RxQueueContext = RxQueueContext {
+        base: 0x2_0000_0080,
+        descriptors: 1024,
+        buf_len: 2048,
+        max_frame: 1522,
+    };
+
+    #[test]
+    fn pack_contexts() {
+        let tx = TX.pack().unwrap();
+        assert_eq!(unpack(&tx, TXCTX_BASE), 0x1_2345_6780 >> 7);
+        assert_eq!(unpack(&tx, TXCTX_QLEN), 512);
+        assert_eq!(unpack(&tx, TXCTX_NEW_CONTEXT), 1);
+        assert_eq!(unpack(&tx, TXCTX_RDYLIST), 0x123);
+        // base starts at byte 4, rdylist at bit 4 of byte 122
+        assert_eq!(tx[4..8], [0xcf, 0x8a, 0x46, 0x02]);
+        assert_eq!(tx[122..124], [0x30, 0x12]);
+
+        let rx = RX.pack().unwrap();
+        assert_eq!(unpack(&rx, RXCTX_BASE), 0x2_0000_0080 >> 7);
+        assert_eq!(unpack(&rx, RXCTX_QLEN), 1024);
+        assert_eq!(unpack(&rx, RXCTX_DBUFF), 16);
+        assert_eq!(unpack(&rx, RXCTX_DSIZE), 1);
+        assert_eq!(unpack(&rx, RXCTX_CRCSTRIP), 1);
+        assert_eq!(unpack(&rx, RXCTX_RXMAX), 1522);
+        assert_eq!(unpack(&rx, RXCTX_PREFENA), 1);
+        assert!(rx[26..].iter().all(|b| *b == 0));
+
+        for ring in [
+            (TX.base + 64, TX.descriptors),
+            (TX.base, 48),
+            (TX.base, 0),
+            (TX.base, MAX_RING_DESCRIPTORS + 32),
+        ] {
+            let invalid = TxQueueContext {
+                base: ring.0,
+                descriptors: ring.1,
+                ..TX
+            };
+            assert!(matches!(invalid.pack(), Err(I40eError::InvalidRing)));
+        }
+        for (buf_len, max_frame) in [(0, 1522), (2000, 1522), (16384, 1522), (2048, 9729)] {
+            let invalid = RxQueueContext {
+                buf_len,
+                max_frame,
+                ..RX
+            };
+            assert!(matches!(
+                invalid.pack(),
+                Err(I40eError::InvalidBufferSize { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn hmc_setup() {
+        let lan = Lan::start();
+        // More queues than the PF owns
+        assert!(matches!(lan.queues(17), Err(I40eError::InvalidArgument)));
+        assert!(matches!(lan.queues(0), Err(I40eError::InvalidArgument)));
+
+        let queues = lan.queues(Lan::QUEUES).unwrap();
+        let page = queues.page_ioaddr();
+        assert!(page.is_multiple_of(HMC_PAGE_SIZE as u64));
+        assert_eq!(lan.reg(PFHMC_SDDATAHIGH), (page >> 32) as u32);
+        assert_eq!(lan.reg(PFHMC_SDDATALOW), page as u32 | 512 << 2 | 0b11);
+        assert_eq!(lan.reg(PFHMC_SDCMD), 1 << 31);
+        // PF 2, 4 * 128 bytes of TX contexts
+        assert_eq!(lan.reg(glhmc_lantxbase(2)), 0);
+        assert_eq!(lan.reg(glhmc_lantxcnt(2)), 4);
+        assert_eq!(lan.reg(glhmc_lanrxbase(2)), 1);
+        assert_eq!(lan.reg(glhmc_lanrxcnt(2)), 4);
+
+        drop(queues);
+        assert_eq!(lan.reg(PFHMC_SDDATALOW), 0);
+        assert_eq!(lan.reg(glhmc_lantxcnt(2)), 0);
+    }
+
+    #[test]
+    fn queue_contexts() {
+        let lan = Lan::start();
+        let mut queues = lan.queues(Lan::QUEUES).unwrap();
+
+        queues.set_tx_context(1, &TX).unwrap();
+        queues.set_rx_context(3, &RX).unwrap();
+        let page = &queues.backing.as_slice()[queues.page_offset..];
+        assert_eq!(page[128..256], TX.pack().unwrap());
+        assert_eq!(page[512 + 3 * 32..512 + 4 * 32], RX.pack().unwrap());
+        assert!(page[..128].iter().all(|b| *b == 0));
+
+        assert!(matches!(
+            queues.set_tx_context(4, &TX),
+            Err(I40eError::InvalidQueue { queue: 4 })
+        ));
+        assert!(matches!(
+            queues.set_rx_tail(4, 0),
+            Err(I40eError::InvalidQueue { queue: 4 })
+        ));
+    }
+
+    #[test]
+    fn enable_queues() {
+        let lan = Lan::start();
+        let mut queues = lan.queues(Lan::QUEUES).unwrap();
+
+        queues.enable_tx_queue(1).unwrap();
+        // PF queue of PF 2
+        assert_eq!(lan.reg(qtx_ctl(1)), 2 | 2 << 2);
+        // Absolute queue 65
+        assert_eq!(lan.reg(gllan_txpre_qdis(65)), 1 << 31 | 65);
+        assert!(lan.reg(qtx_ena(1)).get_bit(QENA_STAT));
+        queues.enable_rx_queue(2).unwrap();
+        assert!(lan.reg(qrx_ena(2)).get_bit(QENA_STAT));
+
+        queues.set_tx_tail(1, 17).unwrap();
+        queues.set_rx_tail(2, 1023).unwrap();
+        assert_eq!((lan.reg(qtx_tail(1)), lan.reg(qrx_tail(2))), (17, 1023));
+
+        queues.disable_tx_queue(1).unwrap();
+        assert_eq!(lan.reg(gllan_txpre_qdis(65)), 1 << 30 | 65);
+        assert!(!lan.reg(qtx_ena(1)).get_bit(QENA_STAT));
+        queues.disable_rx_queue(2).unwrap();
+        assert!(!lan.reg(qrx_ena(2)).get_bit(QENA_STAT));
+    }
+}
@@ -0,0 +1,829 @@
+//! The VF side of the ixgbe (82599/X540/X550) SR-IOV mailbox protocol.
+//!
+//! A virtual function can't configure its MAC address, filters or maximum
+//! frame size itself, it asks the PF driver to do so with messages sent
+//! through a 16-word mailbox. This implements the VF side of that protocol
+//! (as spoken by the Linux/DPDK PF drivers): reset, API negotiation, MAC
+//! address, maximum frame size and queue configuration queries. The message
+//! exchange itself is done by [`Mailbox`].
+//!
+//! The VF's own registers configure its descriptor rings
+//! ([`IxgbeVf::setup_tx_queue`], [`IxgbeVf::setup_rx_queue`]) and route its
+//! interrupt causes to MSI-X vectors ([`IxgbeVf::map_vector`]). Filling the
+//! rings with (advanced) descriptors is up to the queue implementation.
+
+use core::time::Duration;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use crate::arch::VAddr;
+use crate::mailbox::{Mailbox, MailboxError, MailboxHw};
+use crate::poll::poll_until;
+
+use super::{MacAddress, ETH_FCS_LEN, ETH_HLEN};
+
+/// VF mailbox control register.
+const VFMAILBOX: usize = 0x002fc;
+/// VF mailbox memory (shared with the PF).
+const VFMBMEM: usize = 0x00200;
+/// Size of the mailbox memory in 32-bit words.
+pub const MAILBOX_SIZE: usize = 16;
+
+/// VFMAILBOX: VF requests the PF to read a message.
+const VFMAILBOX_REQ: usize = 0;
+/// VFMAILBOX: VF acknowledges a PF message.
+const VFMAILBOX_ACK: usize = 1;
+/// VFMAILBOX: the buffer is taken by the VF.
+const VFMAILBOX_VFU: usize = 2;
+/// VFMAILBOX: the buffer is taken by the PF.
+const VFMAILBOX_PFU: usize = 3;
+/// VFMAILBOX: the PF wrote a message.
+const VFMAILBOX_PFSTS: usize = 4;
+/// VFMAILBOX: the PF acknowledged a VF message.
+const VFMAILBOX_PFACK: usize = 5;
+/// VFMAILBOX: the PF reset is in progress.
+const VFMAILBOX_RSTI: usize = 6;
+/// VFMAILBOX: the PF reset is done.
+const VFMAILBOX_RSTD: usize = 7;
+/// Bits that are cleared when VFMAILBOX is read.
+const VFMAILBOX_R2C_BITS: u32 = 0xb0;
+
+/// Interrupt mask set/clear, auto-clear and auto-mask registers (one bit per
+/// MSI-X vector).
+const VTEIMS: usize = 0x00108;
+const VTEIMC: usize = 0x0010c;
+const VTEIAC: usize = 0x00110;
+const VTEIAM: usize = 0x00114;
+/// Vectors of the queue interrupt causes (two queues per register).
+const fn vtivar(queue: u8) -> usize {
+    0x00120 + 4 * (queue as usize >> 1)
+}
+/// Vector of the mailbox interrupt cause.
+const VTIVAR_MISC: usize = 0x00140;
+/// IVAR: the entry is valid.
+const IVAR_ALLOC_VAL: u32 = 0x80;
+
+/// RX queue registers (0x40 bytes apart).
+const fn vfrdbal(queue: u8) -> usize {
+    0x01000 + 0x40 * queue as usize
+}
+const VFRDBAH: usize = 0x04;
+const VFRDLEN: usize = 0x08;
+const VFRDH: usize = 0x10;
+const VFSRRCTL: usize = 0x14;
+const VFRDT: usize = 0x18;
+const VFRXDCTL: usize = 0x28;
+
+/// TX queue registers (0x40 bytes apart).
+const fn vftdbal(queue: u8) -> usize {
+    0x02000 + 0x40 * queue as usize
+}
+const VFTDBAH: usize = 0x04;
+const VFTDLEN: usize = 0x08;
+const VFTDH: usize = 0x10;
+const VFTDT: usize = 0x18;
+const VFTXDCTL: usize = 0x28;
+const VFTDWBAL: usize = 0x38;
+const VFTDWBAH: usize = 0x3c;
+
+/// RXDCTL/TXDCTL: the queue is enabled.
+const XDCTL_ENABLE: usize = 25;
+/// TXDCTL: flush the queue (while disabling it).
+const TXDCTL_SWFLSH: usize = 26;
+/// RXDCTL: strip VLAN tags.
+const RXDCTL_VME: usize = 30;
+/// TXDCTL: prefetch, host and write-back thresholds (as Linux uses them).
+const TXDCTL_PTHRESH: u32 = 32;
+const TXDCTL_HTHRESH: u32 = 1 << 8;
+const TXDCTL_WTHRESH: u32 = 8 << 16;
+
+/// SRRCTL: RX buffer size in KiB.
+const SRRCTL_BSIZEPKT: core::ops::Range<usize> = 0..5;
+/// SRRCTL: header buffer size in 64 bytes (unused with one buffer).
+const SRRCTL_BSIZEHDR: core::ops::Range<usize> = 8..14;
+/// SRRCTL: descriptor type, advanced with one buffer.
+const SRRCTL_DESCTYPE: core::ops::Range<usize> = 25..28;
+const SRRCTL_DESCTYPE_ADV_ONEBUF: u32 = 1;
+/// SRRCTL: drop packets if no descriptors are available.
+const SRRCTL_DROP_EN: usize = 28;
+
+/// Number of queue pairs a VF can have.
+pub const MAX_VF_QUEUES: u8 = 8;
+/// Number of MSI-X vectors of a VF.
+pub const MAX_VF_VECTORS: u8 = 3;
+/// Size of the (advanced) TX and RX descriptors.
+pub const VF_DESC_LEN: usize = 16;
+/// Maximum number of descriptors of a ring.
+pub const VF_MAX_DESCRIPTORS: u32 = 4096;
+/// Alignment of the rings (their length is a multiple of it as well).
+const RING_ALIGN: u64 = 128;
+/// How long a queue may take to change its enable state.
+const QUEUE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Message types (low 16 bits of the first word).
+pub const VF_RESET: u32 = 0x01;
+pub const VF_SET_MAC_ADDR: u32 = 0x02;
+pub const VF_SET_MULTICAST: u32 = 0x03;
+pub const VF_SET_VLAN: u32 = 0x04;
+pub const VF_SET_LPE: u32 = 0x05;
+pub const VF_API_NEGOTIATE: u32 = 0x08;
+pub const VF_GET_QUEUES: u32 = 0x09;
+
+/// Message flags (high bits of the first word).
+pub const VT_MSGTYPE_ACK: u32 = 0x8000_0000;
+pub const VT_MSGTYPE_NACK: u32 = 0x4000_0000;
+pub const VT_MSGTYPE_CTS: u32 = 0x2000_0000;
+
+/// Mailbox API versions understood by this implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u32)]
+pub enum MailboxApi {
+    V10 = 0,
+    V11 = 2,
+}
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub IxgbeVfError
+    Mailbox{error: MailboxError} = "mailbox: {error}",
+    Nack{msg_type: u32} = "the PF rejected the request {msg_type}",
+    Malformed = "the PF sent an unexpected reply",
+    ResetInProgress = "the PF is resetting",
+    InvalidQueue{queue: u8} = "the VF has no queue {queue}",
+    InvalidRing = "rings must be 128-byte aligned and have a multiple of 8 descriptors (up to 4096)",
+    InvalidBufferSize{len: usize} = "RX buffers of {len} bytes aren't supported",
+    InvalidVector{vector: u8} = "the VF has no MSI-X vector {vector}",
+    QueueTimeout{queue: u8} = "queue {queue} didn't change its enable state",
+}
+
+impl From<MailboxError> for IxgbeVfError {
+    fn from(error: MailboxError) -> Self {
+        IxgbeVfError::Mailbox { error }
+    }
+}
+
+/// Queue configuration reported by the PF (`VF_GET_QUEUES`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VfQueues {
+    pub tx_queues: u32,
+    pub rx_queues: u32,
+    /// Whether the PF inserts a (port) VLAN tag on transmit.
+    pub transparent_vlan: bool,
+    /// The default queue for received packets.
+    pub default_queue: u32,
+}
+
+/// A descriptor ring of a VF queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VfRing {
+    /// IO address of the descriptors (128-byte aligned).
+    pub base: u64,
+    /// Number of descriptors (a multiple of 8).
+    pub descriptors: u32,
+}
+
+impl VfRing {
+    fn validate(&self) -> Result<(), IxgbeVfError> {
+        let len = self.descriptors as u64 * VF_DESC_LEN as u64;
+        if !self.base.is_multiple_of(RING_ALIGN)
+            || len == 0
+            || !len.is_multiple_of(RING_ALIGN)
+            || self.descriptors > VF_MAX_DESCRIPTORS
+        {
+            return Err(IxgbeVfError::InvalidRing);
+        }
+        Ok(())
+    }
+}
+
+/// An interrupt cause of a VF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VfCause {
+    Rx(u8),
+    Tx(u8),
+    /// Messages and acknowledgements from the PF.
+    Mailbox,
+}
+
+/// Extracts the message type from the first word of a message.
+pub fn msg_type(word: u32) -> u32 {
+    word.get_bits(0..16)
+}
+
+/// The VF side of the mailbox registers of an ixgbe virtual function.
+pub struct IxgbeVfMailbox {
+    regs: VAddr,
+    /// Read-to-clear bits of VFMAILBOX we've seen but not consumed yet.
+    r2c: u32,
+}
+
+impl IxgbeVfMailbox {
+    /// # Safety
+    /// `regs` must be the mapped register BAR (BAR0) of an ixgbe VF.
+    pub unsafe fn new(regs: VAddr) -> IxgbeVfMailbox {
+        IxgbeVfMailbox { regs, r2c: 0 }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.regs + offset).as_mut_ptr::<u32>()
+    }
+
+    fn read_mailbox(&mut self) -> u32 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        let value = unsafe { core::ptr::read_volatile(self.reg(VFMAILBOX)) };
+        self.r2c |= value & VFMAILBOX_R2C_BITS;
+        value | self.r2c
+    }
+
+    fn write_mailbox(&mut self, value: u32) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile(self.reg(VFMAILBOX), value) };
+    }
+
+    /// Checks (and consumes) a read-to-clear bit of VFMAILBOX.
+    fn check_bit(&mut self, bit: usize) -> bool {
+        let set = self.read_mailbox().get_bit(bit);
+        self.r2c.set_bit(bit, false);
+        set
+    }
+
+    /// Whether the PF finished resetting (and the mailbox can be used).
+    pub fn reset_done(&mut self) -> bool {
+        let mailbox = self.read_mailbox();
+        self.r2c.set_bit(VFMAILBOX_RSTD, false);
+        mailbox.get_bit(VFMAILBOX_RSTD) && !mailbox.get_bit(VFMAILBOX_RSTI)
+    }
+}
+
+impl MailboxHw for IxgbeVfMailbox {
+    fn size(&self) -> usize {
+        MAILBOX_SIZE
+    }
+
+    fn try_lock(&mut self) -> bool {
+        self.write_mailbox(1 << VFMAILBOX_VFU);
+        let mailbox = self.read_mailbox();
+        mailbox.get_bit(VFMAILBOX_VFU) && !mailbox.get_bit(VFMAILBOX_PFU)
+    }
+
+    fn read_word(&mut self, index: usize) -> u32 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::read_volatile(self.reg(VFMBMEM + index * 4)) }
+    }
+
+    fn write_word(&mut self, index: usize, value: u32) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile(self.reg(VFMBMEM + index * 4), value) };
+    }
+
+    fn ring_doorbell(&mut self) {
+        self.write_mailbox(1 << VFMAILBOX_REQ);
+    }
+
+    fn ack(&mut self) {
+        self.write_mailbox(1 << VFMAILBOX_ACK);
+    }
+
+    fn check_message(&mut self) -> bool {
+        self.check_bit(VFMAILBOX_PFSTS)
+    }
+
+    fn check_ack(&mut self) -> bool {
+        self.check_bit(VFMAILBOX_PFACK)
+    }
+
+    fn check_reset(&mut self) -> bool {
+        let mailbox = self.read_mailbox();
+        mailbox.get_bit(VFMAILBOX_RSTI) || mailbox.get_bit(VFMAILBOX_RSTD)
+    }
+}
+
+/// An ixgbe virtual function, configured through the PF and its own queue
+/// and interrupt registers.
+pub struct IxgbeVf {
+    regs: VAddr,
+    mailbox: Mailbox<IxgbeVfMailbox>,
+    api: MailboxApi,
+}
+
+impl IxgbeVf {
+    /// # Safety
+    /// `regs` must be the mapped register BAR (BAR0) of an ixgbe VF.
+    pub unsafe fn new(regs: VAddr) -> IxgbeVf {
+        IxgbeVf {
+            regs,
+            mailbox: Mailbox::new(IxgbeVfMailbox::new(regs)),
+            api: MailboxApi::V10,
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u32>()) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u32>(), value) };
+    }
+
+    fn check_queue(queue: u8) -> Result<(), IxgbeVfError> {
+        if queue >= MAX_VF_QUEUES {
+            return Err(IxgbeVfError::InvalidQueue { queue });
+        }
+        Ok(())
+    }
+
+    /// Writes `ctrl` to the RXDCTL/TXDCTL register at `offset` and waits
+    /// until the queue reports the enable state `ctrl` asks for.
+    fn set_queue_ctrl(&mut self, queue: u8, offset: usize, ctrl: u32) -> Result<(), IxgbeVfError> {
+        self.write(offset, ctrl);
+        let enable = ctrl.get_bit(XDCTL_ENABLE);
+        if !poll_until(
+            || self.read(offset).get_bit(XDCTL_ENABLE) == enable,
+            QUEUE_TIMEOUT,
+        ) {
+            warn!(
+                "ixgbevf: queue {} didn't become {}",
+                queue,
+                if enable { "enabled" } else { "disabled" }
+            );
+            return Err(IxgbeVfError::QueueTimeout { queue });
+        }
+        Ok(())
+    }
+
+    /// Points TX queue `queue` to `ring` and enables it. The ring starts out
+    /// empty (head and tail 0).
+    pub fn setup_tx_queue(&mut self, queue: u8, ring: VfRing) -> Result<(), IxgbeVfError> {
+        Self::check_queue(queue)?;
+        ring.validate()?;
+        let regs = vftdbal(queue);
+        self.disable_tx_queue(queue)?;
+
+        self.write(regs, ring.base as u32);
+        self.write(regs + VFTDBAH, (ring.base >> 32) as u32);
+        self.write(regs + VFTDLEN, ring.descriptors * VF_DESC_LEN as u32);
+        self.write(regs + VFTDH, 0);
+        self.write(regs + VFTDT, 0);
+        // No head write-back, completions are reported in the descriptors
+        self.write(regs + VFTDWBAL, 0);
+        self.write(regs + VFTDWBAH, 0);
+
+        let mut txdctl = TXDCTL_PTHRESH | TXDCTL_HTHRESH | TXDCTL_WTHRESH;
+        txdctl.set_bit(XDCTL_ENABLE, true);
+        self.set_queue_ctrl(queue, regs + VFTXDCTL, txdctl)
+    }
+
+    /// Flushes and disables TX queue `queue`.
+    pub fn disable_tx_queue(&mut self, queue: u8) -> Result<(), IxgbeVfError> {
+        Self::check_queue(queue)?;
+        let mut txdctl = 0;
+        txdctl.set_bit(TXDCTL_SWFLSH, true);
+        self.set_queue_ctrl(queue, vftdbal(queue) + VFTXDCTL, txdctl)
+    }
+
+    /// Points RX queue `queue` to `ring` with buffers of `buf_len` bytes (a
+    /// multiple of 1 KiB up to 16 KiB) and enables it.
+    ///
+    /// The ring starts out without buffers, the queue implementation posts
+    /// them with [`IxgbeVf::set_rx_tail`]. VLAN tags are stripped, the PF
+    /// decides which VLANs the VF receives.
+    pub fn setup_rx_queue(
+        &mut self,
+        queue: u8,
+        ring: VfRing,
+        buf_len: usize,
+    ) -> Result<(), IxgbeVfError> {
+        Self::check_queue(queue)?;
+        ring.validate()?;
+        if buf_len == 0 || !buf_len.is_multiple_of(1024) || buf_len > 16 * 1024 {
+            return Err(IxgbeVfError::InvalidBufferSize { len: buf_len });
+        }
+        let regs = vfrdbal(queue);
+        self.disable_rx_queue(queue)?;
+
+        self.write(regs, ring.base as u32);
+        self.write(regs + VFRDBAH, (ring.base >> 32) as u32);
+        self.write(regs + VFRDLEN, ring.descriptors * VF_DESC_LEN as u32);
+        self.write(regs + VFRDH, 0);
+        self.write(regs + VFRDT, 0);
+
+        let mut srrctl = 0u32;
+        srrctl.set_bits(SRRCTL_BSIZEPKT, (buf_len / 1024) as u32);
+        srrctl.set_bits(SRRCTL_BSIZEHDR, 256 / 64);
+        srrctl.set_bits(SRRCTL_DESCTYPE, SRRCTL_DESCTYPE_ADV_ONEBUF);
+        srrctl.set_bit(SRRCTL_DROP_EN, true);
+        self.write(regs + VFSRRCTL, srrctl);
+
+        let mut rxdctl = self.read(regs + VFRXDCTL);
+        rxdctl.set_bit(XDCTL_ENABLE, true);
+        rxdctl.set_bit(RXDCTL_VME, true);
+        self.set_queue_ctrl(queue, regs + VFRXDCTL, rxdctl)
+    }
+
+    /// Disables RX queue `queue`.
+    pub fn disable_rx_queue(&mut self, queue: u8) -> Result<(), IxgbeVfError> {
+        Self::check_queue(queue)?;
+        let offset = vfrdbal(queue) + VFRXDCTL;
+        let mut rxdctl = self.read(offset);
+        rxdctl.set_bit(XDCTL_ENABLE, false);
+        self.set_queue_ctrl(queue, offset, rxdctl)
+    }
+
+    /// Hands the TX descriptors up to (excluding) `tail` to the device.
+    pub fn set_tx_tail(&mut self, queue: u8, tail: u32) -> Result<(), IxgbeVfError> {
+        Self::check_queue(queue)?;
+        self.write(vftdbal(queue) + VFTDT, tail);
+        Ok(())
+    }
+
+    /// Hands the RX descriptors up to (excluding) `tail` to the device.
+    pub fn set_rx_tail(&mut self, queue: u8, tail: u32) -> Result<(), IxgbeVfError> {
+        Self::check_queue(queue)?;
+        self.write(vfrdbal(queue) + VFRDT, tail);
+        Ok(())
+    }
+
+    /// Routes interrupt `cause` to MSI-X vector `vector`.
+    pub fn map_vector(&mut self, cause: VfCause, vector: u8) -> Result<(), IxgbeVfError> {
+        if vector >= MAX_VF_VECTORS {
+            return Err(IxgbeVfError::InvalidVector { vector });
+        }
+        let entry = vector as u32 | IVAR_ALLOC_VAL;
+        let (offset, shift) = match cause {
+            VfCause::Rx(queue) => {
+                Self::check_queue(queue)?;
+                (vtivar(queue), 16 * (queue as usize & 1))
+            }
+            VfCause::Tx(queue) => {
+                Self::check_queue(queue)?;
+                (vtivar(queue), 16 * (queue as usize & 1) + 8)
+            }
+            VfCause::Mailbox => (VTIVAR_MISC, 0),
+        };
+        let mut ivar = self.read(offset);
+        ivar.set_bits(shift..shift + 8, entry);
+        self.write(offset, ivar);
+        Ok(())
+    }
+
+    /// Enables the MSI-X vectors in `vectors` (bit `n` for vector `n`).
+    ///
+    /// The vectors are masked and their causes cleared automatically when
+    /// they fire, the handler re-enables them once it is done.
+    pub fn enable_vectors(&mut self, vectors: u32) {
+        let vectors = vectors & ((1 << MAX_VF_VECTORS) - 1);
+        let (iam, iac) = (self.read(VTEIAM), self.read(VTEIAC));
+        self.write(VTEIAM, iam | vectors);
+        self.write(VTEIAC, iac | vectors);
+        self.write(VTEIMS, vectors);
+    }
+
+    /// Masks the MSI-X vectors in `vectors`.
+    pub fn disable_vectors(&mut self, vectors: u32) {
+        self.write(VTEIMC, vectors & ((1 << MAX_VF_VECTORS) - 1));
+    }
+
+    /// The negotiated mailbox API version.
+    pub fn api(&self) -> MailboxApi {
+        self.api
+    }
+
+    pub fn mailbox(&mut self) -> &mut Mailbox<IxgbeVfMailbox> {
+        &mut self.mailbox
+    }
+
+    /// Sends a request and reads the reply into `msg`, checking that the PF
+    /// acknowledged it.
+    fn request(&mut self, msg: &mut [u32], len: usize) -> Result<(), IxgbeVfError> {
+        let request = msg_type(msg[0]);
+        self.mailbox.request(msg, len)?;
+
+        if msg_type(msg[0]) != request {
+            return Err(IxgbeVfError::Malformed);
+        }
+        if msg[0] & VT_MSGTYPE_NACK != 0 {
+            return Err(IxgbeVfError::Nack { msg_type: request });
+        }
+        Ok(())
+    }
+
+    /// Resets the VF state in the PF and returns the MAC address the PF
+    /// assigned (all zeros if it didn't assign one).
+    ///
+    /// Has to be done first, after a function level reset of the VF.
+    pub fn reset(&mut self) -> Result<MacAddress, IxgbeVfError> {
+        if !self.mailbox.hw_mut().reset_done() {
+            return Err(IxgbeVfError::ResetInProgress);
+        }
+
+        self.api = MailboxApi::V10;
+        let mut msg = [0u32; 4];
+        msg[0] = VF_RESET;
+        self.mailbox.request(&mut msg, 1)?;
+
+        match msg[0] & !VT_MSGTYPE_CTS {
+            m if m == VF_RESET | VT_MSGTYPE_ACK => {
+                let mut mac = [0u8; 6];
+                mac[..4].copy_from_slice(&msg[1].to_le_bytes());
+                mac[4..].copy_from_slice(&msg[2].to_le_bytes()[..2]);
+                Ok(mac)
+            }
+            // The PF has no address for us, the VF has to pick one
+            m if m == VF_RESET | VT_MSGTYPE_NACK => Ok([0; 6]),
+            _ => Err(IxgbeVfError::Malformed),
+        }
+    }
+
+    /// Negotiates the newest mailbox API both sides understand.
+    pub fn negotiate_api(&mut self) -> Result<MailboxApi, IxgbeVfError> {
+        for api in [MailboxApi::V11, MailboxApi::V10] {
+            let mut msg = [VF_API_NEGOTIATE, api as u32, 0];
+            match self.request(&mut msg, 3) {
+                Ok(()) => {
+                    self.api = api;
+                    return Ok(api);
+                }
+                Err(IxgbeVfError::Nack { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(IxgbeVfError::Nack {
+            msg_type: VF_API_NEGOTIATE,
+        })
+    }
+
+    /// Asks the PF to set the unicast MAC address of the VF.
+    pub fn set_mac_address(&mut self, mac: MacAddress) -> Result<(), IxgbeVfError> {
+        let mut msg = [VF_SET_MAC_ADDR, 0, 0];
+        msg[1] = u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]);
+        msg[2] = u32::from_le_bytes([mac[4], mac[5], 0, 0]);
+        self.request(&mut msg, 3)
+    }
+
+    /// Asks the PF to accept frames with `mtu` bytes of payload.
+    pub fn set_mtu(&mut self, mtu: u16) -> Result<(), IxgbeVfError> {
+        let max_frame = mtu as u32 + (ETH_HLEN + ETH_FCS_LEN) as u32;
+        let mut msg = [VF_SET_LPE, max_frame];
+        self.request(&mut msg, 2)
+    }
+
+    /// Queries the queue configuration (needs API 1.1).
+    pub fn queues(&mut self) -> Result<VfQueues, IxgbeVfError> {
+        let mut msg = [0u32; 5];
+        msg[0] = VF_GET_QUEUES;
+        self.request(&mut msg, 1)?;
+        Ok(VfQueues {
+            tx_queues: msg[1],
+            rx_queues: msg[2],
+            transparent_vlan: msg[3] != 0,
+            default_queue: msg[4],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailbox::MailboxConfig;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use core::time::Duration;
+    use std::thread::JoinHandle;
+
+    /// The registers of a VF in memory, with a PF thread answering the
+    /// requests of the VF with `respond`.
+    struct MockPf {
+        regs: Arc<Vec<AtomicU32>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl MockPf {
+        fn start(mailbox: u32, respond: fn(&mut [u32; MAILBOX_SIZE])) -> MockPf {
+            let regs: Arc<Vec<AtomicU32>> =
+                Arc::new((0..=VFMAILBOX / 4).map(|_| AtomicU32::new(0)).collect());
+            regs[VFMAILBOX / 4].store(mailbox, Ordering::Release);
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let (regs, stop) = (regs.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    let word = |index: usize| &regs[VFMBMEM / 4 + index];
+                    while !stop.load(Ordering::Acquire) {
+                        if !regs[VFMAILBOX / 4]
+                            .load(Ordering::Acquire)
+                            .get_bit(VFMAILBOX_REQ)
+                        {
+                            core::hint::spin_loop();
+                            continue;
+                        }
+                        let mut msg = [0u32; MAILBOX_SIZE];
+                        for (i, w) in msg.iter_mut().enumerate() {
+                            *w = word(i).load(Ordering::Acquire);
+                        }
+                        respond(&mut msg);
+                        for (i, w) in msg.iter().enumerate() {
+                            word(i).store(*w, Ordering::Release);
+                        }
+                        // Acknowledge the request and post the reply
+                        regs[VFMAILBOX / 4].store(
+                            1 << VFMAILBOX_PFACK | 1 << VFMAILBOX_PFSTS,
+                            Ordering::Release,
+                        );
+                    }
+                })
+            };
+            MockPf {
+                regs,
+                stop,
+                thread: Some(thread),
+            }
+        }
+
+        fn vf(&self) -> IxgbeVf {
+            // Safety: the tests drop the VF before the registers
+            let mut vf = unsafe { IxgbeVf::new(VAddr::from(self.regs.as_ptr() as u64)) };
+            vf.mailbox().set_config(MailboxConfig {
+                timeout: Duration::from_secs(1),
+                retries: 0,
+            });
+            vf
+        }
+    }
+
+    impl Drop for MockPf {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Release);
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    /// A PF that assigns a MAC address and only speaks API 1.0.
+    fn pf(msg: &mut [u32; MAILBOX_SIZE]) {
+        let reply = match msg_type(msg[0]) {
+            VF_RESET => {
+                msg[1] = u32::from_le_bytes([0x02, 0x09, 0xc0, 0x01]);
+                msg[2] = u32::from_le_bytes([0x02, 0x03, 0, 0]);
+                VT_MSGTYPE_ACK
+            }
+            VF_API_NEGOTIATE if msg[1] == MailboxApi::V10 as u32 => VT_MSGTYPE_ACK,
+            _ => VT_MSGTYPE_NACK,
+        };
+        msg[0] = msg_type(msg[0]) | reply | VT_MSGTYPE_CTS;
+    }
+
+    #[test]
+    fn reset_and_negotiate() {
+        let mock = MockPf::start(1 << VFMAILBOX_RSTD, pf);
+        let mut vf = mock.vf();
+
+        assert_eq!(vf.reset().unwrap(), [0x02, 0x09, 0xc0, 0x01, 0x02, 0x03]);
+        // The PF rejects 1.1, so 1.0 it is
+        assert_eq!(vf.negotiate_api().unwrap(), MailboxApi::V10);
+        assert_eq!(vf.api(), MailboxApi::V10);
+        assert!(matches!(
+            vf.queues(),
+            Err(IxgbeVfError::Nack {
+                msg_type: VF_GET_QUEUES
+            })
+        ));
+    }
+
+    /// The queue and interrupt registers of a VF in memory (they don't need
+    /// the PF).
+    fn regs() -> Vec<u32> {
+        vec![0; 0x3000 / 4]
+    }
+
+    fn reg(regs: &[u32], offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(&regs[offset / 4]) }
+    }
+
+    #[test]
+    fn setup_queues() {
+        let mut regs = regs();
+        let mut vf = unsafe { IxgbeVf::new(VAddr::from(regs.as_mut_ptr() as u64)) };
+        let ring = VfRing {
+            base: 0x1_2345_6780,
+            descriptors: 512,
+        };
+
+        vf.setup_tx_queue(1, ring).unwrap();
+        vf.setup_rx_queue(1, ring, 2048).unwrap();
+        vf.set_rx_tail(1, 511).unwrap();
+
+        let tx = vftdbal(1);
+        assert_eq!(tx, 0x2040);
+        assert_eq!((reg(&regs, tx), reg(&regs, tx + VFTDBAH)), (0x2345_6780, 1));
+        assert_eq!(reg(&regs, tx + VFTDLEN), 512 * 16);
+        assert_eq!((reg(&regs, tx + VFTDH), reg(&regs, tx + VFTDT)), (0, 0));
+        assert_eq!(reg(&regs, tx + VFTXDCTL), 0x0208_0120);
+
+        let rx = vfrdbal(1);
+        assert_eq!(rx, 0x1040);
+        assert_eq!((reg(&regs, rx), reg(&regs, rx + VFRDBAH)), (0x2345_6780, 1));
+        assert_eq!(reg(&regs, rx + VFRDLEN), 512 * 16);
+        assert_eq!(reg(&regs, rx + VFRDT), 511);
+        // 2 KiB buffers, advanced one-buffer descriptors, drop when full
+        assert_eq!(reg(&regs, rx + VFSRRCTL), 0x1200_0402);
+        assert_eq!(reg(&regs, rx + VFRXDCTL), 0x4200_0000);
+
+        vf.disable_rx_queue(1).unwrap();
+        assert_eq!(reg(&regs, rx + VFRXDCTL), 0x4000_0000);
+        vf.disable_tx_queue(1).unwrap();
+        assert_eq!(reg(&regs, tx + VFTXDCTL), 0x0400_0000);
+    }
+
+    #[test]
+    fn reject_invalid_queues() {
+        let mut regs = regs();
+        let mut vf = unsafe { IxgbeVf::new(VAddr::from(regs.as_mut_ptr() as u64)) };
+        let ring = VfRing {
+            base: 0x10_0000,
+            descriptors: 64,
+        };
+
+        assert!(matches!(
+            vf.setup_tx_queue(MAX_VF_QUEUES, ring),
+            Err(IxgbeVfError::InvalidQueue { queue: 8 })
+        ));
+        for bad in [
+            VfRing {
+                base: 0x10_0040,
+                ..ring
+            },
+            VfRing {
+                descriptors: 12,
+                ..ring
+            },
+            VfRing {
+                descriptors: 0,
+                ..ring
+            },
+            VfRing {
+                descriptors: 8192,
+                ..ring
+            },
+        ] {
+            assert!(matches!(
+                vf.setup_tx_queue(0, bad),
+                Err(IxgbeVfError::InvalidRing)
+            ));
+        }
+        for len in [0, 1536, 32 * 1024] {
+            assert!(matches!(
+                vf.setup_rx_queue(0, ring, len),
+                Err(IxgbeVfError::InvalidBufferSize { .. })
+            ));
+        }
+        // Nothing was written
+        assert!(regs.iter().all(|reg| *reg == 0));
+    }
+
+    #[test]
+    fn map_vectors() {
+        let mut regs = regs();
+        let mut vf = unsafe { IxgbeVf::new(VAddr::from(regs.as_mut_ptr() as u64)) };
+
+        vf.map_vector(VfCause::Rx(0), 0).unwrap();
+        vf.map_vector(VfCause::Tx(0), 0).unwrap();
+        vf.map_vector(VfCause::Rx(3), 1).unwrap();
+        vf.map_vector(VfCause::Tx(3), 1).unwrap();
+        vf.map_vector(VfCause::Mailbox, 2).unwrap();
+        assert!(matches!(
+            vf.map_vector(VfCause::Mailbox, MAX_VF_VECTORS),
+            Err(IxgbeVfError::InvalidVector { vector: 3 })
+        ));
+        assert!(matches!(
+            vf.map_vector(VfCause::Tx(8), 0),
+            Err(IxgbeVfError::InvalidQueue { queue: 8 })
+        ));
+        assert_eq!(reg(&regs, vtivar(0)), 0x0000_8080);
+        // Queue 3 is the odd queue of the second register
+        assert_eq!(reg(&regs, vtivar(3)), 0x8181_0000);
+        assert_eq!(reg(&regs, VTIVAR_MISC), 0x82);
+
+        vf.enable_vectors(0b011);
+        vf.enable_vectors(0b100 | 1 << 8);
+        assert_eq!(reg(&regs, VTEIAM), 0b111);
+        assert_eq!(reg(&regs, VTEIAC), 0b111);
+        assert_eq!(reg(&regs, VTEIMS), 0b100);
+        vf.disable_vectors(!0);
+        assert_eq!(reg(&regs, VTEIMC), 0b111);
+    }
+
+    #[test]
+    fn reset_in_progress() {
+        let mock = MockPf::start(1 << VFMAILBOX_RSTI, pf);
+        let mut vf = mock.vf();
+        assert!(matches!(vf.reset(), Err(IxgbeVfError::ResetInProgress)));
+    }
+}
@@ -1,2 +1,150 @@
+use custom_error::custom_error;
+
 pub mod csum;
+pub mod device;
+pub mod flow;
+pub mod gso;
+pub mod i40e;
+pub mod ixgbevf;
+pub mod nvm;
+pub mod pause;
+pub mod phy;
+pub mod ptp;
 pub mod rss;
+pub mod sfp;
+
+pub use device::NetworkDevice;
+pub use flow::FlowSteering;
+pub use nvm::Nvm;
+pub use ptp::HwTimestamp;
+
+/// An Ethernet MAC address.
+pub type MacAddress = [u8; 6];
+
+/// Length of the Ethernet header.
+pub const ETH_HLEN: usize = 14;
+/// Length of an 802.1Q VLAN tag.
+pub const VLAN_HLEN: usize = 4;
+/// Length of the frame check sequence.
+pub const ETH_FCS_LEN: usize = 4;
+/// Smallest MTU an IPv4 host must support.
+pub const ETH_MIN_MTU: u16 = 68;
+/// Default Ethernet MTU.
+pub const ETH_DATA_LEN: u16 = 1500;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub NetError
+    MtuOutOfRange{mtu: u16, min: u16, max: u16} = "MTU {mtu} is outside of the supported range {min}..={max}",
+}
+
+/// Size of an RX buffer that holds a (VLAN tagged) frame with `mtu` bytes
+/// of payload.
+pub fn rx_frame_size(mtu: u16) -> usize {
+    mtu as usize + ETH_HLEN + VLAN_HLEN + ETH_FCS_LEN
+}
+
+/// MTU configuration of a NIC.
+///
+/// Implementations check `mtu` with [`Mtu::validate_mtu`] and resize their
+/// RX buffers (see [`rx_frame_size`] and
+/// [`IOBufPool::set_buf_len`](crate::iomem::IOBufPool::set_buf_len)) in
+/// [`Mtu::set_mtu`].
+pub trait Mtu {
+    type Error: From<NetError>;
+
+    fn mtu(&self) -> u16;
+
+    /// Largest MTU the hardware supports.
+    fn max_mtu(&self) -> u16;
+
+    fn min_mtu(&self) -> u16 {
+        ETH_MIN_MTU
+    }
+
+    fn validate_mtu(&self, mtu: u16) -> Result<(), NetError> {
+        let (min, max) = (self.min_mtu(), self.max_mtu());
+        if (min..=max).contains(&mtu) {
+            Ok(())
+        } else {
+            Err(NetError::MtuOutOfRange { mtu, min, max })
+        }
+    }
+
+    fn set_mtu(&mut self, mtu: u16) -> Result<(), Self::Error>;
+}
+
+/// Receive filtering of a NIC.
+pub trait RxFilter {
+    type Error;
+
+    /// Receive all frames regardless of their destination.
+    fn set_promiscuous(&mut self, enable: bool) -> Result<(), Self::Error>;
+
+    /// Receive all multicast frames.
+    fn set_all_multicast(&mut self, enable: bool) -> Result<(), Self::Error>;
+
+    /// Replaces the unicast and multicast addresses frames are accepted for
+    /// (in addition to the primary MAC address).
+    fn set_mac_filter(
+        &mut self,
+        unicast: &[MacAddress],
+        multicast: &[MacAddress],
+    ) -> Result<(), Self::Error>;
+
+    /// Accept frames tagged with VLAN `vid`.
+    fn add_vlan(&mut self, vid: u16) -> Result<(), Self::Error>;
+
+    /// Stop accepting frames tagged with VLAN `vid`.
+    fn remove_vlan(&mut self, vid: u16) -> Result<(), Self::Error>;
+}
+
+/// NICs with multiple TX/RX queue pairs.
+pub trait MultiQueue {
+    type Error;
+
+    /// Maximum number of queue pairs supported by the device.
+    fn max_queue_pairs(&self) -> u16;
+
+    /// Number of queue pairs the device currently steers traffic to.
+    fn queue_pairs(&self) -> u16;
+
+    /// Makes the device use queue pairs `0..n`.
+    fn set_queue_pairs(&mut self, n: u16) -> Result<(), Self::Error>;
+}
+
+/// IEEE 802.3x pause frame (link-level flow control) settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PauseConfig {
+    /// Honor pause frames received from the link partner.
+    pub rx_pause: bool,
+    /// Send pause frames when the RX buffer fills up.
+    pub tx_pause: bool,
+    /// Negotiate pause settings with the link partner.
+    pub autoneg: bool,
+    /// RX buffer fill level (in bytes) at which an XOFF frame is sent.
+    pub high_water: u32,
+    /// RX buffer fill level (in bytes) at which an XON frame is sent.
+    pub low_water: u32,
+    /// Pause time requested in XOFF frames (in 512 bit times).
+    pub pause_time: u16,
+}
+
+impl PauseConfig {
+    /// Whether the thresholds are consistent (`low_water < high_water`).
+    pub fn is_valid(&self) -> bool {
+        !self.tx_pause || self.low_water < self.high_water
+    }
+}
+
+/// Configuration of pause frames (see [`pause::IxgbePause`] for ixgbe).
+pub trait FlowControl {
+    type Error;
+
+    fn pause_config(&self) -> PauseConfig;
+
+    /// Applies `config`; implementations reject invalid thresholds (see
+    /// [`PauseConfig::is_valid`]).
+    fn set_pause_config(&mut self, config: PauseConfig) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,211 @@
+//! Access to the EEPROM/NVM of a NIC (MAC address, board configuration).
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use crate::arch::VAddr;
+
+use super::MacAddress;
+
+/// Intel NICs: the words up to (and including) the checksum word sum up to
+/// this value.
+pub const INTEL_NVM_CHECKSUM: u16 = 0xbaba;
+/// Intel NICs: index of the checksum word.
+pub const INTEL_NVM_CHECKSUM_WORD: usize = 0x3f;
+
+/// Maximum number of polls of EERD before giving up.
+const EERD_POLL_LIMIT: usize = 100_000;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub NvmError
+    Timeout = "timed out waiting for the NVM",
+    OutOfRange = "the word offset is beyond the end of the NVM",
+    Unsupported = "the NVM doesn't support this operation",
+}
+
+/// Word-wise access to the NVM of a device.
+pub trait Nvm {
+    /// Size of the NVM in 16-bit words.
+    fn word_count(&self) -> usize;
+
+    fn read_word(&mut self, offset: usize) -> Result<u16, NvmError>;
+
+    /// Writes a word.
+    ///
+    /// Writing the NVM can brick the device, callers should know exactly
+    /// what they change and update the checksum afterwards.
+    fn write_word(&mut self, offset: usize, value: u16) -> Result<(), NvmError>;
+
+    fn read(&mut self, offset: usize, words: &mut [u16]) -> Result<(), NvmError> {
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = self.read_word(offset + i)?;
+        }
+        Ok(())
+    }
+
+    /// Verifies the checksum using the Intel scheme (see
+    /// [`INTEL_NVM_CHECKSUM`]).
+    fn verify_checksum(&mut self) -> Result<bool, NvmError> {
+        let mut sum = 0u16;
+        for offset in 0..=INTEL_NVM_CHECKSUM_WORD {
+            sum = sum.wrapping_add(self.read_word(offset)?);
+        }
+        Ok(sum == INTEL_NVM_CHECKSUM)
+    }
+
+    /// Recomputes and writes the checksum word (Intel scheme).
+    fn update_checksum(&mut self) -> Result<(), NvmError> {
+        let mut sum = 0u16;
+        for offset in 0..INTEL_NVM_CHECKSUM_WORD {
+            sum = sum.wrapping_add(self.read_word(offset)?);
+        }
+        self.write_word(
+            INTEL_NVM_CHECKSUM_WORD,
+            INTEL_NVM_CHECKSUM.wrapping_sub(sum),
+        )
+    }
+
+    /// The MAC address stored in words 0..3 (Intel layout).
+    fn mac_address(&mut self) -> Result<MacAddress, NvmError> {
+        let mut words = [0u16; 3];
+        self.read(0, &mut words)?;
+        let mut mac = [0u8; 6];
+        for (i, word) in words.iter().enumerate() {
+            mac[2 * i..2 * i + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(mac)
+    }
+}
+
+/// Layout of the EEPROM Read register.
+#[derive(Debug, Clone, Copy)]
+struct EerdLayout {
+    /// Offset of EERD in the register BAR.
+    register: usize,
+    /// Bit position of the word address.
+    addr_shift: usize,
+    /// The DONE bit.
+    done: usize,
+}
+
+const E1000_EERD: EerdLayout = EerdLayout {
+    register: 0x14,
+    addr_shift: 8,
+    done: 4,
+};
+
+const IXGBE_EERD: EerdLayout = EerdLayout {
+    register: 0x10014,
+    addr_shift: 2,
+    done: 1,
+};
+
+/// Reads the NVM through the EEPROM Read register (EERD) of e1000 (8254x
+/// and later) and ixgbe (82599 and later) NICs.
+///
+/// Writing isn't supported, it requires device-specific flash
+/// programming sequences.
+#[derive(Debug)]
+pub struct Eerd {
+    regs: VAddr,
+    layout: EerdLayout,
+    words: usize,
+}
+
+impl Eerd {
+    /// # Safety
+    /// `regs` must be the mapped register BAR of an e1000 NIC with an NVM of
+    /// `words` words.
+    pub unsafe fn e1000(regs: VAddr, words: usize) -> Eerd {
+        Eerd {
+            regs,
+            layout: E1000_EERD,
+            words,
+        }
+    }
+
+    /// # Safety
+    /// `regs` must be the mapped register BAR of an ixgbe NIC with an NVM of
+    /// `words` words.
+    pub unsafe fn ixgbe(regs: VAddr, words: usize) -> Eerd {
+        Eerd {
+            regs,
+            layout: IXGBE_EERD,
+            words,
+        }
+    }
+
+    fn register(&self) -> *mut u32 {
+        (self.regs + self.layout.register).as_mut_ptr::<u32>()
+    }
+}
+
+impl Nvm for Eerd {
+    fn word_count(&self) -> usize {
+        self.words
+    }
+
+    fn read_word(&mut self, offset: usize) -> Result<u16, NvmError> {
+        if offset >= self.words {
+            return Err(NvmError::OutOfRange);
+        }
+
+        // Safety: `register` points into the register BAR (see constructors)
+        unsafe {
+            core::ptr::write_volatile(
+                self.register(),
+                ((offset as u32) << self.layout.addr_shift) | 1,
+            );
+            for _ in 0..EERD_POLL_LIMIT {
+                let eerd = core::ptr::read_volatile(self.register());
+                if eerd.get_bit(self.layout.done) {
+                    return Ok(eerd.get_bits(16..32) as u16);
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        warn!("EERD read of word {:#x} timed out", offset);
+        Err(NvmError::Timeout)
+    }
+
+    fn write_word(&mut self, _offset: usize, _value: u16) -> Result<(), NvmError> {
+        Err(NvmError::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Words([u16; 64]);
+
+    impl Nvm for Words {
+        fn word_count(&self) -> usize {
+            self.0.len()
+        }
+
+        fn read_word(&mut self, offset: usize) -> Result<u16, NvmError> {
+            self.0.get(offset).copied().ok_or(NvmError::OutOfRange)
+        }
+
+        fn write_word(&mut self, offset: usize, value: u16) -> Result<(), NvmError> {
+            *self.0.get_mut(offset).ok_or(NvmError::OutOfRange)? = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checksum_and_mac() {
+        let mut nvm = Words([0; 64]);
+        nvm.0[..3].copy_from_slice(&[0x1b00, 0x3c21, 0x5e4d]);
+        assert!(!nvm.verify_checksum().unwrap());
+        nvm.update_checksum().unwrap();
+        assert!(nvm.verify_checksum().unwrap());
+        assert_eq!(
+            nvm.mac_address().unwrap(),
+            [0x00, 0x1b, 0x21, 0x3c, 0x4d, 0x5e]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Link-level flow control (IEEE 802.3x pause frames) of ixgbe NICs.
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use crate::arch::VAddr;
+
+use super::{FlowControl, PauseConfig};
+
+/// MAC flow control register.
+const MFLCN: usize = 0x04294;
+/// Flow control configuration.
+const FCCFG: usize = 0x03d00;
+/// Transmit pause timers (of traffic classes 0 and 1).
+const FCTTV0: usize = 0x03200;
+/// Receive XON threshold of packet buffer 0.
+const FCRTL0: usize = 0x03220;
+/// Receive XOFF threshold of packet buffer 0.
+const FCRTH0: usize = 0x03260;
+/// Flow control refresh threshold.
+const FCRTV: usize = 0x032a0;
+
+/// MFLCN: discard received pause frames instead of passing them up.
+const MFLCN_DPF: usize = 1;
+/// MFLCN: honor received pause frames.
+const MFLCN_RFCE: usize = 3;
+/// MFLCN: priority flow control enables (and RFCE), cleared for link-level
+/// flow control.
+const MFLCN_RPFCE_MASK: u32 = 0x0000_0ffc;
+/// FCCFG: transmit flow control mode.
+const FCCFG_TFCE: core::ops::Range<usize> = 3..5;
+/// FCCFG: send link-level pause frames.
+const FCCFG_TFCE_802_3X: u32 = 1;
+/// FCRTL: send XON frames when the buffer drained to the threshold.
+const FCRTL_XONE: usize = 31;
+/// FCRTH: send XOFF frames when the buffer filled to the threshold.
+const FCRTH_FCEN: usize = 31;
+/// The thresholds in bytes (in units of 32 bytes).
+const FCRT_THRESHOLD_MASK: u32 = 0x0007_ffe0;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub PauseError
+    InvalidThresholds{low_water: u32, high_water: u32} = "invalid XON/XOFF thresholds {low_water}/{high_water}",
+    Unsupported = "negotiating pause frames is up to the link setup",
+}
+
+/// Pause frame configuration of an ixgbe (82599 and later) NIC without DCB,
+/// i.e., with a single RX packet buffer.
+///
+/// The thresholds are rounded down to multiples of 32 bytes. Negotiation
+/// with the link partner (`autoneg`) is done with the link and not
+/// supported here.
+#[derive(Debug)]
+pub struct IxgbePause {
+    regs: VAddr,
+}
+
+impl IxgbePause {
+    /// # Safety
+    /// `regs` must be the mapped register BAR (BAR0) of an ixgbe NIC.
+    pub unsafe fn new(regs: VAddr) -> IxgbePause {
+        IxgbePause { regs }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::read_volatile((self.regs + offset).as_ptr::<u32>()) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe { core::ptr::write_volatile((self.regs + offset).as_mut_ptr::<u32>(), value) };
+    }
+}
+
+impl FlowControl for IxgbePause {
+    type Error = PauseError;
+
+    fn pause_config(&self) -> PauseConfig {
+        PauseConfig {
+            rx_pause: self.read(MFLCN).get_bit(MFLCN_RFCE),
+            tx_pause: self.read(FCCFG).get_bits(FCCFG_TFCE) == FCCFG_TFCE_802_3X,
+            autoneg: false,
+            high_water: self.read(FCRTH0) & FCRT_THRESHOLD_MASK,
+            low_water: self.read(FCRTL0) & FCRT_THRESHOLD_MASK,
+            pause_time: self.read(FCTTV0).get_bits(0..16) as u16,
+        }
+    }
+
+    fn set_pause_config(&mut self, config: PauseConfig) -> Result<(), PauseError> {
+        if config.autoneg {
+            return Err(PauseError::Unsupported);
+        }
+        if config.tx_pause && (!config.is_valid() || config.high_water > FCRT_THRESHOLD_MASK) {
+            return Err(PauseError::InvalidThresholds {
+                low_water: config.low_water,
+                high_water: config.high_water,
+            });
+        }
+
+        let mut mflcn = self.read(MFLCN) & !MFLCN_RPFCE_MASK;
+        mflcn.set_bit(MFLCN_DPF, true);
+        mflcn.set_bit(MFLCN_RFCE, config.rx_pause);
+        self.write(MFLCN, mflcn);
+
+        let mut fccfg = self.read(FCCFG);
+        fccfg.set_bits(
+            FCCFG_TFCE,
+            if config.tx_pause {
+                FCCFG_TFCE_802_3X
+            } else {
+                0
+            },
+        );
+        self.write(FCCFG, fccfg);
+
+        let (mut fcrtl, mut fcrth) = (0, 0);
+        if config.tx_pause {
+            fcrtl = config.low_water & FCRT_THRESHOLD_MASK;
+            fcrtl.set_bit(FCRTL_XONE, true);
+            fcrth = config.high_water & FCRT_THRESHOLD_MASK;
+            fcrth.set_bit(FCRTH_FCEN, true);
+        }
+        // XOFF has to be disabled before the XON threshold changes
+        self.write(FCRTH0, 0);
+        self.write(FCRTL0, fcrtl);
+        self.write(FCRTH0, fcrth);
+
+        let pause_time = config.pause_time as u32;
+        self.write(FCTTV0, pause_time << 16 | pause_time);
+        // Refresh the pause before the link partner's timer runs out
+        self.write(FCRTV, pause_time / 2);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const PAUSE: PauseConfig = PauseConfig {
+        rx_pause: true,
+        tx_pause: true,
+        autoneg: false,
+        high_water: 0x2_8000,
+        low_water: 0x2_0010,
+        pause_time: 0x680,
+    };
+
+    #[test]
+    fn valid_thresholds() {
+        assert!(PAUSE.is_valid());
+        let inverted = PauseConfig {
+            low_water: PAUSE.high_water,
+            ..PAUSE
+        };
+        assert!(!inverted.is_valid());
+        // The thresholds don't matter if no pause frames are sent
+        assert!(PauseConfig {
+            tx_pause: false,
+            ..inverted
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn program_registers() {
+        let mut regs = vec![0u32; MFLCN / 4 + 1];
+        regs[MFLCN / 4] = 0x0000_0014;
+        let mut pause = unsafe { IxgbePause::new(VAddr::from(regs.as_mut_ptr() as u64)) };
+
+        pause.set_pause_config(PAUSE).unwrap();
+        let reg = |offset: usize| unsafe { core::ptr::read_volatile(&regs[offset / 4]) };
+        // Priority flow control is off
+        assert_eq!(reg(MFLCN), 0x0000_000a);
+        assert_eq!(reg(FCCFG), 0x0000_0008);
+        assert_eq!(reg(FCRTH0), 0x8002_8000);
+        // Rounded down to 32 bytes
+        assert_eq!(reg(FCRTL0), 0x8002_0000);
+        assert_eq!(reg(FCTTV0), 0x0680_0680);
+        assert_eq!(reg(FCRTV), 0x340);
+        assert_eq!(
+            pause.pause_config(),
+            PauseConfig {
+                low_water: 0x2_0000,
+                ..PAUSE
+            }
+        );
+
+        let rx_only = PauseConfig {
+            tx_pause: false,
+            ..PAUSE
+        };
+        pause.set_pause_config(rx_only).unwrap();
+        let reg = |offset: usize| unsafe { core::ptr::read_volatile(&regs[offset / 4]) };
+        assert_eq!((reg(FCCFG), reg(FCRTH0), reg(FCRTL0)), (0, 0, 0));
+        assert!(pause.pause_config().rx_pause && !pause.pause_config().tx_pause);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let mut regs = vec![0u32; MFLCN / 4 + 1];
+        let mut pause = unsafe { IxgbePause::new(VAddr::from(regs.as_mut_ptr() as u64)) };
+
+        let inverted = PauseConfig {
+            low_water: 0x3_0000,
+            ..PAUSE
+        };
+        assert!(matches!(
+            pause.set_pause_config(inverted),
+            Err(PauseError::InvalidThresholds { .. })
+        ));
+        let too_high = PauseConfig {
+            high_water: 0x8_0000,
+            ..PAUSE
+        };
+        assert!(matches!(
+            pause.set_pause_config(too_high),
+            Err(PauseError::InvalidThresholds { .. })
+        ));
+        let autoneg = PauseConfig {
+            autoneg: true,
+            ..PAUSE
+        };
+        assert!(matches!(
+            pause.set_pause_config(autoneg),
+            Err(PauseError::Unsupported)
+        ));
+        // Nothing was written
+        assert!(regs.iter().all(|reg| *reg == 0));
+    }
+}
@@ -0,0 +1,545 @@
+//! MDIO buses and IEEE 802.3 PHYs.
+//!
+//! MACs talk to external PHYs through a two-wire management interface
+//! (MDIO), either with clause 22 frames (32 PHYs with 32 registers each) or
+//! clause 45 frames (adding 32 MMDs with 65536 registers each). [`MdioBus`]
+//! abstracts the MAC's MDIO controller, [`Phy`] drives a PHY through the
+//! standard registers: reset, autonegotiation, forced modes and link
+//! polling.
+
+use core::time::Duration;
+
+use bit_field::BitField;
+use bitflags::bitflags;
+use custom_error::custom_error;
+
+use crate::arch::VAddr;
+use crate::poll::poll_until;
+
+use super::device::LinkState;
+
+/// Clause 22 registers.
+pub const MII_BMCR: u8 = 0x00;
+pub const MII_BMSR: u8 = 0x01;
+pub const MII_PHYSID1: u8 = 0x02;
+pub const MII_PHYSID2: u8 = 0x03;
+pub const MII_ADVERTISE: u8 = 0x04;
+pub const MII_LPA: u8 = 0x05;
+pub const MII_CTRL1000: u8 = 0x09;
+pub const MII_STAT1000: u8 = 0x0a;
+pub const MII_MMD_CTRL: u8 = 0x0d;
+pub const MII_MMD_DATA: u8 = 0x0e;
+
+/// BMCR bits.
+const BMCR_SPEED1000: usize = 6;
+const BMCR_FULL_DUPLEX: usize = 8;
+const BMCR_ANRESTART: usize = 9;
+const BMCR_ANENABLE: usize = 12;
+const BMCR_SPEED100: usize = 13;
+const BMCR_RESET: usize = 15;
+
+/// BMSR bits.
+const BMSR_LINK: usize = 2;
+const BMSR_ANEG_COMPLETE: usize = 5;
+const BMSR_EXTENDED_STATUS: usize = 8;
+
+/// MMD access control: function "data, no post increment".
+const MMD_CTRL_DATA: u16 = 0x4000;
+
+/// Clause 45 devices and registers.
+pub const MMD_PMA_PMD: u8 = 1;
+pub const MMD_PCS: u8 = 3;
+pub const MMD_AN: u8 = 7;
+const MDIO_CTRL1: u16 = 0;
+const MDIO_STAT1: u16 = 1;
+
+/// Time a PHY reset may take.
+const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub MdioError
+    Timeout = "timed out waiting for the MDIO controller",
+    NoPhy{addr: u8} = "no PHY responds at address {addr}",
+    Unsupported = "the bus doesn't support this access",
+    ResetTimeout = "the PHY didn't come out of reset",
+}
+
+/// An MDIO controller.
+pub trait MdioBus {
+    /// Reads register `reg` of PHY `phy` (clause 22).
+    fn read(&mut self, phy: u8, reg: u8) -> Result<u16, MdioError>;
+
+    fn write(&mut self, phy: u8, reg: u8, value: u16) -> Result<(), MdioError>;
+
+    /// Reads register `reg` of MMD `devad` (clause 45).
+    ///
+    /// Controllers without clause 45 frames go through the MMD access
+    /// registers of the PHY (clause 22 registers 13 and 14).
+    fn read_c45(&mut self, phy: u8, devad: u8, reg: u16) -> Result<u16, MdioError> {
+        self.write(phy, MII_MMD_CTRL, devad as u16)?;
+        self.write(phy, MII_MMD_DATA, reg)?;
+        self.write(phy, MII_MMD_CTRL, MMD_CTRL_DATA | devad as u16)?;
+        self.read(phy, MII_MMD_DATA)
+    }
+
+    fn write_c45(&mut self, phy: u8, devad: u8, reg: u16, value: u16) -> Result<(), MdioError> {
+        self.write(phy, MII_MMD_CTRL, devad as u16)?;
+        self.write(phy, MII_MMD_DATA, reg)?;
+        self.write(phy, MII_MMD_CTRL, MMD_CTRL_DATA | devad as u16)?;
+        self.write(phy, MII_MMD_DATA, value)
+    }
+}
+
+/// MDI Control register of e1000 (8254x and later) NICs.
+const E1000_MDIC: usize = 0x00020;
+const MDIC_OP_WRITE: u32 = 0b01;
+const MDIC_OP_READ: u32 = 0b10;
+const MDIC_READY: usize = 28;
+const MDIC_ERROR: usize = 30;
+/// Maximum number of polls of MDIC before giving up.
+const MDIC_POLL_LIMIT: usize = 100_000;
+
+/// The MDIO controller of e1000 NICs (MDIC register, clause 22 only).
+#[derive(Debug)]
+pub struct E1000Mdic {
+    regs: VAddr,
+}
+
+impl E1000Mdic {
+    /// # Safety
+    /// `regs` must be the mapped register BAR of an e1000 NIC.
+    pub unsafe fn new(regs: VAddr) -> E1000Mdic {
+        E1000Mdic { regs }
+    }
+
+    fn transaction(&mut self, phy: u8, reg: u8, op: u32, data: u16) -> Result<u16, MdioError> {
+        let mdic = (self.regs + E1000_MDIC).as_mut_ptr::<u32>();
+        let mut cmd = data as u32;
+        cmd.set_bits(16..21, reg as u32);
+        cmd.set_bits(21..26, phy as u32);
+        cmd.set_bits(26..28, op);
+
+        // Safety: `regs` points to the register BAR (see `new`)
+        unsafe {
+            core::ptr::write_volatile(mdic, cmd);
+            for _ in 0..MDIC_POLL_LIMIT {
+                let value = core::ptr::read_volatile(mdic);
+                if value.get_bit(MDIC_READY) {
+                    if value.get_bit(MDIC_ERROR) {
+                        return Err(MdioError::NoPhy { addr: phy });
+                    }
+                    return Ok(value as u16);
+                }
+                core::hint::spin_loop();
+            }
+        }
+        Err(MdioError::Timeout)
+    }
+}
+
+impl MdioBus for E1000Mdic {
+    fn read(&mut self, phy: u8, reg: u8) -> Result<u16, MdioError> {
+        self.transaction(phy, reg, MDIC_OP_READ, 0)
+    }
+
+    fn write(&mut self, phy: u8, reg: u8, value: u16) -> Result<(), MdioError> {
+        self.transaction(phy, reg, MDIC_OP_WRITE, value)
+            .map(|_value| ())
+    }
+}
+
+bitflags! {
+    /// Link modes a PHY can advertise.
+    pub struct LinkModes: u32 {
+        const M10_HALF = 1 << 0;
+        const M10_FULL = 1 << 1;
+        const M100_HALF = 1 << 2;
+        const M100_FULL = 1 << 3;
+        const M1000_HALF = 1 << 4;
+        const M1000_FULL = 1 << 5;
+        const PAUSE = 1 << 6;
+        const ASYM_PAUSE = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// Deviations of PHYs from the standard.
+    pub struct PhyQuirks: u32 {
+        /// Changes of the advertisement only take effect after a soft reset.
+        const RESET_AFTER_ADVERTISE = 1 << 0;
+        /// Doesn't do 1000BASE-T although it claims to (or isn't wired
+        /// for it).
+        const NO_GIGABIT = 1 << 1;
+    }
+}
+
+/// Known PHYs (ID without the revision, quirks).
+const PHY_QUIRKS: &[(u32, PhyQuirks)] = &[
+    // Marvell 88E1111
+    (0x0141_0cc0, PhyQuirks::RESET_AFTER_ADVERTISE),
+    // Marvell 88E1510/88E1512
+    (0x0141_0dd0, PhyQuirks::RESET_AFTER_ADVERTISE),
+];
+
+/// Mask of the PHY ID without the revision.
+const PHY_ID_MASK: u32 = 0xffff_fff0;
+
+/// A PHY on an MDIO bus.
+pub struct Phy<B> {
+    bus: B,
+    addr: u8,
+    id: u32,
+    clause45: bool,
+    quirks: PhyQuirks,
+    last_state: LinkState,
+}
+
+impl<B: MdioBus> Phy<B> {
+    /// Identifies the (clause 22) PHY at `addr`.
+    pub fn probe(mut bus: B, addr: u8) -> Result<Phy<B>, MdioError> {
+        let id = (bus.read(addr, MII_PHYSID1)? as u32) << 16 | bus.read(addr, MII_PHYSID2)? as u32;
+        if id == 0 || id == u32::MAX {
+            return Err(MdioError::NoPhy { addr });
+        }
+        let quirks = PHY_QUIRKS
+            .iter()
+            .find(|(known, _quirks)| *known == id & PHY_ID_MASK)
+            .map_or(PhyQuirks::empty(), |(_id, quirks)| *quirks);
+        Ok(Phy {
+            bus,
+            addr,
+            id,
+            clause45: false,
+            quirks,
+            last_state: LinkState::Down,
+        })
+    }
+
+    /// Identifies the clause 45 PHY (e.g., a 10GBASE-T PHY) at `addr`.
+    ///
+    /// Only reset and link polling are supported for these.
+    pub fn probe_c45(mut bus: B, addr: u8) -> Result<Phy<B>, MdioError> {
+        let id = (bus.read_c45(addr, MMD_PMA_PMD, 2)? as u32) << 16
+            | bus.read_c45(addr, MMD_PMA_PMD, 3)? as u32;
+        if id == 0 || id == u32::MAX {
+            return Err(MdioError::NoPhy { addr });
+        }
+        Ok(Phy {
+            bus,
+            addr,
+            id,
+            clause45: true,
+            quirks: PhyQuirks::empty(),
+            last_state: LinkState::Down,
+        })
+    }
+
+    /// Probes the addresses 0..32 for a clause 22 PHY.
+    pub fn scan(mut bus: B) -> Result<Phy<B>, MdioError> {
+        for addr in 0..32 {
+            match bus.read(addr, MII_PHYSID1) {
+                Ok(id) if id != 0 && id != u16::MAX => return Phy::probe(bus, addr),
+                _ => continue,
+            }
+        }
+        Err(MdioError::NoPhy { addr: 0 })
+    }
+
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    /// OUI, model and revision (PHYSID1 and PHYSID2).
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn quirks(&self) -> PhyQuirks {
+        self.quirks
+    }
+
+    /// Adds quirks (e.g., known from the board).
+    pub fn set_quirks(&mut self, quirks: PhyQuirks) {
+        self.quirks |= quirks;
+    }
+
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    pub fn read(&mut self, reg: u8) -> Result<u16, MdioError> {
+        self.bus.read(self.addr, reg)
+    }
+
+    pub fn write(&mut self, reg: u8, value: u16) -> Result<(), MdioError> {
+        self.bus.write(self.addr, reg, value)
+    }
+
+    fn modify(&mut self, reg: u8, f: impl FnOnce(&mut u16)) -> Result<(), MdioError> {
+        let mut value = self.read(reg)?;
+        f(&mut value);
+        self.write(reg, value)
+    }
+
+    /// Soft-resets the PHY and waits until it's done.
+    pub fn reset(&mut self) -> Result<(), MdioError> {
+        if self.clause45 {
+            let mut ctrl = self.bus.read_c45(self.addr, MMD_PMA_PMD, MDIO_CTRL1)?;
+            ctrl.set_bit(BMCR_RESET, true);
+            self.bus
+                .write_c45(self.addr, MMD_PMA_PMD, MDIO_CTRL1, ctrl)?;
+        } else {
+            self.modify(MII_BMCR, |bmcr| {
+                bmcr.set_bit(BMCR_RESET, true);
+            })?;
+        }
+
+        let (bus, addr, clause45) = (&mut self.bus, self.addr, self.clause45);
+        let done = poll_until(
+            || {
+                let ctrl = if clause45 {
+                    bus.read_c45(addr, MMD_PMA_PMD, MDIO_CTRL1)
+                } else {
+                    bus.read(addr, MII_BMCR)
+                };
+                ctrl.is_ok_and(|ctrl| !ctrl.get_bit(BMCR_RESET))
+            },
+            RESET_TIMEOUT,
+        );
+        if done {
+            Ok(())
+        } else {
+            Err(MdioError::ResetTimeout)
+        }
+    }
+
+    /// The modes the PHY supports (from BMSR and the extended status).
+    pub fn supported(&mut self) -> Result<LinkModes, MdioError> {
+        if self.clause45 {
+            return Err(MdioError::Unsupported);
+        }
+        let bmsr = self.read(MII_BMSR)?;
+        let mut modes = LinkModes::PAUSE | LinkModes::ASYM_PAUSE;
+        modes.set(LinkModes::M10_HALF, bmsr.get_bit(11));
+        modes.set(LinkModes::M10_FULL, bmsr.get_bit(12));
+        modes.set(LinkModes::M100_HALF, bmsr.get_bit(13));
+        modes.set(LinkModes::M100_FULL, bmsr.get_bit(14));
+        if bmsr.get_bit(BMSR_EXTENDED_STATUS) && !self.quirks.contains(PhyQuirks::NO_GIGABIT) {
+            // Extended status register
+            let estatus = self.read(0x0f)?;
+            modes.set(LinkModes::M1000_HALF, estatus.get_bit(12));
+            modes.set(LinkModes::M1000_FULL, estatus.get_bit(13));
+        }
+        Ok(modes)
+    }
+
+    /// Advertises `modes` (limited to the supported ones) and restarts
+    /// autonegotiation.
+    pub fn start_autoneg(&mut self, modes: LinkModes) -> Result<(), MdioError> {
+        let modes = modes & self.supported()?;
+        self.modify(MII_ADVERTISE, |adv| {
+            adv.set_bit(5, modes.contains(LinkModes::M10_HALF));
+            adv.set_bit(6, modes.contains(LinkModes::M10_FULL));
+            adv.set_bit(7, modes.contains(LinkModes::M100_HALF));
+            adv.set_bit(8, modes.contains(LinkModes::M100_FULL));
+            adv.set_bit(10, modes.contains(LinkModes::PAUSE));
+            adv.set_bit(11, modes.contains(LinkModes::ASYM_PAUSE));
+        })?;
+        if self.read(MII_BMSR)?.get_bit(BMSR_EXTENDED_STATUS) {
+            self.modify(MII_CTRL1000, |ctrl| {
+                ctrl.set_bit(8, modes.contains(LinkModes::M1000_HALF));
+                ctrl.set_bit(9, modes.contains(LinkModes::M1000_FULL));
+            })?;
+        }
+        if self.quirks.contains(PhyQuirks::RESET_AFTER_ADVERTISE) {
+            self.reset()?;
+        }
+
+        self.modify(MII_BMCR, |bmcr| {
+            bmcr.set_bit(BMCR_ANENABLE, true);
+            bmcr.set_bit(BMCR_ANRESTART, true);
+        })
+    }
+
+    /// Disables autonegotiation and forces `speed` (10, 100 or 1000 Mbit/s)
+    /// and duplex.
+    pub fn force(&mut self, speed: u32, full_duplex: bool) -> Result<(), MdioError> {
+        if self.clause45 || !matches!(speed, 10 | 100 | 1000) {
+            return Err(MdioError::Unsupported);
+        }
+        self.modify(MII_BMCR, |bmcr| {
+            bmcr.set_bit(BMCR_ANENABLE, false);
+            bmcr.set_bit(BMCR_SPEED100, speed == 100);
+            bmcr.set_bit(BMCR_SPEED1000, speed == 1000);
+            bmcr.set_bit(BMCR_FULL_DUPLEX, full_duplex);
+        })
+    }
+
+    /// Reads the current link state.
+    pub fn link_state(&mut self) -> Result<LinkState, MdioError> {
+        if self.clause45 {
+            return self.link_state_c45();
+        }
+
+        // The link bit latches low, the first read returns whether it
+        // went down since the last read
+        self.read(MII_BMSR)?;
+        let bmsr = self.read(MII_BMSR)?;
+        if !bmsr.get_bit(BMSR_LINK) {
+            return Ok(LinkState::Down);
+        }
+
+        let bmcr = self.read(MII_BMCR)?;
+        if !bmcr.get_bit(BMCR_ANENABLE) {
+            let speed = match (bmcr.get_bit(BMCR_SPEED1000), bmcr.get_bit(BMCR_SPEED100)) {
+                (true, _) => 1000,
+                (false, true) => 100,
+                (false, false) => 10,
+            };
+            let full_duplex = bmcr.get_bit(BMCR_FULL_DUPLEX);
+            return Ok(LinkState::Up { speed, full_duplex });
+        }
+        if !bmsr.get_bit(BMSR_ANEG_COMPLETE) {
+            return Ok(LinkState::Down);
+        }
+
+        // The best mode both sides advertise
+        if bmsr.get_bit(BMSR_EXTENDED_STATUS) {
+            let ctrl = self.read(MII_CTRL1000)?;
+            // The partner's abilities are two bits above ours
+            let common = ctrl & (self.read(MII_STAT1000)? >> 2);
+            if common.get_bit(9) {
+                return Ok(LinkState::Up {
+                    speed: 1000,
+                    full_duplex: true,
+                });
+            }
+            if common.get_bit(8) {
+                return Ok(LinkState::Up {
+                    speed: 1000,
+                    full_duplex: false,
+                });
+            }
+        }
+        let common = self.read(MII_ADVERTISE)? & self.read(MII_LPA)?;
+        let (speed, full_duplex) = if common.get_bit(8) {
+            (100, true)
+        } else if common.get_bit(7) {
+            (100, false)
+        } else if common.get_bit(6) {
+            (10, true)
+        } else {
+            (10, false)
+        };
+        Ok(LinkState::Up { speed, full_duplex })
+    }
+
+    fn link_state_c45(&mut self) -> Result<LinkState, MdioError> {
+        // Latching low as well
+        self.bus.read_c45(self.addr, MMD_PCS, MDIO_STAT1)?;
+        if !self
+            .bus
+            .read_c45(self.addr, MMD_PCS, MDIO_STAT1)?
+            .get_bit(BMSR_LINK)
+        {
+            return Ok(LinkState::Down);
+        }
+        let ctrl = self.bus.read_c45(self.addr, MMD_PMA_PMD, MDIO_CTRL1)?;
+        let speed = match (ctrl.get_bit(BMCR_SPEED100), ctrl.get_bit(BMCR_SPEED1000)) {
+            // Speed selection in bits 2..6
+            (true, true) => match ctrl.get_bits(2..6) {
+                0 => 10_000,
+                3 => 100_000,
+                2 => 40_000,
+                4 => 25_000,
+                7 => 2_500,
+                8 => 5_000,
+                _ => 0,
+            },
+            (false, true) => 1000,
+            (true, false) => 100,
+            (false, false) => 10,
+        };
+        Ok(LinkState::Up {
+            speed,
+            full_duplex: true,
+        })
+    }
+
+    /// Reads the link state, returns it if it changed since the last call.
+    pub fn poll_link(&mut self) -> Result<Option<LinkState>, MdioError> {
+        let state = self.link_state()?;
+        if state == self.last_state {
+            return Ok(None);
+        }
+        self.last_state = state;
+        Ok(Some(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A gigabit PHY at address 1 whose partner advertises everything.
+    struct Mii([u16; 32]);
+
+    impl MdioBus for Mii {
+        fn read(&mut self, phy: u8, reg: u8) -> Result<u16, MdioError> {
+            match (phy, reg) {
+                // Resets and restarts complete immediately
+                (1, MII_BMCR) => Ok(self.0[0] & !(1 << BMCR_RESET | 1 << BMCR_ANRESTART)),
+                (1, reg) => Ok(self.0[reg as usize]),
+                _ => Ok(u16::MAX),
+            }
+        }
+
+        fn write(&mut self, _phy: u8, reg: u8, value: u16) -> Result<(), MdioError> {
+            self.0[reg as usize] = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn autoneg_resolution() {
+        let mut regs = [0u16; 32];
+        // Autonegotiation enabled, 10/100 full and half, extended status,
+        // link up, autonegotiation complete
+        regs[MII_BMCR as usize] = 1 << BMCR_ANENABLE;
+        regs[MII_BMSR as usize] = 0x7924;
+        regs[MII_PHYSID1 as usize] = 0x0141;
+        regs[MII_PHYSID2 as usize] = 0x0cc2;
+        regs[MII_LPA as usize] = 0x05e1;
+        regs[MII_STAT1000 as usize] = 0x0c00;
+        regs[0x0f] = 0x3000;
+
+        let mut phy = Phy::scan(Mii(regs)).unwrap();
+        assert_eq!(phy.addr(), 1);
+        assert!(phy.quirks().contains(PhyQuirks::RESET_AFTER_ADVERTISE));
+
+        phy.start_autoneg(LinkModes::all()).unwrap();
+        assert_eq!(
+            phy.poll_link().unwrap(),
+            Some(LinkState::Up {
+                speed: 1000,
+                full_duplex: true
+            })
+        );
+        assert_eq!(phy.poll_link().unwrap(), None);
+
+        phy.start_autoneg(LinkModes::M100_FULL | LinkModes::M10_FULL)
+            .unwrap();
+        assert_eq!(
+            phy.link_state().unwrap(),
+            LinkState::Up {
+                speed: 100,
+                full_duplex: true
+            }
+        );
+    }
+}
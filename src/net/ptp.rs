@@ -0,0 +1,169 @@
+//! Hardware timestamping and the PTP hardware clock (PHC) of a NIC.
+
+/// A time of the PHC in nanoseconds since its epoch (about 292 years either
+/// way).
+pub type PhcTime = i64;
+
+/// Which received packets get a hardware timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RxTimestampFilter {
+    None,
+    /// PTP event messages (Sync, Delay_Req etc.) only.
+    PtpEvents,
+    All,
+}
+
+impl RxTimestampFilter {
+    /// Whether a received packet gets a timestamp, `ptp_event` if it's a PTP
+    /// event message.
+    pub fn matches(self, ptp_event: bool) -> bool {
+        match self {
+            RxTimestampFilter::None => false,
+            RxTimestampFilter::PtpEvents => ptp_event,
+            RxTimestampFilter::All => true,
+        }
+    }
+}
+
+/// Hardware timestamping of a NIC.
+///
+/// Timestamps of received packets are reported in
+/// [`IOBufChain::hw_timestamp`](crate::iomem::IOBufChain::hw_timestamp),
+/// timestamps of transmitted packets are collected with
+/// [`HwTimestamp::tx_timestamp`].
+pub trait HwTimestamp {
+    type Error;
+
+    /// Timestamp transmitted packets that request it.
+    fn set_tx_timestamping(&mut self, enable: bool) -> Result<(), Self::Error>;
+
+    fn set_rx_timestamping(&mut self, filter: RxTimestampFilter) -> Result<(), Self::Error>;
+
+    /// Returns the timestamp of the last transmitted packet that requested
+    /// one (if it is available yet).
+    fn tx_timestamp(&mut self) -> Option<PhcTime>;
+
+    /// Reads the current time of the PHC.
+    fn phc_time(&self) -> PhcTime;
+
+    /// Sets the PHC to `time`.
+    fn set_phc_time(&mut self, time: PhcTime) -> Result<(), Self::Error>;
+
+    /// Steps the PHC by `delta` nanoseconds.
+    fn adjust_offset(&mut self, delta: i64) -> Result<(), Self::Error>;
+
+    /// Changes the frequency of the PHC by `ppb` parts per billion.
+    fn adjust_frequency(&mut self, ppb: i64) -> Result<(), Self::Error>;
+
+    /// Largest frequency adjustment (in ppb) the hardware supports.
+    fn max_frequency_adjustment(&self) -> i64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iomem::IOBufChain;
+
+    /// A NIC whose PHC advances 1 us per packet.
+    #[derive(Default)]
+    struct MockNic {
+        time: PhcTime,
+        ppb: i64,
+        tx: bool,
+        filter: Option<RxTimestampFilter>,
+        tx_timestamp: Option<PhcTime>,
+    }
+
+    impl MockNic {
+        fn receive(&mut self, ptp_event: bool) -> IOBufChain {
+            self.time += 1000;
+            let mut chain = IOBufChain::new(0, 0).unwrap();
+            if self.filter.is_some_and(|filter| filter.matches(ptp_event)) {
+                chain.hw_timestamp = Some(self.time);
+            }
+            chain
+        }
+
+        fn transmit(&mut self) {
+            self.time += 1000;
+            if self.tx {
+                self.tx_timestamp = Some(self.time);
+            }
+        }
+    }
+
+    impl HwTimestamp for MockNic {
+        type Error = ();
+
+        fn set_tx_timestamping(&mut self, enable: bool) -> Result<(), ()> {
+            self.tx = enable;
+            Ok(())
+        }
+
+        fn set_rx_timestamping(&mut self, filter: RxTimestampFilter) -> Result<(), ()> {
+            self.filter = Some(filter);
+            Ok(())
+        }
+
+        fn tx_timestamp(&mut self) -> Option<PhcTime> {
+            self.tx_timestamp.take()
+        }
+
+        fn phc_time(&self) -> PhcTime {
+            self.time
+        }
+
+        fn set_phc_time(&mut self, time: PhcTime) -> Result<(), ()> {
+            self.time = time;
+            Ok(())
+        }
+
+        fn adjust_offset(&mut self, delta: i64) -> Result<(), ()> {
+            self.time += delta;
+            Ok(())
+        }
+
+        fn adjust_frequency(&mut self, ppb: i64) -> Result<(), ()> {
+            match ppb.abs() <= self.max_frequency_adjustment() {
+                true => {
+                    self.ppb = ppb;
+                    Ok(())
+                }
+                false => Err(()),
+            }
+        }
+
+        fn max_frequency_adjustment(&self) -> i64 {
+            1_000_000
+        }
+    }
+
+    #[test]
+    fn rx_timestamps() {
+        let mut nic = MockNic::default();
+        assert_eq!(nic.receive(true).hw_timestamp, None);
+
+        // 2065, past where 32-bit seconds overflow
+        nic.set_phc_time(3_000_000_000 * 1_000_000_000).unwrap();
+        nic.set_rx_timestamping(RxTimestampFilter::PtpEvents)
+            .unwrap();
+        assert_eq!(nic.receive(false).hw_timestamp, None);
+        assert_eq!(
+            nic.receive(true).hw_timestamp,
+            Some(3_000_000_000_000_002_000)
+        );
+        nic.set_rx_timestamping(RxTimestampFilter::All).unwrap();
+        nic.adjust_offset(-2000).unwrap();
+        assert_eq!(
+            nic.receive(false).hw_timestamp,
+            Some(3_000_000_000_000_001_000)
+        );
+
+        nic.set_tx_timestamping(true).unwrap();
+        nic.transmit();
+        assert_eq!(nic.tx_timestamp(), Some(nic.phc_time()));
+        assert_eq!(nic.tx_timestamp(), None);
+        assert!(nic.adjust_frequency(-2_000_000).is_err() && nic.ppb == 0);
+    }
+}
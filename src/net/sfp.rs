@@ -0,0 +1,412 @@
+//! Pluggable optical/copper modules (SFP, QSFP).
+//!
+//! Modules describe themselves in an EEPROM on the I2C bus of their cage:
+//! SFP modules follow SFF-8472 (ID EEPROM at 0x50, diagnostics at 0x51),
+//! QSFP modules SFF-8636 (a paged memory at 0x50). [`SfpModule`] parses the
+//! identification (vendor, part number, wavelength) and the digital
+//! diagnostics (DDM: temperature, voltage, laser bias and optical power) and
+//! controls the soft TX disable. The module presence and TX disable pins are
+//! wired to the NIC and exposed by its driver as a [`ModuleCage`].
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use bit_field::BitField;
+use custom_error::custom_error;
+
+use crate::i2c::{I2cBus, I2cError};
+
+/// I2C address of the ID EEPROM (and the SFF-8636 memory).
+pub const EEPROM_ADDR: u8 = 0x50;
+/// I2C address of the SFF-8472 diagnostics.
+pub const DIAG_ADDR: u8 = 0x51;
+
+/// SFF-8472 (ID EEPROM) fields.
+const SFP_VENDOR_NAME: u8 = 20;
+const SFP_VENDOR_PN: u8 = 40;
+const SFP_VENDOR_REV: u8 = 56;
+const SFP_WAVELENGTH: u8 = 60;
+const SFP_VENDOR_SN: u8 = 68;
+const SFP_DATE_CODE: u8 = 84;
+const SFP_DIAG_TYPE: u8 = 92;
+/// SFF-8472 diagnostics.
+const SFP_DIAG_VALUES: u8 = 96;
+const SFP_STATUS_CONTROL: u8 = 110;
+/// Status/control: soft TX disable select and TX disable state.
+const SFP_SOFT_TX_DISABLE: usize = 6;
+const SFP_TX_DISABLE_STATE: usize = 7;
+
+/// SFF-8636 fields (lower page).
+const QSFP_STATUS: u8 = 2;
+const QSFP_TEMPERATURE: u8 = 22;
+const QSFP_VCC: u8 = 26;
+const QSFP_RX_POWER: u8 = 34;
+const QSFP_TX_BIAS: u8 = 42;
+const QSFP_TX_POWER: u8 = 50;
+const QSFP_TX_DISABLE: u8 = 86;
+const QSFP_PAGE_SELECT: u8 = 127;
+/// SFF-8636 fields (upper page 00h).
+const QSFP_VENDOR_NAME: u8 = 148;
+const QSFP_VENDOR_PN: u8 = 168;
+const QSFP_VENDOR_REV: u8 = 184;
+const QSFP_WAVELENGTH: u8 = 186;
+const QSFP_VENDOR_SN: u8 = 196;
+const QSFP_DATE_CODE: u8 = 212;
+/// Status: the memory is flat (has no pages).
+const QSFP_FLAT_MEM: usize = 2;
+/// Channels of a QSFP module.
+const QSFP_CHANNELS: usize = 4;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub SfpError
+    I2c{error: I2cError} = "i2c: {error}",
+    NotPresent = "no module is plugged in",
+    UnknownModule{identifier: u8} = "unsupported module type {identifier}",
+    NoDiagnostics = "the module doesn't implement (internally calibrated) diagnostics",
+}
+
+impl From<I2cError> for SfpError {
+    fn from(error: I2cError) -> Self {
+        SfpError::I2c { error }
+    }
+}
+
+/// The module type (SFF-8024 identifier).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ModuleType {
+    /// SFP/SFP+/SFP28.
+    Sfp,
+    Qsfp,
+    QsfpPlus,
+    Qsfp28,
+    Unknown(u8),
+}
+
+impl From<u8> for ModuleType {
+    fn from(identifier: u8) -> Self {
+        match identifier {
+            0x03 => ModuleType::Sfp,
+            0x0c => ModuleType::Qsfp,
+            0x0d => ModuleType::QsfpPlus,
+            0x11 => ModuleType::Qsfp28,
+            x => ModuleType::Unknown(x),
+        }
+    }
+}
+
+impl ModuleType {
+    /// Whether the module follows SFF-8636 (rather than SFF-8472).
+    pub fn is_qsfp(&self) -> bool {
+        matches!(
+            self,
+            ModuleType::Qsfp | ModuleType::QsfpPlus | ModuleType::Qsfp28
+        )
+    }
+}
+
+/// What the module says about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub module_type: ModuleType,
+    pub vendor_name: String,
+    pub part_number: String,
+    pub revision: String,
+    pub serial_number: String,
+    /// Manufacturing date as YYMMDD (plus an optional lot code).
+    pub date_code: String,
+    /// Laser wavelength in nm, None for copper (DAC) modules.
+    pub wavelength: Option<u16>,
+}
+
+/// Diagnostics of a channel (lane) of a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelDiagnostics {
+    /// Laser bias current in µA.
+    pub tx_bias: u32,
+    /// Transmitted optical power in 0.1 µW.
+    pub tx_power: u16,
+    /// Received optical power in 0.1 µW.
+    pub rx_power: u16,
+}
+
+/// Digital diagnostics (DDM) of a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// Temperature in 1/256 °C.
+    pub temperature: i16,
+    /// Supply voltage in 100 µV.
+    pub vcc: u16,
+    /// One entry per channel (one for SFP, four for QSFP).
+    pub channels: Vec<ChannelDiagnostics>,
+}
+
+impl Diagnostics {
+    /// The temperature in millidegrees Celsius.
+    pub fn temperature_millicelsius(&self) -> i32 {
+        self.temperature as i32 * 1000 / 256
+    }
+}
+
+/// The cage a module is plugged into (the pins the NIC wires to it).
+pub trait ModuleCage {
+    /// Whether a module is plugged in (MOD_ABS/ModPrsL pin).
+    fn module_present(&mut self) -> bool;
+
+    /// Drives the TX disable pin (SFP only, QSFP uses a register).
+    fn set_tx_disable(&mut self, disable: bool);
+}
+
+/// Big-endian word at `offset` of `bytes`.
+fn be16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// An ASCII field of the EEPROM without the space padding.
+fn ascii(bytes: &[u8]) -> String {
+    let s: String = bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '?'
+            }
+        })
+        .collect();
+    String::from(s.trim_end())
+}
+
+/// A module behind an I2C bus.
+pub struct SfpModule<B> {
+    bus: B,
+    module_type: ModuleType,
+}
+
+impl<B: I2cBus> SfpModule<B> {
+    /// Reads the identifier of the module on `bus`.
+    pub fn probe(mut bus: B) -> Result<SfpModule<B>, SfpError> {
+        let identifier = match bus.read_byte_data(EEPROM_ADDR, 0) {
+            Err(I2cError::Nack { .. }) => return Err(SfpError::NotPresent),
+            result => result?,
+        };
+        match ModuleType::from(identifier) {
+            ModuleType::Unknown(identifier) => Err(SfpError::UnknownModule { identifier }),
+            module_type => Ok(SfpModule { bus, module_type }),
+        }
+    }
+
+    pub fn module_type(&self) -> ModuleType {
+        self.module_type
+    }
+
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    fn read(&mut self, addr: u8, offset: u8, buf: &mut [u8]) -> Result<(), SfpError> {
+        self.bus.write_read(addr, &[offset], buf)?;
+        Ok(())
+    }
+
+    /// Selects upper page 00h of a QSFP module (unless it has a flat
+    /// memory).
+    fn select_qsfp_page0(&mut self) -> Result<(), SfpError> {
+        let status = self.bus.read_byte_data(EEPROM_ADDR, QSFP_STATUS)?;
+        if !status.get_bit(QSFP_FLAT_MEM) {
+            self.bus.write_byte_data(EEPROM_ADDR, QSFP_PAGE_SELECT, 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn info(&mut self) -> Result<ModuleInfo, SfpError> {
+        let (base, fields) = if self.module_type.is_qsfp() {
+            self.select_qsfp_page0()?;
+            (
+                QSFP_VENDOR_NAME,
+                [
+                    QSFP_VENDOR_NAME,
+                    QSFP_VENDOR_PN,
+                    QSFP_VENDOR_REV,
+                    QSFP_WAVELENGTH,
+                    QSFP_VENDOR_SN,
+                    QSFP_DATE_CODE,
+                ],
+            )
+        } else {
+            (
+                SFP_VENDOR_NAME,
+                [
+                    SFP_VENDOR_NAME,
+                    SFP_VENDOR_PN,
+                    SFP_VENDOR_REV,
+                    SFP_WAVELENGTH,
+                    SFP_VENDOR_SN,
+                    SFP_DATE_CODE,
+                ],
+            )
+        };
+        // Everything from the vendor name to the end of the date code
+        let mut eeprom = [0u8; 72];
+        self.read(EEPROM_ADDR, base, &mut eeprom)?;
+        let field = |i: usize, len: usize| {
+            let start = (fields[i] - base) as usize;
+            &eeprom[start..start + len]
+        };
+
+        let wavelength = match be16(field(3, 2), 0) {
+            0 => None,
+            // SFF-8636 encodes the wavelength in 0.05 nm
+            w if self.module_type.is_qsfp() => Some(w / 20),
+            w => Some(w),
+        };
+        Ok(ModuleInfo {
+            module_type: self.module_type,
+            vendor_name: ascii(field(0, 16)),
+            part_number: ascii(field(1, 16)),
+            revision: ascii(field(2, 2)),
+            serial_number: ascii(field(4, 16)),
+            date_code: ascii(field(5, 8)),
+            wavelength,
+        })
+    }
+
+    /// Reads the digital diagnostics.
+    ///
+    /// Only internally calibrated SFP modules are supported.
+    pub fn diagnostics(&mut self) -> Result<Diagnostics, SfpError> {
+        if self.module_type.is_qsfp() {
+            let mut lower = [0u8; (QSFP_TX_POWER - QSFP_TEMPERATURE) as usize + 2 * QSFP_CHANNELS];
+            self.read(EEPROM_ADDR, QSFP_TEMPERATURE, &mut lower)?;
+            let word = |offset: u8, channel: usize| {
+                be16(&lower, (offset - QSFP_TEMPERATURE) as usize + 2 * channel)
+            };
+            Ok(Diagnostics {
+                temperature: word(QSFP_TEMPERATURE, 0) as i16,
+                vcc: word(QSFP_VCC, 0),
+                channels: (0..QSFP_CHANNELS)
+                    .map(|ch| ChannelDiagnostics {
+                        tx_bias: word(QSFP_TX_BIAS, ch) as u32 * 2,
+                        tx_power: word(QSFP_TX_POWER, ch),
+                        rx_power: word(QSFP_RX_POWER, ch),
+                    })
+                    .collect(),
+            })
+        } else {
+            let diag_type = self.bus.read_byte_data(EEPROM_ADDR, SFP_DIAG_TYPE)?;
+            // DDM implemented and internally calibrated
+            if !diag_type.get_bit(6) || !diag_type.get_bit(5) {
+                return Err(SfpError::NoDiagnostics);
+            }
+            let mut values = [0u8; 10];
+            self.read(DIAG_ADDR, SFP_DIAG_VALUES, &mut values)?;
+            Ok(Diagnostics {
+                temperature: be16(&values, 0) as i16,
+                vcc: be16(&values, 2),
+                channels: alloc::vec![ChannelDiagnostics {
+                    tx_bias: be16(&values, 4) as u32 * 2,
+                    tx_power: be16(&values, 6),
+                    rx_power: be16(&values, 8),
+                }],
+            })
+        }
+    }
+
+    /// Whether the transmitter is disabled (by the pin or the soft TX
+    /// disable).
+    pub fn tx_disabled(&mut self) -> Result<bool, SfpError> {
+        if self.module_type.is_qsfp() {
+            Ok(self
+                .bus
+                .read_byte_data(EEPROM_ADDR, QSFP_TX_DISABLE)?
+                .get_bits(0..4)
+                != 0)
+        } else {
+            Ok(self
+                .bus
+                .read_byte_data(DIAG_ADDR, SFP_STATUS_CONTROL)?
+                .get_bit(SFP_TX_DISABLE_STATE))
+        }
+    }
+
+    /// Disables or enables the transmitter(s) through the module's control
+    /// register.
+    pub fn set_soft_tx_disable(&mut self, disable: bool) -> Result<(), SfpError> {
+        if self.module_type.is_qsfp() {
+            let value = if disable { 0x0f } else { 0x00 };
+            self.bus
+                .write_byte_data(EEPROM_ADDR, QSFP_TX_DISABLE, value)?;
+        } else {
+            let mut control = self.bus.read_byte_data(DIAG_ADDR, SFP_STATUS_CONTROL)?;
+            control.set_bit(SFP_SOFT_TX_DISABLE, disable);
+            self.bus
+                .write_byte_data(DIAG_ADDR, SFP_STATUS_CONTROL, control)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An SFP module: ID EEPROM and diagnostics.
+    struct Eeprom([[u8; 256]; 2]);
+
+    impl I2cBus for Eeprom {
+        fn write_read(&mut self, addr: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+            let page = &self.0[(addr - EEPROM_ADDR) as usize];
+            let start = write[0] as usize;
+            read.copy_from_slice(&page[start..start + read.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), I2cError> {
+            self.0[(addr - EEPROM_ADDR) as usize][data[0] as usize] = data[1];
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sfp_info_and_diagnostics() {
+        let mut id = [0u8; 256];
+        id[0] = 0x03;
+        id[20..36].copy_from_slice(b"ACME            ");
+        id[40..56].copy_from_slice(b"SFP-10G-SR      ");
+        id[60..62].copy_from_slice(&850u16.to_be_bytes());
+        id[84..92].copy_from_slice(b"210401  ");
+        id[92] = 0x60;
+        let mut diag = [0u8; 256];
+        // 35.5 °C, 3.3 V, 6 mA, 0.5 mW, 0.4 mW
+        diag[96..106]
+            .copy_from_slice(&[0x23, 0x80, 0x80, 0xe8, 0x0b, 0xb8, 0x13, 0x88, 0x0f, 0xa0]);
+
+        let mut module = SfpModule::probe(Eeprom([id, diag])).unwrap();
+        let info = module.info().unwrap();
+        assert_eq!(info.module_type, ModuleType::Sfp);
+        assert_eq!(info.vendor_name, "ACME");
+        assert_eq!(info.part_number, "SFP-10G-SR");
+        assert_eq!(info.wavelength, Some(850));
+
+        let diag = module.diagnostics().unwrap();
+        assert_eq!(diag.temperature_millicelsius(), 35_500);
+        assert_eq!(diag.vcc, 33_000);
+        assert_eq!(
+            diag.channels[0],
+            ChannelDiagnostics {
+                tx_bias: 6000,
+                tx_power: 5000,
+                rx_power: 4000
+            }
+        );
+
+        module.set_soft_tx_disable(true).unwrap();
+        assert_eq!(module.bus().0[1][110], 1 << 6);
+    }
+}
@@ -0,0 +1,112 @@
+//! Submission queue entries.
+
+use bit_field::BitField;
+
+use super::DataPointer;
+use crate::endian::{Le16, Le32, Le64};
+
+/// Opcodes of the admin command set used by the crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdminOpcode {
+    GetLogPage = 0x02,
+    Identify = 0x06,
+    AsyncEventRequest = 0x0c,
+}
+
+/// Opcodes of the NVM command set used by the crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IoOpcode {
+    Flush = 0x00,
+    Write = 0x01,
+    Read = 0x02,
+}
+
+/// A command (submission queue entry).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct Command {
+    pub opcode: u8,
+    /// Fused operation (bits 0..2) and PSDT (bits 6..8).
+    pub flags: u8,
+    pub command_id: Le16,
+    pub nsid: Le32,
+    pub cdw2: Le32,
+    pub cdw3: Le32,
+    pub mptr: Le64,
+    pub dptr: [Le64; 2],
+    pub cdw10: Le32,
+    pub cdw11: Le32,
+    pub cdw12: Le32,
+    pub cdw13: Le32,
+    pub cdw14: Le32,
+    pub cdw15: Le32,
+}
+
+crate::dma_safe!(Command {
+    opcode,
+    flags,
+    command_id,
+    nsid,
+    cdw2,
+    cdw3,
+    mptr,
+    dptr,
+    cdw10,
+    cdw11,
+    cdw12,
+    cdw13,
+    cdw14,
+    cdw15
+});
+
+impl Command {
+    pub fn admin(opcode: AdminOpcode) -> Command {
+        Command {
+            opcode: opcode as u8,
+            ..Default::default()
+        }
+    }
+
+    pub fn io(opcode: IoOpcode, nsid: u32) -> Command {
+        Command {
+            opcode: opcode as u8,
+            nsid: nsid.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Points the command to its data.
+    pub fn set_data(&mut self, data: DataPointer) {
+        self.flags.set_bits(6..8, data.psdt());
+        let [dptr1, dptr2] = data.dptr();
+        self.dptr = [dptr1.into(), dptr2.into()];
+    }
+
+    /// Identify with controller or namespace structure `cns`.
+    pub fn identify(cns: u8, nsid: u32) -> Command {
+        let mut command = Command::admin(AdminOpcode::Identify);
+        command.nsid = nsid.into();
+        command.cdw10 = (cns as u32).into();
+        command
+    }
+
+    /// Get Log Page `lid` (`len` bytes, a multiple of 4).
+    pub fn get_log_page(lid: u8, nsid: u32, len: usize) -> Command {
+        let dwords = (len / 4 - 1) as u32;
+        let mut cdw10 = 0u32;
+        cdw10.set_bits(0..8, lid as u32);
+        cdw10.set_bits(16..32, dwords.get_bits(0..16));
+        let mut command = Command::admin(AdminOpcode::GetLogPage);
+        command.nsid = nsid.into();
+        command.cdw10 = cdw10.into();
+        command.cdw11 = dwords.get_bits(16..32).into();
+        command
+    }
+
+    pub fn async_event_request() -> Command {
+        Command::admin(AdminOpcode::AsyncEventRequest)
+    }
+}
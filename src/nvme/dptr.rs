@@ -0,0 +1,399 @@
+//! Data pointers (PRPs and SGLs) of NVMe commands.
+//!
+//! Commands describe their data either with physical region page (PRP)
+//! entries or with a scatter gather list (SGL), and both encodings are easy
+//! to get subtly wrong: only the first PRP may start in the middle of a
+//! page and only the last may end in one, PRP lists longer than a page are
+//! chained through their last entry, and SGL segments are chained through a
+//! segment descriptor whose type depends on whether the next segment is the
+//! last one.
+//!
+//! [`build_prps`] and [`build_sgl`] turn a list of [`SgEntry`]s into a
+//! [`DataPointer`] for the command, allocating list pages from an
+//! [`IOBufPool`] whose buffers are (at least) one memory page large, e.g.
+//! `IOBufPool::new(NVME_PAGE_SIZE, NVME_PAGE_SIZE)`. Lists in buffers that
+//! don't start at a page boundary are continued in another buffer at the
+//! next boundary.
+
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+
+use super::NvmeError;
+use crate::iomem::{DmaObject, IOBuf, IOBufPool};
+
+/// Size of a PRP entry.
+const PRP_ENTRY_SIZE: usize = 8;
+/// Size of an SGL descriptor.
+const SGL_DESCRIPTOR_SIZE: usize = 16;
+
+/// A contiguous piece of a transfer, in device address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SgEntry {
+    pub addr: u64,
+    pub len: usize,
+}
+
+impl SgEntry {
+    /// The contents of `buf`.
+    pub fn from_buf(buf: &IOBuf) -> SgEntry {
+        SgEntry {
+            addr: buf.ioaddr().as_u64(),
+            len: buf.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SglDescriptorType {
+    DataBlock = 0x0,
+    Segment = 0x2,
+    LastSegment = 0x3,
+}
+
+/// An SGL descriptor (with the address sub type).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SglDescriptor {
+    pub address: u64,
+    pub length: u32,
+    pub kind: SglDescriptorType,
+}
+
+impl SglDescriptor {
+    /// The descriptor as the device reads it.
+    pub fn to_bytes(&self) -> [u8; SGL_DESCRIPTOR_SIZE] {
+        let mut bytes = [0u8; SGL_DESCRIPTOR_SIZE];
+        bytes[0..8].copy_from_slice(&self.address.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes[15] = (self.kind as u8) << 4;
+        bytes
+    }
+
+    /// A descriptor pointing to the segment with `descriptors` entries at
+    /// `address`.
+    fn segment(address: u64, descriptors: usize, last: bool) -> SglDescriptor {
+        SglDescriptor {
+            address,
+            length: (descriptors * SGL_DESCRIPTOR_SIZE) as u32,
+            kind: if last {
+                SglDescriptorType::LastSegment
+            } else {
+                SglDescriptorType::Segment
+            },
+        }
+    }
+}
+
+/// The data pointer of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DataPointer {
+    Prp { prp1: u64, prp2: u64 },
+    Sgl(SglDescriptor),
+}
+
+impl DataPointer {
+    /// The PRP or SGL for data transfer (PSDT) field of command dword 0.
+    pub fn psdt(&self) -> u8 {
+        match self {
+            DataPointer::Prp { .. } => 0b00,
+            DataPointer::Sgl(_) => 0b01,
+        }
+    }
+
+    /// The data pointer field (command dwords 6 to 9).
+    pub fn dptr(&self) -> [u64; 2] {
+        match self {
+            DataPointer::Prp { prp1, prp2 } => [*prp1, *prp2],
+            DataPointer::Sgl(descriptor) => {
+                let bytes = descriptor.to_bytes();
+                [
+                    u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+                    u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+                ]
+            }
+        }
+    }
+}
+
+/// The data pointer of a command and the list pages it refers to, which
+/// have to stay alive until the command completed.
+#[derive(Debug)]
+pub struct DataTransfer {
+    pointer: DataPointer,
+    pages: Vec<IOBuf>,
+}
+
+impl DataTransfer {
+    pub fn pointer(&self) -> DataPointer {
+        self.pointer
+    }
+
+    /// The PRP list or SGL segment pages, in chain order.
+    pub fn list_pages(&self) -> &[IOBuf] {
+        &self.pages
+    }
+
+    /// Returns the list pages to `pool` (once the command completed).
+    pub fn release(self, pool: &mut IOBufPool) {
+        for page in self.pages {
+            pool.put_buf(page);
+        }
+    }
+}
+
+fn check_page_size(page_size: usize, pool: &IOBufPool) -> Result<(), NvmeError> {
+    if !page_size.is_power_of_two() || page_size < super::NVME_PAGE_SIZE {
+        return Err(NvmeError::InvalidPageSize);
+    }
+    if pool.buf_len() < page_size {
+        return Err(NvmeError::InvalidPool);
+    }
+    Ok(())
+}
+
+/// Allocates the pages of a list with `entries` entries of `entry_size`
+/// bytes and returns them with the number of entries each holds. Lists
+/// mustn't cross memory pages, so all pages but the last hold the pointer to
+/// the next one in their last slot before the page boundary.
+fn alloc_list(
+    pool: &mut IOBufPool,
+    entries: usize,
+    entry_size: usize,
+    page_size: usize,
+) -> Result<(Vec<IOBuf>, Vec<usize>), NvmeError> {
+    let mut pages = Vec::new();
+    let mut lens = Vec::new();
+    let mut remaining = entries;
+    while remaining > 0 {
+        let mut page = pool.get_buf()?;
+        page.expand();
+        let offset = page.ioaddr().as_u64() as usize & (page_size - 1);
+        let slots = (page_size - offset) / entry_size;
+        if !offset.is_multiple_of(entry_size) || slots < 2 {
+            pool.put_buf(page);
+            pages.into_iter().for_each(|page| pool.put_buf(page));
+            return Err(NvmeError::InvalidPool);
+        }
+        let len = if remaining > slots {
+            slots - 1
+        } else {
+            remaining
+        };
+        remaining -= len;
+        pages.push(page);
+        lens.push(len);
+    }
+    Ok((pages, lens))
+}
+
+/// Splits the transfer into the memory pages PRP entries point to.
+fn prp_entries(entries: &[SgEntry], page_size: usize) -> Result<Vec<u64>, NvmeError> {
+    let mask = page_size as u64 - 1;
+    let mut prps = Vec::new();
+    let mut ended_in_page = false;
+    for (index, entry) in entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.len > 0)
+    {
+        // The first entry may start at any dword, the others have to start
+        // at a page, and only the last may end in the middle of one
+        let first = prps.is_empty();
+        if entry.addr & 0x3 != 0 || (!first && entry.addr & mask != 0) || ended_in_page {
+            return Err(NvmeError::Misaligned { index });
+        }
+        let end = entry.addr + entry.len as u64;
+        let mut page = entry.addr;
+        while page < end {
+            prps.push(page);
+            page = (page & !mask) + page_size as u64;
+        }
+        ended_in_page = end & mask != 0;
+    }
+    Ok(prps)
+}
+
+/// Describes `entries` with PRPs, allocating PRP lists from `pool` if the
+/// transfer spans more than two memory pages of `page_size` bytes.
+pub fn build_prps(
+    entries: &[SgEntry],
+    page_size: usize,
+    pool: &mut IOBufPool,
+) -> Result<DataTransfer, NvmeError> {
+    check_page_size(page_size, pool)?;
+    let prps = prp_entries(entries, page_size)?;
+    let (prp1, prp2, pages) = match prps[..] {
+        [] => return Err(NvmeError::EmptyTransfer),
+        [prp1] => (prp1, 0, Vec::new()),
+        [prp1, prp2] => (prp1, prp2, Vec::new()),
+        [prp1, ref list @ ..] => {
+            let (mut pages, lens) = alloc_list(pool, list.len(), PRP_ENTRY_SIZE, page_size)?;
+            let mut list = list;
+            for i in 0..pages.len() {
+                let next = pages.get(i + 1).map(|page| page.ioaddr().as_u64());
+                let (chunk, rest) = list.split_at(lens[i]);
+                let slots = chunk.iter().copied().chain(next);
+                for (slot, prp) in pages[i]
+                    .as_mut_slice()
+                    .chunks_exact_mut(PRP_ENTRY_SIZE)
+                    .zip(slots)
+                {
+                    slot.copy_from_slice(&prp.to_le_bytes());
+                }
+                list = rest;
+            }
+            (prp1, pages[0].ioaddr().as_u64(), pages)
+        }
+    };
+    Ok(DataTransfer {
+        pointer: DataPointer::Prp { prp1, prp2 },
+        pages,
+    })
+}
+
+/// Describes `entries` with an SGL, allocating segments from `pool` if
+/// there's more than one entry (`page_size` is the size of the pool's
+/// buffers).
+///
+/// The device has to support SGLs for the command (see the SGLS field of
+/// the controller's identify data).
+pub fn build_sgl(
+    entries: &[SgEntry],
+    page_size: usize,
+    pool: &mut IOBufPool,
+) -> Result<DataTransfer, NvmeError> {
+    check_page_size(page_size, pool)?;
+    let mut blocks = Vec::with_capacity(entries.len());
+    for (index, entry) in entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.len > 0)
+    {
+        blocks.push(SglDescriptor {
+            address: entry.addr,
+            length: u32::try_from(entry.len).map_err(|_e| NvmeError::EntryTooLarge { index })?,
+            kind: SglDescriptorType::DataBlock,
+        });
+    }
+
+    let (descriptor, pages) = match blocks[..] {
+        [] => return Err(NvmeError::EmptyTransfer),
+        [block] => (block, Vec::new()),
+        _ => {
+            let (mut pages, lens) = alloc_list(pool, blocks.len(), SGL_DESCRIPTOR_SIZE, page_size)?;
+            // Segments list their descriptors plus the pointer to the next
+            let descriptors = |i: usize| lens[i] + usize::from(i + 1 < lens.len());
+            let mut blocks = &blocks[..];
+            for i in 0..pages.len() {
+                let next = pages.get(i + 1).map(|page| {
+                    SglDescriptor::segment(
+                        page.ioaddr().as_u64(),
+                        descriptors(i + 1),
+                        i + 2 == lens.len(),
+                    )
+                });
+                let (chunk, rest) = blocks.split_at(lens[i]);
+                let slots = chunk.iter().copied().chain(next);
+                for (slot, d) in pages[i]
+                    .as_mut_slice()
+                    .chunks_exact_mut(SGL_DESCRIPTOR_SIZE)
+                    .zip(slots)
+                {
+                    slot.copy_from_slice(&d.to_bytes());
+                }
+                blocks = rest;
+            }
+            let first =
+                SglDescriptor::segment(pages[0].ioaddr().as_u64(), descriptors(0), lens.len() == 1);
+            (first, pages)
+        }
+    };
+    Ok(DataTransfer {
+        pointer: DataPointer::Sgl(descriptor),
+        pages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvme::NVME_PAGE_SIZE;
+
+    fn entry(page: &IOBuf, slot: usize, size: usize) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..size].copy_from_slice(&page.as_slice()[slot * size..(slot + 1) * size]);
+        bytes
+    }
+
+    #[test]
+    fn prps_and_sgls() {
+        let mut pool = IOBufPool::new(NVME_PAGE_SIZE, NVME_PAGE_SIZE).unwrap();
+        let sg = |addr: u64, len: usize| SgEntry { addr, len };
+
+        let transfer = build_prps(&[sg(0x10_0200, 0x1000)], NVME_PAGE_SIZE, &mut pool).unwrap();
+        assert_eq!(transfer.pointer().dptr(), [0x10_0200, 0x10_1000]);
+        assert!(transfer.list_pages().is_empty());
+        assert!(matches!(
+            build_prps(
+                &[sg(0x10_0200, 0x100), sg(0x20_0000, 0x1000)],
+                NVME_PAGE_SIZE,
+                &mut pool
+            ),
+            Err(NvmeError::Misaligned { index: 1 })
+        ));
+
+        // 600 pages: the first in PRP1, the rest in a chain of list pages
+        let transfer =
+            build_prps(&[sg(0x100_0000, 600 * 0x1000)], NVME_PAGE_SIZE, &mut pool).unwrap();
+        let pages = transfer.list_pages();
+        assert!(pages.len() >= 2);
+        assert_eq!(
+            transfer.pointer().dptr(),
+            [0x100_0000, pages[0].ioaddr().as_u64()]
+        );
+        let mut prps = Vec::new();
+        for (i, page) in pages.iter().enumerate() {
+            let slots = (NVME_PAGE_SIZE - (page.ioaddr().as_u64() as usize % NVME_PAGE_SIZE)) / 8;
+            let entries = (0..slots)
+                .map(|slot| u64::from_le_bytes(entry(page, slot, 8)[..8].try_into().unwrap()));
+            match pages.get(i + 1) {
+                Some(next) => {
+                    prps.extend(entries.clone().take(slots - 1));
+                    assert_eq!(entries.clone().nth(slots - 1), Some(next.ioaddr().as_u64()));
+                }
+                None => prps.extend(entries.take(599 - prps.len())),
+            }
+        }
+        assert!(prps
+            .iter()
+            .copied()
+            .eq((1..600).map(|page| 0x100_0000 + page * 0x1000)));
+        transfer.release(&mut pool);
+
+        let transfer = build_sgl(
+            &[sg(0x10_0001, 3), sg(0x20_0000, 0x2000)],
+            NVME_PAGE_SIZE,
+            &mut pool,
+        )
+        .unwrap();
+        let page = &transfer.list_pages()[0];
+        assert_eq!(transfer.pointer().psdt(), 1);
+        assert_eq!(
+            transfer.pointer(),
+            DataPointer::Sgl(SglDescriptor {
+                address: page.ioaddr().as_u64(),
+                length: 32,
+                kind: SglDescriptorType::LastSegment,
+            })
+        );
+        let data = SglDescriptor {
+            address: 0x20_0000,
+            length: 0x2000,
+            kind: SglDescriptorType::DataBlock,
+        };
+        assert_eq!(entry(page, 1, 16), data.to_bytes());
+    }
+}
@@ -0,0 +1,40 @@
+//! Definitions for NVMe devices.
+//!
+//! See the NVM Express Base Specification, revision 2.0.
+
+use custom_error::custom_error;
+
+use crate::iomem::IOMemError;
+
+pub mod command;
+pub mod dptr;
+pub mod namespace;
+pub mod queue;
+
+pub use command::Command;
+pub use dptr::{DataPointer, DataTransfer, SgEntry, SglDescriptor};
+pub use namespace::{AdminCommands, AsyncEvent, Namespace, NamespaceChange, Namespaces};
+pub use queue::{CompletionEntry, CompletionMode, CompletionQueue};
+
+/// The smallest memory page size (CC.MPS = 0), used by most drivers.
+pub const NVME_PAGE_SIZE: usize = 4096;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub NvmeError
+    EmptyTransfer = "the transfer doesn't contain any data",
+    Misaligned{index: usize} = "scatter-gather entry {index} isn't aligned as PRPs require",
+    EntryTooLarge{index: usize} = "scatter-gather entry {index} is too large for an SGL data block",
+    InvalidPageSize = "the memory page size isn't a power of two of at least 4 KiB",
+    InvalidPool = "the pool's buffers are too small or misaligned for PRP lists or SGL segments",
+    CommandFailed{status: u16} = "the command failed with status {status:#x}",
+    OutOfRange{lba: u64} = "the transfer at LBA {lba} is empty or beyond the end of the namespace",
+    NoInterruptVector = "the queue doesn't have an interrupt vector",
+    OutOfMemory = "out of DMA memory",
+}
+
+impl From<IOMemError> for NvmeError {
+    fn from(_e: IOMemError) -> Self {
+        NvmeError::OutOfMemory
+    }
+}
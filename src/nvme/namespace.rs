@@ -0,0 +1,387 @@
+//! Namespaces and their attach/detach events.
+//!
+//! [`Namespaces::scan`] enumerates the active namespaces of a controller and
+//! hands out a [`Namespace`] (block device handle) for each. When the
+//! controller reports a namespace attribute change through an asynchronous
+//! event, [`Namespaces::handle_event`] reads the changed namespace list and
+//! returns which namespaces were attached, detached or changed.
+
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use bit_field::BitField;
+
+use super::command::{Command, IoOpcode};
+use super::{CompletionEntry, DataPointer, NvmeError, NVME_PAGE_SIZE};
+use crate::iomem::IOBuf;
+
+/// Identify: namespace data structure of the given namespace.
+const CNS_NAMESPACE: u8 = 0x00;
+/// Identify: active namespace ID list (starting after the given ID).
+const CNS_ACTIVE_NAMESPACES: u8 = 0x02;
+/// Log page: changed namespace list.
+const LOG_CHANGED_NAMESPACES: u8 = 0x04;
+/// First entry of the changed namespace list if more than 1024 changed.
+const CHANGED_LIST_OVERFLOW: u32 = 0xffff_ffff;
+
+/// Execution of admin commands, provided by the controller driver.
+pub trait AdminCommands {
+    /// Executes `command` and waits for its completion. The implementation
+    /// points the command's data pointer to `data`, if given.
+    fn execute(
+        &mut self,
+        command: Command,
+        data: Option<&mut IOBuf>,
+    ) -> Result<CompletionEntry, NvmeError>;
+}
+
+/// Executes a command that reads a page of data.
+fn read_page(admin: &mut dyn AdminCommands, command: Command) -> Result<IOBuf, NvmeError> {
+    let layout = Layout::from_size_align(NVME_PAGE_SIZE, NVME_PAGE_SIZE).unwrap();
+    let mut buf = IOBuf::new(layout)?;
+    let completion = admin.execute(command, Some(&mut buf))?;
+    if !completion.is_success() {
+        return Err(NvmeError::CommandFailed {
+            status: completion.status.get() >> 1,
+        });
+    }
+    Ok(buf)
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// The IDs in a namespace ID list (terminated by 0).
+fn id_list(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks_exact(4)
+        .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+        .take_while(|id| *id != 0)
+}
+
+/// A namespace as a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Namespace {
+    pub id: u32,
+    /// Size in logical blocks.
+    pub blocks: u64,
+    pub block_size: u32,
+    /// Metadata bytes per block.
+    pub metadata_size: u16,
+}
+
+impl Namespace {
+    /// Parses the Identify Namespace data structure, returns None for an
+    /// inactive namespace.
+    pub fn from_identify(id: u32, data: &[u8]) -> Option<Namespace> {
+        let blocks = le_u64(data, 0);
+        if blocks == 0 {
+            return None;
+        }
+        let format = data[26].get_bits(0..4) as usize;
+        let lbaf = le_u32(data, 128 + 4 * format);
+        Some(Namespace {
+            id,
+            blocks,
+            block_size: 1 << lbaf.get_bits(16..24),
+            metadata_size: lbaf.get_bits(0..16) as u16,
+        })
+    }
+
+    /// Size in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.blocks * self.block_size as u64
+    }
+
+    fn transfer(
+        &self,
+        opcode: IoOpcode,
+        lba: u64,
+        blocks: u16,
+        data: DataPointer,
+    ) -> Result<Command, NvmeError> {
+        if blocks == 0
+            || lba
+                .checked_add(blocks as u64)
+                .is_none_or(|end| end > self.blocks)
+        {
+            return Err(NvmeError::OutOfRange { lba });
+        }
+        let mut command = Command::io(opcode, self.id);
+        command.set_data(data);
+        command.cdw10 = (lba as u32).into();
+        command.cdw11 = ((lba >> 32) as u32).into();
+        command.cdw12 = ((blocks - 1) as u32).into();
+        Ok(command)
+    }
+
+    /// A command reading `blocks` blocks starting at `lba` to `data`.
+    pub fn read(&self, lba: u64, blocks: u16, data: DataPointer) -> Result<Command, NvmeError> {
+        self.transfer(IoOpcode::Read, lba, blocks, data)
+    }
+
+    /// A command writing `blocks` blocks starting at `lba` from `data`.
+    pub fn write(&self, lba: u64, blocks: u16, data: DataPointer) -> Result<Command, NvmeError> {
+        self.transfer(IoOpcode::Write, lba, blocks, data)
+    }
+
+    pub fn flush(&self) -> Command {
+        Command::io(IoOpcode::Flush, self.id)
+    }
+}
+
+/// An asynchronous event (the result of an Asynchronous Event Request).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AsyncEvent {
+    pub event_type: u8,
+    pub info: u8,
+    /// The log page to read to clear the event.
+    pub log_page: u8,
+}
+
+impl AsyncEvent {
+    pub fn from_completion(completion: &CompletionEntry) -> AsyncEvent {
+        AsyncEvent {
+            event_type: completion.result.get().get_bits(0..3) as u8,
+            info: completion.result.get().get_bits(8..16) as u8,
+            log_page: completion.result.get().get_bits(16..24) as u8,
+        }
+    }
+
+    pub fn is_namespace_change(&self) -> bool {
+        // Notice: namespace attribute changed
+        self.event_type == 0x2 && self.info == 0x00
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NamespaceChange {
+    Attached(Namespace),
+    Detached(u32),
+    /// The namespace is still attached but its attributes (e.g., its size)
+    /// changed.
+    Changed(Namespace),
+}
+
+/// The active namespaces of a controller.
+#[derive(Debug, Default)]
+pub struct Namespaces {
+    /// Sorted by ID.
+    namespaces: Vec<Namespace>,
+}
+
+impl Namespaces {
+    /// Enumerates the active namespaces.
+    pub fn scan(admin: &mut dyn AdminCommands) -> Result<Namespaces, NvmeError> {
+        let mut namespaces = Vec::new();
+        let mut last = 0;
+        loop {
+            let list = read_page(admin, Command::identify(CNS_ACTIVE_NAMESPACES, last))?;
+            let ids: Vec<u32> = id_list(list.as_slice()).collect();
+            for id in ids.iter().copied() {
+                namespaces.extend(Namespaces::identify(admin, id)?);
+            }
+            match ids.last() {
+                Some(id) if ids.len() == NVME_PAGE_SIZE / 4 => last = *id,
+                _ => break,
+            }
+        }
+        Ok(Namespaces { namespaces })
+    }
+
+    fn identify(admin: &mut dyn AdminCommands, id: u32) -> Result<Option<Namespace>, NvmeError> {
+        let data = read_page(admin, Command::identify(CNS_NAMESPACE, id))?;
+        Ok(Namespace::from_identify(id, data.as_slice()))
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Namespace> {
+        self.namespaces
+            .binary_search_by_key(&id, |ns| ns.id)
+            .ok()
+            .map(|index| &self.namespaces[index])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Namespace> {
+        self.namespaces.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.namespaces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.namespaces.is_empty()
+    }
+
+    /// Re-identifies namespace `id` and records the change, if any.
+    fn update(
+        &mut self,
+        admin: &mut dyn AdminCommands,
+        id: u32,
+        changes: &mut Vec<NamespaceChange>,
+    ) -> Result<(), NvmeError> {
+        let namespace = Namespaces::identify(admin, id)?;
+        let change = match (
+            self.namespaces.binary_search_by_key(&id, |ns| ns.id),
+            namespace,
+        ) {
+            (Ok(index), Some(ns)) if self.namespaces[index] == ns => None,
+            (Ok(index), Some(ns)) => {
+                self.namespaces[index] = ns;
+                Some(NamespaceChange::Changed(ns))
+            }
+            (Ok(index), None) => {
+                self.namespaces.remove(index);
+                Some(NamespaceChange::Detached(id))
+            }
+            (Err(index), Some(ns)) => {
+                self.namespaces.insert(index, ns);
+                Some(NamespaceChange::Attached(ns))
+            }
+            (Err(_), None) => None,
+        };
+        changes.extend(change);
+        Ok(())
+    }
+
+    /// Handles an asynchronous event of the controller.
+    ///
+    /// For namespace attribute changes this reads the changed namespace
+    /// list (which clears the event) and updates the namespaces, other
+    /// events are ignored. The driver has to submit a new
+    /// [`Command::async_event_request`] afterwards either way.
+    pub fn handle_event(
+        &mut self,
+        admin: &mut dyn AdminCommands,
+        event: AsyncEvent,
+    ) -> Result<Vec<NamespaceChange>, NvmeError> {
+        let mut changes = Vec::new();
+        if !event.is_namespace_change() {
+            debug!("Ignoring asynchronous event {:?}", event);
+            return Ok(changes);
+        }
+
+        let log = read_page(
+            admin,
+            Command::get_log_page(LOG_CHANGED_NAMESPACES, 0xffff_ffff, NVME_PAGE_SIZE),
+        )?;
+        let ids: Vec<u32> = if le_u32(log.as_slice(), 0) == CHANGED_LIST_OVERFLOW {
+            // Too many changes to list, compare against a new scan
+            let active = Namespaces::scan(admin)?;
+            let mut ids: Vec<u32> = self.iter().chain(active.iter()).map(|ns| ns.id).collect();
+            ids.sort_unstable();
+            ids.dedup();
+            ids
+        } else {
+            id_list(log.as_slice()).collect()
+        };
+        for id in ids {
+            self.update(admin, id, &mut changes)?;
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A controller with namespaces (ID, blocks).
+    struct Controller(Vec<(u32, u64)>, Vec<u32>);
+
+    impl AdminCommands for Controller {
+        fn execute(
+            &mut self,
+            command: Command,
+            data: Option<&mut IOBuf>,
+        ) -> Result<CompletionEntry, NvmeError> {
+            let data = data.unwrap();
+            match (command.opcode, command.cdw10.get() as u8) {
+                (0x06, CNS_ACTIVE_NAMESPACES) => {
+                    let ids = self.0.iter().filter(|(id, _)| *id > command.nsid.get());
+                    for (i, (id, _)) in ids.enumerate() {
+                        data.copy_in_at(i * 4, &id.to_le_bytes())?;
+                    }
+                }
+                (0x06, CNS_NAMESPACE) => {
+                    if let Some((_, blocks)) =
+                        self.0.iter().find(|(id, _)| *id == command.nsid.get())
+                    {
+                        data.copy_in_at(0, &blocks.to_le_bytes())?;
+                        // Format 1: 4 KiB blocks, 8 bytes of metadata
+                        data.copy_in_at(26, &[1])?;
+                        data.copy_in_at(132, &0x000c_0008u32.to_le_bytes())?;
+                    }
+                }
+                (0x02, LOG_CHANGED_NAMESPACES) => {
+                    for (i, id) in self.1.iter().enumerate() {
+                        data.copy_in_at(i * 4, &id.to_le_bytes())?;
+                    }
+                }
+                _ => unreachable!(),
+            }
+            Ok(CompletionEntry::default())
+        }
+    }
+
+    #[test]
+    fn scan_and_events() {
+        let mut controller = Controller(vec![(1, 0x1000), (3, 0x2000)], Vec::new());
+        let mut namespaces = Namespaces::scan(&mut controller).unwrap();
+        assert_eq!(namespaces.len(), 2);
+        let ns = *namespaces.get(3).unwrap();
+        assert_eq!(
+            (ns.block_size, ns.metadata_size, ns.capacity()),
+            (4096, 8, 0x200_0000)
+        );
+        let dptr = DataPointer::Prp {
+            prp1: 0x1000,
+            prp2: 0,
+        };
+        let read = ns.read(0x1ff0, 0x10, dptr).unwrap();
+        assert_eq!(
+            (
+                read.opcode,
+                read.nsid.get(),
+                read.cdw10.get(),
+                read.cdw12.get()
+            ),
+            (0x02, 3, 0x1ff0, 0xf)
+        );
+        assert!(matches!(
+            ns.write(0x1ff1, 0x10, dptr),
+            Err(NvmeError::OutOfRange { lba: 0x1ff1 })
+        ));
+
+        // Namespace 1 is resized, 2 attached and 3 detached
+        controller = Controller(vec![(1, 0x1800), (2, 0x100)], vec![1, 2, 3]);
+        let completion = CompletionEntry {
+            result: 0x0004_0002.into(),
+            ..Default::default()
+        };
+        let changes = namespaces
+            .handle_event(&mut controller, AsyncEvent::from_completion(&completion))
+            .unwrap();
+        assert!(matches!(
+            changes[..],
+            [
+                NamespaceChange::Changed(Namespace {
+                    id: 1,
+                    blocks: 0x1800,
+                    ..
+                }),
+                NamespaceChange::Attached(Namespace { id: 2, .. }),
+                NamespaceChange::Detached(3),
+            ]
+        ));
+        assert!(namespaces.iter().map(|ns| ns.id).eq([1, 2]));
+    }
+}
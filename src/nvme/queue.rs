@@ -0,0 +1,294 @@
+//! Completion queues of NVMe IO queue pairs.
+//!
+//! Each [`CompletionQueue`] is either driven by its MSI-X vector or busy
+//! polled, see [`CompletionMode`]. Polling trades CPU time for latency, so
+//! latency-sensitive consumers can poll their queues while the rest of the
+//! device keeps using interrupts, and switch a queue back and forth at run
+//! time.
+
+use bit_field::BitField;
+
+use super::NvmeError;
+use crate::adminq::DmaRing;
+use crate::endian::{Le16, Le32};
+use crate::IOAddr;
+
+/// Poll budget of [`CompletionMode::polling`].
+pub const DEFAULT_POLL_BUDGET: usize = 64;
+
+/// A completion queue entry.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct CompletionEntry {
+    /// Command specific result (dword 0).
+    pub result: Le32,
+    pub reserved: Le32,
+    /// Head of the submission queue when the command completed.
+    pub sq_head: Le16,
+    pub sq_id: Le16,
+    pub command_id: Le16,
+    /// Phase tag (bit 0) and status field.
+    pub status: Le16,
+}
+
+crate::dma_safe!(CompletionEntry {
+    result,
+    reserved,
+    sq_head,
+    sq_id,
+    command_id,
+    status
+});
+
+impl CompletionEntry {
+    pub fn phase(&self) -> bool {
+        self.status.get().get_bit(0)
+    }
+
+    pub fn status_code(&self) -> u8 {
+        self.status.get().get_bits(1..9) as u8
+    }
+
+    pub fn status_code_type(&self) -> u8 {
+        self.status.get().get_bits(9..12) as u8
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status.get().get_bits(1..12) == 0
+    }
+}
+
+/// How completions of a queue are picked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CompletionMode {
+    /// Drain the queue when its MSI-X vector fires.
+    Interrupt,
+    /// Busy-poll, processing at most `budget` completions per poll.
+    Polling { budget: usize },
+}
+
+impl CompletionMode {
+    /// Polling with [`DEFAULT_POLL_BUDGET`].
+    pub const fn polling() -> CompletionMode {
+        CompletionMode::Polling {
+            budget: DEFAULT_POLL_BUDGET,
+        }
+    }
+}
+
+/// A doorbell register of a queue.
+pub trait Doorbell {
+    fn ring(&mut self, value: u16);
+}
+
+/// Offset of the doorbell of submission queue `queue` (or of its
+/// completion queue) in BAR 0, `stride` is CAP.DSTRD.
+pub fn doorbell_offset(queue: u16, completion: bool, stride: u8) -> usize {
+    0x1000 + (2 * queue as usize + usize::from(completion)) * (4 << stride)
+}
+
+/// A completion queue and its head doorbell.
+pub struct CompletionQueue<D: Doorbell> {
+    id: u16,
+    ring: DmaRing<CompletionEntry>,
+    doorbell: D,
+    head: usize,
+    /// Phase tag of entries the device posted in the current pass.
+    phase: bool,
+    vector: Option<u16>,
+    mode: CompletionMode,
+}
+
+impl<D: Doorbell> CompletionQueue<D> {
+    /// Allocates queue `id` with `len` entries. Queues that should ever run
+    /// in [`CompletionMode::Interrupt`] need an MSI-X `vector`.
+    pub fn new(
+        id: u16,
+        len: usize,
+        doorbell: D,
+        vector: Option<u16>,
+        mode: CompletionMode,
+    ) -> Result<CompletionQueue<D>, NvmeError> {
+        if mode == CompletionMode::Interrupt && vector.is_none() {
+            return Err(NvmeError::NoInterruptVector);
+        }
+        Ok(CompletionQueue {
+            id,
+            ring: DmaRing::new(len)?,
+            doorbell,
+            head: 0,
+            phase: true,
+            vector,
+            mode,
+        })
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+
+    pub fn ioaddr(&self) -> IOAddr {
+        self.ring.ioaddr()
+    }
+
+    pub fn vector(&self) -> Option<u16> {
+        self.vector
+    }
+
+    /// Command dword 11 of the Create I/O Completion Queue command
+    /// (physically contiguous, interrupts enabled if the queue has a
+    /// vector).
+    pub fn create_flags(&self) -> u32 {
+        let mut dw11 = 0u32;
+        dw11.set_bit(0, true);
+        if let Some(vector) = self.vector {
+            dw11.set_bit(1, true);
+            dw11.set_bits(16..32, vector as u32);
+        }
+        dw11
+    }
+
+    pub fn mode(&self) -> CompletionMode {
+        self.mode
+    }
+
+    /// Switches between interrupts and polling.
+    ///
+    /// Interrupts stay enabled in the controller, so the driver should mask
+    /// the queue's vector (see [`MsixVector::mask`](crate::pci::MsixVector::mask))
+    /// while polling.
+    pub fn set_mode(&mut self, mode: CompletionMode) -> Result<(), NvmeError> {
+        if mode == CompletionMode::Interrupt && self.vector.is_none() {
+            return Err(NvmeError::NoInterruptVector);
+        }
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Processes at most `budget` new completions with `f` and updates the
+    /// head doorbell.
+    ///
+    /// # Returns
+    /// The number of completions processed.
+    pub fn process(&mut self, budget: usize, mut f: impl FnMut(CompletionEntry)) -> usize {
+        let mut processed = 0;
+        while processed < budget {
+            let entry = self.ring.read(self.head);
+            if entry.phase() != self.phase {
+                break;
+            }
+            f(entry);
+            processed += 1;
+            self.head += 1;
+            if self.head == self.ring.len() {
+                self.head = 0;
+                self.phase = !self.phase;
+            }
+        }
+        if processed > 0 {
+            self.doorbell.ring(self.head as u16);
+        }
+        processed
+    }
+
+    /// Called from the queue's interrupt handler, drains the queue in
+    /// [`CompletionMode::Interrupt`] (and does nothing while polling).
+    pub fn on_interrupt(&mut self, f: impl FnMut(CompletionEntry)) -> usize {
+        match self.mode {
+            CompletionMode::Interrupt => self.process(self.ring.len(), f),
+            CompletionMode::Polling { .. } => 0,
+        }
+    }
+
+    /// Polls for completions in [`CompletionMode::Polling`] (and does
+    /// nothing in interrupt mode).
+    pub fn poll(&mut self, f: impl FnMut(CompletionEntry)) -> usize {
+        match self.mode {
+            CompletionMode::Polling { budget } => self.process(budget, f),
+            CompletionMode::Interrupt => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[derive(Default)]
+    struct Recorder(Vec<u16>);
+
+    impl Doorbell for &mut Recorder {
+        fn ring(&mut self, value: u16) {
+            self.0.push(value);
+        }
+    }
+
+    fn post(queue: &mut CompletionQueue<&mut Recorder>, slot: usize, command_id: u16, phase: bool) {
+        let entry = CompletionEntry {
+            command_id: command_id.into(),
+            status: (phase as u16).into(),
+            ..Default::default()
+        };
+        queue.ring.write(slot, entry);
+    }
+
+    #[test]
+    fn interrupt_and_polling() {
+        let mut doorbell = Recorder::default();
+        let mut queue = CompletionQueue::new(
+            1,
+            4,
+            &mut doorbell,
+            None,
+            CompletionMode::Polling { budget: 2 },
+        )
+        .unwrap();
+        assert!(matches!(
+            queue.set_mode(CompletionMode::Interrupt),
+            Err(NvmeError::NoInterruptVector)
+        ));
+        assert_eq!(queue.create_flags(), 1);
+
+        for slot in 0..3 {
+            post(&mut queue, slot, slot as u16, true);
+        }
+        let mut ids = Vec::new();
+        assert_eq!(queue.on_interrupt(|c| ids.push(c.command_id.get())), 0);
+        assert_eq!(queue.poll(|c| ids.push(c.command_id.get())), 2);
+        assert_eq!(queue.poll(|c| ids.push(c.command_id.get())), 1);
+        assert_eq!(queue.poll(|c| ids.push(c.command_id.get())), 0);
+
+        // The device wraps around and flips the phase tag
+        post(&mut queue, 3, 3, true);
+        post(&mut queue, 0, 4, false);
+        queue
+            .set_mode(CompletionMode::Polling { budget: 8 })
+            .unwrap();
+        assert_eq!(queue.poll(|c| ids.push(c.command_id.get())), 2);
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+        drop(queue);
+        assert_eq!(doorbell.0, [2, 3, 1]);
+        assert_eq!(doorbell_offset(1, true, 0), 0x100c);
+
+        let mut doorbell = Recorder::default();
+        let mut queue =
+            CompletionQueue::new(2, 4, &mut doorbell, Some(3), CompletionMode::Interrupt).unwrap();
+        assert_eq!(queue.create_flags(), 0x0003_0003);
+        for slot in 0..3 {
+            post(&mut queue, slot, slot as u16, true);
+        }
+        assert_eq!(queue.poll(|_| ()), 0);
+        assert_eq!(queue.on_interrupt(|_| ()), 3);
+    }
+}
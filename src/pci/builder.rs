@@ -0,0 +1,492 @@
+//! Bring-up of a PCI device for a driver.
+//!
+//! [`PciDriverBuilder`] bundles the steps every driver goes through before it
+//! can talk to its device: finding it on the bus, taking it away from the
+//! kernel driver (on Linux), enabling bus mastering, mapping its BARs and
+//! enabling MSI-X. The result is a [`PciDeviceHandle`].
+
+use crate::arch::{PAddr, VAddr};
+use crate::iomem::{DmaAllocator, IOBufPool, IOMemError, MapOptions, MemoryType};
+
+use alloc::vec::Vec;
+
+use super::{
+    msix, queues, quirks, scan_bus, Bar, BarIndex, DeviceId, DoorbellLayout, MsiXTableEntry,
+    MsixVector, PCIAddress, PciDevice, PciError, QueueHandle, QuirkFlags, Quirks, VendorId,
+    MAX_BARS,
+};
+
+/// Size of the largest MSI-X table (2048 entries).
+const MSIX_TABLE_MAX_SIZE: usize = 2048 * core::mem::size_of::<MsiXTableEntry>();
+
+/// How to find the device on the bus.
+#[derive(Debug, Clone, Copy)]
+enum Selector {
+    Address(PCIAddress),
+    /// The `n`th device with the given vendor and device ID.
+    Id(VendorId, DeviceId, usize),
+}
+
+/// A memory BAR mapped into the driver's address space.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedBar {
+    pub index: u8,
+    pub bar: Bar,
+    pub vaddr: VAddr,
+    pub options: MapOptions,
+}
+
+/// Maps BARs into the driver's address space.
+#[derive(Clone, Copy)]
+enum BarMapper<'a> {
+    Untyped(&'a dyn Fn(PAddr) -> VAddr),
+    WithOptions(&'a dyn Fn(PAddr, usize, MapOptions) -> VAddr),
+}
+
+impl<'a> BarMapper<'a> {
+    fn map(&self, paddr: PAddr, size: usize, options: MapOptions) -> VAddr {
+        match self {
+            BarMapper::Untyped(map) => map(paddr),
+            BarMapper::WithOptions(map) => map(paddr, size, options),
+        }
+    }
+}
+
+/// Configures and claims a PCI device for a driver.
+pub struct PciDriverBuilder<'a> {
+    selector: Selector,
+    #[cfg(target_os = "linux")]
+    unbind_kernel_driver: bool,
+    #[cfg(target_os = "linux")]
+    lock_device: bool,
+    bus_master: bool,
+    mapper: Option<BarMapper<'a>>,
+    bar_options: [MapOptions; MAX_BARS],
+    msix: bool,
+    allocator: DmaAllocator,
+}
+
+impl<'a> PciDriverBuilder<'a> {
+    /// Drive the device at `addr`.
+    pub fn with_address(addr: PCIAddress) -> Self {
+        Self::new(Selector::Address(addr))
+    }
+
+    /// Drive the first device with the given vendor and device ID.
+    pub fn with_id(vendor: VendorId, device: DeviceId) -> Self {
+        Self::new(Selector::Id(vendor, device, 0))
+    }
+
+    fn new(selector: Selector) -> Self {
+        PciDriverBuilder {
+            selector,
+            #[cfg(target_os = "linux")]
+            unbind_kernel_driver: true,
+            #[cfg(target_os = "linux")]
+            lock_device: true,
+            bus_master: true,
+            mapper: None,
+            bar_options: [MapOptions::device(); MAX_BARS],
+            msix: false,
+            allocator: DmaAllocator::new(),
+        }
+    }
+
+    /// Pick the `n`th matching device instead of the first one (only
+    /// meaningful with [`PciDriverBuilder::with_id`]).
+    pub fn nth(mut self, n: usize) -> Self {
+        if let Selector::Id(vendor, device, _) = self.selector {
+            self.selector = Selector::Id(vendor, device, n);
+        }
+        self
+    }
+
+    /// Unbind the Linux kernel driver from the device (default: true).
+    #[cfg(target_os = "linux")]
+    pub fn unbind_kernel_driver(mut self, unbind: bool) -> Self {
+        self.unbind_kernel_driver = unbind;
+        self
+    }
+
+    /// Take an advisory lock on the device so no other process can claim it
+    /// while the handle is alive (default: true, see
+    /// [`DeviceLock`](crate::linux::lock::DeviceLock)).
+    #[cfg(target_os = "linux")]
+    pub fn lock_device(mut self, lock: bool) -> Self {
+        self.lock_device = lock;
+        self
+    }
+
+    /// Enable bus mastering so the device can do DMA (default: true).
+    pub fn bus_master(mut self, enable: bool) -> Self {
+        self.bus_master = enable;
+        self
+    }
+
+    /// Map all memory BARs using `paddr_to_vaddr`.
+    pub fn map_bars(mut self, paddr_to_vaddr: &'a dyn Fn(PAddr) -> VAddr) -> Self {
+        self.mapper = Some(BarMapper::Untyped(paddr_to_vaddr));
+        self
+    }
+
+    /// Map all memory BARs using `map`, which gets the physical address,
+    /// the size and the options of the mapping (see
+    /// [`PciDriverBuilder::bar_options`]).
+    pub fn map_bars_with_options(
+        mut self,
+        map: &'a dyn Fn(PAddr, usize, MapOptions) -> VAddr,
+    ) -> Self {
+        self.mapper = Some(BarMapper::WithOptions(map));
+        self
+    }
+
+    /// Sets how BAR `index` is mapped (default: [`MapOptions::device`],
+    /// i.e., uncached with posted writes).
+    ///
+    /// Only [`PciDriverBuilder::map_bars_with_options`] honors it, the MSI-X
+    /// table is always mapped with the default options.
+    pub fn bar_options(mut self, index: BarIndex, options: MapOptions) -> Self {
+        self.bar_options[u8::from(index) as usize] = options;
+        self
+    }
+
+    /// Sets the memory type of BAR `index` (see
+    /// [`PciDriverBuilder::bar_options`]).
+    pub fn bar_memory_type(mut self, index: BarIndex, memory_type: MemoryType) -> Self {
+        self.bar_options[u8::from(index) as usize].memory_type = memory_type;
+        self
+    }
+
+    /// Enable MSI-X and make the vector table available to the driver.
+    ///
+    /// Requires the BARs to be mapped, [`PciDriverBuilder::build`] fails
+    /// otherwise. Ignored for devices with the
+    /// [`QuirkFlags::NO_MSIX`] quirk.
+    pub fn msix(mut self, enable: bool) -> Self {
+        self.msix = enable;
+        self
+    }
+
+    /// The allocator the driver should use for DMA memory.
+    ///
+    /// Allocators that aren't tagged with a device yet account their
+    /// allocations to this one (see [`dma_debug`](crate::dma_debug)).
+    pub fn dma_allocator(mut self, allocator: DmaAllocator) -> Self {
+        self.allocator = allocator;
+        self
+    }
+
+    fn find(&self) -> Option<PciDevice> {
+        match self.selector {
+            Selector::Address(addr) => PciDevice::new(addr.bus, addr.dev, addr.fun),
+            Selector::Id(vendor, device, n) => scan_bus()
+                .filter(|dev| dev.vendor_id() == vendor && dev.device_id() == device)
+                .nth(n),
+        }
+    }
+
+    pub fn build(self) -> Result<PciDeviceHandle, PciError> {
+        if self.msix && self.mapper.is_none() {
+            return Err(PciError::MsiXBarsNotMapped);
+        }
+        let mut device = self.find().ok_or(PciError::DeviceNotFound)?;
+        span!(DEBUG, "pci_attach", device = device.pci_address());
+
+        // Claim the device before touching it
+        #[cfg(target_os = "linux")]
+        let lock = if self.lock_device {
+            match crate::linux::lock::DeviceLock::acquire(device.pci_address()) {
+                Ok(lock) => Some(lock),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Err(PciError::DeviceClaimed)
+                }
+                Err(_e) => return Err(PciError::LockFailed),
+            }
+        } else {
+            None
+        };
+
+        #[cfg(target_os = "linux")]
+        if self.unbind_kernel_driver {
+            crate::linux::sysfs::unbind_kernel_driver(device.pci_address())
+                .map_err(|_e| PciError::UnbindFailed)?;
+        }
+
+        let quirks = quirks::apply(&mut device);
+
+        if self.bus_master && !device.is_bus_master() {
+            device.enable_bus_mastering();
+        }
+
+        let mut bars = [None; MAX_BARS];
+        if let Some(mapper) = self.mapper {
+            for (index, bar) in device.iter_bars() {
+                let options = self.bar_options[index as usize];
+                if options.memory_type.requires_prefetchable() && !bar.prefetchable {
+                    return Err(PciError::NotPrefetchable {
+                        index: index.into(),
+                    });
+                }
+                span!(DEBUG, "map_bar", index = index, size = bar.size);
+                bars[index as usize] = Some(MappedBar {
+                    index: index.into(),
+                    bar,
+                    vaddr: mapper.map(PAddr::from(bar.address), bar.size as usize, options),
+                    options,
+                });
+            }
+        }
+
+        let msix = self.msix && !quirks.contains(QuirkFlags::NO_MSIX);
+        if self.msix && !msix {
+            warn!(
+                "MSI-X is broken on {:?}, not enabling it",
+                device.pci_address()
+            );
+        }
+        let msix_table = match (msix, self.mapper) {
+            (true, Some(mapper)) => {
+                let table = device.get_msix_irq_table_mut(&|paddr| {
+                    mapper.map(paddr, MSIX_TABLE_MAX_SIZE, MapOptions::device())
+                })?;
+                Some((table.as_mut_ptr(), table.len()))
+            }
+            _ => None,
+        };
+
+        let allocator = match self.allocator.device() {
+            Some(_) => self.allocator,
+            None => DmaAllocator::for_device(device.pci_address()),
+        };
+
+        Ok(PciDeviceHandle {
+            device,
+            bars,
+            msix_table,
+            allocator,
+            quirks,
+            #[cfg(target_os = "linux")]
+            _lock: lock,
+        })
+    }
+}
+
+/// A PCI device that is ready to be used by a driver.
+pub struct PciDeviceHandle {
+    device: PciDevice,
+    bars: [Option<MappedBar>; MAX_BARS],
+    msix_table: Option<(*mut MsiXTableEntry, usize)>,
+    allocator: DmaAllocator,
+    quirks: Quirks,
+    /// Held for as long as the driver uses the device.
+    #[cfg(target_os = "linux")]
+    _lock: Option<crate::linux::lock::DeviceLock>,
+}
+
+impl PciDeviceHandle {
+    pub fn device(&self) -> &PciDevice {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut PciDevice {
+        &mut self.device
+    }
+
+    /// Returns the mapping of BAR `index` (if it's a mapped memory BAR).
+    pub fn bar(&self, index: u8) -> Option<&MappedBar> {
+        self.bars.get(index as usize).and_then(|bar| bar.as_ref())
+    }
+
+    /// All mapped BARs of the device.
+    pub fn bars(&self) -> impl Iterator<Item = &MappedBar> {
+        self.bars.iter().flatten()
+    }
+
+    /// The MSI-X vector table (if MSI-X was enabled by the builder).
+    pub fn msix_table(&mut self) -> Option<&mut [MsiXTableEntry]> {
+        // Safety:
+        // - The table was validated and handed out by `get_msix_irq_table_mut`
+        // - We have &mut self when giving out a mut reference to the table
+        self.msix_table
+            .map(|(ptr, len)| unsafe { core::slice::from_raw_parts_mut(ptr, len) })
+    }
+
+    /// Claims the first `vectors` MSI-X vectors (see [`MsixVector`]),
+    /// programming vector `i` with `message(i)`.
+    ///
+    /// The vectors are claimed together since each borrows its own entry of
+    /// the table. Returns None if MSI-X wasn't enabled or the table has fewer
+    /// than `vectors` entries.
+    pub fn claim_msix_vectors(
+        &mut self,
+        vectors: usize,
+        message: impl Fn(usize) -> (u64, u32),
+    ) -> Option<Vec<MsixVector<'_>>> {
+        let entries = self.msix_table()?.get_mut(..vectors)?;
+        Some(msix::claim_all(entries, message).collect())
+    }
+
+    /// Splits the device into a handle per queue (see [`queues`]) and the
+    /// device itself for the control path, which the queues don't need.
+    ///
+    /// The handles use the vectors of the MSI-X table if the builder enabled
+    /// MSI-X, queue `n` gets the message `message(n)`.
+    pub fn split_queues(
+        &mut self,
+        layout: DoorbellLayout,
+        queues: usize,
+        message: impl Fn(usize) -> (u64, u32),
+    ) -> Result<(&mut PciDevice, Vec<QueueHandle<'_>>), PciError> {
+        let bar = self
+            .bars
+            .get(layout.bar as usize)
+            .and_then(|bar| bar.as_ref())
+            .ok_or(PciError::BarNotMapped { index: layout.bar })?;
+        // Safety: as in `msix_table`, the table is borrowed along with the
+        // rest of the handle
+        let msix = self
+            .msix_table
+            .map(|(ptr, len)| unsafe { core::slice::from_raw_parts_mut(ptr, len) });
+        let handles = queues::split_queues(bar, msix, layout, queues, message)?;
+        Ok((&mut self.device, handles))
+    }
+
+    pub fn dma_allocator(&self) -> DmaAllocator {
+        self.allocator
+    }
+
+    /// Logs the DMA memory the driver still holds (for the debug mode of
+    /// [`dma_debug`](crate::dma_debug)) and returns the number of leaked
+    /// allocations and mappings. Meant to be called at detach.
+    pub fn report_dma_leaks(&self) -> usize {
+        crate::dma_debug::report_leaks(self.allocator.device())
+    }
+
+    /// The quirks of the device (their fixups ran when it was attached).
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Creates a buffer pool backed by the device's DMA allocator.
+    pub fn iobuf_pool(&self, len: usize, align: usize) -> Result<IOBufPool, IOMemError> {
+        IOBufPool::new_in(len, align, self.allocator)
+    }
+
+    /// Gives up the handle, returning the underlying device (this releases
+    /// the device lock).
+    pub fn into_device(self) -> PciDevice {
+        self.device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::PCIHeader;
+
+    #[test]
+    fn msix_requires_mapped_bars() {
+        let addr = PCIAddress {
+            bus: 0xfe,
+            dev: 4,
+            fun: 0,
+        };
+        let builder = PciDriverBuilder::with_address(addr).msix(true);
+        assert!(matches!(builder.build(), Err(PciError::MsiXBarsNotMapped)));
+    }
+
+    #[test]
+    fn per_bar_options() {
+        let addr = PCIAddress {
+            bus: 0xfe,
+            dev: 6,
+            fun: 0,
+        };
+        let builder = PciDriverBuilder::with_address(addr)
+            .bar_options(BarIndex::Bar2, MapOptions::device())
+            .bar_memory_type(BarIndex::Bar5, MemoryType::WriteCombining);
+        assert_eq!(
+            builder.bar_options[5].memory_type,
+            MemoryType::WriteCombining
+        );
+        assert_eq!(
+            builder.bar_options[2].memory_type,
+            MapOptions::device().memory_type
+        );
+    }
+
+    #[test]
+    fn claimed_msix_vectors() {
+        let addr = PCIAddress {
+            bus: 0xfe,
+            dev: 7,
+            fun: 0,
+        };
+        let mut table: Vec<MsiXTableEntry> = (0..4)
+            .map(|_| MsiXTableEntry {
+                addr: 0,
+                data: 0,
+                vector_control: 1,
+            })
+            .collect();
+        let mut handle = PciDeviceHandle {
+            device: PciDevice {
+                header: PCIHeader::from_config(addr),
+            },
+            bars: [None; MAX_BARS],
+            msix_table: Some((table.as_mut_ptr(), table.len())),
+            allocator: DmaAllocator::for_device(addr),
+            quirks: Quirks::default(),
+            #[cfg(target_os = "linux")]
+            _lock: None,
+        };
+
+        assert!(handle.claim_msix_vectors(5, |_| (0, 0)).is_none());
+        let vectors = handle
+            .claim_msix_vectors(3, |i| (0xfee0_0000, 0x40 + i as u32))
+            .unwrap();
+        for (i, vector) in vectors.iter().enumerate() {
+            assert_eq!(vector.index(), i);
+            assert_eq!(
+                (vector.address(), vector.data()),
+                (0xfee0_0000, 0x40 + i as u32)
+            );
+            assert!(!vector.is_masked());
+        }
+        drop(vectors);
+        drop(handle);
+
+        for entry in &table[..3] {
+            assert!(entry.is_masked());
+            assert_eq!((entry.address(), entry.data()), (0, 0));
+        }
+        // Never claimed
+        assert!(table[3].is_masked());
+    }
+
+    #[test]
+    fn iobuf_pool_uses_device_allocator() {
+        let addr = PCIAddress {
+            bus: 0xfe,
+            dev: 5,
+            fun: 0,
+        };
+        let handle = PciDeviceHandle {
+            device: PciDevice {
+                header: PCIHeader::from_config(addr),
+            },
+            bars: [None; MAX_BARS],
+            msix_table: None,
+            allocator: DmaAllocator::for_device(addr),
+            quirks: Quirks::default(),
+            #[cfg(target_os = "linux")]
+            _lock: None,
+        };
+
+        let before = crate::dma_debug::stats(Some(addr)).allocations;
+        let mut pool = handle.iobuf_pool(256, 64).unwrap();
+        let buf = pool.get_buf().unwrap();
+        assert_eq!(crate::dma_debug::stats(Some(addr)).allocations, before + 1);
+        pool.put_buf(buf);
+    }
+}
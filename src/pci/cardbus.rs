@@ -0,0 +1,194 @@
+//! Registers of CardBus bridges (header type 2).
+
+use bit_field::BitField;
+
+use super::{ConfigSpace, PCIAddress, PCIHeader};
+
+/// Offset of the capabilities pointer in a CardBus header.
+pub(crate) const CARDBUS_CAPABILITIES_POINTER: u32 = 0x14;
+
+const MEMORY_WINDOWS: u32 = 0x1c;
+const IO_WINDOWS: u32 = 0x2c;
+
+/// A memory or IO window forwarded to the CardBus (base and limit are
+/// inclusive).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CardBusWindow {
+    pub base: u32,
+    pub limit: u32,
+}
+
+impl CardBusWindow {
+    /// Whether the bridge forwards the window (i.e., limit >= base).
+    pub fn is_enabled(&self) -> bool {
+        self.limit >= self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        if self.is_enabled() {
+            (self.limit - self.base) as u64 + 1
+        } else {
+            0
+        }
+    }
+}
+
+/// The CardBus specific part of a header type 2 configuration space.
+#[derive(Debug)]
+pub struct CardBus<'s, A = PCIAddress> {
+    /// A reference to the device's PCI header.
+    pub(super) header: &'s PCIHeader<A>,
+}
+
+impl<'s, A: ConfigSpace> CardBus<'s, A> {
+    /// Base address of the socket/ExCA registers (the only BAR).
+    pub fn socket_base(&self) -> u32 {
+        self.header.read(0x10) & !0xfff
+    }
+
+    pub fn secondary_status(&self) -> u16 {
+        (self.header.read(0x14) >> 16) as u16
+    }
+
+    pub fn primary_bus(&self) -> u8 {
+        self.header.read(0x18).get_bits(0..8) as u8
+    }
+
+    /// The bus number of the CardBus.
+    pub fn cardbus_bus(&self) -> u8 {
+        self.header.read(0x18).get_bits(8..16) as u8
+    }
+
+    pub fn subordinate_bus(&self) -> u8 {
+        self.header.read(0x18).get_bits(16..24) as u8
+    }
+
+    /// The latency timer of the CardBus.
+    pub fn cardbus_latency_timer(&self) -> u8 {
+        self.header.read(0x18).get_bits(24..32) as u8
+    }
+
+    /// Memory window `index` (0 or 1), with 4 KiB granularity.
+    pub fn memory_window(&self, index: u8) -> CardBusWindow {
+        assert!(index < 2);
+        let offset = MEMORY_WINDOWS + index as u32 * 8;
+        CardBusWindow {
+            base: self.header.read(offset) & !0xfff,
+            limit: self.header.read(offset + 4) | 0xfff,
+        }
+    }
+
+    /// Sets memory window `index` (0 or 1), `base` and `limit + 1` must be
+    /// 4 KiB aligned.
+    pub fn set_memory_window(&mut self, index: u8, window: CardBusWindow) {
+        assert!(index < 2);
+        let offset = MEMORY_WINDOWS + index as u32 * 8;
+        self.header.write(offset, window.base & !0xfff);
+        self.header.write(offset + 4, window.limit & !0xfff);
+    }
+
+    /// IO window `index` (0 or 1), with 4 byte granularity.
+    pub fn io_window(&self, index: u8) -> CardBusWindow {
+        assert!(index < 2);
+        let offset = IO_WINDOWS + index as u32 * 8;
+        let base = self.header.read(offset);
+        let limit = self.header.read(offset + 4);
+        // Bit 0 tells whether the upper 16 bits are implemented
+        let mask = if base.get_bit(0) { u32::MAX } else { 0xffff };
+        CardBusWindow {
+            base: base & mask & !0b11,
+            limit: (limit & mask) | 0b11,
+        }
+    }
+
+    /// Sets IO window `index` (0 or 1), `base` and `limit + 1` must be 4 byte
+    /// aligned.
+    pub fn set_io_window(&mut self, index: u8, window: CardBusWindow) {
+        assert!(index < 2);
+        let offset = IO_WINDOWS + index as u32 * 8;
+        self.header.write(offset, window.base & !0b11);
+        self.header.write(offset + 4, window.limit & !0b11);
+    }
+
+    /// The bridge control register.
+    pub fn bridge_control(&self) -> u16 {
+        (self.header.read(0x3c) >> 16) as u16
+    }
+
+    pub fn subsystem_vendor_id(&self) -> u16 {
+        self.header.read(0x40) as u16
+    }
+
+    pub fn subsystem_id(&self) -> u16 {
+        (self.header.read(0x40) >> 16) as u16
+    }
+
+    /// Base address of the 16-bit PC Card legacy mode registers.
+    pub fn legacy_mode_base(&self) -> u32 {
+        self.header.read(0x44)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::mock::MockConfig;
+    use crate::pci::{PciDevice, PciDeviceType};
+
+    #[test]
+    fn windows() {
+        let mut space = [0u8; 0x80];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        // CardBus bridge with capabilities at 0x80 (pointer at 0x14)
+        space[0x06] = 0x10;
+        space[0x0e] = 0x02;
+        space[0x14] = 0x80;
+        // Buses 2..=5 behind bus 1
+        space[0x18..0x1c].copy_from_slice(&[1, 2, 5, 0xb0]);
+        // Memory window 0: 0xa000_0000..=0xa0ff_ffff, window 1 disabled
+        space[0x1c..0x20].copy_from_slice(&0xa000_0000u32.to_le_bytes());
+        space[0x20..0x24].copy_from_slice(&0xa0ff_f000u32.to_le_bytes());
+        space[0x24..0x28].copy_from_slice(&0xffff_f000u32.to_le_bytes());
+        // 16-bit IO window 0: 0x1000..=0x10ff
+        space[0x2c..0x30].copy_from_slice(&0xabcd_1000u32.to_le_bytes());
+        space[0x30..0x34].copy_from_slice(&0x0000_10fcu32.to_le_bytes());
+
+        let addr = PCIAddress {
+            bus: 1,
+            dev: 0,
+            fun: 0,
+        };
+        let mut device = PciDevice::from_config(MockConfig::from_bytes(addr, &space)).unwrap();
+        assert!(matches!(device.device_type(), PciDeviceType::CardBusBridge));
+        assert_eq!(device.capabilities_pointer(), Some(0x80));
+
+        let mut cardbus = device.cardbus().unwrap();
+        assert_eq!(
+            (
+                cardbus.primary_bus(),
+                cardbus.cardbus_bus(),
+                cardbus.subordinate_bus()
+            ),
+            (1, 2, 5)
+        );
+        assert_eq!(cardbus.cardbus_latency_timer(), 0xb0);
+        assert_eq!(cardbus.memory_window(0).size(), 0x100_0000);
+        assert!(!cardbus.memory_window(1).is_enabled());
+        assert_eq!(
+            cardbus.io_window(0),
+            CardBusWindow {
+                base: 0x1000,
+                limit: 0x10ff
+            }
+        );
+
+        let window = CardBusWindow {
+            base: 0xb000_0000,
+            limit: 0xb000_ffff,
+        };
+        cardbus.set_memory_window(1, window);
+        assert_eq!(cardbus.memory_window(1), window);
+    }
+}
@@ -13,10 +13,18 @@
 //! See also <https://github.com/pciutils/pciids> and
 //! <https://github.com/ilyazzz/pci-id-parser> for more details.
 
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use custom_error::custom_error;
 use phf;
+use spin::Mutex;
+
+use super::{BaseClass, DeviceId, SubClass, VendorId};
 
 /// Information about a PCI device.
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct PciDeviceInfo {
     pub vendor_id: u16,
     pub device_id: u16,
@@ -30,8 +38,224 @@ pub fn make_key(vendor: u16, device: u16) -> u32 {
 
 include!(concat!(env!("OUT_DIR"), "/pci_device_map.rs"));
 
+/// Maximum number of runtime sources that can be registered at the same
+/// time.
+pub const MAX_SOURCES: usize = 4;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub DeviceDbError
+    TooManySources = "no free slot to register another device info source",
+}
+
+/// A device database that is only available at runtime (e.g., a pci.ids
+/// file, see `linux::pciids`).
+pub trait DeviceInfoSource: Sync {
+    fn lookup(&'static self, vendor: VendorId, device: DeviceId) -> Option<&'static PciDeviceInfo>;
+}
+
+/// Whether a runtime source is consulted before or after the compiled-in
+/// database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SourceOrder {
+    /// The source overrides the compiled-in database.
+    BeforeBuiltin,
+    /// The source only fills in devices the compiled-in database lacks.
+    AfterBuiltin,
+}
+
+type Source = (&'static dyn DeviceInfoSource, SourceOrder);
+
+static SOURCES: Mutex<[Option<Source>; MAX_SOURCES]> = Mutex::new([None; MAX_SOURCES]);
+
+/// Registers an additional source for [`lookup`] (and
+/// [`PciDevice::info`](super::PciDevice::info)).
+pub fn register_source(
+    source: &'static dyn DeviceInfoSource,
+    order: SourceOrder,
+) -> Result<(), DeviceDbError> {
+    let mut sources = SOURCES.lock();
+    let slot = sources
+        .iter_mut()
+        .find(|s| s.is_none())
+        .ok_or(DeviceDbError::TooManySources)?;
+    *slot = Some((source, order));
+    Ok(())
+}
+
+/// Removes a previously registered source.
+pub fn unregister_source(source: &'static dyn DeviceInfoSource) {
+    let mut sources = SOURCES.lock();
+    for slot in sources.iter_mut() {
+        if let Some((s, _)) = slot {
+            if core::ptr::eq(
+                *s as *const dyn DeviceInfoSource as *const (),
+                source as *const dyn DeviceInfoSource as *const (),
+            ) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+fn lookup_sources(
+    order: SourceOrder,
+    vendor: VendorId,
+    device: DeviceId,
+) -> Option<&'static PciDeviceInfo> {
+    let sources = SOURCES.lock();
+    sources
+        .iter()
+        .flatten()
+        .filter(|(_, o)| *o == order)
+        .find_map(|(source, _)| source.lookup(vendor, device))
+}
+
+/// Looks up a device by its vendor and device ID, in the registered
+/// sources and the compiled-in database.
+pub fn lookup(vendor: VendorId, device: DeviceId) -> Option<&'static PciDeviceInfo> {
+    lookup_sources(SourceOrder::BeforeBuiltin, vendor, device)
+        .or_else(|| PCI_DEVICES.get(&make_key(vendor, device)))
+        .or_else(|| lookup_sources(SourceOrder::AfterBuiltin, vendor, device))
+}
+
+/// The name of `vendor` (only known for vendors with at least one device in
+/// the compiled-in database).
+pub fn vendor_name(vendor: VendorId) -> Option<&'static str> {
+    PCI_DEVICES
+        .values()
+        .find(|info| info.vendor_id == vendor)
+        .map(|info| info.vendor_name)
+}
+
+/// All devices of `vendor` in the compiled-in database, sorted by device
+/// ID.
+#[cfg(feature = "alloc")]
+pub fn vendor_devices(vendor: VendorId) -> Vec<&'static PciDeviceInfo> {
+    let mut devices: Vec<_> = PCI_DEVICES
+        .values()
+        .filter(|info| info.vendor_id == vendor)
+        .collect();
+    devices.sort_unstable_by_key(|info| info.device_id);
+    devices
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    needle.is_empty()
+        || haystack
+            .as_bytes()
+            .windows(needle.len())
+            .any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// All devices in the compiled-in database whose vendor name contains
+/// `pattern` (ignoring ASCII case), in no particular order.
+pub fn find_by_vendor_name(pattern: &str) -> impl Iterator<Item = &'static PciDeviceInfo> + '_ {
+    PCI_DEVICES
+        .values()
+        .filter(move |info| contains_ignore_case(info.vendor_name, pattern))
+}
+
+/// Names of the base classes (see the PCI Code and ID Assignment
+/// Specification).
+const BASE_CLASSES: [(BaseClass, &str); 22] = [
+    (0x00, "Unclassified device"),
+    (0x01, "Mass storage controller"),
+    (0x02, "Network controller"),
+    (0x03, "Display controller"),
+    (0x04, "Multimedia controller"),
+    (0x05, "Memory controller"),
+    (0x06, "Bridge"),
+    (0x07, "Communication controller"),
+    (0x08, "Generic system peripheral"),
+    (0x09, "Input device controller"),
+    (0x0a, "Docking station"),
+    (0x0b, "Processor"),
+    (0x0c, "Serial bus controller"),
+    (0x0d, "Wireless controller"),
+    (0x0e, "Intelligent controller"),
+    (0x0f, "Satellite communications controller"),
+    (0x10, "Encryption controller"),
+    (0x11, "Signal processing controller"),
+    (0x12, "Processing accelerators"),
+    (0x13, "Non-Essential Instrumentation"),
+    (0x40, "Coprocessor"),
+    (0xff, "Unassigned class"),
+];
+
+/// Names of the common sub classes.
+const SUB_CLASSES: [(BaseClass, SubClass, &str); 43] = [
+    (0x00, 0x01, "VGA compatible unclassified device"),
+    (0x01, 0x00, "SCSI storage controller"),
+    (0x01, 0x01, "IDE interface"),
+    (0x01, 0x04, "RAID bus controller"),
+    (0x01, 0x05, "ATA controller"),
+    (0x01, 0x06, "SATA controller"),
+    (0x01, 0x07, "Serial Attached SCSI controller"),
+    (0x01, 0x08, "Non-Volatile memory controller"),
+    (0x01, 0x80, "Mass storage controller"),
+    (0x02, 0x00, "Ethernet controller"),
+    (0x02, 0x07, "Infiniband controller"),
+    (0x02, 0x08, "Fabric controller"),
+    (0x02, 0x80, "Network controller"),
+    (0x03, 0x00, "VGA compatible controller"),
+    (0x03, 0x01, "XGA compatible controller"),
+    (0x03, 0x02, "3D controller"),
+    (0x03, 0x80, "Display controller"),
+    (0x04, 0x01, "Multimedia audio controller"),
+    (0x04, 0x03, "Audio device"),
+    (0x05, 0x00, "RAM memory"),
+    (0x05, 0x01, "FLASH memory"),
+    (0x06, 0x00, "Host bridge"),
+    (0x06, 0x01, "ISA bridge"),
+    (0x06, 0x04, "PCI bridge"),
+    (0x06, 0x07, "CardBus bridge"),
+    (0x06, 0x80, "Bridge"),
+    (0x07, 0x00, "Serial controller"),
+    (0x07, 0x80, "Communication controller"),
+    (0x08, 0x00, "PIC"),
+    (0x08, 0x01, "DMA controller"),
+    (0x08, 0x02, "Timer"),
+    (0x08, 0x05, "SD Host controller"),
+    (0x08, 0x06, "IOMMU"),
+    (0x08, 0x80, "System peripheral"),
+    (0x0c, 0x03, "USB controller"),
+    (0x0c, 0x05, "SMBus"),
+    (0x0c, 0x07, "IPMI Interface"),
+    (0x0c, 0x80, "Serial bus controller"),
+    (0x0d, 0x80, "Network controller"),
+    (0x10, 0x00, "Network and computing encryption device"),
+    (0x11, 0x80, "Signal processing controller"),
+    (0x12, 0x00, "Processing accelerators"),
+    (
+        0x12,
+        0x01,
+        "SNIA Smart Data Accelerator Interface (SDXI) controller",
+    ),
+];
+
+/// The name of the base class `base`.
+pub fn base_class_name(base: BaseClass) -> Option<&'static str> {
+    BASE_CLASSES
+        .iter()
+        .find(|(class, _)| *class == base)
+        .map(|(_, name)| *name)
+}
+
+/// The canonical name of a class code, falling back to the name of the base
+/// class for unknown sub classes (as lspci does).
+pub fn class_name(base: BaseClass, sub: SubClass) -> Option<&'static str> {
+    SUB_CLASSES
+        .iter()
+        .find(|(class, subclass, _)| *class == base && *subclass == sub)
+        .map(|(_, _, name)| *name)
+        .or_else(|| base_class_name(base))
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn test_device_map() {
@@ -42,4 +266,24 @@ mod tests {
         assert_eq!(dev.vendor_id, 0xfffe);
         assert_eq!(dev.device_id, 0x0710);
     }
+
+    #[test]
+    fn queries() {
+        assert_eq!(vendor_name(0xfffe), Some("VMWare Inc (temporary ID)"));
+        assert!(find_by_vendor_name("vmware inc (TEMP").all(|dev| dev.vendor_id == 0xfffe));
+        #[cfg(feature = "alloc")]
+        {
+            assert!(vendor_devices(0xfffe)
+                .iter()
+                .any(|dev| dev.device_id == 0x0710));
+            assert_eq!(
+                find_by_vendor_name("vmware inc (temp").count(),
+                vendor_devices(0xfffe).len()
+            );
+        }
+
+        assert_eq!(class_name(0x02, 0x00), Some("Ethernet controller"));
+        assert_eq!(class_name(0x02, 0x42), Some("Network controller"));
+        assert_eq!(class_name(0x20, 0x00), None);
+    }
 }
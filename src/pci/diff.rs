@@ -0,0 +1,651 @@
+//! Comparing two snapshots of a function's configuration space.
+//!
+//! Useful to find out what firmware, a reset or a driver changed on a
+//! device: take a [`ConfigSnapshot`] before and after, and [`ConfigDiff`]
+//! lists the dwords that differ, which header register or capability they
+//! belong to and the (named) fields within them that changed.
+//!
+//! The layout (header type, capability list) is taken from the first
+//! snapshot, falling back to the second one for registers the first doesn't
+//! know about (e.g., if the first snapshot was taken while the device was in
+//! D3cold and read as all ones).
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use bit_field::BitField;
+
+use super::{CapabilityChain, CapabilityId, ConfigSpace, PCIAddress, PciDevice};
+
+/// Size of the configuration space of conventional PCI functions.
+const PCI_CONFIG_SIZE: usize = 0x100;
+/// Size of the configuration space of PCIe functions.
+const PCIE_CONFIG_SIZE: usize = 0x1000;
+/// First offset after the header.
+const FIRST_CAPABILITY: usize = 0x40;
+
+/// Extended capability IDs with a name.
+const EXT_CAP_NAMES: [(u16, &str); 12] = [
+    (0x0001, "Advanced Error Reporting"),
+    (0x0002, "Virtual Channel"),
+    (0x0003, "Device Serial Number"),
+    (0x000b, "Vendor Specific"),
+    (0x000d, "Access Control Services"),
+    (0x000e, "Alternative Routing-ID"),
+    (0x0010, "SR-IOV"),
+    (0x0015, "Resizable BAR"),
+    (0x0017, "TPH Requester"),
+    (0x0018, "Latency Tolerance Reporting"),
+    (0x0019, "Secondary PCI Express"),
+    (0x001e, "L1 PM Substates"),
+];
+const EXT_CAP_AER: u16 = 0x0001;
+
+/// A named field of a register: bits `start..end` of the dword.
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    name: &'static str,
+    start: usize,
+    end: usize,
+}
+
+const fn field(name: &'static str, start: usize, end: usize) -> Field {
+    Field { name, start, end }
+}
+
+const fn bit(name: &'static str, bit: usize) -> Field {
+    Field {
+        name,
+        start: bit,
+        end: bit + 1,
+    }
+}
+
+const IDS: &[Field] = &[field("Vendor ID", 0, 16), field("Device ID", 16, 32)];
+const COMMAND_STATUS: &[Field] = &[
+    bit("I/O Space", 0),
+    bit("Memory Space", 1),
+    bit("Bus Master", 2),
+    bit("Special Cycles", 3),
+    bit("Memory Write and Invalidate", 4),
+    bit("VGA Palette Snoop", 5),
+    bit("Parity Error Response", 6),
+    bit("SERR# Enable", 8),
+    bit("Fast Back-to-Back Enable", 9),
+    bit("Interrupt Disable", 10),
+    bit("Interrupt Status", 19),
+    bit("Capabilities List", 20),
+    bit("66 MHz Capable", 21),
+    bit("Master Data Parity Error", 24),
+    field("DEVSEL Timing", 25, 27),
+    bit("Signaled Target Abort", 27),
+    bit("Received Target Abort", 28),
+    bit("Received Master Abort", 29),
+    bit("Signaled System Error", 30),
+    bit("Detected Parity Error", 31),
+];
+const CLASS: &[Field] = &[
+    field("Revision ID", 0, 8),
+    field("Programming Interface", 8, 16),
+    field("Subclass", 16, 24),
+    field("Base Class", 24, 32),
+];
+const MISC: &[Field] = &[
+    field("Cache Line Size", 0, 8),
+    field("Latency Timer", 8, 16),
+    field("Header Type", 16, 24),
+    field("BIST", 24, 32),
+];
+const BARS: [&[Field]; 6] = [
+    &[field("BAR 0", 0, 32)],
+    &[field("BAR 1", 0, 32)],
+    &[field("BAR 2", 0, 32)],
+    &[field("BAR 3", 0, 32)],
+    &[field("BAR 4", 0, 32)],
+    &[field("BAR 5", 0, 32)],
+];
+const CAPABILITIES_POINTER: &[Field] = &[field("Capabilities Pointer", 0, 8)];
+
+/// The header of type 0 (endpoint) functions, by dword.
+const ENDPOINT_HEADER: [&[Field]; 16] = [
+    IDS,
+    COMMAND_STATUS,
+    CLASS,
+    MISC,
+    BARS[0],
+    BARS[1],
+    BARS[2],
+    BARS[3],
+    BARS[4],
+    BARS[5],
+    &[field("CardBus CIS Pointer", 0, 32)],
+    &[
+        field("Subsystem Vendor ID", 0, 16),
+        field("Subsystem ID", 16, 32),
+    ],
+    &[field("Expansion ROM Base Address", 0, 32)],
+    CAPABILITIES_POINTER,
+    &[],
+    &[
+        field("Interrupt Line", 0, 8),
+        field("Interrupt Pin", 8, 16),
+        field("Min_Gnt", 16, 24),
+        field("Max_Lat", 24, 32),
+    ],
+];
+
+/// The header of type 1 (PCI-to-PCI bridge) functions, by dword.
+const BRIDGE_HEADER: [&[Field]; 16] = [
+    IDS,
+    COMMAND_STATUS,
+    CLASS,
+    MISC,
+    BARS[0],
+    BARS[1],
+    &[
+        field("Primary Bus Number", 0, 8),
+        field("Secondary Bus Number", 8, 16),
+        field("Subordinate Bus Number", 16, 24),
+        field("Secondary Latency Timer", 24, 32),
+    ],
+    &[
+        field("I/O Base", 0, 8),
+        field("I/O Limit", 8, 16),
+        field("Secondary Status", 16, 32),
+    ],
+    &[field("Memory Base", 0, 16), field("Memory Limit", 16, 32)],
+    &[
+        field("Prefetchable Memory Base", 0, 16),
+        field("Prefetchable Memory Limit", 16, 32),
+    ],
+    &[field("Prefetchable Base Upper 32 Bits", 0, 32)],
+    &[field("Prefetchable Limit Upper 32 Bits", 0, 32)],
+    &[
+        field("I/O Base Upper 16 Bits", 0, 16),
+        field("I/O Limit Upper 16 Bits", 16, 32),
+    ],
+    CAPABILITIES_POINTER,
+    &[field("Expansion ROM Base Address", 0, 32)],
+    &[
+        field("Interrupt Line", 0, 8),
+        field("Interrupt Pin", 8, 16),
+        field("Bridge Control", 16, 32),
+    ],
+];
+
+/// Other header types (CardBus), only the common part is decoded.
+const OTHER_HEADER: [&[Field]; 4] = [IDS, COMMAND_STATUS, CLASS, MISC];
+
+const PM_CONTROL: &[Field] = &[
+    field("Power State", 0, 2),
+    bit("No Soft Reset", 3),
+    bit("PME Enable", 8),
+    bit("PME Status", 15),
+];
+const MSI_CONTROL: &[Field] = &[
+    bit("MSI Enable", 16),
+    field("Multiple Message Capable", 17, 20),
+    field("Multiple Message Enable", 20, 23),
+    bit("64-bit Address", 23),
+    bit("Per-Vector Masking", 24),
+];
+const MSI_ADDRESS: &[Field] = &[field("Message Address", 0, 32)];
+const MSI_UPPER_ADDRESS: &[Field] = &[field("Message Upper Address", 0, 32)];
+const MSI_DATA: &[Field] = &[field("Message Data", 0, 16)];
+const MSI_MASK: &[Field] = &[field("Mask Bits", 0, 32)];
+const MSI_PENDING: &[Field] = &[field("Pending Bits", 0, 32)];
+const MSIX_CONTROL: &[Field] = &[
+    field("Table Size", 16, 27),
+    bit("Function Mask", 30),
+    bit("MSI-X Enable", 31),
+];
+const MSIX_TABLE: &[Field] = &[field("Table BIR", 0, 3), field("Table Offset", 3, 32)];
+const MSIX_PBA: &[Field] = &[field("PBA BIR", 0, 3), field("PBA Offset", 3, 32)];
+const PCIE_DEVICE_CONTROL_STATUS: &[Field] = &[
+    bit("Correctable Error Reporting", 0),
+    bit("Non-Fatal Error Reporting", 1),
+    bit("Fatal Error Reporting", 2),
+    bit("Unsupported Request Reporting", 3),
+    bit("Relaxed Ordering", 4),
+    field("Max Payload Size", 5, 8),
+    bit("Extended Tag", 8),
+    bit("Phantom Functions", 9),
+    bit("Aux Power PM", 10),
+    bit("No Snoop", 11),
+    field("Max Read Request Size", 12, 15),
+    bit("Initiate FLR", 15),
+    bit("Correctable Error Detected", 16),
+    bit("Non-Fatal Error Detected", 17),
+    bit("Fatal Error Detected", 18),
+    bit("Unsupported Request Detected", 19),
+    bit("Transactions Pending", 21),
+];
+const PCIE_LINK_CONTROL_STATUS: &[Field] = &[
+    field("ASPM Control", 0, 2),
+    bit("Read Completion Boundary", 3),
+    bit("Link Disable", 4),
+    bit("Retrain Link", 5),
+    bit("Common Clock Configuration", 6),
+    bit("Extended Synch", 7),
+    field("Current Link Speed", 16, 20),
+    field("Negotiated Link Width", 20, 26),
+    bit("Link Training", 27),
+    bit("Slot Clock Configuration", 28),
+    bit("Data Link Layer Link Active", 29),
+    bit("Link Bandwidth Management Status", 30),
+    bit("Link Autonomous Bandwidth Status", 31),
+];
+const PCIE_SLOT_CONTROL_STATUS: &[Field] =
+    &[field("Slot Control", 0, 16), field("Slot Status", 16, 32)];
+const PCIE_DEVICE_CONTROL_STATUS_2: &[Field] = &[
+    field("Device Control 2", 0, 16),
+    field("Device Status 2", 16, 32),
+];
+const PCIE_LINK_CONTROL_STATUS_2: &[Field] = &[
+    field("Target Link Speed", 0, 4),
+    field("Link Control 2", 4, 16),
+    field("Link Status 2", 16, 32),
+];
+const AER_UNCORRECTABLE: &[Field] = &[
+    bit("Data Link Protocol Error", 4),
+    bit("Surprise Down Error", 5),
+    bit("Poisoned TLP", 12),
+    bit("Flow Control Protocol Error", 13),
+    bit("Completion Timeout", 14),
+    bit("Completer Abort", 15),
+    bit("Unexpected Completion", 16),
+    bit("Receiver Overflow", 17),
+    bit("Malformed TLP", 18),
+    bit("ECRC Error", 19),
+    bit("Unsupported Request Error", 20),
+    bit("ACS Violation", 21),
+];
+const AER_CORRECTABLE: &[Field] = &[
+    bit("Receiver Error", 0),
+    bit("Bad TLP", 6),
+    bit("Bad DLLP", 7),
+    bit("REPLAY_NUM Rollover", 8),
+    bit("Replay Timer Timeout", 12),
+    bit("Advisory Non-Fatal Error", 13),
+    bit("Corrected Internal Error", 14),
+    bit("Header Log Overflow", 15),
+];
+const AER_CONTROL: &[Field] = &[
+    field("First Error Pointer", 0, 5),
+    bit("ECRC Generation Enable", 6),
+    bit("ECRC Check Enable", 8),
+];
+
+/// The part of the configuration space a register belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Region {
+    /// The type 0/1/2 header.
+    Header,
+    /// The capability at `offset`.
+    Capability { id: CapabilityId, offset: u8 },
+    /// The extended (PCIe) capability at `offset`.
+    ExtendedCapability { id: u16, offset: u16 },
+    /// Not part of the header or a known capability (e.g., device specific
+    /// registers).
+    Unknown,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Header => write!(f, "header"),
+            Region::Capability { id, offset } => write!(f, "[{:02x}] {:?}", offset, id),
+            Region::ExtendedCapability { id, offset } => {
+                match EXT_CAP_NAMES.iter().find(|(known, _)| known == id) {
+                    Some((_, name)) => write!(f, "[{:03x}] {}", offset, name),
+                    None => write!(f, "[{:03x}] Extended Capability {:#06x}", offset, id),
+                }
+            }
+            Region::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A snapshot of the configuration space of a function.
+#[derive(Debug, Clone)]
+pub struct ConfigSnapshot {
+    address: PCIAddress,
+    dwords: Vec<u32>,
+}
+
+impl ConfigSnapshot {
+    /// Reads the configuration space of `device`, including the extended
+    /// configuration space if it is a PCIe function.
+    pub fn capture<A: ConfigSpace>(device: &PciDevice<A>) -> ConfigSnapshot {
+        let len = if device.has_capability(CapabilityId::PCIExpress) {
+            PCIE_CONFIG_SIZE
+        } else {
+            PCI_CONFIG_SIZE
+        };
+        ConfigSnapshot::capture_len(&*device.config(), len)
+    }
+
+    /// Reads the first `len` bytes (rounded up to dwords) of `config`.
+    pub fn capture_len<A: ConfigSpace>(config: &A, len: usize) -> ConfigSnapshot {
+        let dwords = (0..len.min(PCIE_CONFIG_SIZE).div_ceil(4))
+            .map(|i| config.read32(i as u32 * 4))
+            .collect();
+        ConfigSnapshot {
+            address: config.address(),
+            dwords,
+        }
+    }
+
+    pub fn address(&self) -> PCIAddress {
+        self.address
+    }
+
+    /// Number of bytes captured.
+    pub fn len(&self) -> usize {
+        self.dwords.len() * 4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dwords.is_empty()
+    }
+
+    /// The dword at `offset` (rounded down to a dword), None if it wasn't
+    /// captured.
+    pub fn read32(&self, offset: usize) -> Option<u32> {
+        self.dwords.get(offset / 4).copied()
+    }
+
+    fn read8(&self, offset: usize) -> u8 {
+        self.read32(offset)
+            .map_or(0, |dword| (dword >> ((offset & 0b11) * 8)) as u8)
+    }
+
+    /// The region the dword at `offset` belongs to.
+    pub fn region(&self, offset: usize) -> Region {
+        if offset < FIRST_CAPABILITY {
+            return Region::Header;
+        }
+        if offset < PCI_CONFIG_SIZE {
+            return self
+                .capabilities()
+                .filter(|(_, cap_offset)| *cap_offset as usize <= offset)
+                .max_by_key(|(_, cap_offset)| *cap_offset)
+                .map_or(Region::Unknown, |(id, offset)| Region::Capability {
+                    id,
+                    offset,
+                });
+        }
+        self.extended_capabilities()
+            .filter(|(_, cap_offset)| *cap_offset as usize <= offset)
+            .max_by_key(|(_, cap_offset)| *cap_offset)
+            .map_or(Region::Unknown, |(id, offset)| Region::ExtendedCapability {
+                id,
+                offset,
+            })
+    }
+
+    /// Walks the capability list, stopping at loops and bogus pointers.
+    fn capabilities(&self) -> impl Iterator<Item = (CapabilityId, u8)> + '_ {
+        let has_list = self.read32(0x04).is_some_and(|dword| dword.get_bit(20));
+        let mut next = if has_list { self.read8(0x34) } else { 0 };
+        let mut chain = CapabilityChain::new(FIRST_CAPABILITY as u16, PCI_CONFIG_SIZE as u16);
+        core::iter::from_fn(move || {
+            if next == 0 || chain.check(next as u16).is_err() {
+                return None;
+            }
+            let offset = next;
+            next = self.read8(offset as usize + 1);
+            Some((CapabilityId::from(self.read8(offset as usize)), offset))
+        })
+    }
+
+    /// Walks the extended capability list, stopping at loops and bogus
+    /// pointers.
+    fn extended_capabilities(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let mut next = PCI_CONFIG_SIZE as u16;
+        let mut chain = CapabilityChain::new(PCI_CONFIG_SIZE as u16, PCIE_CONFIG_SIZE as u16);
+        core::iter::from_fn(move || {
+            if next == 0 || chain.check(next).is_err() {
+                return None;
+            }
+            let header = self
+                .read32(next as usize)
+                .filter(|header| *header != 0 && *header != u32::MAX)?;
+            let offset = next;
+            next = header.get_bits(20..32) as u16;
+            Some((header.get_bits(0..16) as u16, offset))
+        })
+    }
+
+    /// The named fields of the dword at `offset`, empty if there are none.
+    fn fields(&self, offset: usize) -> &'static [Field] {
+        match self.region(offset) {
+            Region::Header => match self.read8(0x0e).get_bits(0..7) {
+                0 => ENDPOINT_HEADER[offset / 4],
+                1 => BRIDGE_HEADER[offset / 4],
+                _ => OTHER_HEADER.get(offset / 4).copied().unwrap_or(&[]),
+            },
+            Region::Capability { id, offset: base } => {
+                self.capability_fields(id, base as usize, offset - base as usize)
+            }
+            Region::ExtendedCapability {
+                id: EXT_CAP_AER,
+                offset: base,
+            } => match offset - base as usize {
+                0x04..=0x0c => AER_UNCORRECTABLE,
+                0x10 | 0x14 => AER_CORRECTABLE,
+                0x18 => AER_CONTROL,
+                _ => &[],
+            },
+            _ => &[],
+        }
+    }
+
+    fn capability_fields(&self, id: CapabilityId, base: usize, reg: usize) -> &'static [Field] {
+        match (id, reg) {
+            (CapabilityId::PowerManagement, 0x04) => PM_CONTROL,
+            (CapabilityId::Msi, 0x00) => MSI_CONTROL,
+            (CapabilityId::Msi, 0x04) => MSI_ADDRESS,
+            (CapabilityId::Msi, _) => {
+                let is_64bit = self.read32(base).is_some_and(|dword| dword.get_bit(23));
+                match (reg, is_64bit) {
+                    (0x08, true) => MSI_UPPER_ADDRESS,
+                    (0x08, false) | (0x0c, true) => MSI_DATA,
+                    (0x0c, false) | (0x10, true) => MSI_MASK,
+                    (0x10, false) | (0x14, true) => MSI_PENDING,
+                    _ => &[],
+                }
+            }
+            (CapabilityId::MsiX, 0x00) => MSIX_CONTROL,
+            (CapabilityId::MsiX, 0x04) => MSIX_TABLE,
+            (CapabilityId::MsiX, 0x08) => MSIX_PBA,
+            (CapabilityId::PCIExpress, 0x08) => PCIE_DEVICE_CONTROL_STATUS,
+            (CapabilityId::PCIExpress, 0x10) => PCIE_LINK_CONTROL_STATUS,
+            (CapabilityId::PCIExpress, 0x18) => PCIE_SLOT_CONTROL_STATUS,
+            (CapabilityId::PCIExpress, 0x28) => PCIE_DEVICE_CONTROL_STATUS_2,
+            (CapabilityId::PCIExpress, 0x30) => PCIE_LINK_CONTROL_STATUS_2,
+            _ => &[],
+        }
+    }
+
+    /// Compares this (earlier) snapshot to `after`.
+    pub fn diff(&self, after: &ConfigSnapshot) -> ConfigDiff {
+        ConfigDiff::new(self, after)
+    }
+}
+
+/// A changed field of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FieldChange {
+    pub name: &'static str,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// A changed configuration space dword.
+#[derive(Debug, Clone)]
+pub struct RegisterChange {
+    /// Offset of the dword.
+    pub offset: u16,
+    pub region: Region,
+    pub old: u32,
+    pub new: u32,
+    /// The named fields that changed, empty if the register isn't decoded.
+    pub fields: Vec<FieldChange>,
+}
+
+impl RegisterChange {
+    /// The bits that changed.
+    pub fn changed_bits(&self) -> u32 {
+        self.old ^ self.new
+    }
+}
+
+/// The differences between two snapshots of the same function.
+#[derive(Debug, Clone)]
+pub struct ConfigDiff {
+    address: PCIAddress,
+    changes: Vec<RegisterChange>,
+}
+
+impl ConfigDiff {
+    /// Compares the dwords both `before` and `after` captured.
+    pub fn new(before: &ConfigSnapshot, after: &ConfigSnapshot) -> ConfigDiff {
+        // A function that wasn't there (or powered off) reads as all ones.
+        let primary = match before.read32(0) {
+            Some(ids) if ids != u32::MAX => before,
+            _ => after,
+        };
+        let changes = before
+            .dwords
+            .iter()
+            .zip(&after.dwords)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(index, (&old, &new))| {
+                let offset = index * 4;
+                let layout = match primary.region(offset) {
+                    Region::Unknown => after,
+                    _ => primary,
+                };
+                let fields = layout
+                    .fields(offset)
+                    .iter()
+                    .map(|field| FieldChange {
+                        name: field.name,
+                        old: old.get_bits(field.start..field.end),
+                        new: new.get_bits(field.start..field.end),
+                    })
+                    .filter(|change| change.old != change.new)
+                    .collect();
+                RegisterChange {
+                    offset: offset as u16,
+                    region: layout.region(offset),
+                    old,
+                    new,
+                    fields,
+                }
+            })
+            .collect();
+        ConfigDiff {
+            address: after.address,
+            changes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn changes(&self) -> &[RegisterChange] {
+        &self.changes
+    }
+
+    /// The first change of a field called `name` (e.g., "Bus Master").
+    pub fn field(&self, name: &str) -> Option<&FieldChange> {
+        self.changes
+            .iter()
+            .flat_map(|change| change.fields.iter())
+            .find(|field| field.name == name)
+    }
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:?}: {} registers changed",
+            self.address,
+            self.changes.len()
+        )?;
+        for change in &self.changes {
+            writeln!(
+                f,
+                "\t{:03x} {}: {:08x} -> {:08x}",
+                change.offset, change.region, change.old, change.new
+            )?;
+            for field in &change.fields {
+                writeln!(
+                    f,
+                    "\t\t{}: {:#x} -> {:#x}",
+                    field.name, field.old, field.new
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::mock::MockConfig;
+    use crate::pci::PciInterface;
+    use alloc::string::ToString;
+
+    #[test]
+    fn decodes_changed_fields() {
+        // PCIe -> MSI-X, AER in the extended configuration space
+        let mut config = MockConfig::with_capability(0x40, &[0x0002_5010, 0, 0, 0, 0x0007_0011]);
+        config.write32(0x04, 0x0010_0006);
+        config.write32(0x100, 0x0001_0001);
+        let mut device = PciDevice::from_config(config).unwrap();
+        let before = ConfigSnapshot::capture(&device);
+        assert_eq!(before.len(), PCIE_CONFIG_SIZE);
+
+        // Bus mastering off, the MSI-X function masked, a new interrupt line
+        // and a surprise down logged.
+        let config = device.config_mut();
+        config.write(0x04, 0x0010_0002);
+        config.write(0x50, 0x4007_0011);
+        config.write(0x104, 1 << 5);
+        config.write(0x3c, 0x0000_010b);
+        let after = ConfigSnapshot::capture(&device);
+
+        let diff = before.diff(&after);
+        let offsets: Vec<u16> = diff.changes().iter().map(|change| change.offset).collect();
+        assert_eq!(offsets, [0x04, 0x3c, 0x50, 0x104]);
+        assert_eq!(
+            diff.field("Bus Master"),
+            Some(&FieldChange {
+                name: "Bus Master",
+                old: 1,
+                new: 0
+            })
+        );
+        assert_eq!(diff.changes()[1].fields.len(), 2);
+        assert_eq!(
+            diff.changes()[2].region,
+            Region::Capability {
+                id: CapabilityId::MsiX,
+                offset: 0x50
+            }
+        );
+        assert_eq!(diff.field("Function Mask").unwrap().new, 1);
+        assert!(diff.field("Surprise Down Error").is_some());
+        assert!(diff.to_string().contains("[100] Advanced Error Reporting"));
+        assert!(before.diff(&before).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+//! Configuration space access through ECAM (memory mapped configuration,
+//! "MMCONFIG").
+//!
+//! The legacy mechanism ([`PCIAddress`] through ports 0xCF8/0xCFC) only
+//! reaches the first 256 bytes of a function's configuration space. PCIe
+//! functions have 4 KiB, the rest (extended capabilities like AER, SR-IOV,
+//! ATS) is only accessible through the ECAM window, where every function's
+//! configuration space is a page of memory. The window's physical address
+//! and bus range come from the ACPI MCFG table or the device tree, the
+//! driver maps it uncached and wraps it in an [`EcamRegion`].
+
+use core::ops::RangeInclusive;
+
+use crate::arch::{PciInterface, VAddr};
+
+use super::{ConfigSpace, PCIAddress, PciDevice};
+
+/// Size of the configuration space of a function in the ECAM window.
+pub const ECAM_FUNCTION_SIZE: usize = 1 << 12;
+/// Size of the configuration spaces of a bus in the ECAM window.
+pub const ECAM_BUS_SIZE: usize = 1 << 20;
+
+/// A mapped ECAM window of a range of buses.
+#[derive(Debug, Clone)]
+pub struct EcamRegion {
+    base: VAddr,
+    buses: RangeInclusive<u8>,
+}
+
+impl EcamRegion {
+    /// The region of `buses` mapped at `base`.
+    ///
+    /// # Safety
+    /// `base` has to map the window of `buses` (see
+    /// [`EcamRegion::window_len`]) uncached, for as long as the region and
+    /// the configuration spaces it hands out are used.
+    pub unsafe fn new(base: VAddr, buses: RangeInclusive<u8>) -> Self {
+        assert!(!buses.is_empty(), "empty bus range");
+        EcamRegion { base, buses }
+    }
+
+    /// Size of the window of `buses` (to map it).
+    pub fn window_len(buses: &RangeInclusive<u8>) -> usize {
+        if buses.is_empty() {
+            return 0;
+        }
+        (*buses.end() as usize - *buses.start() as usize + 1) * ECAM_BUS_SIZE
+    }
+
+    pub fn buses(&self) -> RangeInclusive<u8> {
+        self.buses.clone()
+    }
+
+    /// Offset of the configuration space of `addr` in the window.
+    fn offset(&self, addr: PCIAddress) -> Option<usize> {
+        if !self.buses.contains(&addr.bus) || addr.dev >= 32 || addr.fun >= 8 {
+            return None;
+        }
+        let bus = (addr.bus - self.buses.start()) as usize;
+        Some(bus * ECAM_BUS_SIZE + ((addr.dev as usize) << 15 | (addr.fun as usize) << 12))
+    }
+
+    /// The configuration space of `addr`, if it's in the window.
+    pub fn config(&self, addr: PCIAddress) -> Option<EcamConfig<'_>> {
+        let offset = self.offset(addr)?;
+        Some(EcamConfig {
+            region: self,
+            addr,
+            offset,
+        })
+    }
+
+    /// The function at `addr`, if it's in the window and present.
+    pub fn device(&self, addr: PCIAddress) -> Option<PciDevice<EcamConfig<'_>>> {
+        PciDevice::from_config(self.config(addr)?)
+    }
+
+    /// All functions present in the window.
+    pub fn scan(&self) -> impl Iterator<Item = PciDevice<EcamConfig<'_>>> + '_ {
+        self.buses()
+            .flat_map(|bus| {
+                (0..32).flat_map(move |dev| (0..8).map(move |fun| PCIAddress { bus, dev, fun }))
+            })
+            .filter_map(move |addr| self.device(addr))
+    }
+}
+
+/// The configuration space of a function in an [`EcamRegion`], all
+/// [`ECAM_FUNCTION_SIZE`] bytes of it.
+#[derive(Debug, Clone)]
+pub struct EcamConfig<'a> {
+    region: &'a EcamRegion,
+    addr: PCIAddress,
+    /// Offset of the function in the window.
+    offset: usize,
+}
+
+impl<'a> EcamConfig<'a> {
+    fn register(&self, offset: u32) -> *mut u32 {
+        assert!(offset.is_multiple_of(4) && (offset as usize) < ECAM_FUNCTION_SIZE);
+        self.region
+            .base
+            .as_mut_ptr::<u8>()
+            .wrapping_add(self.offset + offset as usize) as *mut u32
+    }
+}
+
+impl<'a> PciInterface for EcamConfig<'a> {
+    fn read(&self, offset: u32) -> u32 {
+        crate::metrics::PCI_CONFIG_READS.inc();
+        // Safety: the register is in the mapped window (see `register` and
+        // `EcamRegion::new`)
+        unsafe { core::ptr::read_volatile(self.register(offset)) }
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        crate::metrics::PCI_CONFIG_WRITES.inc();
+        // Safety: as in `read`
+        unsafe { core::ptr::write_volatile(self.register(offset), value) };
+    }
+}
+
+impl<'a> ConfigSpace for EcamConfig<'a> {
+    fn address(&self) -> PCIAddress {
+        self.addr
+    }
+
+    fn config_size(&self) -> u32 {
+        ECAM_FUNCTION_SIZE as u32
+    }
+}
+
+// The ECAM window of the test is too large for the stack
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    #[test]
+    fn extended_config_space() {
+        let buses = 2..=3;
+        let mut window = vec![u32::MAX; EcamRegion::window_len(&buses) / 4];
+        // Functions 03:00.0 and 02:1f.7
+        let function = |bus: usize, dev: usize, fun: usize| {
+            ((bus - 2) * ECAM_BUS_SIZE + (dev << 15 | fun << 12)) / 4
+        };
+        window[function(3, 0, 0)] = 0x1533_8086;
+        window[function(2, 31, 7)] = 0x7d00_1022;
+        // Safety: the vector outlives the region
+        let region = unsafe { EcamRegion::new(VAddr::from(window.as_mut_ptr() as u64), buses) };
+
+        let found: Vec<PCIAddress> = region.scan().map(|device| device.pci_address()).collect();
+        assert_eq!(
+            found,
+            [
+                PCIAddress {
+                    bus: 2,
+                    dev: 31,
+                    fun: 7
+                },
+                PCIAddress {
+                    bus: 3,
+                    dev: 0,
+                    fun: 0
+                }
+            ]
+        );
+        assert!(region
+            .config(PCIAddress {
+                bus: 4,
+                dev: 0,
+                fun: 0
+            })
+            .is_none());
+
+        let mut config = region
+            .config(PCIAddress {
+                bus: 3,
+                dev: 0,
+                fun: 0,
+            })
+            .unwrap();
+        config.write32(0xffc, 0x1234_5678);
+        assert_eq!(config.read16(0xffe), 0x1234);
+        assert_eq!(config.read32(0), 0x1533_8086);
+        assert_eq!(window[function(3, 0, 0) + 0x3ff], 0x1234_5678);
+    }
+}
@@ -0,0 +1,142 @@
+//! A configuration space backed by memory.
+//!
+//! Useful to test and fuzz the parsing code in this crate without hardware.
+//! Apart from BARs (which can be given a size) the configuration space
+//! behaves like plain memory.
+
+use bit_field::BitField;
+
+use super::{ConfigSpace, PCIAddress};
+use crate::arch::PciInterface;
+
+/// Size of the (extended) configuration space of a function.
+pub const CONFIG_SPACE_SIZE: usize = 4096;
+
+const BAR0: usize = 0x10;
+const BARS: usize = 6;
+
+#[derive(Clone)]
+pub struct MockConfig {
+    addr: PCIAddress,
+    space: [u8; CONFIG_SPACE_SIZE],
+    /// Writable bits of every BAR register.
+    bar_masks: [u32; BARS],
+}
+
+impl MockConfig {
+    /// A configuration space with all bytes set to zero.
+    pub fn new(addr: PCIAddress) -> MockConfig {
+        MockConfig {
+            addr,
+            space: [0; CONFIG_SPACE_SIZE],
+            bar_masks: [u32::MAX; BARS],
+        }
+    }
+
+    /// A configuration space initialized with `bytes` (truncated to
+    /// [`CONFIG_SPACE_SIZE`], the rest is zero).
+    pub fn from_bytes(addr: PCIAddress, bytes: &[u8]) -> MockConfig {
+        let mut config = MockConfig::new(addr);
+        let len = bytes.len().min(CONFIG_SPACE_SIZE);
+        config.space[..len].copy_from_slice(&bytes[..len]);
+        config
+    }
+
+    /// A function at 00:01.0 (device 0x1234 of vendor 0x8086) with a
+    /// capability list at `offset`, made of the dwords in `capability`
+    /// (which link any further capabilities themselves).
+    pub fn with_capability(offset: u8, capability: &[u32]) -> MockConfig {
+        let mut config = MockConfig::new(PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        });
+        config.write_raw(0x00, 0x1234_8086);
+        // Status: capabilities list
+        config.space[0x06] = 0x10;
+        config.space[0x34] = offset;
+        for (i, dword) in capability.iter().enumerate() {
+            config.write_raw(offset as usize + i * 4, *dword);
+        }
+        config
+    }
+
+    /// Makes BAR register `index` behave like hardware: only the bits in
+    /// `mask` can be written, so sizing the BAR returns `mask` (plus the
+    /// read-only type bits).
+    pub fn set_bar_mask(&mut self, index: usize, mask: u32) {
+        self.bar_masks[index] = mask;
+    }
+
+    /// Sets up BAR `index` as a memory BAR at `address` with `size` bytes
+    /// (`size` must be a power of two >= 16). A 64-bit BAR also occupies
+    /// `index + 1`.
+    pub fn set_memory_bar(&mut self, index: usize, address: u64, size: u64, is_64bit: bool) {
+        assert!(size.is_power_of_two() && size >= 16);
+        let mask = !(size - 1);
+
+        let mut low = address as u32 & !0xF;
+        if is_64bit {
+            low.set_bits(1..3, 0b10);
+            self.set_bar_mask(index, mask as u32 & !0xF);
+            self.set_bar_mask(index + 1, (mask >> 32) as u32);
+            self.write_raw(BAR0 + (index + 1) * 4, (address >> 32) as u32);
+        } else {
+            self.set_bar_mask(index, mask as u32 & !0xF);
+        }
+        self.write_raw(BAR0 + index * 4, low);
+    }
+
+    /// The raw bytes of the configuration space.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.space
+    }
+
+    fn write_raw(&mut self, offset: usize, value: u32) {
+        self.space[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl PciInterface for MockConfig {
+    fn read(&self, offset: u32) -> u32 {
+        let offset = offset as usize & !0b11;
+        if offset + 4 > CONFIG_SPACE_SIZE {
+            return u32::MAX;
+        }
+
+        let mut value = [0u8; 4];
+        value.copy_from_slice(&self.space[offset..offset + 4]);
+        u32::from_le_bytes(value)
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        let offset = offset as usize & !0b11;
+        if offset + 4 > CONFIG_SPACE_SIZE {
+            return;
+        }
+
+        let value = if (BAR0..BAR0 + BARS * 4).contains(&offset) {
+            let mask = self.bar_masks[(offset - BAR0) / 4];
+            (value & mask) | (self.read(offset as u32) & !mask)
+        } else {
+            value
+        };
+        self.write_raw(offset, value);
+    }
+}
+
+impl ConfigSpace for MockConfig {
+    fn address(&self) -> PCIAddress {
+        self.addr
+    }
+
+    fn config_size(&self) -> u32 {
+        CONFIG_SPACE_SIZE as u32
+    }
+}
+
+impl core::fmt::Debug for MockConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "MockConfig({:?})", self.addr)
+    }
+}
@@ -1,10 +1,93 @@
-use core::{fmt, ptr::addr_of_mut};
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::addr_of_mut;
 
 use bit_field::BitField;
+use custom_error::custom_error;
 
-use crate::arch::{PAddr, VAddr, PciInterface};
+use crate::arch::{PAddr, PciInterface, VAddr};
+use crate::fixed::FixedVec;
 
+#[cfg(feature = "alloc")]
+pub mod builder;
+pub mod cardbus;
 pub mod device_db;
+#[cfg(feature = "alloc")]
+pub mod diff;
+pub mod ecam;
+pub mod mock;
+pub mod msix;
+pub mod pcie;
+#[cfg(feature = "alloc")]
+pub mod queues;
+pub mod quirks;
+#[cfg(feature = "alloc")]
+pub mod readonly;
+pub mod recovery;
+pub mod verbose;
+
+#[cfg(feature = "alloc")]
+pub use builder::{PciDeviceHandle, PciDriverBuilder};
+pub use cardbus::{CardBus, CardBusWindow};
+#[cfg(feature = "alloc")]
+pub use diff::{ConfigDiff, ConfigSnapshot};
+pub use ecam::{EcamConfig, EcamRegion};
+pub use msix::{MsixTable, MsixVector, PbaPoller};
+pub use pcie::{PciExpress, PciExpressPortType, SlotPower};
+#[cfg(feature = "alloc")]
+pub use queues::{DoorbellLayout, QueueHandle};
+pub use quirks::{QuirkFlags, Quirks};
+#[cfg(feature = "alloc")]
+pub use readonly::ReadOnlyPciDevice;
+pub use recovery::{LinkEvent, LinkEventHandler, LinkMonitor, SavedState};
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub PciError
+    DeviceNotFound = "no matching PCI device was found",
+    NoMsi = "the device doesn't have an MSI capability",
+    NoMsiX = "the device doesn't have an MSI-X capability",
+    UnbindFailed = "couldn't unbind the kernel driver from the device",
+    DeviceClaimed = "the device is claimed by another process",
+    LockFailed = "couldn't lock the device",
+    MsiAddressTooWide{address: u64} = "MSI address {address:#x} is above 4 GiB, the device only supports 32-bit addresses",
+    MsiInvalidVectors{count: usize} = "the device can't enable {count} MSI vectors",
+    MsiMaskingUnsupported = "the device doesn't support masking MSI vectors",
+    MsiVectorOutOfRange{index: usize} = "MSI vector {index} is beyond the vectors of the device",
+    MsiDestinationInvalid{cpu: u32} = "CPU {cpu} can't be addressed by an MSI message",
+    MsiAffinityUnsupported = "MSI messages can't be routed to a CPU on this architecture",
+    MsiXInvalidBar{bir: u8} = "the MSI-X structures are in BAR {bir}, which isn't a memory BAR",
+    MsiXOutOfBounds{bir: u8, offset: u64, len: u64} = "the MSI-X structure at offset {offset} ({len} bytes) doesn't fit in BAR {bir}",
+    MsiXMisaligned{offset: u64} = "the MSI-X table at offset {offset} isn't 8-byte aligned",
+    MsiXVectorOutOfRange{index: usize} = "MSI-X vector {index} is beyond the end of the table",
+    MsiXBarsNotMapped = "MSI-X setup requires the BARs to be mapped",
+    MsiXMapFailed{paddr: u64, len: usize} = "couldn't map {len} bytes of MSI-X structures at {paddr}",
+    LinkDown = "the link to the device didn't come back up",
+    NotPrefetchable{index: u8} = "BAR {index} isn't prefetchable and can't be mapped write-combining",
+    CapabilityInvalidOffset{offset: u16} = "capability pointer {offset} is unaligned or points into the header",
+    CapabilityLoop{offset: u16} = "capability list loops back to offset {offset}",
+    BarNotMapped{index: u8} = "BAR {index} isn't a mapped memory BAR",
+    DoorbellOutOfBounds{bar: u8, offset: u64, len: u64} = "the doorbells at offset {offset} ({len} bytes) don't fit in BAR {bar}",
+}
+
+/// Latency timer set by [`PciDevice::configure_cacheline_and_latency`] (in
+/// PCI bus clocks, as Linux does).
+pub const DEFAULT_LATENCY_TIMER: u8 = 64;
+/// Latency timers below this are considered unconfigured.
+pub const MIN_LATENCY_TIMER: u8 = 16;
+
+/// The most BARs a header has (type 0).
+pub const MAX_BARS: usize = 6;
+/// The most capabilities that fit into the 192 bytes after the header.
+pub const MAX_CAPABILITIES: usize = 48;
+
+/// The memory BARs of a device, see [`PciDevice::bar_table`].
+pub type BarTable = FixedVec<(BarIndex, Bar), MAX_BARS>;
+/// The capabilities of a device, see [`PciDevice::capability_list`].
+pub type CapabilityList = FixedVec<Capability, MAX_CAPABILITIES>;
 
 pub type VendorId = u16;
 pub type DeviceId = u16;
@@ -15,19 +98,42 @@ pub type Interface = u8;
 pub type HeaderType = u8;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PciDeviceType {
     Endpoint = 0x00,
     PciBridge = 0x01,
+    CardBusBridge = 0x02,
     Unknown = 0xff,
 }
 
+impl PciDeviceType {
+    /// Number of BARs in the header.
+    pub fn bar_count(&self) -> u8 {
+        match self {
+            PciDeviceType::Endpoint => 6,
+            PciDeviceType::PciBridge => 2,
+            PciDeviceType::CardBusBridge => 1,
+            PciDeviceType::Unknown => 0,
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PCIAddress {
     pub bus: u8,
     pub dev: u8,
     pub fun: u8,
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for PCIAddress {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=u8}:{=u8}.{=u8}", self.bus, self.dev, self.fun)
+    }
+}
+
 impl PCIAddress {
     fn new(bus: u8, dev: u8, fun: u8) -> Self {
         assert!(dev <= 31);
@@ -48,14 +154,115 @@ impl fmt::Debug for PCIAddress {
     }
 }
 
+/// Access to the configuration space of a single PCI function.
+///
+/// `read`/`write` of [`PciInterface`] take an offset into the function's
+/// configuration space. [`PCIAddress`] goes through the legacy configuration
+/// mechanism, other backends (e.g., sysfs on Linux) can be plugged into
+/// [`PciDevice`] by implementing this trait.
+pub trait ConfigSpace: PciInterface {
+    /// The address of the function this configuration space belongs to.
+    fn address(&self) -> PCIAddress;
+
+    /// Bytes of the configuration space the backend can reach: 256 with the
+    /// legacy mechanism, 4 KiB with backends that reach the PCIe extended
+    /// configuration space.
+    fn config_size(&self) -> u32 {
+        0x100
+    }
+
+    /// Reads the (aligned) dword at `offset`.
+    fn read32(&self, offset: u32) -> u32 {
+        debug_assert!(offset.is_multiple_of(4));
+        self.read(offset & !0b11)
+    }
+
+    /// Reads the (aligned) word at `offset`.
+    fn read16(&self, offset: u32) -> u16 {
+        debug_assert!(offset.is_multiple_of(2));
+        (self.read(offset & !0b11) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    fn read8(&self, offset: u32) -> u8 {
+        (self.read(offset & !0b11) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    /// Writes the (aligned) dword at `offset`.
+    fn write32(&mut self, offset: u32, value: u32) {
+        debug_assert!(offset.is_multiple_of(4));
+        self.write(offset & !0b11, value);
+    }
+
+    /// Writes the (aligned) word at `offset`.
+    ///
+    /// The backends only do dword accesses, so this is a read-modify-write
+    /// of the dword (see [`merge_write`] for what happens to the rest of
+    /// it).
+    fn write16(&mut self, offset: u32, value: u16) {
+        debug_assert!(offset.is_multiple_of(2));
+        let current = self.read(offset & !0b11);
+        self.write(
+            offset & !0b11,
+            merge_write(current, offset, 2, value as u32),
+        );
+    }
+
+    /// Writes the byte at `offset` (see [`ConfigSpace::write16`]).
+    fn write8(&mut self, offset: u32, value: u8) {
+        let current = self.read(offset & !0b11);
+        self.write(
+            offset & !0b11,
+            merge_write(current, offset, 1, value as u32),
+        );
+    }
+}
+
+/// Bits of header dwords that have side effects when written back as read:
+/// the status register (RW1C, writing 0 doesn't change it) and the start
+/// bit of the BIST register.
+const WRITE_BACK_CLEAR: [(u32, u32); 2] = [(0x04, 0xffff_0000), (0x0c, 1 << 30)];
+
+/// Merges a `width` byte write of `value` at `offset` into the dword
+/// `current` it's in.
+///
+/// The other bytes of the dword keep their value, except for the bits in
+/// [`WRITE_BACK_CLEAR`], which are written as 0 so that, e.g., writing the
+/// command register doesn't clear status bits.
+pub fn merge_write(current: u32, offset: u32, width: u32, value: u32) -> u32 {
+    let shift = (offset & 0b11) * 8;
+    let mask = (u32::MAX >> (32 - width * 8)) << shift;
+    let clear = WRITE_BACK_CLEAR
+        .iter()
+        .find(|(dword, _)| *dword == offset & !0b11)
+        .map_or(0, |(_, bits)| *bits);
+    (current & !mask & !clear) | ((value << shift) & mask)
+}
+
+impl ConfigSpace for PCIAddress {
+    fn address(&self) -> PCIAddress {
+        *self
+    }
+}
+
+/// The configuration space of a function.
+///
+/// The backend is behind a reader-writer lock, so that reads, and
+/// sequences that have to write to read something (BAR sizing), work on a
+/// shared device. Reads share the lock, writes take it exclusively, a
+/// read-modify-write holds it for the whole sequence (see
+/// [`PCIHeader::update`]).
 #[derive(Debug)]
-pub struct PCIHeader(PCIAddress);
+pub struct PCIHeader<A = PCIAddress> {
+    config: spin::RwLock<A>,
+    /// BARs aren't sized (see [`ReadOnlyPciDevice`]).
+    read_only: bool,
+}
 
 impl PCIHeader {
     pub fn new(bus: u8, device: u8, function: u8) -> Option<Self> {
         let addr = PCIAddress::new(bus, device, function);
         if PCIHeader::is_valid(addr) {
-            Some(PCIHeader(addr))
+            Some(PCIHeader::from_config(addr))
         } else {
             None
         }
@@ -66,9 +273,84 @@ impl PCIHeader {
     }
 }
 
+impl<A: ConfigSpace> PCIHeader<A> {
+    fn from_config(config: A) -> Self {
+        PCIHeader {
+            config: spin::RwLock::new(config),
+            read_only: false,
+        }
+    }
+
+    /// Shares the backend with other readers.
+    fn shared(&self) -> spin::RwLockReadGuard<'_, A> {
+        self.config.read()
+    }
+
+    /// Locks the backend exclusively, for sequences of accesses that write.
+    ///
+    /// Spins while a guard of [`PCIHeader::shared`] is held, including one
+    /// of the calling thread.
+    fn lock(&self) -> spin::RwLockWriteGuard<'_, A> {
+        self.config.write()
+    }
+
+    fn address(&self) -> PCIAddress {
+        self.shared().address()
+    }
+
+    fn config_size(&self) -> u32 {
+        self.shared().config_size()
+    }
+
+    fn read(&self, offset: u32) -> u32 {
+        self.shared().read(offset)
+    }
+
+    fn read32(&self, offset: u32) -> u32 {
+        self.shared().read32(offset)
+    }
+
+    fn read16(&self, offset: u32) -> u16 {
+        self.shared().read16(offset)
+    }
+
+    fn read8(&self, offset: u32) -> u8 {
+        self.shared().read8(offset)
+    }
+
+    fn write(&self, offset: u32, value: u32) {
+        self.lock().write(offset, value);
+    }
+
+    fn write16(&self, offset: u32, value: u16) {
+        self.lock().write16(offset, value);
+    }
+
+    fn write8(&self, offset: u32, value: u8) {
+        self.lock().write8(offset, value);
+    }
+
+    /// Replaces the dword at `offset` with `f` of its value, without
+    /// anybody writing it in between.
+    fn update(&self, offset: u32, f: impl FnOnce(u32) -> u32) {
+        let mut config = self.lock();
+        let value = config.read(offset);
+        config.write(offset, f(value));
+    }
+
+    /// Like [`PCIHeader::update`] for the word at `offset`.
+    fn update16(&self, offset: u32, f: impl FnOnce(u16) -> u16) {
+        let mut config = self.lock();
+        let value = config.read16(offset);
+        config.write16(offset, f(value));
+    }
+}
+
 /// # See also
 /// <https://wiki.osdev.org/PCI#Class_Codes>
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ClassCode {
     IDEController = 0x0101,
     SATAController = 0x0106,
@@ -98,6 +380,8 @@ impl From<u16> for ClassCode {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BarType {
     IO,
     Mem,
@@ -113,6 +397,8 @@ impl From<bool> for BarType {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bar {
     pub region_type: BarType,
     pub prefetchable: bool,
@@ -120,7 +406,49 @@ pub struct Bar {
     pub size: u64,
 }
 
+/// Index of a BAR of a type 0 header (bridges only implement a prefix).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BarIndex {
+    Bar0 = 0,
+    Bar1 = 1,
+    Bar2 = 2,
+    Bar3 = 3,
+    Bar4 = 4,
+    Bar5 = 5,
+}
+
+impl BarIndex {
+    pub const ALL: [BarIndex; 6] = [
+        BarIndex::Bar0,
+        BarIndex::Bar1,
+        BarIndex::Bar2,
+        BarIndex::Bar3,
+        BarIndex::Bar4,
+        BarIndex::Bar5,
+    ];
+
+    /// Returns None if `index` is not a BAR index (i.e., above 5).
+    pub fn new(index: u8) -> Option<BarIndex> {
+        BarIndex::ALL.get(index as usize).copied()
+    }
+
+    /// Offset of the BAR register in the configuration space.
+    pub fn offset(self) -> u32 {
+        0x10 + self as u32 * 4
+    }
+}
+
+impl From<BarIndex> for u8 {
+    fn from(index: BarIndex) -> u8 {
+        index as u8
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CapabilityId {
     /// Null Capability
     ///
@@ -260,23 +588,276 @@ impl From<u8> for CapabilityId {
     }
 }
 
-pub enum CapabilityType<'s> {
-    MsiX(MsiX<'s>),
+/// A capability of a shared device (see [`PciDevice::capability`]).
+///
+/// Derefs to the capability, so only its reading methods are reachable.
+#[derive(Debug)]
+pub struct CapabilityRef<C>(C);
+
+impl<C> core::ops::Deref for CapabilityRef<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.0
+    }
+}
+
+/// A capability with a typed accessor (see [`PciDevice::capability`]).
+pub trait TypedCapability<'s, A>: Sized {
+    const ID: CapabilityId;
+
+    /// Wraps the capability at `offset`.
+    fn at(header: &'s PCIHeader<A>, offset: u32) -> Self;
+}
+
+impl<'s, A> TypedCapability<'s, A> for MsiX<'s, A> {
+    const ID: CapabilityId = CapabilityId::MsiX;
+
+    fn at(header: &'s PCIHeader<A>, offset: u32) -> Self {
+        MsiX { header, offset }
+    }
+}
+
+impl<'s, A> TypedCapability<'s, A> for Msi<'s, A> {
+    const ID: CapabilityId = CapabilityId::Msi;
+
+    fn at(header: &'s PCIHeader<A>, offset: u32) -> Self {
+        Msi { header, offset }
+    }
+}
+
+impl<'s, A> TypedCapability<'s, A> for PowerManagement<'s, A> {
+    const ID: CapabilityId = CapabilityId::PowerManagement;
+
+    fn at(header: &'s PCIHeader<A>, offset: u32) -> Self {
+        PowerManagement { header, offset }
+    }
+}
+
+pub enum CapabilityType<'s, A = PCIAddress> {
+    Msi(Msi<'s, A>),
+    MsiX(MsiX<'s, A>),
+    PowerManagement(PowerManagement<'s, A>),
     Unknown(CapabilityId),
 }
 
+/// Device power states as encoded in the PowerState field of the PMCSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerState {
+    D0 = 0b00,
+    D1 = 0b01,
+    D2 = 0b10,
+    D3Hot = 0b11,
+}
+
+impl From<u16> for PowerState {
+    fn from(value: u16) -> PowerState {
+        match value & 0b11 {
+            0b00 => PowerState::D0,
+            0b01 => PowerState::D1,
+            0b10 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct MsiX<'s> {
+pub struct PowerManagement<'s, A = PCIAddress> {
     /// A reference to the device's PCI header.
-    header: &'s mut PCIHeader,
-    /// The offset where the MSI-X config is located within the PCI header.
+    header: &'s PCIHeader<A>,
+    /// The offset where the PM capability is located within the PCI header.
     pub offset: u32,
 }
 
-impl<'s> MsiX<'s> {
+impl<'s, A: ConfigSpace> PowerManagement<'s, A> {
+    /// The Power Management Capabilities register (PMC).
+    pub fn capabilities(&self) -> u16 {
+        (self.header.read(self.offset) >> 16) as u16
+    }
+
+    pub fn supports_d1(&self) -> bool {
+        self.capabilities().get_bit(9)
+    }
+
+    pub fn supports_d2(&self) -> bool {
+        self.capabilities().get_bit(10)
+    }
+
+    /// The Power Management Control/Status register (PMCSR).
+    pub fn control_status(&self) -> u16 {
+        self.header.read(self.offset + 4) as u16
+    }
+
+    /// If set, the function keeps its configuration when going from D3hot
+    /// back to D0 and doesn't need to be re-initialized.
+    pub fn no_soft_reset(&self) -> bool {
+        self.control_status().get_bit(3)
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        PowerState::from(self.control_status())
+    }
+
+    /// Moves the function to `state`.
+    ///
+    /// The caller is responsible for waiting the transition recovery time
+    /// (10 ms for D3hot -> D0, 200 us for D2) before accessing the device.
+    pub fn set_power_state(&mut self, state: PowerState) {
+        self.header.update(self.offset + 4, |mut reg| {
+            // Don't write back PME_Status (RW1C)
+            reg.set_bit(15, false);
+            *reg.set_bits(0..2, state as u32)
+        });
+    }
+}
+
+/// The MSI capability (see [`PciDevice::msi`]).
+///
+/// Plain MSI has a single message address for all vectors: a function with
+/// `n` vectors enabled (a power of two, up to 32) signals vector `i` by
+/// writing the message data with its low `log2(n)` bits replaced by `i`,
+/// so the base data has to be aligned accordingly.
+#[derive(Debug)]
+pub struct Msi<'s, A = PCIAddress> {
+    /// A reference to the device's PCI header.
+    header: &'s PCIHeader<A>,
+    /// The offset where the MSI capability is located within the PCI header.
+    pub offset: u32,
+}
 
+impl<'s, A: ConfigSpace> Msi<'s, A> {
     pub fn message_control(&self) -> u16 {
-        (self.header.0.read(self.offset) >> 16) as u16
+        self.header.read16(self.offset + 2)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.message_control().get_bit(0)
+    }
+
+    /// Enables or disables MSI. While MSI is enabled, the function doesn't
+    /// assert INTx.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.header
+            .update16(self.offset + 2, |mut ctrl| *ctrl.set_bit(0, enabled));
+    }
+
+    /// Whether the function supports 64-bit message addresses.
+    pub fn is_64bit(&self) -> bool {
+        self.message_control().get_bit(7)
+    }
+
+    /// Whether the function has Mask and Pending Bits registers.
+    pub fn per_vector_masking(&self) -> bool {
+        self.message_control().get_bit(8)
+    }
+
+    /// The number of vectors the function requests (Multiple Message
+    /// Capable).
+    pub fn vectors_capable(&self) -> usize {
+        1 << self.message_control().get_bits(1..4).min(5)
+    }
+
+    /// The number of vectors enabled (Multiple Message Enable).
+    pub fn vectors_enabled(&self) -> usize {
+        1 << self.message_control().get_bits(4..7).min(5)
+    }
+
+    /// Enables `vectors` vectors, a power of two up to
+    /// [`Msi::vectors_capable`].
+    pub fn set_vectors_enabled(&mut self, vectors: usize) -> Result<(), PciError> {
+        if !vectors.is_power_of_two() || vectors > self.vectors_capable() {
+            return Err(PciError::MsiInvalidVectors { count: vectors });
+        }
+        let enabled = vectors.trailing_zeros() as u16;
+        self.header
+            .update16(self.offset + 2, |mut ctrl| *ctrl.set_bits(4..7, enabled));
+        Ok(())
+    }
+
+    /// Offset of the Message Data register, the registers after the address
+    /// move by 4 bytes with 64-bit addresses.
+    fn data_offset(&self) -> u32 {
+        self.offset + if self.is_64bit() { 0xc } else { 0x8 }
+    }
+
+    pub fn message_address(&self) -> u64 {
+        let low = self.header.read(self.offset + 4) as u64;
+        match self.is_64bit() {
+            true => low | (self.header.read(self.offset + 8) as u64) << 32,
+            false => low,
+        }
+    }
+
+    pub fn message_data(&self) -> u16 {
+        self.header.read16(self.data_offset())
+    }
+
+    /// Programs the message address and (base) data of the function.
+    ///
+    /// Fails if `address` is above 4 GiB and the function only supports
+    /// 32-bit addresses.
+    pub fn set_message(&mut self, address: u64, data: u16) -> Result<(), PciError> {
+        let is_64bit = self.is_64bit();
+        if !is_64bit && address > u32::MAX as u64 {
+            return Err(PciError::MsiAddressTooWide { address });
+        }
+        let data_offset = self.data_offset();
+        let mut config = self.header.lock();
+        config.write(self.offset + 4, address as u32);
+        if is_64bit {
+            config.write(self.offset + 8, (address >> 32) as u32);
+        }
+        config.write16(data_offset, data);
+        Ok(())
+    }
+
+    /// The Mask Bits register, None without per-vector masking.
+    pub fn mask_bits(&self) -> Option<u32> {
+        match self.per_vector_masking() {
+            true => Some(self.header.read(self.data_offset() + 4)),
+            false => None,
+        }
+    }
+
+    /// The Pending Bits register, None without per-vector masking.
+    pub fn pending_bits(&self) -> Option<u32> {
+        match self.per_vector_masking() {
+            true => Some(self.header.read(self.data_offset() + 8)),
+            false => None,
+        }
+    }
+
+    pub fn is_masked(&self, vector: usize) -> bool {
+        vector < 32 && self.mask_bits().is_some_and(|bits| bits.get_bit(vector))
+    }
+
+    /// Masks or unmasks `vector`.
+    pub fn set_masked(&mut self, vector: usize, masked: bool) -> Result<(), PciError> {
+        if !self.per_vector_masking() {
+            return Err(PciError::MsiMaskingUnsupported);
+        }
+        if vector >= self.vectors_capable() {
+            return Err(PciError::MsiVectorOutOfRange { index: vector });
+        }
+        self.header.update(self.data_offset() + 4, |mut bits| {
+            *bits.set_bit(vector, masked)
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MsiX<'s, A = PCIAddress> {
+    /// A reference to the device's PCI header.
+    header: &'s PCIHeader<A>,
+    /// The offset where the MSI-X config is located within the PCI header.
+    pub offset: u32,
+}
+
+impl<'s, A: ConfigSpace> MsiX<'s, A> {
+    pub fn message_control(&self) -> u16 {
+        (self.header.read(self.offset) >> 16) as u16
     }
 
     pub fn enabled(&self) -> bool {
@@ -284,23 +865,57 @@ impl<'s> MsiX<'s> {
     }
 
     pub fn enable(&mut self) {
-        let ctrl = *self.message_control().set_bit(15, true);
-
-        let mut hdr = self.header.0.read(self.offset);
-        hdr = (hdr & 0xFFFF) | ((ctrl as u32) << 16);
-        self.header.0.write(self.offset, hdr);
+        self.header
+            .update(self.offset, |mut hdr| *hdr.set_bit(16 + 15, true));
     }
 
     pub fn function_mask(&self) -> bool {
         self.message_control().get_bit(14)
     }
 
+    /// Masks all vectors of the function, regardless of their own mask bits.
+    pub fn set_function_mask(&mut self, masked: bool) {
+        self.header
+            .update(self.offset, |mut hdr| *hdr.set_bit(16 + 14, masked));
+    }
+
+    /// Programs the entries of `table` (this function's MSI-X table, see
+    /// [`PciDevice::get_msix_irq_table_mut`]) given as `(index, (address,
+    /// data))` and unmasks them.
+    ///
+    /// The whole function is masked while the entries are written, so the
+    /// individual vectors don't have to be masked and flushed one by one.
+    /// A single read of the table flushes the posted writes before the
+    /// function is unmasked (if it wasn't masked before).
+    pub fn program_vectors(
+        &mut self,
+        table: &mut [MsiXTableEntry],
+        vectors: &[(usize, (u64, u32))],
+    ) -> Result<(), PciError> {
+        if let Some(&(index, _)) = vectors.iter().find(|(index, _)| *index >= table.len()) {
+            return Err(PciError::MsiXVectorOutOfRange { index });
+        }
+
+        let masked = self.function_mask();
+        self.set_function_mask(true);
+        for &(index, (address, data)) in vectors {
+            table[index].set_message(address, data);
+            table[index].set_masked(false);
+        }
+        if let Some(&(index, _)) = vectors.last() {
+            // Non-posted read, forces the writes above to the device
+            let _ = table[index].is_masked();
+        }
+        self.set_function_mask(masked);
+        Ok(())
+    }
+
     /// Table Size is N - 1 encoded, and is the number of entries in the MSI-X
-    /// table.
+    /// table (up to 2048).
     ///
     /// This field is Read-Only.
     pub fn table_size(&self) -> usize {
-        self.message_control().get_bits(0..10) as usize
+        self.message_control().get_bits(0..11) as usize
     }
 
     /// BIR specifies which BAR is used for the Message Table.
@@ -308,33 +923,43 @@ impl<'s> MsiX<'s> {
     /// This may be a 64-bit BAR, and is zero-indexed (so BIR=0, BAR0, offset
     /// 0x10 into the header).
     pub fn bir(&self) -> u8 {
-        (self.header.0.read(self.offset + 4) & 0b111) as u8
+        (self.header.read(self.offset + 4) & 0b111) as u8
     }
 
     /// Table Offset is an offset into that BAR where the Message Table lives.
     ///
     /// Note that it is 8-byte aligned.
     pub fn table_offset(&self) -> u32 {
-        self.header.0.read(self.offset + 4) & !0b111
+        self.header.read(self.offset + 4) & !0b111
     }
 
-
     /// BIR specifies which BAR is used for the Message Table.
     ///
     /// This may be a 64-bit BAR, and is zero-indexed (so BIR=0, BAR0, offset
     /// 0x10 into the header).
     pub fn pending_bit_bir(&self) -> u8 {
-        (self.header.0.read(self.offset + 8) & 0b111) as u8
+        (self.header.read(self.offset + 8) & 0b111) as u8
     }
 
     /// Table Offset is an offset into that BAR where the Message Table lives.
     ///
     /// Note that it is 8-byte aligned.
     pub fn pending_bit_table_offset(&self) -> u32 {
-        self.header.0.read(self.offset + 8) & !0b111
+        self.header.read(self.offset + 8) & !0b111
     }
 }
 
+/// Where the MSI-X structures of a function are (see
+/// [`PciDevice::msix_layout`]).
+struct MsixLayout {
+    /// Physical address of the table.
+    table: u64,
+    /// Offset of the table in its BAR.
+    table_offset: u64,
+    /// Physical address of the PBA.
+    pba: u64,
+    entries: usize,
+}
 
 #[derive(Debug)]
 #[repr(C)]
@@ -344,8 +969,47 @@ pub struct MsiXTableEntry {
     vector_control: u32,
 }
 
+crate::dma_safe!(MsiXTableEntry {
+    addr,
+    data,
+    vector_control
+});
+
+impl MsiXTableEntry {
+    /// Message address the device writes to when the vector fires.
+    pub fn address(&self) -> u64 {
+        unsafe { core::ptr::read_volatile(&self.addr) }
+    }
+
+    /// Message data the device writes when the vector fires.
+    pub fn data(&self) -> u32 {
+        unsafe { core::ptr::read_volatile(&self.data) }
+    }
+
+    pub fn is_masked(&self) -> bool {
+        unsafe { core::ptr::read_volatile(&self.vector_control) }.get_bit(0)
+    }
+
+    pub fn set_masked(&mut self, masked: bool) {
+        let mut ctrl = unsafe { core::ptr::read_volatile(&self.vector_control) };
+        ctrl.set_bit(0, masked);
+        unsafe { core::ptr::write_volatile(addr_of_mut!(self.vector_control), ctrl) };
+    }
+
+    /// Sets the message address and data.
+    ///
+    /// Should only be done while the vector is masked.
+    pub fn set_message(&mut self, address: u64, data: u32) {
+        unsafe {
+            core::ptr::write_volatile(addr_of_mut!(self.addr), address);
+            core::ptr::write_volatile(addr_of_mut!(self.data), data);
+        }
+    }
+}
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Capability {
     /// The (parsed) ID of the capability (read from bits 0..8 at offset).
     pub id: CapabilityId,
@@ -353,20 +1017,92 @@ pub struct Capability {
     pub offset: u8,
 }
 
-pub struct CapabilitiesIter<'s> {
-    header: &'s PCIHeader,
+/// Checks the offsets of a capability list as it is walked, so broken
+/// hardware (or a device that fell off the bus and reads as all ones) can't
+/// send a walk into the header or around in circles.
+#[derive(Debug, Clone)]
+pub(crate) struct CapabilityChain {
+    /// Lowest valid offset.
+    start: u16,
+    /// Offsets seen so far, one bit per dword from `start`.
+    visited: [u64; 16],
+    /// Capabilities that still fit between `start` and the end of the
+    /// (extended) configuration space.
+    remaining: usize,
+}
+
+impl CapabilityChain {
+    /// A list of capabilities located in `start..end`.
+    pub(crate) fn new(start: u16, end: u16) -> CapabilityChain {
+        CapabilityChain {
+            start,
+            visited: [0; 16],
+            remaining: (end.saturating_sub(start) / 4) as usize,
+        }
+    }
+
+    /// Validates the next capability `offset` of the list.
+    pub(crate) fn check(&mut self, offset: u16) -> Result<(), PciError> {
+        if offset < self.start || !offset.is_multiple_of(4) {
+            return Err(PciError::CapabilityInvalidOffset { offset });
+        }
+        let index = ((offset - self.start) / 4) as usize;
+        if self.remaining == 0
+            || index >= self.visited.len() * 64
+            || self.visited[index / 64].get_bit(index % 64)
+        {
+            return Err(PciError::CapabilityLoop { offset });
+        }
+        self.visited[index / 64].set_bit(index % 64, true);
+        self.remaining -= 1;
+        Ok(())
+    }
+}
+
+/// Iterator over the capabilities of a device, see
+/// [`PciDevice::capabilities`].
+///
+/// A malformed list ends the iteration, [`CapabilitiesIter::error`] tells
+/// what was wrong with it.
+pub struct CapabilitiesIter<'s, A = PCIAddress> {
+    header: &'s PCIHeader<A>,
     next: u8,
+    chain: CapabilityChain,
+    error: Option<PciError>,
 }
 
-impl<'s> Iterator for CapabilitiesIter<'s> {
+impl<'s, A: ConfigSpace> CapabilitiesIter<'s, A> {
+    fn new(header: &'s PCIHeader<A>, next: u8) -> Self {
+        CapabilitiesIter {
+            header,
+            next,
+            chain: CapabilityChain::new(0x40, 0x100),
+            error: None,
+        }
+    }
+
+    /// Why the iteration stopped early, None if the list was well-formed
+    /// (so far).
+    pub fn error(&self) -> Option<&PciError> {
+        self.error.as_ref()
+    }
+}
+
+impl<'s, A: ConfigSpace> Iterator for CapabilitiesIter<'s, A> {
     type Item = Capability;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next == 0 {
             return None;
         }
+        if let Err(e) = self.chain.check(self.next as u16) {
+            warn!("{:?}: {}", self.header.address(), e);
+            self.error = Some(e);
+            self.next = 0;
+            return None;
+        }
 
-        let cap_header = self.header.0.read(self.next as u32);
+        let cap_header = self.header.read(self.next as u32);
         let id = CapabilityId::from(cap_header.get_bits(0..8) as u8);
         let cap = Capability {
             id,
@@ -378,9 +1114,235 @@ impl<'s> Iterator for CapabilitiesIter<'s> {
     }
 }
 
+/// IDs of the PCIe extended capabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExtendedCapabilityId {
+    /// Null Capability (an empty list starts with one).
+    Null,
+    /// Advanced Error Reporting (AER)
+    AdvancedErrorReporting,
+    /// Virtual Channel (VC), without MFVC
+    VirtualChannel,
+    DeviceSerialNumber,
+    PowerBudgeting,
+    RootComplexLinkDeclaration,
+    RootComplexInternalLinkControl,
+    RootComplexEventCollector,
+    /// Multi-Function Virtual Channel (MFVC)
+    MultiFunctionVirtualChannel,
+    /// Virtual Channel (VC), used with MFVC
+    VirtualChannelMfvc,
+    /// Root Complex Register Block (RCRB) Header
+    RcrbHeader,
+    /// Vendor-Specific Extended Capability (VSEC)
+    VendorSpecific,
+    /// Configuration Access Correlation (CAC)
+    ConfigAccessCorrelation,
+    /// Access Control Services (ACS)
+    AccessControlServices,
+    /// Alternative Routing-ID Interpretation (ARI)
+    AlternativeRoutingId,
+    /// Address Translation Services (ATS)
+    AddressTranslationServices,
+    /// Single Root I/O Virtualization (SR-IOV)
+    SrIov,
+    /// Multi-Root I/O Virtualization (MR-IOV)
+    MrIov,
+    Multicast,
+    /// Page Request Interface (PRI)
+    PageRequest,
+    ResizableBar,
+    /// Dynamic Power Allocation (DPA)
+    DynamicPowerAllocation,
+    /// TLP Processing Hints (TPH) Requester
+    TphRequester,
+    /// Latency Tolerance Reporting (LTR)
+    LatencyToleranceReporting,
+    SecondaryPciExpress,
+    /// Protocol Multiplexing (PMUX)
+    ProtocolMultiplexing,
+    /// Process Address Space ID (PASID)
+    Pasid,
+    /// LN Requester (LNR)
+    LnRequester,
+    /// Downstream Port Containment (DPC)
+    DownstreamPortContainment,
+    L1PmSubstates,
+    /// Precision Time Measurement (PTM)
+    PrecisionTimeMeasurement,
+    /// Designated Vendor-Specific Extended Capability (DVSEC)
+    DesignatedVendorSpecific,
+    VfResizableBar,
+    DataLinkFeature,
+    /// Physical Layer 16.0 GT/s
+    PhysicalLayer16,
+    /// Physical Layer 32.0 GT/s
+    PhysicalLayer32,
+    /// Reserved
+    Unknown(u16),
+}
+
+impl From<u16> for ExtendedCapabilityId {
+    fn from(id: u16) -> Self {
+        match id {
+            0x0000 => ExtendedCapabilityId::Null,
+            0x0001 => ExtendedCapabilityId::AdvancedErrorReporting,
+            0x0002 => ExtendedCapabilityId::VirtualChannel,
+            0x0003 => ExtendedCapabilityId::DeviceSerialNumber,
+            0x0004 => ExtendedCapabilityId::PowerBudgeting,
+            0x0005 => ExtendedCapabilityId::RootComplexLinkDeclaration,
+            0x0006 => ExtendedCapabilityId::RootComplexInternalLinkControl,
+            0x0007 => ExtendedCapabilityId::RootComplexEventCollector,
+            0x0008 => ExtendedCapabilityId::MultiFunctionVirtualChannel,
+            0x0009 => ExtendedCapabilityId::VirtualChannelMfvc,
+            0x000A => ExtendedCapabilityId::RcrbHeader,
+            0x000B => ExtendedCapabilityId::VendorSpecific,
+            0x000C => ExtendedCapabilityId::ConfigAccessCorrelation,
+            0x000D => ExtendedCapabilityId::AccessControlServices,
+            0x000E => ExtendedCapabilityId::AlternativeRoutingId,
+            0x000F => ExtendedCapabilityId::AddressTranslationServices,
+            0x0010 => ExtendedCapabilityId::SrIov,
+            0x0011 => ExtendedCapabilityId::MrIov,
+            0x0012 => ExtendedCapabilityId::Multicast,
+            0x0013 => ExtendedCapabilityId::PageRequest,
+            0x0015 => ExtendedCapabilityId::ResizableBar,
+            0x0016 => ExtendedCapabilityId::DynamicPowerAllocation,
+            0x0017 => ExtendedCapabilityId::TphRequester,
+            0x0018 => ExtendedCapabilityId::LatencyToleranceReporting,
+            0x0019 => ExtendedCapabilityId::SecondaryPciExpress,
+            0x001A => ExtendedCapabilityId::ProtocolMultiplexing,
+            0x001B => ExtendedCapabilityId::Pasid,
+            0x001C => ExtendedCapabilityId::LnRequester,
+            0x001D => ExtendedCapabilityId::DownstreamPortContainment,
+            0x001E => ExtendedCapabilityId::L1PmSubstates,
+            0x001F => ExtendedCapabilityId::PrecisionTimeMeasurement,
+            0x0023 => ExtendedCapabilityId::DesignatedVendorSpecific,
+            0x0024 => ExtendedCapabilityId::VfResizableBar,
+            0x0025 => ExtendedCapabilityId::DataLinkFeature,
+            0x0026 => ExtendedCapabilityId::PhysicalLayer16,
+            0x002A => ExtendedCapabilityId::PhysicalLayer32,
+            other => ExtendedCapabilityId::Unknown(other),
+        }
+    }
+}
+
+/// A capability in the PCIe extended configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ExtendedCapability {
+    pub id: ExtendedCapabilityId,
+    /// Capability Version (bits 16..20 of the header).
+    pub version: u8,
+    /// The offset of the capability in the configuration space (0x100 or
+    /// above).
+    pub offset: u16,
+}
+
+/// Iterator over the extended capabilities of a device, see
+/// [`PciDevice::extended_capabilities`].
+///
+/// Like [`CapabilitiesIter`], a malformed list ends the iteration and
+/// [`ExtendedCapabilitiesIter::error`] tells what was wrong with it.
+pub struct ExtendedCapabilitiesIter<'s, A = PCIAddress> {
+    header: &'s PCIHeader<A>,
+    next: u16,
+    chain: CapabilityChain,
+    error: Option<PciError>,
+}
+
+impl<'s, A: ConfigSpace> ExtendedCapabilitiesIter<'s, A> {
+    fn new(header: &'s PCIHeader<A>) -> Self {
+        // The legacy mechanism can't address the extended configuration
+        // space (offsets above 0xff would select another function)
+        let next = if header.config_size() > 0x100 {
+            0x100
+        } else {
+            0
+        };
+        ExtendedCapabilitiesIter {
+            header,
+            next,
+            chain: CapabilityChain::new(0x100, 0x1000),
+            error: None,
+        }
+    }
+
+    /// Why the iteration stopped early, None if the list was well-formed
+    /// (so far).
+    pub fn error(&self) -> Option<&PciError> {
+        self.error.as_ref()
+    }
+}
+
+impl<'s, A: ConfigSpace> Iterator for ExtendedCapabilitiesIter<'s, A> {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == 0 {
+            return None;
+        }
+        if let Err(e) = self.chain.check(self.next) {
+            warn!("{:?}: {}", self.header.address(), e);
+            self.error = Some(e);
+            self.next = 0;
+            return None;
+        }
+
+        let cap_header = self.header.read32(self.next as u32);
+        // Conventional PCI functions (and PCIe functions without extended
+        // capabilities) read as 0, missing ones as all ones
+        if cap_header == 0 || cap_header == u32::MAX {
+            self.next = 0;
+            return None;
+        }
+        let cap = ExtendedCapability {
+            id: ExtendedCapabilityId::from(cap_header.get_bits(0..16) as u16),
+            version: cap_header.get_bits(16..20) as u8,
+            offset: self.next,
+        };
+
+        self.next = cap_header.get_bits(20..32) as u16;
+        Some(cap)
+    }
+}
+
+/// Iterator over the BARs of a device, see [`PciDevice::iter_bars`].
+pub struct BarIter<'d, A = PCIAddress> {
+    device: &'d PciDevice<A>,
+    next: u8,
+}
+
+impl<'d, A: ConfigSpace> Iterator for BarIter<'d, A> {
+    type Item = (BarIndex, Bar);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bars = self.device.device_type().bar_count();
+        while self.next < bars {
+            let index = self.next;
+            let raw = self.device.bar_raw(index);
+            let is_io = raw.get_bit(0);
+            // 64-bit BARs use the next slot for the upper half of the address
+            let is_64bit = !is_io && raw.get_bits(1..3) == 2;
+            self.next += if is_64bit { 2 } else { 1 };
+
+            // IO BARs are not supported by `bar()`, and a 64-bit BAR in the
+            // last slot is malformed
+            if is_io || self.next > bars {
+                continue;
+            }
+            if let Some(bar) = self.device.bar(index) {
+                return Some((BarIndex::ALL[index as usize], bar));
+            }
+        }
+        None
+    }
+}
+
 #[derive(Debug)]
-pub struct PciDevice {
-    header: PCIHeader,
+pub struct PciDevice<A = PCIAddress> {
+    header: PCIHeader<A>,
 }
 
 impl PciDevice {
@@ -388,154 +1350,606 @@ impl PciDevice {
         let header = PCIHeader::new(bus, device, function);
         header.map(|header| PciDevice { header })
     }
+}
+
+impl<A: ConfigSpace> PciDevice<A> {
+    /// Creates a device that is accessed through `config`, returns None if
+    /// there is no function behind it.
+    pub fn from_config(config: A) -> Option<Self> {
+        if config.read(0) != u32::MAX {
+            Some(PciDevice {
+                header: PCIHeader::from_config(config),
+            })
+        } else {
+            None
+        }
+    }
 
     pub fn pci_address(&self) -> PCIAddress {
-        self.header.0
+        self.header.address()
     }
 
-    pub fn device_type(&self) -> PciDeviceType {
-        let header = self.header.0.read(0x0c);
+    /// The backend used to access the configuration space.
+    ///
+    /// Other readers can use the device while the guard is held, but
+    /// methods that write through `&self` (e.g., [`PciDevice::bar`]) wait
+    /// until it's dropped. The lock isn't reentrant: calling them on the
+    /// thread holding the guard deadlocks.
+    pub fn config(&self) -> spin::RwLockReadGuard<'_, A> {
+        self.header.shared()
+    }
+
+    /// The backend, for device specific configuration registers.
+    pub fn config_mut(&mut self) -> &mut A {
+        self.header.config.get_mut()
+    }
+
+    /// A detailed description of the device for display.
+    pub fn verbose(&self) -> verbose::Verbose<'_, A> {
+        verbose::Verbose::new(self)
+    }
 
-        match header.get_bits(16..23) as u8 {
+    pub fn device_type(&self) -> PciDeviceType {
+        match self.header.read8(0x0e).get_bits(0..7) {
             0x00 => PciDeviceType::Endpoint,
             0x01 => PciDeviceType::PciBridge,
+            0x02 => PciDeviceType::CardBusBridge,
             _ => PciDeviceType::Unknown,
         }
     }
 
-    pub fn get_cap_region_mut(&mut self, cap: Capability) -> CapabilityType {
+    pub fn get_cap_region_mut(&mut self, cap: Capability) -> CapabilityType<'_, A> {
         match cap.id {
-            CapabilityId::MsiX => CapabilityType::MsiX(MsiX { header: &mut self.header, offset: cap.offset as u32 }),
+            CapabilityId::Msi => CapabilityType::Msi(Msi {
+                header: &self.header,
+                offset: cap.offset as u32,
+            }),
+            CapabilityId::MsiX => CapabilityType::MsiX(MsiX {
+                header: &self.header,
+                offset: cap.offset as u32,
+            }),
+            CapabilityId::PowerManagement => CapabilityType::PowerManagement(PowerManagement {
+                header: &self.header,
+                offset: cap.offset as u32,
+            }),
             _ => unimplemented!(),
         }
     }
 
-    fn get_msix_config(&mut self) -> Option<MsiX> {
-        self.capabilities().find(|cap| cap.id == CapabilityId::MsiX).map(move |cap| {
-            MsiX { header: &mut self.header, offset: cap.offset as u32 }
+    /// Returns the first capability with ID `id`.
+    pub fn find_capability(&self, id: CapabilityId) -> Option<Capability> {
+        self.capabilities().find(|cap| cap.id == id)
+    }
+
+    pub fn has_capability(&self, id: CapabilityId) -> bool {
+        self.find_capability(id).is_some()
+    }
+
+    /// Returns the capability `C` (e.g., `device.capability::<MsiX<_>>()`),
+    /// if the device has it, for reading only.
+    pub fn capability<'s, C: TypedCapability<'s, A>>(&'s self) -> Option<CapabilityRef<C>> {
+        let cap = self.find_capability(C::ID)?;
+        Some(CapabilityRef(C::at(&self.header, cap.offset as u32)))
+    }
+
+    /// Returns the capability `C`, if the device has it.
+    pub fn capability_mut<'s, C: TypedCapability<'s, A>>(&'s mut self) -> Option<C> {
+        let cap = self.find_capability(C::ID)?;
+        Some(C::at(&self.header, cap.offset as u32))
+    }
+
+    fn get_msix_config(&mut self) -> Option<MsiX<'_, A>> {
+        self.capability_mut()
+    }
+
+    /// Returns the MSI capability, if the device has one.
+    pub fn msi(&mut self) -> Option<Msi<'_, A>> {
+        self.capability_mut()
+    }
+
+    /// Returns the PCI power management capability, if the device has one.
+    pub fn power_management(&mut self) -> Option<PowerManagement<'_, A>> {
+        self.capability_mut()
+    }
+
+    /// Returns the PCI Express capability, if the device has one.
+    pub fn pci_express(&mut self) -> Option<PciExpress<'_, A>> {
+        self.capability_mut()
+    }
+
+    /// Returns the CardBus registers, if the device is a CardBus bridge.
+    pub fn cardbus(&mut self) -> Option<CardBus<'_, A>> {
+        match self.device_type() {
+            PciDeviceType::CardBusBridge => Some(CardBus {
+                header: &self.header,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Checks that BAR `bir` is a memory BAR that holds `len` bytes of MSI-X
+    /// structures at `offset`.
+    fn msix_bar(&self, bir: u8, offset: u64, len: u64) -> Result<Bar, PciError> {
+        if bir >= self.device_type().bar_count() || self.bar_raw(bir).get_bit(0) {
+            return Err(PciError::MsiXInvalidBar { bir });
+        }
+        let bar = self.bar(bir).ok_or(PciError::MsiXInvalidBar { bir })?;
+        if offset.checked_add(len).is_none_or(|end| end > bar.size) {
+            return Err(PciError::MsiXOutOfBounds { bir, offset, len });
+        }
+        Ok(bar)
+    }
+
+    /// Checks that the MSI-X table and PBA fit in their BARs.
+    fn msix_layout(&self) -> Result<MsixLayout, PciError> {
+        let (table_bir, table_offset, entries, pba_bir, pba_offset) = {
+            let msi = self.capability::<MsiX<_>>().ok_or(PciError::NoMsiX)?;
+            (
+                msi.bir(),
+                msi.table_offset() as u64,
+                msi.table_size() + 1,
+                msi.pending_bit_bir(),
+                msi.pending_bit_table_offset() as u64,
+            )
+        };
+        info!(
+            "Device MSI-X table is at bar {} offset {} table size is {}",
+            table_bir, table_offset, entries
+        );
+
+        let table_len = (entries * core::mem::size_of::<MsiXTableEntry>()) as u64;
+        // One pending bit per entry, in QWORDs
+        let pba_len = (entries as u64).div_ceil(64) * 8;
+        let bar = self.msix_bar(table_bir, table_offset, table_len)?;
+        let pba_bar = self.msix_bar(pba_bir, pba_offset, pba_len)?;
+
+        let paddr = bar.address + table_offset;
+        if !paddr.is_multiple_of(core::mem::align_of::<MsiXTableEntry>() as u64) {
+            return Err(PciError::MsiXMisaligned {
+                offset: table_offset,
+            });
+        }
+        Ok(MsixLayout {
+            table: paddr,
+            table_offset,
+            pba: pba_bar.address + pba_offset,
+            entries,
         })
     }
 
-    pub fn get_msix_irq_table_mut(&mut self, paddr_to_vaddr_conversion: &Fn(PAddr) -> VAddr) -> Option<&mut [MsiXTableEntry]> {
+    fn enable_msix(&mut self) -> Result<(), PciError> {
+        let mut msi = self.get_msix_config().ok_or(PciError::NoMsiX)?;
+        if !msi.enabled() {
+            msi.enable();
+        }
+        info!(
+            "Device has MSI-X capability and it's {}",
+            if msi.enabled() {
+                "enabled"
+            } else {
+                "not enabled"
+            }
+        );
+        Ok(())
+    }
 
-        if let Some(mut msi) = self.get_msix_config() {
-            log::info!("Device has MSI-X capability and it's {}", if msi.enabled() { "enabled" } else { "not enabled" });
-            if !msi.enabled() {
-                msi.enable();
+    /// Enables `vectors` MSI vectors (a power of two) with message `address`
+    /// and base `data`, unmasked.
+    ///
+    /// Fails if the device has no MSI capability, can't enable that many
+    /// vectors or can't reach `address`.
+    pub fn enable_msi(&mut self, vectors: usize, address: u64, data: u16) -> Result<(), PciError> {
+        let mut msi = self.msi().ok_or(PciError::NoMsi)?;
+        msi.set_enabled(false);
+        msi.set_message(address, data)?;
+        msi.set_vectors_enabled(vectors)?;
+        if msi.per_vector_masking() {
+            for vector in 0..vectors {
+                msi.set_masked(vector, false)?;
             }
-            log::info!("Device has MSI-X capability and it's {}", if msi.enabled() { "enabled" } else { "not enabled" });
-            log::info!("Device MSI-X table is at bar {} offset {} table size is {}", msi.bir(), msi.table_offset(), msi.table_size());
-
-            let table_bar = msi.bir();
-            let table_offset = msi.table_offset();
-
-            let entries = msi.table_size() + 1;
-            let bar = self.bar(table_bar).unwrap();
-            let addr = paddr_to_vaddr_conversion(PAddr::from(bar.address + table_offset as u64));
-
-            // Safety:
-            // - We're casting the part of the memory to a MSI-X table according to the spec
-            // - It's just plain-old-data
-            // - We have &mut self when giving out a mut reference to the table
-            // - Sanity check that we're within `bar`'s range (TODO)
-            // - Check that `addr` satisfies alignment for [MsiXTableEntry] (TODO)
-            let msix_table = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<MsiXTableEntry>(), entries) };
-            return Some(msix_table);
-        } else {
-            return None;
         }
+        msi.set_enabled(true);
+        info!(
+            "Enabled {} MSI vectors with address {:#x} data {:#x}",
+            vectors, address, data
+        );
+        Ok(())
+    }
+
+    /// Enables MSI-X and returns the MSI-X table, mapped with
+    /// `paddr_to_vaddr_conversion`.
+    ///
+    /// Fails if the device has no MSI-X capability, or if the table or the
+    /// PBA don't fit in their BARs or are misaligned. For large tables that
+    /// are mapped on demand, see [`PciDevice::msix_table`].
+    pub fn get_msix_irq_table_mut(
+        &mut self,
+        paddr_to_vaddr_conversion: &dyn Fn(PAddr) -> VAddr,
+    ) -> Result<&mut [MsiXTableEntry], PciError> {
+        let layout = self.msix_layout()?;
+        let addr = paddr_to_vaddr_conversion(PAddr::from(layout.table));
+        if !addr
+            .as_u64()
+            .is_multiple_of(core::mem::align_of::<MsiXTableEntry>() as u64)
+        {
+            return Err(PciError::MsiXMisaligned {
+                offset: layout.table_offset,
+            });
+        }
+        self.enable_msix()?;
+
+        // Safety:
+        // - We're casting the part of the memory to a MSI-X table according to the spec
+        // - It's just plain-old-data
+        // - We have &mut self when giving out a mut reference to the table
+        // - The table lies within `bar` and `addr` is aligned (checked above)
+        let msix_table = unsafe {
+            core::slice::from_raw_parts_mut(addr.as_mut_ptr::<MsiXTableEntry>(), layout.entries)
+        };
+        Ok(msix_table)
+    }
+
+    /// Enables MSI-X and maps the parts of the table and the PBA that hold
+    /// the first `vectors` entries, calling `map` with the physical address
+    /// and length of every range that has to be mapped.
+    ///
+    /// The table can be grown later with [`MsixTable::grow`], which only maps
+    /// the ranges that aren't mapped yet.
+    pub fn msix_table(
+        &mut self,
+        vectors: usize,
+        map: &mut dyn FnMut(PAddr, usize) -> Option<VAddr>,
+    ) -> Result<MsixTable, PciError> {
+        let layout = self.msix_layout()?;
+        let mut msix = MsixTable::new(layout.table, layout.pba, layout.entries);
+        msix.grow(vectors, map)?;
+        self.enable_msix()?;
+        Ok(msix)
     }
 
     pub fn vendor_id(&self) -> VendorId {
-        self.header.0.read(0x00) as VendorId
+        self.header.read16(0x00)
     }
 
     pub fn device_id(&self) -> DeviceId {
-        self.header.0.read(0x02) as DeviceId
+        self.header.read16(0x02)
     }
 
     pub fn is_bus_master(&self) -> bool {
-        self.header.0.read(0x04).get_bit(2)
+        self.header.read16(0x04).get_bit(2)
     }
 
     pub fn enable_bus_mastering(&mut self) {
-        let mut command = self.header.0.read(0x04);
-        command.set_bit(2, true);
-        self.header.0.write(0x04, command);
+        self.header
+            .update16(0x04, |mut command| *command.set_bit(2, true));
+    }
+
+    /// The cache line size register, in DWORDs (0 if unset or not
+    /// implemented).
+    pub fn cacheline_size(&self) -> u8 {
+        self.header.read8(0x0c)
+    }
+
+    /// Sets the cache line size (in DWORDs), returns false if the device
+    /// doesn't support the value (and reverted to 0).
+    pub fn set_cacheline_size(&mut self, dwords: u8) -> bool {
+        self.header.write8(0x0c, dwords);
+        self.cacheline_size() == dwords
+    }
+
+    /// The latency timer, in PCI bus clocks (always 0 for PCIe devices).
+    pub fn latency_timer(&self) -> u8 {
+        self.header.read8(0x0d)
+    }
+
+    pub fn set_latency_timer(&mut self, clocks: u8) {
+        self.header.write8(0x0d, clocks);
     }
 
-    pub fn bar(&mut self, index: u8) -> Option<Bar> {
+    /// The latency timer of the secondary bus of a bridge.
+    pub fn secondary_latency_timer(&self) -> Option<u8> {
         match self.device_type() {
-            PciDeviceType::Endpoint => assert!(index < 6),
-            PciDeviceType::PciBridge => assert!(index < 2),
-            PciDeviceType::Unknown => return None,
+            PciDeviceType::PciBridge => Some(self.header.read8(0x1b)),
+            _ => None,
+        }
+    }
+
+    /// Sets the latency timer of the secondary bus (for bridges, ignored
+    /// otherwise).
+    pub fn set_secondary_latency_timer(&mut self, clocks: u8) {
+        if let PciDeviceType::PciBridge = self.device_type() {
+            self.header.write8(0x1b, clocks);
+        }
+    }
+
+    /// Programs the cache line size of the CPU and, if the latency timer is
+    /// below [`MIN_LATENCY_TIMER`], sets it to [`DEFAULT_LATENCY_TIMER`] (on
+    /// the secondary bus of bridges too), as conventional PCI devices expect
+    /// from the firmware.
+    ///
+    /// Returns false if the device doesn't support the CPU's cache line size.
+    pub fn configure_cacheline_and_latency(&mut self) -> bool {
+        let supported = self.set_cacheline_size((crate::arch::cache_line_size() / 4) as u8);
+        if self.latency_timer() < MIN_LATENCY_TIMER {
+            self.set_latency_timer(DEFAULT_LATENCY_TIMER);
+        }
+        if self
+            .secondary_latency_timer()
+            .is_some_and(|t| t < MIN_LATENCY_TIMER)
+        {
+            self.set_secondary_latency_timer(DEFAULT_LATENCY_TIMER);
+        }
+        supported
+    }
+
+    /// The raw (undecoded) content of BAR `index`.
+    pub(crate) fn bar_raw(&self, index: u8) -> u32 {
+        self.header.read(0x10 + (index as u32) * 4)
+    }
+
+    /// Iterates over the implemented memory BARs (skipping IO BARs and the
+    /// upper halves of 64-bit BARs).
+    ///
+    /// Note that this sizes the BARs, see [`PciDevice::bar`].
+    pub fn iter_bars(&self) -> BarIter<'_, A> {
+        BarIter {
+            device: self,
+            next: 0,
+        }
+    }
+
+    /// The memory BARs (as [`PciDevice::iter_bars`]), without allocating.
+    pub fn bar_table(&self) -> BarTable {
+        let mut bars = BarTable::new();
+        // Can't overflow, there are at most `MAX_BARS` BARs
+        let _ = bars.try_extend(self.iter_bars());
+        bars
+    }
+
+    /// Decodes and sizes memory BAR `index`.
+    ///
+    /// Returns None for BARs the header doesn't have (e.g., `index` 2 of a
+    /// bridge), unimplemented BARs, IO BARs, BARs with a reserved memory type
+    /// and 64-bit BARs in the last slot of the header.
+    ///
+    /// Sizing writes all ones to the BAR and restores it afterwards, with
+    /// the configuration space locked so nobody sees the BAR in between.
+    /// Read-only devices can't be sized, see [`ReadOnlyPciDevice::bar`].
+    ///
+    /// Deadlocks if the calling thread holds a guard of
+    /// [`PciDevice::config`].
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if self.header.read_only {
+            return None;
+        }
+        let bars = self.device_type().bar_count();
+        if index >= bars {
+            return None;
         }
 
         let offset = 0x10 + (index as u32) * 4;
-        let base = self.header.0.read(offset);
+        let mut config = self.header.lock();
+        let base = config.read(offset);
         let bartype_is_io = base.get_bit(0);
+        if bartype_is_io {
+            return None;
+        }
 
-        if !bartype_is_io {
-            let locatable = base.get_bits(1..3);
-            let prefetchable = base.get_bit(3);
-
-            self.header.0.write(offset, u32::MAX);
-            let size_encoded = self.header.0.read(offset);
-            self.header.0.write(offset, base);
+        let locatable = base.get_bits(1..3);
+        let prefetchable = base.get_bit(3);
+        // 1 (below 1 MiB in PCI 2.x) and 3 are reserved
+        if locatable != 0 && locatable != 2 {
+            warn!(
+                "BAR {} of {:?} has the reserved type {}",
+                index,
+                config.address(),
+                locatable
+            );
+            return None;
+        }
+        if locatable == 2 && index + 1 >= bars {
+            warn!(
+                "BAR {} of {:?} is 64-bit but the last BAR",
+                index,
+                config.address()
+            );
+            return None;
+        }
 
-            if size_encoded == 0x0 {
-                return None;
-            }
+        config.write(offset, u32::MAX);
+        let size_encoded = config.read(offset);
+        config.write(offset, base);
 
-            // To get the region size using BARs:
-            // - Clear lower 4 bits
-            // - Invert all all-bits
-            // - Add 1 to the result
-            // Ref: https://wiki.osdev.org/PCI#Base_Address_Registers
-            let (address, size) = {
-                match locatable {
-                    // 32-bit address
-                    0 => {
-                        let size = !(size_encoded & !0xF) + 1;
-                        ((base & 0xFFFF_FFF0) as u64, size as u64)
-                    }
-                    // 64-bit address
-                    2 => {
-                        let next_offset = offset + 4;
-                        let next_bar = self.header.0.read(next_offset);
-                        let address = (base & 0xFFFF_FFF0) as u64
-                            | (next_bar as u64 & (u32::MAX as u64)) << 32;
-
-                        // Size for 64-bit Memory Space BARs:
-                        self.header.0.write(next_offset, u32::MAX);
-                        let msb_size_encoded = self.header.0.read(next_offset);
-                        self.header.0.write(next_offset, next_bar);
-                        let size = (msb_size_encoded as u64) << 32 | size_encoded as u64;
-
-                        (address, (!(size & !0xF) + 1))
-                    }
-                    _ => unimplemented!("Unsupported locatable: {}", locatable),
-                }
-            };
+        if size_encoded == 0x0 {
+            return None;
+        }
 
-            Some(Bar {
-                region_type: bartype_is_io.into(),
-                prefetchable,
-                address,
-                size,
-            })
+        // To get the region size using BARs:
+        // - Clear lower 4 bits
+        // - Invert all all-bits
+        // - Add 1 to the result
+        // Ref: https://wiki.osdev.org/PCI#Base_Address_Registers
+        // The addition overflows if no address bit is writable, which is a
+        // broken BAR rather than one spanning the whole address space.
+        let (address, size) = if locatable == 0 {
+            // 32-bit address
+            let size = (!(size_encoded & !0xF)).checked_add(1)?;
+            ((base & 0xFFFF_FFF0) as u64, size as u64)
         } else {
-            unimplemented!("Unable to handle IO BARs")
-        }
+            // 64-bit address
+            let next_offset = offset + 4;
+            let next_bar = config.read(next_offset);
+            let address = (base & 0xFFFF_FFF0) as u64 | (next_bar as u64 & (u32::MAX as u64)) << 32;
+
+            // Size for 64-bit Memory Space BARs:
+            config.write(next_offset, u32::MAX);
+            let msb_size_encoded = config.read(next_offset);
+            config.write(next_offset, next_bar);
+            let size = (msb_size_encoded as u64) << 32 | size_encoded as u64;
+
+            (address, (!(size & !0xF)).checked_add(1)?)
+        };
+
+        Some(Bar {
+            region_type: bartype_is_io.into(),
+            prefetchable,
+            address,
+            size,
+        })
     }
 
     pub fn status(&self) -> u16 {
-        (self.header.0.read(0x4) >> 16)as u16
+        self.header.read16(0x06)
+    }
+
+    /// The command register.
+    pub fn command(&self) -> u16 {
+        self.header.read16(0x04)
+    }
+
+    pub fn set_command(&mut self, command: u16) {
+        self.header.write16(0x04, command);
+    }
+
+    pub fn revision_id(&self) -> DeviceRevision {
+        self.header.read8(0x08)
+    }
+
+    /// The programming interface (part of the class code).
+    pub fn prog_if(&self) -> Interface {
+        self.header.read8(0x09)
+    }
+
+    pub fn sub_class(&self) -> SubClass {
+        self.header.read8(0x0a)
+    }
+
+    pub fn base_class(&self) -> BaseClass {
+        self.header.read8(0x0b)
+    }
+
+    /// The header type register, including the multi-function bit (see
+    /// [`PciDevice::device_type`] for the decoded layout).
+    pub fn header_type(&self) -> HeaderType {
+        self.header.read8(0x0e)
+    }
+
+    /// Whether the device implements more than one function.
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type().get_bit(7)
+    }
+
+    /// The BIST register (0 if the device isn't BIST capable).
+    pub fn bist(&self) -> u8 {
+        self.header.read8(0x0f)
+    }
+
+    /// The legacy interrupt line (an IRQ number assigned by the firmware or
+    /// OS, 0xff if unknown).
+    pub fn interrupt_line(&self) -> u8 {
+        self.header.read8(0x3c)
+    }
+
+    pub fn set_interrupt_line(&mut self, line: u8) {
+        self.header.write8(0x3c, line);
+    }
+
+    /// The legacy interrupt pin the device uses (1 = INTA# ... 4 = INTD#,
+    /// 0 = none).
+    pub fn interrupt_pin(&self) -> u8 {
+        self.header.read8(0x3d)
+    }
+
+    /// Whether the function asserts its INTx pin (Interrupt Status), even
+    /// while INTx is disabled.
+    pub fn intx_pending(&self) -> bool {
+        self.header.read16(0x06).get_bit(3)
+    }
+
+    /// Whether INTx is disabled (Interrupt Disable, "DisINTx+" in lspci).
+    pub fn intx_disabled(&self) -> bool {
+        self.header.read16(0x04).get_bit(10)
+    }
+
+    /// Disables INTx, which deasserts the pin until it is enabled again
+    /// (the Interrupt Status still shows whether the function wants to
+    /// interrupt).
+    pub fn set_intx_disabled(&mut self, disabled: bool) {
+        self.header
+            .update16(0x04, |mut command| *command.set_bit(10, disabled));
+    }
+
+    /// Disables INTx if the function asserts it, as the kernel's handlers
+    /// for shared lines do.
+    ///
+    /// Returns whether the function asserted INTx.
+    pub fn check_and_mask_intx(&mut self) -> bool {
+        if !self.intx_pending() {
+            return false;
+        }
+        self.set_intx_disabled(true);
+        true
+    }
+
+    /// Whether Interrupt Disable is implemented (functions before PCI 2.3
+    /// lack it), which a function needs to share its INTx line.
+    pub fn intx_mask_supported(&mut self) -> bool {
+        let disabled = self.intx_disabled();
+        self.set_intx_disabled(!disabled);
+        let supported = self.intx_disabled() != disabled;
+        self.set_intx_disabled(disabled);
+        supported
+    }
+
+    /// Reads a register that only exists in type 0 (endpoint) headers.
+    fn endpoint_register<T>(&self, read: impl FnOnce(&A) -> T) -> Option<T> {
+        match self.device_type() {
+            PciDeviceType::Endpoint => Some(read(&self.header.shared())),
+            _ => None,
+        }
+    }
+
+    /// Pointer to the Card Information Structure of CardBus cards (type 0
+    /// headers only).
+    pub fn cardbus_cis_pointer(&self) -> Option<u32> {
+        self.endpoint_register(|config| config.read32(0x28))
+    }
+
+    /// Subsystem vendor ID (type 0 headers only, bridges report it in a
+    /// capability).
+    pub fn subsystem_vendor_id(&self) -> Option<VendorId> {
+        self.endpoint_register(|config| config.read16(0x2c))
+    }
+
+    /// Subsystem ID (type 0 headers only).
+    pub fn subsystem_id(&self) -> Option<DeviceId> {
+        self.endpoint_register(|config| config.read16(0x2e))
+    }
+
+    /// The raw expansion ROM base address register (type 0 headers only).
+    pub fn expansion_rom_base(&self) -> Option<u32> {
+        self.endpoint_register(|config| config.read32(0x30))
+    }
+
+    /// Burst period the device needs, in units of 0.25 us (type 0 headers
+    /// only, conventional PCI).
+    pub fn min_gnt(&self) -> Option<u8> {
+        self.endpoint_register(|config| config.read8(0x3e))
+    }
+
+    /// How often the device needs bus access, in units of 0.25 us (type 0
+    /// headers only, conventional PCI).
+    pub fn max_lat(&self) -> Option<u8> {
+        self.endpoint_register(|config| config.read8(0x3f))
     }
 
     /// Offset to capability pointer
     pub fn capabilities_pointer(&self) -> Option<u8> {
-        let cap_ptr = self.header.0.read(0x34).get_bits(0..8) as u8;
+        let offset = match self.device_type() {
+            PciDeviceType::CardBusBridge => cardbus::CARDBUS_CAPABILITIES_POINTER,
+            _ => 0x34,
+        };
+        let cap_ptr = self.header.read8(offset);
         if self.status().get_bit(4) && cap_ptr != 0x0 {
             Some(cap_ptr)
         } else {
@@ -543,18 +1957,50 @@ impl PciDevice {
         }
     }
 
-    pub fn capabilities(&self) -> CapabilitiesIter {
-        self.capabilities_pointer().map_or(CapabilitiesIter {
-            header: &self.header,
-            next: 0x0
-        }, |cap_ptr| CapabilitiesIter {
-            header: &self.header,
-            next: cap_ptr
-        })
+    pub fn capabilities(&self) -> CapabilitiesIter<'_, A> {
+        CapabilitiesIter::new(&self.header, self.capabilities_pointer().unwrap_or(0))
+    }
+
+    /// Walks the capability list and checks that it is well-formed.
+    ///
+    /// # Returns
+    /// The number of capabilities, or what is wrong with the list (a pointer
+    /// into the header, an unaligned pointer or a loop).
+    pub fn validate_capabilities(&self) -> Result<usize, PciError> {
+        let mut capabilities = self.capabilities();
+        let count = capabilities.by_ref().count();
+        capabilities.error.map_or(Ok(count), Err)
+    }
+
+    /// The capabilities in the PCIe extended configuration space.
+    ///
+    /// Empty if the backend can't reach it (see
+    /// [`ConfigSpace::config_size`]) or the function has none.
+    pub fn extended_capabilities(&self) -> ExtendedCapabilitiesIter<'_, A> {
+        ExtendedCapabilitiesIter::new(&self.header)
+    }
+
+    /// Returns the first extended capability with ID `id`.
+    pub fn find_extended_capability(&self, id: ExtendedCapabilityId) -> Option<ExtendedCapability> {
+        self.extended_capabilities().find(|cap| cap.id == id)
+    }
+
+    /// The capabilities (as [`PciDevice::capabilities`]), without
+    /// allocating. A malformed list is cut off where it goes wrong.
+    pub fn capability_list(&self) -> CapabilityList {
+        let mut capabilities = CapabilityList::new();
+        if capabilities.try_extend(self.capabilities()).is_err() {
+            warn!(
+                "{:?}: capability list is longer than {} entries",
+                self.pci_address(),
+                MAX_CAPABILITIES
+            );
+        }
+        capabilities
     }
 
     pub fn revision_and_class(&self) -> (DeviceRevision, BaseClass, SubClass, Interface) {
-        let field = { self.header.0.read(0x08) };
+        let field = self.header.read32(0x08);
         (
             field.get_bits(0..8) as DeviceRevision,
             field.get_bits(24..32) as BaseClass,
@@ -569,16 +2015,52 @@ impl PciDevice {
         class.into()
     }
 
+    /// Looks the device up in the device database (see
+    /// [`device_db::lookup`]).
     pub fn info(&self) -> Option<&'static device_db::PciDeviceInfo> {
-        let key = device_db::make_key(self.vendor_id(), self.device_id());
-        crate::pci::device_db::PCI_DEVICES.get(&key)
+        device_db::lookup(self.vendor_id(), self.device_id())
+    }
+
+    /// Collects everything known about the device into a summary (e.g., for
+    /// inventory reports).
+    ///
+    /// Note that this sizes all memory BARs (see [`PciDevice::bar`]).
+    #[cfg(feature = "alloc")]
+    pub fn summary(&self) -> PciDeviceSummary {
+        let bars = self
+            .iter_bars()
+            .map(|(index, bar)| (index.into(), bar))
+            .collect();
+        self.summary_with_bars(bars)
+    }
+
+    /// A summary with the given BARs (as (BAR index, BAR)).
+    #[cfg(feature = "alloc")]
+    pub(crate) fn summary_with_bars(&self, bars: Vec<(u8, Bar)>) -> PciDeviceSummary {
+        let (revision, base_class, sub_class, interface) = self.revision_and_class();
+        let info = self.info();
+
+        PciDeviceSummary {
+            address: self.pci_address(),
+            vendor_id: self.vendor_id(),
+            device_id: self.device_id(),
+            revision,
+            base_class,
+            sub_class,
+            interface,
+            class: self.device_class(),
+            vendor_name: info.map(|i| String::from(i.vendor_name)),
+            device_name: info.map(|i| String::from(i.device_name)),
+            bars,
+            capabilities: self.capabilities().collect(),
+        }
     }
 }
 
-impl fmt::Display for PciDevice {
+impl<A: ConfigSpace> fmt::Display for PciDevice<A> {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}: ", self.header.0)?;
+        write!(f, "{:?}: ", self.pci_address())?;
         if let Some(dev_info) = self.info() {
             write!(f, "{} {}", dev_info.vendor_name, dev_info.device_name)
         } else {
@@ -592,6 +2074,49 @@ impl fmt::Display for PciDevice {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl<A: ConfigSpace> defmt::Format for PciDevice<A> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}: ", self.pci_address());
+        if let Some(dev_info) = self.info() {
+            defmt::write!(
+                f,
+                "{=str} {=str}",
+                dev_info.vendor_name,
+                dev_info.device_name
+            )
+        } else {
+            defmt::write!(
+                f,
+                "Unknown[{=u16:#x}] Unknown[{=u16:#x}]",
+                self.vendor_id(),
+                self.device_id()
+            )
+        }
+    }
+}
+
+/// A snapshot of a device's identity and resources (see
+/// [`PciDevice::summary`]).
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PciDeviceSummary {
+    pub address: PCIAddress,
+    pub vendor_id: VendorId,
+    pub device_id: DeviceId,
+    pub revision: DeviceRevision,
+    pub base_class: BaseClass,
+    pub sub_class: SubClass,
+    pub interface: Interface,
+    pub class: ClassCode,
+    pub vendor_name: Option<String>,
+    pub device_name: Option<String>,
+    /// Memory BARs as (BAR index, BAR).
+    pub bars: Vec<(u8, Bar)>,
+    pub capabilities: Vec<Capability>,
+}
+
 pub struct PciDeviceIterator {
     bus: u8,
     device: u8,
@@ -633,3 +2158,473 @@ pub fn scan_bus() -> PciDeviceIterator {
         function: 0x0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixed::FixedVec;
+    use mock::MockConfig;
+
+    #[test]
+    fn capability_lookup() {
+        // Power management -> MSI-X with 8 entries in BAR 2
+        let config =
+            MockConfig::with_capability(0x40, &[0x0003_5001, 0, 0, 0, 0x0007_0011, 0x0000_2002]);
+        let mut device = PciDevice::from_config(config).unwrap();
+        assert!(device.has_capability(CapabilityId::MsiX));
+        assert!(!device.has_capability(CapabilityId::Msi));
+        assert_eq!(
+            device
+                .find_capability(CapabilityId::PowerManagement)
+                .unwrap()
+                .offset,
+            0x40
+        );
+
+        let msix = device.capability::<MsiX<_>>().unwrap();
+        assert_eq!(
+            (
+                msix.offset,
+                msix.table_size(),
+                msix.bir(),
+                msix.table_offset()
+            ),
+            (0x50, 7, 2, 0x2000)
+        );
+        assert_eq!(device.power_management().unwrap().offset, 0x40);
+    }
+
+    #[test]
+    fn malformed_capability_lists() {
+        let device = |links: &[(u32, u8)], first: u8| {
+            let mut config = MockConfig::with_capability(first, &[]);
+            for &(offset, next) in links {
+                config.write16(offset, 0x09 | (next as u16) << 8);
+            }
+            PciDevice::from_config(config).unwrap()
+        };
+
+        assert_eq!(
+            device(&[(0x40, 0x50), (0x50, 0)], 0x40)
+                .validate_capabilities()
+                .unwrap(),
+            2
+        );
+        let looping = device(&[(0x40, 0x50), (0x50, 0x40)], 0x40);
+        assert_eq!(looping.capabilities().count(), 2);
+        assert!(matches!(
+            looping.validate_capabilities(),
+            Err(PciError::CapabilityLoop { offset: 0x40 })
+        ));
+        let self_loop = device(&[(0xfc, 0xfc)], 0xfc);
+        assert_eq!(self_loop.capability_list().len(), 1);
+        assert!(matches!(
+            device(&[(0x40, 0x10)], 0x40).validate_capabilities(),
+            Err(PciError::CapabilityInvalidOffset { offset: 0x10 })
+        ));
+        assert!(matches!(
+            device(&[], 0x42).validate_capabilities(),
+            Err(PciError::CapabilityInvalidOffset { offset: 0x42 })
+        ));
+    }
+
+    #[test]
+    fn extended_capability_list() {
+        let mut space = [0u8; 0x1000];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        // AER v2 -> SR-IOV v1 -> ARI v1
+        space[0x100..0x104].copy_from_slice(&0x1482_0001u32.to_le_bytes());
+        space[0x148..0x14c].copy_from_slice(&0x1b81_0010u32.to_le_bytes());
+        space[0x1b8..0x1bc].copy_from_slice(&0x0001_000eu32.to_le_bytes());
+
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        let device = PciDevice::from_config(MockConfig::from_bytes(addr, &space)).unwrap();
+        let mut caps: FixedVec<(ExtendedCapabilityId, u8, u16), 4> = FixedVec::default();
+        caps.try_extend(
+            device
+                .extended_capabilities()
+                .map(|cap| (cap.id, cap.version, cap.offset)),
+        )
+        .unwrap();
+        assert_eq!(
+            caps.as_slice(),
+            [
+                (ExtendedCapabilityId::AdvancedErrorReporting, 2, 0x100),
+                (ExtendedCapabilityId::SrIov, 1, 0x148),
+                (ExtendedCapabilityId::AlternativeRoutingId, 1, 0x1b8),
+            ]
+        );
+        assert_eq!(
+            device
+                .find_extended_capability(ExtendedCapabilityId::SrIov)
+                .unwrap()
+                .offset,
+            0x148
+        );
+        assert!(device
+            .find_extended_capability(ExtendedCapabilityId::Pasid)
+            .is_none());
+
+        // ARI points back to SR-IOV
+        space[0x1b8..0x1bc].copy_from_slice(&0x1481_000eu32.to_le_bytes());
+        let device = PciDevice::from_config(MockConfig::from_bytes(addr, &space)).unwrap();
+        let mut caps = device.extended_capabilities();
+        assert_eq!(caps.by_ref().count(), 3);
+        assert!(matches!(
+            caps.error(),
+            Some(PciError::CapabilityLoop { offset: 0x148 })
+        ));
+    }
+
+    #[test]
+    fn msix_table_bounds() {
+        // MSI-X with 8 entries in BAR 2 at 0x2000, PBA in BAR 2 at 0x3000
+        let mut config =
+            MockConfig::with_capability(0x50, &[0x0007_0011, 0x0000_2002, 0x0000_3002]);
+        config.set_memory_bar(2, 0xfe00_0000, 0x2000, false);
+        let mut device = PciDevice::from_config(config.clone()).unwrap();
+        let identity = |paddr: PAddr| VAddr::from(paddr.as_u64());
+        assert!(matches!(
+            device.get_msix_irq_table_mut(&identity),
+            Err(PciError::MsiXOutOfBounds {
+                bir: 2,
+                offset: 0x2000,
+                len: 128
+            })
+        ));
+
+        config.set_memory_bar(2, 0xfe00_0000, 0x4000, false);
+        let mut device = PciDevice::from_config(config).unwrap();
+        let mut bar = vec![0u64; 0x4000 / 8];
+        let base = bar.as_mut_ptr() as u64;
+        let map = move |paddr: PAddr| VAddr::from(base + (paddr.as_u64() - 0xfe00_0000));
+        assert_eq!(device.get_msix_irq_table_mut(&map).unwrap().len(), 8);
+        assert!(device.get_msix_config().unwrap().enabled());
+    }
+
+    #[test]
+    fn msix_lazy_mapping() {
+        // MSI-X with 2048 entries in BAR 0 at 0, PBA in BAR 0 at 0x8000
+        let mut config = MockConfig::with_capability(0x50, &[0x07ff_0011, 0, 0x0000_8000]);
+        config.set_memory_bar(0, 0xfe00_0000, 0x10000, false);
+        let mut device = PciDevice::from_config(config).unwrap();
+        assert_eq!(device.get_msix_config().unwrap().table_size(), 2047);
+
+        let mut bar = vec![0u64; 0x10000 / 8];
+        let base = bar.as_mut_ptr() as u64;
+        let mut mapped: FixedVec<(u64, usize), 8> = FixedVec::default();
+        let mut map = |paddr: PAddr, len: usize| {
+            mapped.push((paddr.as_u64() - 0xfe00_0000, len)).unwrap();
+            Some(VAddr::from(base + (paddr.as_u64() - 0xfe00_0000)))
+        };
+        let mut table = device.msix_table(4, &mut map).unwrap();
+        assert_eq!((table.size(), table.active()), (2048, 4));
+        table.claim(3, 0xfee0_0000, 0x41).unwrap();
+        assert!(table.entry_mut(4).is_none());
+
+        table.grow(600, &mut map).unwrap();
+        assert!(matches!(
+            table.grow(2049, &mut map),
+            Err(PciError::MsiXVectorOutOfRange { index: 2048 })
+        ));
+        assert_eq!(
+            mapped.as_slice(),
+            [
+                (0, 0x1000),
+                (0x8000, 32),
+                (0x1000, 0x1000),
+                (0x8020, 32),
+                (0x2000, 0x1000),
+                (0x8040, 32)
+            ]
+        );
+        assert!(!table.entry_mut(599).unwrap().is_masked());
+        bar[0x8000 / 8 + 9] = 1 << 23;
+        assert_eq!(table.is_pending(601), None);
+        assert_eq!(table.is_pending(599), Some(true));
+        assert!(device.get_msix_config().unwrap().enabled());
+
+        let mut poller = PbaPoller::new(&mut table);
+        let mut fired: FixedVec<usize, 4> = FixedVec::default();
+        assert_eq!(poller.poll(|index| fired.push(index).unwrap()), 1);
+        assert_eq!(fired.as_slice(), [599]);
+        assert!(poller.table().is_pending(599).unwrap());
+        drop(poller);
+        assert!(!table.entry_mut(0).unwrap().is_masked());
+        // Claiming and dropping a vector leaves it masked
+        assert!(table.entry_mut(3).unwrap().is_masked());
+    }
+
+    #[test]
+    fn sub_dword_access() {
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        // Command: memory space, status: capabilities list and a received
+        // master abort (RW1C)
+        space[0x04..0x08].copy_from_slice(&0x2010_0002u32.to_le_bytes());
+
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        let mut device = PciDevice::from_config(MockConfig::from_bytes(addr, &space)).unwrap();
+        assert_eq!((device.vendor_id(), device.device_id()), (0x8086, 0x1234));
+        assert_eq!(device.config().read8(0x01), 0x80);
+
+        // The status bits are written as 0 (the mock doesn't implement RW1C,
+        // so they read back as written)
+        device.enable_bus_mastering();
+        assert_eq!(device.config().read32(0x04), 0x0000_0006);
+        assert_eq!(merge_write(0x1122_3344, 0x0d, 1, 0xab), 0x1122_ab44);
+        assert_eq!(merge_write(0x4000_0000, 0x0c, 1, 0x10), 0x0000_0010);
+    }
+
+    #[test]
+    fn type0_header_fields() {
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        space[0x08..0x0c].copy_from_slice(&0x0c03_3003u32.to_le_bytes());
+        space[0x0e] = 0x80;
+        space[0x2c..0x30].copy_from_slice(&0x0001_15d9u32.to_le_bytes());
+        space[0x3c..0x40].copy_from_slice(&0x1020_010bu32.to_le_bytes());
+
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        let mut device = PciDevice::from_config(MockConfig::from_bytes(addr, &space)).unwrap();
+        assert_eq!(
+            (
+                device.revision_id(),
+                device.prog_if(),
+                device.sub_class(),
+                device.base_class()
+            ),
+            (3, 0x30, 3, 0x0c)
+        );
+        assert!(device.is_multifunction());
+        assert_eq!(
+            (device.subsystem_vendor_id(), device.subsystem_id()),
+            (Some(0x15d9), Some(1))
+        );
+        assert_eq!((device.interrupt_line(), device.interrupt_pin()), (0x0b, 1));
+        assert_eq!(
+            (device.min_gnt(), device.max_lat()),
+            (Some(0x20), Some(0x10))
+        );
+
+        device.set_interrupt_line(0x0a);
+        assert_eq!((device.interrupt_line(), device.interrupt_pin()), (0x0a, 1));
+    }
+
+    #[test]
+    fn program_msix_vectors() {
+        let mut device =
+            PciDevice::from_config(MockConfig::with_capability(0x50, &[0x0003_0011])).unwrap();
+        let mut table: [MsiXTableEntry; 4] = core::array::from_fn(|_| MsiXTableEntry {
+            addr: 0,
+            data: 0,
+            vector_control: 1,
+        });
+        let mut msix = device.capability_mut::<MsiX<_>>().unwrap();
+        assert!(matches!(
+            msix.program_vectors(
+                &mut table,
+                &[(1, (0xfee0_0000, 0x41)), (4, (0xfee0_0000, 0x42))]
+            ),
+            Err(PciError::MsiXVectorOutOfRange { index: 4 })
+        ));
+        assert!(table.iter().all(|entry| entry.is_masked()));
+
+        msix.program_vectors(
+            &mut table,
+            &[(1, (0xfee0_0000, 0x41)), (3, (0xfee0_1000, 0x42))],
+        )
+        .unwrap();
+        assert!(!msix.function_mask());
+        assert_eq!((table[3].address(), table[3].data()), (0xfee0_1000, 0x42));
+        assert!(table[0].is_masked() && !table[1].is_masked() && !table[3].is_masked());
+    }
+
+    #[test]
+    fn program_msi() {
+        // MSI with 4 vectors requested, 64-bit addresses and masking
+        let config = MockConfig::with_capability(0x50, &[0x0184_0005, 0, 0, 0, u32::MAX]);
+        let mut device = PciDevice::from_config(config).unwrap();
+        assert!(matches!(
+            device.enable_msi(8, 0xfee0_0000, 0x40),
+            Err(PciError::MsiInvalidVectors { count: 8 })
+        ));
+        device.enable_msi(2, 0x1_fee0_0000, 0x40).unwrap();
+
+        let mut msi = device.msi().unwrap();
+        assert!(msi.enabled() && msi.is_64bit() && msi.per_vector_masking());
+        assert_eq!((msi.vectors_capable(), msi.vectors_enabled()), (4, 2));
+        assert_eq!(
+            (msi.message_address(), msi.message_data()),
+            (0x1_fee0_0000, 0x40)
+        );
+        assert_eq!(msi.mask_bits(), Some(0xffff_fffc));
+        msi.set_masked(1, true).unwrap();
+        assert!(msi.is_masked(1) && !msi.is_masked(0));
+        assert!(matches!(
+            msi.set_masked(4, true),
+            Err(PciError::MsiVectorOutOfRange { index: 4 })
+        ));
+
+        // 32-bit only, without masking: the data follows the address
+        let mut device =
+            PciDevice::from_config(MockConfig::with_capability(0x50, &[0x0000_0005])).unwrap();
+        let mut msi = device.msi().unwrap();
+        assert!(matches!(
+            msi.set_message(0x1_0000_0000, 0),
+            Err(PciError::MsiAddressTooWide { .. })
+        ));
+        msi.set_message(0xfee0_1000, 0x21).unwrap();
+        assert_eq!(device.config().read(0x58), 0x21);
+        assert!(matches!(
+            device.msi().unwrap().set_masked(0, true),
+            Err(PciError::MsiMaskingUnsupported)
+        ));
+    }
+
+    #[test]
+    fn shared_device() {
+        let mut config = MockConfig::with_capability(0x50, &[0x0007_0011]);
+        config.set_memory_bar(0, 0x40_0000_0000, 0x10_0000, true);
+        let device = PciDevice::from_config(config).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let bar = device.bar(0).unwrap();
+                        assert_eq!((bar.address, bar.size), (0x40_0000_0000, 0x10_0000));
+                        assert_eq!(device.capability::<MsiX<_>>().unwrap().table_size(), 7);
+                    }
+                });
+            }
+        });
+        assert_eq!(device.bar_raw(0), 0x0000_0004);
+    }
+
+    #[test]
+    fn iter_bars() {
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        // IO BAR 2
+        space[0x18..0x1c].copy_from_slice(&0x0000_e001u32.to_le_bytes());
+        // BARs 4 and 5 with the reserved types 1 (below 1 MiB) and 3
+        space[0x20..0x24].copy_from_slice(&0x000d_0002u32.to_le_bytes());
+        space[0x24..0x28].copy_from_slice(&0xfd00_0006u32.to_le_bytes());
+
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        let mut config = MockConfig::from_bytes(addr, &space);
+        config.set_memory_bar(0, 0x40_0000_0000, 0x10_0000, true);
+        config.set_bar_mask(2, 0xffff_fff0);
+        config.set_memory_bar(3, 0xfe00_0000, 0x1000, false);
+        config.set_bar_mask(4, 0xffff_f000);
+        config.set_bar_mask(5, 0xffff_f000);
+
+        let device = PciDevice::from_config(config).unwrap();
+        assert!(device.bar(2).is_none());
+        assert!(device.bar(4).is_none());
+        assert!(device.bar(5).is_none());
+        let bars = device.bar_table();
+        assert!(bars.iter().map(|(i, bar)| (*i, bar.address, bar.size)).eq([
+            (BarIndex::Bar0, 0x40_0000_0000, 0x10_0000),
+            (BarIndex::Bar3, 0xfe00_0000, 0x1000)
+        ]));
+        assert_eq!(BarIndex::new(5), Some(BarIndex::Bar5));
+        assert_eq!(BarIndex::new(6), None);
+    }
+
+    #[test]
+    fn bridge_bars() {
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        space[0x0e] = 0x01;
+        // A 64-bit BAR 1 would have its upper half in the bus numbers
+        space[0x14..0x18].copy_from_slice(&0xfd00_0004u32.to_le_bytes());
+        space[0x18..0x1c].copy_from_slice(&0x0002_0100u32.to_le_bytes());
+
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        let mut config = MockConfig::from_bytes(addr, &space);
+        config.set_memory_bar(0, 0xfe00_0000, 0x1000, false);
+        config.set_bar_mask(1, 0xffff_f000);
+        let device = PciDevice::from_config(config).unwrap();
+        assert_eq!(device.bar(0).unwrap().size, 0x1000);
+        assert!(device.bar(1).is_none());
+        assert!(device.bar(2).is_none());
+        assert!(device.bar(6).is_none());
+        assert_eq!(device.config().read(0x18), 0x0002_0100);
+        assert_eq!(device.bar_table().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn summary_of_bridges() {
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        for (header_type, bars) in [(0x01, 2), (0x02, 1)] {
+            let mut space = [0u8; 0x80];
+            space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+            space[0x0e] = header_type;
+            let mut config = MockConfig::from_bytes(addr, &space);
+            config.set_memory_bar(0, 0xfe00_0000, 0x1000, false);
+            if bars == 2 {
+                config.set_memory_bar(1, 0xfe10_0000, 0x4000, false);
+            }
+
+            let device = PciDevice::from_config(config).unwrap();
+            let summary = device.summary();
+            assert_eq!(summary.bars.len(), bars, "header type {}", header_type);
+            assert_eq!(
+                (summary.bars[0].0, summary.bars[0].1.address),
+                (0, 0xfe00_0000)
+            );
+        }
+    }
+
+    #[test]
+    fn cacheline_and_latency() {
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        // Bridge with BIST capable and a secondary latency timer of 32
+        space[0x0e] = 0x01;
+        space[0x0f] = 0x80;
+        space[0x1b] = 32;
+
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        let mut device = PciDevice::from_config(MockConfig::from_bytes(addr, &space)).unwrap();
+        assert!(device.configure_cacheline_and_latency());
+        assert_eq!(
+            device.cacheline_size() as usize * 4,
+            crate::arch::cache_line_size()
+        );
+        assert_eq!(device.latency_timer(), DEFAULT_LATENCY_TIMER);
+        assert_eq!(device.secondary_latency_timer(), Some(32));
+        assert!(matches!(device.device_type(), PciDeviceType::PciBridge));
+        assert_eq!(device.config().read(0x0c) >> 24, 0x80);
+    }
+}
@@ -0,0 +1,350 @@
+//! Ownership of individual MSI-X vectors and on-demand mapping of large
+//! MSI-X tables.
+//!
+//! A table can have up to 2048 entries (32 KiB, plus 256 bytes of pending
+//! bits), most of which a driver that uses a handful of queues never
+//! touches. [`MsixTable`] maps the table and the PBA in chunks of
+//! [`MSIX_ENTRIES_PER_CHUNK`] entries as vectors are activated.
+//!
+//! [`PbaPoller`] detects vectors firing from the pending bits instead of
+//! messages, for hosts without interrupt delivery (yet) and to debug lost
+//! interrupts.
+
+use bit_field::BitField;
+
+use crate::arch::{PAddr, VAddr};
+
+use super::{MsiXTableEntry, PciError};
+
+/// Table entries covered by one mapping (a 4 KiB page of the table).
+pub const MSIX_ENTRIES_PER_CHUNK: usize = 256;
+/// Largest MSI-X table (Table Size is an 11-bit field).
+pub const MSIX_MAX_ENTRIES: usize = 2048;
+
+const CHUNKS: usize = MSIX_MAX_ENTRIES / MSIX_ENTRIES_PER_CHUNK;
+const ENTRY_LEN: usize = core::mem::size_of::<MsiXTableEntry>();
+/// Pending bits of a chunk in bytes.
+const PBA_CHUNK_LEN: usize = MSIX_ENTRIES_PER_CHUNK / 8;
+
+/// An MSI-X table entry claimed by a driver.
+///
+/// The vector is programmed and unmasked when claimed. Dropping it masks the
+/// vector again and clears its address and data, so a partially torn down
+/// driver can't receive stale interrupts.
+#[derive(Debug)]
+pub struct MsixVector<'t> {
+    index: usize,
+    entry: &'t mut MsiXTableEntry,
+}
+
+impl<'t> MsixVector<'t> {
+    /// Claims table entry `index`, programs it with `address` and `data` and
+    /// unmasks it.
+    pub fn claim(index: usize, entry: &'t mut MsiXTableEntry, address: u64, data: u32) -> Self {
+        entry.set_masked(true);
+        entry.set_message(address, data);
+        entry.set_masked(false);
+        MsixVector { index, entry }
+    }
+
+    /// Index of the vector in the MSI-X table.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn address(&self) -> u64 {
+        self.entry.address()
+    }
+
+    pub fn data(&self) -> u32 {
+        self.entry.data()
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.entry.is_masked()
+    }
+
+    pub fn mask(&mut self) {
+        self.entry.set_masked(true);
+    }
+
+    pub fn unmask(&mut self) {
+        self.entry.set_masked(false);
+    }
+
+    /// Routes the vector to `cpu` by rewriting the destination of its message
+    /// address (`cpu` is the local APIC ID on x86).
+    ///
+    /// This only works when the driver programs the MSI-X table itself (bare
+    /// metal). Under Linux/VFIO the host owns the table, use
+    /// `linux::irq::HostIrq::set_affinity` instead.
+    ///
+    /// Fails if the message address can't encode `cpu` (APIC IDs above 255)
+    /// or, on aarch64, doesn't select a CPU at all (route through the ITS
+    /// instead). The vector is left unchanged in that case.
+    pub fn set_affinity(&mut self, cpu: u32) -> Result<(), PciError> {
+        let address = crate::arch::msi_set_destination(self.address(), cpu)?;
+        let data = self.data();
+        self.set_message(address, data);
+        Ok(())
+    }
+
+    /// Reprograms the vector, masking it while the message is updated.
+    pub fn set_message(&mut self, address: u64, data: u32) {
+        let masked = self.is_masked();
+        self.entry.set_masked(true);
+        self.entry.set_message(address, data);
+        self.entry.set_masked(masked);
+    }
+}
+
+impl<'t> Drop for MsixVector<'t> {
+    fn drop(&mut self) {
+        self.entry.set_masked(true);
+        self.entry.set_message(0, 0);
+    }
+}
+
+/// Claims all entries of `table`, programming entry `i` with `message(i)`.
+pub fn claim_all<'t>(
+    table: &'t mut [MsiXTableEntry],
+    message: impl Fn(usize) -> (u64, u32),
+) -> impl Iterator<Item = MsixVector<'t>> {
+    table.iter_mut().enumerate().map(move |(index, entry)| {
+        let (address, data) = message(index);
+        MsixVector::claim(index, entry, address, data)
+    })
+}
+
+/// The MSI-X table and PBA of a function, mapped up to the active vectors.
+///
+/// Created by [`PciDevice::msix_table`](super::PciDevice::msix_table). The
+/// mappings have to stay valid as long as the table is used, the table never
+/// unmaps them.
+#[derive(Debug)]
+pub struct MsixTable {
+    table: u64,
+    pba: u64,
+    size: usize,
+    active: usize,
+    /// Virtual addresses of the table and PBA parts of every mapped chunk.
+    chunks: [Option<(VAddr, VAddr)>; CHUNKS],
+}
+
+impl MsixTable {
+    pub(super) fn new(table: u64, pba: u64, size: usize) -> Self {
+        MsixTable {
+            table,
+            pba,
+            size: size.min(MSIX_MAX_ENTRIES),
+            active: 0,
+            chunks: [None; CHUNKS],
+        }
+    }
+
+    /// Number of entries of the table in hardware.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of usable entries (starting at 0).
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Makes the first `vectors` entries usable, mapping the chunks of the
+    /// table and PBA that hold them with `map` (see
+    /// [`PciDevice::msix_table`](super::PciDevice::msix_table)). Chunks that
+    /// are already mapped are kept.
+    pub fn grow(
+        &mut self,
+        vectors: usize,
+        map: &mut dyn FnMut(PAddr, usize) -> Option<VAddr>,
+    ) -> Result<(), PciError> {
+        if vectors > self.size {
+            return Err(PciError::MsiXVectorOutOfRange { index: vectors - 1 });
+        }
+        for chunk in 0..vectors.div_ceil(MSIX_ENTRIES_PER_CHUNK) {
+            if self.chunks[chunk].is_some() {
+                continue;
+            }
+            let first = chunk * MSIX_ENTRIES_PER_CHUNK;
+            let entries = (self.size - first).min(MSIX_ENTRIES_PER_CHUNK);
+
+            let table = self.table + (first * ENTRY_LEN) as u64;
+            let table_len = entries * ENTRY_LEN;
+            let table_vaddr =
+                map(PAddr::from(table), table_len).ok_or(PciError::MsiXMapFailed {
+                    paddr: table,
+                    len: table_len,
+                })?;
+            if !table_vaddr
+                .as_u64()
+                .is_multiple_of(core::mem::align_of::<MsiXTableEntry>() as u64)
+            {
+                return Err(PciError::MsiXMisaligned { offset: table });
+            }
+            let pba = self.pba + (chunk * PBA_CHUNK_LEN) as u64;
+            let pba_len = entries.div_ceil(64) * 8;
+            let pba_vaddr = map(PAddr::from(pba), pba_len).ok_or(PciError::MsiXMapFailed {
+                paddr: pba,
+                len: pba_len,
+            })?;
+            if !pba_vaddr.as_u64().is_multiple_of(8) {
+                return Err(PciError::MsiXMisaligned { offset: pba });
+            }
+            trace!("Mapped MSI-X entries {} to {}", first, first + entries - 1);
+            self.chunks[chunk] = Some((table_vaddr, pba_vaddr));
+        }
+        self.active = self.active.max(vectors);
+        Ok(())
+    }
+
+    /// Table entry `index`, None if it isn't active.
+    pub fn entry_mut(&mut self, index: usize) -> Option<&mut MsiXTableEntry> {
+        if index >= self.active {
+            return None;
+        }
+        let (table, _) = self.chunks[index / MSIX_ENTRIES_PER_CHUNK]?;
+        // Safety: the chunk maps the entry (checked to be aligned in
+        // `grow`), `&mut self` makes the access exclusive
+        Some(unsafe {
+            &mut *table
+                .as_mut_ptr::<MsiXTableEntry>()
+                .add(index % MSIX_ENTRIES_PER_CHUNK)
+        })
+    }
+
+    /// Claims active entry `index` (see [`MsixVector::claim`]).
+    pub fn claim(&mut self, index: usize, address: u64, data: u32) -> Option<MsixVector<'_>> {
+        let entry = self.entry_mut(index)?;
+        Some(MsixVector::claim(index, entry, address, data))
+    }
+
+    /// Whether active vector `index` has a pending message (it fired while
+    /// masked), None if it isn't active.
+    pub fn is_pending(&self, index: usize) -> Option<bool> {
+        if index >= self.active {
+            return None;
+        }
+        let (_, pba) = self.chunks[index / MSIX_ENTRIES_PER_CHUNK]?;
+        let bit = index % MSIX_ENTRIES_PER_CHUNK;
+        // Safety: the chunk maps the PBA QWORD of the entry
+        let qword = unsafe { core::ptr::read_volatile(pba.as_ptr::<u64>().add(bit / 64)) };
+        Some(qword.get_bit(bit % 64))
+    }
+
+    /// The active vectors with pending messages, in ascending order.
+    pub fn pending(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.active.div_ceil(64))
+            .flat_map(move |qword| {
+                let (_, pba) = self.chunks[qword * 64 / MSIX_ENTRIES_PER_CHUNK]
+                    .expect("active chunks are mapped");
+                // Safety: the chunk maps the PBA QWORDs of its entries
+                let bits = unsafe {
+                    core::ptr::read_volatile(
+                        pba.as_ptr::<u64>()
+                            .add(qword % (MSIX_ENTRIES_PER_CHUNK / 64)),
+                    )
+                };
+                (0..64)
+                    .filter(move |bit| bits.get_bit(*bit))
+                    .map(move |bit| qword * 64 + bit)
+            })
+            .take_while(move |index| *index < self.active)
+    }
+}
+
+/// Polls the PBA for vectors that fired instead of taking their interrupts.
+///
+/// All active vectors are masked while the poller exists, so the function
+/// only sets their pending bits. A pending bit stays set until the driver
+/// services the cause (how is function-specific, e.g., by draining the
+/// queue and acknowledging the interrupt cause register), the handler is
+/// called on every poll until then. Dropping the poller unmasks the vectors
+/// that weren't masked before, which delivers the messages still pending.
+#[derive(Debug)]
+pub struct PbaPoller<'t> {
+    table: &'t mut MsixTable,
+    /// Vectors that were unmasked when polling started.
+    unmasked: [u64; MSIX_MAX_ENTRIES / 64],
+}
+
+impl<'t> PbaPoller<'t> {
+    pub fn new(table: &'t mut MsixTable) -> Self {
+        let mut unmasked = [0u64; MSIX_MAX_ENTRIES / 64];
+        for index in 0..table.active() {
+            if let Some(entry) = table.entry_mut(index) {
+                if !entry.is_masked() {
+                    unmasked[index / 64].set_bit(index % 64, true);
+                    entry.set_masked(true);
+                }
+            }
+        }
+        PbaPoller { table, unmasked }
+    }
+
+    pub fn table(&self) -> &MsixTable {
+        self.table
+    }
+
+    /// Calls `handler` with every pending vector.
+    ///
+    /// # Returns
+    /// The number of pending vectors.
+    pub fn poll(&mut self, mut handler: impl FnMut(usize)) -> usize {
+        let mut pending = 0;
+        for index in self.table.pending() {
+            handler(index);
+            pending += 1;
+        }
+        pending
+    }
+}
+
+impl<'t> Drop for PbaPoller<'t> {
+    fn drop(&mut self) {
+        for index in 0..self.table.active() {
+            if self.unmasked[index / 64].get_bit(index % 64) {
+                if let Some(entry) = self.table.entry_mut(index) {
+                    entry.set_masked(false);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn affinity() {
+        let mut entry = MsiXTableEntry {
+            addr: 0,
+            data: 0,
+            vector_control: 1,
+        };
+        let mut vector = MsixVector::claim(0, &mut entry, 0xfee0_1000, 0x41);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            vector.set_affinity(0xab).unwrap();
+            assert_eq!(
+                (vector.address(), vector.data()),
+                (0xfee0_0000 | 0xab << 12, 0x41)
+            );
+            // x2APIC IDs don't fit into the address
+            assert!(matches!(
+                vector.set_affinity(256),
+                Err(PciError::MsiDestinationInvalid { cpu: 256 })
+            ));
+        }
+        #[cfg(target_arch = "aarch64")]
+        assert!(matches!(
+            vector.set_affinity(1),
+            Err(PciError::MsiAffinityUnsupported)
+        ));
+        assert!(!vector.is_masked());
+    }
+}
@@ -0,0 +1,364 @@
+//! The PCI Express capability.
+
+use bit_field::BitField;
+
+use super::{CapabilityId, ConfigSpace, PCIAddress, PCIHeader, TypedCapability};
+
+/// Register offsets within the capability.
+const PCIE_CAPABILITIES: u32 = 0x00;
+const DEVICE_CAPABILITIES: u32 = 0x04;
+const DEVICE_CONTROL: u32 = 0x08;
+const LINK_CAPABILITIES: u32 = 0x0c;
+const LINK_CONTROL: u32 = 0x10;
+const SLOT_CAPABILITIES: u32 = 0x14;
+const DEVICE_CAPABILITIES_2: u32 = 0x24;
+const DEVICE_CONTROL_2: u32 = 0x28;
+const LINK_CONTROL_2: u32 = 0x30;
+
+/// Device Capabilities: Extended Tag Field Supported.
+const DEVCAP_EXT_TAG: usize = 5;
+/// Device Control: Extended Tag Field Enable.
+const DEVCTL_EXT_TAG: usize = 8;
+/// Device Capabilities 2: 10-Bit Tag Completer/Requester Supported.
+const DEVCAP2_10BIT_TAG_COMP: usize = 16;
+const DEVCAP2_10BIT_TAG_REQ: usize = 17;
+/// Device Control 2: 10-Bit Tag Requester Enable.
+const DEVCTL2_10BIT_TAG_REQ: usize = 12;
+/// PCI Express Capabilities: Slot Implemented.
+const PCIECAP_SLOT: usize = 8;
+/// Link Capabilities: Data Link Layer Link Active Reporting Capable.
+const LNKCAP_DLLLA_REPORTING: usize = 20;
+/// Link Status: Link Training and Data Link Layer Link Active.
+const LNKSTA_TRAINING: usize = 11;
+const LNKSTA_DLLLA: usize = 13;
+
+/// Device/Port type field of the PCI Express Capabilities register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PciExpressPortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamSwitchPort,
+    DownstreamSwitchPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    RootComplexIntegratedEndpoint,
+    RootComplexEventCollector,
+    Unknown(u8),
+}
+
+impl From<u8> for PciExpressPortType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x0 => PciExpressPortType::Endpoint,
+            0x1 => PciExpressPortType::LegacyEndpoint,
+            0x4 => PciExpressPortType::RootPort,
+            0x5 => PciExpressPortType::UpstreamSwitchPort,
+            0x6 => PciExpressPortType::DownstreamSwitchPort,
+            0x7 => PciExpressPortType::PcieToPciBridge,
+            0x8 => PciExpressPortType::PciToPcieBridge,
+            0x9 => PciExpressPortType::RootComplexIntegratedEndpoint,
+            0xa => PciExpressPortType::RootComplexEventCollector,
+            x => PciExpressPortType::Unknown(x),
+        }
+    }
+}
+
+/// A power limit (Slot Power Limit Value and Scale fields).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SlotPower {
+    pub value: u8,
+    /// 0: 1.0x, 1: 0.1x, 2: 0.01x, 3: 0.001x.
+    pub scale: u8,
+}
+
+impl SlotPower {
+    /// The limit in milliwatts, None for reserved encodings.
+    pub fn milliwatts(&self) -> Option<u32> {
+        let value = self.value as u32;
+        match (self.scale, self.value) {
+            // Values above 239 W with scale 1.0x encode 250, 275 and 300 W
+            (0, 0xf0..=0xf2) => Some((250 + 25 * (value - 0xf0)) * 1000),
+            (0, 0xf3..=0xff) => None,
+            (scale, _) => Some(value * [1000, 100, 10, 1][scale as usize & 0b11]),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PciExpress<'s, A = PCIAddress> {
+    /// A reference to the device's PCI header.
+    header: &'s PCIHeader<A>,
+    /// The offset where the PCIe capability is located within the PCI header.
+    pub offset: u32,
+}
+
+impl<'s, A> TypedCapability<'s, A> for PciExpress<'s, A> {
+    const ID: CapabilityId = CapabilityId::PCIExpress;
+
+    fn at(header: &'s PCIHeader<A>, offset: u32) -> Self {
+        PciExpress { header, offset }
+    }
+}
+
+impl<'s, A: ConfigSpace> PciExpress<'s, A> {
+    fn read(&self, register: u32) -> u32 {
+        self.header.read(self.offset + register)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.header.write(self.offset + register, value)
+    }
+
+    /// The PCI Express Capabilities register.
+    pub fn capabilities(&self) -> u16 {
+        (self.read(PCIE_CAPABILITIES) >> 16) as u16
+    }
+
+    /// Version of the capability structure (registers past the slot
+    /// registers only exist in version 2).
+    pub fn version(&self) -> u8 {
+        self.capabilities().get_bits(0..4) as u8
+    }
+
+    pub fn port_type(&self) -> PciExpressPortType {
+        PciExpressPortType::from(self.capabilities().get_bits(4..8) as u8)
+    }
+
+    /// Whether the port is connected to a slot (rather than an integrated
+    /// device).
+    pub fn slot_implemented(&self) -> bool {
+        self.capabilities().get_bit(PCIECAP_SLOT)
+    }
+
+    /// The Device Capabilities register.
+    pub fn device_capabilities(&self) -> u32 {
+        self.read(DEVICE_CAPABILITIES)
+    }
+
+    /// The Device Control register.
+    pub fn device_control(&self) -> u16 {
+        self.read(DEVICE_CONTROL) as u16
+    }
+
+    pub fn set_device_control(&mut self, control: u16) {
+        // The upper half is the Device Status register (RW1C), writing zeros
+        // leaves it alone
+        self.write(DEVICE_CONTROL, control as u32);
+    }
+
+    /// The Device Capabilities 2 register (zero before version 2).
+    pub fn device_capabilities_2(&self) -> u32 {
+        if self.version() < 2 {
+            return 0;
+        }
+        self.read(DEVICE_CAPABILITIES_2)
+    }
+
+    /// The Device Control 2 register (zero before version 2).
+    pub fn device_control_2(&self) -> u16 {
+        if self.version() < 2 {
+            return 0;
+        }
+        self.read(DEVICE_CONTROL_2) as u16
+    }
+
+    pub fn set_device_control_2(&mut self, control: u16) {
+        if self.version() >= 2 {
+            self.write(DEVICE_CONTROL_2, control as u32);
+        }
+    }
+
+    /// The Link Capabilities register.
+    pub fn link_capabilities(&self) -> u32 {
+        self.read(LINK_CAPABILITIES)
+    }
+
+    /// The Link Control register.
+    pub fn link_control(&self) -> u16 {
+        self.read(LINK_CONTROL) as u16
+    }
+
+    pub fn set_link_control(&mut self, control: u16) {
+        // The upper half is the Link Status register (partly RW1C)
+        self.write(LINK_CONTROL, control as u32);
+    }
+
+    /// The Link Status register.
+    pub fn link_status(&self) -> u16 {
+        (self.read(LINK_CONTROL) >> 16) as u16
+    }
+
+    /// The Link Control 2 register (zero before version 2).
+    pub fn link_control_2(&self) -> u16 {
+        if self.version() < 2 {
+            return 0;
+        }
+        self.read(LINK_CONTROL_2) as u16
+    }
+
+    pub fn set_link_control_2(&mut self, control: u16) {
+        if self.version() >= 2 {
+            self.write(LINK_CONTROL_2, control as u32);
+        }
+    }
+
+    /// The Slot Capabilities register (zero if no slot is implemented).
+    pub fn slot_capabilities(&self) -> u32 {
+        if !self.slot_implemented() {
+            return 0;
+        }
+        self.read(SLOT_CAPABILITIES)
+    }
+
+    /// The power the slot of a downstream port provides, None if it has no
+    /// slot.
+    pub fn slot_power_limit(&self) -> Option<SlotPower> {
+        if !self.slot_implemented() {
+            return None;
+        }
+        let caps = self.slot_capabilities();
+        Some(SlotPower {
+            value: caps.get_bits(7..15) as u8,
+            scale: caps.get_bits(15..17) as u8,
+        })
+    }
+
+    /// The slot power limit the port above sent to the (upstream) function,
+    /// i.e., how much the function may draw.
+    pub fn captured_slot_power_limit(&self) -> SlotPower {
+        let caps = self.device_capabilities();
+        SlotPower {
+            value: caps.get_bits(18..26) as u8,
+            scale: caps.get_bits(26..28) as u8,
+        }
+    }
+
+    /// Whether the port reports if its link is active (mandatory for
+    /// downstream ports that support hot-plug).
+    pub fn link_active_reporting(&self) -> bool {
+        self.link_capabilities().get_bit(LNKCAP_DLLLA_REPORTING)
+    }
+
+    /// Whether the link of the (downstream) port is up, None if the port
+    /// doesn't report it.
+    pub fn link_active(&self) -> Option<bool> {
+        if self.link_active_reporting() {
+            Some(self.link_status().get_bit(LNKSTA_DLLLA))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the link is (re-)training.
+    pub fn link_training(&self) -> bool {
+        self.link_status().get_bit(LNKSTA_TRAINING)
+    }
+
+    /// Whether the function supports 8-bit tags (instead of 5-bit ones).
+    pub fn extended_tag_supported(&self) -> bool {
+        self.device_capabilities().get_bit(DEVCAP_EXT_TAG)
+    }
+
+    pub fn extended_tag_enabled(&self) -> bool {
+        self.device_control().get_bit(DEVCTL_EXT_TAG)
+    }
+
+    /// Enables or disables 8-bit tags, returns false if they are not
+    /// supported.
+    pub fn set_extended_tag(&mut self, enable: bool) -> bool {
+        if enable && !self.extended_tag_supported() {
+            return false;
+        }
+        let mut control = self.device_control();
+        control.set_bit(DEVCTL_EXT_TAG, enable);
+        self.set_device_control(control);
+        true
+    }
+
+    /// Whether the function can complete requests with 10-bit tags.
+    pub fn ten_bit_tag_completer_supported(&self) -> bool {
+        self.device_capabilities_2().get_bit(DEVCAP2_10BIT_TAG_COMP)
+    }
+
+    /// Whether the function can issue requests with 10-bit tags.
+    pub fn ten_bit_tag_requester_supported(&self) -> bool {
+        self.device_capabilities_2().get_bit(DEVCAP2_10BIT_TAG_REQ)
+    }
+
+    pub fn ten_bit_tag_enabled(&self) -> bool {
+        self.device_control_2().get_bit(DEVCTL2_10BIT_TAG_REQ)
+    }
+
+    /// Enables or disables 10-bit tags for requests of the function, returns
+    /// false if they are not supported.
+    ///
+    /// Only enable them if every completer the function talks to (usually
+    /// the root port, and peers for peer-to-peer DMA) supports them, see
+    /// [`PciExpress::ten_bit_tag_completer_supported`].
+    pub fn set_ten_bit_tag(&mut self, enable: bool) -> bool {
+        if enable && !self.ten_bit_tag_requester_supported() {
+            return false;
+        }
+        let mut control = self.device_control_2();
+        control.set_bit(DEVCTL2_10BIT_TAG_REQ, enable);
+        self.set_device_control_2(control);
+        true
+    }
+
+    /// Enables the largest tags the function supports (10-bit if
+    /// `completer_10bit` is set, i.e., the completer supports them, and
+    /// 8-bit otherwise) and returns the number of tag bits now in use.
+    ///
+    /// More tags allow more outstanding requests, which matters for devices
+    /// with deep queues (NVMe, NICs).
+    pub fn enable_tags(&mut self, completer_10bit: bool) -> u8 {
+        let extended = self.set_extended_tag(true);
+        if completer_10bit && self.set_ten_bit_tag(true) {
+            10
+        } else if extended {
+            8
+        } else {
+            5
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::PciInterface;
+    use crate::pci::mock::MockConfig;
+    use crate::pci::PciDevice;
+
+    #[test]
+    fn tags() {
+        // PCIe v2 endpoint, extended tags supported and a correctable error
+        // detected (RW1C) in the device status
+        let mut config = MockConfig::with_capability(0x40, &[0x0002_0010, 1 << 5, 0x0001_0000]);
+        // 10-bit tag requester supported
+        config.write32(0x64, 1 << 17);
+        let mut device = PciDevice::from_config(config).unwrap();
+        let mut pcie = device.pci_express().unwrap();
+        assert_eq!(pcie.port_type(), PciExpressPortType::Endpoint);
+        assert_eq!(pcie.enable_tags(false), 8);
+        assert!(pcie.extended_tag_enabled());
+        assert!(!pcie.ten_bit_tag_enabled());
+        assert_eq!(pcie.enable_tags(true), 10);
+        assert!(pcie.ten_bit_tag_enabled());
+        assert_eq!(device.config().read(0x48), 1 << 8);
+    }
+
+    #[test]
+    fn slot_power() {
+        let power = |value, scale| SlotPower { value, scale }.milliwatts();
+        assert_eq!(power(75, 0), Some(75_000));
+        assert_eq!(power(250, 1), Some(25_000));
+        assert_eq!(power(0xf1, 0), Some(275_000));
+        assert_eq!(power(0xf3, 0), None);
+    }
+}
@@ -0,0 +1,223 @@
+//! Per-queue handles of multi-queue devices.
+//!
+//! NICs and NVMe controllers give every queue its own doorbell register(s),
+//! at a fixed stride in one BAR, and its own MSI-X vector. Nothing else of
+//! the device is touched on the data path, so once the queues are set up,
+//! each can be driven from a different core. [`split_queues`] (or
+//! [`PciDeviceHandle::split_queues`](super::PciDeviceHandle::split_queues))
+//! hands out a [`QueueHandle`] per queue owning exactly these resources;
+//! the handles are `Send` and don't borrow each other or the device.
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use super::builder::MappedBar;
+use super::{MsiXTableEntry, MsixVector, PciError};
+
+/// Where the doorbells of the queues are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DoorbellLayout {
+    /// BAR the doorbells are in.
+    pub bar: u8,
+    /// Offset of the doorbell region of queue 0.
+    pub offset: u64,
+    /// Distance between the doorbell regions of two queues (and the size of
+    /// a region), a multiple of 4 bytes.
+    pub stride: u64,
+    /// MSI-X vector of queue 0, queue `n` gets vector `first_vector + n`.
+    pub first_vector: usize,
+}
+
+/// The resources of one queue: its doorbell registers and MSI-X vector.
+#[derive(Debug)]
+pub struct QueueHandle<'d> {
+    index: usize,
+    doorbell: *mut u8,
+    len: usize,
+    vector: Option<MsixVector<'d>>,
+    _bar: PhantomData<&'d mut [u8]>,
+}
+
+// Safety: the doorbell region is owned by this handle alone
+unsafe impl Send for QueueHandle<'_> {}
+
+impl<'d> QueueHandle<'d> {
+    /// Index of the queue.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Size of the doorbell region in bytes.
+    pub fn doorbell_len(&self) -> usize {
+        self.len
+    }
+
+    /// The MSI-X vector of the queue, None if MSI-X isn't enabled.
+    pub fn vector(&mut self) -> Option<&mut MsixVector<'d>> {
+        self.vector.as_mut()
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset.is_multiple_of(core::mem::size_of::<T>())
+                && offset + core::mem::size_of::<T>() <= self.len,
+            "doorbell register out of bounds"
+        );
+        self.doorbell.wrapping_add(offset) as *mut T
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        // Safety: in bounds of the mapped region and aligned (checked by
+        // `register`)
+        unsafe { core::ptr::read_volatile(self.register(offset)) }
+    }
+
+    pub fn write32(&mut self, offset: usize, value: u32) {
+        // Safety: as in `read32`
+        unsafe { core::ptr::write_volatile(self.register(offset), value) }
+    }
+
+    pub fn write64(&mut self, offset: usize, value: u64) {
+        // Safety: as in `read32`
+        unsafe { core::ptr::write_volatile(self.register(offset), value) }
+    }
+
+    /// Rings the doorbell, i.e., writes `value` (usually the new tail or
+    /// head index) to the first register of the region.
+    pub fn ring(&mut self, value: u32) {
+        self.write32(0, value);
+    }
+}
+
+/// Splits the doorbells of `queues` queues in `bar` (laid out as described
+/// by `layout`) and their vectors in `msix` into per-queue handles.
+///
+/// The vector of queue `n` is programmed with `message(n)` and unmasked
+/// (see [`MsixVector::claim`]). Without an MSI-X table, the handles have no
+/// vectors.
+pub fn split_queues<'d>(
+    bar: &'d MappedBar,
+    msix: Option<&'d mut [MsiXTableEntry]>,
+    layout: DoorbellLayout,
+    queues: usize,
+    message: impl Fn(usize) -> (u64, u32),
+) -> Result<Vec<QueueHandle<'d>>, PciError> {
+    let len = layout.stride.checked_mul(queues as u64);
+    if layout.stride == 0
+        || !layout.stride.is_multiple_of(4)
+        || len
+            .and_then(|len| len.checked_add(layout.offset))
+            .is_none_or(|end| end > bar.bar.size)
+    {
+        return Err(PciError::DoorbellOutOfBounds {
+            bar: layout.bar,
+            offset: layout.offset,
+            len: len.unwrap_or(u64::MAX),
+        });
+    }
+
+    let mut vectors = match msix {
+        Some(table) => {
+            let end = layout.first_vector + queues;
+            if end > table.len() {
+                return Err(PciError::MsiXVectorOutOfRange { index: end - 1 });
+            }
+            Some(table[layout.first_vector..end].iter_mut())
+        }
+        None => None,
+    };
+
+    let base = bar
+        .vaddr
+        .as_mut_ptr::<u8>()
+        .wrapping_add(layout.offset as usize);
+    let handles = (0..queues)
+        .map(|index| {
+            let vector = vectors
+                .as_mut()
+                .and_then(|entries| entries.next())
+                .map(|entry| {
+                    let (address, data) = message(index);
+                    MsixVector::claim(layout.first_vector + index, entry, address, data)
+                });
+            QueueHandle {
+                index,
+                doorbell: base.wrapping_add(index * layout.stride as usize),
+                len: layout.stride as usize,
+                vector,
+                _bar: PhantomData,
+            }
+        })
+        .collect();
+    debug!(
+        "Split {} queues with doorbells at BAR {} offset {:#x}",
+        queues, layout.bar, layout.offset
+    );
+    Ok(handles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::VAddr;
+    use crate::iomem::MapOptions;
+    use crate::pci::{Bar, BarType};
+
+    #[test]
+    fn queues_on_threads() {
+        let mut registers = [0u32; 64];
+        let bar = MappedBar {
+            index: 0,
+            bar: Bar {
+                region_type: BarType::Mem,
+                prefetchable: false,
+                address: 0xfe00_0000,
+                size: 256,
+            },
+            vaddr: VAddr::from(registers.as_mut_ptr() as u64),
+            options: MapOptions::device(),
+        };
+        let mut table: Vec<MsiXTableEntry> = (0..8)
+            .map(|_| MsiXTableEntry {
+                addr: 0,
+                data: 0,
+                vector_control: 1,
+            })
+            .collect();
+        let layout = DoorbellLayout {
+            bar: 0,
+            offset: 0x40,
+            stride: 8,
+            first_vector: 1,
+        };
+
+        let too_many = split_queues(&bar, None, layout, 25, |_| (0, 0));
+        assert!(matches!(
+            too_many,
+            Err(PciError::DoorbellOutOfBounds { .. })
+        ));
+        let queues = split_queues(&bar, Some(&mut table), layout, 4, |n| {
+            (0xfee0_0000, 0x40 + n as u32)
+        })
+        .unwrap();
+        std::thread::scope(|scope| {
+            for mut queue in queues {
+                scope.spawn(move || {
+                    let index = queue.index() as u32;
+                    let vector = queue.vector().unwrap();
+                    assert_eq!(vector.index(), index as usize + 1);
+                    assert!(!vector.is_masked() && vector.data() == 0x40 + index);
+                    queue.ring(index + 100);
+                    queue.write32(4, index);
+                });
+            }
+        });
+
+        assert_eq!(&registers[16..24], &[100, 0, 101, 1, 102, 2, 103, 3]);
+        // Dropping the handles masked the vectors again
+        assert!(table
+            .iter()
+            .all(|entry| entry.is_masked() && entry.data() == 0));
+    }
+}
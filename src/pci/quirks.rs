@@ -0,0 +1,264 @@
+//! Workarounds for devices that don't behave as the specifications say.
+//!
+//! Quirks are matched by vendor, device and revision ID. Most of them are
+//! flags the driver (or the code resetting the device) has to check, some
+//! come with a fixup that rewrites configuration registers when the device
+//! is attached by [`PciDriverBuilder`](super::PciDriverBuilder). Besides the
+//! built-in table, hosts can register their own with [`register_quirks`].
+
+use core::time::Duration;
+
+use bitflags::bitflags;
+use custom_error::custom_error;
+use spin::Mutex;
+
+use super::{ConfigSpace, DeviceId, DeviceRevision, PciDevice, VendorId};
+
+/// Maximum number of quirk tables that can be registered at the same time.
+pub const MAX_QUIRK_TABLES: usize = 4;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub QuirkError
+    TooManyTables = "no free slot to register another quirk table",
+}
+
+bitflags! {
+    /// Deviations of PCI devices from the specifications.
+    pub struct QuirkFlags: u32 {
+        /// Function level reset hangs or corrupts the device, reset through
+        /// the power management capability (D3hot -> D0) instead.
+        const NO_FLR = 1 << 0;
+        /// MSI-X doesn't work (wrong table offset or broken delivery), use
+        /// MSI or legacy interrupts.
+        const NO_MSIX = 1 << 1;
+        /// MSI doesn't work.
+        const NO_MSI = 1 << 2;
+        /// A secondary bus reset of the upstream bridge doesn't reset (or
+        /// kills) the device.
+        const NO_BUS_RESET = 1 << 3;
+        /// The device mustn't be put into D3.
+        const NO_D3 = 1 << 4;
+    }
+}
+
+/// Rewrites registers of a device (through [`ConfigAccess`]) on attach.
+pub type Fixup = fn(&mut dyn ConfigAccess);
+
+/// Configuration space access for [`Fixup`]s.
+pub trait ConfigAccess {
+    fn read32(&self, offset: u32) -> u32;
+    fn write32(&mut self, offset: u32, value: u32);
+}
+
+impl<A: ConfigSpace> ConfigAccess for A {
+    fn read32(&self, offset: u32) -> u32 {
+        ConfigSpace::read32(self, offset)
+    }
+
+    fn write32(&mut self, offset: u32, value: u32) {
+        ConfigSpace::write32(self, offset, value)
+    }
+}
+
+/// A quirk of the devices with the given IDs.
+#[derive(Clone, Copy)]
+pub struct Quirk {
+    pub vendor: VendorId,
+    /// The device ID, None matches all devices of the vendor.
+    pub device: Option<DeviceId>,
+    /// First and last affected revision (inclusive).
+    pub revisions: (DeviceRevision, DeviceRevision),
+    pub flags: QuirkFlags,
+    /// Extra time to wait after the device was brought to D0 (on top of
+    /// the 10 ms the specification requires).
+    pub d0_delay: Duration,
+    pub fixup: Option<Fixup>,
+}
+
+impl Quirk {
+    /// A quirk for all revisions of `vendor`:`device` with just `flags`.
+    pub const fn flags(vendor: VendorId, device: DeviceId, flags: QuirkFlags) -> Quirk {
+        Quirk {
+            vendor,
+            device: Some(device),
+            revisions: (0, DeviceRevision::MAX),
+            flags,
+            d0_delay: Duration::ZERO,
+            fixup: None,
+        }
+    }
+
+    pub fn matches(&self, vendor: VendorId, device: DeviceId, revision: DeviceRevision) -> bool {
+        self.vendor == vendor
+            && self.device.is_none_or(|d| d == device)
+            && (self.revisions.0..=self.revisions.1).contains(&revision)
+    }
+}
+
+impl core::fmt::Debug for Quirk {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("Quirk")
+            .field("vendor", &self.vendor)
+            .field("device", &self.device)
+            .field("revisions", &self.revisions)
+            .field("flags", &self.flags)
+            .field("d0_delay", &self.d0_delay)
+            .field("fixup", &self.fixup.is_some())
+            .finish()
+    }
+}
+
+/// Quirks known to the crate (the same devices Linux has quirks for).
+pub static BUILTIN_QUIRKS: [Quirk; 4] = [
+    // AMD Starship/Matisse HD Audio and USB 3.0 controllers
+    Quirk::flags(0x1022, 0x1487, QuirkFlags::NO_FLR),
+    Quirk::flags(0x1022, 0x149c, QuirkFlags::NO_FLR),
+    // Intel 82579LM/82579V Gigabit Ethernet
+    Quirk::flags(0x8086, 0x1502, QuirkFlags::NO_FLR),
+    Quirk::flags(0x8086, 0x1503, QuirkFlags::NO_FLR),
+];
+
+static TABLES: Mutex<[Option<&'static [Quirk]>; MAX_QUIRK_TABLES]> =
+    Mutex::new([None; MAX_QUIRK_TABLES]);
+
+/// Registers additional quirks, which are applied besides the built-in
+/// ones.
+pub fn register_quirks(table: &'static [Quirk]) -> Result<(), QuirkError> {
+    let mut tables = TABLES.lock();
+    let slot = tables
+        .iter_mut()
+        .find(|t| t.is_none())
+        .ok_or(QuirkError::TooManyTables)?;
+    *slot = Some(table);
+    Ok(())
+}
+
+/// Removes a previously registered quirk table.
+pub fn unregister_quirks(table: &'static [Quirk]) {
+    let mut tables = TABLES.lock();
+    for slot in tables.iter_mut() {
+        if slot.is_some_and(|t| core::ptr::eq(t, table)) {
+            *slot = None;
+        }
+    }
+}
+
+/// The combined quirks of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    pub flags: QuirkFlags,
+    /// The longest D0 delay of all matching quirks.
+    pub d0_delay: Duration,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            flags: QuirkFlags::empty(),
+            d0_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl Quirks {
+    pub fn contains(&self, flags: QuirkFlags) -> bool {
+        self.flags.contains(flags)
+    }
+}
+
+/// Calls `f` with every quirk that matches the IDs.
+fn for_each_match(
+    vendor: VendorId,
+    device: DeviceId,
+    revision: DeviceRevision,
+    f: impl FnMut(&Quirk),
+) {
+    let tables = TABLES.lock();
+    core::iter::once(&BUILTIN_QUIRKS[..])
+        .chain(tables.iter().flatten().copied())
+        .flatten()
+        .filter(|quirk| quirk.matches(vendor, device, revision))
+        .for_each(f);
+}
+
+/// The quirks of the device with the given IDs (without running fixups).
+pub fn lookup(vendor: VendorId, device: DeviceId, revision: DeviceRevision) -> Quirks {
+    let mut quirks = Quirks::default();
+    for_each_match(vendor, device, revision, |quirk| {
+        quirks.flags |= quirk.flags;
+        quirks.d0_delay = quirks.d0_delay.max(quirk.d0_delay);
+    });
+    quirks
+}
+
+/// Runs the fixups of all quirks matching `device` and returns its
+/// quirks.
+pub fn apply<A: ConfigSpace>(device: &mut PciDevice<A>) -> Quirks {
+    let (vendor, device_id, revision) =
+        (device.vendor_id(), device.device_id(), device.revision_id());
+    let quirks = lookup(vendor, device_id, revision);
+    if quirks != Quirks::default() {
+        info!(
+            "Quirks of {:x}:{:x} (rev {:x}): {:#x}",
+            vendor,
+            device_id,
+            revision,
+            quirks.flags.bits()
+        );
+    }
+    let config = device.config_mut();
+    for_each_match(vendor, device_id, revision, |quirk| {
+        if let Some(fixup) = quirk.fixup {
+            fixup(config);
+        }
+    });
+    quirks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::mock::MockConfig;
+    use crate::pci::PCIAddress;
+
+    fn clear_interrupt_pin(config: &mut dyn ConfigAccess) {
+        let reg = config.read32(0x3c);
+        config.write32(0x3c, reg & !0xff00);
+    }
+
+    static TEST_QUIRKS: [Quirk; 2] = [
+        Quirk {
+            vendor: 0xfffd,
+            device: None,
+            revisions: (2, 3),
+            flags: QuirkFlags::NO_MSIX,
+            d0_delay: Duration::from_millis(100),
+            fixup: Some(clear_interrupt_pin),
+        },
+        Quirk::flags(0xfffd, 0x0001, QuirkFlags::NO_D3),
+    ];
+
+    #[test]
+    fn match_and_apply() {
+        register_quirks(&TEST_QUIRKS).unwrap();
+        assert_eq!(lookup(0xfffd, 0x0002, 1), Quirks::default());
+        assert!(lookup(0x8086, 0x1502, 0).contains(QuirkFlags::NO_FLR));
+
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x0001_fffdu32.to_le_bytes());
+        space[0x08] = 2;
+        space[0x3d] = 1;
+        let addr = PCIAddress {
+            bus: 0,
+            dev: 1,
+            fun: 0,
+        };
+        let mut device = PciDevice::from_config(MockConfig::from_bytes(addr, &space)).unwrap();
+        let quirks = apply(&mut device);
+        assert_eq!(quirks.flags, QuirkFlags::NO_MSIX | QuirkFlags::NO_D3);
+        assert_eq!(quirks.d0_delay, Duration::from_millis(100));
+        assert_eq!(device.interrupt_pin(), 0);
+        unregister_quirks(&TEST_QUIRKS);
+    }
+}
@@ -0,0 +1,245 @@
+//! A view of a device that never writes its configuration space.
+//!
+//! Sizing a BAR temporarily overwrites it, which can break a device that is
+//! in use (e.g., one driven by the OS while a monitoring tool enumerates the
+//! system). [`ReadOnlyPciDevice`] only hands out `&PciDevice`, so none of the
+//! writing methods are reachable, marks the device read-only so BAR sizing
+//! (which works through `&self`) doesn't write either, and takes the BAR
+//! sizes from the Enhanced Allocation capability or from a source supplied
+//! by the caller (e.g., [`sysfs::resources`](crate::sysfs::resources))
+//! instead.
+
+use alloc::vec::Vec;
+use core::ops::Deref;
+
+use bit_field::BitField;
+
+use super::{
+    verbose, Bar, BarType, CapabilityId, ConfigSpace, PCIAddress, PciDevice, PciDeviceSummary,
+    PciDeviceType,
+};
+
+/// EA entry properties (PCIe Base Spec, Table 7-96).
+const EA_PROP_MEM: u8 = 0x00;
+const EA_PROP_MEM_PREFETCH: u8 = 0x01;
+const EA_PROP_IO: u8 = 0x02;
+
+/// Number of BAR equivalent indicators (BEI) that refer to BARs.
+const EA_BARS: u8 = 6;
+
+/// An entry of the Enhanced Allocation capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EaEntry {
+    /// BAR equivalent indicator (0..=5 are BARs, the rest are ROMs, VF BARs,
+    /// etc.).
+    pub bei: u8,
+    pub primary_properties: u8,
+    pub enabled: bool,
+    pub base: u64,
+    pub size: u64,
+}
+
+impl EaEntry {
+    /// The BAR this entry replaces, if it is enabled and describes one.
+    pub fn to_bar(&self) -> Option<(u8, Bar)> {
+        let (region_type, prefetchable) = match self.primary_properties {
+            EA_PROP_MEM => (BarType::Mem, false),
+            EA_PROP_MEM_PREFETCH => (BarType::Mem, true),
+            EA_PROP_IO => (BarType::IO, false),
+            _ => return None,
+        };
+        if !self.enabled || self.bei >= EA_BARS {
+            return None;
+        }
+        Some((
+            self.bei,
+            Bar {
+                region_type,
+                prefetchable,
+                address: self.base,
+                size: self.size,
+            },
+        ))
+    }
+}
+
+/// Parses the Enhanced Allocation capability of `device` (empty if it
+/// doesn't have one).
+pub fn ea_entries<A: ConfigSpace>(device: &PciDevice<A>) -> Vec<EaEntry> {
+    let mut entries = Vec::new();
+    let cap = match device.find_capability(CapabilityId::EnhancedAllocation) {
+        Some(cap) => cap,
+        None => return entries,
+    };
+
+    let config = device.config();
+    let header = config.read(cap.offset as u32);
+    let count = header.get_bits(16..22);
+    // Bridges have an extra DW with the fixed bus numbers
+    let mut offset = cap.offset as u32
+        + match device.device_type() {
+            PciDeviceType::PciBridge => 8,
+            _ => 4,
+        };
+
+    for _ in 0..count {
+        let dw0 = config.read(offset);
+        let size_dws = dw0.get_bits(0..3);
+        let read = |i: u32| {
+            if i <= size_dws {
+                config.read(offset + 4 * i)
+            } else {
+                0
+            }
+        };
+
+        let base_low = read(1);
+        let max_offset_low = read(2);
+        let mut next = 3;
+        let mut base = (base_low & !0b11) as u64;
+        if base_low.get_bit(1) {
+            base |= (read(next) as u64) << 32;
+            next += 1;
+        }
+        let mut max_offset = (max_offset_low | 0b11) as u64;
+        if max_offset_low.get_bit(1) {
+            max_offset |= (read(next) as u64) << 32;
+        }
+
+        entries.push(EaEntry {
+            bei: dw0.get_bits(4..8) as u8,
+            primary_properties: dw0.get_bits(8..16) as u8,
+            enabled: dw0.get_bit(31),
+            base,
+            size: max_offset + 1,
+        });
+        offset += 4 * (size_dws + 1);
+    }
+    entries
+}
+
+/// A device whose configuration space is only ever read.
+///
+/// Derefs to `&PciDevice`, so all reading accessors are available while the
+/// ones that write are not, and BARs are never sized.
+pub struct ReadOnlyPciDevice<A = PCIAddress> {
+    device: PciDevice<A>,
+    /// Known BARs (index, BAR), sorted by index.
+    bars: Vec<(u8, Bar)>,
+}
+
+impl<A: ConfigSpace> ReadOnlyPciDevice<A> {
+    pub fn new(mut device: PciDevice<A>) -> Self {
+        device.header.read_only = true;
+        let mut bars: Vec<(u8, Bar)> = ea_entries(&device)
+            .iter()
+            .filter_map(EaEntry::to_bar)
+            .collect();
+        bars.sort_by_key(|(index, _bar)| *index);
+        ReadOnlyPciDevice { device, bars }
+    }
+
+    /// Creates a read-only device accessed through `config`, returns None if
+    /// there is no function behind it.
+    pub fn from_config(config: A) -> Option<Self> {
+        PciDevice::from_config(config).map(ReadOnlyPciDevice::new)
+    }
+
+    /// Uses `bars` (as (BAR index, BAR)) for the BARs, e.g., as assigned by
+    /// the OS. These take precedence over the Enhanced Allocation entries.
+    pub fn with_bars(mut self, bars: &[(u8, Bar)]) -> Self {
+        for (index, bar) in bars {
+            self.bars.retain(|(i, _bar)| i != index);
+            self.bars.push((*index, *bar));
+        }
+        self.bars.sort_by_key(|(index, _bar)| *index);
+        self
+    }
+
+    /// The BARs with a known size.
+    pub fn bars(&self) -> &[(u8, Bar)] {
+        &self.bars
+    }
+
+    /// BAR `index`, if its size is known (from Enhanced Allocation or
+    /// [`ReadOnlyPciDevice::with_bars`]).
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        self.bars
+            .iter()
+            .find(|(i, _bar)| *i == index)
+            .map(|(_i, bar)| *bar)
+    }
+
+    /// A detailed description of the device for display, with the known
+    /// BARs.
+    pub fn verbose(&self) -> verbose::Verbose<'_, A> {
+        if self.bars.is_empty() {
+            self.device.verbose()
+        } else {
+            self.device.verbose().with_bars(&self.bars)
+        }
+    }
+
+    /// Like [`PciDevice::summary`] but with the known BARs only.
+    pub fn summary(&self) -> PciDeviceSummary {
+        self.device.summary_with_bars(self.bars.clone())
+    }
+
+    pub fn into_inner(mut self) -> PciDevice<A> {
+        self.device.header.read_only = false;
+        self.device
+    }
+}
+
+impl<A> Deref for ReadOnlyPciDevice<A> {
+    type Target = PciDevice<A>;
+
+    fn deref(&self) -> &PciDevice<A> {
+        &self.device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::mock::MockConfig;
+
+    #[test]
+    fn enhanced_allocation() {
+        let config = MockConfig::with_capability(
+            0x40,
+            &[
+                // EA with two entries
+                0x0002_0014,
+                // BEI 0, 32-bit memory, 4 KiB at 0xfe00_0000
+                0x8000_0002,
+                0xfe00_0000,
+                0x0000_0ffc,
+                // BEI 2, 64-bit prefetchable memory, 8 GiB at 0x40_0000_0000
+                0x8000_0124,
+                0x0000_0002,
+                0xffff_fffe,
+                0x40,
+                0x1,
+            ],
+        );
+
+        let device = ReadOnlyPciDevice::from_config(config.clone()).unwrap();
+        let bars = device.bars();
+        assert_eq!(bars.len(), 2);
+        assert_eq!(
+            (bars[0].0, bars[0].1.address, bars[0].1.size),
+            (0, 0xfe00_0000, 0x1000)
+        );
+        assert_eq!(
+            (bars[1].0, bars[1].1.address, bars[1].1.size),
+            (2, 0x40_0000_0000, 0x2_0000_0000)
+        );
+        assert!(bars[1].1.prefetchable);
+        assert!(device.bar(1).is_none());
+        // Sizing would write the BARs
+        assert!(device.iter_bars().next().is_none());
+        assert_eq!(device.config().as_bytes(), config.as_bytes());
+    }
+}
@@ -0,0 +1,289 @@
+//! Surviving link-down events and resets initiated upstream.
+//!
+//! A hot reset (or surprise link-down) issued by a switch or root port above
+//! the device wipes its configuration: BARs, the command register and the
+//! PCIe/MSI-X control bits come back with their defaults and the device stops
+//! decoding the memory its driver has mapped. [`LinkMonitor`] detects this,
+//! either through the Data Link Layer Link Active bit of the port above the
+//! device or by noticing that the configuration was lost, waits for the link
+//! to come back, restores the configuration saved in a [`SavedState`] and
+//! tells the driver (a [`LinkEventHandler`]) so it can re-initialize the
+//! device.
+
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::{ConfigSpace, PCIAddress, PciDevice, PciError};
+use crate::poll::poll_until;
+
+/// Number of header dwords saved (the type 0/1 header).
+const HEADER_DWORDS: usize = 16;
+
+/// The PCIe control registers saved by [`SavedState`].
+#[derive(Debug, Clone, Copy)]
+struct SavedPciExpress {
+    device_control: u16,
+    link_control: u16,
+    device_control_2: u16,
+    link_control_2: u16,
+}
+
+/// The configuration of a function that a reset clears.
+#[derive(Debug, Clone)]
+pub struct SavedState {
+    header: [u32; HEADER_DWORDS],
+    pcie: Option<SavedPciExpress>,
+    /// MSI-X capability offset and message control.
+    msix: Option<(u32, u16)>,
+}
+
+impl SavedState {
+    /// Saves the header and the PCIe and MSI-X control registers of
+    /// `device`.
+    pub fn save<A: ConfigSpace>(device: &mut PciDevice<A>) -> SavedState {
+        let mut header = [0; HEADER_DWORDS];
+        for (i, dword) in header.iter_mut().enumerate() {
+            *dword = device.header.read(i as u32 * 4);
+        }
+        let pcie = device.pci_express().map(|pcie| SavedPciExpress {
+            device_control: pcie.device_control(),
+            link_control: pcie.link_control(),
+            device_control_2: pcie.device_control_2(),
+            link_control_2: pcie.link_control_2(),
+        });
+        let msix = device
+            .get_msix_config()
+            .map(|msix| (msix.offset, msix.message_control()));
+        SavedState { header, pcie, msix }
+    }
+
+    /// Writes the saved configuration back to `device`.
+    ///
+    /// Like Linux, this goes from the end of the header to the start, so the
+    /// command register (which enables decoding) is written last.
+    pub fn restore<A: ConfigSpace>(&self, device: &mut PciDevice<A>) {
+        if let Some(saved) = self.pcie {
+            if let Some(mut pcie) = device.pci_express() {
+                pcie.set_device_control(saved.device_control);
+                pcie.set_link_control(saved.link_control);
+                pcie.set_device_control_2(saved.device_control_2);
+                pcie.set_link_control_2(saved.link_control_2);
+            }
+        }
+        if let Some((offset, control)) = self.msix {
+            let mut reg = device.header.read(offset);
+            reg.set_bits(16..32, control as u32);
+            device.header.write(offset, reg);
+        }
+
+        for i in (1..HEADER_DWORDS).rev() {
+            let offset = i as u32 * 4;
+            let mut value = self.header[i];
+            if offset == 0x04 {
+                // Don't clear the (RW1C) status bits
+                value.set_bits(16..32, 0);
+            } else if offset == 0x0c {
+                // Don't start BIST
+                value.set_bit(30, false);
+            }
+            if device.header.read(offset) != value {
+                device.header.write(offset, value);
+            }
+        }
+    }
+
+    /// Whether `device` lost the saved configuration, i.e., was reset: it
+    /// stopped decoding memory/IO, isn't a bus master anymore or its first
+    /// BAR moved.
+    pub fn is_lost<A: ConfigSpace>(&self, device: &PciDevice<A>) -> bool {
+        let command = device.header.read(0x04);
+        command.get_bits(0..3) != self.header[1].get_bits(0..3)
+            || device.header.read(0x10) != self.header[4]
+    }
+}
+
+/// The driver of a monitored device.
+pub trait LinkEventHandler<A = PCIAddress> {
+    /// The link went down or the device was reset: it lost its state and
+    /// everything in flight (DMA, pending requests) is gone.
+    fn link_down(&mut self);
+
+    /// The link is back up and the saved configuration was restored, the
+    /// driver has to re-initialize the device (MSI-X table, queues, ...).
+    fn link_restored(&mut self, device: &mut PciDevice<A>);
+
+    /// The device didn't come back in time.
+    fn recovery_failed(&mut self) {}
+}
+
+/// What [`LinkMonitor::poll`] found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkEvent {
+    /// Nothing happened.
+    Up,
+    /// The device was reset or its link went down, and it was recovered.
+    Recovered,
+}
+
+/// Watches the link of a device and restores its configuration after
+/// resets.
+pub struct LinkMonitor<A = PCIAddress> {
+    /// The downstream port above the device.
+    port: Option<PciDevice<A>>,
+    state: SavedState,
+    timeout: Duration,
+}
+
+impl<A: ConfigSpace> LinkMonitor<A> {
+    /// Default time to wait for the link to come back.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Monitors `device` (and the link of `port`, the downstream or root
+    /// port above it, if given) and saves its current configuration, which
+    /// is restored after resets.
+    pub fn new(device: &mut PciDevice<A>, port: Option<PciDevice<A>>) -> LinkMonitor<A> {
+        LinkMonitor {
+            port,
+            state: SavedState::save(device),
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn saved_state(&self) -> &SavedState {
+        &self.state
+    }
+
+    /// Saves the configuration of `device` again (after the driver changed
+    /// it).
+    pub fn save(&mut self, device: &mut PciDevice<A>) {
+        self.state = SavedState::save(device);
+    }
+
+    /// Whether the link is up and `device` responds to configuration
+    /// requests.
+    pub fn link_up(&mut self, device: &PciDevice<A>) -> bool {
+        link_up(&mut self.port, device)
+    }
+
+    /// Checks the link and, if the device went away or was reset, waits for
+    /// it to come back, restores its configuration and notifies `handler`.
+    ///
+    /// Call this periodically or when the device misbehaves (e.g., registers
+    /// read as all ones).
+    pub fn poll<H: LinkEventHandler<A>>(
+        &mut self,
+        device: &mut PciDevice<A>,
+        handler: &mut H,
+    ) -> Result<LinkEvent, PciError> {
+        if self.link_up(device) {
+            if !self.state.is_lost(device) {
+                return Ok(LinkEvent::Up);
+            }
+            warn!("pci: {:?} was reset", device.pci_address());
+            handler.link_down();
+        } else {
+            warn!("pci: link to {:?} is down", device.pci_address());
+            handler.link_down();
+            let port = &mut self.port;
+            let dev = &*device;
+            if !poll_until(|| link_up(port, dev), self.timeout) {
+                handler.recovery_failed();
+                return Err(PciError::LinkDown);
+            }
+        }
+
+        self.state.restore(device);
+        handler.link_restored(device);
+        info!("pci: recovered {:?}", device.pci_address());
+        Ok(LinkEvent::Recovered)
+    }
+}
+
+fn link_up<A: ConfigSpace>(port: &mut Option<PciDevice<A>>, device: &PciDevice<A>) -> bool {
+    let port_link = port
+        .as_mut()
+        .and_then(|port| port.pci_express())
+        .and_then(|pcie| pcie.link_active());
+    // Reads from a function that isn't there return all ones
+    port_link != Some(false) && device.vendor_id() != 0xffff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::PciInterface;
+    use crate::pci::mock::MockConfig;
+
+    #[derive(Default)]
+    struct Driver {
+        down: u32,
+        restored: u32,
+        failed: u32,
+    }
+
+    impl<A> LinkEventHandler<A> for Driver {
+        fn link_down(&mut self) {
+            self.down += 1;
+        }
+
+        fn link_restored(&mut self, _device: &mut PciDevice<A>) {
+            self.restored += 1;
+        }
+
+        fn recovery_failed(&mut self) {
+            self.failed += 1;
+        }
+    }
+
+    #[test]
+    fn restore_after_reset() {
+        let mut space = [0u8; 0x40];
+        space[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        let addr = PCIAddress {
+            bus: 1,
+            dev: 0,
+            fun: 0,
+        };
+        let mut config = MockConfig::from_bytes(addr, &space);
+        config.set_memory_bar(0, 0xfe00_0000, 0x1000, false);
+        let mut device = PciDevice::from_config(config).unwrap();
+        device.enable_bus_mastering();
+
+        let mut monitor = LinkMonitor::new(&mut device, None);
+        monitor.set_timeout(Duration::from_millis(5));
+        let mut driver = Driver::default();
+        assert_eq!(
+            monitor.poll(&mut device, &mut driver).unwrap(),
+            LinkEvent::Up
+        );
+
+        // Hot reset: the command register and the BARs are cleared
+        device.header.write(0x04, 0);
+        device.header.write(0x10, 0);
+        assert_eq!(
+            monitor.poll(&mut device, &mut driver).unwrap(),
+            LinkEvent::Recovered
+        );
+        assert!(device.is_bus_master());
+        assert_eq!(device.config().read(0x10), 0xfe00_0000);
+        assert_eq!((driver.down, driver.restored), (1, 1));
+
+        // The function doesn't come back
+        device.header.write(0x00, u32::MAX);
+        assert!(matches!(
+            monitor.poll(&mut device, &mut driver),
+            Err(PciError::LinkDown)
+        ));
+        assert_eq!((driver.down, driver.failed), (2, 1));
+    }
+}
@@ -0,0 +1,127 @@
+//! Detailed, human readable description of a device (similar to `lspci -v`).
+
+use core::fmt;
+
+use bit_field::BitField;
+
+use super::{Bar, BarType, ConfigSpace, PciDevice};
+
+/// Formats a device with its class, command/status, BARs and capabilities.
+///
+/// Created by [`PciDevice::verbose`]. Formatting only reads from the
+/// configuration space: BARs are decoded from their registers without sizing
+/// them, unless the BARs are supplied with [`Verbose::with_bars`] (e.g., from
+/// the OS).
+pub struct Verbose<'a, A> {
+    device: &'a PciDevice<A>,
+    bars: Option<&'a [(u8, Bar)]>,
+}
+
+impl<'a, A: ConfigSpace> Verbose<'a, A> {
+    pub(super) fn new(device: &'a PciDevice<A>) -> Self {
+        Verbose { device, bars: None }
+    }
+
+    /// Use `bars` (as (BAR index, BAR)) instead of decoding the BAR registers.
+    pub fn with_bars(mut self, bars: &'a [(u8, Bar)]) -> Self {
+        self.bars = Some(bars);
+        self
+    }
+
+    fn fmt_bar(f: &mut fmt::Formatter, index: u8, bar: &Bar, is_64bit: bool) -> fmt::Result {
+        match bar.region_type {
+            BarType::IO => write!(f, "\tRegion {}: I/O ports at {:#x}", index, bar.address)?,
+            BarType::Mem => write!(
+                f,
+                "\tRegion {}: Memory at {:#x} ({}-bit, {}prefetchable)",
+                index,
+                bar.address,
+                if is_64bit { 64 } else { 32 },
+                if bar.prefetchable { "" } else { "non-" }
+            )?,
+        }
+        if bar.size > 0 {
+            write!(f, " [size={:#x}]", bar.size)?;
+        }
+        writeln!(f)
+    }
+
+    fn fmt_bar_registers(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bars = self.device.device_type().bar_count();
+
+        let mut index = 0;
+        while index < bars {
+            let raw = self.device.bar_raw(index);
+            let is_io = raw.get_bit(0);
+            let is_64bit = !is_io && raw.get_bits(1..3) == 2;
+
+            let address = if is_io {
+                (raw & !0b11) as u64
+            } else if is_64bit && index + 1 < bars {
+                (raw & !0xF) as u64 | (self.device.bar_raw(index + 1) as u64) << 32
+            } else {
+                (raw & !0xF) as u64
+            };
+
+            if address != 0 {
+                let bar = Bar {
+                    region_type: BarType::from(is_io),
+                    prefetchable: !is_io && raw.get_bit(3),
+                    address,
+                    size: 0,
+                };
+                Verbose::<A>::fmt_bar(f, index, &bar, is_64bit)?;
+            }
+            index += if is_64bit { 2 } else { 1 };
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, A: ConfigSpace> fmt::Display for Verbose<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let dev = self.device;
+        let (revision, base_class, sub_class, interface) = dev.revision_and_class();
+
+        writeln!(f, "{}", dev)?;
+        writeln!(
+            f,
+            "\tClass: [{:02x}{:02x}] {:?} (rev {:02x}, prog-if {:02x})",
+            base_class,
+            sub_class,
+            dev.device_class(),
+            revision,
+            interface
+        )?;
+        writeln!(
+            f,
+            "\tID: [{:04x}:{:04x}] {:?}",
+            dev.vendor_id(),
+            dev.device_id(),
+            dev.device_type()
+        )?;
+        writeln!(
+            f,
+            "\tControl: BusMaster{}",
+            if dev.is_bus_master() { "+" } else { "-" }
+        )?;
+        writeln!(f, "\tStatus: {:#06x}", dev.status())?;
+
+        match self.bars {
+            Some(bars) => {
+                for (index, bar) in bars {
+                    let is_64bit = bar.address > u32::MAX as u64;
+                    Verbose::<A>::fmt_bar(f, *index, bar, is_64bit)?;
+                }
+            }
+            None => self.fmt_bar_registers(f)?,
+        }
+
+        for cap in dev.capabilities() {
+            writeln!(f, "\tCapabilities: [{:02x}] {:?}", cap.offset, cap.id)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+//! Runtime power management.
+//!
+//! An optional layer on top of [`DriverControl::set_sleep_level`] that puts
+//! an attached device to sleep after it has been idle for a while and wakes it
+//! up again transparently on the next access.
+//!
+//! Time is supplied by the caller as a monotonic tick count (whatever unit the
+//! host uses for its clock) so this works the same in `no_std` kernels and in
+//! Linux user-space. Waiting for a device to come back to D0 takes a
+//! [`Clock`].
+
+use core::time::Duration;
+
+use crate::clock::Clock;
+use crate::pci::{quirks, ConfigSpace, PCIAddress, PciDevice, PowerState, QuirkFlags, SavedState};
+use crate::{DriverControl, DriverState};
+
+/// Sleep level used by [`DriverControl::set_sleep_level`] for a running device.
+pub const SLEEP_LEVEL_ACTIVE: usize = 0;
+
+/// Sleep level used by [`DriverControl::set_sleep_level`] for a device in D3hot.
+pub const SLEEP_LEVEL_D3HOT: usize = 3;
+
+/// Time a function needs to recover when going from `state` back to D0.
+fn recovery_time(state: PowerState) -> Duration {
+    match state {
+        PowerState::D0 | PowerState::D1 => Duration::ZERO,
+        PowerState::D2 => Duration::from_micros(200),
+        PowerState::D3Hot => Duration::from_millis(10),
+    }
+}
+
+/// Hooks a driver implements to take part in runtime power management.
+pub trait RuntimePower<A = PCIAddress>: DriverControl {
+    /// Quiesce the device before it is put to sleep.
+    ///
+    /// Returning false vetoes the suspend (e.g., because there is still
+    /// outstanding I/O), the idle timer is re-armed in that case.
+    fn runtime_suspend(&mut self) -> bool {
+        true
+    }
+
+    /// Restore device state after it was woken up from sleep.
+    fn runtime_resume(&mut self) {}
+
+    /// The PCI function whose PM capability should follow the driver's sleep
+    /// level. Drivers without one only get their sleep level updated.
+    fn pm_device(&mut self) -> Option<&mut PciDevice<A>> {
+        None
+    }
+}
+
+/// Per-device idle timer driving runtime suspend/resume.
+#[derive(Debug)]
+pub struct IdleTimer {
+    /// Ticks of inactivity before the device is suspended.
+    idle_timeout: u64,
+    /// Time of the last access to the device.
+    last_busy: u64,
+    /// Number of accesses currently in progress.
+    usage: usize,
+    /// Whether automatic suspend is allowed at all.
+    enabled: bool,
+    /// The configuration of the suspended device.
+    saved: Option<SavedState>,
+}
+
+impl IdleTimer {
+    pub fn new(idle_timeout: u64, now: u64) -> IdleTimer {
+        IdleTimer {
+            idle_timeout,
+            last_busy: now,
+            usage: 0,
+            enabled: true,
+            saved: None,
+        }
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: u64) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Allow or forbid automatic suspend (e.g., while a reset is in progress).
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Records activity at time `now`, pushing back the next suspend.
+    pub fn mark_busy(&mut self, now: u64) {
+        self.last_busy = now;
+    }
+
+    /// Returns true if the device has been idle for at least the timeout.
+    pub fn expired(&self, now: u64) -> bool {
+        self.enabled && self.usage == 0 && now.saturating_sub(self.last_busy) >= self.idle_timeout
+    }
+
+    /// Runs `f` with the device awake, resuming it first if necessary.
+    pub fn access<A, D, C, F, R>(&mut self, drv: &mut D, now: u64, clock: &C, f: F) -> R
+    where
+        A: ConfigSpace,
+        D: RuntimePower<A>,
+        C: Clock + ?Sized,
+        F: FnOnce(&mut D) -> R,
+    {
+        if is_suspended(drv) {
+            resume(drv, &mut self.saved, clock);
+        }
+
+        self.usage += 1;
+        let ret = f(drv);
+        self.usage -= 1;
+        self.mark_busy(now);
+
+        ret
+    }
+
+    /// Checks the idle timer and suspends the device if it expired.
+    ///
+    /// Should be called periodically by the host. Returns true if the device
+    /// was put to sleep by this call.
+    pub fn poll<A: ConfigSpace, D: RuntimePower<A>>(&mut self, drv: &mut D, now: u64) -> bool {
+        if is_suspended(drv) || !matches!(drv.state(), DriverState::Attached(_)) {
+            return false;
+        }
+        if !self.expired(now) {
+            return false;
+        }
+
+        if suspend(drv, &mut self.saved) {
+            true
+        } else {
+            self.mark_busy(now);
+            false
+        }
+    }
+}
+
+fn is_suspended<D: DriverControl>(drv: &D) -> bool {
+    drv.state() == DriverState::Attached(SLEEP_LEVEL_D3HOT)
+}
+
+/// Puts the device into D3hot, returns false if the driver vetoed.
+///
+/// The configuration of the function is saved to `saved` for [`resume`].
+/// Functions with the [`QuirkFlags::NO_D3`] quirk stay in D0.
+pub fn suspend<A: ConfigSpace, D: RuntimePower<A>>(
+    drv: &mut D,
+    saved: &mut Option<SavedState>,
+) -> bool {
+    if !drv.runtime_suspend() {
+        return false;
+    }
+
+    if let Some(dev) = drv.pm_device() {
+        let quirks = quirks::lookup(dev.vendor_id(), dev.device_id(), dev.revision_id());
+        if !quirks.contains(QuirkFlags::NO_D3) {
+            *saved = Some(SavedState::save(dev));
+            if let Some(mut pm) = dev.power_management() {
+                pm.set_power_state(PowerState::D3Hot);
+            }
+        }
+    }
+    drv.set_sleep_level(SLEEP_LEVEL_D3HOT);
+
+    true
+}
+
+/// Brings the device back to D0 and lets the driver restore its state.
+///
+/// Waits the recovery time of the transition (10 ms from D3hot, plus the
+/// D0 delay of the device's quirks) on `clock` before the device is touched
+/// again, and restores the configuration in `saved` unless the function
+/// keeps it in D3hot (No_Soft_Reset).
+pub fn resume<A, D, C>(drv: &mut D, saved: &mut Option<SavedState>, clock: &C)
+where
+    A: ConfigSpace,
+    D: RuntimePower<A>,
+    C: Clock + ?Sized,
+{
+    let saved = saved.take();
+    if let Some(dev) = drv.pm_device() {
+        let woken = dev.power_management().and_then(|mut pm| {
+            let state = pm.power_state();
+            if state == PowerState::D0 {
+                return None;
+            }
+            let keeps_config = pm.no_soft_reset();
+            pm.set_power_state(PowerState::D0);
+            Some((state, keeps_config))
+        });
+
+        if let Some((state, keeps_config)) = woken {
+            let quirks = quirks::lookup(dev.vendor_id(), dev.device_id(), dev.revision_id());
+            clock.delay(recovery_time(state) + quirks.d0_delay);
+            if let (false, Some(saved)) = (keeps_config, saved) {
+                saved.restore(dev);
+            }
+        }
+    }
+    drv.set_sleep_level(SLEEP_LEVEL_ACTIVE);
+    drv.runtime_resume();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::mock::MockConfig;
+    use core::cell::Cell;
+
+    struct Dummy {
+        state: DriverState,
+        resumed: usize,
+        device: Option<PciDevice<MockConfig>>,
+    }
+
+    impl DriverControl for Dummy {
+        fn state(&self) -> DriverState {
+            self.state
+        }
+
+        fn set_state(&mut self, ds: DriverState) {
+            self.state = ds;
+        }
+    }
+
+    impl RuntimePower<MockConfig> for Dummy {
+        fn runtime_resume(&mut self) {
+            self.resumed += 1;
+        }
+
+        fn pm_device(&mut self) -> Option<&mut PciDevice<MockConfig>> {
+            self.device.as_mut()
+        }
+    }
+
+    /// Adds up the delays instead of waiting.
+    struct FakeClock(Cell<Duration>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+
+        fn delay(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    #[test]
+    fn suspend_after_idle_and_resume_on_access() {
+        let mut drv = Dummy {
+            state: DriverState::Attached(SLEEP_LEVEL_ACTIVE),
+            resumed: 0,
+            device: None,
+        };
+        let clock = FakeClock(Cell::new(Duration::ZERO));
+        let mut timer = IdleTimer::new(10, 0);
+
+        assert!(!timer.poll(&mut drv, 5));
+        assert!(timer.poll(&mut drv, 10));
+        assert_eq!(drv.state(), DriverState::Attached(SLEEP_LEVEL_D3HOT));
+
+        timer.access(&mut drv, 12, &clock, |d| {
+            assert_eq!(d.state(), DriverState::Attached(SLEEP_LEVEL_ACTIVE))
+        });
+        assert_eq!(drv.resumed, 1);
+        assert_eq!(clock.now(), Duration::ZERO);
+        assert!(!timer.poll(&mut drv, 21));
+        assert!(timer.poll(&mut drv, 22));
+    }
+
+    #[test]
+    fn resume_from_d3hot() {
+        // PM capability version 3
+        let mut config = MockConfig::with_capability(0x50, &[0x0003_0001]);
+        // Memory decoding and bus mastering enabled
+        config.write32(0x04, 0x0010_0006);
+        let mut drv = Dummy {
+            state: DriverState::Attached(SLEEP_LEVEL_ACTIVE),
+            resumed: 0,
+            device: PciDevice::from_config(config),
+        };
+        let clock = FakeClock(Cell::new(Duration::ZERO));
+        let mut saved = None;
+
+        assert!(suspend(&mut drv, &mut saved));
+        let device = drv.device.as_mut().unwrap();
+        assert_eq!(
+            device.power_management().unwrap().power_state(),
+            PowerState::D3Hot
+        );
+        // The function loses its configuration in D3hot
+        device.config_mut().write32(0x04, 0x0010_0000);
+
+        resume(&mut drv, &mut saved, &clock);
+        let device = drv.device.as_mut().unwrap();
+        assert_eq!(device.command(), 0x06);
+        // Restoring writes zeroes to the (RW1C) status, which the mock takes
+        // literally, so check PMCSR directly
+        assert_eq!(
+            device.config_mut().read32(0x54) & 0b11,
+            PowerState::D0 as u32
+        );
+        assert_eq!(clock.now(), Duration::from_millis(10));
+        assert_eq!(drv.resumed, 1);
+        assert!(saved.is_none());
+    }
+}
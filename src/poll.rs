@@ -0,0 +1,27 @@
+//! Polling with a timeout, with or without a clock.
+
+use core::time::Duration;
+
+/// Polls `cond` until it holds or `timeout` passed, returns whether it holds.
+#[cfg(unix)]
+pub(crate) fn poll_until<F: FnMut() -> bool>(cond: F, timeout: Duration) -> bool {
+    let cond = core::cell::RefCell::new(cond);
+    crate::timedops::wait_until(|| (cond.borrow_mut())(), timeout).is_ok()
+}
+
+/// Polls `cond` until it holds or `timeout` passed, returns whether it holds.
+///
+/// Without a clock this polls (roughly) once per microsecond.
+#[cfg(not(unix))]
+pub(crate) fn poll_until<F: FnMut() -> bool>(mut cond: F, timeout: Duration) -> bool {
+    const SPINS_PER_US: u32 = 1000;
+    for _ in 0..timeout.as_micros() {
+        if cond() {
+            return true;
+        }
+        for _ in 0..SPINS_PER_US {
+            core::hint::spin_loop();
+        }
+    }
+    cond()
+}
@@ -1,7 +1,12 @@
+use std::convert::TryFrom;
+use std::fmt;
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
+
+pub use crate::clock::Clock;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WaitError {
     Timeout,
 }
@@ -30,3 +35,232 @@ where
 
     Ok(())
 }
+
+/// The monotonic clock of the OS.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+}
+
+/// Like [`wait_until`], but measures the timeout with `clock` (e.g., the
+/// HPET on machines without a reliable TSC) and spins instead of sleeping.
+pub fn wait_until_with<C, F>(clock: &C, cond_fn: F, max_wait: Duration) -> Result<(), WaitError>
+where
+    C: Clock + ?Sized,
+    F: Fn() -> bool,
+{
+    let start = clock.now();
+
+    while !cond_fn() {
+        if clock.now().saturating_sub(start) > max_wait {
+            return Err(WaitError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Number of linear sub-buckets per power of two (2^SUB_BUCKET_BITS).
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Values below SUB_BUCKETS are exact, above that every power of two is split
+/// into SUB_BUCKETS buckets.
+const LATENCY_BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// A latency histogram with a bounded relative error (HDR-style).
+///
+/// Values are kept in log-linear buckets: every power of two is divided into
+/// 16 equally sized buckets, so the recorded value is accurate to within ~6%
+/// over the whole range from nanoseconds to centuries, using a fixed amount
+/// of memory and no allocations on the recording path.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKETS],
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub const fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: [0; LATENCY_BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    fn index(nanos: u64) -> usize {
+        if nanos < SUB_BUCKETS as u64 {
+            return nanos as usize;
+        }
+        let msb = 63 - nanos.leading_zeros();
+        let sub = (nanos >> (msb - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+        (msb - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub
+    }
+
+    /// The smallest value that is counted in bucket `index`.
+    fn lower_bound(index: usize) -> u64 {
+        if index < SUB_BUCKETS {
+            return index as u64;
+        }
+        let msb = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+        let sub = (index % SUB_BUCKETS) as u64;
+        (1 << msb) | (sub << (msb - SUB_BUCKET_BITS))
+    }
+
+    /// Records a latency of `nanos` nanoseconds.
+    #[inline]
+    pub fn record_nanos(&mut self, nanos: u64) {
+        self.counts[LatencyHistogram::index(nanos)] += 1;
+        self.count += 1;
+        self.sum += nanos as u128;
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    #[inline]
+    pub fn record(&mut self, latency: Duration) {
+        self.record_nanos(u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX));
+    }
+
+    /// Number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.min))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.max))
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos((self.sum / self.count as u128) as u64))
+    }
+
+    /// Returns the latency below which a fraction `q` (0.0 ..= 1.0) of the
+    /// recorded latencies fall, e.g. `percentile(0.99)` for the p99.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let value = LatencyHistogram::lower_bound(index)
+                    .max(self.min)
+                    .min(self.max);
+                return Some(Duration::from_nanos(value));
+            }
+        }
+
+        self.max()
+    }
+
+    /// Adds all latencies recorded in `other` to this histogram.
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c += o;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn reset(&mut self) {
+        *self = LatencyHistogram::new();
+    }
+}
+
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("count", &self.count)
+            .field("min", &self.min())
+            .field("p50", &self.percentile(0.5))
+            .field("p99", &self.percentile(0.99))
+            .field("max", &self.max())
+            .finish()
+    }
+}
+
+/// Evaluates an expression and records how long it took in a
+/// [`LatencyHistogram`](crate::timedops::LatencyHistogram).
+///
+/// ```ignore
+/// let mut doorbell = LatencyHistogram::new();
+/// let status = measure!(doorbell, queue.flush());
+/// ```
+#[macro_export]
+macro_rules! measure {
+    ($histogram:expr, $op:expr) => {{
+        let start = std::time::Instant::now();
+        let ret = $op;
+        $histogram.record(start.elapsed());
+        ret
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_histogram_percentiles() {
+        let mut h = LatencyHistogram::new();
+        for nanos in 1..=1000 {
+            h.record_nanos(nanos);
+        }
+
+        assert_eq!(h.count(), 1000);
+        assert_eq!(h.min(), Some(Duration::from_nanos(1)));
+        assert_eq!(h.max(), Some(Duration::from_nanos(1000)));
+
+        let p50 = h.percentile(0.5).unwrap().as_nanos() as f64;
+        assert!((p50 - 500.0).abs() / 500.0 < 0.07, "p50 was {}", p50);
+        let p99 = h.percentile(0.99).unwrap().as_nanos() as f64;
+        assert!((p99 - 990.0).abs() / 990.0 < 0.07, "p99 was {}", p99);
+
+        for index in 0..LATENCY_BUCKETS {
+            let lower = LatencyHistogram::lower_bound(index);
+            assert_eq!(LatencyHistogram::index(lower), index);
+        }
+    }
+}
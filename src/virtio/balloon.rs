@@ -0,0 +1,301 @@
+//! virtio-balloon memory balloon devices (5.5 of the specification).
+//!
+//! The host asks for a balloon size (`num_pages`) in the device
+//! configuration. The driver inflates the balloon by taking pages from the
+//! guest's allocator and sending their frame numbers on the inflate queue,
+//! and deflates it by sending frame numbers on the deflate queue and handing
+//! the pages back. The OS provides its allocator through [`BalloonHost`] and
+//! the virtqueues through [`BalloonQueue`].
+
+use alloc::vec::Vec;
+
+use crate::devq::DevQueueError;
+use crate::PAddr;
+
+use super::features::{BalloonFeatures, VirtioFeatures};
+use super::VirtioError;
+
+/// Balloon pages are always 4 KiB, independent of the guest's page size.
+pub const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+pub const VIRTIO_BALLOON_PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+/// Frame numbers sent in one inflate or deflate buffer.
+pub const VIRTIO_BALLOON_ARRAY_PFNS_MAX: usize = 256;
+
+/// Size of an entry of the stats buffer (`struct virtio_balloon_stat`).
+const STAT_LEN: usize = 10;
+
+/// The queues of a balloon device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BalloonVq {
+    Inflate,
+    Deflate,
+    /// Only with [`BalloonFeatures::STATS_VQ`].
+    Stats,
+}
+
+/// Tags of the memory statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StatTag {
+    /// Bytes swapped in.
+    SwapIn = 0,
+    /// Bytes swapped out.
+    SwapOut = 1,
+    MajorFaults = 2,
+    MinorFaults = 3,
+    /// Unused memory in bytes.
+    FreeMemory = 4,
+    /// Total memory in bytes.
+    TotalMemory = 5,
+    /// Memory that could be made available without swapping, in bytes.
+    AvailableMemory = 6,
+    /// Disk caches in bytes.
+    Caches = 7,
+    HugetlbAllocations = 8,
+    HugetlbFailures = 9,
+}
+
+/// A memory statistic reported to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BalloonStat {
+    pub tag: StatTag,
+    pub value: u64,
+}
+
+/// The OS side of the balloon.
+pub trait BalloonHost {
+    /// Takes a 4 KiB page from the guest to donate it to the host, None if
+    /// the guest can't spare one.
+    fn donate_page(&mut self) -> Option<PAddr>;
+
+    /// Returns a page the host gave back to the guest's allocator.
+    fn reclaim_page(&mut self, page: PAddr);
+
+    /// Appends the current memory statistics to `stats`.
+    fn memory_stats(&mut self, _stats: &mut Vec<BalloonStat>) {}
+}
+
+/// The virtqueues of a balloon device.
+pub trait BalloonQueue {
+    /// Sends the device-readable `data` on `queue`. For the inflate and
+    /// deflate queues this waits for the device to process the buffer, for
+    /// the stats queue it only posts it (the device returns it when it wants
+    /// new statistics).
+    fn send(&mut self, queue: BalloonVq, data: &[u8]) -> Result<(), DevQueueError>;
+}
+
+/// A memory balloon.
+#[derive(Debug)]
+pub struct VirtioBalloon<Q, H> {
+    queue: Q,
+    host: H,
+    features: VirtioFeatures<BalloonFeatures>,
+    /// Pages currently given to the host.
+    pages: Vec<PAddr>,
+}
+
+impl<Q: BalloonQueue, H: BalloonHost> VirtioBalloon<Q, H> {
+    /// `features` must be the negotiated features.
+    pub fn new(queue: Q, host: H, features: VirtioFeatures<BalloonFeatures>) -> Self {
+        VirtioBalloon {
+            queue,
+            host,
+            features,
+            pages: Vec::new(),
+        }
+    }
+
+    /// Size of the balloon in pages, the value of `actual` in the device
+    /// configuration.
+    pub fn actual(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    /// Inflates or deflates the balloon towards `num_pages` (from the device
+    /// configuration, e.g., after a configuration change interrupt).
+    ///
+    /// # Returns
+    /// The new size of the balloon, which the driver writes to `actual`. It
+    /// falls short of `num_pages` if the guest can't spare more pages.
+    pub fn update(&mut self, num_pages: u32) -> Result<u32, VirtioError> {
+        let target = num_pages as usize;
+        if target > self.pages.len() {
+            self.inflate(target - self.pages.len())?;
+        } else if target < self.pages.len() {
+            self.deflate(self.pages.len() - target)?;
+        }
+        Ok(self.actual())
+    }
+
+    fn inflate(&mut self, count: usize) -> Result<(), VirtioError> {
+        let mut remaining = count;
+        while remaining > 0 {
+            let mut batch = Vec::new();
+            while batch.len() < remaining.min(VIRTIO_BALLOON_ARRAY_PFNS_MAX) {
+                match self.host.donate_page() {
+                    Some(page) => batch.push(page),
+                    None => break,
+                }
+            }
+            if batch.is_empty() {
+                debug!("virtio-balloon: guest can't spare more pages");
+                break;
+            }
+            if let Err(e) = self.queue.send(BalloonVq::Inflate, &pfns(&batch)) {
+                for page in batch {
+                    self.host.reclaim_page(page);
+                }
+                return Err(VirtioError::Queue { error: e });
+            }
+            remaining -= batch.len();
+            let exhausted = remaining > 0 && batch.len() < VIRTIO_BALLOON_ARRAY_PFNS_MAX;
+            self.pages.extend(batch);
+            if exhausted {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes `count` pages out of the balloon and returns them to the guest.
+    ///
+    /// The host is always told first, so this works with and without
+    /// [`BalloonFeatures::MUST_TELL_HOST`].
+    fn deflate(&mut self, count: usize) -> Result<usize, VirtioError> {
+        let mut deflated = 0;
+        while deflated < count && !self.pages.is_empty() {
+            let len = (count - deflated)
+                .min(VIRTIO_BALLOON_ARRAY_PFNS_MAX)
+                .min(self.pages.len());
+            let start = self.pages.len() - len;
+            self.queue
+                .send(BalloonVq::Deflate, &pfns(&self.pages[start..]))
+                .map_err(|e| VirtioError::Queue { error: e })?;
+            for page in self.pages.drain(start..) {
+                self.host.reclaim_page(page);
+            }
+            deflated += len;
+        }
+        Ok(deflated)
+    }
+
+    /// Gives up to `count` pages back to the guest when it runs out of
+    /// memory (only with [`BalloonFeatures::DEFLATE_ON_OOM`]).
+    ///
+    /// # Returns
+    /// The number of pages reclaimed.
+    pub fn deflate_on_oom(&mut self, count: usize) -> Result<usize, VirtioError> {
+        if !self.features.has(BalloonFeatures::DEFLATE_ON_OOM) {
+            return Err(VirtioError::Unsupported);
+        }
+        self.deflate(count)
+    }
+
+    /// Sends the guest's memory statistics, initially and whenever the device
+    /// returned the previous stats buffer (only with
+    /// [`BalloonFeatures::STATS_VQ`]).
+    pub fn send_stats(&mut self) -> Result<(), VirtioError> {
+        if !self.features.has(BalloonFeatures::STATS_VQ) {
+            return Err(VirtioError::Unsupported);
+        }
+        let mut stats = Vec::new();
+        self.host.memory_stats(&mut stats);
+        let mut data = Vec::with_capacity(stats.len() * STAT_LEN);
+        for stat in stats {
+            data.extend_from_slice(&(stat.tag as u16).to_le_bytes());
+            data.extend_from_slice(&stat.value.to_le_bytes());
+        }
+        self.queue
+            .send(BalloonVq::Stats, &data)
+            .map_err(|e| VirtioError::Queue { error: e })
+    }
+
+    pub fn host(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    pub fn into_inner(self) -> (Q, H) {
+        (self.queue, self.host)
+    }
+}
+
+/// The frame number array of `pages`.
+fn pfns(pages: &[PAddr]) -> Vec<u8> {
+    pages
+        .iter()
+        .flat_map(|page| ((page.as_u64() >> VIRTIO_BALLOON_PFN_SHIFT) as u32).to_le_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::features::CommonFeatures;
+
+    struct Recorder(Vec<(BalloonVq, Vec<u8>)>);
+
+    impl BalloonQueue for Recorder {
+        fn send(&mut self, queue: BalloonVq, data: &[u8]) -> Result<(), DevQueueError> {
+            self.0.push((queue, data.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// A guest with `free` pages to spare.
+    struct Guest {
+        free: Vec<PAddr>,
+    }
+
+    impl BalloonHost for Guest {
+        fn donate_page(&mut self) -> Option<PAddr> {
+            self.free.pop()
+        }
+
+        fn reclaim_page(&mut self, page: PAddr) {
+            self.free.push(page);
+        }
+
+        fn memory_stats(&mut self, stats: &mut Vec<BalloonStat>) {
+            stats.push(BalloonStat {
+                tag: StatTag::FreeMemory,
+                value: self.free.len() as u64 * VIRTIO_BALLOON_PAGE_SIZE,
+            });
+        }
+    }
+
+    #[test]
+    fn inflate_deflate_and_stats() {
+        let free = (1..=300u64)
+            .map(|pfn| PAddr::from(pfn << VIRTIO_BALLOON_PFN_SHIFT))
+            .collect();
+        let features = VirtioFeatures::new(BalloonFeatures::STATS_VQ, CommonFeatures::VERSION_1);
+        let mut balloon = VirtioBalloon::new(Recorder(Vec::new()), Guest { free }, features);
+
+        assert_eq!(balloon.update(400).unwrap(), 300);
+        assert_eq!(balloon.update(10).unwrap(), 10);
+        assert_eq!(balloon.host().free.len(), 290);
+        assert!(matches!(
+            balloon.deflate_on_oom(1),
+            Err(VirtioError::Unsupported)
+        ));
+        balloon.send_stats().unwrap();
+
+        let (queue, _) = balloon.into_inner();
+        let sent: Vec<(BalloonVq, usize)> =
+            queue.0.iter().map(|(vq, data)| (*vq, data.len())).collect();
+        assert_eq!(
+            sent,
+            [
+                (BalloonVq::Inflate, 256 * 4),
+                (BalloonVq::Inflate, 44 * 4),
+                (BalloonVq::Deflate, 256 * 4),
+                (BalloonVq::Deflate, 34 * 4),
+                (BalloonVq::Stats, STAT_LEN),
+            ]
+        );
+        assert_eq!(queue.0[0].1[0..4], 300u32.to_le_bytes());
+        assert_eq!(queue.0[4].1, [4, 0, 0x00, 0x20, 0x12, 0, 0, 0, 0, 0]);
+    }
+}
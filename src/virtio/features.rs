@@ -0,0 +1,300 @@
+//! Feature bits and feature negotiation (section 2.2 and 6 of the
+//! specification).
+//!
+//! Bits 0..24 and 41.. are specific to the device type, bits 24..41 are
+//! reserved for the transport and ring layout ([`CommonFeatures`]). The
+//! device-specific bits are modeled by one bitflags type per device type,
+//! [`VirtioFeatures`] combines them with the common bits.
+
+use core::fmt;
+use core::marker::PhantomData;
+
+use bitflags::bitflags;
+
+use super::VirtioError;
+
+/// Mask of the feature bits reserved for the transport and ring layout.
+const COMMON_FEATURES_MASK: u64 = ((1 << 41) - 1) & !((1 << 24) - 1);
+
+bitflags! {
+    /// Feature bits that are independent of the device type.
+    pub struct CommonFeatures: u64 {
+        const INDIRECT_DESC = 1 << 28;
+        const EVENT_IDX = 1 << 29;
+        /// Compliance with version 1 of the specification (modern device).
+        const VERSION_1 = 1 << 32;
+        const ACCESS_PLATFORM = 1 << 33;
+        const RING_PACKED = 1 << 34;
+        const IN_ORDER = 1 << 35;
+        const ORDER_PLATFORM = 1 << 36;
+        const SR_IOV = 1 << 37;
+        const NOTIFICATION_DATA = 1 << 38;
+    }
+}
+
+bitflags! {
+    /// Feature bits of network devices (5.1.3).
+    pub struct NetFeatures: u64 {
+        const CSUM = 1 << 0;
+        const GUEST_CSUM = 1 << 1;
+        const CTRL_GUEST_OFFLOADS = 1 << 2;
+        const MTU = 1 << 3;
+        const MAC = 1 << 5;
+        const GUEST_TSO4 = 1 << 7;
+        const GUEST_TSO6 = 1 << 8;
+        const GUEST_ECN = 1 << 9;
+        const GUEST_UFO = 1 << 10;
+        const HOST_TSO4 = 1 << 11;
+        const HOST_TSO6 = 1 << 12;
+        const HOST_ECN = 1 << 13;
+        const HOST_UFO = 1 << 14;
+        const MRG_RXBUF = 1 << 15;
+        const STATUS = 1 << 16;
+        const CTRL_VQ = 1 << 17;
+        const CTRL_RX = 1 << 18;
+        const CTRL_VLAN = 1 << 19;
+        const GUEST_ANNOUNCE = 1 << 21;
+        const MQ = 1 << 22;
+        const CTRL_MAC_ADDR = 1 << 23;
+        const RSC_EXT = 1 << 61;
+        const STANDBY = 1 << 62;
+    }
+}
+
+bitflags! {
+    /// Feature bits of block devices (5.2.3).
+    pub struct BlkFeatures: u64 {
+        const SIZE_MAX = 1 << 1;
+        const SEG_MAX = 1 << 2;
+        const GEOMETRY = 1 << 4;
+        const RO = 1 << 5;
+        const BLK_SIZE = 1 << 6;
+        const FLUSH = 1 << 9;
+        const TOPOLOGY = 1 << 10;
+        const CONFIG_WCE = 1 << 11;
+        const MQ = 1 << 12;
+        const DISCARD = 1 << 13;
+        const WRITE_ZEROES = 1 << 14;
+    }
+}
+
+bitflags! {
+    /// Feature bits of SCSI host devices (5.6.3).
+    pub struct ScsiFeatures: u64 {
+        /// Requests can have both device-readable and device-writable data.
+        const INOUT = 1 << 0;
+        const HOTPLUG = 1 << 1;
+        const CHANGE = 1 << 2;
+        const T10_PI = 1 << 3;
+    }
+}
+
+bitflags! {
+    /// Feature bits of memory balloon devices (5.5.3).
+    pub struct BalloonFeatures: u64 {
+        /// The host must be told before deflated pages are used.
+        const MUST_TELL_HOST = 1 << 0;
+        const STATS_VQ = 1 << 1;
+        /// The guest may deflate the balloon when it runs out of memory.
+        const DEFLATE_ON_OOM = 1 << 2;
+        const FREE_PAGE_HINT = 1 << 3;
+        const PAGE_POISON = 1 << 4;
+        const PAGE_REPORTING = 1 << 5;
+    }
+}
+
+bitflags! {
+    /// Feature bits of 9P transport devices.
+    pub struct P9Features: u64 {
+        /// The configuration holds a mount tag.
+        const MOUNT_TAG = 1 << 0;
+    }
+}
+
+bitflags! {
+    /// Feature bits of file system devices (5.11.3).
+    pub struct FsFeatures: u64 {
+        const NOTIFICATION = 1 << 0;
+    }
+}
+
+/// The device-specific feature bits of a device type.
+pub trait DeviceFeatures: Copy + fmt::Debug {
+    fn bits(&self) -> u64;
+    fn from_bits_truncate(bits: u64) -> Self;
+}
+
+macro_rules! device_features {
+    ($($t:ty),*) => {
+        $(
+            impl DeviceFeatures for $t {
+                fn bits(&self) -> u64 {
+                    <$t>::bits(self)
+                }
+
+                fn from_bits_truncate(bits: u64) -> Self {
+                    <$t>::from_bits_truncate(bits)
+                }
+            }
+        )*
+    };
+}
+
+device_features!(
+    NetFeatures,
+    BlkFeatures,
+    ScsiFeatures,
+    BalloonFeatures,
+    P9Features,
+    FsFeatures
+);
+
+/// A set of feature bits of a device with device-specific features `D`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VirtioFeatures<D> {
+    bits: u64,
+    _device: PhantomData<D>,
+}
+
+impl<D: DeviceFeatures> VirtioFeatures<D> {
+    pub fn new(device: D, common: CommonFeatures) -> Self {
+        Self::from_bits(device.bits() | common.bits())
+    }
+
+    pub fn empty() -> Self {
+        Self::from_bits(0)
+    }
+
+    /// Keeps all bits, including ones unknown to `D` and [`CommonFeatures`].
+    pub fn from_bits(bits: u64) -> Self {
+        VirtioFeatures {
+            bits,
+            _device: PhantomData,
+        }
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    /// The known device-specific bits.
+    pub fn device(&self) -> D {
+        D::from_bits_truncate(self.bits & !COMMON_FEATURES_MASK)
+    }
+
+    /// The known device-independent bits.
+    pub fn common(&self) -> CommonFeatures {
+        CommonFeatures::from_bits_truncate(self.bits)
+    }
+
+    pub fn has(&self, feature: D) -> bool {
+        self.bits & feature.bits() == feature.bits()
+    }
+
+    pub fn has_common(&self, feature: CommonFeatures) -> bool {
+        self.common().contains(feature)
+    }
+
+    pub fn intersection(&self, other: Self) -> Self {
+        Self::from_bits(self.bits & other.bits)
+    }
+
+    pub fn union(&self, other: Self) -> Self {
+        Self::from_bits(self.bits | other.bits)
+    }
+}
+
+impl<D: DeviceFeatures> fmt::Debug for VirtioFeatures<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtioFeatures")
+            .field("device", &self.device())
+            .field("common", &self.common())
+            .finish()
+    }
+}
+
+/// Negotiates the feature set with a device.
+///
+/// The driver states which features it supports and which of them it can't
+/// work without. [`FeatureNegotiation::negotiate`] takes the features offered
+/// by the device and keeps the result so queue setup (e.g., packed vs. split
+/// rings, event index) can be decided later on.
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureNegotiation<D: DeviceFeatures> {
+    supported: VirtioFeatures<D>,
+    required: VirtioFeatures<D>,
+    negotiated: Option<VirtioFeatures<D>>,
+}
+
+impl<D: DeviceFeatures> FeatureNegotiation<D> {
+    /// `required` features are implicitly supported.
+    pub fn new(supported: VirtioFeatures<D>, required: VirtioFeatures<D>) -> Self {
+        FeatureNegotiation {
+            supported: supported.union(required),
+            required,
+            negotiated: None,
+        }
+    }
+
+    /// Computes the features to acknowledge from the ones `offered` by the
+    /// device.
+    ///
+    /// Fails if the device doesn't offer all required features.
+    pub fn negotiate(&mut self, offered: u64) -> Result<VirtioFeatures<D>, VirtioError> {
+        let missing = self.required.bits() & !offered;
+        if missing != 0 {
+            warn!("virtio device lacks required features {:#x}", missing);
+            return Err(VirtioError::MissingFeatures { missing });
+        }
+
+        let negotiated = self
+            .supported
+            .intersection(VirtioFeatures::from_bits(offered));
+        self.negotiated = Some(negotiated);
+        Ok(negotiated)
+    }
+
+    /// The result of the last successful negotiation.
+    pub fn negotiated(&self) -> Result<VirtioFeatures<D>, VirtioError> {
+        self.negotiated.ok_or(VirtioError::NotNegotiated)
+    }
+
+    /// Whether `feature` was negotiated.
+    pub fn has(&self, feature: D) -> bool {
+        self.negotiated.is_some_and(|f| f.has(feature))
+    }
+
+    /// Whether `feature` was negotiated.
+    pub fn has_common(&self, feature: CommonFeatures) -> bool {
+        self.negotiated.is_some_and(|f| f.has_common(feature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_net() {
+        let supported = VirtioFeatures::new(
+            NetFeatures::MAC | NetFeatures::CSUM | NetFeatures::MQ,
+            CommonFeatures::EVENT_IDX,
+        );
+        let required = VirtioFeatures::new(NetFeatures::MAC, CommonFeatures::VERSION_1);
+        let mut negotiation = FeatureNegotiation::new(supported, required);
+
+        let offered = (NetFeatures::MAC | NetFeatures::MQ | NetFeatures::STATUS).bits()
+            | (CommonFeatures::VERSION_1 | CommonFeatures::RING_PACKED).bits();
+        let features = negotiation.negotiate(offered).unwrap();
+        assert_eq!(features.device(), NetFeatures::MAC | NetFeatures::MQ);
+        assert_eq!(features.common(), CommonFeatures::VERSION_1);
+        assert!(negotiation.has(NetFeatures::MQ));
+        assert!(!negotiation.has_common(CommonFeatures::EVENT_IDX));
+
+        let offered = NetFeatures::MAC.bits();
+        assert!(matches!(
+            negotiation.negotiate(offered),
+            Err(VirtioError::MissingFeatures { missing }) if missing == CommonFeatures::VERSION_1.bits()
+        ));
+    }
+}
@@ -0,0 +1,311 @@
+//! virtio-9p and virtio-fs transports (5.11 of the specification).
+//!
+//! Both devices carry a file system protocol over their virtqueues, 9P2000
+//! for virtio-9p and FUSE for virtio-fs: every request is a device-readable
+//! message followed by a device-writable buffer for the reply. This module
+//! only covers the transport (configuration, mount tag, queue selection and
+//! message framing), the protocol is up to the file system built on top.
+//! The virtqueues themselves are provided by the driver through
+//! [`MessageQueue`].
+
+use alloc::string::String;
+use core::convert::{TryFrom, TryInto};
+
+use crate::devq::DevQueueError;
+use crate::iomem::IOBuf;
+
+use super::features::{FsFeatures, P9Features, VirtioFeatures};
+use super::VirtioError;
+
+/// PCI device ID of (modern) 9P transport devices.
+pub const VIRTIO_9P_PCI_DEVICE_ID: u16 = 0x1049;
+/// PCI device ID of (modern) file system devices.
+pub const VIRTIO_FS_PCI_DEVICE_ID: u16 = 0x105a;
+
+/// Length of the tag in the virtio-fs configuration.
+const FS_TAG_LEN: usize = 36;
+/// Size of a 9P message header (size, type, tag).
+const P9_HDR_LEN: usize = 7;
+/// Size of `struct fuse_in_header` and `struct fuse_out_header`.
+const FUSE_IN_HDR_LEN: usize = 40;
+const FUSE_OUT_HDR_LEN: usize = 16;
+
+/// Index of the high priority queue of virtio-fs (FUSE_INTERRUPT and
+/// FUSE_FORGET, which get no reply).
+const FS_HIPRIO_QUEUE: u16 = 0;
+
+/// The virtqueues of a 9P or file system device.
+pub trait MessageQueue {
+    /// Sends the device-readable `request` on virtqueue `queue`, followed by
+    /// the device-writable `response` if given, and waits for the device to
+    /// process it.
+    ///
+    /// # Returns
+    /// The number of bytes the device wrote to `response`.
+    fn transact(
+        &mut self,
+        queue: u16,
+        request: &[u8],
+        response: Option<&mut IOBuf>,
+    ) -> Result<usize, DevQueueError>;
+}
+
+/// The little-endian length field at the start of a message.
+fn message_len(message: &[u8]) -> Option<usize> {
+    Some(u32::from_le_bytes(message.get(0..4)?.try_into().unwrap()) as usize)
+}
+
+/// A tag without trailing NUL padding, which has to be valid UTF-8.
+fn parse_tag(bytes: &[u8]) -> Result<String, VirtioError> {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len])
+        .map(String::from)
+        .map_err(|_| VirtioError::MalformedPacket)
+}
+
+/// Sends `request` and checks the framing of the reply, whose length field
+/// (at the start of the header of `hdr_len` bytes) has to match what the
+/// device wrote.
+fn transact<Q: MessageQueue>(
+    queue: &mut Q,
+    index: u16,
+    request: &[u8],
+    response: &mut IOBuf,
+    hdr_len: usize,
+) -> Result<usize, VirtioError> {
+    response.expand();
+    let written = queue
+        .transact(index, request, Some(response))
+        .map_err(|e| VirtioError::Queue { error: e })?;
+    if written < hdr_len
+        || written > response.len()
+        || message_len(response.as_slice()) != Some(written)
+    {
+        return Err(VirtioError::MalformedPacket);
+    }
+    response.truncate(written);
+    Ok(written)
+}
+
+/// A virtio-9p device.
+#[derive(Debug)]
+pub struct Virtio9p<Q> {
+    queue: Q,
+    tag: String,
+}
+
+impl<Q: MessageQueue> Virtio9p<Q> {
+    /// `features` must be the negotiated features, `config` the device
+    /// configuration (`struct virtio_9p_config`).
+    pub fn new(
+        queue: Q,
+        features: VirtioFeatures<P9Features>,
+        config: &[u8],
+    ) -> Result<Self, VirtioError> {
+        if !features.has(P9Features::MOUNT_TAG) {
+            return Err(VirtioError::Unsupported);
+        }
+        let len = config
+            .get(0..2)
+            .map(|len| u16::from_le_bytes([len[0], len[1]]) as usize);
+        let tag = match len {
+            Some(len) if config.len() >= 2 + len => parse_tag(&config[2..2 + len])?,
+            _ => return Err(VirtioError::MalformedPacket),
+        };
+        Ok(Virtio9p { queue, tag })
+    }
+
+    /// The tag the file system is mounted by.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Sends the 9P message `request` and receives the reply into `response`
+    /// (which should hold `msize` bytes).
+    ///
+    /// # Returns
+    /// The length of the reply, `response` is truncated to it.
+    pub fn transact(&mut self, request: &[u8], response: &mut IOBuf) -> Result<usize, VirtioError> {
+        if request.len() < P9_HDR_LEN || message_len(request) != Some(request.len()) {
+            return Err(VirtioError::InvalidArgument);
+        }
+        transact(&mut self.queue, 0, request, response, P9_HDR_LEN)
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.queue
+    }
+}
+
+/// A virtio-fs device.
+#[derive(Debug)]
+pub struct VirtioFs<Q> {
+    queue: Q,
+    features: VirtioFeatures<FsFeatures>,
+    tag: String,
+    num_request_queues: u32,
+}
+
+impl<Q: MessageQueue> VirtioFs<Q> {
+    /// `features` must be the negotiated features, `config` the device
+    /// configuration (`struct virtio_fs_config`).
+    pub fn new(
+        queue: Q,
+        features: VirtioFeatures<FsFeatures>,
+        config: &[u8],
+    ) -> Result<Self, VirtioError> {
+        if config.len() < FS_TAG_LEN + 4 {
+            return Err(VirtioError::MalformedPacket);
+        }
+        let tag = parse_tag(&config[..FS_TAG_LEN])?;
+        let num_request_queues =
+            u32::from_le_bytes(config[FS_TAG_LEN..FS_TAG_LEN + 4].try_into().unwrap());
+        if num_request_queues == 0 {
+            return Err(VirtioError::MalformedPacket);
+        }
+        Ok(VirtioFs {
+            queue,
+            features,
+            tag,
+            num_request_queues,
+        })
+    }
+
+    /// The tag the file system is mounted by.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn num_request_queues(&self) -> u32 {
+        self.num_request_queues
+    }
+
+    /// The virtqueue index of request queue `index`, which come after the
+    /// high priority queue and the notification queue (if negotiated).
+    pub fn request_queue_index(&self, index: u32) -> Result<u16, VirtioError> {
+        if index >= self.num_request_queues {
+            return Err(VirtioError::InvalidArgument);
+        }
+        let first = if self.features.has(FsFeatures::NOTIFICATION) {
+            2
+        } else {
+            1
+        };
+        u16::try_from(first + index).map_err(|_| VirtioError::InvalidArgument)
+    }
+
+    /// Sends a FUSE request that gets no reply (FUSE_INTERRUPT or
+    /// FUSE_FORGET) on the high priority queue.
+    pub fn send_hiprio(&mut self, request: &[u8]) -> Result<(), VirtioError> {
+        if request.len() < FUSE_IN_HDR_LEN || message_len(request) != Some(request.len()) {
+            return Err(VirtioError::InvalidArgument);
+        }
+        self.queue
+            .transact(FS_HIPRIO_QUEUE, request, None)
+            .map_err(|e| VirtioError::Queue { error: e })?;
+        Ok(())
+    }
+
+    /// Sends the FUSE request `request` on request queue `index` and
+    /// receives the reply into `response`.
+    ///
+    /// # Returns
+    /// The length of the reply, `response` is truncated to it.
+    pub fn transact(
+        &mut self,
+        index: u32,
+        request: &[u8],
+        response: &mut IOBuf,
+    ) -> Result<usize, VirtioError> {
+        if request.len() < FUSE_IN_HDR_LEN || message_len(request) != Some(request.len()) {
+            return Err(VirtioError::InvalidArgument);
+        }
+        let queue = self.request_queue_index(index)?;
+        transact(&mut self.queue, queue, request, response, FUSE_OUT_HDR_LEN)
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::features::CommonFeatures;
+    use alloc::alloc::Layout;
+    use alloc::vec::Vec;
+
+    /// Answers every request with `reply` and records the queue indices.
+    struct Echo {
+        reply: Vec<u8>,
+        queues: Vec<u16>,
+    }
+
+    impl MessageQueue for Echo {
+        fn transact(
+            &mut self,
+            queue: u16,
+            _request: &[u8],
+            response: Option<&mut IOBuf>,
+        ) -> Result<usize, DevQueueError> {
+            self.queues.push(queue);
+            match response {
+                Some(response) => {
+                    response.as_mut_slice()[..self.reply.len()].copy_from_slice(&self.reply);
+                    Ok(self.reply.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn tags_and_framing() {
+        let mut buf = IOBuf::new(Layout::from_size_align(64, 8).unwrap()).unwrap();
+
+        // Rversion: size 21, msize 8192, "9P2000.L"
+        let mut rversion = Vec::new();
+        rversion.extend_from_slice(&21u32.to_le_bytes());
+        rversion.extend_from_slice(&[101, 0xff, 0xff, 0x00, 0x20, 0, 0, 8, 0]);
+        rversion.extend_from_slice(b"9P2000.L");
+        let echo = Echo {
+            reply: rversion.clone(),
+            queues: Vec::new(),
+        };
+        let features = VirtioFeatures::new(P9Features::MOUNT_TAG, CommonFeatures::VERSION_1);
+        let mut p9 =
+            Virtio9p::new(echo, features, &[6, 0, b's', b'h', b'a', b'r', b'e', b'd']).unwrap();
+        assert_eq!(p9.tag(), "shared");
+        let mut tversion = rversion.clone();
+        tversion[4] = 100;
+        assert_eq!(p9.transact(&tversion, &mut buf).unwrap(), 21);
+        assert_eq!(buf.as_slice(), &rversion[..]);
+        assert!(matches!(
+            p9.transact(&tversion[..20], &mut buf),
+            Err(VirtioError::InvalidArgument)
+        ));
+
+        let mut config = [0u8; FS_TAG_LEN + 4];
+        config[..5].copy_from_slice(b"myfs\0");
+        config[FS_TAG_LEN] = 2;
+        let mut fuse_out = Vec::from(16u32.to_le_bytes());
+        fuse_out.extend_from_slice(&[0; 12]);
+        let echo = Echo {
+            reply: fuse_out,
+            queues: Vec::new(),
+        };
+        let features = VirtioFeatures::new(FsFeatures::NOTIFICATION, CommonFeatures::VERSION_1);
+        let mut fs = VirtioFs::new(echo, features, &config).unwrap();
+        assert_eq!((fs.tag(), fs.num_request_queues()), ("myfs", 2));
+        let mut fuse_in = Vec::from(40u32.to_le_bytes());
+        fuse_in.extend_from_slice(&[0; 36]);
+        fs.send_hiprio(&fuse_in).unwrap();
+        assert_eq!(fs.transact(1, &fuse_in, &mut buf).unwrap(), 16);
+        assert!(matches!(
+            fs.transact(2, &fuse_in, &mut buf),
+            Err(VirtioError::InvalidArgument)
+        ));
+        assert_eq!(fs.into_inner().queues, [0, 3]);
+    }
+}
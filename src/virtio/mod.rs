@@ -0,0 +1,37 @@
+//! Definitions for virtio devices.
+//!
+//! See the Virtual I/O Device (VIRTIO) specification, version 1.1.
+//!
+//! The virtqueues themselves are up to the driver, which can use
+//! [`Notifier`](crate::devq::Notifier) to suppress notifications.
+
+use custom_error::custom_error;
+
+use crate::devq::DevQueueError;
+
+pub mod balloon;
+pub mod features;
+pub mod fs;
+pub mod net;
+pub mod pci;
+pub mod rng;
+pub mod scsi;
+
+pub use features::{FeatureNegotiation, VirtioFeatures};
+
+/// PCI vendor ID of all virtio devices.
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+
+custom_error! {
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub VirtioError
+    MissingFeatures{missing: u64} = "the device doesn't offer required features {missing:#x}",
+    NotNegotiated = "feature negotiation hasn't completed",
+    Unsupported = "the operation requires a feature that wasn't negotiated",
+    InvalidArgument = "invalid argument",
+    MalformedPacket = "the device returned a malformed packet",
+    OutOfMemory = "out of memory",
+    CommandFailed{class: u8, command: u8} = "the device rejected control command {class}.{command}",
+    ScsiFailed{response: u8, status: u8} = "the SCSI request failed (response {response}, status {status:#x})",
+    Queue{error: DevQueueError} = "virtqueue error: {error}",
+}
@@ -0,0 +1,562 @@
+//! virtio-net packet headers and control virtqueue (5.1.6 of the
+//! specification).
+//!
+//! Control commands are a class/command header followed by command-specific
+//! data; the device answers with a one byte ack. The virtqueue itself is
+//! provided by the driver through [`ControlQueue`].
+
+use alloc::vec::Vec;
+
+use crate::devq::DevQueueError;
+use crate::iomem::{IOBuf, IOBufChain};
+use crate::net::csum::RxChecksum;
+use crate::net::gso::{Gso, GsoType};
+use crate::net::{MacAddress, MultiQueue, RxFilter};
+
+use super::features::{CommonFeatures, NetFeatures, VirtioFeatures};
+use super::VirtioError;
+
+pub const VIRTIO_NET_OK: u8 = 0;
+pub const VIRTIO_NET_ERR: u8 = 1;
+
+pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+pub const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+pub const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+
+pub const VIRTIO_NET_CTRL_MAC: u8 = 1;
+pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+pub const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+
+pub const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+
+pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: u16 = 1;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: u16 = 0x8000;
+
+/// Highest VLAN ID.
+const VLAN_MAX: u16 = 4095;
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+pub const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+pub const VIRTIO_NET_HDR_F_RSC_INFO: u8 = 4;
+
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+/// `struct virtio_net_hdr`, which precedes every packet on the RX and TX
+/// queues.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+    /// Number of RX buffers the packet spans (only with `MRG_RXBUF` or
+    /// `VERSION_1`).
+    pub num_buffers: u16,
+}
+
+impl VirtioNetHdr {
+    /// Size of the header without `num_buffers` (legacy devices without
+    /// `MRG_RXBUF`).
+    pub const LEGACY_LEN: usize = 10;
+    pub const LEN: usize = 12;
+
+    /// Size of the header on a device with the negotiated `features`.
+    pub fn len(features: VirtioFeatures<NetFeatures>) -> usize {
+        if features.has(NetFeatures::MRG_RXBUF) || features.has_common(CommonFeatures::VERSION_1) {
+            VirtioNetHdr::LEN
+        } else {
+            VirtioNetHdr::LEGACY_LEN
+        }
+    }
+
+    /// The header for transmitting `bufs`, requesting segmentation if the
+    /// chain carries GSO metadata.
+    ///
+    /// Requires `HOST_TSO4`/`HOST_TSO6`/`HOST_UFO` (and `CSUM`) to have been
+    /// negotiated for the respective GSO type.
+    pub fn for_tx(bufs: &IOBufChain) -> VirtioNetHdr {
+        match bufs.gso {
+            Some(gso) => VirtioNetHdr {
+                flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                gso_type: match gso.gso_type {
+                    GsoType::TcpV4 => VIRTIO_NET_HDR_GSO_TCPV4,
+                    GsoType::TcpV6 => VIRTIO_NET_HDR_GSO_TCPV6,
+                    GsoType::Udp => VIRTIO_NET_HDR_GSO_UDP,
+                } | if gso.ecn { VIRTIO_NET_HDR_GSO_ECN } else { 0 },
+                hdr_len: gso.hdr_len,
+                gso_size: gso.mss,
+                csum_start: gso.l4_offset,
+                csum_offset: gso.csum_offset(),
+                num_buffers: 0,
+            },
+            None => VirtioNetHdr::default(),
+        }
+    }
+
+    /// The L4 checksum status of a received packet.
+    ///
+    /// `DATA_VALID` means the device validated the checksum. With
+    /// `NEEDS_CSUM` the packet came from a local sender and has no (full)
+    /// checksum yet, it is as trustworthy as a validated one. virtio doesn't
+    /// report bad checksums or anything about L3.
+    pub fn rx_csum(&self) -> RxChecksum {
+        if self.flags & (VIRTIO_NET_HDR_F_DATA_VALID | VIRTIO_NET_HDR_F_NEEDS_CSUM) != 0 {
+            RxChecksum::Good
+        } else {
+            RxChecksum::Unknown
+        }
+    }
+
+    /// The feature the device must have negotiated to segment `gso`.
+    pub fn gso_feature(gso: &Gso) -> NetFeatures {
+        match gso.gso_type {
+            GsoType::TcpV4 => NetFeatures::HOST_TSO4,
+            GsoType::TcpV6 => NetFeatures::HOST_TSO6,
+            GsoType::Udp => NetFeatures::HOST_UFO,
+        }
+    }
+
+    /// Parses a header of `len` bytes (see [`VirtioNetHdr::len`]) from the
+    /// start of `bytes`.
+    pub fn parse(bytes: &[u8], len: usize) -> Option<VirtioNetHdr> {
+        if bytes.len() < len || len < VirtioNetHdr::LEGACY_LEN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        Some(VirtioNetHdr {
+            flags: bytes[0],
+            gso_type: bytes[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+            num_buffers: if len >= VirtioNetHdr::LEN {
+                u16_at(10)
+            } else {
+                1
+            },
+        })
+    }
+
+    /// Writes the first `len` bytes of the header to `bytes`.
+    pub fn write(&self, bytes: &mut [u8], len: usize) {
+        let mut raw = [0u8; VirtioNetHdr::LEN];
+        raw[0] = self.flags;
+        raw[1] = self.gso_type;
+        raw[2..4].copy_from_slice(&self.hdr_len.to_le_bytes());
+        raw[4..6].copy_from_slice(&self.gso_size.to_le_bytes());
+        raw[6..8].copy_from_slice(&self.csum_start.to_le_bytes());
+        raw[8..10].copy_from_slice(&self.csum_offset.to_le_bytes());
+        raw[10..12].copy_from_slice(&self.num_buffers.to_le_bytes());
+        bytes[..len].copy_from_slice(&raw[..len]);
+    }
+}
+
+/// Reassembles packets that span multiple RX buffers (`MRG_RXBUF`).
+///
+/// The driver hands every used RX buffer to [`MergeableRx::push`] in the
+/// order the device returned them. The first buffer of a packet starts with a
+/// [`VirtioNetHdr`] whose `num_buffers` says how many buffers follow; once all
+/// of them arrived the packet is returned as one [`IOBufChain`] with the
+/// header stripped.
+#[derive(Debug)]
+pub struct MergeableRx {
+    hdr_len: usize,
+    pending: Option<(VirtioNetHdr, IOBufChain)>,
+    remaining: u16,
+}
+
+impl MergeableRx {
+    pub fn new(features: VirtioFeatures<NetFeatures>) -> Self {
+        MergeableRx {
+            hdr_len: VirtioNetHdr::len(features),
+            pending: None,
+            remaining: 0,
+        }
+    }
+
+    /// Whether a partially received packet is waiting for more buffers.
+    pub fn in_progress(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Adds a buffer the device wrote `used_len` bytes to.
+    ///
+    /// Returns the header and data of the packet once its last buffer has
+    /// been added. A malformed header drops the buffer and returns an error.
+    pub fn push(
+        &mut self,
+        mut buf: IOBuf,
+        used_len: usize,
+    ) -> Result<Option<(VirtioNetHdr, IOBufChain)>, VirtioError> {
+        buf.truncate(used_len);
+
+        if self.pending.is_none() {
+            let hdr = VirtioNetHdr::parse(buf.as_slice(), self.hdr_len)
+                .filter(|hdr| hdr.num_buffers > 0)
+                .ok_or(VirtioError::MalformedPacket)?;
+
+            // Strip the header so the chain only holds the frame
+            buf.as_mut_slice().copy_within(self.hdr_len.., 0);
+            buf.truncate(used_len - self.hdr_len);
+
+            let chain = IOBufChain::new(0, hdr.num_buffers as usize)
+                .map_err(|_e| VirtioError::OutOfMemory)?;
+            self.pending = Some((hdr, chain));
+            self.remaining = hdr.num_buffers;
+        }
+
+        if let Some((_hdr, chain)) = self.pending.as_mut() {
+            chain.append(buf);
+        }
+        self.remaining -= 1;
+
+        if self.remaining == 0 {
+            Ok(self.pending.take().map(|(hdr, mut chain)| {
+                chain.set_rx_csum(RxChecksum::Unknown, hdr.rx_csum());
+                (hdr, chain)
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Drops a partially received packet (e.g., when the queue is reset).
+    pub fn reset(&mut self) -> Option<IOBufChain> {
+        self.remaining = 0;
+        self.pending.take().map(|(_hdr, chain)| chain)
+    }
+}
+
+/// A command for the control virtqueue.
+#[derive(Debug, Clone, Copy)]
+pub enum CtrlCommand<'a> {
+    Promiscuous(bool),
+    AllMulticast(bool),
+    MacTable {
+        unicast: &'a [MacAddress],
+        multicast: &'a [MacAddress],
+    },
+    MacAddress(MacAddress),
+    VlanAdd(u16),
+    VlanDel(u16),
+    QueuePairs(u16),
+}
+
+impl<'a> CtrlCommand<'a> {
+    pub fn class(&self) -> u8 {
+        match self {
+            CtrlCommand::Promiscuous(_) | CtrlCommand::AllMulticast(_) => VIRTIO_NET_CTRL_RX,
+            CtrlCommand::MacTable { .. } | CtrlCommand::MacAddress(_) => VIRTIO_NET_CTRL_MAC,
+            CtrlCommand::VlanAdd(_) | CtrlCommand::VlanDel(_) => VIRTIO_NET_CTRL_VLAN,
+            CtrlCommand::QueuePairs(_) => VIRTIO_NET_CTRL_MQ,
+        }
+    }
+
+    pub fn command(&self) -> u8 {
+        match self {
+            CtrlCommand::Promiscuous(_) => VIRTIO_NET_CTRL_RX_PROMISC,
+            CtrlCommand::AllMulticast(_) => VIRTIO_NET_CTRL_RX_ALLMULTI,
+            CtrlCommand::MacTable { .. } => VIRTIO_NET_CTRL_MAC_TABLE_SET,
+            CtrlCommand::MacAddress(_) => VIRTIO_NET_CTRL_MAC_ADDR_SET,
+            CtrlCommand::VlanAdd(_) => VIRTIO_NET_CTRL_VLAN_ADD,
+            CtrlCommand::VlanDel(_) => VIRTIO_NET_CTRL_VLAN_DEL,
+            CtrlCommand::QueuePairs(_) => VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+        }
+    }
+
+    /// The feature the device must have negotiated to accept the command.
+    pub fn required_feature(&self) -> NetFeatures {
+        match self {
+            CtrlCommand::Promiscuous(_)
+            | CtrlCommand::AllMulticast(_)
+            | CtrlCommand::MacTable { .. } => NetFeatures::CTRL_RX,
+            CtrlCommand::MacAddress(_) => NetFeatures::CTRL_MAC_ADDR,
+            CtrlCommand::VlanAdd(_) | CtrlCommand::VlanDel(_) => NetFeatures::CTRL_VLAN,
+            CtrlCommand::QueuePairs(_) => NetFeatures::MQ,
+        }
+    }
+
+    /// Appends the device-readable part of the command (header and data) to
+    /// `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.class());
+        buf.push(self.command());
+        match *self {
+            CtrlCommand::Promiscuous(on) | CtrlCommand::AllMulticast(on) => buf.push(on as u8),
+            CtrlCommand::MacTable { unicast, multicast } => {
+                for table in [unicast, multicast] {
+                    buf.extend_from_slice(&(table.len() as u32).to_le_bytes());
+                    for mac in table {
+                        buf.extend_from_slice(mac);
+                    }
+                }
+            }
+            CtrlCommand::MacAddress(mac) => buf.extend_from_slice(&mac),
+            CtrlCommand::VlanAdd(vid)
+            | CtrlCommand::VlanDel(vid)
+            | CtrlCommand::QueuePairs(vid) => buf.extend_from_slice(&vid.to_le_bytes()),
+        }
+    }
+}
+
+/// The control virtqueue of a virtio-net device.
+pub trait ControlQueue {
+    /// Submits `command` (as encoded by [`CtrlCommand::encode`]) followed by
+    /// a device-writable ack byte, waits for the device to process it and
+    /// returns the ack.
+    fn execute(&mut self, command: &[u8]) -> Result<u8, DevQueueError>;
+}
+
+/// Receive filtering and multi-queue control of a virtio-net device.
+#[derive(Debug)]
+pub struct VirtioNetControl<Q> {
+    queue: Q,
+    features: VirtioFeatures<NetFeatures>,
+    /// `max_virtqueue_pairs` from the device configuration.
+    max_queue_pairs: u16,
+    queue_pairs: u16,
+}
+
+impl<Q: ControlQueue> VirtioNetControl<Q> {
+    /// `features` must be the negotiated features, `max_queue_pairs` the
+    /// value the device reports in its configuration (1 without MQ).
+    pub fn new(queue: Q, features: VirtioFeatures<NetFeatures>, max_queue_pairs: u16) -> Self {
+        VirtioNetControl {
+            queue,
+            features,
+            max_queue_pairs: max_queue_pairs.max(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN),
+            queue_pairs: VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
+        }
+    }
+
+    /// Sends `command` to the device.
+    pub fn execute(&mut self, command: CtrlCommand) -> Result<(), VirtioError> {
+        if !self.features.has(NetFeatures::CTRL_VQ)
+            || !self.features.has(command.required_feature())
+        {
+            return Err(VirtioError::Unsupported);
+        }
+
+        let mut buf = Vec::new();
+        command.encode(&mut buf);
+        match self.queue.execute(&buf) {
+            Ok(VIRTIO_NET_OK) => Ok(()),
+            Ok(_ack) => {
+                warn!(
+                    "virtio-net control command {}.{} failed",
+                    command.class(),
+                    command.command()
+                );
+                Err(VirtioError::CommandFailed {
+                    class: command.class(),
+                    command: command.command(),
+                })
+            }
+            Err(e) => Err(VirtioError::Queue { error: e }),
+        }
+    }
+
+    /// Changes the primary MAC address.
+    pub fn set_mac_address(&mut self, mac: MacAddress) -> Result<(), VirtioError> {
+        self.execute(CtrlCommand::MacAddress(mac))
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.queue
+    }
+}
+
+impl<Q: ControlQueue> RxFilter for VirtioNetControl<Q> {
+    type Error = VirtioError;
+
+    fn set_promiscuous(&mut self, enable: bool) -> Result<(), VirtioError> {
+        self.execute(CtrlCommand::Promiscuous(enable))
+    }
+
+    fn set_all_multicast(&mut self, enable: bool) -> Result<(), VirtioError> {
+        self.execute(CtrlCommand::AllMulticast(enable))
+    }
+
+    fn set_mac_filter(
+        &mut self,
+        unicast: &[MacAddress],
+        multicast: &[MacAddress],
+    ) -> Result<(), VirtioError> {
+        self.execute(CtrlCommand::MacTable { unicast, multicast })
+    }
+
+    fn add_vlan(&mut self, vid: u16) -> Result<(), VirtioError> {
+        if vid > VLAN_MAX {
+            return Err(VirtioError::InvalidArgument);
+        }
+        self.execute(CtrlCommand::VlanAdd(vid))
+    }
+
+    fn remove_vlan(&mut self, vid: u16) -> Result<(), VirtioError> {
+        if vid > VLAN_MAX {
+            return Err(VirtioError::InvalidArgument);
+        }
+        self.execute(CtrlCommand::VlanDel(vid))
+    }
+}
+
+impl<Q: ControlQueue> MultiQueue for VirtioNetControl<Q> {
+    type Error = VirtioError;
+
+    fn max_queue_pairs(&self) -> u16 {
+        self.max_queue_pairs
+    }
+
+    fn queue_pairs(&self) -> u16 {
+        self.queue_pairs
+    }
+
+    fn set_queue_pairs(&mut self, n: u16) -> Result<(), VirtioError> {
+        if !(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN
+            ..=self.max_queue_pairs.min(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX))
+            .contains(&n)
+        {
+            return Err(VirtioError::InvalidArgument);
+        }
+        self.execute(CtrlCommand::QueuePairs(n))?;
+        self.queue_pairs = n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::alloc::Layout;
+
+    struct Recorder(Vec<Vec<u8>>);
+
+    impl ControlQueue for Recorder {
+        fn execute(&mut self, command: &[u8]) -> Result<u8, DevQueueError> {
+            self.0.push(command.to_vec());
+            Ok(VIRTIO_NET_OK)
+        }
+    }
+
+    #[test]
+    fn encode_commands() {
+        let features = VirtioFeatures::new(
+            NetFeatures::CTRL_VQ | NetFeatures::CTRL_RX | NetFeatures::MQ,
+            CommonFeatures::VERSION_1,
+        );
+        let mut ctrl = VirtioNetControl::new(Recorder(Vec::new()), features, 4);
+
+        ctrl.set_mac_filter(&[[1, 2, 3, 4, 5, 6]], &[]).unwrap();
+        ctrl.set_queue_pairs(4).unwrap();
+        assert!(matches!(
+            ctrl.set_queue_pairs(5),
+            Err(VirtioError::InvalidArgument)
+        ));
+        assert!(matches!(ctrl.add_vlan(1), Err(VirtioError::Unsupported)));
+        assert_eq!(ctrl.queue_pairs(), 4);
+
+        let sent = ctrl.into_inner().0;
+        assert_eq!(sent[0], [1, 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, 6, 0, 0, 0, 0]);
+        assert_eq!(sent[1], [4, 0, 4, 0]);
+    }
+
+    #[test]
+    fn tx_header_for_gso() {
+        let mut chain = IOBufChain::new(0, 1).unwrap();
+        let hdr = VirtioNetHdr::for_tx(&chain);
+        assert_eq!((hdr.flags, hdr.gso_type), (0, VIRTIO_NET_HDR_GSO_NONE));
+
+        let cases = [
+            // Ethernet + IPv4 + TCP with timestamps
+            (
+                GsoType::TcpV4,
+                34,
+                66,
+                VIRTIO_NET_HDR_GSO_TCPV4,
+                16,
+                NetFeatures::HOST_TSO4,
+            ),
+            // Ethernet + IPv6 + TCP
+            (
+                GsoType::TcpV6,
+                54,
+                74,
+                VIRTIO_NET_HDR_GSO_TCPV6,
+                16,
+                NetFeatures::HOST_TSO6,
+            ),
+            // Ethernet + IPv4 + UDP
+            (
+                GsoType::Udp,
+                34,
+                42,
+                VIRTIO_NET_HDR_GSO_UDP,
+                6,
+                NetFeatures::HOST_UFO,
+            ),
+        ];
+        for (gso_type, l4_offset, hdr_len, virtio_type, csum_offset, feature) in cases {
+            for ecn in [false, true] {
+                let gso = Gso {
+                    gso_type,
+                    l4_offset,
+                    hdr_len,
+                    mss: 1448,
+                    ecn,
+                };
+                assert_eq!(gso.csum_offset(), csum_offset);
+                assert_eq!(VirtioNetHdr::gso_feature(&gso), feature);
+
+                chain.gso = Some(gso);
+                let hdr = VirtioNetHdr::for_tx(&chain);
+                let ecn_bit = if ecn { VIRTIO_NET_HDR_GSO_ECN } else { 0 };
+                assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+                assert_eq!(hdr.gso_type, virtio_type | ecn_bit);
+                assert_eq!(
+                    (hdr.hdr_len, hdr.gso_size, hdr.csum_start, hdr.csum_offset),
+                    (hdr_len, 1448, l4_offset, csum_offset)
+                );
+                assert_eq!(hdr.num_buffers, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn merge_rx_buffers() {
+        let features = VirtioFeatures::new(NetFeatures::MRG_RXBUF, CommonFeatures::VERSION_1);
+        let mut rx = MergeableRx::new(features);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        let mut first = IOBuf::new(layout).unwrap();
+        let hdr = VirtioNetHdr {
+            num_buffers: 2,
+            ..Default::default()
+        };
+        hdr.write(first.as_mut_slice(), VirtioNetHdr::LEN);
+        first.as_mut_slice()[12..16].copy_from_slice(&[1, 2, 3, 4]);
+        assert!(rx.push(first, 16).unwrap().is_none());
+        assert!(rx.in_progress());
+
+        let mut second = IOBuf::new(layout).unwrap();
+        second.as_mut_slice()[..2].copy_from_slice(&[5, 6]);
+        let (hdr, chain) = rx.push(second, 2).unwrap().unwrap();
+        assert_eq!(hdr.num_buffers, 2);
+        assert_eq!(chain.segments.len(), 2);
+        assert_eq!(chain.segments[0].as_slice(), [1, 2, 3, 4]);
+        assert_eq!(chain.segments[1].as_slice(), [5, 6]);
+        assert_eq!(chain.rx_l4_csum(), RxChecksum::Unknown);
+        assert!(!rx.in_progress());
+    }
+}
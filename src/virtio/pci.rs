@@ -0,0 +1,108 @@
+//! The virtio PCI transport (section 4.1 of the specification).
+//!
+//! Virtio devices describe where their configuration structures are located
+//! with vendor-specific PCI capabilities.
+
+use crate::pci::{Capability, CapabilityId, ConfigSpace, PciDevice};
+
+/// Minimum length of a `struct virtio_pci_cap`.
+const VIRTIO_PCI_CAP_LEN: u8 = 16;
+
+/// The kind of configuration structure a capability points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VirtioCfgType {
+    /// Common configuration
+    Common,
+    /// Notifications
+    Notify,
+    /// ISR Status
+    Isr,
+    /// Device specific configuration
+    Device,
+    /// PCI configuration access
+    PciCfg,
+    /// Shared memory region
+    SharedMemory,
+    /// Vendor-specific data
+    Vendor,
+    Unknown(u8),
+}
+
+impl From<u8> for VirtioCfgType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => VirtioCfgType::Common,
+            2 => VirtioCfgType::Notify,
+            3 => VirtioCfgType::Isr,
+            4 => VirtioCfgType::Device,
+            5 => VirtioCfgType::PciCfg,
+            8 => VirtioCfgType::SharedMemory,
+            9 => VirtioCfgType::Vendor,
+            x => VirtioCfgType::Unknown(x),
+        }
+    }
+}
+
+/// A parsed `struct virtio_pci_cap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VirtioPciCap {
+    pub cfg_type: VirtioCfgType,
+    /// The BAR the structure is located in.
+    pub bar: u8,
+    /// Distinguishes multiple capabilities of the same type.
+    pub id: u8,
+    /// Offset of the structure within the BAR.
+    pub offset: u32,
+    /// Length of the structure in bytes.
+    pub length: u32,
+    /// Only present for [`VirtioCfgType::Notify`].
+    pub notify_off_multiplier: Option<u32>,
+}
+
+impl VirtioPciCap {
+    /// Parses the vendor-specific capability `cap` of `device`.
+    ///
+    /// Returns None if `cap` isn't a vendor-specific capability or too short
+    /// to be a virtio capability.
+    pub fn parse<A: ConfigSpace>(device: &PciDevice<A>, cap: &Capability) -> Option<Self> {
+        if cap.id != CapabilityId::VendorSpecific {
+            return None;
+        }
+
+        let config = device.config();
+        let offset = cap.offset as u32;
+        let dw0 = config.read(offset);
+        let cap_len = (dw0 >> 16) as u8;
+        if cap_len < VIRTIO_PCI_CAP_LEN {
+            return None;
+        }
+
+        let cfg_type = VirtioCfgType::from((dw0 >> 24) as u8);
+        let dw1 = config.read(offset + 4);
+        let notify_off_multiplier = if cfg_type == VirtioCfgType::Notify && cap_len >= 20 {
+            Some(config.read(offset + 16))
+        } else {
+            None
+        };
+
+        Some(VirtioPciCap {
+            cfg_type,
+            bar: dw1 as u8,
+            id: (dw1 >> 8) as u8,
+            offset: config.read(offset + 8),
+            length: config.read(offset + 12),
+            notify_off_multiplier,
+        })
+    }
+}
+
+/// Iterates over the virtio capabilities of `device`.
+pub fn virtio_capabilities<A: ConfigSpace>(
+    device: &PciDevice<A>,
+) -> impl Iterator<Item = VirtioPciCap> + '_ {
+    device
+        .capabilities()
+        .filter_map(move |cap| VirtioPciCap::parse(device, &cap))
+}
@@ -0,0 +1,154 @@
+//! virtio-rng entropy devices (5.4 of the specification).
+//!
+//! The device has a single request queue and no configuration: the driver
+//! posts device-writable buffers and the device fills (a prefix of) them with
+//! random bytes. The virtqueue itself is provided by the driver through
+//! [`EntropyQueue`].
+
+use alloc::alloc::Layout;
+
+use crate::devq::DevQueueError;
+use crate::iomem::IOBuf;
+
+use super::VirtioError;
+
+/// PCI device ID of (modern) entropy devices.
+pub const VIRTIO_RNG_PCI_DEVICE_ID: u16 = 0x1044;
+/// PCI device ID of transitional entropy devices.
+pub const VIRTIO_RNG_PCI_DEVICE_ID_TRANSITIONAL: u16 = 0x1005;
+
+/// Size of the buffer posted to the device by default.
+pub const DEFAULT_REQUEST_LEN: usize = 64;
+/// Requests that may come back empty in a row before [`VirtioRng::fill`]
+/// gives up.
+const MAX_EMPTY_REQUESTS: usize = 16;
+
+/// The request queue of a virtio-rng device.
+pub trait EntropyQueue {
+    /// Posts `buf` as a device-writable buffer, waits for the device to
+    /// return it and returns the number of bytes the device wrote.
+    fn request(&mut self, buf: &mut IOBuf) -> Result<usize, DevQueueError>;
+}
+
+/// A virtio-rng device.
+#[derive(Debug)]
+pub struct VirtioRng<Q> {
+    queue: Q,
+    buf: IOBuf,
+}
+
+impl<Q: EntropyQueue> VirtioRng<Q> {
+    pub fn new(queue: Q) -> Result<Self, VirtioError> {
+        VirtioRng::with_request_len(queue, DEFAULT_REQUEST_LEN)
+    }
+
+    /// A device posting buffers of `len` bytes.
+    pub fn with_request_len(queue: Q, len: usize) -> Result<Self, VirtioError> {
+        let layout =
+            Layout::from_size_align(len.max(1), 1).map_err(|_| VirtioError::InvalidArgument)?;
+        let buf = IOBuf::new(layout).map_err(|_| VirtioError::OutOfMemory)?;
+        Ok(VirtioRng { queue, buf })
+    }
+
+    /// Reads at most one request's worth of random bytes into `dst`, bytes
+    /// that don't fit are discarded.
+    ///
+    /// # Returns
+    /// The number of bytes read, which may be less than `dst.len()` (and
+    /// zero if the device has no entropy right now).
+    pub fn read(&mut self, dst: &mut [u8]) -> Result<usize, VirtioError> {
+        if dst.is_empty() {
+            return Ok(0);
+        }
+        self.buf.expand();
+        let written = self
+            .queue
+            .request(&mut self.buf)
+            .map_err(|e| VirtioError::Queue { error: e })?;
+        if written > self.buf.len() {
+            return Err(VirtioError::MalformedPacket);
+        }
+        let len = written.min(dst.len());
+        dst[..len].copy_from_slice(&self.buf.as_slice()[..len]);
+        Ok(len)
+    }
+
+    /// Fills `dst` with random bytes, issuing as many requests as needed.
+    pub fn fill(&mut self, dst: &mut [u8]) -> Result<(), VirtioError> {
+        let mut filled = 0;
+        let mut empty = 0;
+        while filled < dst.len() {
+            match self.read(&mut dst[filled..])? {
+                0 => {
+                    empty += 1;
+                    if empty == MAX_EMPTY_REQUESTS {
+                        warn!("virtio-rng returned no entropy for {} requests", empty);
+                        return Err(VirtioError::Queue {
+                            error: DevQueueError::QueueEmpty,
+                        });
+                    }
+                }
+                len => {
+                    filled += len;
+                    empty = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out a counter, at most `per_request` bytes at a time.
+    struct Counter {
+        next: u8,
+        per_request: usize,
+    }
+
+    impl EntropyQueue for Counter {
+        fn request(&mut self, buf: &mut IOBuf) -> Result<usize, DevQueueError> {
+            let len = self.per_request.min(buf.len());
+            for byte in &mut buf.as_mut_slice()[..len] {
+                *byte = self.next;
+                self.next = self.next.wrapping_add(1);
+            }
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn read_and_fill() {
+        let mut rng = VirtioRng::with_request_len(
+            Counter {
+                next: 0,
+                per_request: 3,
+            },
+            8,
+        )
+        .unwrap();
+        let mut bytes = [0u8; 2];
+        assert_eq!(rng.read(&mut bytes).unwrap(), 2);
+        assert_eq!(bytes, [0, 1]);
+
+        let mut bytes = [0u8; 7];
+        rng.fill(&mut bytes).unwrap();
+        assert_eq!(bytes, [3, 4, 5, 6, 7, 8, 9]);
+
+        let mut rng = VirtioRng::new(Counter {
+            next: 0,
+            per_request: 0,
+        })
+        .unwrap();
+        assert!(matches!(
+            rng.fill(&mut bytes),
+            Err(VirtioError::Queue { .. })
+        ));
+    }
+}
@@ -0,0 +1,569 @@
+//! virtio-scsi request queues and SCSI commands (5.6 of the specification).
+//!
+//! Every request on a request queue is a device-readable header with the
+//! CDB, optionally followed by data for the device, and a device-writable
+//! response with the SCSI status and sense data, optionally followed by data
+//! from the device. The virtqueues themselves are provided by the driver
+//! through [`ScsiQueue`].
+
+use alloc::alloc::Layout;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::devq::DevQueueError;
+use crate::iomem::IOBuf;
+
+use super::features::{ScsiFeatures, VirtioFeatures};
+use super::VirtioError;
+
+/// Size of the request header without the CDB.
+const REQ_HDR_LEN: usize = 19;
+/// Size of the response without the sense data.
+const RESP_HDR_LEN: usize = 12;
+
+/// Default `cdb_size` and `sense_size` of the device configuration.
+pub const VIRTIO_SCSI_CDB_DEFAULT_SIZE: usize = 32;
+pub const VIRTIO_SCSI_SENSE_DEFAULT_SIZE: usize = 96;
+
+/// Response codes of request queue requests.
+pub const VIRTIO_SCSI_S_OK: u8 = 0;
+pub const VIRTIO_SCSI_S_OVERRUN: u8 = 1;
+pub const VIRTIO_SCSI_S_ABORTED: u8 = 2;
+pub const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
+pub const VIRTIO_SCSI_S_RESET: u8 = 4;
+pub const VIRTIO_SCSI_S_BUSY: u8 = 5;
+pub const VIRTIO_SCSI_S_TRANSPORT_FAILURE: u8 = 6;
+pub const VIRTIO_SCSI_S_TARGET_FAILURE: u8 = 7;
+pub const VIRTIO_SCSI_S_NEXUS_FAILURE: u8 = 8;
+pub const VIRTIO_SCSI_S_FAILURE: u8 = 9;
+
+/// SCSI status codes.
+pub const SCSI_STATUS_GOOD: u8 = 0x00;
+pub const SCSI_STATUS_CHECK_CONDITION: u8 = 0x02;
+
+/// Task attribute of all requests (simple queue tag).
+const VIRTIO_SCSI_S_SIMPLE: u8 = 0;
+
+const INQUIRY: u8 = 0x12;
+const TEST_UNIT_READY: u8 = 0x00;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+
+/// Bytes of standard INQUIRY data the driver asks for.
+const INQUIRY_LEN: usize = 96;
+/// Standard INQUIRY data up to and including the product revision.
+const INQUIRY_MIN_LEN: usize = 36;
+const READ_CAPACITY_10_LEN: usize = 8;
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// An ASCII field of INQUIRY data without the space padding.
+fn ascii(bytes: &[u8]) -> String {
+    let s: String = bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '?'
+            }
+        })
+        .collect();
+    String::from(s.trim_end())
+}
+
+/// `struct virtio_scsi_config`, the device configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VirtioScsiConfig {
+    /// Number of request queues.
+    pub num_queues: u32,
+    /// Most data segments of a request.
+    pub seg_max: u32,
+    pub max_sectors: u32,
+    /// Most linked commands per LUN.
+    pub cmd_per_lun: u32,
+    pub event_info_size: u32,
+    pub sense_size: u32,
+    pub cdb_size: u32,
+    pub max_channel: u16,
+    pub max_target: u16,
+    pub max_lun: u32,
+}
+
+impl VirtioScsiConfig {
+    pub const LEN: usize = 36;
+
+    /// Parses the device configuration structure.
+    pub fn parse(bytes: &[u8]) -> Option<VirtioScsiConfig> {
+        if bytes.len() < VirtioScsiConfig::LEN {
+            return None;
+        }
+        Some(VirtioScsiConfig {
+            num_queues: le_u32(bytes, 0),
+            seg_max: le_u32(bytes, 4),
+            max_sectors: le_u32(bytes, 8),
+            cmd_per_lun: le_u32(bytes, 12),
+            event_info_size: le_u32(bytes, 16),
+            sense_size: le_u32(bytes, 20),
+            cdb_size: le_u32(bytes, 24),
+            max_channel: le_u16(bytes, 28),
+            max_target: le_u16(bytes, 30),
+            max_lun: le_u32(bytes, 32),
+        })
+    }
+}
+
+/// A SCSI command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Cdb {
+    TestUnitReady,
+    Inquiry { allocation_len: u16 },
+    ReadCapacity10,
+    Read { lba: u64, blocks: u32 },
+    Write { lba: u64, blocks: u32 },
+}
+
+impl Cdb {
+    /// Writes the command descriptor block to the start of `cdb`, using the
+    /// 10 byte READ/WRITE variants where they suffice.
+    ///
+    /// # Returns
+    /// The length of the CDB.
+    pub fn encode(&self, cdb: &mut [u8]) -> usize {
+        match *self {
+            Cdb::TestUnitReady => {
+                cdb[..6].fill(0);
+                cdb[0] = TEST_UNIT_READY;
+                6
+            }
+            Cdb::Inquiry { allocation_len } => {
+                cdb[..6].fill(0);
+                cdb[0] = INQUIRY;
+                cdb[3..5].copy_from_slice(&allocation_len.to_be_bytes());
+                6
+            }
+            Cdb::ReadCapacity10 => {
+                cdb[..10].fill(0);
+                cdb[0] = READ_CAPACITY_10;
+                10
+            }
+            Cdb::Read { lba, blocks } | Cdb::Write { lba, blocks } => {
+                let read = matches!(self, Cdb::Read { .. });
+                if lba <= u32::MAX as u64 && blocks <= u16::MAX as u32 {
+                    cdb[..10].fill(0);
+                    cdb[0] = if read { READ_10 } else { WRITE_10 };
+                    cdb[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+                    cdb[7..9].copy_from_slice(&(blocks as u16).to_be_bytes());
+                    10
+                } else {
+                    cdb[..16].fill(0);
+                    cdb[0] = if read { READ_16 } else { WRITE_16 };
+                    cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+                    cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+                    16
+                }
+            }
+        }
+    }
+}
+
+/// The data of a request.
+#[derive(Debug)]
+pub enum ScsiData<'a> {
+    None,
+    /// Data written to the device (device-readable).
+    ToDevice(&'a IOBuf),
+    /// Data read from the device (device-writable).
+    FromDevice(&'a mut IOBuf),
+}
+
+/// `struct virtio_scsi_resp_cmd`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScsiResponse {
+    /// Bytes of data the device didn't transfer.
+    pub residual: u32,
+    pub status_qualifier: u16,
+    /// SCSI status.
+    pub status: u8,
+    /// virtio-scsi response code.
+    pub response: u8,
+    pub sense: Vec<u8>,
+}
+
+impl ScsiResponse {
+    pub fn parse(bytes: &[u8]) -> Option<ScsiResponse> {
+        if bytes.len() < RESP_HDR_LEN {
+            return None;
+        }
+        let sense_len = (le_u32(bytes, 0) as usize).min(bytes.len() - RESP_HDR_LEN);
+        Some(ScsiResponse {
+            residual: le_u32(bytes, 4),
+            status_qualifier: le_u16(bytes, 8),
+            status: bytes[10],
+            response: bytes[11],
+            sense: bytes[RESP_HDR_LEN..RESP_HDR_LEN + sense_len].to_vec(),
+        })
+    }
+
+    /// The sense key of fixed or descriptor format sense data.
+    pub fn sense_key(&self) -> Option<u8> {
+        match self.sense.first().map(|code| code & 0x7f) {
+            Some(0x70) | Some(0x71) => self.sense.get(2).map(|key| key & 0xf),
+            Some(0x72) | Some(0x73) => self.sense.get(1).map(|key| key & 0xf),
+            _ => None,
+        }
+    }
+}
+
+/// Standard INQUIRY data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InquiryData {
+    pub qualifier: u8,
+    /// Peripheral device type (0 for disks, 5 for CD/DVD drives).
+    pub device_type: u8,
+    pub removable: bool,
+    pub vendor: String,
+    pub product: String,
+    pub revision: String,
+}
+
+impl InquiryData {
+    pub fn parse(bytes: &[u8]) -> Option<InquiryData> {
+        if bytes.len() < INQUIRY_MIN_LEN {
+            return None;
+        }
+        Some(InquiryData {
+            qualifier: bytes[0] >> 5,
+            device_type: bytes[0] & 0x1f,
+            removable: bytes[1] & 0x80 != 0,
+            vendor: ascii(&bytes[8..16]),
+            product: ascii(&bytes[16..32]),
+            revision: ascii(&bytes[32..36]),
+        })
+    }
+}
+
+/// A request queue of a virtio-scsi device.
+pub trait ScsiQueue {
+    /// Submits a request: the device-readable `request` and data, followed
+    /// by the device-writable `response` and data. Waits for the device to
+    /// process it.
+    fn execute(
+        &mut self,
+        request: &[u8],
+        data: ScsiData,
+        response: &mut [u8],
+    ) -> Result<(), DevQueueError>;
+}
+
+/// A virtio-scsi host and the LUNs behind it.
+#[derive(Debug)]
+pub struct VirtioScsi<Q> {
+    queue: Q,
+    features: VirtioFeatures<ScsiFeatures>,
+    config: VirtioScsiConfig,
+    next_id: u64,
+}
+
+impl<Q: ScsiQueue> VirtioScsi<Q> {
+    /// `features` must be the negotiated features, `config` the device
+    /// configuration.
+    pub fn new(queue: Q, features: VirtioFeatures<ScsiFeatures>, config: VirtioScsiConfig) -> Self {
+        VirtioScsi {
+            queue,
+            features,
+            config,
+            next_id: 0,
+        }
+    }
+
+    pub fn features(&self) -> VirtioFeatures<ScsiFeatures> {
+        self.features
+    }
+
+    pub fn config(&self) -> &VirtioScsiConfig {
+        &self.config
+    }
+
+    /// The LUN field addressing `lun` of `target` (single level LUN
+    /// structure, flat space addressing).
+    fn lun_address(&self, target: u16, lun: u16) -> Result<[u8; 8], VirtioError> {
+        if target > self.config.max_target.min(u8::MAX as u16)
+            || lun as u32 > self.config.max_lun
+            || lun > 0x3fff
+        {
+            return Err(VirtioError::InvalidArgument);
+        }
+        let mut address = [0u8; 8];
+        address[0] = 1;
+        address[1] = target as u8;
+        address[2..4].copy_from_slice(&(0x4000 | lun).to_be_bytes());
+        Ok(address)
+    }
+
+    /// Sends `cdb` to `lun` of `target` and returns the response, which
+    /// may have a status other than GOOD.
+    pub fn execute(
+        &mut self,
+        target: u16,
+        lun: u16,
+        cdb: &Cdb,
+        data: ScsiData,
+    ) -> Result<ScsiResponse, VirtioError> {
+        let cdb_size = self.config.cdb_size as usize;
+        let mut request = vec![0u8; REQ_HDR_LEN + cdb_size];
+        request[0..8].copy_from_slice(&self.lun_address(target, lun)?);
+        request[8..16].copy_from_slice(&self.next_id.to_le_bytes());
+        request[16] = VIRTIO_SCSI_S_SIMPLE;
+        let mut cdb_bytes = [0u8; 16];
+        let cdb_len = cdb.encode(&mut cdb_bytes);
+        if cdb_len > cdb_size {
+            return Err(VirtioError::Unsupported);
+        }
+        request[REQ_HDR_LEN..REQ_HDR_LEN + cdb_len].copy_from_slice(&cdb_bytes[..cdb_len]);
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut response = vec![0u8; RESP_HDR_LEN + self.config.sense_size as usize];
+        self.queue
+            .execute(&request, data, &mut response)
+            .map_err(|e| VirtioError::Queue { error: e })?;
+        let response = ScsiResponse::parse(&response).ok_or(VirtioError::MalformedPacket)?;
+        if response.response != VIRTIO_SCSI_S_OK {
+            warn!(
+                "virtio-scsi request to {}:{} failed with response {}",
+                target, lun, response.response
+            );
+            return Err(VirtioError::ScsiFailed {
+                response: response.response,
+                status: response.status,
+            });
+        }
+        Ok(response)
+    }
+
+    /// Executes `cdb` and fails unless the status is GOOD.
+    fn execute_good(
+        &mut self,
+        target: u16,
+        lun: u16,
+        cdb: &Cdb,
+        data: ScsiData,
+    ) -> Result<ScsiResponse, VirtioError> {
+        let response = self.execute(target, lun, cdb, data)?;
+        if response.status != SCSI_STATUS_GOOD {
+            return Err(VirtioError::ScsiFailed {
+                response: response.response,
+                status: response.status,
+            });
+        }
+        Ok(response)
+    }
+
+    /// Whether `lun` of `target` is ready (e.g., has a medium).
+    pub fn test_unit_ready(&mut self, target: u16, lun: u16) -> Result<bool, VirtioError> {
+        let response = self.execute(target, lun, &Cdb::TestUnitReady, ScsiData::None)?;
+        Ok(response.status == SCSI_STATUS_GOOD)
+    }
+
+    pub fn inquiry(&mut self, target: u16, lun: u16) -> Result<InquiryData, VirtioError> {
+        let mut buf = IOBuf::new(Layout::from_size_align(INQUIRY_LEN, 1).unwrap())
+            .map_err(|_| VirtioError::OutOfMemory)?;
+        let cdb = Cdb::Inquiry {
+            allocation_len: INQUIRY_LEN as u16,
+        };
+        let response = self.execute_good(target, lun, &cdb, ScsiData::FromDevice(&mut buf))?;
+        let len = INQUIRY_LEN.saturating_sub(response.residual as usize);
+        InquiryData::parse(&buf.as_slice()[..len]).ok_or(VirtioError::MalformedPacket)
+    }
+
+    /// The capacity of `lun` of `target` as (blocks, block size).
+    pub fn read_capacity(&mut self, target: u16, lun: u16) -> Result<(u64, u32), VirtioError> {
+        let mut buf = IOBuf::new(Layout::from_size_align(READ_CAPACITY_10_LEN, 4).unwrap())
+            .map_err(|_| VirtioError::OutOfMemory)?;
+        self.execute_good(
+            target,
+            lun,
+            &Cdb::ReadCapacity10,
+            ScsiData::FromDevice(&mut buf),
+        )?;
+        let data = buf.as_slice();
+        let last_lba = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        Ok((last_lba as u64 + 1, block_size))
+    }
+
+    /// Reads `blocks` blocks starting at `lba` into `buf`.
+    pub fn read(
+        &mut self,
+        target: u16,
+        lun: u16,
+        lba: u64,
+        blocks: u32,
+        buf: &mut IOBuf,
+    ) -> Result<(), VirtioError> {
+        self.execute_good(
+            target,
+            lun,
+            &Cdb::Read { lba, blocks },
+            ScsiData::FromDevice(buf),
+        )?;
+        Ok(())
+    }
+
+    /// Writes `blocks` blocks starting at `lba` from `buf`.
+    pub fn write(
+        &mut self,
+        target: u16,
+        lun: u16,
+        lba: u64,
+        blocks: u32,
+        buf: &IOBuf,
+    ) -> Result<(), VirtioError> {
+        self.execute_good(
+            target,
+            lun,
+            &Cdb::Write { lba, blocks },
+            ScsiData::ToDevice(buf),
+        )?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::features::CommonFeatures;
+
+    /// A disk at target 0, LUN 0 that records the requests.
+    struct Disk(Vec<Vec<u8>>);
+
+    impl ScsiQueue for Disk {
+        fn execute(
+            &mut self,
+            request: &[u8],
+            data: ScsiData,
+            response: &mut [u8],
+        ) -> Result<(), DevQueueError> {
+            self.0.push(request.to_vec());
+            response.fill(0);
+            if request[1] != 0 {
+                response[11] = VIRTIO_SCSI_S_BAD_TARGET;
+                return Ok(());
+            }
+            match (request[REQ_HDR_LEN], data) {
+                (INQUIRY, ScsiData::FromDevice(buf)) => {
+                    let data = buf.as_mut_slice();
+                    data[1] = 0x80;
+                    data[8..16].copy_from_slice(b"QEMU    ");
+                    data[16..32].copy_from_slice(b"QEMU HARDDISK   ");
+                    data[32..36].copy_from_slice(b"2.5+");
+                    response[4..8].copy_from_slice(&((INQUIRY_LEN - 36) as u32).to_le_bytes());
+                }
+                (READ_CAPACITY_10, ScsiData::FromDevice(buf)) => {
+                    buf.as_mut_slice()[0..8].copy_from_slice(&[0, 0, 0x1f, 0xff, 0, 0, 2, 0]);
+                }
+                (TEST_UNIT_READY, _) => {
+                    response[0] = 18;
+                    response[10] = SCSI_STATUS_CHECK_CONDITION;
+                    response[12] = 0x70;
+                    response[14] = 0x02;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn config() -> VirtioScsiConfig {
+        let mut bytes = [0u8; VirtioScsiConfig::LEN];
+        bytes[20..24].copy_from_slice(&(VIRTIO_SCSI_SENSE_DEFAULT_SIZE as u32).to_le_bytes());
+        bytes[24..28].copy_from_slice(&(VIRTIO_SCSI_CDB_DEFAULT_SIZE as u32).to_le_bytes());
+        bytes[30..32].copy_from_slice(&255u16.to_le_bytes());
+        bytes[32..36].copy_from_slice(&16383u32.to_le_bytes());
+        VirtioScsiConfig::parse(&bytes).unwrap()
+    }
+
+    #[test]
+    fn commands_and_responses() {
+        let features = VirtioFeatures::new(ScsiFeatures::empty(), CommonFeatures::VERSION_1);
+        let mut scsi = VirtioScsi::new(Disk(Vec::new()), features, config());
+
+        let inquiry = scsi.inquiry(0, 0).unwrap();
+        assert_eq!((inquiry.device_type, inquiry.removable), (0, true));
+        assert_eq!(
+            (
+                inquiry.vendor.as_str(),
+                inquiry.product.as_str(),
+                inquiry.revision.as_str()
+            ),
+            ("QEMU", "QEMU HARDDISK", "2.5+")
+        );
+        assert_eq!(scsi.read_capacity(0, 0).unwrap(), (0x2000, 512));
+        assert!(!scsi.test_unit_ready(0, 0).unwrap());
+        assert!(matches!(
+            scsi.inquiry(1, 0),
+            Err(VirtioError::ScsiFailed {
+                response: VIRTIO_SCSI_S_BAD_TARGET,
+                ..
+            })
+        ));
+        assert!(matches!(
+            scsi.inquiry(256, 0),
+            Err(VirtioError::InvalidArgument)
+        ));
+
+        let sent = scsi.into_inner().0;
+        assert_eq!(sent[0].len(), REQ_HDR_LEN + VIRTIO_SCSI_CDB_DEFAULT_SIZE);
+        assert_eq!(sent[0][0..4], [1, 0, 0x40, 0]);
+        assert_eq!(sent[1][8], 1);
+        assert_eq!(
+            sent[0][REQ_HDR_LEN..REQ_HDR_LEN + 6],
+            [INQUIRY, 0, 0, 0, 96, 0]
+        );
+
+        let mut cdb = [0u8; 16];
+        assert_eq!(
+            Cdb::Read {
+                lba: 0x1234,
+                blocks: 8
+            }
+            .encode(&mut cdb),
+            10
+        );
+        assert_eq!(cdb[..10], [READ_10, 0, 0, 0, 0x12, 0x34, 0, 0, 8, 0]);
+        assert_eq!(
+            Cdb::Write {
+                lba: 1 << 32,
+                blocks: 1
+            }
+            .encode(&mut cdb),
+            16
+        );
+        assert_eq!(cdb[..14], [WRITE_16, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let response =
+            ScsiResponse::parse(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0x72, 0x05, 0x24, 0])
+                .unwrap();
+        assert_eq!(
+            (response.sense.len(), response.sense_key()),
+            (3, Some(0x05))
+        );
+    }
+}
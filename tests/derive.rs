@@ -0,0 +1,56 @@
+//! Tests of `#[derive(DriverControl)]`.
+
+#![cfg(feature = "derive")]
+
+use driverkit::{DriverControl, DriverState};
+
+#[derive(DriverControl)]
+struct Named {
+    id: usize,
+    #[driver_state]
+    state: DriverState,
+}
+
+#[derive(DriverControl)]
+struct Tuple(u32, #[driver_state] DriverState);
+
+#[derive(DriverControl)]
+struct Generic<T> {
+    #[driver_state]
+    state: DriverState,
+    _inner: T,
+}
+
+#[test]
+fn named_field() {
+    let mut drv = Named {
+        id: 7,
+        state: DriverState::Uninitialized,
+    };
+    drv.init();
+    drv.attach();
+    drv.set_sleep_level(2);
+    assert_eq!(drv.state(), DriverState::Attached(2));
+    assert_eq!(drv.state, DriverState::Attached(2));
+    assert_eq!(drv.id, 7);
+}
+
+#[test]
+fn tuple_and_generic() {
+    let mut drv = Tuple(1, DriverState::Uninitialized);
+    drv.init();
+    assert_eq!((drv.0, drv.1), (1, DriverState::Initialized));
+
+    let mut drv = Generic {
+        state: DriverState::Detached,
+        _inner: [0u8; 4],
+    };
+    drv.attach();
+    assert_eq!(drv.state(), DriverState::Attached(0));
+}
+
+#[test]
+fn invalid_input() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
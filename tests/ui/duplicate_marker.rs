@@ -0,0 +1,11 @@
+use driverkit::{DriverControl, DriverState};
+
+#[derive(DriverControl)]
+struct Driver {
+    #[driver_state]
+    state: DriverState,
+    #[driver_state]
+    previous: DriverState,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use driverkit::DriverControl;
+
+#[derive(DriverControl)]
+enum Driver {
+    Up,
+    Down,
+}
+
+fn main() {}
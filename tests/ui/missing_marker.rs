@@ -0,0 +1,8 @@
+use driverkit::{DriverControl, DriverState};
+
+#[derive(DriverControl)]
+struct Driver {
+    state: DriverState,
+}
+
+fn main() {}